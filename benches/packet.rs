@@ -0,0 +1,81 @@
+//! How long parsing and writing a typical response takes, run with
+//! `cargo bench`. The response is a referral-sized one: a question, a few
+//! answers, the name servers of the zone and their glue, all compressed.
+
+use std::{
+    hint::black_box,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dns_server::{BytePacketBuffer, DnsPacket, DnsRecord, QueryBuilder, QueryType};
+
+fn response() -> DnsPacket {
+    let query = QueryBuilder::new()
+        .id(4321)
+        .edns(1232)
+        .question("www.example.com", QueryType::A)
+        .build();
+
+    let mut response = DnsPacket::response_to(&query).recursion_available(true);
+    response = response.answer(DnsRecord::CNAME {
+        domain: "www.example.com".to_string(),
+        host: "edge.cdn.example.com".to_string(),
+        ttl: 300,
+    });
+    for i in 1..=4 {
+        response = response.answer(DnsRecord::A {
+            domain: "edge.cdn.example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, i),
+            ttl: 60,
+        });
+    }
+    for ns in ["ns1", "ns2"] {
+        let host = format!("{}.example.com", ns);
+        response = response
+            .authority(DnsRecord::NS {
+                domain: "example.com".to_string(),
+                host: host.clone(),
+                ttl: 86400,
+            })
+            .additional(DnsRecord::A {
+                domain: host.clone(),
+                addr: Ipv4Addr::new(198, 51, 100, 53),
+                ttl: 86400,
+            })
+            .additional(DnsRecord::AAAA {
+                domain: host,
+                addr: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53),
+                ttl: 86400,
+            });
+    }
+
+    response.build()
+}
+
+fn packet(c: &mut Criterion) {
+    let mut packet = response();
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    let wire = buffer.buf[..buffer.pos()].to_vec();
+
+    c.bench_function("from_buffer", |b| {
+        b.iter(|| {
+            let mut buffer = BytePacketBuffer::from_slice(black_box(&wire));
+            DnsPacket::from_buffer(&mut buffer).unwrap()
+        })
+    });
+
+    // Writing only ever updates the header counts of the packet, so the
+    // same one can be written over and over
+    c.bench_function("write", |b| {
+        b.iter(|| {
+            let mut buffer = BytePacketBuffer::new();
+            black_box(&mut packet).write(&mut buffer).unwrap();
+            buffer.pos()
+        })
+    });
+}
+
+criterion_group!(benches, packet);
+criterion_main!(benches);
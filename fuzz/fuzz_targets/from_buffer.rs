@@ -0,0 +1,19 @@
+#![no_main]
+
+//! Feeds arbitrary bytes to the packet parser, which sees nothing but
+//! untrusted input from the network. Whatever it manages to parse is written
+//! back out as well, since the records of upstream responses are relayed to
+//! clients as they are.
+//!
+//! Run with `cargo +nightly fuzz run from_buffer` from the root of the crate.
+
+use dns_server::{BytePacketBuffer, DnsPacket};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buffer = BytePacketBuffer::from_slice(data);
+    if let Ok(mut packet) = DnsPacket::from_buffer(&mut buffer) {
+        let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+        let _ = packet.write(&mut buffer);
+    }
+});
@@ -0,0 +1,114 @@
+use std::net::SocketAddr;
+
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::{timeout, timeout_at, Instant},
+};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    client::{self, QueryOptions},
+    dns_packet::DnsPacket,
+    error::{DnsError, Result},
+    query_type::QueryType,
+};
+
+/// An async counterpart to `client::query`, for firing off many queries
+/// concurrently. Only the transport differs, the packets are built and checked
+/// with the same code, and truncated responses are retried over TCP likewise.
+pub async fn query(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket> {
+    let (packet, req_buffer) = client::build_query(qname, qtype, options)?;
+
+    debug!(
+        "Sending query {} for {} {:?} to {}",
+        packet.header.id, qname, qtype, server
+    );
+
+    let mut response = send_udp(&packet, &req_buffer, server, options).await?;
+
+    if response.header.truncated_message {
+        warn!(
+            "Response from {} for {} {:?} was truncated, retrying over TCP",
+            server, qname, qtype
+        );
+
+        let mut res_buffer = send_tcp(&req_buffer, server, options).await?;
+        response = client::check_response(&packet, &mut res_buffer, options)?;
+    }
+
+    Ok(response)
+}
+
+/// Look up a name with the default query options
+pub async fn lookup(
+    qname: &str,
+    qtype: QueryType,
+    server: impl Into<SocketAddr>,
+) -> Result<DnsPacket> {
+    query(qname, qtype, server.into(), &QueryOptions::default()).await
+}
+
+async fn send_udp(
+    packet: &DnsPacket,
+    req_buffer: &BytePacketBuffer,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket> {
+    // Every query gets its own ephemeral port, so that concurrent queries
+    // never see each others responses, and only the server may answer.
+    let socket = UdpSocket::bind(client::unspecified_addr(server)).await?;
+    socket.connect(server).await?;
+
+    // Unanswered queries are sent again with the same ID, as in
+    // `client::query`
+    let mut buf = vec![0; client::max_response_size(options)];
+    for attempt in 0..=options.retries {
+        if attempt > 0 {
+            debug!(
+                "No response from {} within {:?}, sending query {} again",
+                server, options.timeout, packet.header.id
+            );
+        }
+        socket.send(&req_buffer.buf[0..req_buffer.pos]).await?;
+
+        let deadline = Instant::now() + options.timeout;
+        while let Ok(len) = timeout_at(deadline, socket.recv(&mut buf)).await {
+            let mut res_buffer = BytePacketBuffer::from_slice(&buf[..len?]);
+            match client::check_response(packet, &mut res_buffer, options) {
+                Ok(response) => return Ok(response),
+                // The real response may still be on its way
+                Err(e) => warn!("Ignoring response from {}: {}", server, e),
+            }
+        }
+    }
+
+    Err(DnsError::Timeout)
+}
+
+async fn send_tcp(
+    req_buffer: &BytePacketBuffer,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<BytePacketBuffer> {
+    let mut stream = timeout(options.timeout, TcpStream::connect(server)).await??;
+
+    let len = req_buffer.pos() as u16;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(&req_buffer.buf[0..req_buffer.pos]).await?;
+
+    let mut len = [0; 2];
+    timeout(options.timeout, stream.read_exact(&mut len)).await??;
+    let len = u16::from_be_bytes(len) as usize;
+
+    let mut buf = vec![0; len];
+    timeout(options.timeout, stream.read_exact(&mut buf)).await??;
+
+    Ok(BytePacketBuffer::from_slice(&buf))
+}
@@ -0,0 +1,136 @@
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use log::error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task,
+    time::timeout,
+};
+
+use crate::{
+    byte_packet_buffer::{self, BytePacketBuffer},
+    error::{DnsError, Result},
+    metrics::Metrics,
+    resolver::ResolverOptions,
+    server::{handle_request, Transport, TCP_IDLE_TIMEOUT},
+    shutdown,
+};
+
+/// An async counterpart to the blocking server loop. Every query is handled in
+/// a task of its own, so that a slow upstream only holds up the clients
+/// waiting on it rather than everyone else.
+///
+/// The resolution itself is the same blocking code the threaded server runs,
+/// which is moved off the runtime with `spawn_blocking`. Once
+/// `options.shutdown` stops, this returns while the queries already received
+/// are still being answered.
+pub async fn serve_udp(socket: UdpSocket, options: Arc<ResolverOptions>) -> Result<()> {
+    let socket = Arc::new(socket);
+
+    let mut buf = vec![0; byte_packet_buffer::MAX_UDP_SIZE];
+    while !options.shutdown.is_stopping() {
+        let (len, src) = match timeout(shutdown::POLL_INTERVAL, socket.recv_from(&mut buf)).await {
+            Ok(received) => received?,
+            Err(_) => continue,
+        };
+
+        let req_buffer = BytePacketBuffer::from_slice(&buf[..len]);
+        let socket = socket.clone();
+        let options = options.clone();
+        tokio::spawn(async move {
+            let result = match respond(req_buffer, src, Transport::Udp, options).await {
+                Ok(res_buffer) => socket
+                    .send_to(&res_buffer.buf[0..res_buffer.pos()], src)
+                    .await
+                    .map(|_| ())
+                    .map_err(DnsError::from),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) | Err(DnsError::RateLimited | DnsError::NotAQuery) => {}
+                Err(e) => error!("An error occurred: {}", e),
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Accept TCP connections until `options.shutdown` stops, serving each one
+/// in a task of its own
+pub async fn serve_tcp(listener: TcpListener, options: Arc<ResolverOptions>) -> Result<()> {
+    options.shutdown.wake_tcp_on_stop(listener.local_addr()?);
+
+    loop {
+        let accepted = listener.accept().await;
+        if options.shutdown.is_stopping() {
+            return Ok(());
+        }
+        let (stream, src) = match accepted {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("Failed to accept TCP connection: {}", e);
+                continue;
+            }
+        };
+
+        let options = options.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_tcp_connection(stream, src, options).await {
+                error!("An error occurred: {}", e);
+            }
+        });
+    }
+}
+
+/// Answer the length prefixed queries on a TCP connection until the client
+/// closes it, or leaves it idle for too long
+async fn handle_tcp_connection(
+    mut stream: TcpStream,
+    src: SocketAddr,
+    options: Arc<ResolverOptions>,
+) -> Result<()> {
+    let _connection = options.metrics.as_ref().map(Metrics::connection);
+
+    while !options.shutdown.is_stopping() {
+        let mut len = [0; 2];
+        match timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut len)).await? {
+            Ok(_) => {}
+            // The client is done
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u16::from_be_bytes(len) as usize;
+
+        let mut buf = vec![0; len];
+        timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut buf)).await??;
+        let req_buffer = BytePacketBuffer::from_slice(&buf);
+
+        let res_buffer = respond(req_buffer, src, Transport::Tcp, options.clone()).await?;
+
+        let len = res_buffer.pos() as u16;
+        stream.write_all(&len.to_be_bytes()).await?;
+        stream
+            .write_all(&res_buffer.buf[0..res_buffer.pos()])
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Work out the response to a query on the blocking thread pool
+pub(crate) async fn respond(
+    mut req_buffer: BytePacketBuffer,
+    src: SocketAddr,
+    transport: Transport,
+    options: Arc<ResolverOptions>,
+) -> Result<BytePacketBuffer> {
+    task::spawn_blocking(move || handle_request(&mut req_buffer, src, transport, &options))
+        .await
+        .map_err(|e| DnsError::Io(io::Error::other(e)))?
+}
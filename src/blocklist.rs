@@ -0,0 +1,314 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use log::info;
+
+use crate::{
+    dns_packet::DnsPacket,
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+    nxdomain::{negative_response, DEFAULT_NEGATIVE_TTL},
+    query_type::QueryType,
+    result_code::ResultCode,
+};
+
+/// The names found in hosts files that are there for the system itself,
+/// rather than to be blocked
+const HOSTS_NAMES: [&str; 5] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "0.0.0.0",
+];
+
+/// The category of the names that are blocked without one, see
+/// `Blocklist::category`
+pub const DEFAULT_CATEGORY: &str = "default";
+
+/// How queries for blocked names are answered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockMode {
+    /// Claim that the name doesn't exist
+    #[default]
+    Nxdomain,
+    /// Answer A queries with `0.0.0.0` and AAAA queries with `::`, which
+    /// clients fail to connect to right away. Queries of any other type get
+    /// an empty answer.
+    NullAddress,
+}
+
+impl FromStr for BlockMode {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<BlockMode> {
+        match s {
+            "nxdomain" => Ok(BlockMode::Nxdomain),
+            "null" => Ok(BlockMode::NullAddress),
+            _ => Err(DnsError::Parse(format!("Unknown block mode: {}", s))),
+        }
+    }
+}
+
+/// Names matched either exactly or along with everything below them, each
+/// with the category of the list it came from
+#[derive(Clone, Debug, Default)]
+struct Patterns {
+    names: HashMap<String, String>,
+    subdomains: HashMap<String, String>,
+}
+
+impl Patterns {
+    /// A plain name only matches itself, `*.example.com` matches what's below
+    /// `example.com` but not the name itself, and the adblock style
+    /// `||example.com^` matches both.
+    fn insert(&mut self, pattern: &str, category: &str) {
+        let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+        let category = category.to_string();
+
+        if let Some(name) = pattern.strip_prefix("*.") {
+            self.subdomains.insert(name.to_string(), category);
+        } else if let Some(name) = pattern
+            .strip_prefix("||")
+            .map(|name| name.trim_end_matches('^'))
+        {
+            self.names.insert(name.to_string(), category.clone());
+            self.subdomains.insert(name.to_string(), category);
+        } else {
+            self.names.insert(pattern, category);
+        }
+    }
+
+    /// The category of the pattern that matches `name`, the name itself
+    /// before the nearest domain above it
+    fn matches(&self, name: &str) -> Option<&str> {
+        if let Some(category) = self.names.get(name) {
+            return Some(category);
+        }
+
+        let mut parent = name;
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(category) = self.subdomains.get(rest) {
+                return Some(category);
+            }
+            parent = rest;
+        }
+
+        None
+    }
+}
+
+/// Blocks names, e.g. those of ad and tracking servers, by answering queries
+/// for them locally instead of looking them up. Names can be blocked one by
+/// one or loaded from lists in either hosts or domain list format, as
+/// published for Pi-hole and the like. The allowlist takes precedence over
+/// the blocked names, for unblocking names the lists block by mistake.
+///
+/// Lists can be put in categories of their own, such as `ads` or `malware`,
+/// for answering the names of each category differently, see `Redirect`.
+/// Everything else is in `DEFAULT_CATEGORY`.
+///
+/// Clones share their counters, so that every thread of the server counts
+/// towards the same totals.
+#[derive(Clone, Debug)]
+pub struct Blocklist {
+    blocked: Patterns,
+    allowed: Patterns,
+    pub mode: BlockMode,
+    /// How long clients may cache the answer for
+    pub ttl: u32,
+    counts: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Default for Blocklist {
+    fn default() -> Blocklist {
+        Blocklist::new()
+    }
+}
+
+impl Blocklist {
+    pub fn new() -> Blocklist {
+        Blocklist {
+            blocked: Patterns::default(),
+            allowed: Patterns::default(),
+            mode: BlockMode::default(),
+            ttl: DEFAULT_NEGATIVE_TTL,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Block a name, or a whole domain with `*.example.com` or
+    /// `||example.com^`. Names are matched case-insensitively.
+    pub fn block(&mut self, pattern: &str) {
+        self.block_category(DEFAULT_CATEGORY, pattern);
+    }
+
+    /// Block a name, or a whole domain, as part of `category`
+    pub fn block_category(&mut self, category: &str, pattern: &str) {
+        self.blocked.insert(pattern, category);
+    }
+
+    /// Never block a name, or a whole domain, no matter what the lists say
+    pub fn allow(&mut self, pattern: &str) {
+        self.allowed.insert(pattern, "");
+    }
+
+    /// Block the names in a file, returning how many there were. Each line
+    /// either holds a name, or an address followed by any number of names
+    /// as in a hosts file. Comments start with `#`, or with `!` as in
+    /// adblock lists.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        self.load_category(DEFAULT_CATEGORY, path)
+    }
+
+    /// Block the names in a file as part of `category`, see `load`
+    pub fn load_category<P: AsRef<Path>>(&mut self, category: &str, path: P) -> Result<usize> {
+        let patterns = read_list(path)?;
+        for pattern in &patterns {
+            self.block_category(category, pattern);
+        }
+
+        Ok(patterns.len())
+    }
+
+    /// Allow the names in a file, in the same format as `load`
+    pub fn load_allowlist<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let patterns = read_list(path)?;
+        for pattern in &patterns {
+            self.allow(pattern);
+        }
+
+        Ok(patterns.len())
+    }
+
+    pub fn is_blocked(&self, name: &str) -> bool {
+        self.category(name).is_some()
+    }
+
+    /// The category of the list that blocks `name`, if it's blocked
+    pub fn category(&self, name: &str) -> Option<&str> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if self.allowed.matches(&name).is_some() {
+            return None;
+        }
+
+        self.blocked.matches(&name)
+    }
+
+    /// The response to a query for `qname`, if the name is blocked
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        if !self.is_blocked(qname) {
+            return None;
+        }
+
+        info!("Blocked query for {} {:?}", qname, qtype);
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(qname.to_ascii_lowercase())
+            .or_default() += 1;
+
+        // In null mode, ANY gets the null address of both families
+        let mut records = Vec::new();
+        if self.mode == BlockMode::NullAddress {
+            if matches!(qtype, QueryType::A | QueryType::ANY) {
+                records.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: Ipv4Addr::UNSPECIFIED,
+                    ttl: self.ttl,
+                });
+            }
+            if matches!(qtype, QueryType::AAAA | QueryType::ANY) {
+                records.push(DnsRecord::AAAA {
+                    domain: qname.to_string(),
+                    addr: Ipv6Addr::UNSPECIFIED,
+                    ttl: self.ttl,
+                });
+            }
+        }
+        let rescode = match self.mode {
+            BlockMode::Nxdomain => ResultCode::NXDOMAIN,
+            BlockMode::NullAddress => ResultCode::NOERROR,
+        };
+
+        let mut packet = negative_response(qname, qtype, rescode, self.ttl);
+        if !records.is_empty() {
+            // A positive answer doesn't come with an SOA
+            packet.authorities.clear();
+            packet.answers = records;
+        }
+
+        Some(packet)
+    }
+
+    /// How many queries have been blocked in total
+    pub fn blocked_queries(&self) -> u64 {
+        self.counts.lock().unwrap().values().sum()
+    }
+
+    /// The `n` names that were blocked most often, along with how often
+    pub fn top_blocked(&self, n: usize) -> Vec<(String, u64)> {
+        let mut counts: Vec<(String, u64)> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, count)| (name.clone(), *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(n);
+
+        counts
+    }
+}
+
+/// Parse a list of names to block in a category of its own, as given on the
+/// command line, e.g. `ads=ads.txt`
+pub fn parse_category_file(s: &str) -> Result<(String, PathBuf)> {
+    match s.split_once('=') {
+        Some((category, path)) if !category.is_empty() && !path.is_empty() => {
+            Ok((category.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(DnsError::Parse(format!(
+            "Expected CATEGORY=FILE for a blocklist: {}",
+            s
+        ))),
+    }
+}
+
+/// The names, or patterns, listed in a file, see `Blocklist::load`
+fn read_list<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)?;
+    let mut patterns = Vec::new();
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() || line.starts_with('!') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace().peekable();
+        let is_hosts_entry = fields
+            .peek()
+            .is_some_and(|first| first.parse::<IpAddr>().is_ok());
+        if is_hosts_entry {
+            fields.next();
+            patterns.extend(
+                fields
+                    .filter(|name| !HOSTS_NAMES.contains(name))
+                    .map(str::to_string),
+            );
+        } else if let Some(pattern) = fields.next() {
+            patterns.push(pattern.to_string());
+        }
+    }
+
+    Ok(patterns)
+}
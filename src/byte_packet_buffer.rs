@@ -1,21 +1,68 @@
-type Error = Box<dyn std::error::Error>;
-type Result<T> = std::result::Result<T, Error>;
+use std::{borrow::Cow, collections::HashMap};
+
+use crate::error::{DnsError, Result};
+
+/// The size of a plain DNS message over UDP, without EDNS
+pub const DEFAULT_SIZE: usize = 512;
+
+/// The largest message a UDP datagram can carry. Queries are read into this
+/// much room, as a client may send anything up to it, whatever payload size
+/// we advertise.
+pub const MAX_UDP_SIZE: usize = 65535;
+
+/// Compression pointers hold 14 bits of offset, so names any further into the
+/// message can't be pointed at
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
 
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
+    /// Where the names written so far start, along with every name they end
+    /// in, for later names to point back to. `None` with compression turned
+    /// off.
+    names: Option<HashMap<String, usize>>,
+}
+
+impl Default for BytePacketBuffer {
+    fn default() -> BytePacketBuffer {
+        BytePacketBuffer::new()
+    }
 }
 
 impl BytePacketBuffer {
     /// This gives us a fresh buffer for holding the packet contents, and a
     /// field for keeping track of where we are.
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_capacity(DEFAULT_SIZE)
+    }
+
+    /// A buffer holding up to `size` bytes, for messages that may be larger
+    /// than 512 bytes, such as responses over TCP or EDNS
+    pub fn with_capacity(size: usize) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: vec![0; size],
+            pos: 0,
+            names: Some(HashMap::new()),
+        }
+    }
+
+    /// A buffer holding a copy of `data`, e.g. a message that was just
+    /// received. Reading past the end of the data is an error, rather than
+    /// reading the zeros of unused space.
+    pub fn from_slice(data: &[u8]) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: data.to_vec(),
             pos: 0,
+            names: Some(HashMap::new()),
         }
     }
 
+    /// Write every name in full from now on, e.g. for the canonical form of
+    /// records that DNSSEC signatures are made over
+    pub fn disable_compression(&mut self) {
+        self.names = None;
+    }
+
     /// Current position within buffer
     pub fn pos(&self) -> usize {
         self.pos
@@ -29,16 +76,46 @@ impl BytePacketBuffer {
     }
 
     /// Change the buffer position
-    fn seek(&mut self, steps: usize) -> Result<()> {
+    ///
+    /// Together with `pos` and `step`, this allows for navigating the buffer
+    /// when parsing parts of a packet by hand, e.g. skipping straight past the
+    /// header to read the name of the first question:
+    ///
+    /// ```
+    /// use dns_server::byte_packet_buffer::BytePacketBuffer;
+    ///
+    /// let mut buffer = BytePacketBuffer::new();
+    /// buffer.buf[12..24].copy_from_slice(b"\x06google\x03com\x00");
+    ///
+    /// buffer.seek(12).unwrap();
+    /// let mut name = String::new();
+    /// buffer.read_qname(&mut name).unwrap();
+    ///
+    /// assert_eq!(name, "google.com");
+    /// assert_eq!(buffer.pos(), 24);
+    /// ```
+    pub fn seek(&mut self, steps: usize) -> Result<()> {
         self.pos = steps;
 
         Ok(())
     }
 
+    /// Move back to `pos` to discard what was written after it, such as a
+    /// record that didn't fit. Names written after it are no longer pointed
+    /// at, since they're about to be overwritten.
+    pub fn truncate(&mut self, pos: usize) -> Result<()> {
+        self.seek(pos)?;
+        if let Some(names) = &mut self.names {
+            names.retain(|_, offset| *offset < pos);
+        }
+
+        Ok(())
+    }
+
     /// Read a single byte and move the position one step forward
     fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
-            return Err("End of buffer".into());
+        if self.pos >= self.buf.len() {
+            return Err(DnsError::EndOfBuffer);
         }
         let res = self.buf[self.pos];
         self.pos += 1;
@@ -46,20 +123,45 @@ impl BytePacketBuffer {
         Ok(res)
     }
 
+    /// Read a single byte, stepping one step forward
+    ///
+    /// This is the building block for parsing fields that don't line up with
+    /// a byte boundary, such as the flags in the preamble of a packet:
+    ///
+    /// ```
+    /// use dns_server::byte_packet_buffer::BytePacketBuffer;
+    ///
+    /// let mut buffer = BytePacketBuffer::new();
+    /// buffer.buf[..4].copy_from_slice(&[0x3d, 0xe8, 0x01, 0x20]);
+    ///
+    /// let id = buffer.read_u16().unwrap();
+    /// let flags = buffer.read_u8().unwrap();
+    /// let recursion_desired = flags & 1 > 0;
+    /// let opcode = (flags >> 3) & 0x0F;
+    ///
+    /// assert_eq!(id, 0x3de8);
+    /// assert!(recursion_desired);
+    /// assert_eq!(opcode, 0);
+    /// assert_eq!(buffer.pos(), 3);
+    /// ```
+    pub fn read_u8(&mut self) -> Result<u8> {
+        self.read()
+    }
+
     /// Get a single byte, without changing the buffer position
     fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
-            return Err("End of buffer".into());
+        if pos >= self.buf.len() {
+            return Err(DnsError::EndOfBuffer);
         }
         Ok(self.buf[pos])
     }
 
     /// Get a range of bytes
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
-            return Err("End of buffer".into());
+        if start + len > self.buf.len() {
+            return Err(DnsError::EndOfBuffer);
         }
-        Ok(&self.buf[start..start + len as usize])
+        Ok(&self.buf[start..start + len])
     }
 
     /// Read two bytes, stepping two steps forward
@@ -74,7 +176,22 @@ impl BytePacketBuffer {
         let res = ((self.read()? as u32) << 24)
             | ((self.read()? as u32) << 16)
             | ((self.read()? as u32) << 8)
-            | ((self.read()? as u32) << 0);
+            | (self.read()? as u32);
+
+        Ok(res)
+    }
+
+    /// Read eight bytes, stepping eight steps forward
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let res = ((self.read_u32()? as u64) << 32) | (self.read_u32()? as u64);
+
+        Ok(res)
+    }
+
+    /// Read a fixed number of bytes, stepping past them
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let res = self.get_range(self.pos, len)?.to_vec();
+        self.pos += len;
 
         Ok(res)
     }
@@ -96,6 +213,11 @@ impl BytePacketBuffer {
         let max_jumps = 5;
         let mut jumps_performed = 0;
 
+        // Names are limited to 255 octets on the wire, counting the length
+        // bytes of every label as well as the terminating zero length byte.
+        let max_name_len = 255;
+        let mut name_len = 0;
+
         // Our delimeter which we append for each label. Since we don't want a
         // dot at the beginning of the domain name we'll leave it empty for now
         // and set it to "." at the end of first iteration.
@@ -105,14 +227,14 @@ impl BytePacketBuffer {
             // can craft a packet with a cycle in the jump instructions. This guards
             // agains such packets.
             if jumps_performed > max_jumps {
-                return Err(format!("Limit of {} jumps exceeded", max_jumps).into());
+                return Err(DnsError::JumpLimitExceeded(max_jumps));
             }
 
             // At this point, we're always at the beginning of the label. Recall
             // that labels start with a length byte
             let len = self.get(pos)?;
 
-            // If len has two most significant bit are set, it represents a 
+            // If len has two most significant bit are set, it represents a
             // jump to some other offset in the packet:
             // two most significant bits of a single byte means 192 value in decimal
             if (len & 0xC0) == 0xC0 {
@@ -123,19 +245,26 @@ impl BytePacketBuffer {
                 }
 
                 // Read another byte, calculate offset and perform the jump by
-                // updating our local position variable.
+                // updating our local position variable. Offsets are relative to
+                // the start of the packet rather than the current section, so
+                // e.g. glue in the additional section can point straight into
+                // the NS records of the authority section.
                 let b2 = self.get(pos + 1)? as u16;
                 let offset = (((len as u16) ^ 0xC0) << 8) | b2;
+                // Pointers only ever point back at names that were already
+                // written, anything else is a crafted packet
+                if offset as usize > pos {
+                    return Err(DnsError::ForwardPointer(pos, offset as usize));
+                }
                 pos = offset as usize;
 
-
                 // Indicate that a jump was performed
                 jumped = true;
                 jumps_performed += 1;
 
                 continue;
             }
-            // the base scenario, where we're reading a single label and 
+            // the base scenario, where we're reading a single label and
             // appending it to the output
             else {
                 // Move a single byte forward to move past the length byte
@@ -147,13 +276,43 @@ impl BytePacketBuffer {
                     break;
                 }
 
+                // Labels are limited to 63 octets, which is also what leaves
+                // the two most significant bits free for the jump marker. A
+                // length byte with only one of them set is reserved.
+                if len > 0x3f {
+                    return Err(DnsError::LabelTooLong {
+                        len: len as usize,
+                        offset: pos - 1,
+                    });
+                }
+
+                // Make sure the whole label is actually present before we
+                // start appending it, rather than failing halfway through.
+                if pos + len as usize > self.buf.len() {
+                    return Err(DnsError::TruncatedLabel {
+                        len,
+                        offset: pos - 1,
+                    });
+                }
+
+                name_len += len as usize + 1;
+                if name_len + 1 > max_name_len {
+                    return Err(DnsError::NameTooLong(max_name_len));
+                }
+
                 // Append the delimeter to our output buffer first
                 outstr.push_str(delim);
 
                 // Extract the actual ASCII bytes for this label and append them
-                // to the output buffer.
+                // to the output buffer, in the case they came in. That's what
+                // nearly every label is made of, anything else is escaped
+                // where it isn't UTF-8, see `push_label`.
                 let str_buffer = self.get_range(pos, len as usize)?;
-                outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+                if str_buffer.is_ascii() && !str_buffer.contains(&b'\\') {
+                    outstr.extend(str_buffer.iter().map(|b| *b as char));
+                } else {
+                    push_label(outstr, str_buffer);
+                }
 
                 delim = ".";
 
@@ -169,9 +328,31 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    /// Read a <character-string>: a single length byte followed by that many
+    /// bytes of data, as used by X25, ISDN and friends.
+    ///
+    /// Strings that aren't UTF-8 are an error rather than being patched up
+    /// with replacement characters, which leaves the record to be read as
+    /// one of an unknown type, and passed on byte for byte.
+    pub fn read_character_string(&mut self, outstr: &mut String) -> Result<()> {
+        let start_pos = self.pos;
+        let len = self.read()? as usize;
+        let str_buffer = self.get_range(self.pos, len)?;
+        let text = std::str::from_utf8(str_buffer).map_err(|_| {
+            DnsError::InvalidRecord(format!(
+                "Character string at offset {} isn't UTF-8",
+                start_pos
+            ))
+        })?;
+        outstr.push_str(text);
+        self.step(len)?;
+
+        Ok(())
+    }
+
     pub fn write(&mut self, val: u8) -> Result<()> {
-        if self.pos >= 512 {
-            return Err("End of buffer".into());
+        if self.pos >= self.buf.len() {
+            return Err(DnsError::BufferOverflow);
         }
         self.buf[self.pos] = val;
         self.pos += 1;
@@ -196,29 +377,155 @@ impl BytePacketBuffer {
         self.write(((val >> 24) & 0xFF) as u8)?;
         self.write(((val >> 16) & 0xFF) as u8)?;
         self.write(((val >> 8) & 0xFF) as u8)?;
-        self.write(((val >> 0) & 0xFF) as u8)?;
+        self.write((val & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    pub fn write_u64(&mut self, val: u64) -> Result<()> {
+        self.write_u32((val >> 32) as u32)?;
+        self.write_u32((val & 0xFFFF_FFFF) as u32)?;
 
         Ok(())
     }
 
+    /// Write a name using the message compression of RFC 1035, section
+    /// 4.1.4: the longest suffix of the name that was written before is
+    /// replaced with a pointer to where it was written. Only owner names and
+    /// the names in the records of RFC 1035 may be compressed, see
+    /// `write_qname_uncompressed` for the rest.
     pub fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
-            let len = label.len();
-            if len > 0x3f {
-                return Err("Single label exceeds 63 characters of length".into());
-            }
+        let labels = name_labels(qname, self.pos)?;
+
+        // Every suffix of the name is a slice of it, starting at one of its
+        // labels, so that looking them up doesn't take a string each. Only
+        // names with empty labels, such as `a..b`, need putting together.
+        let trimmed = qname.trim_end_matches('.');
+        let name = if trimmed.split('.').count() == labels.len() {
+            Cow::Borrowed(trimmed)
+        } else {
+            Cow::Owned(labels.join("."))
+        };
+        let suffixes: Vec<&str> = labels
+            .iter()
+            .scan(0, |start, label| {
+                let suffix = &name[*start..];
+                *start += label.len() + 1;
+                Some(suffix)
+            })
+            .collect();
+
+        // The suffixes are tried from the longest on down, so the first one
+        // found saves the most
+        let pointer = self.names.as_ref().and_then(|names| {
+            suffixes
+                .iter()
+                .enumerate()
+                .find_map(|(i, suffix)| names.get(*suffix).map(|&offset| (i, offset)))
+        });
+        let (written_labels, pointer) = match pointer {
+            Some((i, offset)) => (&labels[..i], Some(offset)),
+            None => (&labels[..], None),
+        };
+
+        // Check that the whole name fits before writing any of it, so that a
+        // name is never cut in half by the end of the buffer.
+        let end_len = if pointer.is_some() { 2 } else { 1 };
+        let len = written_labels
+            .iter()
+            .map(|label| label_bytes(label).len() + 1)
+            .sum::<usize>()
+            + end_len;
+        if self.pos + len > self.buf.len() {
+            return Err(DnsError::BufferOverflow);
+        }
 
-            self.write_u8(len as u8)?;
-            for b in label.as_bytes() {
-                self.write_u8(*b)?;
+        for (label, suffix) in written_labels.iter().zip(&suffixes) {
+            if let Some(names) = &mut self.names {
+                if self.pos <= MAX_POINTER_OFFSET {
+                    names.insert(suffix.to_string(), self.pos);
+                }
             }
+            self.write_label(label)?;
+        }
+
+        match pointer {
+            Some(offset) => self.write_u16(0xC000 | offset as u16)?,
+            None => self.write_u8(0)?,
+        }
+
+        Ok(())
+    }
+
+    /// Write a name in full, for the names in the data of records that came
+    /// after RFC 1035, such as the target of SRV records. Those mustn't be
+    /// compressed, since servers that don't know the type can't tell where
+    /// the names are to decompress them (RFC 3597, section 4).
+    pub fn write_qname_uncompressed(&mut self, qname: &str) -> Result<()> {
+        let labels = name_labels(qname, self.pos)?;
+
+        let len: usize = labels
+            .iter()
+            .map(|label| label_bytes(label).len() + 1)
+            .sum::<usize>()
+            + 1;
+        if self.pos + len > self.buf.len() {
+            return Err(DnsError::BufferOverflow);
         }
 
+        for label in labels {
+            self.write_label(label)?;
+        }
         self.write_u8(0)?;
 
         Ok(())
     }
 
+    /// Let later names point at a name that was written by other means, such
+    /// as the question of a query, copied verbatim
+    pub fn remember_name(&mut self, qname: &str, pos: usize) {
+        let names = match &mut self.names {
+            Some(names) => names,
+            None => return,
+        };
+
+        let labels: Vec<&str> = qname.split('.').filter(|label| !label.is_empty()).collect();
+        let mut offset = pos;
+        for i in 0..labels.len() {
+            if offset > MAX_POINTER_OFFSET {
+                break;
+            }
+            names.entry(labels[i..].join(".")).or_insert(offset);
+            offset += label_bytes(labels[i]).len() + 1;
+        }
+    }
+
+    fn write_label(&mut self, label: &str) -> Result<()> {
+        let label = label_bytes(label);
+        self.write_u8(label.len() as u8)?;
+        for b in label.iter() {
+            self.write_u8(*b)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_character_string(&mut self, val: &str) -> Result<()> {
+        let len = val.len();
+        if len > 0xff {
+            return Err(DnsError::InvalidRecord(
+                "Character string exceeds 255 characters of length".to_string(),
+            ));
+        }
+
+        self.write_u8(len as u8)?;
+        for b in val.as_bytes() {
+            self.write_u8(*b)?;
+        }
+
+        Ok(())
+    }
+
     pub fn set(&mut self, pos: usize, val: u8) -> Result<()> {
         self.buf[pos] = val;
 
@@ -232,3 +539,180 @@ impl BytePacketBuffer {
         Ok(())
     }
 }
+
+/// The labels of a name to be written at `pos`. The root domain is written as
+/// just the terminating empty label, so empty labels are skipped rather than
+/// written out as extra zeros.
+fn name_labels(qname: &str, pos: usize) -> Result<Vec<&str>> {
+    let labels: Vec<&str> = qname.split('.').filter(|label| !label.is_empty()).collect();
+
+    let mut offset = pos;
+    for label in &labels {
+        let len = label_bytes(label).len();
+        if len > 0x3f {
+            return Err(DnsError::LabelTooLong { len, offset });
+        }
+        offset += len + 1;
+    }
+
+    Ok(labels)
+}
+
+/// Append a label that isn't plain ASCII to a name. UTF-8 is kept as it is,
+/// while any other bytes become `\DDD` escapes, as does the backslash
+/// itself, so that the name is written back the way it came, see
+/// `label_bytes`.
+fn push_label(outstr: &mut String, label: &[u8]) {
+    for chunk in label.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => outstr.push_str("\\092"),
+                c => outstr.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            outstr.push_str(&format!("\\{:03}", b));
+        }
+    }
+}
+
+/// The bytes of a label as they go on the wire, with the `\DDD` escapes of
+/// `push_label` undone. A backslash that isn't followed by three digits is
+/// just a backslash.
+pub(crate) fn label_bytes(label: &str) -> Cow<'_, [u8]> {
+    if !label.contains('\\') {
+        return Cow::Borrowed(label.as_bytes());
+    }
+
+    let bytes = label.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 4) {
+            Some(digits) if bytes[i] == b'\\' && digits.iter().all(u8::is_ascii_digit) => digits
+                .iter()
+                .try_fold(0u8, |value, d| value.checked_mul(10)?.checked_add(d - b'0')),
+            _ => None,
+        };
+        match escaped {
+            Some(b) => {
+                unescaped.push(b);
+                i += 4;
+            }
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    Cow::Owned(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The name at `pos` of a buffer holding `data` there
+    fn read_qname(data: &[u8], pos: usize) -> Result<String> {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buf[pos..pos + data.len()].copy_from_slice(data);
+        buffer.seek(pos)?;
+        let mut name = String::new();
+        buffer.read_qname(&mut name)?;
+        Ok(name)
+    }
+
+    #[test]
+    fn names_cut_off_are_rejected() {
+        match read_qname(b"\x03www\x07e", 505) {
+            Err(DnsError::TruncatedLabel { len, offset }) => assert_eq!((len, offset), (7, 509)),
+            result => panic!("{:?}", result),
+        }
+
+        // Cut short at any byte, by the end of the buffer
+        let name = b"\x03www\x07example\x03com\x00";
+        for len in 1..name.len() {
+            let result = read_qname(&name[..len], 512 - len);
+            assert!(
+                matches!(
+                    result,
+                    Err(DnsError::EndOfBuffer) | Err(DnsError::TruncatedLabel { .. })
+                ),
+                "{} bytes: {:?}",
+                len,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn labels_longer_than_63_bytes_are_rejected() {
+        let mut data = vec![64];
+        data.extend_from_slice(&[b'a'; 64]);
+        data.push(0);
+        assert!(matches!(
+            read_qname(&data, 0),
+            Err(DnsError::LabelTooLong { len: 64, offset: 0 })
+        ));
+
+        // The same goes for the other reserved length bytes, 0b10xxxxxx
+        assert!(matches!(
+            read_qname(&[0x80, 0], 0),
+            Err(DnsError::LabelTooLong {
+                len: 0x80,
+                offset: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn names_longer_than_255_bytes_are_rejected() {
+        let mut data = Vec::new();
+        for _ in 0..4 {
+            data.push(63);
+            data.extend_from_slice(&[b'a'; 63]);
+        }
+        data.push(0);
+        assert_eq!(
+            read_qname(&data, 0).unwrap_err().to_string(),
+            "Name exceeds 255 octets of length"
+        );
+
+        // With the zero length byte, 255 octets fit
+        data[192] = 61;
+        data.drain(193..195);
+        assert_eq!(read_qname(&data, 0).unwrap().len(), 3 * 64 + 61);
+    }
+
+    #[test]
+    fn writing_past_the_end_is_a_full_buffer() {
+        // One byte short of room for the last value
+        let mut buffer = BytePacketBuffer::new();
+        buffer.seek(509).unwrap();
+        buffer.write_u16(0x1234).unwrap();
+        let result = buffer.write_u16(0x5678);
+        assert!(matches!(result, Err(DnsError::BufferOverflow)));
+        // Which reads differently in the logs from a packet cut short
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Packet doesn't fit in the buffer"
+        );
+
+        // Names are checked for room up front, leaving the buffer as it was
+        buffer.seek(505).unwrap();
+        let result = buffer.write_qname("example");
+        assert!(matches!(result, Err(DnsError::BufferOverflow)));
+        assert_eq!(buffer.pos(), 505);
+
+        // Which is a different thing from a name that can't be written at all
+        let result = buffer.write_qname(&"a".repeat(64));
+        assert!(matches!(
+            result,
+            Err(DnsError::LabelTooLong {
+                len: 64,
+                offset: 505
+            })
+        ));
+    }
+}
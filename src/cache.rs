@@ -0,0 +1,524 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_packet::DnsPacket,
+    dns_question::DnsQuestion,
+    dns_record::DnsRecord,
+    ecs::ClientSubnet,
+    error::{DnsError, Result},
+    name,
+    query_type::QueryType,
+    result_code::ResultCode,
+};
+
+/// How often the cache is saved to its snapshot file unless configured
+/// otherwise, in seconds
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 300;
+
+/// What snapshot files start with, followed by the version of the format
+const SNAPSHOT_MAGIC: &[u8; 4] = b"DNSC";
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// The TTL of the records in stale answers, as RFC 8767 recommends, so that
+/// clients come back soon for a fresh one
+pub const STALE_TTL: u32 = 30;
+
+/// How long past expiry answers may be served stale unless configured
+/// otherwise, in seconds. RFC 8767 suggests one to three days.
+pub const DEFAULT_MAX_STALE: u32 = 86400;
+
+/// The share of its lifetime left when an entry that's looked up is
+/// refreshed ahead of time, in percent
+const PREFETCH_PERCENT: u32 = 10;
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    packet: DnsPacket,
+    /// When the entry was stored, which the TTLs of its records count down
+    /// from
+    stored: Instant,
+    expires: Instant,
+    /// How long the entry was valid for when it was stored
+    lifetime: Duration,
+    /// Whether a refresh of the entry is on its way, so that it isn't
+    /// prefetched over and over again
+    refreshing: bool,
+}
+
+impl CacheEntry {
+    fn new(packet: DnsPacket, lifetime: Duration) -> CacheEntry {
+        let now = Instant::now();
+        CacheEntry {
+            packet,
+            stored: now,
+            expires: now + lifetime,
+            lifetime,
+            refreshing: false,
+        }
+    }
+
+    /// The stored response, with the time it has spent in the cache by `now`
+    /// taken off the TTLs of its records
+    fn packet_at(&self, now: Instant) -> DnsPacket {
+        let mut packet = self.packet.clone();
+        age(
+            &mut packet,
+            now.saturating_duration_since(self.stored).as_secs() as u32,
+        );
+
+        packet
+    }
+}
+
+/// Holds on to the responses of earlier lookups for as long as their records
+/// are valid, so that repeated queries don't have to go out to the network.
+/// Responses are keyed by their question, see `DnsQuestion::cache_key`.
+#[derive(Clone, Debug, Default)]
+pub struct Cache {
+    entries: Arc<Mutex<HashMap<String, CacheEntry>>>,
+    /// Query types that are always looked up fresh, e.g. SOA when monitoring
+    /// the serial of a zone
+    pub bypass: HashSet<QueryType>,
+    /// The most responses to hold on to at once, or `None` for no limit. A
+    /// full cache makes room by dropping the expired entries first, and the
+    /// ones closest to expiring after that.
+    pub max_entries: Option<usize>,
+    /// Keep responses for at least this many seconds, even if their TTL is
+    /// lower. Responses with a TTL of 0 are never cached regardless.
+    pub min_ttl: u32,
+    /// Keep responses for at most this many seconds, even if their TTL is
+    /// higher
+    pub max_ttl: Option<u32>,
+    /// Keep negative answers for at most this many seconds, which takes
+    /// precedence over `min_ttl`
+    pub max_negative_ttl: Option<u32>,
+    /// Domains whose names are always looked up fresh, e.g. those that are
+    /// updated through dynamic DNS
+    pub no_cache: Vec<String>,
+    /// Refresh the entries that are looked up in the last tenth of their
+    /// lifetime in the background, so that popular names never expire, see
+    /// `claim_prefetch`
+    pub prefetch: bool,
+    /// Keep expired entries around for this many more seconds, to answer
+    /// with when the upstreams can't be reached, see `get_stale`
+    pub serve_stale: Option<u32>,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache::default()
+    }
+
+    /// A cache with the same settings, but with nothing in it and nothing
+    /// shared with this one
+    pub fn empty_copy(&self) -> Cache {
+        Cache {
+            entries: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the responses to a question may be cached at all, see
+    /// `bypass` and `no_cache`
+    pub fn is_cacheable(&self, qname: &str, qtype: QueryType) -> bool {
+        !self.bypass.contains(&qtype)
+            && !self
+                .no_cache
+                .iter()
+                .any(|domain| name::is_subdomain(qname, domain))
+    }
+
+    /// A previous response to the same question, if it hasn't expired yet.
+    /// The TTLs of its records are what's left of them, see `DnsRecord::age`.
+    pub fn get(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        self.get_scoped(qname, qtype, None)
+    }
+
+    /// Like `get`, for a client in `subnet`: a previous response meant for
+    /// the clients of that network, or failing that one meant for everyone.
+    /// See `insert_scoped`.
+    pub fn get_scoped(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        subnet: Option<&ClientSubnet>,
+    ) -> Option<DnsPacket> {
+        if !self.is_cacheable(qname, qtype) {
+            return None;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let key = DnsQuestion::new(qname.to_string(), qtype).cache_key();
+        let keys = subnet
+            .map(|subnet| scoped_key(&key, subnet))
+            .into_iter()
+            .chain([key]);
+
+        let now = Instant::now();
+        for key in keys {
+            match entries.get(&key) {
+                Some(entry) if entry.expires > now => {
+                    debug!("Cache hit for {} {:?}", qname, qtype);
+                    return Some(entry.packet_at(now));
+                }
+                Some(entry) if entry.expires + self.stale_window() <= now => {
+                    entries.remove(&key);
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Whether the entry for a question is due to be refreshed, i.e. it's in
+    /// the last tenth of its lifetime and no one else is refreshing it yet.
+    /// Those told so are expected to look the question up again and `insert`
+    /// the response, or to call `release_prefetch` if that fails.
+    pub fn claim_prefetch(&self, qname: &str, qtype: QueryType) -> bool {
+        if !self.prefetch {
+            return false;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let key = DnsQuestion::new(qname.to_string(), qtype).cache_key();
+        let now = Instant::now();
+        match entries.get_mut(&key) {
+            Some(entry)
+                if !entry.refreshing
+                    && entry.expires > now
+                    && entry.expires - now <= entry.lifetime * PREFETCH_PERCENT / 100 =>
+            {
+                entry.refreshing = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Let others refresh the entry for a question again, after a prefetch
+    /// didn't work out
+    pub fn release_prefetch(&self, qname: &str, qtype: QueryType) {
+        let key = DnsQuestion::new(qname.to_string(), qtype).cache_key();
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(&key) {
+            entry.refreshing = false;
+        }
+    }
+
+    /// A previous response to the same question that has expired, but not
+    /// longer ago than `serve_stale` allows, for when there's no getting a
+    /// fresh one (RFC 8767). The TTLs of its records are `STALE_TTL`.
+    pub fn get_stale(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        self.serve_stale?;
+        if !self.is_cacheable(qname, qtype) {
+            return None;
+        }
+
+        let entries = self.entries.lock().unwrap();
+        let key = DnsQuestion::new(qname.to_string(), qtype).cache_key();
+        let entry = entries
+            .get(&key)
+            .filter(|entry| entry.expires + self.stale_window() > Instant::now())?;
+
+        let mut packet = entry.packet.clone();
+        for rec in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            rec.set_ttl(STALE_TTL);
+        }
+        debug!("Serving stale answer for {} {:?}", qname, qtype);
+
+        Some(packet)
+    }
+
+    /// How long past expiry entries are kept
+    fn stale_window(&self) -> Duration {
+        Duration::from_secs(self.serve_stale.unwrap_or(0) as u64)
+    }
+
+    /// Store a response for as long as the shortest lived of its answers.
+    ///
+    /// Negative answers, i.e. `NXDOMAIN` or a successful response without any
+    /// answers, are cached as well, for as long as the SOA in their authority
+    /// section says (RFC 2308). Without an SOA there's no telling how long
+    /// they're valid, so they aren't cached at all. Neither are errors.
+    pub fn insert(&self, qname: &str, qtype: QueryType, packet: &DnsPacket) {
+        self.insert_scoped(qname, qtype, packet, None)
+    }
+
+    /// Like `insert`, for the response to a query asked on behalf of the
+    /// clients in `subnet`. Responses the upstream says depend on the network
+    /// of the client are only handed out to the clients of the same network
+    /// by `get_scoped`, and they're never served stale or prefetched.
+    pub fn insert_scoped(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        packet: &DnsPacket,
+        subnet: Option<&ClientSubnet>,
+    ) {
+        if !self.is_cacheable(qname, qtype) {
+            return;
+        }
+
+        let (ttl, negative) = match packet.header.rescode {
+            ResultCode::NOERROR if !packet.answers.is_empty() => {
+                (packet.answers.iter().map(|rec| rec.ttl()).min(), false)
+            }
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => (negative_ttl(packet), true),
+            _ => (None, false),
+        };
+        let ttl = match ttl {
+            Some(ttl) if ttl > 0 => ttl.max(self.min_ttl),
+            _ => return,
+        };
+        let ttl = self.max_ttl.map_or(ttl, |max_ttl| ttl.min(max_ttl));
+        let ttl = match self.max_negative_ttl {
+            Some(max_ttl) if negative => ttl.min(max_ttl),
+            _ => ttl,
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let key = DnsQuestion::new(qname.to_string(), qtype).cache_key();
+        let key = match subnet {
+            Some(subnet) if subnet.scope_of(packet) > 0 => scoped_key(&key, subnet),
+            _ => key,
+        };
+        self.insert_entry(
+            key,
+            CacheEntry::new(packet.clone(), Duration::from_secs(ttl as u64)),
+        );
+    }
+
+    /// Store an entry, making room for it first if the cache is full
+    fn insert_entry(&self, key: String, entry: CacheEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(max_entries) = self.max_entries {
+            let now = Instant::now();
+            if entries.len() >= max_entries && !entries.contains_key(&key) {
+                entries.retain(|_, entry| entry.expires > now);
+            }
+            while entries.len() >= max_entries && !entries.contains_key(&key) {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone());
+                match soonest {
+                    Some(soonest) => entries.remove(&soonest),
+                    // There's no room at all
+                    None => return,
+                };
+            }
+        }
+
+        entries.insert(key, entry);
+    }
+
+    /// How many responses the cache holds, including expired ones that
+    /// haven't been dropped yet
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The keys of everything in the cache that's still valid, along with how
+    /// much longer each of them is
+    pub fn entries(&self) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        let mut entries: Vec<(String, Duration)> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.expires > now)
+            .map(|(key, entry)| (key.clone(), entry.expires - now))
+            .collect();
+        entries.sort();
+
+        entries
+    }
+
+    /// Remove the entries that have expired, and are past serving stale,
+    /// which are otherwise only dropped once they're looked up again
+    pub fn purge_expired(&self) {
+        let now = Instant::now();
+        let window = self.stale_window();
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.expires + window > now);
+    }
+
+    /// Forget everything
+    pub fn flush(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Write everything in the cache that's still valid to `path`, along with
+    /// how much longer each entry is, so that a restarted server can pick up
+    /// where this one left off with `load`. Returns the number of entries
+    /// written.
+    ///
+    /// The responses are kept in their wire format, with what's left of their
+    /// TTLs, each preceded by its key and the seconds it has left. The file
+    /// is written next to `path` first and then moved over it, so that it's
+    /// never left half written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        let path = path.as_ref();
+        let now = Instant::now();
+        let entries: Vec<(String, CacheEntry)> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.expires > now)
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect();
+
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.push(SNAPSHOT_VERSION);
+        data.extend_from_slice(&unix_time().to_be_bytes());
+
+        let mut count = 0;
+        for (key, entry) in entries {
+            let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+            if entry.packet_at(now).write(&mut buffer).is_err() || key.len() > u16::MAX as usize {
+                continue;
+            }
+            // Whatever is left of the last second counts as a full one
+            let remaining = (entry.expires - now).as_secs_f64().ceil() as u32;
+
+            data.extend_from_slice(&(key.len() as u16).to_be_bytes());
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(&remaining.to_be_bytes());
+            data.extend_from_slice(&(buffer.pos() as u16).to_be_bytes());
+            data.extend_from_slice(&buffer.buf[..buffer.pos()]);
+            count += 1;
+        }
+
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, &data)?;
+        fs::rename(&temp, path)?;
+
+        Ok(count)
+    }
+
+    /// Fill the cache with the entries saved to `path` by `save`, taking off
+    /// the time that passed since. The entries that expired in the meantime
+    /// are left out, and so are the ones of types that aren't cached. A file
+    /// that doesn't exist yet holds no entries. Returns the number of entries
+    /// loaded.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        let path = path.as_ref();
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut buffer = BytePacketBuffer::from_slice(&data);
+        let invalid = || {
+            DnsError::Parse(format!(
+                "{} isn't a cache snapshot of version {}",
+                path.display(),
+                SNAPSHOT_VERSION
+            ))
+        };
+        if buffer.read_bytes(SNAPSHOT_MAGIC.len()).ok().as_deref() != Some(SNAPSHOT_MAGIC)
+            || buffer.read_u8().ok() != Some(SNAPSHOT_VERSION)
+        {
+            return Err(invalid());
+        }
+        let elapsed = unix_time().saturating_sub(buffer.read_u64()?);
+
+        let mut count = 0;
+        while buffer.pos() < data.len() {
+            let len = buffer.read_u16()? as usize;
+            let key = String::from_utf8(buffer.read_bytes(len)?).map_err(|_| invalid())?;
+            let remaining = (buffer.read_u32()? as u64).saturating_sub(elapsed);
+            let len = buffer.read_u16()? as usize;
+            let packet = buffer.read_bytes(len)?;
+
+            let mut parts = key.split('|');
+            let (qname, qtype) = (parts.next(), parts.next());
+            let cacheable = match (qname, qtype.and_then(|qtype| qtype.parse().ok())) {
+                (Some(qname), Some(qtype)) => self.is_cacheable(qname, QueryType::from_num(qtype)),
+                _ => false,
+            };
+            if remaining == 0 || !cacheable {
+                continue;
+            }
+
+            let mut packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&packet))?;
+            age(&mut packet, elapsed as u32);
+            self.insert_entry(key, CacheEntry::new(packet, Duration::from_secs(remaining)));
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Save the cache to `path` every `interval` for good, see `save`
+    pub fn save_every(&self, path: PathBuf, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            match self.save(&path) {
+                Ok(count) => debug!("Saved {} cache entries to {}", count, path.display()),
+                Err(e) => warn!("Failed to save the cache to {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// The key of the response to a question that's meant for the clients of a
+/// network, e.g. `www.example.com|1|1|192.0.2.0/24`
+fn scoped_key(key: &str, subnet: &ClientSubnet) -> String {
+    format!("{}|{}", key, subnet)
+}
+
+/// Take the seconds a response spent in the cache off the TTLs of its records
+fn age(packet: &mut DnsPacket, elapsed: u32) {
+    for rec in packet
+        .answers
+        .iter_mut()
+        .chain(packet.authorities.iter_mut())
+        .chain(packet.resources.iter_mut())
+    {
+        rec.age(elapsed);
+    }
+}
+
+/// How long a negative answer may be cached: the lower of the TTL of the SOA
+/// record and its minimum field
+fn negative_ttl(packet: &DnsPacket) -> Option<u32> {
+    packet.authorities.iter().find_map(|rec| match rec {
+        DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+        _ => None,
+    })
+}
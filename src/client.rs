@@ -0,0 +1,544 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use rand::{rngs::OsRng, Rng};
+
+use crate::{
+    byte_packet_buffer::{self, BytePacketBuffer},
+    dns_packet::{DnsPacket, QueryBuilder},
+    dns_record::DnsRecord,
+    ecs::ClientSubnet,
+    error::{DnsError, Result},
+    query_type::QueryType,
+    result_code::ResultCode,
+};
+
+/// How long to wait for a response from another server before sending the
+/// query again, unless configured otherwise. Without a timeout an unreachable
+/// server would block the lookup forever.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many times a query over UDP is sent again when it goes unanswered,
+/// unless configured otherwise
+pub const DEFAULT_RETRIES: u8 = 2;
+
+/// The UDP payload size advertised through EDNS unless configured otherwise.
+/// It's the size recommended by DNS Flag Day 2020, which is small enough to
+/// avoid IP fragmentation on just about any network.
+pub const DEFAULT_PAYLOAD_SIZE: u16 = 1232;
+
+/// How queries are sent to other servers
+#[derive(Clone, Copy, Debug)]
+pub struct QueryOptions {
+    /// Randomize the case of the letters of the question we send, and reject
+    /// responses that don't echo it exactly, see `query`
+    pub verify_case: bool,
+    /// The UDP payload size to advertise through EDNS, or `None` to send plain
+    /// queries without an OPT record
+    pub payload_size: Option<u16>,
+    /// Set the DO bit, asking for the DNSSEC records that go with the answer.
+    /// It's carried in the OPT record, so it requires EDNS.
+    pub dnssec_ok: bool,
+    /// The network of the client the query is asked on behalf of, passed on
+    /// in the OPT record, see `ecs`. It requires EDNS as well.
+    pub client_subnet: Option<ClientSubnet>,
+    /// Try the servers with addresses of this family first. Without a
+    /// preference, upstreams are tried in the order they were configured in,
+    /// and name servers are reached over IPv4 first.
+    pub prefer_family: Option<AddressFamily>,
+    /// How long to wait for a response, or for a connection to be set up
+    pub timeout: Duration,
+    /// How many times a query over UDP is sent again when no response arrives
+    /// within the timeout, before giving up with `DnsError::Timeout`. The
+    /// stream transports are reliable, so queries over them are sent once.
+    pub retries: u8,
+}
+
+impl Default for QueryOptions {
+    fn default() -> QueryOptions {
+        QueryOptions {
+            verify_case: false,
+            payload_size: Some(DEFAULT_PAYLOAD_SIZE),
+            dnssec_ok: false,
+            client_subnet: None,
+            prefer_family: None,
+            timeout: QUERY_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+}
+
+/// Either version of IP, for picking which one to reach a server over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn of(ip: IpAddr) -> AddressFamily {
+        match ip {
+            IpAddr::V4(_) => AddressFamily::Ipv4,
+            IpAddr::V6(_) => AddressFamily::Ipv6,
+        }
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<AddressFamily> {
+        match s {
+            "ipv4" => Ok(AddressFamily::Ipv4),
+            "ipv6" => Ok(AddressFamily::Ipv6),
+            _ => Err(DnsError::Parse(format!("Unknown address family: {}", s))),
+        }
+    }
+}
+
+/// Move the items with an address of `family` to the front, keeping them in
+/// order otherwise
+pub fn prefer_family<T>(items: &mut [T], family: AddressFamily, ip: impl Fn(&T) -> IpAddr) {
+    items.sort_by_key(|item| AddressFamily::of(ip(item)) != family);
+}
+
+/// Send a single query to `server` and return the full response packet.
+///
+/// The query goes out over UDP first. If the response is truncated because it
+/// didn't fit in a datagram, the identical query is sent again over TCP and
+/// that response is used instead.
+///
+/// Every query gets a random ID and is sent from a port of its own, and only
+/// the response that matches the ID and question of the query is accepted.
+/// Anything else arriving in the meantime is ignored, since it may well come
+/// from someone trying to get a spoofed response in first.
+///
+/// With `verify_case` set, the letters of the name are sent in a random mix of
+/// upper and lower case, and responses that don't echo the exact case of the
+/// question are rejected as well (the "0x20" encoding). Servers copy the
+/// question into their response as it is, while someone spoofing responses
+/// without seeing our query would have to guess a bit per letter on top of
+/// the ID and the port.
+pub fn query(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket> {
+    query_with_tcp(qname, qtype, server, options, |req_buffer| {
+        send_tcp(req_buffer, server, options)
+    })
+}
+
+/// Like `query`, with the query sent over TCP by `send_tcp`, such as over a
+/// connection that's kept open for other queries
+pub(crate) fn query_with_tcp(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    options: &QueryOptions,
+    send_tcp: impl FnOnce(&BytePacketBuffer) -> Result<BytePacketBuffer>,
+) -> Result<DnsPacket> {
+    let (packet, req_buffer) = build_query(qname, qtype, options)?;
+
+    debug!(
+        "Sending query {} for {} {:?} to {}",
+        packet.header.id, qname, qtype, server
+    );
+
+    let mut response = send_udp(&packet, &req_buffer, server, options)?;
+
+    if response.header.truncated_message {
+        warn!(
+            "Response from {} for {} {:?} was truncated, retrying over TCP",
+            server, qname, qtype
+        );
+
+        let mut res_buffer = send_tcp(&req_buffer)?;
+        response = check_response(&packet, &mut res_buffer, options)?;
+    }
+
+    match response.header.rescode {
+        ResultCode::NOERROR | ResultCode::NXDOMAIN => {}
+        rescode => warn!(
+            "Received {:?} from {} for {} {:?}",
+            rescode, server, qname, qtype
+        ),
+    }
+
+    if !response.answers.is_empty() {
+        info!(
+            "Received {} answers from {} for {} {:?}",
+            response.answers.len(),
+            server,
+            qname,
+            qtype
+        );
+    }
+
+    Ok(response)
+}
+
+/// Put together the query for a question, along with its wire format
+pub(crate) fn build_query(
+    qname: &str,
+    qtype: QueryType,
+    options: &QueryOptions,
+) -> Result<(DnsPacket, BytePacketBuffer)> {
+    let qname = match options.verify_case {
+        true => randomize_case(qname),
+        false => qname.to_string(),
+    };
+    let mut builder = QueryBuilder::new().question(&qname, qtype);
+    if let Some(payload_size) = options.payload_size {
+        builder = builder.edns(payload_size);
+        if options.dnssec_ok {
+            builder = builder.dnssec_ok();
+        }
+        if let Some(subnet) = options.client_subnet {
+            builder = builder.client_subnet(subnet);
+        }
+    }
+    let mut packet = builder.build();
+
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    Ok((packet, req_buffer))
+}
+
+/// The name with each of its letters in upper or lower case at random, see
+/// `verify_case`. Like the query ID, the case comes from the random generator
+/// of the OS.
+pub fn randomize_case(name: &str) -> String {
+    name.chars()
+        .map(|c| match OsRng.gen() {
+            true => c.to_ascii_uppercase(),
+            false => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// The largest UDP response to expect, which can't be larger than what we
+/// said we could handle
+pub(crate) fn max_response_size(options: &QueryOptions) -> usize {
+    options
+        .payload_size
+        .map_or(byte_packet_buffer::DEFAULT_SIZE, |size| {
+            (size as usize).max(byte_packet_buffer::DEFAULT_SIZE)
+        })
+}
+
+fn send_udp(
+    packet: &DnsPacket,
+    req_buffer: &BytePacketBuffer,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket> {
+    // Every query gets its own ephemeral port, so that queries running
+    // concurrently never see each others responses. Connecting the socket
+    // leaves it to the kernel to drop datagrams from anyone but the server.
+    let socket = UdpSocket::bind(unspecified_addr(server))?;
+    socket.connect(server)?;
+
+    // Datagrams get lost, so a query that goes unanswered is sent again. It
+    // keeps its ID, which makes a late response to an earlier attempt just as
+    // good as one to the latest.
+    let mut buf = vec![0; max_response_size(options)];
+    for attempt in 0..=options.retries {
+        if attempt > 0 {
+            debug!(
+                "No response from {} within {:?}, sending query {} again",
+                server, options.timeout, packet.header.id
+            );
+        }
+        socket.send(&req_buffer.buf[0..req_buffer.pos])?;
+
+        let deadline = Instant::now() + options.timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(timeout))?;
+
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let mut res_buffer = BytePacketBuffer::from_slice(&buf[..len]);
+            match check_response(packet, &mut res_buffer, options) {
+                Ok(response) => return Ok(response),
+                // The real response may still be on its way
+                Err(e) => warn!("Ignoring response from {}: {}", server, e),
+            }
+        }
+    }
+
+    Err(DnsError::Timeout)
+}
+
+/// The address to bind a socket for talking to `server` to, which lets the
+/// system pick the port
+pub(crate) fn unspecified_addr(server: SocketAddr) -> SocketAddr {
+    match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+fn send_tcp(
+    req_buffer: &BytePacketBuffer,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<BytePacketBuffer> {
+    let mut stream = TcpStream::connect_timeout(&server, options.timeout)?;
+    stream.set_read_timeout(Some(options.timeout))?;
+
+    exchange(&mut stream, req_buffer)
+}
+
+/// Send a query over a stream and read back the response. Over TCP, and TLS
+/// on top of it, every message is prefixed with its length as a two byte
+/// integer, since there are no datagram boundaries to tell where a message
+/// ends.
+pub(crate) fn exchange<S: Read + Write>(
+    stream: &mut S,
+    req_buffer: &BytePacketBuffer,
+) -> Result<BytePacketBuffer> {
+    write_message(stream, req_buffer)?;
+    read_message(stream)
+}
+
+/// Send a single message over a stream, prefixed with its length
+pub(crate) fn write_message<S: Write>(stream: &mut S, buffer: &BytePacketBuffer) -> Result<()> {
+    let len = buffer.pos() as u16;
+    let mut message = len.to_be_bytes().to_vec();
+    message.extend_from_slice(&buffer.buf[0..buffer.pos]);
+    stream.write_all(&message)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Read the next message off a stream
+pub(crate) fn read_message<S: Read>(stream: &mut S) -> Result<BytePacketBuffer> {
+    let mut len = [0; 2];
+    stream
+        .read_exact(&mut len)
+        .map_err(DnsError::from_timed_read)?;
+    let len = u16::from_be_bytes(len) as usize;
+
+    let mut buf = vec![0; len];
+    stream
+        .read_exact(&mut buf)
+        .map_err(DnsError::from_timed_read)?;
+
+    Ok(BytePacketBuffer::from_slice(&buf))
+}
+
+/// Parse a response and make sure that it actually answers our query
+pub(crate) fn check_response(
+    packet: &DnsPacket,
+    res_buffer: &mut BytePacketBuffer,
+    options: &QueryOptions,
+) -> Result<DnsPacket> {
+    let response = DnsPacket::from_buffer(res_buffer)?;
+
+    // Anything that doesn't carry the ID of our query isn't a response to it.
+    if response.header.id != packet.header.id {
+        return Err(DnsError::InvalidResponse(format!(
+            "Response ID {} doesn't match query ID {}",
+            response.header.id, packet.header.id
+        )));
+    }
+
+    // Nor is anything that answers a different question. Names keep their
+    // case when they're read, so with `verify_case` it's checked along with
+    // the rest.
+    let question = &packet.questions[0];
+    match response.questions.first() {
+        Some(q) if options.verify_case && q.name != question.name && q == question => {
+            return Err(DnsError::InvalidResponse(format!(
+                "Response for {} doesn't match the case of the query",
+                question.name
+            )))
+        }
+        Some(q) if q == question => {}
+        Some(q) => {
+            return Err(DnsError::InvalidResponse(format!(
+                "Response question {} {:?} doesn't match query {} {:?}",
+                q.name, q.qtype, question.name, question.qtype
+            )))
+        }
+        None => {
+            return Err(DnsError::InvalidResponse(format!(
+                "Response for {} {:?} is missing the question",
+                question.name, question.qtype
+            )))
+        }
+    }
+
+    Ok(response)
+}
+
+/// Look up the records of a certain type for a name. This wraps the whole
+/// flow of building the query, sending it, and picking the records we asked
+/// for out of the answer section of the response. For the common types,
+/// `lookup_a`, `lookup_mx` and the like pick the data out of the records as
+/// well.
+pub fn lookup(name: &str, qtype: QueryType, server: SocketAddr) -> Result<Vec<DnsRecord>> {
+    let response = query(name, qtype, server, &QueryOptions::default())?;
+
+    Ok(response
+        .answers
+        .into_iter()
+        .filter(|record| record.query_type() == qtype)
+        .collect())
+}
+
+/// Look up the IPv4 addresses of a name
+pub fn lookup_a(name: &str, server: SocketAddr) -> Result<Vec<Ipv4Addr>> {
+    let records = lookup(name, QueryType::A, server)?;
+
+    Ok(records
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecord::A { addr, .. } => Some(addr),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Look up the IPv6 addresses of a name
+pub fn lookup_aaaa(name: &str, server: SocketAddr) -> Result<Vec<Ipv6Addr>> {
+    let records = lookup(name, QueryType::AAAA, server)?;
+
+    Ok(records
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecord::AAAA { addr, .. } => Some(addr),
+            _ => None,
+        })
+        .collect())
+}
+
+/// A mail server of a domain, see `lookup_mx`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MxRecord {
+    /// Lower values are tried first
+    pub priority: u16,
+    pub host: String,
+}
+
+/// Look up the mail servers of a domain, in the order they're meant to be
+/// tried in, i.e. lowest priority value first
+pub fn lookup_mx(name: &str, server: SocketAddr) -> Result<Vec<MxRecord>> {
+    let records = lookup(name, QueryType::MX, server)?;
+
+    let mut servers: Vec<MxRecord> = records
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecord::MX { priority, host, .. } => Some(MxRecord { priority, host }),
+            _ => None,
+        })
+        .collect();
+    servers.sort_by_key(|mx| mx.priority);
+
+    Ok(servers)
+}
+
+/// Look up the TXT records of a name. The strings each record is made up of
+/// are joined together, which is how SPF policies and DKIM keys too long
+/// for a single string are meant to be read (RFC 7208).
+pub fn lookup_txt(name: &str, server: SocketAddr) -> Result<Vec<String>> {
+    let records = lookup(name, QueryType::TXT, server)?;
+
+    Ok(records
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecord::TXT { data, .. } => Some(data.concat()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// A server offering a service, see `lookup_srv`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Lower values are tried first
+    pub priority: u16,
+    /// How much of the load servers of the same priority should get relative
+    /// to each other
+    pub weight: u16,
+    pub port: u16,
+    pub host: String,
+}
+
+/// Look up the servers of a service, e.g. `_imaps._tcp.example.com`, lowest
+/// priority value first
+pub fn lookup_srv(name: &str, server: SocketAddr) -> Result<Vec<SrvRecord>> {
+    let records = lookup(name, QueryType::SRV, server)?;
+
+    let mut servers: Vec<SrvRecord> = records
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                host,
+                ..
+            } => Some(SrvRecord {
+                priority,
+                weight,
+                port,
+                host,
+            }),
+            _ => None,
+        })
+        .collect();
+    servers.sort_by_key(|srv| srv.priority);
+
+    Ok(servers)
+}
+
+/// The name the PTR records of an address live under: the octets of an IPv4
+/// address in reverse under `in-addr.arpa`, e.g. `4.3.2.1.in-addr.arpa` for
+/// `1.2.3.4`, and the nibbles of an IPv6 address in reverse under `ip6.arpa`
+/// (RFC 3596), all 32 of them with no zeros left out.
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::with_capacity(72);
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0x0F, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// Look up the host names of an address through its PTR records
+pub fn reverse_lookup(ip: IpAddr, server: SocketAddr) -> Result<Vec<String>> {
+    let records = lookup(&reverse_name(ip), QueryType::PTR, server)?;
+
+    Ok(records
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecord::PTR { host, .. } => Some(host),
+            _ => None,
+        })
+        .collect())
+}
@@ -0,0 +1,812 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    blocklist::Blocklist,
+    cache::{self, Cache},
+    dns64::Dns64,
+    dns_record::unbase64,
+    ecs::ClientSubnetPolicy,
+    error::{DnsError, Result},
+    forwarder::Forwarder,
+    health::HealthChecks,
+    hosts::Hosts,
+    mdns::{self, MdnsResolver, Responder},
+    nxdomain::NxdomainList,
+    query_type::QueryType,
+    rate_limit::RateLimiter,
+    redirect::Redirect,
+    resolver::ResolverOptions,
+    rotation::{AnswerRotation, RotationMode},
+    secondary::Secondary,
+    shuffle::AnswerShuffler,
+    tsig::{Algorithm, TsigKey},
+    ttl::TtlPolicy,
+    update::DynamicZone,
+    view::{Subnet, View},
+    zone::Zone,
+};
+
+#[cfg(feature = "dnssec")]
+use crate::dnssec::Validator;
+
+/// The settings of the server, as loaded from a TOML file. Every setting is
+/// optional and falls back to the same default as the command line, e.g.
+///
+/// ```toml
+/// log_level = "info"
+/// zones = ["example.com.zone"]
+/// secondaries = ["example.net@192.0.2.1"]
+///
+/// [server]
+/// listen = ["0.0.0.0:53", "[::]:53"]
+/// metrics_listen = "127.0.0.1:9153"
+/// user = "dns"
+/// pid_file = "/run/dns-server.pid"
+/// log_target = "syslog"
+///
+/// [upstream]
+/// servers = ["tls://1.1.1.1#cloudflare-dns.com", "8.8.8.8"]
+/// policy = "fastest"
+/// domains = { "corp.example" = ["10.0.0.53"] }
+///
+/// [cache]
+/// max_entries = 10000
+/// max_ttl = 86400
+/// file = "cache.bin"
+/// prefetch = true
+/// serve_stale = true
+/// no_cache = ["dyn.example.net"]
+///
+/// [ttl]
+/// min = 60
+/// max = 86400
+/// max_negative = 300
+///
+/// [client_subnet]
+/// ipv4_prefix = 24
+/// ipv6_prefix = 56
+///
+/// [blocklist]
+/// files = ["hosts.txt"]
+/// categories = { malware = ["malware.txt"] }
+/// allow = ["*.example.net"]
+/// mode = "null"
+///
+/// [redirect]
+/// nxdomain = ["192.168.1.80"]
+/// blocked = { malware = ["192.168.1.81"] }
+/// ttl = 30
+///
+/// [hosts]
+/// files = ["/etc/hosts"]
+/// names = { "nas.lan" = ["192.168.1.10"], "*.dev.lan" = ["127.0.0.1", "::1"] }
+///
+/// [mdns]
+/// resolve = true
+/// names = { "printer.local" = ["192.168.1.20"] }
+///
+/// [rate_limit]
+/// responses_per_second = 20
+///
+/// [rotation]
+/// mode = "weighted"
+/// weights = { "192.168.1.10" = 3, "192.168.1.11" = 1 }
+///
+/// [health]
+/// checks = { "192.168.1.10" = "http:8080/healthz", "192.168.1.11" = "tcp:22" }
+///
+/// [dnssec]
+///
+/// [[views]]
+/// name = "internal"
+/// clients = ["192.168.0.0/16", "fd00::/8"]
+/// zones = ["internal/example.com.zone"]
+///
+/// [[dynamic_zones]]
+/// file = "lan.zone"
+/// update_keys = ["dhcp-key"]
+/// # Insecure: takes unsigned updates from anyone who can send packets from
+/// # these addresses, spoofed or not
+/// allow_update = ["192.168.1.2"]
+/// allow_unsigned_updates = true
+///
+/// [[tsig_keys]]
+/// name = "dhcp-key"
+/// algorithm = "hmac-sha256"
+/// secret = "c2VjcmV0IHNoYXJlZCB3aXRoIHRoZSBESENQIHNlcnZlcg=="
+/// ```
+///
+/// The cache, the bounds on TTLs, passing on client subnets, the blocklist,
+/// the list of NXDOMAIN names, redirects, the hosts files, rate limiting,
+/// answer rotation, health checks and DNSSEC validation are only enabled
+/// when their section is present, and the mDNS responder when it has any names. Relative
+/// paths are taken relative to the directory the file is in.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The level to log at unless `RUST_LOG` says otherwise, e.g. `debug`
+    pub log_level: Option<String>,
+    pub server: ServerConfig,
+    pub upstream: UpstreamConfig,
+    pub cache: Option<CacheConfig>,
+    pub ttl: Option<TtlConfig>,
+    pub client_subnet: Option<ClientSubnetConfig>,
+    pub nxdomain: Option<NxdomainConfig>,
+    pub blocklist: Option<BlocklistConfig>,
+    pub redirect: Option<RedirectConfig>,
+    pub hosts: Option<HostsConfig>,
+    pub mdns: Option<MdnsConfig>,
+    pub dnssec: Option<DnssecConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub rotation: Option<RotationConfig>,
+    pub health: Option<HealthConfig>,
+    /// Zone files to serve authoritatively
+    pub zones: Vec<PathBuf>,
+    /// Zones to serve as a secondary, in the format of `Secondary::from_str`
+    pub secondaries: Vec<String>,
+    /// The NAT64 prefix to synthesize AAAA records with, e.g. `64:ff9b::/96`
+    pub dns64: Option<String>,
+    /// Clients that are answered differently from the rest, checked in order
+    pub views: Vec<ViewConfig>,
+    /// Zones that clients may make changes to with UPDATE messages
+    pub dynamic_zones: Vec<DynamicZoneConfig>,
+    /// The keys to sign and verify messages with, which take a build with
+    /// the `tsig` feature
+    pub tsig_keys: Vec<TsigKeyConfig>,
+}
+
+/// Where and how queries are served
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The addresses to serve on, over both UDP and TCP
+    pub listen: Vec<SocketAddr>,
+    /// The number of threads answering UDP queries on each address
+    pub workers: Option<usize>,
+    /// Bind with `SO_REUSEPORT`, see `--reuse-port`
+    pub reuse_port: bool,
+    pub preserve_question: bool,
+    pub minimal_responses: bool,
+    pub shuffle_answers: bool,
+    /// Makes the shuffled order reproducible, implies `shuffle_answers`
+    pub shuffle_seed: Option<u64>,
+    /// The address to serve DNS over HTTPS on, along with the certificate
+    /// chain and private key to serve it with, in PEM format
+    pub doh_listen: Option<SocketAddr>,
+    pub doh_cert: Option<PathBuf>,
+    pub doh_key: Option<PathBuf>,
+    /// The same for DNS over QUIC, which is served over UDP
+    pub doq_listen: Option<SocketAddr>,
+    pub doq_cert: Option<PathBuf>,
+    pub doq_key: Option<PathBuf>,
+    /// The file to log every query to, or `-` for standard output
+    pub query_log: Option<PathBuf>,
+    /// Either `json` or `text`
+    pub query_log_format: Option<String>,
+    /// The address to serve Prometheus metrics on, over plain HTTP, along
+    /// with the statistics that `dns-server stats` shows
+    pub metrics_listen: Option<SocketAddr>,
+    /// Detach from the terminal and carry on in the background
+    pub daemon: bool,
+    /// The file to write the ID of the process to
+    pub pid_file: Option<PathBuf>,
+    /// The user and group to switch to once the addresses are bound. The
+    /// directories of `pid_file` and the cache file have to be writable by
+    /// the user for those to be removed and saved.
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Either `stderr`, `syslog` or `journald`
+    pub log_target: Option<String>,
+}
+
+/// Where queries are forwarded to, if anywhere
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// The upstreams in the format of `Forwarder::parse_upstream`. Queries are
+    /// resolved recursively when there aren't any.
+    pub servers: Vec<String>,
+    /// Either `in-order`, `round-robin` or `fastest`
+    pub policy: Option<String>,
+    /// Either `ipv4` or `ipv6`, to reach the upstreams and name servers over
+    /// that version of IP first
+    pub prefer_family: Option<String>,
+    /// Randomize the case of the names that are sent, and reject responses
+    /// that don't echo it exactly
+    pub verify_case: bool,
+    /// Resolving recursively, only ask the name servers along the way about
+    /// as much of the names as they need to know
+    pub qname_minimization: bool,
+    pub edns_payload_size: Option<u16>,
+    pub no_edns: bool,
+    /// How long to wait for a response before sending the query again
+    pub timeout_ms: Option<u64>,
+    /// How many times a query over UDP is sent again when unanswered
+    pub retries: Option<u8>,
+    /// Upstreams for the names in particular domains, in place of `servers`,
+    /// e.g. `{ "corp.example" = ["10.0.0.53"] }`
+    pub domains: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub max_entries: Option<usize>,
+    /// The bounds on how long responses are cached for, in seconds,
+    /// regardless of their TTLs
+    pub min_ttl: u32,
+    pub max_ttl: Option<u32>,
+    /// The bound on how long negative answers are cached for, in seconds
+    pub max_negative_ttl: Option<u32>,
+    /// Query types that are never cached, e.g. `["SOA"]`
+    pub bypass: Vec<String>,
+    /// Domains whose names are never cached, e.g. `["dyn.example.net"]`
+    pub no_cache: Vec<String>,
+    /// The file to start out with the cache saved to, and to save it to every
+    /// `save_interval` seconds
+    pub file: Option<PathBuf>,
+    pub save_interval: Option<u64>,
+    /// Refresh the entries that are looked up shortly before they expire
+    pub prefetch: bool,
+    /// Answer from entries that expired up to `max_stale` seconds ago when
+    /// the upstreams can't be reached
+    pub serve_stale: bool,
+    pub max_stale: Option<u32>,
+}
+
+/// See `TtlPolicy`, in seconds
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TtlConfig {
+    pub min: u32,
+    pub max: Option<u32>,
+    pub max_negative: Option<u32>,
+}
+
+/// See `ClientSubnetPolicy`. The prefixes are how many bits of the addresses
+/// of clients are passed on, 24 and 56 unless given.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSubnetConfig {
+    pub ipv4_prefix: Option<u8>,
+    pub ipv6_prefix: Option<u8>,
+}
+
+/// See `View`. Anything a view doesn't set is the same as for everyone else.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ViewConfig {
+    pub name: String,
+    /// The subnets of the clients, e.g. `["10.0.0.0/8"]`
+    pub clients: Vec<String>,
+    /// Zone files served in place of the global `zones`
+    pub zones: Vec<PathBuf>,
+    /// Upstreams forwarded to in place of those of `[upstream]`
+    pub upstreams: Vec<String>,
+}
+
+/// See `DynamicZone`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DynamicZoneConfig {
+    /// The zone file, which is written over with every change
+    pub file: PathBuf,
+    /// The names of the TSIG keys that changes have to be signed with
+    pub update_keys: Vec<String>,
+    /// The subnets of the clients that may make changes without signing
+    /// them, e.g. `["10.0.0.2"]`, with `allow_unsigned_updates`
+    pub allow_update: Vec<String>,
+    /// Take unsigned changes from the clients of `allow_update`. Anyone who
+    /// can send packets with one of their addresses can make changes then,
+    /// so it's insecure, and off unless turned on.
+    pub allow_unsigned_updates: bool,
+}
+
+/// See `TsigKey`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TsigKeyConfig {
+    pub name: String,
+    /// One of `hmac-sha1`, `hmac-sha256` (the default), `hmac-sha384` and
+    /// `hmac-sha512`
+    pub algorithm: Option<String>,
+    /// In base64
+    pub secret: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NxdomainConfig {
+    pub names: Vec<String>,
+    pub ttl: Option<u32>,
+}
+
+/// See `Blocklist` for the format of the names and files
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocklistConfig {
+    /// Lists of names to block, in hosts or domain list format
+    pub files: Vec<PathBuf>,
+    /// Lists of names to block by the category they're in, which
+    /// `[redirect]` can send elsewhere than the rest
+    pub categories: BTreeMap<String, Vec<PathBuf>>,
+    pub names: Vec<String>,
+    /// Lists of names to never block, in the same format
+    pub allow_files: Vec<PathBuf>,
+    pub allow: Vec<String>,
+    /// Either `nxdomain` or `null`
+    pub mode: Option<String>,
+    pub ttl: Option<u32>,
+}
+
+/// See `Redirect`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    /// The addresses to answer with for names that don't exist
+    pub nxdomain: Vec<IpAddr>,
+    /// The addresses to answer with for blocked names, by the category of
+    /// their list, or `*` for every category
+    pub blocked: BTreeMap<String, Vec<IpAddr>>,
+    pub ttl: Option<u32>,
+}
+
+/// See `AnswerRotation`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RotationConfig {
+    /// Either `round-robin` (the default), `shuffle` or `weighted`
+    pub mode: Option<String>,
+    /// The weights of addresses for the `weighted` mode, 1 unless given
+    pub weights: BTreeMap<IpAddr, u32>,
+    /// Makes the random orders reproducible
+    pub seed: Option<u64>,
+}
+
+/// See `HealthChecks`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// The probe for each address, in the format of `Probe::from_str`
+    pub checks: BTreeMap<IpAddr, String>,
+    /// How often the addresses are probed, in seconds
+    pub interval: Option<u64>,
+    pub timeout_ms: Option<u64>,
+}
+
+/// See `Hosts` for the format of the names and files
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostsConfig {
+    /// Files in `/etc/hosts` format
+    pub files: Vec<PathBuf>,
+    /// Addresses for names on top of those of the files
+    pub names: BTreeMap<String, Vec<IpAddr>>,
+    pub ttl: Option<u32>,
+}
+
+/// Multicast DNS on the local link, for resolving `.local` names and for
+/// answering the queries of others for a set of names
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MdnsConfig {
+    /// Resolve `.local` names over mDNS rather than sending them upstream
+    pub resolve: bool,
+    /// How long to wait for a host to answer, in milliseconds
+    pub timeout_ms: Option<u64>,
+    /// The names to answer for, in the same format as `[hosts]`
+    pub files: Vec<PathBuf>,
+    pub names: BTreeMap<String, Vec<IpAddr>>,
+    pub ttl: Option<u32>,
+    /// The address of the interface to answer on, the default one otherwise
+    pub interface: Option<Ipv4Addr>,
+}
+
+/// Validating DNSSEC signatures, which takes a build with the `dnssec` feature
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnssecConfig {
+    /// DS records to trust on top of those of the root zone, see
+    /// `Validator::add_anchor`
+    pub trust_anchors: Vec<String>,
+}
+
+/// See `RateLimiter` for what the settings mean
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub responses_per_second: Option<u32>,
+    /// In seconds
+    pub window: Option<u64>,
+    pub slip: Option<u32>,
+    pub ipv4_prefix_len: Option<u8>,
+    pub ipv6_prefix_len: Option<u8>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+
+        let mut config: Config = toml::from_str(&text)
+            .map_err(|e| DnsError::Parse(format!("Invalid config {}: {}", path.display(), e)))?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let paths = config
+            .zones
+            .iter_mut()
+            .chain(
+                config
+                    .views
+                    .iter_mut()
+                    .flat_map(|view| view.zones.iter_mut()),
+            )
+            .chain(config.dynamic_zones.iter_mut().map(|zone| &mut zone.file))
+            .chain(config.server.doh_cert.as_mut())
+            .chain(config.blocklist.iter_mut().flat_map(|blocklist| {
+                blocklist
+                    .files
+                    .iter_mut()
+                    .chain(blocklist.categories.values_mut().flatten())
+                    .chain(blocklist.allow_files.iter_mut())
+            }))
+            .chain(
+                config
+                    .hosts
+                    .iter_mut()
+                    .flat_map(|hosts| hosts.files.iter_mut()),
+            )
+            .chain(
+                config
+                    .mdns
+                    .iter_mut()
+                    .flat_map(|mdns| mdns.files.iter_mut()),
+            )
+            .chain(config.server.doh_key.as_mut())
+            .chain(config.server.doq_cert.as_mut())
+            .chain(config.server.doq_key.as_mut())
+            .chain(config.cache.as_mut().and_then(|cache| cache.file.as_mut()))
+            .chain(
+                config
+                    .server
+                    .query_log
+                    .as_mut()
+                    .filter(|path| path.as_path() != Path::new("-")),
+            );
+        for path in paths {
+            *path = dir.join(&*path);
+        }
+
+        Ok(config)
+    }
+
+    /// Put together the options for resolving queries, loading the zones
+    /// along the way
+    pub fn resolver_options(&self) -> Result<ResolverOptions> {
+        let mut options = ResolverOptions {
+            preserve_question: self.server.preserve_question,
+            minimal_responses: self.server.minimal_responses,
+            ..ResolverOptions::default()
+        };
+
+        if !self.upstream.servers.is_empty() {
+            options.forwarder = Some(self.forwarder(&self.upstream.servers)?);
+        }
+        for (domain, servers) in &self.upstream.domains {
+            options.routes.insert(domain, self.forwarder(servers)?);
+        }
+
+        if let Some(family) = &self.upstream.prefer_family {
+            options.query.prefer_family = Some(family.parse()?);
+        }
+        options.query.verify_case = self.upstream.verify_case;
+        options.qname_minimization = self.upstream.qname_minimization;
+        if self.upstream.no_edns {
+            options.query.payload_size = None;
+        } else if let Some(size) = self.upstream.edns_payload_size {
+            options.query.payload_size = Some(size);
+        }
+        if let Some(timeout) = self.upstream.timeout_ms {
+            options.query.timeout = Duration::from_millis(timeout);
+        }
+        if let Some(retries) = self.upstream.retries {
+            options.query.retries = retries;
+        }
+
+        if let Some(config) = &self.cache {
+            let mut cache = Cache::new();
+            cache.max_entries = config.max_entries;
+            cache.min_ttl = config.min_ttl;
+            cache.max_ttl = config.max_ttl;
+            cache.max_negative_ttl = config.max_negative_ttl;
+            cache.no_cache = config.no_cache.clone();
+            cache.prefetch = config.prefetch;
+            if config.serve_stale {
+                cache.serve_stale = Some(config.max_stale.unwrap_or(cache::DEFAULT_MAX_STALE));
+            }
+            for qtype in &config.bypass {
+                cache.bypass.insert(qtype.parse::<QueryType>()?);
+            }
+            options.cache = Some(cache);
+        }
+
+        if let Some(config) = &self.ttl {
+            options.ttl_policy = Some(TtlPolicy {
+                min_ttl: config.min,
+                max_ttl: config.max,
+                max_negative_ttl: config.max_negative,
+            });
+        }
+
+        if let Some(config) = &self.client_subnet {
+            let mut policy = ClientSubnetPolicy::new();
+            if let Some(prefix) = config.ipv4_prefix {
+                policy.ipv4_prefix = prefix;
+            }
+            if let Some(prefix) = config.ipv6_prefix {
+                policy.ipv6_prefix = prefix;
+            }
+            options.client_subnet = Some(policy);
+        }
+
+        if let Some(config) = &self.nxdomain {
+            let mut nxdomain = NxdomainList::new();
+            for name in &config.names {
+                nxdomain.insert(name);
+            }
+            if let Some(ttl) = config.ttl {
+                nxdomain.ttl = ttl;
+            }
+            options.nxdomain = Some(nxdomain);
+        }
+
+        if let Some(config) = &self.blocklist {
+            let mut blocklist = Blocklist::new();
+            for path in &config.files {
+                blocklist.load(path)?;
+            }
+            for (category, paths) in &config.categories {
+                for path in paths {
+                    blocklist.load_category(category, path)?;
+                }
+            }
+            for name in &config.names {
+                blocklist.block(name);
+            }
+            for path in &config.allow_files {
+                blocklist.load_allowlist(path)?;
+            }
+            for name in &config.allow {
+                blocklist.allow(name);
+            }
+            if let Some(mode) = &config.mode {
+                blocklist.mode = mode.parse()?;
+            }
+            if let Some(ttl) = config.ttl {
+                blocklist.ttl = ttl;
+            }
+            options.blocklist = Some(blocklist);
+        }
+
+        if let Some(config) = &self.redirect {
+            let mut redirect = Redirect::new();
+            redirect.nxdomain = config.nxdomain.clone();
+            for (category, addrs) in &config.blocked {
+                for &addr in addrs {
+                    redirect.block_to(category, addr);
+                }
+            }
+            if let Some(ttl) = config.ttl {
+                redirect.ttl = ttl;
+            }
+            options.redirect = Some(redirect);
+        }
+
+        if let Some(config) = &self.hosts {
+            let mut hosts = Hosts::new();
+            load_hosts(&mut hosts, &config.files, &config.names)?;
+            if let Some(ttl) = config.ttl {
+                hosts.ttl = ttl;
+            }
+            options.hosts = Some(hosts);
+        }
+
+        if let Some(config) = self.mdns.as_ref().filter(|config| config.resolve) {
+            let mut resolver = MdnsResolver::new();
+            if let Some(timeout) = config.timeout_ms {
+                resolver.timeout = Duration::from_millis(timeout);
+            }
+            options.mdns = Some(resolver);
+        }
+
+        if let Some(config) = &self.rate_limit {
+            let mut rate_limit = RateLimiter::default();
+            if let Some(responses_per_second) = config.responses_per_second {
+                rate_limit.responses_per_second = responses_per_second;
+            }
+            if let Some(window) = config.window {
+                rate_limit.window = Duration::from_secs(window);
+            }
+            if let Some(slip) = config.slip {
+                rate_limit.slip = slip;
+            }
+            if let Some(len) = config.ipv4_prefix_len {
+                rate_limit.ipv4_prefix_len = len;
+            }
+            if let Some(len) = config.ipv6_prefix_len {
+                rate_limit.ipv6_prefix_len = len;
+            }
+            options.rate_limit = Some(rate_limit);
+        }
+
+        #[cfg(feature = "dnssec")]
+        if let Some(config) = &self.dnssec {
+            let mut validator = Validator::new();
+            for anchor in &config.trust_anchors {
+                validator.add_anchor(anchor)?;
+            }
+            options.validator = Some(validator);
+            options.query.dnssec_ok = true;
+        }
+        #[cfg(not(feature = "dnssec"))]
+        if self.dnssec.is_some() {
+            return Err(DnsError::Unsupported(
+                "DNSSEC validation requires the dnssec feature".to_string(),
+            ));
+        }
+
+        for path in &self.zones {
+            options.zones.push(Zone::load(path)?);
+        }
+        for config in &self.tsig_keys {
+            let algorithm = match &config.algorithm {
+                Some(algorithm) => algorithm.parse()?,
+                None => Algorithm::HmacSha256,
+            };
+            let secret = unbase64(&config.secret).ok_or_else(|| {
+                DnsError::Parse(format!("Invalid secret for TSIG key {}", config.name))
+            })?;
+            options
+                .tsig_keys
+                .push(TsigKey::new(&config.name, algorithm, &secret)?);
+        }
+
+        for secondary in &self.secondaries {
+            let secondary = Secondary::parse(secondary, &options.tsig_keys)?;
+            options.secondaries.push(secondary);
+        }
+        for config in &self.dynamic_zones {
+            let allow = config
+                .allow_update
+                .iter()
+                .map(|s| s.parse())
+                .collect::<Result<Vec<Subnet>>>()?;
+            let mut zone = DynamicZone::load(&config.file, allow)?;
+            for name in &config.update_keys {
+                TsigKey::find(&options.tsig_keys, name)?;
+            }
+            zone.update_keys = config.update_keys.clone();
+            zone.allow_unsigned = config.allow_unsigned_updates;
+            options.dynamic_zones.push(zone);
+        }
+
+        if let Some(prefix) = &self.dns64 {
+            options.dns64 = Some(Dns64::parse(prefix)?);
+        }
+
+        if self.server.shuffle_answers || self.server.shuffle_seed.is_some() {
+            options.shuffler = Some(AnswerShuffler::new(self.server.shuffle_seed));
+        }
+
+        if let Some(config) = &self.rotation {
+            let mode = match &config.mode {
+                Some(mode) => mode.parse()?,
+                None => RotationMode::default(),
+            };
+            let mut rotation = AnswerRotation::new(mode, config.seed);
+            rotation.weights = config.weights.clone().into_iter().collect();
+            options.rotation = Some(rotation);
+        }
+
+        if let Some(config) = &self.health {
+            let mut health = HealthChecks::new();
+            for (&addr, probe) in &config.checks {
+                health.add(addr, probe.parse()?);
+            }
+            if let Some(interval) = config.interval {
+                health.interval = Duration::from_secs(interval);
+            }
+            if let Some(timeout) = config.timeout_ms {
+                health.timeout = Duration::from_millis(timeout);
+            }
+            options.health = Some(health);
+        }
+
+        Ok(options)
+    }
+
+    /// The views of `[[views]]`, each resolving the way `options` do other
+    /// than for what it sets. They're put together last, once `options` are
+    /// complete.
+    pub fn views(&self, options: &ResolverOptions) -> Result<Vec<View>> {
+        let mut views = Vec::new();
+        for config in &self.views {
+            let clients = config
+                .clients
+                .iter()
+                .map(|s| s.parse())
+                .collect::<Result<Vec<Subnet>>>()?;
+            let mut view = View::new(&config.name, clients, options);
+
+            let options = Arc::make_mut(&mut view.options);
+            if !config.zones.is_empty() {
+                options.zones = config
+                    .zones
+                    .iter()
+                    .map(Zone::load)
+                    .collect::<Result<Vec<_>>>()?;
+            }
+            if !config.upstreams.is_empty() {
+                options.forwarder = Some(self.forwarder(&config.upstreams)?);
+            }
+            views.push(view);
+        }
+
+        Ok(views)
+    }
+
+    /// The forwarder to the given upstreams, with the policy of `[upstream]`
+    fn forwarder(&self, servers: &[String]) -> Result<Forwarder> {
+        let upstreams = servers
+            .iter()
+            .map(|s| Forwarder::parse_upstream(s))
+            .collect::<Result<Vec<_>>>()?;
+        let mut forwarder = Forwarder::new(upstreams);
+        if let Some(policy) = &self.upstream.policy {
+            forwarder.policy = policy.parse()?;
+        }
+
+        Ok(forwarder)
+    }
+
+    /// The mDNS responder for the names of `[mdns]`, if there are any
+    pub fn mdns_responder(&self) -> Result<Option<Responder>> {
+        let Some(config) = &self.mdns else {
+            return Ok(None);
+        };
+        if config.files.is_empty() && config.names.is_empty() {
+            return Ok(None);
+        }
+
+        let mut hosts = Hosts::new();
+        hosts.ttl = config.ttl.unwrap_or(mdns::DEFAULT_MDNS_TTL);
+        load_hosts(&mut hosts, &config.files, &config.names)?;
+        Ok(Some(Responder::new(hosts)))
+    }
+}
+
+fn load_hosts(
+    hosts: &mut Hosts,
+    files: &[PathBuf],
+    names: &BTreeMap<String, Vec<IpAddr>>,
+) -> Result<()> {
+    for path in files {
+        hosts.load(path)?;
+    }
+    for (name, addrs) in names {
+        for &addr in addrs {
+            hosts.insert(name, addr);
+        }
+    }
+
+    Ok(())
+}
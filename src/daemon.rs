@@ -0,0 +1,414 @@
+//! Running as a system service: serving on the sockets systemd binds for us
+//! (socket activation), telling it when we're up, detaching from the terminal
+//! when started by an init system that expects a daemon to fork, giving up
+//! root once the privileged ports are bound, writing a pid file, and logging
+//! to syslog or the journal.
+
+use std::{
+    env,
+    ffi::{CString, OsStr, OsString},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    net::{TcpListener, UdpSocket},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::{ffi::OsStrExt, net::UnixDatagram},
+    },
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+use log::{Level, Log, Metadata, Record};
+use socket2::{Socket, Type};
+
+use crate::error::{DnsError, Result};
+
+/// The first of the sockets systemd hands over, right after standard input,
+/// output and error
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket to serve on that's been bound already
+#[derive(Debug)]
+pub enum Listener {
+    Udp(UdpSocket),
+    Tcp(TcpListener),
+}
+
+/// The sockets systemd bound for us and passed on, for a service started by
+/// a socket unit, the way `sd_listen_fds` finds them. They're only for this
+/// process, not for anything it starts, so the variables that point them
+/// out are cleared. Changing the environment isn't safe once other threads
+/// are running, so this has to be called before any are started, and before
+/// `daemonize`, as the sockets are meant for the process systemd started.
+pub fn listen_fds() -> Result<Vec<Listener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let pid = pid.and_then(|pid| pid.parse::<u32>().ok());
+    let count = count.and_then(|count| count.parse::<RawFd>().ok());
+    let count = match (pid, count) {
+        (Some(pid), Some(count)) if pid == process::id() => count,
+        _ => return Ok(Vec::new()),
+    };
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // The sockets are ours to own from here on
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+            match socket.r#type()? {
+                Type::DGRAM => Ok(Listener::Udp(socket.into())),
+                Type::STREAM => Ok(Listener::Tcp(socket.into())),
+                _ => Err(DnsError::Unsupported(format!(
+                    "Socket {} passed on by systemd is neither UDP nor TCP",
+                    fd
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// Where to tell systemd that the server is up and answering, for services
+/// of `Type=notify`
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    path: OsString,
+}
+
+impl Notifier {
+    /// The socket systemd asked to be told through, if it did. Like
+    /// `listen_fds`, this clears the variable, so it has to be called before
+    /// any other thread is started.
+    pub fn from_env() -> Option<Notifier> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        env::remove_var("NOTIFY_SOCKET");
+        Some(Notifier { path })
+    }
+
+    /// Tell systemd that the server is up
+    pub fn ready(&self) -> Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        let message = b"READY=1";
+        match self.path.as_bytes().strip_prefix(b"@") {
+            // A socket in the abstract namespace, which only Linux has
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                let addr = SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(message, &addr)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(DnsError::Unsupported(
+                    "abstract notification sockets are only supported on Linux".to_string(),
+                ))
+            }
+            None => {
+                socket.send_to(message, OsStr::from_bytes(self.path.as_bytes()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A process that has detached from the terminal it was started from, see
+/// `daemonize`
+#[derive(Debug)]
+pub struct Daemon {
+    /// The process that started us waits for a byte on this pipe
+    ready: File,
+}
+
+/// Carry on in the background, in a session of its own, the way a
+/// traditional daemon does. The process that was started waits until the
+/// daemon says it's up with `Daemon::ready`, and exits with success then, or
+/// with failure should the daemon exit before that, so that whatever started
+/// it can tell whether it came up. Until then the daemon still writes to the
+/// terminal, so that errors while starting up are shown there.
+///
+/// This has to be done before starting any threads, which don't survive
+/// forking.
+pub fn daemonize() -> Result<Daemon> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let (mut waiting, ready) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    if fork()? {
+        drop(ready);
+        let mut byte = [0];
+        process::exit(match waiting.read_exact(&mut byte) {
+            Ok(()) => 0,
+            Err(_) => 1,
+        });
+    }
+    drop(waiting);
+
+    // Leave the session of the terminal, so that closing it doesn't take
+    // the daemon down, and fork once more, so that the daemon isn't the
+    // leader of its session and can never get a terminal again
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if fork()? {
+        unsafe { libc::_exit(0) };
+    }
+    unsafe { libc::umask(0o022) };
+
+    Ok(Daemon { ready })
+}
+
+/// Fork, returning whether this is the parent
+fn fork() -> Result<bool> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+impl Daemon {
+    /// Let the process that started the daemon exit, now that it's up, and
+    /// let go of the terminal. Whatever is written to standard output and
+    /// error from then on goes nowhere, so the log is best sent to syslog.
+    pub fn ready(mut self) -> Result<()> {
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        self.ready.write_all(&[1])?;
+
+        Ok(())
+    }
+}
+
+/// Run as `user`, and as `group` or else the group of the user, rather than
+/// as root. Ports below 1024 take root to bind, but there's no need to hold
+/// on to it once they are. Every thread of the process switches.
+pub fn drop_privileges(user: &str, group: Option<&str>) -> Result<()> {
+    let (uid, gid) = ids(user, group)?;
+
+    // The group has to go first, as changing it takes root
+    if unsafe { libc::setgroups(1, &gid) } != 0
+        || unsafe { libc::setgid(gid) } != 0
+        || unsafe { libc::setuid(uid) } != 0
+    {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Being able to get root back would defeat the purpose
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(DnsError::Unsupported(format!(
+            "Switched to {}, but could still switch back to root",
+            user
+        )));
+    }
+
+    Ok(())
+}
+
+/// Hand `path` over to `user` and `group`, as `drop_privileges` would pick
+/// them, for files written or removed after switching, such as the pid file
+pub fn chown(path: &Path, user: &str, group: Option<&str>) -> Result<()> {
+    let (uid, gid) = ids(user, group)?;
+    std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+
+    Ok(())
+}
+
+fn ids(user: &str, group: Option<&str>) -> Result<(libc::uid_t, libc::gid_t)> {
+    let (uid, user_gid) = lookup_user(user)?;
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => user_gid,
+    };
+
+    Ok((uid, gid))
+}
+
+/// The size of the buffer for the strings of password and group entries
+const ENTRY_BUFFER_SIZE: usize = 16384;
+
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = c_string(name)?;
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; ENTRY_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+    let error = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    match (error, result.is_null()) {
+        (0, false) => Ok((entry.pw_uid, entry.pw_gid)),
+        (0, true) => Err(DnsError::Parse(format!("Unknown user: {}", name))),
+        (error, _) => Err(io::Error::from_raw_os_error(error).into()),
+    }
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t> {
+    let c_name = c_string(name)?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; ENTRY_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+    let error = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    match (error, result.is_null()) {
+        (0, false) => Ok(entry.gr_gid),
+        (0, true) => Err(DnsError::Parse(format!("Unknown group: {}", name))),
+        (error, _) => Err(io::Error::from_raw_os_error(error).into()),
+    }
+}
+
+fn c_string(s: &str) -> Result<CString> {
+    CString::new(s).map_err(|_| DnsError::Parse(format!("Invalid name: {:?}", s)))
+}
+
+/// The file holding the ID of the process, for init scripts to find it by.
+/// It's removed again when dropped, if the process still has the rights to.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the ID of the process to `path`, unless the file is there
+    /// already with the ID of another process that's still running
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<PidFile> {
+        let path = path.as_ref().to_path_buf();
+        let running = fs::read_to_string(&path)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<libc::pid_t>().ok())
+            .filter(|&pid| pid as u32 != process::id())
+            .filter(|&pid| unsafe { libc::kill(pid, 0) } == 0);
+        if let Some(pid) = running {
+            return Err(DnsError::Unsupported(format!(
+                "Already running as process {}, see {}",
+                pid,
+                path.display()
+            )));
+        }
+
+        fs::write(&path, format!("{}\n", process::id()))?;
+
+        Ok(PidFile { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Where the log goes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Standard error, with a timestamp on every line
+    #[default]
+    Stderr,
+    /// The syslog daemon, see `SyslogLogger`
+    Syslog,
+    /// Standard error the way the systemd journal reads it, with the
+    /// priority of each line in front and no timestamps, which the journal
+    /// adds itself, see `format_journald`
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<LogTarget> {
+        match s {
+            "stderr" => Ok(LogTarget::Stderr),
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            _ => Err(DnsError::Parse(format!("Unknown log target: {}", s))),
+        }
+    }
+}
+
+/// The syslog priority of a log level
+pub fn priority(level: Level) -> libc::c_int {
+    match level {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_INFO,
+        Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+/// A line of the log the way the journal reads it from standard error, e.g.
+/// `<6>dns_server::server: Listening on 0.0.0.0:53`, for
+/// `env_logger::Builder::format`
+pub fn format_journald(buf: &mut env_logger::fmt::Formatter, record: &Record) -> io::Result<()> {
+    writeln!(
+        buf,
+        "<{}>{}: {}",
+        priority(record.level()),
+        record.target(),
+        record.args()
+    )
+}
+
+/// Logs to the syslog daemon through syslog(3), as `dns-server` in the
+/// daemon facility, with whatever `filter` lets through
+pub struct SyslogLogger {
+    filter: env_logger::Logger,
+}
+
+impl SyslogLogger {
+    /// Log everything from here on to syslog, filtered the way `filter` is
+    pub fn init(filter: env_logger::Logger) -> Result<()> {
+        unsafe { libc::openlog(c"dns-server".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+        log::set_max_level(filter.filter());
+        log::set_boxed_logger(Box::new(SyslogLogger { filter }))
+            .map_err(|e| DnsError::Unsupported(e.to_string()))
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+
+        let message = format!("{}: {}", record.target(), record.args());
+        // Messages can't hold a NUL, which would cut them short anyway
+        let Ok(message) = CString::new(message.replace('\0', "")) else {
+            return;
+        };
+        unsafe { libc::syslog(priority(record.level()), c"%s".as_ptr(), message.as_ptr()) };
+    }
+
+    fn flush(&self) {}
+}
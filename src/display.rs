@@ -0,0 +1,420 @@
+//! Human readable forms of packets, for debugging and for the output of the
+//! `query` command: the packet the way dig prints it, and an annotated hex
+//! dump of its bytes on the wire.
+
+use std::fmt;
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_header::DnsHeader,
+    dns_packet::DnsPacket,
+    dns_question::DnsQuestion,
+    dns_record::{DnsRecord, MDNS_CLASS_FLAG},
+    ecs::ClientSubnet,
+    idna,
+    query_type::QueryType,
+};
+
+/// The flags that are set in the header, e.g. `qr rd ra`
+fn flags(header: &DnsHeader) -> String {
+    let flags: Vec<&str> = [
+        (header.response, "qr"),
+        (header.authoritative_answer, "aa"),
+        (header.truncated_message, "tc"),
+        (header.recursion_desired, "rd"),
+        (header.recursion_available, "ra"),
+        (header.authed_data, "ad"),
+        (header.checking_disabled, "cd"),
+    ]
+    .into_iter()
+    .filter_map(|(set, flag)| set.then_some(flag))
+    .collect();
+
+    flags.join(" ")
+}
+
+pub(crate) fn class_name(class: u16) -> String {
+    match class {
+        1 => "IN".to_string(),
+        3 => "CH".to_string(),
+        4 => "HS".to_string(),
+        254 => "NONE".to_string(),
+        255 => "ANY".to_string(),
+        class => format!("CLASS{}", class),
+    }
+}
+
+/// The question the way dig prints it, commented out and with tabs in between
+/// the fields, e.g. `;example.com. IN A`. Internationalized names are shown
+/// in Unicode.
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            ";{}.\t\tIN\t{}",
+            idna::to_unicode(self.name.trim_end_matches('.')),
+            self.qtype
+        )
+    }
+}
+
+/// The packet the way dig prints it: the header and its flags, then a section
+/// for each part of the packet that holds anything, with internationalized
+/// owner names in Unicode. The OPT record gets a
+/// pseudosection of its own rather than showing up among the additional
+/// records.
+impl fmt::Display for DnsPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.header;
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}",
+            header.opcode, header.rescode, header.id
+        )?;
+        writeln!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags(header),
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.resources.len()
+        )?;
+
+        if let (Some(version), Some(DnsRecord::OPT { packet_len, .. })) =
+            (self.edns_version(), self.get_opt())
+        {
+            let flags = match self.dnssec_ok() {
+                true => " do",
+                false => "",
+            };
+            writeln!(f)?;
+            writeln!(f, ";; OPT PSEUDOSECTION:")?;
+            writeln!(
+                f,
+                "; EDNS: version: {}, flags:{}; udp: {}",
+                version, flags, packet_len
+            )?;
+            if let Some(subnet) = ClientSubnet::from_packet(self) {
+                writeln!(f, "; CLIENT-SUBNET: {}/{}", subnet, subnet.scope_prefix)?;
+            }
+        }
+
+        if !self.questions.is_empty() {
+            writeln!(f)?;
+            writeln!(f, ";; QUESTION SECTION:")?;
+            for question in &self.questions {
+                writeln!(f, "{}", question)?;
+            }
+        }
+
+        let additional: Vec<_> = self
+            .resources
+            .iter()
+            .filter(|rec| rec.query_type() != QueryType::OPT)
+            .collect();
+        for (title, records) in [
+            ("ANSWER", self.answers.iter().collect::<Vec<_>>()),
+            ("AUTHORITY", self.authorities.iter().collect()),
+            ("ADDITIONAL", additional),
+        ] {
+            if records.is_empty() {
+                continue;
+            }
+
+            writeln!(f)?;
+            writeln!(f, ";; {} SECTION:", title)?;
+            for rec in records {
+                match idna::is_idn(rec.domain()) {
+                    true => {
+                        let mut rec = rec.clone();
+                        rec.set_domain(idna::to_unicode(rec.domain()));
+                        writeln!(f, "{}", rec)?;
+                    }
+                    false => writeln!(f, "{}", rec)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How many bytes go on a line of a hex dump
+const BYTES_PER_LINE: usize = 8;
+
+/// A hex dump of a packet as it's found on the wire, with each field on a
+/// line of its own along with what it holds, e.g.
+///
+/// ```text
+/// 0000  3d e8                    id 15848
+/// 0002  81 80                    flags qr rd ra, opcode QUERY, rcode NOERROR
+/// ...
+///       ;; ANSWER SECTION
+/// 001d  c0 0c                    pointer to 000c: example.com.
+/// ```
+///
+/// Names are broken down into their labels, and compression pointers into
+/// the offset they point to and the name found there. Whatever doesn't parse
+/// is dumped as is, so that a broken packet can be told apart from the
+/// bytes leading up to where it breaks.
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut dump = HexDump {
+        data,
+        pos: 0,
+        out: String::new(),
+    };
+    // Running out of data ends the dump early, and is noted as it happens
+    let _ = dump.packet();
+    if dump.pos < data.len() {
+        dump.field(data.len() - dump.pos, "trailing data");
+    }
+
+    dump.out
+}
+
+struct HexDump<'a> {
+    data: &'a [u8],
+    pos: usize,
+    out: String,
+}
+
+impl HexDump<'_> {
+    fn packet(&mut self) -> Option<()> {
+        self.u16("id")?;
+
+        let mut header = DnsHeader::new();
+        let note = match header.read(&mut BytePacketBuffer::from_slice(self.data)) {
+            Ok(()) => format!(
+                "flags {}, opcode {}, rcode {:?}",
+                flags(&header),
+                header.opcode,
+                header.rescode
+            ),
+            Err(_) => "flags".to_string(),
+        };
+        self.field(2, note)?;
+
+        let mut counts = [0; 4];
+        for (count, what) in counts.iter_mut().zip([
+            "questions",
+            "answers",
+            "authority records",
+            "additional records",
+        ]) {
+            *count = self.u16(what)?;
+        }
+
+        if counts[0] > 0 {
+            self.heading("QUESTION");
+        }
+        for _ in 0..counts[0] {
+            self.name()?;
+            self.number(2, "type", |qtype| {
+                format!("type {}", QueryType::from_num(qtype as u16))
+            })?;
+            self.number(2, "class", |class| match class as u16 & MDNS_CLASS_FLAG {
+                0 => format!("class {}", class_name(class as u16)),
+                _ => format!(
+                    "class {}, unicast response",
+                    class_name(class as u16 & !MDNS_CLASS_FLAG)
+                ),
+            })?;
+        }
+
+        for (count, section) in counts[1..]
+            .iter()
+            .zip(["ANSWER", "AUTHORITY", "ADDITIONAL"])
+        {
+            if *count > 0 {
+                self.heading(section);
+            }
+            for _ in 0..*count {
+                self.record()?;
+            }
+        }
+
+        Some(())
+    }
+
+    fn record(&mut self) -> Option<()> {
+        let start = self.pos;
+        self.name()?;
+
+        let qtype = self.number(2, "type", |qtype| {
+            format!("type {}", QueryType::from_num(qtype as u16))
+        })?;
+        let qtype = QueryType::from_num(qtype as u16);
+        // The class and TTL of an OPT record hold the EDNS parameters instead
+        if qtype == QueryType::OPT {
+            self.u16("udp payload size")?;
+            self.number(4, "edns", |ttl| {
+                format!(
+                    "extended rcode {}, version {}, flags {:#06x}",
+                    ttl >> 24,
+                    (ttl >> 16) & 0xFF,
+                    ttl & 0xFFFF
+                )
+            })?;
+        } else {
+            self.number(2, "class", |class| match class as u16 & MDNS_CLASS_FLAG {
+                0 => format!("class {}", class_name(class as u16)),
+                _ => format!(
+                    "class {}, cache flush",
+                    class_name(class as u16 & !MDNS_CLASS_FLAG)
+                ),
+            })?;
+            self.u32("ttl")?;
+        }
+
+        let len = self.u16("data length")? as usize;
+        let end = self.pos + len;
+        if end > self.data.len() {
+            self.field(len, "record data")?;
+        }
+
+        // The names in the record data may be compressed too, so those are
+        // broken down like any others
+        match qtype {
+            QueryType::NS | QueryType::CNAME | QueryType::PTR => {
+                self.name()?;
+            }
+            QueryType::MX => {
+                self.u16("preference")?;
+                self.name()?;
+            }
+            QueryType::SRV => {
+                self.u16("priority")?;
+                self.u16("weight")?;
+                self.u16("port")?;
+                self.name()?;
+            }
+            QueryType::SOA => {
+                self.name()?;
+                self.name()?;
+                for what in ["serial", "refresh", "retry", "expire", "minimum"] {
+                    self.u32(what)?;
+                }
+            }
+            _ if len > 0 => {
+                let note = rdata(self.data, start).unwrap_or_else(|| "record data".to_string());
+                self.field(len, note)?;
+            }
+            _ => {}
+        }
+
+        if self.pos < end {
+            self.field(end - self.pos, "rest of the record data")?;
+        }
+
+        Some(())
+    }
+
+    /// The labels of a name, up to its end or a pointer to the rest of it
+    fn name(&mut self) -> Option<()> {
+        loop {
+            let len = *self.data.get(self.pos)?;
+            match len {
+                0 => return self.field(1, "root"),
+                len if len & 0xC0 == 0xC0 => {
+                    let data = self.data;
+                    self.number(2, "pointer", |pointer| {
+                        let offset = (pointer & 0x3FFF) as usize;
+                        let name = name_at(data, offset).unwrap_or_else(|| "invalid".to_string());
+                        format!("pointer to {:04x}: {}", offset, name)
+                    })?;
+                    return Some(());
+                }
+                len if len & 0xC0 != 0 => {
+                    self.field(1, format!("unknown label type {:#04x}", len))?;
+                    return None;
+                }
+                len => {
+                    let label = self.data.get(self.pos + 1..self.pos + 1 + len as usize);
+                    let note = match label {
+                        Some(label) => format!("label \"{}\"", String::from_utf8_lossy(label)),
+                        None => "label".to_string(),
+                    };
+                    self.field(1 + len as usize, note)?;
+                }
+            }
+        }
+    }
+
+    /// Dump a number that takes up the next `len` bytes, noted the way
+    /// `note` has it, or as `what` when the data ends before it does
+    fn number(&mut self, len: usize, what: &str, note: impl FnOnce(u32) -> String) -> Option<u32> {
+        let value = self
+            .data
+            .get(self.pos..self.pos + len)
+            .map(|bytes| bytes.iter().fold(0, |n, &b| (n << 8) | b as u32));
+        self.field(len, value.map(note).unwrap_or_else(|| what.to_string()))?;
+        value
+    }
+
+    fn u16(&mut self, what: &str) -> Option<u16> {
+        let value = self.number(2, what, |value| format!("{} {}", what, value))?;
+        Some(value as u16)
+    }
+
+    fn u32(&mut self, what: &str) -> Option<u32> {
+        self.number(4, what, |value| format!("{} {}", what, value))
+    }
+
+    fn heading(&mut self, section: &str) {
+        self.out
+            .push_str(&format!("{:6};; {} SECTION\n", "", section));
+    }
+
+    /// Dump the next `len` bytes, with `note` next to the first line of
+    /// them. When the data ends before that, what's left of it is dumped
+    /// and noted as truncated.
+    fn field(&mut self, len: usize, note: impl Into<String>) -> Option<()> {
+        let mut note = note.into();
+        let end = self.pos + len;
+        let available = end.min(self.data.len());
+        if available < end {
+            note.push_str(" (truncated)");
+        }
+
+        let bytes = &self.data[self.pos..available];
+        if bytes.is_empty() {
+            self.out
+                .push_str(&format!("{:04x}  {:23}  {}\n", self.pos, "", note));
+        }
+        for (i, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let note = match i {
+                0 => note.as_str(),
+                _ => "",
+            };
+            let line = format!(
+                "{:04x}  {:23}  {}",
+                self.pos + i * BYTES_PER_LINE,
+                hex.join(" "),
+                note
+            );
+            self.out.push_str(line.trim_end());
+            self.out.push('\n');
+        }
+
+        self.pos = available;
+        (available == end).then_some(())
+    }
+}
+
+/// The name at `offset`, following any pointers along the way
+fn name_at(data: &[u8], offset: usize) -> Option<String> {
+    let mut buffer = BytePacketBuffer::from_slice(data);
+    buffer.seek(offset).ok()?;
+    let mut name = String::new();
+    buffer.read_qname(&mut name).ok()?;
+    Some(format!("{}.", name))
+}
+
+/// The record data of the record at `start` in zone file format
+fn rdata(data: &[u8], start: usize) -> Option<String> {
+    let mut buffer = BytePacketBuffer::from_slice(data);
+    buffer.seek(start).ok()?;
+    Some(DnsRecord::read(&mut buffer).ok()?.data())
+}
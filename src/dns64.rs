@@ -0,0 +1,103 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::{
+    dns_packet::DnsPacket,
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+};
+
+/// DNS64 (RFC 6147) lets IPv6-only clients behind a NAT64 gateway reach IPv4-only
+/// hosts, by synthesizing AAAA records out of the A records of a name. The IPv4
+/// address is embedded in a prefix that the NAT64 gateway knows how to route,
+/// usually the well-known `64:ff9b::/96`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dns64 {
+    pub prefix: Ipv6Addr,
+    pub prefix_len: u8,
+}
+
+impl Dns64 {
+    pub fn new(prefix: Ipv6Addr, prefix_len: u8) -> Result<Dns64> {
+        // RFC 6052 only defines a handful of prefix lengths, chosen so that
+        // the embedded address never overlaps the reserved "u" octet.
+        if ![32, 40, 48, 56, 64, 96].contains(&prefix_len) {
+            return Err(DnsError::Parse(format!(
+                "Unsupported DNS64 prefix length /{}",
+                prefix_len
+            )));
+        }
+
+        Ok(Dns64 { prefix, prefix_len })
+    }
+
+    /// Parse a prefix written as `64:ff9b::/96`. The length defaults to /96 if
+    /// it's left out.
+    pub fn parse(s: &str) -> Result<Dns64> {
+        let (prefix, prefix_len) = match s.split_once('/') {
+            Some((prefix, len)) => (prefix, len.parse::<u8>()?),
+            None => (s, 96),
+        };
+
+        Dns64::new(prefix.parse::<Ipv6Addr>()?, prefix_len)
+    }
+
+    /// Embed an IPv4 address in the configured prefix, following the layout
+    /// of RFC 6052 section 2.2.
+    pub fn synthesize(&self, addr: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+
+        // Everything after the prefix starts out as zero, which takes care of
+        // the "u" octet (bits 64 to 71) and the suffix.
+        for octet in octets.iter_mut().skip(self.prefix_len as usize / 8) {
+            *octet = 0;
+        }
+
+        // The IPv4 address follows the prefix directly, except that it has to
+        // skip over the "u" octet if the prefix is shorter than /64.
+        let mut pos = self.prefix_len as usize / 8;
+        for octet in addr.octets() {
+            if pos == 8 {
+                pos += 1;
+            }
+            octets[pos] = octet;
+            pos += 1;
+        }
+
+        Ipv6Addr::from(octets)
+    }
+
+    /// Turn the A records of a response into synthesized AAAA records. Any
+    /// other records, such as the CNAMEs leading up to the A records, are
+    /// kept as they are.
+    ///
+    /// The synthesized records live no longer than `max_ttl`, which is how
+    /// long the lack of AAAA records may be cached for (RFC 6147, section
+    /// 5.1.7).
+    pub fn synthesize_records(&self, records: &[DnsRecord], max_ttl: u32) -> Vec<DnsRecord> {
+        records
+            .iter()
+            .map(|record| match record {
+                DnsRecord::A { domain, addr, ttl } => DnsRecord::AAAA {
+                    domain: domain.clone(),
+                    addr: self.synthesize(*addr),
+                    ttl: (*ttl).min(max_ttl),
+                },
+                other => other.clone(),
+            })
+            .collect()
+    }
+}
+
+/// How long the empty AAAA answer `response` may be cached for: the lower of
+/// the TTL and the minimum of the SOA record sent along with it, or 600
+/// seconds without one (RFC 6147, section 5.1.7)
+pub fn negative_ttl(response: &DnsPacket) -> u32 {
+    response
+        .authorities
+        .iter()
+        .find_map(|rec| match rec {
+            DnsRecord::SOA { minimum, ttl, .. } => Some((*minimum).min(*ttl)),
+            _ => None,
+        })
+        .unwrap_or(600)
+}
@@ -1,16 +1,68 @@
-use crate::{result_code::ResultCode, byte_packet_buffer::BytePacketBuffer};
+use std::fmt;
+
+use crate::{byte_packet_buffer::BytePacketBuffer, error::Result, result_code::ResultCode};
+
+/// The kind of message, from the four bits of the header after the QR bit
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Opcode {
+    UNKNOWN(u8),
+    /// A standard query
+    QUERY, // 0
+    /// An inverse query, which asked for the names of some data, and which
+    /// RFC 3425 has since retired
+    IQUERY, // 1
+    /// A request for the status of the server, which was never specified
+    STATUS, // 2
+    /// A primary telling its secondaries that a zone changed (RFC 1996)
+    NOTIFY, // 4
+    /// Records to add to a zone or delete from it (RFC 2136)
+    UPDATE, // 5
+}
 
-type Error = Box<dyn std::error::Error>;
-type Result<T> = std::result::Result<T, Error>;
+impl Opcode {
+    pub fn to_num(self) -> u8 {
+        match self {
+            Opcode::UNKNOWN(x) => x,
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+        }
+    }
+
+    pub fn from_num(num: u8) -> Opcode {
+        match num {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            _ => Opcode::UNKNOWN(num),
+        }
+    }
+}
 
-#[derive(Clone, Debug)]
+/// The name of the opcode, or its number for those that don't have one
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Opcode::UNKNOWN(x) => write!(f, "{}", x),
+            opcode => write!(f, "{:?}", opcode),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsHeader {
     pub id: u16, // 16 bits
 
-    pub recursion_desired: bool,   // 1 bit
+    pub recursion_desired: bool,    // 1 bit
     pub truncated_message: bool,    // 1 bit
     pub authoritative_answer: bool, // 1 bit
-    pub opcode: u8,                 // 4 bits
+    pub opcode: Opcode,             // 4 bits
     pub response: bool,             // 1 bit
 
     pub rescode: ResultCode,       // 4 bits
@@ -25,6 +77,12 @@ pub struct DnsHeader {
     pub resource_entries: u16,      // 16 bits
 }
 
+impl Default for DnsHeader {
+    fn default() -> DnsHeader {
+        DnsHeader::new()
+    }
+}
+
 impl DnsHeader {
     pub fn new() -> DnsHeader {
         DnsHeader {
@@ -32,7 +90,7 @@ impl DnsHeader {
             recursion_desired: false,
             truncated_message: false,
             authoritative_answer: false,
-            opcode:0,
+            opcode: Opcode::QUERY,
             response: false,
 
             rescode: ResultCode::NOERROR,
@@ -57,7 +115,7 @@ impl DnsHeader {
         self.recursion_desired = (a & (1 << 0)) > 0;
         self.truncated_message = (a & (1 << 1)) > 0;
         self.authoritative_answer = (a & (1 << 2)) > 0;
-        self.opcode = (a >> 3) & 0x0F;
+        self.opcode = Opcode::from_num((a >> 3) & 0x0F);
         self.response = (a & (1 << 7)) > 0;
 
         self.rescode = ResultCode::from_num(b & 0x0F);
@@ -81,12 +139,12 @@ impl DnsHeader {
             (self.recursion_desired as u8)
                 | ((self.truncated_message as u8) << 1)
                 | ((self.authoritative_answer as u8) << 2)
-                | (self.opcode << 3)
-                | ((self.response as u8) << 7) as u8,
+                | ((self.opcode.to_num() & 0x0F) << 3)
+                | ((self.response as u8) << 7),
         )?;
 
         buffer.write_u8(
-            (self.rescode as u8)
+            ((self.rescode as u8) & 0x0F)
                 | ((self.checking_disabled as u8) << 4)
                 | ((self.authed_data as u8) << 5)
                 | ((self.z as u8) << 6)
@@ -101,3 +159,28 @@ impl DnsHeader {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes_survive_a_round_trip() {
+        for num in 0..16 {
+            let mut header = DnsHeader::new();
+            header.opcode = Opcode::from_num(num);
+            let mut buffer = BytePacketBuffer::new();
+            header.write(&mut buffer).unwrap();
+
+            buffer.seek(0).unwrap();
+            let mut read = DnsHeader::new();
+            read.read(&mut buffer).unwrap();
+            assert_eq!(read.opcode, header.opcode);
+            assert_eq!(read.opcode.to_num(), num);
+        }
+
+        assert_eq!(Opcode::from_num(5), Opcode::UPDATE);
+        assert_eq!(Opcode::UPDATE.to_string(), "UPDATE");
+        assert_eq!(Opcode::from_num(6).to_string(), "6");
+    }
+}
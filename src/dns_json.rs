@@ -0,0 +1,96 @@
+//! The JSON format that the DNS over HTTPS APIs of Google and Cloudflare
+//! answer with (`application/dns-json`), for handing responses to tools that
+//! speak JSON rather than DNS. For the rest of the packet, or for reading it
+//! back in, there's the `serde` feature.
+
+use crate::{dns_packet::DnsPacket, dns_record::DnsRecord, query_type::QueryType};
+
+/// The packet as a single line of JSON, e.g.
+///
+/// ```text
+/// {"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,
+///  "Question":[{"name":"example.com.","type":1}],
+///  "Answer":[{"name":"example.com.","type":1,"TTL":300,"data":"93.184.216.34"}]}
+/// ```
+///
+/// The record data is in zone file format. Like those APIs, the sections
+/// without records are left out, and so is the OPT record.
+pub fn to_dns_json(packet: &DnsPacket) -> String {
+    let header = &packet.header;
+    let mut json = format!(
+        "{{\"Status\":{},\"TC\":{},\"RD\":{},\"RA\":{},\"AD\":{},\"CD\":{}",
+        header.rescode as u8,
+        header.truncated_message,
+        header.recursion_desired,
+        header.recursion_available,
+        header.authed_data,
+        header.checking_disabled
+    );
+
+    let questions: Vec<String> = packet
+        .questions
+        .iter()
+        .map(|question| {
+            format!(
+                "{{\"name\":{},\"type\":{}}}",
+                json_string(&fqdn(&question.name)),
+                question.qtype.to_num()
+            )
+        })
+        .collect();
+    json.push_str(&format!(",\"Question\":[{}]", questions.join(",")));
+
+    let additional: Vec<&DnsRecord> = packet
+        .resources
+        .iter()
+        .filter(|rec| rec.query_type() != QueryType::OPT)
+        .collect();
+    for (title, records) in [
+        ("Answer", packet.answers.iter().collect::<Vec<_>>()),
+        ("Authority", packet.authorities.iter().collect()),
+        ("Additional", additional),
+    ] {
+        if records.is_empty() {
+            continue;
+        }
+
+        let records: Vec<String> = records
+            .into_iter()
+            .map(|rec| {
+                format!(
+                    "{{\"name\":{},\"type\":{},\"TTL\":{},\"data\":{}}}",
+                    json_string(&fqdn(rec.domain())),
+                    rec.query_type().to_num(),
+                    rec.ttl(),
+                    json_string(&rec.data())
+                )
+            })
+            .collect();
+        json.push_str(&format!(",\"{}\":[{}]", title, records.join(",")));
+    }
+
+    json.push('}');
+    json
+}
+
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+/// A string as a JSON literal. Names can hold any byte, quotes and control
+/// characters included.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
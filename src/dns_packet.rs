@@ -1,14 +1,50 @@
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use rand::{rngs::OsRng, Rng};
 
 use crate::{
-    byte_packet_buffer::BytePacketBuffer, dns_header::DnsHeader, dns_question::DnsQuestion,
-    dns_record::DnsRecord, query_type::QueryType,
+    byte_packet_buffer::BytePacketBuffer,
+    client::{prefer_family, AddressFamily},
+    dns_header::DnsHeader,
+    dns_question::DnsQuestion,
+    dns_record::DnsRecord,
+    ecs::ClientSubnet,
+    error::{DnsError, Result},
+    name,
+    query_type::QueryType,
+    result_code::ResultCode,
 };
 
-type Error = Box<dyn std::error::Error>;
-type Result<T> = std::result::Result<T, Error>;
+/// The DO bit among the flags of an OPT record
+pub const DNSSEC_OK: u32 = 1 << 15;
+
+/// The sections of records, in the order they're written
+const ANSWER: usize = 0;
+const AUTHORITY: usize = 1;
+const ADDITIONAL: usize = 2;
+
+/// Fail with `CountMismatch` when the packet ends right where the next of
+/// the `expected` entries of `section` should start. A packet that ends in
+/// the middle of one is merely truncated, and fails to parse as such.
+fn check_count(
+    buffer: &BytePacketBuffer,
+    section: &'static str,
+    expected: u16,
+    found: u16,
+) -> Result<()> {
+    if buffer.pos() >= buffer.buf.len() {
+        return Err(DnsError::CountMismatch {
+            section,
+            expected,
+            found,
+        });
+    }
 
-#[derive(Clone, Debug)]
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsPacket {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
@@ -17,6 +53,12 @@ pub struct DnsPacket {
     pub resources: Vec<DnsRecord>,
 }
 
+impl Default for DnsPacket {
+    fn default() -> DnsPacket {
+        DnsPacket::new()
+    }
+}
+
 impl DnsPacket {
     pub fn new() -> DnsPacket {
         DnsPacket {
@@ -28,32 +70,93 @@ impl DnsPacket {
         }
     }
 
+    /// A query for a single question, ready to be sent off. It gets a random ID
+    /// and has recursion desired set, see `QueryBuilder` for more control.
+    pub fn query(qname: &str, qtype: QueryType) -> DnsPacket {
+        QueryBuilder::new().question(qname, qtype).build()
+    }
+
+    /// Start on the response to `query`, which shares its ID, opcode and
+    /// questions. See `ResponseBuilder` for what else can be set.
+    pub fn response_to(query: &DnsPacket) -> ResponseBuilder {
+        ResponseBuilder::reply_to(&query.header).questions(query.questions.iter().cloned())
+    }
+
+    /// Parse the packet starting at the current position of `buffer`. The
+    /// packet has to hold as many questions and records as its header says,
+    /// and anything that's malformed about it is an error for which
+    /// `DnsError::is_malformed_packet` holds. Whatever follows the last
+    /// record is left alone, such as the unused space of a fixed size buffer.
     pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<DnsPacket> {
+        if buffer.buf.len() > u16::MAX as usize {
+            return Err(DnsError::MessageTooLong(buffer.buf.len()));
+        }
+
         let mut result = DnsPacket::new();
         result.header.read(buffer)?;
 
-        for _ in 0..result.header.questions {
+        for found in 0..result.header.questions {
+            check_count(buffer, "question", result.header.questions, found)?;
             let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
             question.read(buffer)?;
             result.questions.push(question);
         }
 
-        for _ in 0..result.header.answers {
-            let rec = DnsRecord::read(buffer)?;
-            result.answers.push(rec);
+        for (section, expected, records) in [
+            ("answer", result.header.answers, &mut result.answers),
+            (
+                "authority",
+                result.header.authoritative_entries,
+                &mut result.authorities,
+            ),
+            (
+                "additional",
+                result.header.resource_entries,
+                &mut result.resources,
+            ),
+        ] {
+            for found in 0..expected {
+                check_count(buffer, section, expected, found)?;
+                records.push(DnsRecord::read(buffer)?);
+            }
         }
 
-        for _ in 0..result.header.authoritative_entries {
-            let rec = DnsRecord::read(buffer)?;
-            result.authorities.push(rec);
+        // With EDNS, the header only holds the lower four bits of the RCODE
+        // and the rest of it is found in the OPT record.
+        if let Some(DnsRecord::OPT { flags, .. }) = result.get_opt() {
+            let code = ((flags >> 24) << 4) | result.header.rescode as u32;
+            if let Ok(code) = u8::try_from(code) {
+                result.header.rescode = ResultCode::from_num(code);
+            }
         }
 
-        for _ in 0..result.header.resource_entries {
-            let rec = DnsRecord::read(buffer)?;
-            result.resources.push(rec);
+        Ok(result)
+    }
+
+    /// Parse a packet from a hex dump, such as the ones found in logs or
+    /// copied out of a packet capture. Any whitespace in between the bytes is
+    /// ignored, so both `3de80120...` and `3d e8 01 20 ...` work.
+    pub fn from_hex(s: &str) -> Result<DnsPacket> {
+        let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        if let Some(b) = digits.iter().find(|b| !b.is_ascii_hexdigit()) {
+            return Err(DnsError::Parse(format!(
+                "Invalid hex digit: {}",
+                *b as char
+            )));
+        }
+        if !digits.len().is_multiple_of(2) {
+            return Err(DnsError::Parse(
+                "Hex string has an odd number of digits".to_string(),
+            ));
         }
 
-        Ok(result)
+        let data = digits
+            .chunks(2)
+            .map(|pair| Ok(u8::from_str_radix(&String::from_utf8_lossy(pair), 16)?))
+            .collect::<Result<Vec<u8>>>()?;
+
+        let mut buffer = BytePacketBuffer::from_slice(&data);
+        DnsPacket::from_buffer(&mut buffer)
     }
 
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
@@ -62,24 +165,137 @@ impl DnsPacket {
         self.header.authoritative_entries = self.authorities.len() as u16;
         self.header.resource_entries = self.resources.len() as u16;
 
+        // Stash the upper bits of an extended RCODE in the OPT record, the
+        // header only has room for the lower four.
+        let ext_rcode = (self.header.rescode as u32) >> 4;
+        for rec in self.resources.iter_mut() {
+            if let DnsRecord::OPT { flags, .. } = rec {
+                *flags = (*flags & 0x00FF_FFFF) | (ext_rcode << 24);
+            }
+        }
+
         self.header.write(buffer)?;
 
         for question in &self.questions {
             question.write(buffer)?;
         }
-        for rec in &self.answers {
-            rec.write(buffer)?;
+
+        // If we run out of room part way through the records, the packet is
+        // cut off after the last record that did fit, and marked as
+        // truncated so the client knows to retry over TCP. Records left out
+        // of the additional section are only extras though, which don't make
+        // the packet truncated (RFC 2181, section 9). Where each record
+        // starts is kept track of, so that records can be taken back off the
+        // end to make room, see below.
+        let mut written: Vec<(usize, usize)> = Vec::new();
+        let mut cut_off = false;
+        let sections = [&self.answers, &self.authorities, &self.resources];
+        'sections: for (section, records) in sections.into_iter().enumerate() {
+            for rec in records {
+                let start_pos = buffer.pos();
+                match rec.write(buffer) {
+                    Ok(_) => written.push((section, start_pos)),
+                    Err(DnsError::BufferOverflow) => {
+                        buffer.truncate(start_pos)?;
+                        cut_off = true;
+                        self.header.truncated_message |= section < ADDITIONAL;
+                        break 'sections;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
         }
-        for rec in &self.authorities {
-            rec.write(buffer)?;
+
+        // The OPT record has to make it into the packet all the same, so
+        // that the client still learns what we support (RFC 6891, section
+        // 7), in place of as many of the last records as it takes
+        let opt_written = written
+            .iter()
+            .filter(|(section, _)| *section == ADDITIONAL)
+            .count();
+        let missing_opt = self
+            .resources
+            .iter()
+            .skip(opt_written)
+            .filter(|rec| matches!(rec, DnsRecord::OPT { .. }));
+        for rec in missing_opt {
+            loop {
+                let start_pos = buffer.pos();
+                match rec.write(buffer) {
+                    Ok(_) => {
+                        written.push((ADDITIONAL, start_pos));
+                        break;
+                    }
+                    Err(DnsError::BufferOverflow) => {
+                        buffer.truncate(start_pos)?;
+                        let (section, start_pos) = written.pop().ok_or(DnsError::BufferOverflow)?;
+                        buffer.truncate(start_pos)?;
+                        self.header.truncated_message |= section < ADDITIONAL;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
         }
-        for rec in &self.resources {
-            rec.write(buffer)?;
+
+        if cut_off {
+            let count = |section| written.iter().filter(|(s, _)| *s == section).count() as u16;
+            self.header.answers = count(ANSWER);
+            self.header.authoritative_entries = count(AUTHORITY);
+            self.header.resource_entries = count(ADDITIONAL);
+
+            let end_pos = buffer.pos();
+            buffer.seek(0)?;
+            self.header.write(buffer)?;
+            buffer.seek(end_pos)?;
         }
 
         Ok(())
     }
 
+    /// The EDNS OPT record of the packet, if the sender supports EDNS
+    pub fn get_opt(&self) -> Option<&DnsRecord> {
+        self.resources
+            .iter()
+            .find(|rec| matches!(rec, DnsRecord::OPT { .. }))
+    }
+
+    /// Drop the additional section for a minimal response, except for the OPT
+    /// record, which has to stay for EDNS to keep working.
+    pub fn strip_additional(&mut self) {
+        self.resources
+            .retain(|rec| matches!(rec, DnsRecord::OPT { .. }));
+        self.header.resource_entries = self.resources.len() as u16;
+    }
+
+    /// The EDNS version the sender speaks, or `None` if it doesn't use EDNS
+    pub fn edns_version(&self) -> Option<u8> {
+        match self.get_opt() {
+            Some(DnsRecord::OPT { flags, .. }) => Some(((flags >> 16) & 0xFF) as u8),
+            _ => None,
+        }
+    }
+
+    /// Whether the sender asked for DNSSEC records by setting the DO bit
+    pub fn dnssec_ok(&self) -> bool {
+        match self.get_opt() {
+            Some(DnsRecord::OPT { flags, .. }) => flags & DNSSEC_OK != 0,
+            _ => false,
+        }
+    }
+
+    /// Drop the DNSSEC records a client that didn't set the DO bit has no use
+    /// for, unless it asked for them explicitly (RFC 3225)
+    pub fn strip_dnssec(&mut self, qtype: QueryType) {
+        let keep = |rec: &DnsRecord| {
+            let rtype = rec.query_type();
+            rtype == qtype
+                || !matches!(rtype, QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3)
+        };
+        self.answers.retain(keep);
+        self.authorities.retain(keep);
+        self.resources.retain(keep);
+    }
+
     /// It's useful to be able to pick a random A from the packet. When we
     /// get multiple IP's for a single name, it doesn't matter which one we
     /// choose, so in those cases we can now pick one at random.
@@ -90,6 +306,14 @@ impl DnsPacket {
         })
     }
 
+    /// Like `get_random_a`, for IPv6 addresses
+    pub fn get_random_aaaa(&self) -> Option<Ipv6Addr> {
+        self.answers.iter().find_map(|record| match record {
+            DnsRecord::AAAA { addr, .. } => Some(*addr),
+            _ => None,
+        })
+    }
+
     /// A helper function which returns an iterator over all name servers in
     /// the authorities section, respresented as (domain, host) tuples
     pub fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
@@ -103,28 +327,38 @@ impl DnsPacket {
                 _ => None,
             })
             // Discard servers which aren't authorative to our query
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+            .filter(move |(domain, _)| name::is_subdomain(qname, domain))
     }
 
     /// We'll use the fact that the name servers often bundle the corresponding
-    /// A records when replying to an NS query to implement a funciton that
-    /// returns the actual IP for an NS record if possible.
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
+    /// A and AAAA records when replying to an NS query to implement a funciton
+    /// that returns the actual IP for an NS record if possible, preferably
+    /// one of `family`.
+    pub fn get_resolved_ns(&self, qname: &str, family: AddressFamily) -> Option<IpAddr> {
         // Get an iterator over the nameservers in the authorities section
-        self.get_ns(qname)
-            // Now we need to look for a matching A record in the additional
-            // section. Since we just want the first valid record, we can just
-            // build a stream of matching records
-            .find_map(|(_, host)| {
+        let mut addrs = self
+            .get_ns(qname)
+            // Now we need to look for matching A and AAAA records in the
+            // additional section
+            .flat_map(|(_, host)| {
                 self.resources
                     .iter()
-                    // Filter for A records where the domain match the host
-                    // of the NS record that we are constantly processing
-                    .find_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(*addr),
+                    // Filter for records where the domain match the host of
+                    // the NS record that we are constantly processing
+                    .filter_map(move |record| match record {
+                        DnsRecord::A { domain, addr, .. } if name::eq(domain, host) => {
+                            Some(IpAddr::V4(*addr))
+                        }
+                        DnsRecord::AAAA { domain, addr, .. } if name::eq(domain, host) => {
+                            Some(IpAddr::V6(*addr))
+                        }
                         _ => None,
                     })
             })
+            .collect::<Vec<_>>();
+
+        prefer_family(&mut addrs, family, |addr| *addr);
+        addrs.first().copied()
     }
 
     /// However, not all name servers are as that nice. In certain cases there won't
@@ -139,3 +373,183 @@ impl DnsPacket {
             .next()
     }
 }
+
+/// Builds query packets while keeping the header consistent with the contents,
+/// so that e.g. the question count can't be forgotten when adding a question.
+pub struct QueryBuilder {
+    packet: DnsPacket,
+}
+
+impl Default for QueryBuilder {
+    fn default() -> QueryBuilder {
+        QueryBuilder::new()
+    }
+}
+
+impl QueryBuilder {
+    pub fn new() -> QueryBuilder {
+        let mut packet = DnsPacket::new();
+        // The ID is one of the few things a spoofed response has to guess
+        // right, so it comes straight from the random generator of the OS.
+        packet.header.id = OsRng.gen();
+        packet.header.recursion_desired = true;
+
+        QueryBuilder { packet }
+    }
+
+    pub fn id(mut self, id: u16) -> QueryBuilder {
+        self.packet.header.id = id;
+        self
+    }
+
+    pub fn recursion_desired(mut self, recursion_desired: bool) -> QueryBuilder {
+        self.packet.header.recursion_desired = recursion_desired;
+        self
+    }
+
+    /// Signal support for EDNS, and that responses of up to `payload_size`
+    /// bytes can be received over UDP
+    pub fn edns(mut self, payload_size: u16) -> QueryBuilder {
+        self.packet.resources.push(DnsRecord::OPT {
+            packet_len: payload_size,
+            flags: 0,
+            data: Vec::new(),
+        });
+        self.packet.header.resource_entries += 1;
+        self
+    }
+
+    /// Ask for DNSSEC records along with the answer, by setting the DO bit of
+    /// the OPT record added by `edns`
+    pub fn dnssec_ok(mut self) -> QueryBuilder {
+        for rec in self.packet.resources.iter_mut() {
+            if let DnsRecord::OPT { flags, .. } = rec {
+                *flags |= DNSSEC_OK;
+            }
+        }
+        self
+    }
+
+    /// Ask on behalf of a network of clients, with the EDNS Client Subnet
+    /// option in the OPT record added by `edns`
+    pub fn client_subnet(mut self, subnet: ClientSubnet) -> QueryBuilder {
+        for rec in self.packet.resources.iter_mut() {
+            if let DnsRecord::OPT { data, .. } = rec {
+                data.extend(subnet.to_option());
+            }
+        }
+        self
+    }
+
+    pub fn question(mut self, qname: &str, qtype: QueryType) -> QueryBuilder {
+        self.packet
+            .questions
+            .push(DnsQuestion::new(qname.to_string(), qtype));
+        self.packet.header.questions += 1;
+        self
+    }
+
+    pub fn build(self) -> DnsPacket {
+        self.packet
+    }
+}
+
+/// Builds response packets while keeping the header consistent with the
+/// contents, so that the counts always match the sections and the flags of a
+/// response are set the way they should be.
+pub struct ResponseBuilder {
+    packet: DnsPacket,
+}
+
+impl Default for ResponseBuilder {
+    fn default() -> ResponseBuilder {
+        ResponseBuilder::new()
+    }
+}
+
+impl ResponseBuilder {
+    /// A response that doesn't answer any query in particular, such as the
+    /// answers made up from the local zones, which are copied into the
+    /// actual response later on
+    pub fn new() -> ResponseBuilder {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+
+        ResponseBuilder { packet }
+    }
+
+    /// A response to the query with the given header, for when nothing but
+    /// the header could be parsed. Recursion is advertised as available, as
+    /// it is for every query we answer.
+    pub fn reply_to(query: &DnsHeader) -> ResponseBuilder {
+        let mut builder = ResponseBuilder::new();
+        let header = &mut builder.packet.header;
+        header.id = query.id;
+        header.opcode = query.opcode;
+        header.recursion_desired = query.recursion_desired;
+        header.checking_disabled = query.checking_disabled;
+        header.recursion_available = true;
+
+        builder
+    }
+
+    pub fn rcode(mut self, rescode: ResultCode) -> ResponseBuilder {
+        self.packet.header.rescode = rescode;
+        self
+    }
+
+    pub fn authoritative(mut self, authoritative: bool) -> ResponseBuilder {
+        self.packet.header.authoritative_answer = authoritative;
+        self
+    }
+
+    pub fn recursion_available(mut self, recursion_available: bool) -> ResponseBuilder {
+        self.packet.header.recursion_available = recursion_available;
+        self
+    }
+
+    pub fn question(mut self, qname: &str, qtype: QueryType) -> ResponseBuilder {
+        self.packet
+            .questions
+            .push(DnsQuestion::new(qname.to_string(), qtype));
+        self
+    }
+
+    pub fn questions(
+        mut self,
+        questions: impl IntoIterator<Item = DnsQuestion>,
+    ) -> ResponseBuilder {
+        self.packet.questions.extend(questions);
+        self
+    }
+
+    pub fn answer(mut self, record: DnsRecord) -> ResponseBuilder {
+        self.packet.answers.push(record);
+        self
+    }
+
+    pub fn answers(mut self, records: impl IntoIterator<Item = DnsRecord>) -> ResponseBuilder {
+        self.packet.answers.extend(records);
+        self
+    }
+
+    pub fn authority(mut self, record: DnsRecord) -> ResponseBuilder {
+        self.packet.authorities.push(record);
+        self
+    }
+
+    pub fn additional(mut self, record: DnsRecord) -> ResponseBuilder {
+        self.packet.resources.push(record);
+        self
+    }
+
+    pub fn build(mut self) -> DnsPacket {
+        let packet = &mut self.packet;
+        packet.header.questions = packet.questions.len() as u16;
+        packet.header.answers = packet.answers.len() as u16;
+        packet.header.authoritative_entries = packet.authorities.len() as u16;
+        packet.header.resource_entries = packet.resources.len() as u16;
+
+        self.packet
+    }
+}
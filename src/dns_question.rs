@@ -1,33 +1,118 @@
-use crate::{byte_packet_buffer::BytePacketBuffer, query_type::QueryType};
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_record::{CLASS_IN, MDNS_CLASS_FLAG},
+    error::Result,
+    idna, name,
+    query_type::QueryType,
+};
 
-type Error = Box<dyn std::error::Error>;
-type Result<T> = std::result::Result<T, Error>;
-
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
+    /// The question exactly as it appeared on the wire, if it was parsed from
+    /// a packet. When set, it's written back verbatim instead of being
+    /// re-serialized from `name` and `qtype`, which preserves its class.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub raw: Option<Vec<u8>>,
+    /// The QU bit of mDNS, which asks for the response to be sent straight
+    /// back rather than to the multicast group
+    pub unicast_response: bool,
 }
 
 impl DnsQuestion {
+    /// A question for `name`, which may be an internationalized name such as
+    /// `bücher.example`, in which case it's asked for in punycode
     pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
-        DnsQuestion { name, qtype }
+        DnsQuestion {
+            name: idna::to_ascii(&name),
+            qtype,
+            raw: None,
+            unicast_response: false,
+        }
     }
 
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        let _ = buffer.read_qname(&mut self.name);
+        let start_pos = buffer.pos();
+
+        buffer.read_qname(&mut self.name)?;
         self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
-        let _ = buffer.read_u16()?; // class
+        let class = buffer.read_u16()?;
+        self.unicast_response = class & MDNS_CLASS_FLAG != 0;
+
+        // A compressed name points at some earlier part of the packet, which
+        // wouldn't hold up once the question is written somewhere else. Those
+        // are only kept in their parsed form. An uncompressed name takes up a
+        // length byte per label plus the terminating zero, on top of the four
+        // bytes of qtype and class.
+        let len = buffer.pos() - start_pos;
+        let uncompressed_len = match self.name.len() {
+            0 => 1,
+            n => n + 2,
+        };
+        self.raw = if len == uncompressed_len + 4 {
+            Some(buffer.get_range(start_pos, len)?.to_vec())
+        } else {
+            None
+        };
+
         Ok(())
     }
 
+    /// A string identifying what the question asks for, of the form
+    /// `name|qtype|class`, for keying caches and stats. Questions that only
+    /// differ in the case of their name get the same key.
+    pub fn cache_key(&self) -> String {
+        // Only questions read off the wire can be of a class other than IN
+        let class = match &self.raw {
+            Some(raw) if raw.len() >= 2 => {
+                u16::from_be_bytes([raw[raw.len() - 2], raw[raw.len() - 1]])
+            }
+            _ => 1,
+        };
+
+        format!(
+            "{}|{}|{}",
+            name::normalize(&self.name),
+            self.qtype.to_num(),
+            class
+        )
+    }
+
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        if let Some(raw) = &self.raw {
+            // A raw question is never compressed, so the name it starts with
+            // is there in full for the records after it to point at
+            buffer.remember_name(&self.name, buffer.pos());
+            for b in raw {
+                buffer.write_u8(*b)?;
+            }
+
+            return Ok(());
+        }
+
         buffer.write_qname(&self.name)?;
 
         let typenum = self.qtype.to_num();
         buffer.write_u16(typenum)?;
-        buffer.write_u16(1)?;
+        if self.unicast_response {
+            buffer.write_u16(CLASS_IN | MDNS_CLASS_FLAG)?;
+        } else {
+            buffer.write_u16(CLASS_IN)?;
+        }
 
         Ok(())
     }
 }
+
+/// Two questions are the same if they ask for the same thing, regardless of
+/// the case of their names or whether one of them still holds on to its raw
+/// bytes.
+impl PartialEq for DnsQuestion {
+    fn eq(&self, other: &Self) -> bool {
+        name::eq(&self.name, &other.name) && self.qtype == other.qtype
+    }
+}
+
+impl Eq for DnsQuestion {}
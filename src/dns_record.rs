@@ -1,17 +1,32 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
 
-use crate::{byte_packet_buffer::BytePacketBuffer, query_type::QueryType};
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    display::class_name,
+    error::{DnsError, Result},
+    query_type::QueryType,
+    svcb::SvcParams,
+};
 
-type Error = Box<dyn std::error::Error>;
-type Result<T> = std::result::Result<T, Error>;
+/// The class of the records of the internet, the only one in use
+pub const CLASS_IN: u16 = 1;
+
+/// The top bit of the class, which mDNS repurposes (RFC 6762). In a record
+/// it's the cache-flush bit, in a question it asks for a unicast response.
+pub const MDNS_CLASS_FLAG: u16 = 1 << 15;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-#[allow(dead_code)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
         qtype: u16,
+        class: u16,
         data_len: u16,
+        raw: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -29,30 +44,525 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 12
     MX {
         domain: String,
         priority: u16,
         host: String,
         ttl: u32,
     }, // 15
+    /// Free form text, e.g. SPF policies or DKIM keys. The data is made up
+    /// of one or more strings of up to 255 bytes each, which keep their
+    /// boundaries since some uses depend on them.
+    TXT {
+        domain: String,
+        data: Vec<String>,
+        ttl: u32,
+    }, // 16
+    X25 {
+        domain: String,
+        psdn_address: String,
+        ttl: u32,
+    }, // 19
+    ISDN {
+        domain: String,
+        address: String,
+        subaddress: Option<String>,
+        ttl: u32,
+    }, // 20
+    RT {
+        domain: String,
+        preference: u16,
+        host: String,
+        ttl: u32,
+    }, // 21
     AAAA {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+        ttl: u32,
+    }, // 33
+    ATMA {
+        domain: String,
+        format: u8,
+        address: Vec<u8>,
+        ttl: u32,
+    }, // 34
+    KX {
+        domain: String,
+        preference: u16,
+        exchanger: String,
+        ttl: u32,
+    }, // 36
+    /// The EDNS pseudo-record of RFC 6891. It's always owned by the root and
+    /// repurposes the class and TTL fields: the class holds the largest UDP
+    /// payload the sender can handle, while the TTL holds the upper bits of
+    /// the extended RCODE, the EDNS version and the flags.
+    OPT {
+        packet_len: u16,
+        flags: u32,
+        data: Vec<u8>,
+    }, // 41
+    /// The digest of a DNSKEY of a child zone, held by its parent to vouch
+    /// for the key (RFC 4034)
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    }, // 43
+    /// The signature over an RRset, made with a key of the `signer` zone.
+    /// The validity period is in seconds since the epoch, modulo 2^32.
+    RRSIG {
+        domain: String,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    }, // 46
+    /// Proves that no names exist between `domain` and `next` in the zone, and
+    /// that `domain` only has records of the listed types
+    NSEC {
+        domain: String,
+        next: String,
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 47
+    /// A public key of a zone, for validating the signatures of its records
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    }, // 48
+    /// Like NSEC, but between hashes of the names to keep them from being
+    /// enumerated (RFC 5155). The first label of `domain` is the hash of the
+    /// name itself.
+    NSEC3 {
+        domain: String,
+        algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 50
+    /// Points clients at the endpoints of a service, along with the
+    /// parameters for connecting to them. A priority of 0 makes it an alias
+    /// for `target`, in which case there are no params.
+    SVCB {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: SvcParams,
+        ttl: u32,
+    }, // 64
+    /// The same as SVCB, specifically for HTTPS origins
+    HTTPS {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: SvcParams,
+        ttl: u32,
+    }, // 65
+    /// The ILNP records of RFC 6742. NID holds the identifier of a node, while
+    /// L32 and L64 hold the locators of the subnets it can be reached on,
+    /// and LP names another node that holds them.
+    NID {
+        domain: String,
+        preference: u16,
+        node_id: u64,
+        ttl: u32,
+    }, // 104
+    L32 {
+        domain: String,
+        preference: u16,
+        locator: Ipv4Addr,
+        ttl: u32,
+    }, // 105
+    L64 {
+        domain: String,
+        preference: u16,
+        locator: u64,
+        ttl: u32,
+    }, // 106
+    LP {
+        domain: String,
+        preference: u16,
+        host: String,
+        ttl: u32,
+    }, // 107
+    /// Which certificate authorities may issue certificates for the domain
+    /// (RFC 8659). Like the character strings of TXT records, the value has
+    /// to be UTF-8, records with other values are relayed as they came.
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: String,
+        ttl: u32,
+    }, // 257
 }
 
 impl DnsRecord {
+    /// The type of this record, as it would appear on the wire
+    pub fn query_type(&self) -> QueryType {
+        match *self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::X25 { .. } => QueryType::X25,
+            DnsRecord::ISDN { .. } => QueryType::ISDN,
+            DnsRecord::RT { .. } => QueryType::RT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::ATMA { .. } => QueryType::ATMA,
+            DnsRecord::KX { .. } => QueryType::KX,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::SVCB { .. } => QueryType::SVCB,
+            DnsRecord::HTTPS { .. } => QueryType::HTTPS,
+            DnsRecord::NID { .. } => QueryType::NID,
+            DnsRecord::L32 { .. } => QueryType::L32,
+            DnsRecord::L64 { .. } => QueryType::L64,
+            DnsRecord::LP { .. } => QueryType::LP,
+            DnsRecord::CAA { .. } => QueryType::CAA,
+        }
+    }
+
+    /// The name this record belongs to. OPT records always belong to the root.
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::X25 { domain, .. }
+            | DnsRecord::ISDN { domain, .. }
+            | DnsRecord::RT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. }
+            | DnsRecord::ATMA { domain, .. }
+            | DnsRecord::KX { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::SVCB { domain, .. }
+            | DnsRecord::HTTPS { domain, .. }
+            | DnsRecord::NID { domain, .. }
+            | DnsRecord::L32 { domain, .. }
+            | DnsRecord::L64 { domain, .. }
+            | DnsRecord::LP { domain, .. }
+            | DnsRecord::CAA { domain, .. } => domain,
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    /// The address of an A or AAAA record
+    pub fn ip_addr(&self) -> Option<IpAddr> {
+        match self {
+            DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+            DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+            _ => None,
+        }
+    }
+
+    /// Move the record to another name, which is left alone for OPT records
+    pub fn set_domain(&mut self, name: String) {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::X25 { domain, .. }
+            | DnsRecord::ISDN { domain, .. }
+            | DnsRecord::RT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. }
+            | DnsRecord::ATMA { domain, .. }
+            | DnsRecord::KX { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::SVCB { domain, .. }
+            | DnsRecord::HTTPS { domain, .. }
+            | DnsRecord::NID { domain, .. }
+            | DnsRecord::L32 { domain, .. }
+            | DnsRecord::L64 { domain, .. }
+            | DnsRecord::LP { domain, .. }
+            | DnsRecord::CAA { domain, .. } => *domain = name,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    /// A copy of the record with its owner and the names in its data in
+    /// lowercase, the canonical form of RFC 4034, section 6.2. Records that
+    /// only differ in the case of their names hold the same data, and they
+    /// get the same signature. The next name of NSEC records and the names
+    /// of the types that came later keep their case (RFC 6840, section 5.1).
+    pub fn lowercase_names(&self) -> DnsRecord {
+        let mut record = self.clone();
+        match &mut record {
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. }
+            | DnsRecord::MX { host, .. }
+            | DnsRecord::RT { host, .. }
+            | DnsRecord::SRV { host, .. }
+            | DnsRecord::KX {
+                exchanger: host, ..
+            }
+            | DnsRecord::RRSIG { signer: host, .. } => host.make_ascii_lowercase(),
+            DnsRecord::SOA { mname, rname, .. } => {
+                mname.make_ascii_lowercase();
+                rname.make_ascii_lowercase();
+            }
+            _ => {}
+        }
+        let domain = record.domain().to_ascii_lowercase();
+        record.set_domain(domain);
+
+        record
+    }
+
+    /// The record data in zone file format, e.g. `10 mail.example.com.` for
+    /// an MX record, which is what `Display` writes after the type
+    pub fn data(&self) -> String {
+        RecordData(self).to_string()
+    }
+
+    /// The class of the record, which is IN for all but the records of
+    /// unknown types and classes, as they came in. The class of OPT records
+    /// holds their payload size instead, see `DnsRecord::OPT`.
+    pub fn class(&self) -> u16 {
+        match self {
+            DnsRecord::UNKNOWN { class, .. } => *class,
+            _ => CLASS_IN,
+        }
+    }
+
+    /// How long this record may be cached for. The TTL field of OPT records
+    /// holds flags instead, and they must never be cached.
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::X25 { ttl, .. }
+            | DnsRecord::ISDN { ttl, .. }
+            | DnsRecord::RT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::ATMA { ttl, .. }
+            | DnsRecord::KX { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. }
+            | DnsRecord::HTTPS { ttl, .. }
+            | DnsRecord::NID { ttl, .. }
+            | DnsRecord::L32 { ttl, .. }
+            | DnsRecord::L64 { ttl, .. }
+            | DnsRecord::LP { ttl, .. }
+            | DnsRecord::CAA { ttl, .. } => ttl,
+            DnsRecord::OPT { .. } => 0,
+        }
+    }
+
+    /// Change how long the record may be cached for, which is left alone
+    /// for OPT records
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::X25 { ttl, .. }
+            | DnsRecord::ISDN { ttl, .. }
+            | DnsRecord::RT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::ATMA { ttl, .. }
+            | DnsRecord::KX { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. }
+            | DnsRecord::HTTPS { ttl, .. }
+            | DnsRecord::NID { ttl, .. }
+            | DnsRecord::L32 { ttl, .. }
+            | DnsRecord::L64 { ttl, .. }
+            | DnsRecord::LP { ttl, .. }
+            | DnsRecord::CAA { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    /// Take the seconds a record spent in a cache off its TTL, leaving at
+    /// least one, since a TTL of 0 would tell the client not to cache it at
+    /// all. OPT records are left alone.
+    pub fn age(&mut self, elapsed: u32) {
+        if !matches!(self, DnsRecord::OPT { .. }) {
+            self.set_ttl(self.ttl().saturating_sub(elapsed).max(1));
+        }
+    }
+
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<DnsRecord> {
+        let (record, class) = DnsRecord::read_any_class(buffer)?;
+        DnsRecord::in_class(record, class)
+    }
+
+    /// Read a record as if it were of class IN, along with the class it
+    /// has, for e.g. the records UPDATE messages delete, of class NONE
+    pub(crate) fn read_any_class(buffer: &mut BytePacketBuffer) -> Result<(DnsRecord, u16)> {
         let mut domain = String::new();
         buffer.read_qname(&mut domain)?;
 
         let qtype_num = buffer.read_u16()?;
-        let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
+        let start_pos = buffer.pos();
+        let error =
+            match DnsRecord::read_data(buffer, domain.clone(), qtype_num, class, ttl, data_len) {
+                Ok(record) if buffer.pos() == start_pos + data_len as usize => {
+                    return Ok((record, class));
+                }
+                Ok(record) => DnsError::InvalidRecord(format!(
+                    "{} record at offset {} doesn't fill its {} bytes of data",
+                    record.query_type(),
+                    start_pos,
+                    data_len
+                )),
+                Err(e) => e,
+            };
+
+        // Data we can't make sense of, such as a string that isn't UTF-8 or
+        // an A record of other than four bytes, is passed on as it is, the
+        // same as the data of the types we don't know. So is the empty data
+        // of the records that UPDATE messages delete whole RRsets with.
+        // Reading on from wherever the type left off would get every record
+        // after it wrong. That's unless the type has names in its data,
+        // which may be compressed: their pointers would point somewhere else
+        // entirely in the message the data is passed on in.
+        if data_len > 0 && holds_names(QueryType::from_num(qtype_num)) {
+            return Err(error);
+        }
+        buffer.seek(start_pos)?;
+        let record = DnsRecord::UNKNOWN {
+            domain,
+            qtype: qtype_num,
+            class,
+            data_len,
+            raw: buffer.read_bytes(data_len as usize)?,
+            ttl,
+        };
+
+        Ok((record, class))
+    }
+
+    /// A record read as if it were of class IN, in the class it came in as.
+    /// Records of other classes, such as the CH TXT records that servers
+    /// tell their version with, are passed on as the data of their type. The
+    /// cache-flush bit of mDNS doesn't make for another class.
+    fn in_class(record: DnsRecord, class: u16) -> Result<DnsRecord> {
+        match record {
+            record if class & !MDNS_CLASS_FLAG == CLASS_IN => Ok(record),
+            record @ (DnsRecord::OPT { .. } | DnsRecord::UNKNOWN { .. }) => Ok(record),
+            record => {
+                let raw = record.rdata()?;
+                Ok(DnsRecord::UNKNOWN {
+                    domain: record.domain().to_string(),
+                    qtype: record.query_type().to_num(),
+                    class,
+                    data_len: raw.len() as u16,
+                    raw,
+                    ttl: record.ttl(),
+                })
+            }
+        }
+    }
+
+    /// The data of a record, of the type and length given by the fields that
+    /// come before it
+    fn read_data(
+        buffer: &mut BytePacketBuffer,
+        domain: String,
+        qtype_num: u16,
+        class: u16,
+        ttl: u32,
+        data_len: u16,
+    ) -> Result<DnsRecord> {
+        let qtype = QueryType::from_num(qtype_num);
+
         match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
@@ -60,7 +570,7 @@ impl DnsRecord {
                     ((raw_addr >> 24) & 0xFF) as u8,
                     ((raw_addr >> 16) & 0xFF) as u8,
                     ((raw_addr >> 8) & 0xFF) as u8,
-                    ((raw_addr >> 0) & 0xFF) as u8,
+                    (raw_addr & 0xFF) as u8,
                 );
 
                 Ok(DnsRecord::A { domain, addr, ttl })
@@ -72,13 +582,13 @@ impl DnsRecord {
                 let raw_addr4 = buffer.read_u32()?;
                 let addr = Ipv6Addr::new(
                     ((raw_addr1 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr1 >> 0) & 0xFFFF) as u16,
+                    (raw_addr1 & 0xFFFF) as u16,
                     ((raw_addr2 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr2 >> 0) & 0xFFFF) as u16,
+                    (raw_addr2 & 0xFFFF) as u16,
                     ((raw_addr3 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr3 >> 0) & 0xFFFF) as u16,
+                    (raw_addr3 & 0xFFFF) as u16,
                     ((raw_addr4 >> 16) & 0xFFFF) as u16,
-                    ((raw_addr4 >> 0) & 0xFFFF) as u16,
+                    (raw_addr4 & 0xFFFF) as u16,
                 );
 
                 Ok(DnsRecord::AAAA { domain, addr, ttl })
@@ -103,6 +613,30 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
+
+                Ok(DnsRecord::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::PTR { domain, host, ttl })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let mut mx = String::new();
@@ -115,13 +649,344 @@ impl DnsRecord {
                     ttl,
                 })
             }
-            QueryType::UNKNOWN(_) => {
-                buffer.step(data_len as usize)?;
+            QueryType::TXT => {
+                // The strings follow each other until the record runs out
+                let end = buffer.pos() + data_len as usize;
+                let mut data = Vec::new();
+                while buffer.pos() < end {
+                    let mut text = String::new();
+                    buffer.read_character_string(&mut text)?;
+                    data.push(text);
+                }
+                if buffer.pos() != end {
+                    return Err(DnsError::InvalidRecord(
+                        "TXT string runs past the end of the record".to_string(),
+                    ));
+                }
+
+                Ok(DnsRecord::TXT { domain, data, ttl })
+            }
+            QueryType::X25 => {
+                let mut psdn_address = String::new();
+                buffer.read_character_string(&mut psdn_address)?;
+
+                Ok(DnsRecord::X25 {
+                    domain,
+                    psdn_address,
+                    ttl,
+                })
+            }
+            QueryType::ISDN => {
+                let start_pos = buffer.pos();
+
+                let mut address = String::new();
+                buffer.read_character_string(&mut address)?;
+
+                // The subaddress is optional, so it's only present if the
+                // address didn't consume the whole record.
+                let subaddress = if buffer.pos() - start_pos < data_len as usize {
+                    let mut sa = String::new();
+                    buffer.read_character_string(&mut sa)?;
+                    Some(sa)
+                } else {
+                    None
+                };
+
+                Ok(DnsRecord::ISDN {
+                    domain,
+                    address,
+                    subaddress,
+                    ttl,
+                })
+            }
+            QueryType::RT => {
+                let preference = buffer.read_u16()?;
+                let mut rt = String::new();
+                buffer.read_qname(&mut rt)?;
+
+                Ok(DnsRecord::RT {
+                    domain,
+                    preference,
+                    host: rt,
+                    ttl,
+                })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    host,
+                    ttl,
+                })
+            }
+            QueryType::ATMA => {
+                if data_len == 0 {
+                    return Err(DnsError::InvalidRecord(
+                        "ATMA record is missing its format byte".to_string(),
+                    ));
+                }
+
+                let format = buffer.read_u8()?;
+                let address = buffer.read_bytes(data_len as usize - 1)?;
+
+                Ok(DnsRecord::ATMA {
+                    domain,
+                    format,
+                    address,
+                    ttl,
+                })
+            }
+            QueryType::KX => {
+                let preference = buffer.read_u16()?;
+                let mut exchanger = String::new();
+                buffer.read_qname(&mut exchanger)?;
+
+                Ok(DnsRecord::KX {
+                    domain,
+                    preference,
+                    exchanger,
+                    ttl,
+                })
+            }
+            QueryType::OPT => {
+                let data = buffer.read_bytes(data_len as usize)?;
+
+                Ok(DnsRecord::OPT {
+                    packet_len: class,
+                    flags: ttl,
+                    data,
+                })
+            }
+            QueryType::DS => {
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read_u8()?;
+                let digest_type = buffer.read_u8()?;
+                let digest = buffer.read_bytes(remaining(data_len, 4, "DS")?)?;
+
+                Ok(DnsRecord::DS {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                })
+            }
+            QueryType::RRSIG => {
+                let start_pos = buffer.pos();
+
+                let type_covered = QueryType::from_num(buffer.read_u16()?);
+                let algorithm = buffer.read_u8()?;
+                let labels = buffer.read_u8()?;
+                let original_ttl = buffer.read_u32()?;
+                let expiration = buffer.read_u32()?;
+                let inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let mut signer = String::new();
+                buffer.read_qname(&mut signer)?;
+                let signature_len = remaining(data_len, buffer.pos() - start_pos, "RRSIG")?;
+                let signature = buffer.read_bytes(signature_len)?;
+
+                Ok(DnsRecord::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer,
+                    signature,
+                    ttl,
+                })
+            }
+            QueryType::NSEC => {
+                let start_pos = buffer.pos();
+
+                let mut next = String::new();
+                buffer.read_qname(&mut next)?;
+                let bitmap_len = remaining(data_len, buffer.pos() - start_pos, "NSEC")?;
+                let types = read_type_bitmap(&buffer.read_bytes(bitmap_len)?)?;
+
+                Ok(DnsRecord::NSEC {
+                    domain,
+                    next,
+                    types,
+                    ttl,
+                })
+            }
+            QueryType::DNSKEY => {
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read_u8()?;
+                let algorithm = buffer.read_u8()?;
+                let public_key = buffer.read_bytes(remaining(data_len, 4, "DNSKEY")?)?;
+
+                Ok(DnsRecord::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3 => {
+                let start_pos = buffer.pos();
+
+                let algorithm = buffer.read_u8()?;
+                let flags = buffer.read_u8()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read_u8()? as usize;
+                let salt = buffer.read_bytes(salt_len)?;
+                let hash_len = buffer.read_u8()? as usize;
+                let next_hashed = buffer.read_bytes(hash_len)?;
+                let bitmap_len = remaining(data_len, buffer.pos() - start_pos, "NSEC3")?;
+                let types = read_type_bitmap(&buffer.read_bytes(bitmap_len)?)?;
+
+                Ok(DnsRecord::NSEC3 {
+                    domain,
+                    algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed,
+                    types,
+                    ttl,
+                })
+            }
+            QueryType::SVCB | QueryType::HTTPS => {
+                let start_pos = buffer.pos();
+
+                let priority = buffer.read_u16()?;
+                let mut target = String::new();
+                buffer.read_qname(&mut target)?;
+
+                // Like the CAA value, the params fill up the rest of the record
+                let params_len = (data_len as usize)
+                    .checked_sub(buffer.pos() - start_pos)
+                    .ok_or_else(|| {
+                        DnsError::InvalidRecord(
+                            "SVCB target runs past the end of the record".to_string(),
+                        )
+                    })?;
+                let params = SvcParams::read(buffer, params_len)?;
+
+                if qtype == QueryType::SVCB {
+                    Ok(DnsRecord::SVCB {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    })
+                } else {
+                    Ok(DnsRecord::HTTPS {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    })
+                }
+            }
+            QueryType::NID => {
+                let preference = buffer.read_u16()?;
+                let node_id = buffer.read_u64()?;
+
+                Ok(DnsRecord::NID {
+                    domain,
+                    preference,
+                    node_id,
+                    ttl,
+                })
+            }
+            QueryType::L32 => {
+                let preference = buffer.read_u16()?;
+                let locator = Ipv4Addr::from(buffer.read_u32()?);
+
+                Ok(DnsRecord::L32 {
+                    domain,
+                    preference,
+                    locator,
+                    ttl,
+                })
+            }
+            QueryType::L64 => {
+                let preference = buffer.read_u16()?;
+                let locator = buffer.read_u64()?;
+
+                Ok(DnsRecord::L64 {
+                    domain,
+                    preference,
+                    locator,
+                    ttl,
+                })
+            }
+            QueryType::LP => {
+                let preference = buffer.read_u16()?;
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(DnsRecord::LP {
+                    domain,
+                    preference,
+                    host,
+                    ttl,
+                })
+            }
+            QueryType::CAA => {
+                let start_pos = buffer.pos();
+
+                let flags = buffer.read_u8()?;
+                let mut tag = String::new();
+                buffer.read_character_string(&mut tag)?;
+
+                // Unlike the tag, the value isn't length prefixed. It simply
+                // fills up the rest of the record, so we have to rely on the
+                // record length to know where it ends.
+                let value_len = (data_len as usize)
+                    .checked_sub(buffer.pos() - start_pos)
+                    .ok_or_else(|| {
+                        DnsError::InvalidRecord(
+                            "CAA tag runs past the end of the record".to_string(),
+                        )
+                    })?;
+                let value = buffer.get_range(buffer.pos(), value_len)?;
+                let value = String::from_utf8(value.to_vec())
+                    .map_err(|_| DnsError::InvalidRecord("CAA value isn't UTF-8".to_string()))?;
+                buffer.step(value_len)?;
+
+                Ok(DnsRecord::CAA {
+                    domain,
+                    flags,
+                    tag,
+                    value,
+                    ttl,
+                })
+            }
+            // ANY and the zone transfers only ever appear in questions, a
+            // record claiming to be of one of those types is as good as one
+            // of an unknown type
+            QueryType::UNKNOWN(_) | QueryType::ANY | QueryType::IXFR | QueryType::AXFR => {
+                // We don't know how to interpret the data, but we hold on to
+                // it anyway so that the record can be passed on unchanged.
+                let raw = buffer.read_bytes(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
+                    class,
                     data_len,
+                    raw,
                     ttl,
                 })
             }
@@ -129,6 +994,33 @@ impl DnsRecord {
     }
 
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+        self.write_class(buffer, self.class())
+    }
+
+    /// Write the record with the cache-flush bit of mDNS set (RFC 6762),
+    /// which tells those who receive it that it replaces all records of the
+    /// same name and type they have, rather than adding to them
+    pub fn write_cache_flush(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
+        self.write_class(buffer, self.class() | MDNS_CLASS_FLAG)
+    }
+
+    /// The data of the record on its own, without any compressed names, as
+    /// the generic notation of RFC 3597 holds it
+    pub fn rdata(&self) -> Result<Vec<u8>> {
+        let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+        buffer.disable_compression();
+        self.write(&mut buffer)?;
+
+        let mut buffer = BytePacketBuffer::from_slice(&buffer.buf[..buffer.pos()]);
+        buffer.read_qname(&mut String::new())?;
+        buffer.step(8)?;
+        let data_len = buffer.read_u16()?;
+        buffer.read_bytes(data_len as usize)
+    }
+
+    /// Write the record with `class` in place of its own, such as the NONE
+    /// of the records UPDATE messages delete
+    pub(crate) fn write_class(&self, buffer: &mut BytePacketBuffer, class: u16) -> Result<usize> {
         let start_pos = buffer.pos();
 
         match *self {
@@ -139,7 +1031,7 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class)?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(4)?;
 
@@ -156,7 +1048,7 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::NS.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class)?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -174,7 +1066,55 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::CNAME.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(class)?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -193,7 +1133,7 @@ impl DnsRecord {
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::MX.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class)?;
                 buffer.write_u32(ttl)?;
 
                 let pos = buffer.pos();
@@ -205,26 +1145,853 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
-            DnsRecord::AAAA {
+            DnsRecord::TXT {
                 ref domain,
-                ref addr,
+                ref data,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
-                buffer.write_u16(QueryType::AAAA.to_num())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(class)?;
                 buffer.write_u32(ttl)?;
-                buffer.write_u16(16)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for text in data {
+                    buffer.write_character_string(text)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::X25 {
+                ref domain,
+                ref psdn_address,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::X25.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_character_string(psdn_address)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::ISDN {
+                ref domain,
+                ref address,
+                ref subaddress,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::ISDN.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_character_string(address)?;
+                if let Some(sa) = subaddress {
+                    buffer.write_character_string(sa)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::RT {
+                ref domain,
+                preference,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::RT.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(preference)?;
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::ATMA {
+                ref domain,
+                format,
+                ref address,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::ATMA.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(1 + address.len() as u16)?;
+
+                buffer.write_u8(format)?;
+                for b in address {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::KX {
+                ref domain,
+                preference,
+                ref exchanger,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::KX.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // The exchanger must never be compressed (RFC 2230, section 3.1)
+                buffer.write_u16(preference)?;
+                buffer.write_qname_uncompressed(exchanger)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::AAAA {
+                ref domain,
+                ref addr,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::AAAA.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(16)?;
 
                 for octet in &addr.segments() {
                     buffer.write_u16(*octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                ref data,
+            } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::DS {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DS.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + digest.len() as u16)?;
+
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                for b in digest {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::RRSIG {
+                ref domain,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer,
+                ref signature,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::RRSIG.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // The signer must never be compressed (RFC 4034, section 3.1.7)
+                buffer.write_u16(type_covered.to_num())?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(labels)?;
+                buffer.write_u32(original_ttl)?;
+                buffer.write_u32(expiration)?;
+                buffer.write_u32(inception)?;
+                buffer.write_u16(key_tag)?;
+                buffer.write_qname_uncompressed(signer)?;
+                for b in signature {
+                    buffer.write_u8(*b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NSEC {
+                ref domain,
+                ref next,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // Nor may the next name (RFC 4034, section 4.1.1)
+                buffer.write_qname_uncompressed(next)?;
+                for b in write_type_bitmap(types) {
+                    buffer.write_u8(b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::DNSKEY {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNSKEY.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + public_key.len() as u16)?;
+
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                for b in public_key {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::NSEC3 {
+                ref domain,
+                algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write_u8(salt.len() as u8)?;
+                for b in salt {
+                    buffer.write_u8(*b)?;
+                }
+                buffer.write_u8(next_hashed.len() as u8)?;
+                for b in next_hashed {
+                    buffer.write_u8(*b)?;
+                }
+                for b in write_type_bitmap(types) {
+                    buffer.write_u8(b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SVCB {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                ttl,
+            }
+            | DnsRecord::HTTPS {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(self.query_type().to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // The target must not be compressed (RFC 9460, section 2.2)
+                buffer.write_u16(priority)?;
+                buffer.write_qname_uncompressed(target)?;
+                params.write(buffer)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NID {
+                ref domain,
+                preference,
+                node_id: value,
+                ttl,
+            }
+            | DnsRecord::L64 {
+                ref domain,
+                preference,
+                locator: value,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(self.query_type().to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(10)?;
+
+                buffer.write_u16(preference)?;
+                buffer.write_u64(value)?;
+            }
+            DnsRecord::L32 {
+                ref domain,
+                preference,
+                locator,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::L32.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(6)?;
+
+                buffer.write_u16(preference)?;
+                buffer.write_u32(u32::from(locator))?;
+            }
+            DnsRecord::LP {
+                ref domain,
+                preference,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::LP.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(preference)?;
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::CAA {
+                ref domain,
+                flags,
+                ref tag,
+                ref value,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CAA.to_num())?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u8(flags)?;
+                buffer.write_character_string(tag)?;
+                for b in value.as_bytes() {
+                    buffer.write_u8(*b)?;
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref raw,
+                ttl,
+                ..
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(raw.len() as u16)?;
+
+                for b in raw {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
         Ok(buffer.pos() - start_pos)
     }
 }
+
+/// Whether the data of records of the type holds names, which may have been
+/// compressed
+fn holds_names(qtype: QueryType) -> bool {
+    matches!(
+        qtype,
+        QueryType::NS
+            | QueryType::CNAME
+            | QueryType::SOA
+            | QueryType::PTR
+            | QueryType::MX
+            | QueryType::RT
+            | QueryType::SRV
+            | QueryType::KX
+            | QueryType::RRSIG
+            | QueryType::NSEC
+            | QueryType::SVCB
+            | QueryType::HTTPS
+            | QueryType::LP
+    )
+}
+
+/// The length of what's left of a record after the first `used` bytes of
+/// its data
+fn remaining(data_len: u16, used: usize, qtype: &str) -> Result<usize> {
+    (data_len as usize).checked_sub(used).ok_or_else(|| {
+        DnsError::InvalidRecord(format!("{} record is shorter than its fields", qtype))
+    })
+}
+
+/// Read the types of an NSEC or NSEC3 record. They're grouped in windows of
+/// 256 types, each of which is a window number and a bitmap of up to 32
+/// bytes, with the most significant bit standing for the lowest type.
+fn read_type_bitmap(bytes: &[u8]) -> Result<Vec<QueryType>> {
+    let mut types = Vec::new();
+
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let (window, len) = match bytes.get(pos..pos + 2) {
+            Some(&[window, len]) if (1..=32).contains(&len) => (window as u16, len as usize),
+            _ => {
+                return Err(DnsError::InvalidRecord(
+                    "Invalid window in type bitmap".to_string(),
+                ))
+            }
+        };
+        let bitmap = bytes.get(pos + 2..pos + 2 + len).ok_or_else(|| {
+            DnsError::InvalidRecord("Type bitmap runs past the end of the record".to_string())
+        })?;
+
+        for (i, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(QueryType::from_num(window * 256 + (i * 8 + bit) as u16));
+                }
+            }
+        }
+        pos += 2 + len;
+    }
+
+    Ok(types)
+}
+
+fn write_type_bitmap(types: &[QueryType]) -> Vec<u8> {
+    let mut nums: Vec<u16> = types.iter().map(|qtype| qtype.to_num()).collect();
+    nums.sort_unstable();
+    nums.dedup();
+
+    let mut bytes = Vec::new();
+    for window in nums.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let mut bitmap = [0u8; 32];
+        for num in window {
+            let low = (num & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+        }
+        let len = window.last().map_or(0, |num| (num & 0xFF) as usize / 8 + 1);
+
+        bytes.push((window[0] >> 8) as u8);
+        bytes.push(len as u8);
+        bytes.extend_from_slice(&bitmap[..len]);
+    }
+
+    bytes
+}
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding, as used for keys and signatures in zone
+/// files
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// The bytes of standard base64, with or without padding, or `None` if `s`
+/// isn't base64
+pub(crate) fn unbase64(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bits = 0u32;
+    let mut len = 0;
+    for c in s.trim_end_matches('=').bytes() {
+        let value = BASE64.iter().position(|b| *b == c)? as u32;
+        bits = bits << 6 | value;
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            decoded.push((bits >> len) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// The "extended hex" base32 of RFC 4648 without padding, which NSEC3 uses
+/// for hashed names since it sorts the same way as the hashes do
+pub(crate) fn base32hex(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(5) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u64, |n, (i, &b)| n | (b as u64) << (32 - 8 * i));
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            encoded.push(ALPHABET[(n >> (35 - 5 * i) & 0x1F) as usize] as char);
+        }
+    }
+
+    encoded
+}
+
+/// A name the way it's written in zone files, fully qualified
+pub(crate) fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+/// A character string the way it's written in zone files, in double quotes
+/// and with anything but printable ASCII escaped
+pub(crate) fn quote(bytes: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(b as char);
+            }
+            0x20..=0x7e => quoted.push(b as char),
+            _ => quoted.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A 64 bit ILNP identifier or locator, as four groups of hex digits
+fn hex64(value: u64) -> String {
+    let groups: Vec<String> = (0..4)
+        .rev()
+        .map(|i| format!("{:04x}", (value >> (i * 16)) as u16))
+        .collect();
+
+    groups.join(":")
+}
+
+/// The record the way it's written in zone files, e.g.
+/// `example.com. 300 IN A 93.184.216.34`, with tabs in between the fields.
+/// Record data of unknown types is written in the generic notation of RFC
+/// 3597. For an OPT record, the class holds the payload size instead.
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let class = match self {
+            DnsRecord::OPT { packet_len, .. } => format!("CLASS{}", packet_len),
+            record => class_name(record.class()),
+        };
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t",
+            fqdn(self.domain()),
+            self.ttl(),
+            class,
+            self.query_type()
+        )?;
+
+        write!(f, "{}", RecordData(self))
+    }
+}
+
+/// Just the record data of a record, the last of the fields it's written with
+struct RecordData<'a>(&'a DnsRecord);
+
+impl fmt::Display for RecordData<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            DnsRecord::UNKNOWN { raw, .. } => write!(f, "\\# {} {}", raw.len(), hex(raw)),
+            DnsRecord::A { addr, .. } => write!(f, "{}", addr),
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. } => write!(f, "{}", fqdn(host)),
+            DnsRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(mname),
+                fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::MX { priority, host, .. } => write!(f, "{} {}", priority, fqdn(host)),
+            DnsRecord::TXT { data, .. } => {
+                let strings: Vec<String> = data.iter().map(|s| quote(s.as_bytes())).collect();
+                write!(f, "{}", strings.join(" "))
+            }
+            DnsRecord::X25 { psdn_address, .. } => write!(f, "{}", quote(psdn_address.as_bytes())),
+            DnsRecord::ISDN {
+                address,
+                subaddress,
+                ..
+            } => match subaddress {
+                Some(subaddress) => write!(
+                    f,
+                    "{} {}",
+                    quote(address.as_bytes()),
+                    quote(subaddress.as_bytes())
+                ),
+                None => write!(f, "{}", quote(address.as_bytes())),
+            },
+            DnsRecord::RT {
+                preference, host, ..
+            }
+            | DnsRecord::LP {
+                preference, host, ..
+            } => write!(f, "{} {}", preference, fqdn(host)),
+            DnsRecord::KX {
+                preference,
+                exchanger,
+                ..
+            } => write!(f, "{} {}", preference, fqdn(exchanger)),
+            DnsRecord::AAAA { addr, .. } => write!(f, "{}", addr),
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                host,
+                ..
+            } => write!(f, "{} {} {} {}", priority, weight, port, fqdn(host)),
+            // E.164 numbers are digits, anything else is an NSAP address
+            DnsRecord::ATMA {
+                format: 1, address, ..
+            } => write!(f, "+{}", String::from_utf8_lossy(address)),
+            DnsRecord::ATMA { address, .. } => write!(f, "{}", hex(address)),
+            DnsRecord::OPT { data, .. } => write!(f, "\\# {} {}", data.len(), hex(data)),
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                hex(digest)
+            ),
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {} {} {}",
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                fqdn(signer),
+                base64(signature)
+            ),
+            DnsRecord::NSEC { next, types, .. } => {
+                write!(f, "{}", fqdn(next))?;
+                for qtype in types {
+                    write!(f, " {}", qtype)?;
+                }
+                Ok(())
+            }
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                base64(public_key)
+            ),
+            DnsRecord::NSEC3 {
+                algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+                ..
+            } => {
+                let salt = match salt.is_empty() {
+                    true => "-".to_string(),
+                    false => hex(salt),
+                };
+                write!(
+                    f,
+                    "{} {} {} {} {}",
+                    algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    base32hex(next_hashed)
+                )?;
+                for qtype in types {
+                    write!(f, " {}", qtype)?;
+                }
+                Ok(())
+            }
+            DnsRecord::SVCB {
+                priority,
+                target,
+                params,
+                ..
+            }
+            | DnsRecord::HTTPS {
+                priority,
+                target,
+                params,
+                ..
+            } => {
+                write!(f, "{} {}", priority, fqdn(target))?;
+                match params.to_string() {
+                    params if params.is_empty() => Ok(()),
+                    params => write!(f, " {}", params),
+                }
+            }
+            DnsRecord::NID {
+                preference,
+                node_id,
+                ..
+            } => write!(f, "{} {}", preference, hex64(*node_id)),
+            DnsRecord::L32 {
+                preference,
+                locator,
+                ..
+            } => write!(f, "{} {}", preference, locator),
+            DnsRecord::L64 {
+                preference,
+                locator,
+                ..
+            } => write!(f, "{} {}", preference, hex64(*locator)),
+            DnsRecord::CAA {
+                flags, tag, value, ..
+            } => write!(f, "{} {} {}", flags, tag, quote(value.as_bytes())),
+        }
+    }
+}
@@ -0,0 +1,1120 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+use ring::{digest, signature};
+
+use crate::{
+    byte_packet_buffer::{label_bytes, BytePacketBuffer},
+    dns_packet::DnsPacket,
+    dns_record::{self, DnsRecord},
+    error::{DnsError, Result},
+    name::{self, is_subdomain},
+    query_type::QueryType,
+    resolver::{self, ResolverOptions},
+    result_code::ResultCode,
+};
+
+/// The DS records of the keys the root zone is signed with, as published by
+/// IANA
+const ROOT_ANCHORS: [&str; 2] = [
+    ". IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    ". IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+];
+
+/// NSEC3 records with more iterations than this are treated as if their zone
+/// were unsigned rather than spending the CPU on hashing, as recommended by
+/// RFC 9276
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// The longest the keys of a zone are remembered for, whatever their TTL
+const MAX_KEY_TTL: u32 = 3600;
+
+/// The flag of a DNSKEY that is used for signing records of its zone
+const ZONE_KEY: u16 = 1 << 8;
+
+/// The flag of an NSEC3 record that covers unsigned delegations
+const OPT_OUT: u8 = 1;
+
+/// How trustworthy a response turned out to be. Responses that fail
+/// validation are an `Err(DnsError::Dnssec)` instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Validation {
+    /// Signed by keys that are vouched for all the way up to a trust anchor
+    Secure,
+    /// From a zone that isn't signed, as proven by a signed response from a
+    /// zone above it
+    Insecure,
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Validation::Secure => write!(f, "secure"),
+            Validation::Insecure => write!(f, "insecure"),
+        }
+    }
+}
+
+/// The keys of a zone that are known to be good, or `None` for a zone that
+/// is known to be unsigned
+type ZoneKeys = Option<Vec<DnsRecord>>;
+
+/// Validates responses by checking their signatures against the keys of the
+/// zones they come from (RFC 4035). The keys are checked in turn against the
+/// DS records of the zone above, up to a trust anchor, which are the keys of
+/// the root zone unless more are added. That takes some more lookups, for
+/// the DNSKEY and DS records along the way, so the keys that check out are
+/// remembered for the TTL of their records.
+///
+/// Clones share the keys they remember.
+#[derive(Clone, Debug)]
+pub struct Validator {
+    anchors: Vec<DnsRecord>,
+    keys: Arc<Mutex<HashMap<String, (Instant, ZoneKeys)>>>,
+}
+
+impl Default for Validator {
+    fn default() -> Validator {
+        Validator::new()
+    }
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        let anchors = ROOT_ANCHORS
+            .iter()
+            .map(|anchor| parse_anchor(anchor).expect("root trust anchors are valid"))
+            .collect();
+
+        Validator {
+            anchors,
+            keys: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Trust the key with the given DS record, in zone file format without
+    /// the TTL, e.g. `example.com. IN DS 12345 13 2 <digest>`. Useful for zones
+    /// that aren't signed by their parent, like private ones.
+    pub fn add_anchor(&mut self, anchor: &str) -> Result<()> {
+        self.anchors.push(parse_anchor(anchor)?);
+        Ok(())
+    }
+
+    /// Validate the response to a query for `qname`, as found by `lookup`.
+    /// The response has to have been fetched with the DO bit set, or there
+    /// won't be any signatures to check.
+    pub fn validate(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        response: &DnsPacket,
+        options: &Arc<ResolverOptions>,
+    ) -> Result<Validation> {
+        // There's nothing to vouch for in a failure, and signatures aren't
+        // signed themselves
+        if qtype == QueryType::RRSIG
+            || !matches!(
+                response.header.rescode,
+                ResultCode::NOERROR | ResultCode::NXDOMAIN
+            )
+        {
+            return Ok(Validation::Insecure);
+        }
+        let response = &lowercase_names(response);
+
+        // Follow any CNAMEs to the name that actually answers the question
+        let mut target = qname.trim_end_matches('.').to_ascii_lowercase();
+        for _ in 0..response.answers.len() {
+            match response.answers.iter().find_map(|rec| match rec {
+                DnsRecord::CNAME { domain, host, .. } if *domain == target => Some(host),
+                _ => None,
+            }) {
+                Some(host) => target = host.clone(),
+                None => break,
+            }
+        }
+
+        let mut validation = self.verify_section(&response.answers, options)?;
+        if validation == Validation::Insecure {
+            return Ok(Validation::Insecure);
+        }
+
+        let answered = qtype == QueryType::CNAME
+            || response
+                .answers
+                .iter()
+                .any(|rec| rec.domain() == target && rec.query_type() == qtype);
+
+        let authorities = denial_records(&response.authorities, answered);
+        let has_denial = authorities
+            .iter()
+            .any(|rec| matches!(rec, DnsRecord::NSEC { .. } | DnsRecord::NSEC3 { .. }));
+        if answered && !has_denial {
+            // Answers made up from a wildcard have to come with proof that
+            // there's no better match
+            let expanded = response.answers.iter().any(|rec| {
+                matches!(rec, DnsRecord::RRSIG { domain, labels, .. }
+                    if (*labels as usize) < label_count(domain))
+            });
+            if expanded {
+                return Err(bogus(format!(
+                    "No proof that {} doesn't exist for its wildcard answer",
+                    target
+                )));
+            }
+            return Ok(validation);
+        }
+
+        let status = match authorities.is_empty() {
+            true if self.is_insecure(&target, options)? => Validation::Insecure,
+            true => {
+                return Err(bogus(format!(
+                    "No proof that {} {} doesn't exist",
+                    target, qtype
+                )))
+            }
+            false => self.verify_section(&authorities, options)?,
+        };
+        if status == Validation::Insecure {
+            return Ok(Validation::Insecure);
+        }
+
+        let denial = Denial::new(&authorities);
+        if denial.too_many_iterations() {
+            return Ok(Validation::Insecure);
+        }
+
+        if answered {
+            if !denial.expansion(&response.answers) {
+                return Err(bogus(format!(
+                    "No proof that {} doesn't exist for its wildcard answer",
+                    target
+                )));
+            }
+            return Ok(validation);
+        }
+
+        let proven = match response.header.rescode {
+            ResultCode::NXDOMAIN => denial.name_error(&target),
+            _ => {
+                denial.no_data(&target, qtype)
+                    || (qtype == QueryType::DS && denial.insecure_delegation(&target))
+            }
+        };
+        if !proven {
+            return Err(bogus(format!(
+                "No proof that {} {} doesn't exist",
+                target, qtype
+            )));
+        }
+
+        if denial.opt_out(&target) {
+            validation = Validation::Insecure;
+        }
+
+        Ok(validation)
+    }
+
+    /// Check the signatures of every RRset among `records`. Unsigned RRsets
+    /// only pass when they come from a zone that's provably unsigned.
+    fn verify_section(
+        &self,
+        records: &[DnsRecord],
+        options: &Arc<ResolverOptions>,
+    ) -> Result<Validation> {
+        let mut validation = Validation::Secure;
+
+        for (name, qtype) in rrset_keys(records) {
+            let (rrset, signatures) = rrset(records, &name, qtype);
+
+            let signer = match signatures.first() {
+                Some(DnsRecord::RRSIG { signer, .. }) => signer,
+                _ if self.is_insecure(&name, options)? => {
+                    validation = Validation::Insecure;
+                    continue;
+                }
+                _ => return Err(bogus(format!("{} {} isn't signed", name, qtype))),
+            };
+
+            match self.zone_keys(signer, options)? {
+                Some(keys) => verify_rrset(&rrset, &signatures, &keys)?,
+                None => validation = Validation::Insecure,
+            }
+        }
+
+        Ok(validation)
+    }
+
+    /// The keys of `zone` if they check out, or `None` if the zone is
+    /// provably unsigned
+    fn zone_keys(&self, zone: &str, options: &Arc<ResolverOptions>) -> Result<ZoneKeys> {
+        if let Some((expires, keys)) = self.keys.lock().unwrap().get(zone) {
+            if *expires > Instant::now() {
+                return Ok(keys.clone());
+            }
+        }
+
+        let keys = self.fetch_zone_keys(zone, options)?;
+
+        let ttl = match &keys {
+            Some(keys) => keys.iter().map(DnsRecord::ttl).min().unwrap_or(0),
+            None => MAX_KEY_TTL,
+        };
+        let expires = Instant::now() + Duration::from_secs(ttl.min(MAX_KEY_TTL) as u64);
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(zone.to_string(), (expires, keys.clone()));
+
+        Ok(keys)
+    }
+
+    fn fetch_zone_keys(&self, zone: &str, options: &Arc<ResolverOptions>) -> Result<ZoneKeys> {
+        let anchors: Vec<DnsRecord> = self
+            .anchors
+            .iter()
+            .filter(|anchor| anchor.domain() == zone)
+            .cloned()
+            .collect();
+
+        let ds_records = match anchors.is_empty() {
+            false => anchors,
+            true => match self.delegation(zone, options)? {
+                Some(ds_records) => ds_records,
+                None => return Ok(None),
+            },
+        };
+
+        // A zone signed with nothing but algorithms we don't know is as good
+        // as unsigned (RFC 4035, section 5.2)
+        if !ds_records.iter().any(is_supported_ds) {
+            debug!(
+                "No supported algorithm among the DS records of {}",
+                display_zone(zone)
+            );
+            return Ok(None);
+        }
+
+        let response = lookup(zone, QueryType::DNSKEY, options)?;
+        let (keys, signatures) = rrset(&response.answers, zone, QueryType::DNSKEY);
+
+        // The key set has to be signed by one of the keys the DS records
+        // vouch for
+        for key in &keys {
+            if !ds_records.iter().any(|ds| ds_matches(ds, key)) {
+                continue;
+            }
+            if verify_rrset(&keys, &signatures, &[(*key).clone()]).is_ok() {
+                debug!("Validated {} keys of {}", keys.len(), display_zone(zone));
+                return Ok(Some(keys.into_iter().cloned().collect()));
+            }
+        }
+
+        Err(bogus(format!("No valid key for {}", display_zone(zone))))
+    }
+
+    /// The DS records of `zone` as given by the zone above, once they've been
+    /// validated against that zone's keys, or `None` if the zone provably
+    /// doesn't have any
+    fn delegation(
+        &self,
+        zone: &str,
+        options: &Arc<ResolverOptions>,
+    ) -> Result<Option<Vec<DnsRecord>>> {
+        if zone.is_empty() {
+            return Err(bogus("No trust anchor for the root zone".to_string()));
+        }
+
+        let response = lookup(zone, QueryType::DS, options)?;
+
+        let (ds_records, signatures) = rrset(&response.answers, zone, QueryType::DS);
+        if !ds_records.is_empty() {
+            let parent = match signatures.first() {
+                Some(DnsRecord::RRSIG { signer, .. }) if is_parent(signer, zone) => signer,
+                _ => return Err(bogus(format!("Missing or bad signature on DS of {}", zone))),
+            };
+            return match self.zone_keys(parent, options)? {
+                Some(keys) => {
+                    verify_rrset(&ds_records, &signatures, &keys)?;
+                    Ok(Some(ds_records.into_iter().cloned().collect()))
+                }
+                None => Ok(None),
+            };
+        }
+
+        match self.parent_zone(zone, &response)? {
+            Some(parent) if is_parent(&parent, zone) => {
+                self.prove_unsigned(zone, &parent, &response, options)?;
+                Ok(None)
+            }
+            _ => Err(bogus(format!("No DS records for {}", zone))),
+        }
+    }
+
+    /// Whether data for `name` is allowed to come unsigned, because the zone
+    /// it's in is provably unsigned
+    fn is_insecure(&self, name: &str, options: &Arc<ResolverOptions>) -> Result<bool> {
+        let response = lookup(name, QueryType::DS, options)?;
+
+        // It's a delegation point, so it's up to the keys of the zone below
+        let (ds_records, _) = rrset(&response.answers, name, QueryType::DS);
+        if !ds_records.is_empty() {
+            return Ok(self.zone_keys(name, options)?.is_none());
+        }
+
+        let zone = match self.parent_zone(name, &response)? {
+            Some(zone) => zone,
+            None => return Err(bogus(format!("Can't find the zone of {}", name))),
+        };
+
+        if zone != name && self.zone_keys(&zone, options)?.is_some() {
+            return Ok(self.prove_unsigned(name, &zone, &response, options).is_ok());
+        }
+
+        Ok(self.zone_keys(&zone, options)?.is_none())
+    }
+
+    /// Check that a negative response to a DS query for `name` proves it to
+    /// be a delegation to an unsigned zone
+    fn prove_unsigned(
+        &self,
+        name: &str,
+        parent: &str,
+        response: &DnsPacket,
+        options: &Arc<ResolverOptions>,
+    ) -> Result<()> {
+        if self.zone_keys(parent, options)?.is_none() {
+            return Ok(());
+        }
+
+        let authorities = denial_records(&response.authorities, false);
+        if self.verify_section(&authorities, options)? == Validation::Insecure {
+            return Ok(());
+        }
+
+        let denial = Denial::new(&authorities);
+        if denial.too_many_iterations() || denial.insecure_delegation(name) {
+            debug!("{} is an unsigned delegation of {}", name, parent);
+            return Ok(());
+        }
+
+        Err(bogus(format!("No proof that {} is unsigned", name)))
+    }
+
+    /// The zone a negative response comes from, going by its SOA record or
+    /// its signatures
+    fn parent_zone(&self, name: &str, response: &DnsPacket) -> Result<Option<String>> {
+        let zone = response.authorities.iter().find_map(|rec| match rec {
+            DnsRecord::SOA { domain, .. } => Some(domain.clone()),
+            DnsRecord::RRSIG { signer, .. } => Some(signer.clone()),
+            _ => None,
+        });
+
+        match zone {
+            Some(zone) if is_subdomain(name, &zone) => Ok(Some(zone)),
+            Some(zone) => Err(bogus(format!("{} answered for {}", zone, name))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Look up the records that others are validated with, with their names in
+/// lowercase, see `lowercase_names`
+fn lookup(name: &str, qtype: QueryType, options: &Arc<ResolverOptions>) -> Result<DnsPacket> {
+    Ok(lowercase_names(&resolver::lookup(name, qtype, options)?))
+}
+
+/// The response with the names of its records in lowercase, the way the
+/// canonical form has them. Names keep the case they came in, which is up to
+/// the servers, while validation compares them the way they're signed.
+fn lowercase_names(response: &DnsPacket) -> DnsPacket {
+    let mut response = response.clone();
+    for records in [
+        &mut response.answers,
+        &mut response.authorities,
+        &mut response.resources,
+    ] {
+        for record in records.iter_mut() {
+            *record = record.lowercase_names();
+        }
+    }
+
+    response
+}
+
+fn bogus(reason: String) -> DnsError {
+    DnsError::Dnssec(reason)
+}
+
+/// The records of an authority section that prove names or types not to
+/// exist, along with their signatures. The SOA comes along with them unless
+/// the question was answered.
+fn denial_records(records: &[DnsRecord], answered: bool) -> Vec<DnsRecord> {
+    let is_denial = |qtype: QueryType| {
+        matches!(qtype, QueryType::NSEC | QueryType::NSEC3)
+            || (!answered && qtype == QueryType::SOA)
+    };
+
+    records
+        .iter()
+        .filter(|rec| match rec {
+            DnsRecord::RRSIG { type_covered, .. } => is_denial(*type_covered),
+            rec => is_denial(rec.query_type()),
+        })
+        .cloned()
+        .collect()
+}
+
+/// The owner and type of every RRset among `records`, in order of appearance
+fn rrset_keys(records: &[DnsRecord]) -> Vec<(String, QueryType)> {
+    let mut keys: Vec<(String, QueryType)> = Vec::new();
+    for rec in records {
+        let key = match rec {
+            DnsRecord::RRSIG { .. } | DnsRecord::OPT { .. } => continue,
+            rec => (rec.domain().to_string(), rec.query_type()),
+        };
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    keys
+}
+
+/// The records of the RRset of `name` and `qtype`, and the signatures over it
+fn rrset<'a>(
+    records: &'a [DnsRecord],
+    name: &str,
+    qtype: QueryType,
+) -> (Vec<&'a DnsRecord>, Vec<&'a DnsRecord>) {
+    let rrset = records
+        .iter()
+        .filter(|rec| rec.domain() == name && rec.query_type() == qtype)
+        .collect();
+    let signatures = records
+        .iter()
+        .filter(|rec| {
+            matches!(rec, DnsRecord::RRSIG { domain, type_covered, .. }
+                if domain == name && *type_covered == qtype)
+        })
+        .collect();
+
+    (rrset, signatures)
+}
+
+/// Check that at least one of the signatures over an RRset is valid, and
+/// made with one of `keys`
+fn verify_rrset(rrset: &[&DnsRecord], signatures: &[&DnsRecord], keys: &[DnsRecord]) -> Result<()> {
+    let mut reason = "no signatures".to_string();
+    for rrsig in signatures {
+        match verify_signature(rrset, rrsig, keys) {
+            Ok(()) => return Ok(()),
+            Err(e) => reason = e,
+        }
+    }
+
+    let (name, qtype) = match rrset.first() {
+        Some(rec) => (rec.domain(), rec.query_type()),
+        None => return Err(bogus("Signatures over an empty RRset".to_string())),
+    };
+    Err(bogus(format!(
+        "Bad signature on {} {}: {}",
+        name, qtype, reason
+    )))
+}
+
+fn verify_signature(
+    rrset: &[&DnsRecord],
+    rrsig: &DnsRecord,
+    keys: &[DnsRecord],
+) -> std::result::Result<(), String> {
+    let DnsRecord::RRSIG {
+        domain,
+        type_covered,
+        algorithm,
+        labels,
+        original_ttl,
+        expiration,
+        inception,
+        key_tag,
+        signer,
+        signature,
+        ..
+    } = rrsig
+    else {
+        return Err("not a signature".to_string());
+    };
+
+    if !is_subdomain(domain, signer) {
+        return Err(format!("signed by {}, which isn't above it", signer));
+    }
+    // Times are compared in serial number arithmetic, so that they keep
+    // working after 2106 (RFC 4034, section 3.1.5)
+    let now = now();
+    if (now.wrapping_sub(*inception) as i32) < 0 || (expiration.wrapping_sub(now) as i32) < 0 {
+        return Err("signature isn't valid at this time".to_string());
+    }
+
+    // A record expanded from a wildcard is signed as the wildcard
+    let owner_labels = label_count(domain);
+    let owner = match (*labels as usize).cmp(&owner_labels) {
+        Ordering::Greater => return Err("more labels than its owner".to_string()),
+        Ordering::Equal => domain.clone(),
+        Ordering::Less => {
+            let parent: Vec<&str> = domain
+                .split('.')
+                .skip(owner_labels - *labels as usize)
+                .collect();
+            format!("*.{}", parent.join("."))
+        }
+    };
+    let owner = wire_name(owner.trim_end_matches('.'));
+
+    let mut rdatas = rrset
+        .iter()
+        .map(|rec| rdata(rec))
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+    rdatas.sort();
+    rdatas.dedup();
+
+    // The signed data is the RRSIG without its signature, followed by the
+    // records in canonical form and order (RFC 4034, section 3.1.8.1)
+    let mut data = Vec::new();
+    data.extend_from_slice(&type_covered.to_num().to_be_bytes());
+    data.push(*algorithm);
+    data.push(*labels);
+    data.extend_from_slice(&original_ttl.to_be_bytes());
+    data.extend_from_slice(&expiration.to_be_bytes());
+    data.extend_from_slice(&inception.to_be_bytes());
+    data.extend_from_slice(&key_tag.to_be_bytes());
+    data.extend_from_slice(&wire_name(signer));
+    for rdata in &rdatas {
+        data.extend_from_slice(&owner);
+        data.extend_from_slice(&type_covered.to_num().to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&original_ttl.to_be_bytes());
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend_from_slice(rdata);
+    }
+
+    let verified = keys.iter().any(|key| match key {
+        DnsRecord::DNSKEY {
+            domain: key_owner,
+            flags,
+            protocol: 3,
+            algorithm: key_algorithm,
+            public_key,
+            ..
+        } => {
+            key_owner == signer
+                && flags & ZONE_KEY != 0
+                && key_algorithm == algorithm
+                && rdata(key).is_ok_and(|rdata| compute_key_tag(&rdata) == *key_tag)
+                && verify(*algorithm, public_key, &data, signature)
+        }
+        _ => false,
+    });
+
+    match verified {
+        true => Ok(()),
+        false => Err(format!(
+            "no key of {} with tag {} verifies it",
+            signer, key_tag
+        )),
+    }
+}
+
+fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+fn verify(algorithm: u8, public_key: &[u8], data: &[u8], sig: &[u8]) -> bool {
+    let ecdsa = |algorithm| {
+        let mut key = vec![0x04];
+        key.extend_from_slice(public_key);
+        signature::UnparsedPublicKey::new(algorithm, key)
+            .verify(data, sig)
+            .is_ok()
+    };
+
+    match algorithm {
+        5 | 7 => verify_rsa(
+            &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+            public_key,
+            data,
+            sig,
+        ),
+        8 => verify_rsa(
+            &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+            public_key,
+            data,
+            sig,
+        ),
+        10 => verify_rsa(
+            &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+            public_key,
+            data,
+            sig,
+        ),
+        13 => ecdsa(&signature::ECDSA_P256_SHA256_FIXED),
+        14 => ecdsa(&signature::ECDSA_P384_SHA384_FIXED),
+        15 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(data, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// RSA keys are the length of the exponent, in one byte or in three
+/// starting with a zero, then the exponent and the modulus (RFC 3110)
+fn verify_rsa(params: &signature::RsaParameters, key: &[u8], data: &[u8], sig: &[u8]) -> bool {
+    let (exponent_len, rest) = match key {
+        [0, hi, lo, rest @ ..] => (u16::from_be_bytes([*hi, *lo]) as usize, rest),
+        [len, rest @ ..] => (*len as usize, rest),
+        [] => return false,
+    };
+    if exponent_len == 0 || rest.len() <= exponent_len {
+        return false;
+    }
+    let (e, n) = rest.split_at(exponent_len);
+
+    signature::RsaPublicKeyComponents { n, e }
+        .verify(params, data, sig)
+        .is_ok()
+}
+
+/// The tag that tells keys apart, a checksum of the DNSKEY data (RFC 4034,
+/// appendix B)
+fn compute_key_tag(rdata: &[u8]) -> u16 {
+    let mut ac: u32 = 0;
+    for (i, b) in rdata.iter().enumerate() {
+        ac += match i % 2 {
+            0 => (*b as u32) << 8,
+            _ => *b as u32,
+        };
+    }
+    ac += (ac >> 16) & 0xFFFF;
+
+    (ac & 0xFFFF) as u16
+}
+
+fn is_supported_ds(ds: &DnsRecord) -> bool {
+    matches!(ds, DnsRecord::DS { algorithm, digest_type: 1 | 2 | 4, .. }
+        if is_supported_algorithm(*algorithm))
+}
+
+/// Whether a DS record is the digest of `key`
+fn ds_matches(ds: &DnsRecord, key: &DnsRecord) -> bool {
+    let (
+        DnsRecord::DS {
+            key_tag,
+            algorithm,
+            digest_type,
+            digest,
+            ..
+        },
+        DnsRecord::DNSKEY {
+            domain,
+            algorithm: key_algorithm,
+            ..
+        },
+    ) = (ds, key)
+    else {
+        return false;
+    };
+
+    let rdata = match rdata(key) {
+        Ok(rdata) => rdata,
+        Err(_) => return false,
+    };
+    if algorithm != key_algorithm || compute_key_tag(&rdata) != *key_tag {
+        return false;
+    }
+
+    let algorithm = match digest_type {
+        1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        2 => &digest::SHA256,
+        4 => &digest::SHA384,
+        _ => return false,
+    };
+    let mut context = digest::Context::new(algorithm);
+    context.update(&wire_name(domain));
+    context.update(&rdata);
+
+    context.finish().as_ref() == digest.as_slice()
+}
+
+/// The RDATA of a record in canonical form, the way it's written to the wire
+/// with compression turned off and its names in lowercase
+fn rdata(record: &DnsRecord) -> Result<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+    buffer.disable_compression();
+    record.lowercase_names().write(&mut buffer)?;
+
+    // Skip the owner, type, class, TTL and the length of the data
+    let start = wire_name(record.domain()).len() + 10;
+    Ok(buffer.buf[start..buffer.pos].to_vec())
+}
+
+/// The root zone is an empty name, which wouldn't read well in messages
+fn display_zone(zone: &str) -> &str {
+    match zone.is_empty() {
+        true => ".",
+        false => zone,
+    }
+}
+
+/// A name in canonical wire format, lowercase and uncompressed
+fn wire_name(name: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = label_bytes(label);
+        bytes.push(label.len() as u8);
+        bytes.extend(label.iter().map(|b| b.to_ascii_lowercase()));
+    }
+    bytes.push(0);
+
+    bytes
+}
+
+/// The number of labels of a name as counted by RRSIG records, which leave
+/// out a leading wildcard
+fn label_count(name: &str) -> usize {
+    let name = name.strip_prefix("*.").unwrap_or(name);
+    name.split('.').filter(|label| !label.is_empty()).count()
+}
+
+/// Whether `zone` is strictly above `name`
+fn is_parent(zone: &str, name: &str) -> bool {
+    !name::eq(zone, name) && is_subdomain(name, zone)
+}
+
+/// Names of `name` and up, longest first, ending with the root
+fn ancestors(name: &str) -> impl Iterator<Item = &str> {
+    let mut next = Some(name);
+    std::iter::from_fn(move || {
+        let name = next?;
+        next = match name.split_once('.') {
+            Some((_, parent)) => Some(parent),
+            None if name.is_empty() => None,
+            None => Some(""),
+        };
+        Some(name)
+    })
+}
+
+/// The order of names in a zone, which compares them label by label
+/// starting from the root (RFC 4034, section 6.1)
+fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| -> Vec<Vec<u8>> {
+        name.split('.')
+            .filter(|label| !label.is_empty())
+            .rev()
+            .map(|label| label.to_ascii_lowercase().into_bytes())
+            .collect()
+    };
+
+    labels(a).cmp(&labels(b))
+}
+
+/// Whether `name` falls strictly between `owner` and `next`, which wrap
+/// around past the end of the zone
+fn covers<T: Ord + ?Sized>(
+    owner: &T,
+    next: &T,
+    name: &T,
+    cmp: impl Fn(&T, &T) -> Ordering,
+) -> bool {
+    let after_owner = cmp(owner, name) == Ordering::Less;
+    let before_next = cmp(name, next) == Ordering::Less;
+
+    match cmp(owner, next) {
+        Ordering::Less => after_owner && before_next,
+        _ => after_owner || before_next,
+    }
+}
+
+/// The hashed owner name of `name` in base32hex, as NSEC3 records have it
+/// (RFC 5155, section 5)
+fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> String {
+    let hash = |data: &[u8]| {
+        let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        context.update(data);
+        context.update(salt);
+        context.finish()
+    };
+
+    let mut digest = hash(&wire_name(name));
+    for _ in 0..iterations {
+        digest = hash(digest.as_ref());
+    }
+
+    dns_record::base32hex(digest.as_ref())
+}
+
+/// The current time as RRSIG records count it, in seconds since the epoch
+/// modulo 2^32
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+/// Parse a DS record given in zone file format, without the TTL
+fn parse_anchor(anchor: &str) -> Result<DnsRecord> {
+    let invalid = || DnsError::Parse(format!("Invalid trust anchor: {}", anchor));
+
+    let mut fields = anchor.split_whitespace().peekable();
+    let domain = fields.next().ok_or_else(invalid)?;
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    fields.next_if(|field| field.eq_ignore_ascii_case("IN"));
+    fields.next_if(|field| field.eq_ignore_ascii_case("DS"));
+
+    let key_tag = fields.next().ok_or_else(invalid)?.parse()?;
+    let algorithm = fields.next().ok_or_else(invalid)?.parse()?;
+    let digest_type = fields.next().ok_or_else(invalid)?.parse()?;
+
+    // The digest may be split over several fields
+    let digits: String = fields.collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    let digest = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<u8>>>()?;
+
+    Ok(DnsRecord::DS {
+        domain,
+        key_tag,
+        algorithm,
+        digest_type,
+        digest,
+        ttl: 0,
+    })
+}
+
+/// The NSEC and NSEC3 records of a response whose signatures have checked
+/// out, for proving that names or types don't exist
+struct Denial<'a> {
+    nsec: Vec<&'a DnsRecord>,
+    nsec3: Vec<&'a DnsRecord>,
+}
+
+impl<'a> Denial<'a> {
+    fn new(records: &'a [DnsRecord]) -> Denial<'a> {
+        Denial {
+            nsec: records
+                .iter()
+                .filter(|rec| matches!(rec, DnsRecord::NSEC { .. }))
+                .collect(),
+            nsec3: records
+                .iter()
+                .filter(|rec| matches!(rec, DnsRecord::NSEC3 { .. }))
+                .collect(),
+        }
+    }
+
+    fn too_many_iterations(&self) -> bool {
+        self.nsec3.iter().any(|rec| {
+            matches!(rec, DnsRecord::NSEC3 { iterations, .. } if *iterations > MAX_NSEC3_ITERATIONS)
+        })
+    }
+
+    /// The types of `name`, if there's an NSEC or NSEC3 record for it
+    fn types(&self, name: &str) -> Option<&'a [QueryType]> {
+        let nsec = self.nsec.iter().find_map(|rec| match rec {
+            DnsRecord::NSEC { domain, types, .. } if domain == name => Some(types.as_slice()),
+            _ => None,
+        });
+
+        nsec.or_else(|| {
+            self.nsec3.iter().find_map(|rec| match rec {
+                DnsRecord::NSEC3 {
+                    domain,
+                    salt,
+                    iterations,
+                    types,
+                    ..
+                } => {
+                    let (hash, zone) = domain.split_once('.')?;
+                    (is_subdomain(name, zone) && nsec3_hash(name, salt, *iterations) == hash)
+                        .then_some(types.as_slice())
+                }
+                _ => None,
+            })
+        })
+    }
+
+    /// Whether an NSEC record proves there's nothing at `name`
+    fn nsec_covers(&self, name: &str) -> bool {
+        self.nsec.iter().any(|rec| match rec {
+            DnsRecord::NSEC { domain, next, .. } => {
+                covers(domain.as_str(), next.as_str(), name, canonical_cmp)
+            }
+            _ => false,
+        })
+    }
+
+    /// The flags of the NSEC3 record proving that there's nothing at `name`,
+    /// if there is one
+    fn nsec3_covers(&self, name: &str) -> Option<u8> {
+        self.nsec3.iter().find_map(|rec| match rec {
+            DnsRecord::NSEC3 {
+                domain,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                ..
+            } => {
+                let (owner, zone) = domain.split_once('.')?;
+                let next = dns_record::base32hex(next_hashed);
+                let hash = nsec3_hash(name, salt, *iterations);
+                (is_subdomain(name, zone) && covers(owner, next.as_str(), hash.as_str(), Ord::cmp))
+                    .then_some(*flags)
+            }
+            _ => None,
+        })
+    }
+
+    /// The closest encloser proof of RFC 5155, section 7.2.1: the longest
+    /// name above `name` that exists, along with the flags of the NSEC3
+    /// record proving that the next name down towards `name` doesn't
+    fn closest_encloser(&self, name: &str) -> Option<(String, u8)> {
+        let names: Vec<&str> = ancestors(name).collect();
+        names.windows(2).find_map(|pair| {
+            let (next_closer, encloser) = (pair[0], pair[1]);
+            self.types(encloser)?;
+            let flags = self.nsec3_covers(next_closer)?;
+            Some((encloser.to_string(), flags))
+        })
+    }
+
+    /// The closest encloser of `name` by way of the NSEC record proving that
+    /// it doesn't exist, which is as much of `name` as it shares with either
+    /// end of the record
+    fn nsec_encloser(&self, name: &str) -> Option<String> {
+        self.nsec.iter().find_map(|rec| match rec {
+            DnsRecord::NSEC { domain, next, .. }
+                if covers(domain.as_str(), next.as_str(), name, canonical_cmp) =>
+            {
+                let a = common_ancestor(name, domain);
+                let b = common_ancestor(name, next);
+                Some(if a.len() > b.len() { a } else { b })
+            }
+            _ => None,
+        })
+    }
+
+    /// Whether `name` is proven not to exist, and no wildcard to answer for
+    /// it either (RFC 4035, section 5.4, and RFC 5155, section 8.4)
+    fn name_error(&self, name: &str) -> bool {
+        if let Some(encloser) = self.nsec_encloser(name) {
+            if self.nsec_covers(&wildcard(&encloser)) {
+                return true;
+            }
+        }
+
+        match self.closest_encloser(name) {
+            Some((encloser, _)) => self.nsec3_covers(&wildcard(&encloser)).is_some(),
+            None => false,
+        }
+    }
+
+    /// Whether `name` is proven to exist without records of `qtype`, either
+    /// by itself or by way of a wildcard
+    fn no_data(&self, name: &str, qtype: QueryType) -> bool {
+        let lacks =
+            |types: &[QueryType]| !types.contains(&qtype) && !types.contains(&QueryType::CNAME);
+
+        if let Some(types) = self.types(name) {
+            return lacks(types);
+        }
+
+        // An empty non-terminal, a name that only exists for the names below
+        // it, falls between two NSEC records
+        let empty_non_terminal = self.nsec.iter().any(|rec| {
+            matches!(rec, DnsRecord::NSEC { domain, next, .. }
+                if covers(domain.as_str(), next.as_str(), name, canonical_cmp)
+                    && is_parent(name, next))
+        });
+        if empty_non_terminal {
+            return true;
+        }
+
+        let encloser = self
+            .nsec_encloser(name)
+            .or_else(|| self.closest_encloser(name).map(|(encloser, _)| encloser));
+        match encloser {
+            Some(encloser) => self.types(&wildcard(&encloser)).is_some_and(lacks),
+            None => false,
+        }
+    }
+
+    /// Whether `name` is proven to be a delegation without DS records, or to
+    /// be covered by an opt-out NSEC3 record which leaves room for one
+    fn insecure_delegation(&self, name: &str) -> bool {
+        if let Some(types) = self.types(name) {
+            return types.contains(&QueryType::NS)
+                && !types.contains(&QueryType::DS)
+                && !types.contains(&QueryType::SOA);
+        }
+
+        self.opt_out(name)
+    }
+
+    /// Whether the proof for `name` relies on an opt-out NSEC3 record, in
+    /// which case there may be an unsigned delegation in its place
+    fn opt_out(&self, name: &str) -> bool {
+        self.closest_encloser(name)
+            .is_some_and(|(_, flags)| flags & OPT_OUT != 0)
+    }
+
+    /// Whether the answers expanded from a wildcard come with proof that
+    /// there's no closer match for the names they answer for
+    fn expansion(&self, answers: &[DnsRecord]) -> bool {
+        answers.iter().all(|rec| match rec {
+            DnsRecord::RRSIG { domain, labels, .. } if (*labels as usize) < label_count(domain) => {
+                let next_closer = suffix(domain, *labels as usize + 1);
+                self.nsec_covers(domain) || self.nsec3_covers(&next_closer).is_some()
+            }
+            _ => true,
+        })
+    }
+}
+
+/// The wildcard of the names right below `name`
+fn wildcard(name: &str) -> String {
+    match name.is_empty() {
+        true => "*".to_string(),
+        false => format!("*.{}", name),
+    }
+}
+
+/// The last `labels` labels of `name`
+fn suffix(name: &str, labels: usize) -> String {
+    let all: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+    all[all.len().saturating_sub(labels)..].join(".")
+}
+
+/// The longest name that both `a` and `b` are at or below
+fn common_ancestor(a: &str, b: &str) -> String {
+    let a: Vec<&str> = a.split('.').filter(|label| !label.is_empty()).collect();
+    let b: Vec<&str> = b.split('.').filter(|label| !label.is_empty()).collect();
+    let common = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x.eq_ignore_ascii_case(y))
+        .count();
+
+    a[a.len() - common..].join(".")
+}
@@ -0,0 +1,403 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::Arc,
+    thread,
+};
+
+use log::{debug, error, warn};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection, StreamOwned,
+};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    client::{self, QueryOptions},
+    dns_packet::DnsPacket,
+    error::{DnsError, Result},
+    forwarder::DohUrl,
+    metrics::Metrics,
+    query_type::QueryType,
+    resolver::ResolverOptions,
+    server::{handle_request, Transport, TCP_IDLE_TIMEOUT},
+    tls::TlsClient,
+};
+
+/// The media type of DNS messages sent over HTTP
+pub const CONTENT_TYPE: &str = "application/dns-message";
+
+/// The path the server answers queries on
+pub const PATH: &str = "/dns-query";
+
+/// Limits on the size of the HTTP messages we're willing to read, so that a
+/// misbehaving peer can't make us buffer arbitrary amounts of data
+const MAX_LINE_LEN: u64 = 8192;
+const MAX_HEADERS: usize = 64;
+const MAX_BODY_LEN: usize = u16::MAX as usize;
+
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// A request or response, without any of the HTTP/1.1 features that DNS
+/// messages don't need
+struct HttpMessage {
+    start_line: String,
+    /// With the names lowercased
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpMessage {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Send a query over HTTPS (RFC 8484). The connections are shared with DNS
+/// over TLS, so they get reused between queries the same way.
+pub fn query(
+    tls: &TlsClient,
+    qname: &str,
+    qtype: QueryType,
+    url: &DohUrl,
+    options: &QueryOptions,
+) -> Result<DnsPacket> {
+    let (packet, req_buffer) = client::build_query(qname, qtype, options)?;
+
+    debug!(
+        "Sending query {} for {} {:?} to {}",
+        packet.header.id, qname, qtype, url
+    );
+
+    let mut res_buffer = tls.with_connection(url.addr, &url.host, options.timeout, |stream| {
+        exchange(stream, url, &req_buffer)
+    })?;
+
+    client::check_response(&packet, &mut res_buffer, options)
+}
+
+fn exchange<S: Read + Write>(
+    stream: &mut S,
+    url: &DohUrl,
+    req_buffer: &BytePacketBuffer,
+) -> Result<BytePacketBuffer> {
+    let message = &req_buffer.buf[0..req_buffer.pos()];
+
+    let host = match (url.host.contains(':'), url.addr.port()) {
+        (true, 443) => format!("[{}]", url.host),
+        (true, port) => format!("[{}]:{}", url.host, port),
+        (false, 443) => url.host.clone(),
+        (false, port) => format!("{}:{}", url.host, port),
+    };
+
+    let request = if url.get {
+        format!(
+            "GET {}?dns={} HTTP/1.1\r\nHost: {}\r\nAccept: {}\r\n\r\n",
+            url.path,
+            base64url_encode(message),
+            host,
+            CONTENT_TYPE
+        )
+        .into_bytes()
+    } else {
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nAccept: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            url.path,
+            host,
+            CONTENT_TYPE,
+            CONTENT_TYPE,
+            message.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(message);
+        request
+    };
+    stream.write_all(&request)?;
+    stream.flush()?;
+
+    let response = read_message(&mut BufReader::new(stream))?.ok_or_else(|| {
+        DnsError::Http("Connection closed before the response arrived".to_string())
+    })?;
+
+    match response.start_line.split(' ').nth(1) {
+        Some("200") => {}
+        _ => {
+            return Err(DnsError::Http(format!(
+                "{} responded with {}",
+                url, response.start_line
+            )))
+        }
+    }
+    if response.header("content-type") != Some(CONTENT_TYPE) {
+        return Err(DnsError::Http(format!(
+            "{} responded with something other than DNS",
+            url
+        )));
+    }
+
+    Ok(BytePacketBuffer::from_slice(&response.body))
+}
+
+/// Load the certificate chain and private key to serve DNS over HTTPS with,
+/// both in PEM format
+pub fn load_server_config<P: AsRef<Path>>(cert: P, key: P) -> Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<std::result::Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Serve DNS over HTTPS on `/dns-query`, for clients such as browsers to use
+/// this server as their resolver. Queries are accepted both as the body of a
+/// POST request and as the `dns` parameter of a GET request. Only HTTP/1.1 is
+/// supported.
+pub fn serve(listener: TcpListener, config: Arc<ServerConfig>, options: Arc<ResolverOptions>) {
+    if let Ok(addr) = listener.local_addr() {
+        options.shutdown.wake_tcp_on_stop(addr);
+    }
+
+    for stream in listener.incoming() {
+        if options.shutdown.is_stopping() {
+            return;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept HTTPS connection: {}", e);
+                continue;
+            }
+        };
+
+        let config = config.clone();
+        let options = options.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, config, &options) {
+                error!("An error occurred: {}", e);
+            }
+        });
+    }
+}
+
+/// Answer the requests on a connection until the client closes it, or leaves
+/// it idle for too long
+fn handle_connection(
+    socket: TcpStream,
+    config: Arc<ServerConfig>,
+    options: &Arc<ResolverOptions>,
+) -> Result<()> {
+    let _connection = options.metrics.as_ref().map(Metrics::connection);
+    socket.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let src = socket.peer_addr()?;
+
+    let stream = StreamOwned::new(ServerConnection::new(config)?, socket);
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let request = match read_message(&mut reader) {
+            Ok(Some(request)) => request,
+            // The client is done
+            Ok(None) => return Ok(()),
+            Err(DnsError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let response = match respond(&request, src, options) {
+            Ok(res_buffer) => {
+                let message = &res_buffer.buf[0..res_buffer.pos()];
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                    CONTENT_TYPE,
+                    message.len()
+                )
+                .into_bytes();
+                response.extend_from_slice(message);
+                response
+            }
+            Err(status) => {
+                warn!("Rejected HTTPS request from {}: {}", src, status);
+                format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).into_bytes()
+            }
+        };
+
+        let stream = reader.get_mut();
+        stream.write_all(&response)?;
+        stream.flush()?;
+
+        if request
+            .header("connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+        {
+            stream.conn.send_close_notify();
+            stream.flush()?;
+            return Ok(());
+        }
+    }
+}
+
+/// Work out the response to a request, or the HTTP status to reject it with
+fn respond(
+    request: &HttpMessage,
+    src: SocketAddr,
+    options: &Arc<ResolverOptions>,
+) -> std::result::Result<BytePacketBuffer, &'static str> {
+    let mut parts = request.start_line.split(' ');
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let (path, params) = target.split_once('?').unwrap_or((target, ""));
+
+    if path != PATH {
+        return Err("404 Not Found");
+    }
+
+    let message = match method {
+        "GET" => params
+            .split('&')
+            .find_map(|param| param.strip_prefix("dns="))
+            .and_then(base64url_decode)
+            .ok_or("400 Bad Request")?,
+        "POST" if request.header("content-type") == Some(CONTENT_TYPE) => request.body.clone(),
+        "POST" => return Err("415 Unsupported Media Type"),
+        _ => return Err("405 Method Not Allowed"),
+    };
+
+    let mut req_buffer = BytePacketBuffer::from_slice(&message);
+    handle_request(&mut req_buffer, src, Transport::Tcp, options).map_err(|e| {
+        debug!("Failed to handle HTTPS request from {}: {}", src, e);
+        "400 Bad Request"
+    })
+}
+
+/// Read a request or response along with its body, or `None` if the
+/// connection was closed before a new one started
+fn read_message<R: BufRead>(reader: &mut R) -> Result<Option<HttpMessage>> {
+    let start_line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| {
+            DnsError::Http("Connection closed in the middle of the headers".to_string())
+        })?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(DnsError::Http("Too many headers".to_string()));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| DnsError::Http(format!("Invalid header: {}", line)))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    let mut message = HttpMessage {
+        start_line,
+        headers,
+        body: Vec::new(),
+    };
+
+    let chunked = message
+        .header("transfer-encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        loop {
+            let line = read_line(reader)?.ok_or_else(|| {
+                DnsError::Http("Connection closed in the middle of the body".to_string())
+            })?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16)?;
+            if size == 0 {
+                // Skip past the trailers
+                while read_line(reader)?.is_some_and(|line| !line.is_empty()) {}
+                break;
+            }
+            if message.body.len() + size > MAX_BODY_LEN {
+                return Err(DnsError::Http(
+                    "Body is too large for a DNS message".to_string(),
+                ));
+            }
+
+            let start = message.body.len();
+            message.body.resize(start + size, 0);
+            reader
+                .read_exact(&mut message.body[start..])
+                .map_err(DnsError::from_timed_read)?;
+            read_line(reader)?;
+        }
+    } else if let Some(len) = message.header("content-length") {
+        let len = len.parse::<usize>()?;
+        if len > MAX_BODY_LEN {
+            return Err(DnsError::Http(
+                "Body is too large for a DNS message".to_string(),
+            ));
+        }
+        message.body = vec![0; len];
+        reader
+            .read_exact(&mut message.body)
+            .map_err(DnsError::from_timed_read)?;
+    }
+
+    Ok(Some(message))
+}
+
+/// Read a line without its line ending, or `None` at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    let read = reader
+        .take(MAX_LINE_LEN)
+        .read_line(&mut line)
+        .map_err(DnsError::from_timed_read)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(DnsError::Http("Line is too long, or cut short".to_string()));
+    }
+
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Base64 with the URL safe alphabet and without padding, as used for the
+/// `dns` parameter
+fn base64url_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(BASE64URL[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+
+    encoded
+}
+
+fn base64url_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bits = 0u32;
+    let mut len = 0;
+    for c in s.trim_end_matches('=').bytes() {
+        let value = BASE64URL.iter().position(|b| *b == c)? as u32;
+        bits = bits << 6 | value;
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            decoded.push((bits >> len) as u8);
+        }
+    }
+
+    Some(decoded)
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use log::{debug, error};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Connection, ConnectionError, Endpoint, EndpointConfig, Incoming, RecvStream,
+    SendStream, ServerConfig, TokioRuntime, TransportConfig, VarInt,
+};
+use rustls::RootCertStore;
+use tokio::{runtime::Runtime, sync::watch, time::timeout};
+
+use crate::{
+    async_server::respond,
+    byte_packet_buffer::BytePacketBuffer,
+    client::{self, QueryOptions},
+    dns_header::Opcode,
+    dns_packet::DnsPacket,
+    doh,
+    error::{DnsError, Result},
+    metrics::Metrics,
+    query_type::QueryType,
+    resolver::ResolverOptions,
+    server::{Transport, TCP_IDLE_TIMEOUT},
+    shutdown,
+};
+
+/// The ALPN token DNS over QUIC is negotiated with (RFC 9250, section 4.1)
+pub const ALPN: &[u8] = b"doq";
+
+/// The error code a connection is closed with once there's nothing more to
+/// say on it (RFC 9250, section 4.3)
+const DOQ_NO_ERROR: u32 = 0;
+
+/// The error code for a peer that doesn't stick to the protocol
+const DOQ_PROTOCOL_ERROR: u32 = 2;
+
+/// Connections are kept apart by both the address of the upstream and the
+/// name its certificate was checked against
+type ConnectionKey = (SocketAddr, String);
+
+/// The runtime the connections of every `QuicClient` are driven by, which is
+/// started on the first query. A single thread is plenty, since all it does
+/// is shuffle packets around.
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("doq-client")
+            .enable_all()
+            .build()
+            .expect("Failed to start the DNS over QUIC runtime")
+    })
+}
+
+/// Sends queries over QUIC (RFC 9250), which is as private as DNS over TLS
+/// without a slow query holding up the ones after it. There's a connection to
+/// each upstream that every query opens a stream of its own on, and which is
+/// kept open for the next query.
+///
+/// Once an upstream has handed out a session ticket, a new connection to it
+/// resumes the session, and the query goes out right along with the
+/// handshake as 0-RTT data. Should the upstream turn the ticket down, the
+/// query is sent again once the handshake is done.
+#[derive(Clone)]
+pub struct QuicClient {
+    config: ClientConfig,
+    /// Bound on first use, one for each address family
+    endpoints: Arc<Mutex<HashMap<bool, Endpoint>>>,
+    connections: Arc<Mutex<HashMap<ConnectionKey, Connection>>>,
+}
+
+impl fmt::Debug for QuicClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicClient").finish_non_exhaustive()
+    }
+}
+
+impl Default for QuicClient {
+    fn default() -> QuicClient {
+        QuicClient::new()
+    }
+}
+
+impl QuicClient {
+    /// A client that validates certificates against the Mozilla root
+    /// certificates, like `TlsClient`
+    pub fn new() -> QuicClient {
+        QuicClient::with_roots(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        })
+    }
+
+    /// A client that validates certificates against `roots` instead, such as
+    /// those of a private CA
+    pub fn with_roots(roots: RootCertStore) -> QuicClient {
+        let mut tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        tls.enable_early_data = true;
+        let crypto = QuicClientConfig::try_from(tls).expect("TLS 1.3 is always enabled");
+
+        QuicClient {
+            config: ClientConfig::new(Arc::new(crypto)),
+            endpoints: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Send a single query to `server`, whose certificate has to be valid for
+    /// `name`. That's either a host name or the address of the server itself.
+    pub fn query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: SocketAddr,
+        name: &str,
+        options: &QueryOptions,
+    ) -> Result<DnsPacket> {
+        let (mut packet, mut req_buffer) = client::build_query(qname, qtype, options)?;
+
+        // It's the stream that ties a response to its query, so the ID is
+        // always zero (RFC 9250, section 4.2.1)
+        packet.header.id = 0;
+        req_buffer.set_u16(0, 0)?;
+
+        debug!(
+            "Sending query for {} {:?} to {} over QUIC",
+            qname, qtype, server
+        );
+
+        let exchange = self.exchange(server, name, &req_buffer);
+        let mut res_buffer =
+            runtime().block_on(async { timeout(options.timeout, exchange).await })??;
+
+        client::check_response(&packet, &mut res_buffer, options)
+    }
+
+    /// Exchange a query for its response over the connection to `server`,
+    /// opening one if there's none yet
+    async fn exchange(
+        &self,
+        server: SocketAddr,
+        name: &str,
+        req_buffer: &BytePacketBuffer,
+    ) -> Result<BytePacketBuffer> {
+        // The upstream may well have closed the connection in the meantime,
+        // without us having noticed yet, in which case we start over on a
+        // fresh one.
+        let key = (server, name.to_string());
+        let open = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|connection| connection.close_reason().is_none())
+            .cloned();
+        if let Some(connection) = open {
+            match send_query(&connection, req_buffer).await {
+                Ok(res_buffer) => return Ok(res_buffer),
+                Err(e) => debug!("Connection to {} failed: {}", server, e),
+            }
+        }
+
+        let (connection, early) = self.connect(server, name).await?;
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(key, connection.clone());
+
+        match send_query(&connection, req_buffer).await {
+            Err(e) if early => {
+                debug!("0-RTT data rejected by {}: {}", server, e);
+                send_query(&connection, req_buffer).await
+            }
+            result => result,
+        }
+    }
+
+    /// Connect to `server`, telling whether the connection is still being
+    /// set up, with what's sent on it going out as 0-RTT data
+    async fn connect(&self, server: SocketAddr, name: &str) -> Result<(Connection, bool)> {
+        let connecting = self
+            .endpoint(server)?
+            .connect_with(self.config.clone(), server, name)?;
+
+        match connecting.into_0rtt() {
+            Ok((connection, _)) => {
+                debug!("Resuming the session with {} in 0-RTT", server);
+                Ok((connection, true))
+            }
+            Err(connecting) => Ok((connecting.await?, false)),
+        }
+    }
+
+    fn endpoint(&self, server: SocketAddr) -> Result<Endpoint> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if let Some(endpoint) = endpoints.get(&server.is_ipv6()) {
+            return Ok(endpoint.clone());
+        }
+
+        let local = match server {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            None,
+            UdpSocket::bind(local)?,
+            Arc::new(TokioRuntime),
+        )?;
+        endpoints.insert(server.is_ipv6(), endpoint.clone());
+
+        Ok(endpoint)
+    }
+}
+
+/// Send a query on a stream of its own, and read the response off it
+async fn send_query(
+    connection: &Connection,
+    req_buffer: &BytePacketBuffer,
+) -> Result<BytePacketBuffer> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    write_message(&mut send, req_buffer).await?;
+    read_message(&mut recv).await
+}
+
+/// Send the one message of a stream, which is prefixed with its length like
+/// over TCP, and close our end of it (RFC 9250, section 4.2)
+async fn write_message(send: &mut SendStream, buffer: &BytePacketBuffer) -> Result<()> {
+    let mut message = (buffer.pos() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(&buffer.buf[0..buffer.pos()]);
+    send.write_all(&message).await?;
+    send.finish()?;
+
+    Ok(())
+}
+
+async fn read_message(recv: &mut RecvStream) -> Result<BytePacketBuffer> {
+    let mut len = [0; 2];
+    recv.read_exact(&mut len).await?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    recv.read_exact(&mut buf).await?;
+
+    Ok(BytePacketBuffer::from_slice(&buf))
+}
+
+/// Load the certificate chain and private key to serve DNS over QUIC with,
+/// both in PEM format, see `doh::load_server_config`
+pub fn load_server_config<P: AsRef<Path>>(cert: P, key: P) -> Result<rustls::ServerConfig> {
+    let mut config = doh::load_server_config(cert, key)?;
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    // QUIC takes either no 0-RTT data at all, or as much as the client sends
+    config.max_early_data_size = u32::MAX;
+
+    Ok(config)
+}
+
+/// Serve DNS over QUIC on `socket` until `options.shutdown` stops. Every
+/// stream of a connection carries a query of its own, and they're answered
+/// in whatever order they're resolved in, on the blocking thread pool of a
+/// runtime that's started for the purpose.
+///
+/// Clients resuming a session may send their queries in 0-RTT data, which
+/// are answered right away. That data can be replayed by anyone who saw it
+/// go by, which does no harm for queries, but updates wait for the handshake
+/// to prove that the client is really there (RFC 9250, section 4.5).
+pub fn serve(
+    socket: UdpSocket,
+    config: Arc<rustls::ServerConfig>,
+    options: Arc<ResolverOptions>,
+) -> Result<()> {
+    let crypto = QuicServerConfig::try_from(config).map_err(|e| DnsError::Tls(e.to_string()))?;
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(TCP_IDLE_TIMEOUT.try_into().ok());
+    let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+
+    let runtime = Runtime::new()?;
+    runtime.block_on(async move {
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(config),
+            socket,
+            Arc::new(TokioRuntime),
+        )?;
+
+        while !options.shutdown.is_stopping() {
+            let incoming = match timeout(shutdown::POLL_INTERVAL, endpoint.accept()).await {
+                Ok(Some(incoming)) => incoming,
+                Ok(None) => break,
+                Err(_) => continue,
+            };
+
+            let options = options.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(incoming, options).await {
+                    error!("An error occurred: {}", e);
+                }
+            });
+        }
+
+        endpoint.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+        Ok(())
+    })
+}
+
+/// Answer the queries on a connection until the client closes it, or leaves
+/// it idle for too long
+async fn handle_connection(incoming: Incoming, options: Arc<ResolverOptions>) -> Result<()> {
+    let _connection = options.metrics.as_ref().map(Metrics::connection);
+
+    let (done, handshake_done) = watch::channel(false);
+    let connection = match incoming.accept()?.into_0rtt() {
+        Ok((connection, handshake)) => {
+            tokio::spawn(async move {
+                if handshake.await {
+                    done.send_replace(true);
+                }
+            });
+            connection
+        }
+        Err(connecting) => {
+            let connection = connecting.await?;
+            done.send_replace(true);
+            connection
+        }
+    };
+
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            // The client is done
+            Err(ConnectionError::ApplicationClosed(_) | ConnectionError::TimedOut) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let connection = connection.clone();
+        let handshake_done = handshake_done.clone();
+        let options = options.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(&connection, send, recv, handshake_done, options).await {
+                debug!(
+                    "Failed to answer query from {} over QUIC: {}",
+                    connection.remote_address(),
+                    e
+                );
+            }
+        });
+    }
+}
+
+/// Answer the query on a stream, and close the stream
+async fn handle_stream(
+    connection: &Connection,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    mut handshake_done: watch::Receiver<bool>,
+    options: Arc<ResolverOptions>,
+) -> Result<()> {
+    let mut req_buffer = read_message(&mut recv).await?;
+    let header = req_buffer.get_range(0, 3)?;
+    let (id, opcode) = (
+        u16::from_be_bytes([header[0], header[1]]),
+        Opcode::from_num((header[2] >> 3) & 0x0F),
+    );
+
+    // Clients that send anything but a zero ID don't speak the protocol
+    if id != 0 {
+        connection.close(VarInt::from_u32(DOQ_PROTOCOL_ERROR), b"Nonzero message ID");
+        return Err(DnsError::InvalidResponse(
+            "Query over QUIC with a nonzero message ID".to_string(),
+        ));
+    }
+    if opcode == Opcode::UPDATE {
+        handshake_done
+            .wait_for(|done| *done)
+            .await
+            .map_err(|_| DnsError::Quic("Handshake failed".to_string()))?;
+    }
+
+    let src = connection.remote_address();
+    let res_buffer = respond(req_buffer, src, Transport::Tcp, options).await?;
+    write_message(&mut send, &res_buffer).await
+}
@@ -0,0 +1,229 @@
+//! EDNS Client Subnet (RFC 7871). Upstreams such as those of CDNs answer with
+//! the servers closest to the client, rather than to us, when told which
+//! network the client is in. Only the first bits of its address are passed
+//! on, a /24 for IPv4 and a /56 for IPv6 unless configured otherwise, which
+//! is enough to place the client without giving away who it is.
+//!
+//! The upstream says in turn which network its answer is meant for, the
+//! scope, and answers meant for a network of clients are only handed out to
+//! clients in it, see `Cache::get_scoped`.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::{
+    dns_packet::DnsPacket,
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+};
+
+/// The code of the option in the OPT record
+pub const OPTION_CODE: u16 = 8;
+
+/// The bits of the addresses of clients passed on unless configured
+/// otherwise, the most RFC 7871 recommends
+pub const DEFAULT_IPV4_PREFIX: u8 = 24;
+pub const DEFAULT_IPV6_PREFIX: u8 = 56;
+
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+/// The network a query is asked on behalf of, or an answer is meant for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientSubnet {
+    /// The address, with everything past `source_prefix` zeroed
+    pub addr: IpAddr,
+    /// How many bits of the address are given
+    pub source_prefix: u8,
+    /// How many bits of the address the answer depends on, which is 0 in
+    /// queries
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// The network of the first `prefix` bits of `addr`, for a query
+    pub fn new(addr: IpAddr, prefix: u8) -> ClientSubnet {
+        let addr = addr.to_canonical();
+        let prefix = prefix.min(max_prefix(addr));
+
+        ClientSubnet {
+            addr: truncate(addr, prefix),
+            source_prefix: prefix,
+            scope_prefix: 0,
+        }
+    }
+
+    /// The option in the format it takes in the data of an OPT record,
+    /// starting with its code and length
+    pub fn to_option(&self) -> Vec<u8> {
+        let (family, octets) = match self.addr {
+            IpAddr::V4(addr) => (FAMILY_IPV4, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (FAMILY_IPV6, addr.octets().to_vec()),
+        };
+        // Only as many bytes of the address as the prefix takes
+        let len = (self.source_prefix as usize).div_ceil(8);
+
+        let mut data = Vec::with_capacity(8 + len);
+        data.extend_from_slice(&OPTION_CODE.to_be_bytes());
+        data.extend_from_slice(&(4 + len as u16).to_be_bytes());
+        data.extend_from_slice(&family.to_be_bytes());
+        data.push(self.source_prefix);
+        data.push(self.scope_prefix);
+        data.extend_from_slice(&octets[..len]);
+        data
+    }
+
+    /// Read the data of the option, without its code and length
+    pub fn parse(data: &[u8]) -> Result<ClientSubnet> {
+        let invalid = || DnsError::Parse("Invalid client subnet option".to_string());
+        if data.len() < 4 {
+            return Err(invalid());
+        }
+
+        let family = u16::from_be_bytes([data[0], data[1]]);
+        let (source_prefix, scope_prefix) = (data[2], data[3]);
+        let address = &data[4..];
+        let addr = match family {
+            FAMILY_IPV4 if address.len() <= 4 => {
+                let mut octets = [0; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            FAMILY_IPV6 if address.len() <= 16 => {
+                let mut octets = [0; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err(invalid()),
+        };
+        if source_prefix > max_prefix(addr)
+            || scope_prefix > max_prefix(addr)
+            || address.len() != (source_prefix as usize).div_ceil(8)
+        {
+            return Err(invalid());
+        }
+
+        Ok(ClientSubnet {
+            addr,
+            source_prefix,
+            scope_prefix,
+        })
+    }
+
+    /// The option in the OPT record of a packet, if it has one that's valid
+    pub fn from_packet(packet: &DnsPacket) -> Option<ClientSubnet> {
+        let Some(DnsRecord::OPT { data, .. }) = packet.get_opt() else {
+            return None;
+        };
+
+        let mut rest = &data[..];
+        while rest.len() >= 4 {
+            let code = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let option = rest.get(4..4 + len)?;
+            if code == OPTION_CODE {
+                return ClientSubnet::parse(option).ok();
+            }
+            rest = &rest[4 + len..];
+        }
+
+        None
+    }
+
+    /// The scope of the answer to a query asked on behalf of this network.
+    /// Upstreams that leave the option out, or answer for another network
+    /// than the one asked about, mean their answer is for everyone, scope 0.
+    pub fn scope_of(&self, response: &DnsPacket) -> u8 {
+        match ClientSubnet::from_packet(response) {
+            Some(answered)
+                if answered.addr == self.addr && answered.source_prefix == self.source_prefix =>
+            {
+                answered.scope_prefix
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// The network in the usual notation, e.g. `192.0.2.0/24`
+impl fmt::Display for ClientSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.source_prefix)
+    }
+}
+
+/// How much of the addresses of clients is passed on to the upstreams
+#[derive(Clone, Copy, Debug)]
+pub struct ClientSubnetPolicy {
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for ClientSubnetPolicy {
+    fn default() -> ClientSubnetPolicy {
+        ClientSubnetPolicy {
+            ipv4_prefix: DEFAULT_IPV4_PREFIX,
+            ipv6_prefix: DEFAULT_IPV6_PREFIX,
+        }
+    }
+}
+
+impl ClientSubnetPolicy {
+    pub fn new() -> ClientSubnetPolicy {
+        ClientSubnetPolicy::default()
+    }
+
+    /// The network to pass on for a client. The addresses of private
+    /// networks and the like say nothing about where the client is to
+    /// anyone outside of them, so they aren't passed on at all.
+    pub fn subnet_for(&self, client: IpAddr) -> Option<ClientSubnet> {
+        let client = client.to_canonical();
+        let local = match client {
+            IpAddr::V4(addr) => {
+                addr.is_private()
+                    || addr.is_loopback()
+                    || addr.is_link_local()
+                    || addr.is_unspecified()
+            }
+            IpAddr::V6(addr) => {
+                addr.is_loopback()
+                    || addr.is_unspecified()
+                    // Unique local and link-local addresses
+                    || addr.segments()[0] & 0xfe00 == 0xfc00
+                    || addr.segments()[0] & 0xffc0 == 0xfe80
+            }
+        };
+        if local {
+            return None;
+        }
+
+        let prefix = match client {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        Some(ClientSubnet::new(client, prefix))
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// The address with everything past the first `prefix` bits zeroed
+fn truncate(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+        }
+    }
+}
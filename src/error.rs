@@ -0,0 +1,259 @@
+use std::{fmt, io, net::AddrParseError, num::ParseIntError};
+
+use crate::query_type::QueryType;
+
+pub type Result<T> = std::result::Result<T, DnsError>;
+
+/// Everything that can go wrong, from parsing packets to talking to other
+/// servers. The variants from `EndOfBuffer` up to `UnsupportedRecordType` are
+/// problems with a packet itself, see `is_malformed_packet`.
+#[derive(Debug)]
+pub enum DnsError {
+    /// Writing past the end of the buffer. It's kept apart from the other
+    /// errors so that packet writers can tell a packet that's too large, which
+    /// can still be sent truncated, from one that can't be written at all.
+    BufferOverflow,
+    /// Reading past the end of the buffer, a telltale sign of a truncated or
+    /// corrupt packet
+    EndOfBuffer,
+    /// A label of `len` octets at `offset` that runs past the end of the
+    /// buffer
+    TruncatedLabel {
+        len: u8,
+        offset: usize,
+    },
+    /// A label of `len` octets at `offset` of the buffer, read or to be
+    /// written, which is more than the 63 a label may take up
+    LabelTooLong {
+        len: usize,
+        offset: usize,
+    },
+    /// A name longer than the given number of octets
+    NameTooLong(usize),
+    /// A name with more compression pointers than the given limit, which is
+    /// usually a loop of pointers
+    JumpLimitExceeded(usize),
+    /// A compression pointer at the first offset that points forward to the
+    /// second, rather than back at a name that came before it
+    ForwardPointer(usize, usize),
+    /// A section that holds fewer entries than the header says it does
+    CountMismatch {
+        section: &'static str,
+        expected: u16,
+        found: u16,
+    },
+    /// A message of the given length, which is more than the 65535 bytes a
+    /// message may take up
+    MessageTooLong(usize),
+    /// Record data that doesn't add up, e.g. a field that runs past the end
+    /// of the record
+    InvalidRecord(String),
+    /// A record type we don't know how to handle in the given context
+    UnsupportedRecordType(QueryType),
+    /// A response that doesn't answer the query it's supposed to answer
+    InvalidResponse(String),
+    /// Text that doesn't parse, such as an entry of a zone file or an address
+    Parse(String),
+    /// Something this build or this server doesn't support
+    Unsupported(String),
+    /// A DNS over HTTPS exchange that went wrong at the HTTP level
+    Http(String),
+    /// A failed TLS handshake, including certificates that don't validate
+    Tls(String),
+    /// A DNS over QUIC connection or stream that failed, such as one the
+    /// peer closed or reset
+    Quic(String),
+    /// A response that fails DNSSEC validation, such as one with a signature
+    /// that doesn't verify, or without the signatures its zone should have
+    Dnssec(String),
+    /// There were no servers to send the query to
+    NoServers,
+    /// A server didn't answer in time, not even after sending the query again
+    Timeout,
+    /// A response held back by the rate limiter, which is not to be sent at
+    /// all
+    RateLimited,
+    /// A packet that's a response rather than a query, which is dropped
+    /// instead of answered, so that two servers can't be set to answer each
+    /// other forever
+    NotAQuery,
+    Io(io::Error),
+}
+
+impl DnsError {
+    /// The error of a read from a socket with a read timeout, which reports
+    /// that the timeout ran out with `WouldBlock` rather than `TimedOut` on
+    /// some platforms. Only then is `WouldBlock` a timeout, everywhere else
+    /// it's left as it is.
+    pub fn from_timed_read(e: io::Error) -> DnsError {
+        match e.kind() {
+            io::ErrorKind::WouldBlock => DnsError::Timeout,
+            _ => DnsError::from(e),
+        }
+    }
+
+    /// Whether the error is caused by a malformed packet, as opposed to e.g.
+    /// a network failure. A server answers queries like that with `FORMERR`.
+    pub fn is_malformed_packet(&self) -> bool {
+        matches!(
+            self,
+            DnsError::EndOfBuffer
+                | DnsError::TruncatedLabel { .. }
+                | DnsError::LabelTooLong { .. }
+                | DnsError::NameTooLong(_)
+                | DnsError::JumpLimitExceeded(_)
+                | DnsError::ForwardPointer(..)
+                | DnsError::CountMismatch { .. }
+                | DnsError::MessageTooLong(_)
+                | DnsError::InvalidRecord(_)
+                | DnsError::UnsupportedRecordType(_)
+        )
+    }
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsError::BufferOverflow => write!(f, "Packet doesn't fit in the buffer"),
+            DnsError::EndOfBuffer => write!(f, "End of buffer"),
+            DnsError::TruncatedLabel { len, offset } => write!(
+                f,
+                "Label of length {} at offset {} runs past the end of the buffer",
+                len, offset
+            ),
+            DnsError::LabelTooLong { len, offset } => write!(
+                f,
+                "Label of length {} at offset {} exceeds 63 octets",
+                len, offset
+            ),
+            DnsError::NameTooLong(max) => write!(f, "Name exceeds {} octets of length", max),
+            DnsError::JumpLimitExceeded(max) => write!(f, "Limit of {} jumps exceeded", max),
+            DnsError::ForwardPointer(at, to) => write!(
+                f,
+                "Compression pointer at offset {} points forward to {}",
+                at, to
+            ),
+            DnsError::CountMismatch {
+                section,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Header announces {} {} entries, but the packet holds only {}",
+                expected, section, found
+            ),
+            DnsError::MessageTooLong(len) => {
+                write!(f, "Message of {} bytes exceeds 65535 bytes", len)
+            }
+            DnsError::InvalidRecord(reason) => write!(f, "{}", reason),
+            DnsError::UnsupportedRecordType(qtype) => {
+                write!(f, "Unsupported record type {:?}", qtype)
+            }
+            DnsError::InvalidResponse(reason) => write!(f, "{}", reason),
+            DnsError::Parse(reason) => write!(f, "{}", reason),
+            DnsError::Unsupported(reason) => write!(f, "{}", reason),
+            DnsError::Http(reason) => write!(f, "{}", reason),
+            DnsError::Tls(reason) => write!(f, "{}", reason),
+            DnsError::Quic(reason) => write!(f, "{}", reason),
+            DnsError::Dnssec(reason) => write!(f, "DNSSEC validation failed: {}", reason),
+            DnsError::NoServers => write!(f, "No servers to send the query to"),
+            DnsError::Timeout => write!(f, "Timed out waiting for a response"),
+            DnsError::RateLimited => write!(f, "Response dropped by the rate limiter"),
+            DnsError::NotAQuery => write!(f, "Packet is a response, not a query"),
+            DnsError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DnsError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DnsError {
+    fn from(e: io::Error) -> DnsError {
+        match e.kind() {
+            io::ErrorKind::TimedOut => DnsError::Timeout,
+            _ => DnsError::Io(e),
+        }
+    }
+}
+
+impl From<ParseIntError> for DnsError {
+    fn from(e: ParseIntError) -> DnsError {
+        DnsError::Parse(e.to_string())
+    }
+}
+
+impl From<AddrParseError> for DnsError {
+    fn from(e: AddrParseError) -> DnsError {
+        DnsError::Parse(e.to_string())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::Error> for DnsError {
+    fn from(e: rustls::Error) -> DnsError {
+        DnsError::Tls(e.to_string())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::pki_types::InvalidDnsNameError> for DnsError {
+    fn from(e: rustls::pki_types::InvalidDnsNameError) -> DnsError {
+        DnsError::Tls(e.to_string())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::pki_types::pem::Error> for DnsError {
+    fn from(e: rustls::pki_types::pem::Error) -> DnsError {
+        DnsError::Tls(e.to_string())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl From<tokio::time::error::Elapsed> for DnsError {
+    fn from(_: tokio::time::error::Elapsed) -> DnsError {
+        DnsError::Timeout
+    }
+}
+
+#[cfg(feature = "doq")]
+impl From<quinn::ConnectError> for DnsError {
+    fn from(e: quinn::ConnectError) -> DnsError {
+        DnsError::Quic(e.to_string())
+    }
+}
+
+#[cfg(feature = "doq")]
+impl From<quinn::ConnectionError> for DnsError {
+    fn from(e: quinn::ConnectionError) -> DnsError {
+        DnsError::Quic(e.to_string())
+    }
+}
+
+#[cfg(feature = "doq")]
+impl From<quinn::WriteError> for DnsError {
+    fn from(e: quinn::WriteError) -> DnsError {
+        DnsError::Quic(e.to_string())
+    }
+}
+
+#[cfg(feature = "doq")]
+impl From<quinn::ReadExactError> for DnsError {
+    fn from(e: quinn::ReadExactError) -> DnsError {
+        DnsError::Quic(e.to_string())
+    }
+}
+
+#[cfg(feature = "doq")]
+impl From<quinn::ClosedStream> for DnsError {
+    fn from(e: quinn::ClosedStream) -> DnsError {
+        DnsError::Quic(e.to_string())
+    }
+}
@@ -0,0 +1,548 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    client::{self, QueryOptions},
+    dns_packet::DnsPacket,
+    error::{DnsError, Result},
+    name,
+    pool::{ConnectionPool, TcpConnection},
+    query_type::QueryType,
+    result_code::ResultCode,
+};
+
+#[cfg(feature = "tls")]
+use crate::{doh, tls::TlsClient};
+
+#[cfg(feature = "doq")]
+use crate::doq::QuicClient;
+
+/// The port DNS over TLS is served on, and DNS over QUIC as well, over UDP
+const TLS_PORT: u16 = 853;
+
+/// The port DNS over HTTPS is served on, unless the URL says otherwise
+const HTTPS_PORT: u16 = 443;
+
+/// How often the latency based policy gives an upstream other than the
+/// fastest one a go, to find out whether it has gotten any faster.
+const PROBE_INTERVAL: u64 = 16;
+
+/// How long an upstream that failed to answer is passed over for. Every
+/// further failure in a row doubles it, up to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest an upstream is passed over for, however often it failed
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The order in which the upstreams are tried
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SelectionPolicy {
+    /// Always in the order they were configured in
+    #[default]
+    InOrder,
+    /// Each query starting at the upstream after the one the previous query
+    /// started at, spreading the load evenly
+    RoundRobin,
+    /// Fastest first, based on the response times measured so far
+    Fastest,
+}
+
+impl FromStr for SelectionPolicy {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<SelectionPolicy> {
+        match s {
+            "in-order" => Ok(SelectionPolicy::InOrder),
+            "round-robin" => Ok(SelectionPolicy::RoundRobin),
+            "fastest" => Ok(SelectionPolicy::Fastest),
+            _ => Err(DnsError::Parse(format!(
+                "Unknown upstream selection policy: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// An upstream resolver, along with the way queries are sent to it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Upstream {
+    /// Plain DNS over UDP, retrying over TCP when a response is truncated
+    Plain(SocketAddr),
+    /// DNS over TLS, with a certificate that's valid for `name`
+    Tls { addr: SocketAddr, name: String },
+    /// DNS over HTTPS
+    Https(DohUrl),
+    /// DNS over QUIC, with a certificate that's valid for `name`
+    Quic { addr: SocketAddr, name: String },
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Plain(addr) => write!(f, "{}", addr),
+            Upstream::Tls { addr, name } => write!(f, "tls://{}#{}", addr, name),
+            Upstream::Https(url) => write!(f, "{}", url),
+            Upstream::Quic { addr, name } => write!(f, "quic://{}#{}", addr, name),
+        }
+    }
+}
+
+impl Upstream {
+    /// The address queries are sent to
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Upstream::Plain(addr) | Upstream::Tls { addr, .. } | Upstream::Quic { addr, .. } => {
+                *addr
+            }
+            Upstream::Https(url) => url.addr,
+        }
+    }
+}
+
+/// Where to send DNS over HTTPS queries to (RFC 8484)
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DohUrl {
+    /// The host of the URL, which the certificate has to be valid for
+    pub host: String,
+    /// The address the host resolved to
+    pub addr: SocketAddr,
+    /// The path of the endpoint, usually `/dns-query`
+    pub path: String,
+    /// Whether queries are sent as the `dns` parameter of a GET request,
+    /// rather than as the body of a POST request
+    pub get: bool,
+}
+
+impl DohUrl {
+    /// Parse a URL such as `https://dns.google/dns-query`. Following RFC 8484
+    /// it may be a URI template, and ending it in `{?dns}` sends the queries
+    /// with GET requests instead of POST requests. A host name is resolved
+    /// right away through the system resolver, since we're not in a position
+    /// to resolve the name of our own upstream.
+    pub fn parse(url: &str) -> Result<DohUrl> {
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| DnsError::Parse(format!("Not an https URL: {}", url)))?;
+        let (rest, get) = match rest.strip_suffix("{?dns}") {
+            Some(rest) => (rest, true),
+            None => (rest, false),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/dns-query"),
+        };
+
+        // Any IPv6 address is in brackets, so that it's not confused with the
+        // port
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse::<u16>()?),
+            _ => (authority, HTTPS_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(DnsError::Parse(format!("Missing host in URL: {}", url)));
+        }
+
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| DnsError::Parse(format!("No address found for {}", host)))?;
+
+        Ok(DohUrl {
+            host: host.to_string(),
+            addr,
+            path: path.to_string(),
+            get,
+        })
+    }
+}
+
+impl fmt::Display for DohUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        let port = match self.addr.port() {
+            HTTPS_PORT => String::new(),
+            port => format!(":{}", port),
+        };
+        let template = if self.get { "{?dns}" } else { "" };
+        write!(f, "https://{}{}{}{}", host, port, self.path, template)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct UpstreamStats {
+    /// Smoothed response time, in the style of TCP's SRTT
+    srtt: Duration,
+    /// The query count at the time of the last measurement
+    last_measured: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Health {
+    /// How many queries in a row the upstream failed to answer
+    failures: u32,
+    /// Until when the upstream is tried only after all the others
+    down_until: Instant,
+}
+
+#[derive(Debug, Default)]
+struct UpstreamTracker {
+    stats: HashMap<Upstream, UpstreamStats>,
+    /// The upstreams that failed to answer the last query sent to them
+    health: HashMap<Upstream, Health>,
+    queries: u64,
+}
+
+/// Forwards queries to a list of upstream resolvers instead of resolving them
+/// recursively. The upstreams are tried one after the other, moving on to the
+/// next one whenever an upstream fails to answer or answers with `SERVFAIL`,
+/// since that's often a transient problem or specific to that one upstream.
+///
+/// An upstream that fails to answer at all is considered down, and is tried
+/// only after all the others for a while. That while starts at a second and
+/// doubles with every failure in a row, so that a dead upstream doesn't cost
+/// every query a timeout, while one that comes back is noticed soon enough.
+///
+/// The connections to upstreams over TCP and TLS are kept open, and shared
+/// by the queries sent to them, see `pool`.
+#[derive(Clone, Debug)]
+pub struct Forwarder {
+    pub upstreams: Vec<Upstream>,
+    pub policy: SelectionPolicy,
+    tracker: Arc<Mutex<UpstreamTracker>>,
+    /// The connections to plain upstreams, for the responses that don't fit
+    /// in a datagram
+    tcp: ConnectionPool<SocketAddr>,
+    #[cfg(feature = "tls")]
+    tls: TlsClient,
+    #[cfg(feature = "doq")]
+    quic: QuicClient,
+}
+
+impl Forwarder {
+    pub fn new(upstreams: Vec<Upstream>) -> Forwarder {
+        Forwarder {
+            upstreams,
+            policy: SelectionPolicy::default(),
+            tracker: Arc::new(Mutex::new(UpstreamTracker::default())),
+            tcp: ConnectionPool::default(),
+            #[cfg(feature = "tls")]
+            tls: TlsClient::new(),
+            #[cfg(feature = "doq")]
+            quic: QuicClient::new(),
+        }
+    }
+
+    /// Parse an upstream, which is either the address of a plain DNS server,
+    /// e.g. `8.8.8.8`, that of a DNS over TLS server prefixed with `tls://`,
+    /// that of a DNS over QUIC server prefixed with `quic://`, or the URL of a
+    /// DNS over HTTPS server, see `DohUrl::parse`.
+    ///
+    /// The certificate of a DNS over TLS or QUIC server has to be valid for
+    /// its address, unless a name to check it against follows after a `#`, as
+    /// in `tls://8.8.8.8#dns.google`. The port may be left out if it's the
+    /// default port, 53 for plain DNS and 853 for the others.
+    pub fn parse_upstream(s: &str) -> Result<Upstream> {
+        if s.starts_with("tls://") || s.starts_with("https://") {
+            if cfg!(not(feature = "tls")) {
+                return Err(DnsError::Unsupported(
+                    "Built without support for encrypted upstreams".to_string(),
+                ));
+            }
+            if s.starts_with("https://") {
+                return Ok(Upstream::Https(DohUrl::parse(s)?));
+            }
+        }
+        if s.starts_with("quic://") && cfg!(not(feature = "doq")) {
+            return Err(DnsError::Unsupported(
+                "Built without support for DNS over QUIC".to_string(),
+            ));
+        }
+
+        let (s, quic) = match (s.strip_prefix("tls://"), s.strip_prefix("quic://")) {
+            (Some(s), _) => (s, false),
+            (_, Some(s)) => (s, true),
+            (None, None) => return Ok(Upstream::Plain(parse_addr(s, 53)?)),
+        };
+        let (addr, name) = match s.split_once('#') {
+            Some((addr, name)) => (addr, Some(name)),
+            None => (s, None),
+        };
+        let addr = parse_addr(addr, TLS_PORT)?;
+        let name = name.map_or_else(|| addr.ip().to_string(), str::to_string);
+
+        Ok(match quic {
+            true => Upstream::Quic { addr, name },
+            false => Upstream::Tls { addr, name },
+        })
+    }
+
+    /// The upstreams in the order they should be tried for the next query
+    pub fn select(&self) -> Vec<Upstream> {
+        let mut upstreams = self.upstreams.clone();
+        let mut tracker = self.tracker.lock().unwrap();
+        tracker.queries += 1;
+
+        match self.policy {
+            SelectionPolicy::InOrder => {}
+            SelectionPolicy::RoundRobin => {
+                if !upstreams.is_empty() {
+                    let len = upstreams.len();
+                    upstreams.rotate_left(((tracker.queries - 1) % len as u64) as usize);
+                }
+            }
+            SelectionPolicy::Fastest => {
+                // Upstreams we haven't heard from yet go first, so that every
+                // one of them gets measured at least once.
+                upstreams
+                    .sort_by_key(|upstream| tracker.stats.get(upstream).map(|stats| stats.srtt));
+
+                // Their speed changes over time, so every once in a while the
+                // one we have the oldest measurement for is moved to the
+                // front, to see whether it has gotten any faster.
+                if tracker.queries.is_multiple_of(PROBE_INTERVAL) {
+                    let stalest = upstreams
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, upstream)| {
+                            tracker.stats.get(upstream).map(|stats| stats.last_measured)
+                        })
+                        .map(|(i, _)| i);
+                    if let Some(i) = stalest {
+                        let upstream = upstreams.remove(i);
+                        debug!("Probing upstream {}", upstream);
+                        upstreams.insert(0, upstream);
+                    }
+                }
+            }
+        }
+
+        // Upstreams that are down go last, the ones that can be tried again
+        // the soonest first. They are still tried, in case the others turn
+        // out to be down as well.
+        let now = Instant::now();
+        upstreams.sort_by_key(|upstream| {
+            tracker
+                .health
+                .get(upstream)
+                .map(|health| health.down_until)
+                .filter(|&down_until| down_until > now)
+        });
+
+        upstreams
+    }
+
+    /// Whether the upstream is passed over until the others have been tried
+    pub fn is_down(&self, upstream: &Upstream) -> bool {
+        let tracker = self.tracker.lock().unwrap();
+        tracker
+            .health
+            .get(upstream)
+            .is_some_and(|health| health.down_until > Instant::now())
+    }
+
+    /// Fold a new response time into the smoothed response time of an
+    /// upstream, and keep track of whether it's up
+    fn record_result(&self, upstream: &Upstream, rtt: Duration, answered: bool) {
+        let mut tracker = self.tracker.lock().unwrap();
+        let queries = tracker.queries;
+
+        let stats = tracker
+            .stats
+            .entry(upstream.clone())
+            .or_insert(UpstreamStats {
+                srtt: rtt,
+                last_measured: queries,
+            });
+        stats.srtt = (stats.srtt * 7 + rtt) / 8;
+        stats.last_measured = queries;
+
+        if answered {
+            if tracker.health.remove(upstream).is_some() {
+                info!("Upstream {} is answering again", upstream);
+            }
+            return;
+        }
+
+        let failures = tracker
+            .health
+            .get(upstream)
+            .map_or(0, |health| health.failures)
+            + 1;
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(MAX_BACKOFF);
+        warn!(
+            "Upstream {} failed {} time(s) in a row, trying it last for {:?}",
+            upstream, failures, backoff
+        );
+        tracker.health.insert(
+            upstream.clone(),
+            Health {
+                failures,
+                down_until: Instant::now() + backoff,
+            },
+        );
+    }
+
+    pub fn forward(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        options: &QueryOptions,
+    ) -> Result<DnsPacket> {
+        let mut last_servfail = None;
+        let mut last_error = None;
+
+        let mut upstreams = self.select();
+        if let Some(family) = options.prefer_family {
+            client::prefer_family(&mut upstreams, family, |upstream| upstream.addr().ip());
+        }
+
+        for upstream in upstreams {
+            let start = Instant::now();
+            let result = self.query(&upstream, qname, qtype, options);
+
+            // A failed query took at least as long as the timeout, or it was
+            // refused outright. Either way it counts against the upstream.
+            let rtt = match result {
+                Ok(_) => start.elapsed(),
+                Err(_) => start.elapsed().max(options.timeout),
+            };
+            self.record_result(&upstream, rtt, result.is_ok());
+
+            match result {
+                Ok(response) if response.header.rescode == ResultCode::SERVFAIL => {
+                    warn!(
+                        "Upstream {} failed to resolve {}, trying next",
+                        upstream, qname
+                    );
+                    last_servfail = Some(response);
+                }
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    warn!("Upstream {} failed: {}, trying next", upstream, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        // If every upstream gave up, a SERVFAIL is still a proper answer to
+        // relay to the client, whereas an error only tells us nobody answered.
+        if let Some(response) = last_servfail {
+            return Ok(response);
+        }
+
+        Err(last_error.unwrap_or(DnsError::NoServers))
+    }
+
+    fn query(
+        &self,
+        upstream: &Upstream,
+        qname: &str,
+        qtype: QueryType,
+        options: &QueryOptions,
+    ) -> Result<DnsPacket> {
+        match upstream {
+            Upstream::Plain(addr) => {
+                client::query_with_tcp(qname, qtype, *addr, options, |req_buffer| {
+                    self.tcp.exchange(addr, req_buffer, options.timeout, || {
+                        TcpConnection::connect(*addr, options.timeout)
+                    })
+                })
+            }
+            #[cfg(feature = "tls")]
+            Upstream::Tls { addr, name } => self.tls.query(qname, qtype, *addr, name, options),
+            #[cfg(feature = "tls")]
+            Upstream::Https(url) => doh::query(&self.tls, qname, qtype, url, options),
+            #[cfg(not(feature = "tls"))]
+            Upstream::Tls { .. } | Upstream::Https(_) => Err(DnsError::Unsupported(
+                "Built without support for encrypted upstreams".to_string(),
+            )),
+            #[cfg(feature = "doq")]
+            Upstream::Quic { addr, name } => self.quic.query(qname, qtype, *addr, name, options),
+            #[cfg(not(feature = "doq"))]
+            Upstream::Quic { .. } => Err(DnsError::Unsupported(
+                "Built without support for DNS over QUIC".to_string(),
+            )),
+        }
+    }
+}
+
+/// Forwarders for the names in particular domains, such as those of a
+/// corporate network that only its own name servers know about, with the
+/// rest of the names left to the global upstreams. A name is routed to the
+/// forwarder of the most specific domain it's in.
+#[derive(Clone, Debug, Default)]
+pub struct DomainRoutes {
+    routes: HashMap<String, Forwarder>,
+}
+
+impl DomainRoutes {
+    pub fn new() -> DomainRoutes {
+        DomainRoutes::default()
+    }
+
+    /// Forward the names in `domain`, meaning the domain itself and every
+    /// name below it, through `forwarder`. The domain may be written as
+    /// `*.corp.example` as well as `corp.example`.
+    pub fn insert(&mut self, domain: &str, forwarder: Forwarder) {
+        let domain = domain.strip_prefix("*.").unwrap_or(domain);
+        self.routes.insert(name::normalize(domain), forwarder);
+    }
+
+    /// The forwarder of the most specific domain that `qname` is in, if it's
+    /// in any of them
+    pub fn route(&self, qname: &str) -> Option<&Forwarder> {
+        if self.routes.is_empty() {
+            return None;
+        }
+
+        let mut name = name::normalize(qname);
+        loop {
+            if let Some(forwarder) = self.routes.get(&name) {
+                return Some(forwarder);
+            }
+            match name.split_once('.') {
+                Some((_, parent)) => name = parent.to_string(),
+                None => return None,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+/// Parse a route written as `DOMAIN=UPSTREAM`, e.g. `corp.example=10.0.0.53`,
+/// with the upstream in the format of `Forwarder::parse_upstream`
+pub fn parse_route(s: &str) -> Result<(String, Upstream)> {
+    let (domain, upstream) = s
+        .split_once('=')
+        .ok_or_else(|| DnsError::Parse(format!("Expected DOMAIN=UPSTREAM for a route: {}", s)))?;
+    Ok((domain.to_string(), Forwarder::parse_upstream(upstream)?))
+}
+
+/// Parse an address, which may leave out the port if it's `default_port`
+pub(crate) fn parse_addr(s: &str, default_port: u16) -> Result<SocketAddr> {
+    match s.parse::<SocketAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(_) => Ok(SocketAddr::new(s.parse::<IpAddr>()?, default_port)),
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+
+use crate::{
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+    name,
+    query_type::QueryType,
+};
+
+/// How often the addresses are probed unless configured otherwise
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a probe may take before it counts as failed
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many probes in a row fail before an address counts as down, so that
+/// a single dropped connection doesn't take it out of the answers
+pub const FAILURES_UNTIL_DOWN: u32 = 2;
+
+/// How to tell whether the backend at an address is up
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Probe {
+    /// A TCP connection to the port can be opened
+    Tcp(u16),
+    /// A GET of the path on the port gets a 2xx or 3xx status back
+    Http { port: u16, path: String },
+}
+
+/// Probes are written as `tcp:PORT` or `http:PORT`, the latter optionally
+/// followed by the path to get, e.g. `http:8080/healthz`
+impl FromStr for Probe {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Probe> {
+        let invalid = || DnsError::Parse(format!("Invalid health check: {}", s));
+        let (scheme, rest) = s.split_once(':').ok_or_else(invalid)?;
+        match scheme {
+            "tcp" => Ok(Probe::Tcp(rest.parse().map_err(|_| invalid())?)),
+            "http" => {
+                let (port, path) = match rest.find('/') {
+                    Some(slash) => rest.split_at(slash),
+                    None => (rest, "/"),
+                };
+                Ok(Probe::Http {
+                    port: port.parse().map_err(|_| invalid())?,
+                    path: path.to_string(),
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Probe::Tcp(port) => write!(f, "tcp:{}", port),
+            Probe::Http { port, path } => write!(f, "http:{}{}", port, path),
+        }
+    }
+}
+
+impl Probe {
+    /// Whether the backend at `addr` passes the probe within `timeout`
+    pub fn check(&self, addr: IpAddr, timeout: Duration) -> bool {
+        match self {
+            Probe::Tcp(port) => {
+                TcpStream::connect_timeout(&SocketAddr::new(addr, *port), timeout).is_ok()
+            }
+            Probe::Http { port, path } => http_status(SocketAddr::new(addr, *port), path, timeout)
+                .is_some_and(|status| (200..400).contains(&status)),
+        }
+    }
+}
+
+fn http_status(server: SocketAddr, path: &str, timeout: Duration) -> Option<u16> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout).ok()?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    stream.set_write_timeout(Some(timeout)).ok()?;
+
+    let host = match server.ip() {
+        IpAddr::V4(addr) => addr.to_string(),
+        IpAddr::V6(addr) => format!("[{}]", addr),
+    };
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).ok()?;
+
+    // Only the status line matters, e.g. `HTTP/1.1 200 OK`
+    let mut response = [0; 64];
+    let mut len = 0;
+    while len < response.len() && !response[..len].contains(&b'\n') {
+        match stream.read(&mut response[len..]).ok()? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    let line = std::str::from_utf8(&response[..len]).ok()?;
+    line.strip_prefix("HTTP/")?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// The state of an address, as far as the probes so far went
+#[derive(Clone, Copy, Debug, Default)]
+struct Health {
+    failures: u32,
+}
+
+impl Health {
+    fn is_down(&self) -> bool {
+        self.failures >= FAILURES_UNTIL_DOWN
+    }
+}
+
+/// Probes the backends behind the addresses of local records, and leaves the
+/// addresses of those that are down out of the answers, so that clients fail
+/// over to the others.
+///
+/// Addresses without a probe always count as up, and so do those that
+/// haven't been probed yet. When every address of a name is down, they're all
+/// answered with regardless, as the probes may well be what's broken.
+#[derive(Clone, Debug)]
+pub struct HealthChecks {
+    probes: Vec<(IpAddr, Probe)>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    health: Arc<RwLock<HashMap<IpAddr, Health>>>,
+}
+
+impl Default for HealthChecks {
+    fn default() -> HealthChecks {
+        HealthChecks::new()
+    }
+}
+
+impl HealthChecks {
+    pub fn new() -> HealthChecks {
+        HealthChecks {
+            probes: Vec::new(),
+            interval: DEFAULT_INTERVAL,
+            timeout: DEFAULT_TIMEOUT,
+            health: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn add(&mut self, addr: IpAddr, probe: Probe) {
+        self.probes.push((addr, probe));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.probes.is_empty()
+    }
+
+    pub fn is_up(&self, addr: IpAddr) -> bool {
+        self.health
+            .read()
+            .unwrap()
+            .get(&addr)
+            .is_none_or(|health| !health.is_down())
+    }
+
+    /// Probe every address once, all at the same time. An address with
+    /// several probes has to pass all of them.
+    pub fn check(&self) {
+        let results: Vec<(IpAddr, bool)> = thread::scope(|scope| {
+            let probes: Vec<_> = self
+                .probes
+                .iter()
+                .map(|(addr, probe)| scope.spawn(move || (*addr, probe.check(*addr, self.timeout))))
+                .collect();
+            probes
+                .into_iter()
+                .map(|probe| probe.join().unwrap())
+                .collect()
+        });
+
+        let mut passed: HashMap<IpAddr, bool> = HashMap::new();
+        for (addr, up) in results {
+            *passed.entry(addr).or_insert(true) &= up;
+        }
+
+        let mut health = self.health.write().unwrap();
+        for (addr, up) in passed {
+            let state = health.entry(addr).or_default();
+            let was_down = state.is_down();
+            state.failures = if up {
+                0
+            } else {
+                state.failures.saturating_add(1)
+            };
+
+            if state.is_down() && !was_down {
+                warn!("{} failed its health check, it's left out of answers", addr);
+            } else if was_down && !state.is_down() {
+                info!("{} passed its health check again", addr);
+            }
+        }
+    }
+
+    /// Keep probing the addresses every `interval` for good
+    pub fn run(&self) {
+        loop {
+            self.check();
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// Take the addresses that are down out of the A and AAAA RRsets among
+    /// `records`, unless that would leave an RRset empty
+    pub fn filter(&self, records: &mut Vec<DnsRecord>) {
+        if self.probes.is_empty() {
+            return;
+        }
+
+        let mut kept = Vec::with_capacity(records.len());
+        let mut rest = std::mem::take(records).into_iter().peekable();
+        while let Some(first) = rest.next() {
+            let (domain, qtype) = (first.domain().to_string(), first.query_type());
+            let mut rrset = vec![first];
+            while let Some(record) = rest.next_if(|record| {
+                name::eq(record.domain(), &domain) && record.query_type() == qtype
+            }) {
+                rrset.push(record);
+            }
+
+            if matches!(qtype, QueryType::A | QueryType::AAAA) {
+                let up: Vec<DnsRecord> = rrset
+                    .iter()
+                    .filter(|record| record.ip_addr().is_none_or(|addr| self.is_up(addr)))
+                    .cloned()
+                    .collect();
+                if !up.is_empty() {
+                    rrset = up;
+                }
+            }
+            kept.extend(rrset);
+        }
+
+        *records = kept;
+    }
+}
+
+/// Parse a health check written as `ADDRESS=PROBE`, e.g.
+/// `192.168.1.10=http:8080/healthz`, with the probe as in `Probe::from_str`
+pub fn parse_check(s: &str) -> Result<(IpAddr, Probe)> {
+    let (addr, probe) = s.split_once('=').ok_or_else(|| {
+        DnsError::Parse(format!("Expected ADDRESS=PROBE for a health check: {}", s))
+    })?;
+    Ok((addr.parse()?, probe.parse()?))
+}
@@ -0,0 +1,152 @@
+use std::{collections::HashMap, fs, net::IpAddr, path::Path};
+
+use crate::{
+    dns_packet::{DnsPacket, ResponseBuilder},
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+    nxdomain::negative_response,
+    query_type::QueryType,
+    result_code::ResultCode,
+};
+
+/// How long clients may cache the addresses for unless configured otherwise
+pub const DEFAULT_HOSTS_TTL: u32 = 300;
+
+/// Fixed addresses for names, in the style of `/etc/hosts`, which are
+/// answered locally instead of being looked up. That's handy for giving the
+/// machines of a lab names without setting up a zone for them, or for
+/// pointing a name elsewhere than the rest of the world sees it.
+///
+/// A name with `*.` in front, e.g. `*.lab.example`, gives the same addresses
+/// to everything below it, unless there's an entry of its own for the name
+/// or a more specific wildcard.
+#[derive(Clone, Debug)]
+pub struct Hosts {
+    names: HashMap<String, Vec<IpAddr>>,
+    subdomains: HashMap<String, Vec<IpAddr>>,
+    pub ttl: u32,
+}
+
+impl Default for Hosts {
+    fn default() -> Hosts {
+        Hosts::new()
+    }
+}
+
+impl Hosts {
+    pub fn new() -> Hosts {
+        Hosts {
+            names: HashMap::new(),
+            subdomains: HashMap::new(),
+            ttl: DEFAULT_HOSTS_TTL,
+        }
+    }
+
+    /// Give `name` another address. Names are matched case-insensitively,
+    /// and a trailing dot is ignored.
+    pub fn insert(&mut self, name: &str, addr: IpAddr) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let addrs = match name.strip_prefix("*.") {
+            Some(parent) => self.subdomains.entry(parent.to_string()).or_default(),
+            None => self.names.entry(name).or_default(),
+        };
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    /// Load the entries of a hosts file, returning how many names there
+    /// were. Every line holds an address followed by the names that have it,
+    /// and comments start with `#`.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let mut count = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut fields = line.split_whitespace();
+            let addr = match fields.next() {
+                Some(addr) => addr.parse::<IpAddr>().map_err(|_| {
+                    DnsError::Parse(format!(
+                        "Invalid address on line {} of {}: {}",
+                        i + 1,
+                        path.display(),
+                        addr
+                    ))
+                })?,
+                None => continue,
+            };
+
+            for name in fields {
+                self.insert(name, addr);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// The addresses of `name`, if it has any entries
+    pub fn get(&self, name: &str) -> Option<&[IpAddr]> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(addrs) = self.names.get(&name) {
+            return Some(addrs);
+        }
+
+        let mut parent = name.as_str();
+        while let Some((_, rest)) = parent.split_once('.') {
+            if let Some(addrs) = self.subdomains.get(rest) {
+                return Some(addrs);
+            }
+            parent = rest;
+        }
+
+        None
+    }
+
+    /// The response to a query for `qname`, if the name has entries. Queries
+    /// of types other than A and AAAA, or for a family the name has no
+    /// addresses of, get an empty answer.
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let addrs = self.get(qname)?;
+
+        Some(address_response(qname, qtype, addrs, self.ttl))
+    }
+}
+
+/// The response that gives `qname` the addresses `addrs`, those of the family
+/// asked for that is, or an empty answer for other types
+pub(crate) fn address_response(
+    qname: &str,
+    qtype: QueryType,
+    addrs: &[IpAddr],
+    ttl: u32,
+) -> DnsPacket {
+    let answers: Vec<DnsRecord> = addrs
+        .iter()
+        .filter_map(|&addr| match (addr, qtype) {
+            (IpAddr::V4(addr), QueryType::A | QueryType::ANY) => Some(DnsRecord::A {
+                domain: qname.to_string(),
+                addr,
+                ttl,
+            }),
+            (IpAddr::V6(addr), QueryType::AAAA | QueryType::ANY) => Some(DnsRecord::AAAA {
+                domain: qname.to_string(),
+                addr,
+                ttl,
+            }),
+            _ => None,
+        })
+        .collect();
+
+    if answers.is_empty() {
+        return negative_response(qname, qtype, ResultCode::NOERROR, ttl);
+    }
+
+    ResponseBuilder::new()
+        .authoritative(true)
+        .question(qname, qtype)
+        .answers(answers)
+        .build()
+}
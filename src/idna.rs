@@ -0,0 +1,218 @@
+//! Internationalized domain names. Names like `bücher.example` go out on the
+//! wire with each label that isn't ASCII in the punycode of RFC 3492, behind
+//! the `xn--` prefix of IDNA (RFC 5890), e.g. `xn--bcher-kva.example`, and
+//! are turned back into Unicode for showing them to people.
+//!
+//! Labels are mapped to lowercase before they're encoded, the way IDNA2008
+//! has it for user input (RFC 5895). They aren't normalized to NFC or checked
+//! against the code points IDNA2008 disallows, so names are best given in
+//! the form they're registered in.
+
+/// The prefix of the labels that hold punycode, the ACE prefix
+pub const ACE_PREFIX: &str = "xn--";
+
+// The parameters of punycode, RFC 3492, section 5
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 0x80;
+const DELIMITER: char = '-';
+
+/// The name with every label that isn't ASCII encoded as punycode. ASCII
+/// labels are left as they are, case and all. A label that's too long to
+/// encode is left as it is as well, which fails once it's written out.
+pub fn to_ascii(name: &str) -> String {
+    if name.is_ascii() {
+        return name.to_string();
+    }
+
+    let labels: Vec<String> = name
+        .split('.')
+        .map(|label| {
+            if label.is_ascii() {
+                return label.to_string();
+            }
+            let chars: Vec<char> = label.to_lowercase().chars().collect();
+            match encode(&chars) {
+                Some(encoded) => format!("{}{}", ACE_PREFIX, encoded),
+                None => label.to_string(),
+            }
+        })
+        .collect();
+
+    labels.join(".")
+}
+
+/// The name with every label that holds punycode decoded back to Unicode.
+/// Labels that don't decode are left as they are.
+pub fn to_unicode(name: &str) -> String {
+    let labels: Vec<String> = name
+        .split('.')
+        .map(|label| {
+            let encoded = match label.get(..ACE_PREFIX.len()) {
+                Some(prefix) if prefix.eq_ignore_ascii_case(ACE_PREFIX) => {
+                    &label[ACE_PREFIX.len()..]
+                }
+                _ => return label.to_string(),
+            };
+            decode(encoded).unwrap_or_else(|| label.to_string())
+        })
+        .collect();
+
+    labels.join(".")
+}
+
+/// Whether any label of the name holds punycode
+pub fn is_idn(name: &str) -> bool {
+    name.split('.').any(|label| {
+        label
+            .get(..ACE_PREFIX.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(ACE_PREFIX))
+    })
+}
+
+/// Encode a label as punycode, without the ACE prefix, RFC 3492, section
+/// 6.3. Returns `None` should the label be long enough to overflow.
+pub fn encode(input: &[char]) -> Option<String> {
+    let mut output: String = input.iter().filter(|c| c.is_ascii()).collect();
+    let basic = output.len();
+    let mut handled = basic;
+    if basic > 0 {
+        output.push(DELIMITER);
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    while handled < input.len() {
+        // The smallest code point that's yet to be handled
+        let m = input.iter().map(|&c| c as u32).filter(|&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled as u32 + 1)?)?;
+        n = m;
+
+        for &c in input {
+            let c = c as u32;
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c != n {
+                continue;
+            }
+
+            // Delta as a variable-length integer
+            let mut q = delta;
+            let mut k = BASE;
+            loop {
+                let t = threshold(k, bias);
+                if q < t {
+                    break;
+                }
+                output.push(digit(t + (q - t) % (BASE - t)));
+                q = (q - t) / (BASE - t);
+                k += BASE;
+            }
+            output.push(digit(q));
+
+            bias = adapt(delta, handled as u32 + 1, handled == basic);
+            delta = 0;
+            handled += 1;
+        }
+
+        delta = delta.checked_add(1)?;
+        n = n.checked_add(1)?;
+    }
+
+    Some(output)
+}
+
+/// Decode a label from punycode, without the ACE prefix, RFC 3492, section
+/// 6.2. Returns `None` for anything that isn't valid punycode.
+pub fn decode(input: &str) -> Option<String> {
+    if !input.is_ascii() {
+        return None;
+    }
+    let (basic, extended) = match input.rfind(DELIMITER) {
+        Some(i) => (&input[..i], &input[i + 1..]),
+        None => ("", input),
+    };
+
+    let mut output: Vec<char> = basic.chars().collect();
+    let mut n = INITIAL_N;
+    let mut i: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut digits = extended.bytes().peekable();
+    while digits.peek().is_some() {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        loop {
+            let digit = value(digits.next()?)?;
+            i = i.checked_add(digit.checked_mul(w)?)?;
+            let t = threshold(k, bias);
+            if digit < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+        // Basic code points have to be among the basic ones up front
+        if n < INITIAL_N {
+            return None;
+        }
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+
+    Some(output.into_iter().collect())
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        TMIN
+    } else if k >= bias + TMAX {
+        TMAX
+    } else {
+        k - bias
+    }
+}
+
+/// The bias adaptation function of RFC 3492, section 6.1
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = match first {
+        true => delta / DAMP,
+        false => delta / 2,
+    };
+    delta += delta / points;
+
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+fn digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+fn value(b: u8) -> Option<u32> {
+    match b {
+        b'a'..=b'z' => Some((b - b'a') as u32),
+        b'A'..=b'Z' => Some((b - b'A') as u32),
+        b'0'..=b'9' => Some((b - b'0') as u32 + 26),
+        _ => None,
+    }
+}
@@ -0,0 +1,99 @@
+//! A small DNS resolver along with the building blocks it's made of, which
+//! are usable on their own for anything else that needs to speak DNS.
+//!
+//! - `BytePacketBuffer` holds the bytes of a packet on the wire, and knows how
+//!   to read and write the fields they're made of, such as names.
+//! - `DnsPacket` is a parsed packet, made up of a `DnsHeader`, the questions,
+//!   and the `DnsRecord`s of the answer, authority and additional sections.
+//!   `QueryBuilder` puts together queries to send, and `ResponseBuilder`
+//!   the responses to them.
+//! - `client` sends queries to a name server over UDP, and over TCP when the
+//!   response doesn't fit in a datagram.
+//! - `resolver` resolves names recursively starting at the root servers, or
+//!   through upstream resolvers, and `server` answers the queries of clients
+//!   that way. Every query goes through the handlers of a `pipeline`, which
+//!   custom ones can be added to.
+//! - `display` prints packets the way dig does, and dumps the bytes of a
+//!   packet with what each of its fields holds.
+//!
+//! Looking up the addresses of a name through a public resolver:
+//!
+//! ```no_run
+//! use dns_server::client;
+//!
+//! for addr in client::lookup_a("google.com", "8.8.8.8:53".parse()?)? {
+//!     println!("{}", addr);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+#![allow(clippy::upper_case_acronyms)]
+
+#[cfg(feature = "tokio")]
+pub mod async_client;
+#[cfg(feature = "tokio")]
+pub mod async_server;
+pub mod blocklist;
+pub mod byte_packet_buffer;
+pub mod cache;
+pub mod client;
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod display;
+pub mod dns64;
+pub mod dns_header;
+pub mod dns_json;
+pub mod dns_packet;
+pub mod dns_question;
+pub mod dns_record;
+#[cfg(feature = "dnssec")]
+pub mod dnssec;
+#[cfg(feature = "tls")]
+pub mod doh;
+#[cfg(feature = "doq")]
+pub mod doq;
+pub mod ecs;
+pub mod error;
+pub mod forwarder;
+pub mod health;
+pub mod hosts;
+pub mod idna;
+pub mod mdns;
+pub mod metrics;
+pub mod minimize;
+pub mod name;
+pub mod nxdomain;
+pub mod pipeline;
+pub mod pool;
+pub mod query_log;
+pub mod query_type;
+pub mod rate_limit;
+pub mod redirect;
+pub mod replay;
+pub mod resolver;
+pub mod result_code;
+pub mod rotation;
+pub mod secondary;
+pub mod server;
+pub mod shuffle;
+pub mod shutdown;
+pub mod svcb;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transfer;
+pub mod tsig;
+pub mod ttl;
+pub mod update;
+pub mod view;
+pub mod zone;
+
+pub use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_header::{DnsHeader, Opcode},
+    dns_packet::{DnsPacket, QueryBuilder, ResponseBuilder},
+    dns_question::DnsQuestion,
+    dns_record::DnsRecord,
+    error::DnsError,
+    query_type::QueryType,
+    result_code::ResultCode,
+};
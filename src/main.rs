@@ -1,183 +1,1463 @@
-use byte_packet_buffer::BytePacketBuffer;
-use dns_packet::DnsPacket;
-use std::net::{UdpSocket, Ipv4Addr};
+use clap::{Args, Parser, Subcommand};
+use log::{info, warn};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::{dns_question::DnsQuestion, query_type::QueryType, result_code::ResultCode};
-
-mod byte_packet_buffer;
-mod dns_header;
-mod dns_packet;
-mod dns_question;
-mod dns_record;
-mod query_type;
-mod result_code;
+#[cfg(unix)]
+use dns_server::daemon::{self, Daemon, Listener, LogTarget, Notifier, PidFile, SyslogLogger};
+#[cfg(feature = "dnssec")]
+use dns_server::dnssec::Validator;
+#[cfg(feature = "tls")]
+use dns_server::doh;
+#[cfg(feature = "doq")]
+use dns_server::doq;
+use dns_server::{
+    blocklist::{self, BlockMode, Blocklist},
+    byte_packet_buffer::BytePacketBuffer,
+    cache::{self, Cache},
+    client::{reverse_name, AddressFamily, QueryOptions},
+    config::Config,
+    display,
+    dns64::Dns64,
+    dns_json,
+    dns_question::DnsQuestion,
+    ecs::ClientSubnetPolicy,
+    forwarder::{self, Forwarder, SelectionPolicy, Upstream},
+    health::{self, HealthChecks, Probe},
+    hosts::Hosts,
+    idna,
+    mdns::{self, MdnsResolver, Responder},
+    metrics::{self, Metrics, Stats},
+    nxdomain::NxdomainList,
+    query_log::{QueryLog, QueryLogFormat},
+    query_type::QueryType,
+    rate_limit::RateLimiter,
+    redirect::{self, Redirect},
+    replay,
+    resolver::{resolve, ResolverOptions},
+    result_code::ResultCode,
+    rotation::{AnswerRotation, RotationMode},
+    secondary::Secondary,
+    server::{self, BindOptions},
+    shuffle::AnswerShuffler,
+    shutdown::{self, Shutdown},
+    transfer,
+    tsig::TsigKey,
+    ttl::TtlPolicy,
+    update::DynamicZone,
+    view::Subnet,
+    zone::Zone,
+};
 
 type Error = Box<dyn std::error::Error>;
-pub type Result<T> = std::result::Result<T, Error>;
+type Result<T> = std::result::Result<T, Error>;
 
-fn recursive_loopkup(qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-    // For now we're always starting with *a.root-servers.net*.
-    let mut ns = "198.41.0.4".parse::<Ipv4Addr>().unwrap();
+/// The address the server listens on unless told otherwise
+const DEFAULT_LISTEN: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 2053);
 
-    // Since it might take an arbitrary number of steps, we enter an unbouded loop.
-    loop {
-                println!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+/// A DNS server that resolves queries recursively, or forwards them upstream
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Answer queries over UDP and TCP
+    Serve(Box<ServeArgs>),
+    /// Resolve a single name and print the response, the way dig does
+    Query(Box<QueryArgs>),
+    /// Send queries to a server as fast as it answers them, and report how
+    /// long that took
+    Bench(BenchArgs),
+    /// Pull a zone from a server with a zone transfer, and print it in zone
+    /// file format
+    Transfer(TransferArgs),
+    /// Show what a running server has been up to: the names and clients with
+    /// the most queries, the query types, the cache and the blocklist
+    Stats(StatsArgs),
+    /// Run the messages of pcap captures or raw message dumps through the
+    /// parser, and report those that don't parse
+    Replay(ReplayArgs),
+}
+
+/// How names are resolved, shared by serving and querying
+#[derive(Args)]
+struct ResolverArgs {
+    /// Load the settings from this TOML file. The flags take precedence over
+    /// the file.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Forward queries to this upstream rather than resolving them
+    /// recursively, e.g. `8.8.8.8`, `tls://1.1.1.1#cloudflare-dns.com`,
+    /// `quic://94.140.14.14#dns.adguard-dns.com` or
+    /// `https://dns.google/dns-query`. May be given several times, the
+    /// upstreams are tried in order.
+    #[arg(long, value_parser = Forwarder::parse_upstream)]
+    upstream: Vec<Upstream>,
+    /// Which upstream to try first, either `in-order` (the default),
+    /// `round-robin` or `fastest`
+    #[arg(long)]
+    upstream_policy: Option<SelectionPolicy>,
+    /// Forward the names in a domain to an upstream of its own, e.g.
+    /// `corp.example=10.0.0.53`, and everything else as usual. May be given
+    /// several times, also for the same domain.
+    #[arg(long, value_parser = forwarder::parse_route)]
+    forward_domain: Vec<(String, Upstream)>,
+    /// Synthesize AAAA records from A records with this NAT64 prefix, e.g.
+    /// `64:ff9b::/96`
+    #[arg(long, value_parser = Dns64::parse)]
+    dns64: Option<Dns64>,
+    /// Reach upstreams and name servers over this version of IP first,
+    /// either `ipv4` or `ipv6`
+    #[arg(long)]
+    prefer_family: Option<AddressFamily>,
+    /// Send names in a random mix of upper and lower case, and reject
+    /// responses that don't echo the exact case of the question (0x20
+    /// encoding)
+    #[arg(long)]
+    verify_case: bool,
+    /// Resolving recursively, only tell each name server as much of the
+    /// name as it needs to know to refer us further (RFC 9156)
+    #[arg(long)]
+    qname_minimization: bool,
+    /// The largest UDP response we can take, advertised through EDNS
+    #[arg(long, conflicts_with = "no_edns")]
+    edns_payload_size: Option<u16>,
+    /// Send plain queries, without an OPT record
+    #[arg(long)]
+    no_edns: bool,
+    /// How long to wait for a response from an upstream or name server, in
+    /// milliseconds, before sending the query again
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+    /// How many times a query over UDP is sent again before giving up on the
+    /// server
+    #[arg(long)]
+    retries: Option<u8>,
+    /// Answer repeated queries from earlier responses while they're valid
+    #[arg(long)]
+    cache: bool,
+    /// Never cache records of this type, which turns on the cache for all
+    /// the others. May be given several times.
+    #[arg(long)]
+    no_cache_type: Vec<QueryType>,
+    /// Never cache the names in this domain, which turns on the cache for
+    /// all the others. May be given several times.
+    #[arg(long)]
+    no_cache_domain: Vec<String>,
+    /// Refresh cached answers to the names that are looked up shortly before
+    /// they expire, which turns on the cache
+    #[arg(long)]
+    prefetch: bool,
+    /// Answer from expired cache entries when the upstreams can't be
+    /// reached, which turns on the cache
+    #[arg(long)]
+    serve_stale: bool,
+    /// Raise the TTLs of what the upstreams answer to at least this many
+    /// seconds
+    #[arg(long)]
+    min_ttl: Option<u32>,
+    /// Lower the TTLs of what the upstreams answer to at most this many
+    /// seconds
+    #[arg(long)]
+    max_ttl: Option<u32>,
+    /// Lower the TTLs of the negative answers of the upstreams, and so how
+    /// long they're cached for, to at most this many seconds
+    #[arg(long)]
+    max_negative_ttl: Option<u32>,
+    /// Tell the upstreams which network each client is in, with the EDNS
+    /// Client Subnet option, so that they can answer with what's closest
+    #[arg(long)]
+    client_subnet: bool,
+    /// How many bits of the IPv4 addresses of clients are passed on, which
+    /// turns on `--client-subnet`
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=32))]
+    client_subnet_ipv4_prefix: Option<u8>,
+    /// How many bits of the IPv6 addresses of clients are passed on, which
+    /// turns on `--client-subnet`
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=128))]
+    client_subnet_ipv6_prefix: Option<u8>,
+    /// Serve the zone in this file authoritatively. May be given several
+    /// times.
+    #[arg(long)]
+    zone: Vec<PathBuf>,
+    /// Answer the names in this file, in `/etc/hosts` format, with the
+    /// addresses it gives them. May be given several times.
+    #[arg(long)]
+    hosts: Vec<PathBuf>,
+    /// Resolve `.local` names over multicast DNS on the local link rather
+    /// than sending them upstream
+    #[arg(long)]
+    mdns: bool,
+    /// Always answer this name with NXDOMAIN. May be given several times.
+    #[arg(long)]
+    nxdomain: Vec<String>,
+    /// The TTL of the negative answers for `--nxdomain`
+    #[arg(long)]
+    nxdomain_ttl: Option<u32>,
+    /// Block the names in this file, which is either a hosts file or a list
+    /// of names. May be given several times.
+    #[arg(long)]
+    blocklist: Vec<PathBuf>,
+    /// Block the names in a file as part of a category, e.g.
+    /// `malware=malware.txt`, which `--redirect-blocked` can send elsewhere
+    /// than the rest. May be given several times.
+    #[arg(long, value_parser = blocklist::parse_category_file)]
+    blocklist_category: Vec<(String, PathBuf)>,
+    /// Never block this name, or the names below it with `*.example.com`.
+    /// May be given several times.
+    #[arg(long)]
+    allow: Vec<String>,
+    /// How blocked names are answered, either `nxdomain` (the default) or
+    /// `null` for the unspecified address
+    #[arg(long)]
+    block_mode: Option<BlockMode>,
+    /// Answer with this address instead of NXDOMAIN for names that don't
+    /// exist, e.g. that of a landing page. May be given several times, for an
+    /// address of each family.
+    #[arg(long)]
+    redirect_nxdomain: Vec<IpAddr>,
+    /// Answer with this address for blocked names, or for the names of a
+    /// single category with `CATEGORY=ADDRESS`. May be given several times.
+    #[arg(long, value_parser = redirect::parse_blocked)]
+    redirect_blocked: Vec<(String, IpAddr)>,
+    /// The TTL of the answers of `--redirect-nxdomain` and
+    /// `--redirect-blocked`
+    #[arg(long)]
+    redirect_ttl: Option<u32>,
+    /// Validate DNSSEC signatures from the root zone down. Secure answers get
+    /// the AD bit, and answers that fail validation are SERVFAIL.
+    #[cfg(feature = "dnssec")]
+    #[arg(long, conflicts_with = "no_edns")]
+    dnssec: bool,
+    /// Trust the key with this DS record on top of those of the root zone,
+    /// e.g. `example.com. DS 12345 13 2 <digest>`, which turns on DNSSEC
+    /// validation. May be given several times.
+    #[cfg(feature = "dnssec")]
+    #[arg(long, conflicts_with = "no_edns")]
+    trust_anchor: Vec<String>,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// The address to serve on, both over UDP and TCP. May be given several
+    /// times. It's 0.0.0.0:2053 by default, so that the server can run
+    /// without root privileges. `[::]:2053` serves IPv4 clients as well,
+    /// unless 0.0.0.0:2053 is given too.
+    #[arg(long)]
+    bind: Vec<SocketAddr>,
+    /// The number of threads answering UDP queries on each address, one per
+    /// CPU by default
+    #[arg(long)]
+    workers: Option<usize>,
+    /// Bind with SO_REUSEPORT, so that other processes can serve on the same
+    /// addresses alongside this one. Every UDP worker gets a socket of its
+    /// own then, with the kernel spreading the queries over them.
+    #[arg(long)]
+    reuse_port: bool,
+    /// Serve on the tokio runtime instead of the blocking server loop
+    #[cfg(feature = "tokio")]
+    #[arg(long = "async")]
+    use_async: bool,
+    /// Also serve DNS over HTTPS on this address, e.g. `0.0.0.0:443`
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    doh_listen: Option<SocketAddr>,
+    /// The certificate chain for DNS over HTTPS, in PEM format
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    doh_cert: Option<PathBuf>,
+    /// The private key for DNS over HTTPS, in PEM format
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    doh_key: Option<PathBuf>,
+    /// Also serve DNS over QUIC on this address, e.g. `0.0.0.0:853`
+    #[cfg(feature = "doq")]
+    #[arg(long)]
+    doq_listen: Option<SocketAddr>,
+    /// The certificate chain for DNS over QUIC, in PEM format
+    #[cfg(feature = "doq")]
+    #[arg(long)]
+    doq_cert: Option<PathBuf>,
+    /// The private key for DNS over QUIC, in PEM format
+    #[cfg(feature = "doq")]
+    #[arg(long)]
+    doq_key: Option<PathBuf>,
+    /// Echo the question back exactly as the client sent it
+    #[arg(long)]
+    preserve_question: bool,
+    /// Leave out the additional section of responses
+    #[arg(long)]
+    minimal_responses: bool,
+    /// Shuffle the records of each RRset in the answers
+    #[arg(long)]
+    shuffle_answers: bool,
+    /// Shuffle the answers in an order that's reproducible with this seed
+    #[arg(long)]
+    shuffle_seed: Option<u64>,
+    /// Rotate the addresses answered from local zones, either `round-robin`,
+    /// `shuffle` or `weighted` (with the weights of the config file)
+    #[arg(long)]
+    rotate_answers: Option<RotationMode>,
+    /// Probe the backend at an address, and leave the address out of local
+    /// answers while it's down, e.g. `192.168.1.10=http:8080/healthz` or
+    /// `192.168.1.11=tcp:22`. May be given several times.
+    #[arg(long, value_parser = health::parse_check)]
+    health_check: Vec<(IpAddr, Probe)>,
+    /// Start out with the cache saved to this file, and save it there every
+    /// so often, which turns on the cache
+    #[arg(long)]
+    cache_file: Option<PathBuf>,
+    /// How often the cache is saved to `--cache-file`, in seconds
+    #[arg(long, requires = "cache_file")]
+    cache_save_interval: Option<u64>,
+    /// Log every query to this file, or to standard output with `-`
+    #[arg(long)]
+    query_log: Option<PathBuf>,
+    /// How the query log is written, either `json` (the default) for a JSON
+    /// object per line, or `text`
+    #[arg(long)]
+    query_log_format: Option<QueryLogFormat>,
+    /// Serve Prometheus metrics on `/metrics` at this address, e.g.
+    /// `127.0.0.1:9153`, along with the statistics that `stats` shows
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+    /// Send each client subnet at most this many responses a second over
+    /// UDP
+    #[arg(long)]
+    rate_limit: Option<u32>,
+    /// How many seconds worth of responses a client subnet over the limit
+    /// may fall behind by
+    #[arg(long, requires = "rate_limit")]
+    rate_limit_window: Option<u64>,
+    /// Send every so many responses over the limit truncated instead of
+    /// dropping them, or drop all of them with 0
+    #[arg(long, requires = "rate_limit")]
+    rate_limit_slip: Option<u32>,
+    /// Serve this zone as a secondary, kept in sync with its primary through
+    /// zone transfers, e.g. `example.com@192.0.2.1`, or
+    /// `example.com@192.0.2.1/KEY` to sign the transfers with one of the
+    /// `--tsig-key`s. May be given several times.
+    #[arg(long)]
+    secondary: Vec<String>,
+    /// Serve the zone in this file, letting the clients that sign with an
+    /// `--update-key` make changes to it with UPDATE messages, which are
+    /// written back to the file. May be given several times.
+    #[arg(long)]
+    dynamic_zone: Vec<PathBuf>,
+    /// A client subnet that may make changes to the dynamic zones without
+    /// signing them, e.g. `192.168.1.2` or `10.0.0.0/8`, with
+    /// `--allow-unsigned-updates`. May be given several times.
+    #[arg(long, requires = "dynamic_zone")]
+    allow_update: Vec<Subnet>,
+    /// Take unsigned changes from the clients of `--allow-update`. Anyone
+    /// who can send packets with one of their addresses can make changes
+    /// then, so it's insecure.
+    #[arg(long, requires = "allow_update")]
+    allow_unsigned_updates: bool,
+    /// The name of a `--tsig-key` that may sign changes to the dynamic
+    /// zones. May be given several times.
+    #[arg(long, requires = "dynamic_zone")]
+    update_key: Vec<String>,
+    /// A key to verify signed requests with, and to sign the responses to
+    /// them, as `[ALGORITHM:]NAME:SECRET` with the secret in base64. May be
+    /// given several times.
+    #[arg(long)]
+    tsig_key: Vec<TsigKey>,
+    /// Answer the mDNS queries of the local link for the names in this file,
+    /// in `/etc/hosts` format. May be given several times.
+    #[arg(long)]
+    mdns_hosts: Vec<PathBuf>,
+    /// The address of the interface to answer mDNS queries on, the default
+    /// one otherwise
+    #[arg(long)]
+    mdns_interface: Option<Ipv4Addr>,
+    #[cfg(unix)]
+    #[command(flatten)]
+    service: ServiceArgs,
+    #[command(flatten)]
+    resolver: ResolverArgs,
+}
+
+/// Running as a system service, see `dns_server::daemon`. Sockets bound by
+/// systemd are served on in place of `--bind` when passed on.
+#[cfg(unix)]
+#[derive(Args)]
+struct ServiceArgs {
+    /// Detach from the terminal and carry on in the background, once the
+    /// server is up
+    #[arg(long)]
+    daemon: bool,
+    /// Write the ID of the process to this file
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+    /// Switch to this user once the addresses are bound, so that the server
+    /// can serve on port 53 without staying root. The pid file and the cache
+    /// file are handed over to it, but it needs to be able to write to the
+    /// directories they're in to remove and save them.
+    #[arg(long)]
+    user: Option<String>,
+    /// Switch to this group rather than to the group of `--user`
+    #[arg(long, requires = "user")]
+    group: Option<String>,
+    /// Where the log goes, either `stderr` (the default), `syslog` or
+    /// `journald`
+    #[arg(long)]
+    log_target: Option<LogTarget>,
+}
+
+#[derive(Args)]
+struct QueryArgs {
+    /// The name to look up, which may be an internationalized one such as
+    /// `bücher.example`
+    name: String,
+    /// The type of record to look up
+    #[arg(default_value = "A")]
+    qtype: QueryType,
+    /// Take the name for an address, and look up the host names it belongs
+    /// to through its PTR records, like `dig -x`
+    #[arg(short = 'x', long)]
+    reverse: bool,
+    /// The server to ask, in the same format as `--upstream`. Without one,
+    /// the name is resolved recursively.
+    #[arg(long, value_parser = Forwarder::parse_upstream, conflicts_with = "upstream")]
+    server: Option<Upstream>,
+    /// Also print the response the way it goes out on the wire, as a hex
+    /// dump with every field annotated
+    #[arg(long)]
+    hex: bool,
+    /// Print the response as JSON instead, in the format of the DNS over
+    /// HTTPS JSON APIs of Google and Cloudflare
+    #[arg(long, conflicts_with = "hex")]
+    json: bool,
+    #[command(flatten)]
+    resolver: ResolverArgs,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// The names to look up, taking turns
+    #[arg(default_value = "example.com")]
+    names: Vec<String>,
+    /// The type of record to look up
+    #[arg(long = "type", short = 't', default_value = "A")]
+    qtype: QueryType,
+    /// The server to send the queries to, in the same format as `--upstream`
+    #[arg(long, value_parser = Forwarder::parse_upstream, default_value = "127.0.0.1:2053")]
+    server: Upstream,
+    /// The number of queries to send in total
+    #[arg(long, short = 'n', default_value_t = 1000)]
+    queries: usize,
+    /// The number of queries to keep in flight at once
+    #[arg(long, short = 'c', default_value_t = 10)]
+    concurrency: usize,
+}
+
+#[derive(Args)]
+struct TransferArgs {
+    /// The zone to transfer, e.g. `example.com`
+    zone: String,
+    /// The server to transfer the zone from, usually its primary
+    #[arg(long)]
+    server: SocketAddr,
+    /// Only pull what changed since the version of the zone in this file,
+    /// with IXFR, and print the updated zone
+    #[arg(long)]
+    ixfr: Option<PathBuf>,
+    /// How long to wait for the server, in milliseconds
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+    /// Sign the transfer with this key, as `[ALGORITHM:]NAME:SECRET` with
+    /// the secret in base64, the way dig's `-y` takes it
+    #[arg(long, short = 'y')]
+    key: Option<TsigKey>,
+}
+
+#[derive(Args)]
+struct StatsArgs {
+    /// Where the server serves its metrics, see `--metrics-listen`
+    #[arg(long, default_value = "127.0.0.1:9153")]
+    server: SocketAddr,
+    /// How many of the names and clients with the most queries to show
+    #[arg(long, default_value_t = metrics::DEFAULT_TOP)]
+    top: usize,
+    /// Keep showing the latest statistics, refreshed this often in seconds
+    #[arg(long)]
+    watch: Option<u64>,
+    /// How long to wait for the server, in milliseconds
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// pcap captures, files that each hold a single message, or directories
+    /// of those
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    /// Pick the messages out of captures by this port
+    #[arg(long, default_value_t = replay::DEFAULT_PORT)]
+    port: u16,
+    /// Also write every message out again, and report those that don't come
+    /// out byte for byte the same
+    #[arg(long)]
+    roundtrip: bool,
+}
+
+fn main() {
+    let result = match Cli::parse().command {
+        Command::Serve(args) => serve(*args),
+        Command::Query(args) => query(*args),
+        Command::Bench(args) => bench(args),
+        Command::Transfer(args) => transfer(args),
+        Command::Stats(args) => stats(args),
+        Command::Replay(args) => replay(args),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Logging is configured through `RUST_LOG`, e.g. `RUST_LOG=debug` to follow
+/// every step of the resolution, or else the config file. Only the server
+/// logs every query by default, the other commands would drown their output
+/// in it.
+fn init_logging(config: &Config, default_level: &str) {
+    logger(config, default_level).init();
+}
+
+fn logger(config: &Config, default_level: &str) -> env_logger::Builder {
+    let level = config.log_level.as_deref().unwrap_or(default_level);
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level))
+}
 
-        // The next step is to send the query to the active server.
-        let ns_copy = ns;
+impl ResolverArgs {
+    /// The config file, or the defaults if there isn't one
+    fn config(&self) -> Result<Config> {
+        match &self.config {
+            Some(path) => Ok(Config::load(path)?),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Apply the flags on top of the options from the config file. Upstreams
+    /// given as flags replace the ones in the file, while zones and the like
+    /// are added to them.
+    fn apply(self, options: &mut ResolverOptions) -> Result<()> {
+        if !self.upstream.is_empty() {
+            options.forwarder = Some(Forwarder::new(self.upstream));
+        }
+        if let (Some(forwarder), Some(policy)) = (&mut options.forwarder, self.upstream_policy) {
+            forwarder.policy = policy;
+        }
+
+        let mut routes: BTreeMap<String, Vec<Upstream>> = BTreeMap::new();
+        for (domain, upstream) in self.forward_domain {
+            routes.entry(domain).or_default().push(upstream);
+        }
+        for (domain, upstreams) in routes {
+            let mut forwarder = Forwarder::new(upstreams);
+            forwarder.policy = self.upstream_policy.unwrap_or_default();
+            options.routes.insert(&domain, forwarder);
+        }
 
-        let server = (ns_copy, 53);
-        let response = lookup(qname, qtype, server)?;
+        if self.dns64.is_some() {
+            options.dns64 = self.dns64;
+        }
 
-        // If there are entries in the answer section, and no errors, we're done!
-        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
-            return Ok(response);
+        if self.prefer_family.is_some() {
+            options.query.prefer_family = self.prefer_family;
+        }
+        options.query.verify_case |= self.verify_case;
+        options.qname_minimization |= self.qname_minimization;
+        if self.no_edns {
+            options.query.payload_size = None;
+        } else if let Some(size) = self.edns_payload_size {
+            options.query.payload_size = Some(size);
+        }
+        if let Some(timeout) = self.timeout_ms {
+            options.query.timeout = Duration::from_millis(timeout);
+        }
+        if let Some(retries) = self.retries {
+            options.query.retries = retries;
         }
 
-        // We might also get a `NXDOMAIN` reply, which is the authoriative name servers
-        // way of telling us that the name doesn't exists.
-        if response.header.rescode == ResultCode::NXDOMAIN {
-            return Ok(response);
+        if self.cache
+            || !self.no_cache_type.is_empty()
+            || !self.no_cache_domain.is_empty()
+            || self.prefetch
+            || self.serve_stale
+        {
+            let cache = options.cache.get_or_insert_with(Cache::new);
+            cache.bypass.extend(self.no_cache_type);
+            cache.no_cache.extend(self.no_cache_domain);
+            cache.prefetch |= self.prefetch;
+            if self.serve_stale && cache.serve_stale.is_none() {
+                cache.serve_stale = Some(cache::DEFAULT_MAX_STALE);
+            }
         }
 
-        // Otherwise, we'll try to find a new nameserver based on NS and a corresponding A
-        // record in the additional section. If this succeeds, we can switch name server
-        // and retry the loop.
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns;
+        if self.min_ttl.is_some() || self.max_ttl.is_some() || self.max_negative_ttl.is_some() {
+            let policy = options.ttl_policy.get_or_insert_with(TtlPolicy::new);
+            if let Some(min_ttl) = self.min_ttl {
+                policy.min_ttl = min_ttl;
+            }
+            if self.max_ttl.is_some() {
+                policy.max_ttl = self.max_ttl;
+            }
+            if self.max_negative_ttl.is_some() {
+                policy.max_negative_ttl = self.max_negative_ttl;
+            }
+        }
 
-            continue;
+        if self.client_subnet
+            || self.client_subnet_ipv4_prefix.is_some()
+            || self.client_subnet_ipv6_prefix.is_some()
+        {
+            let policy = options
+                .client_subnet
+                .get_or_insert_with(ClientSubnetPolicy::new);
+            if let Some(prefix) = self.client_subnet_ipv4_prefix {
+                policy.ipv4_prefix = prefix;
+            }
+            if let Some(prefix) = self.client_subnet_ipv6_prefix {
+                policy.ipv6_prefix = prefix;
+            }
         }
 
+        if !self.hosts.is_empty() {
+            let hosts = options.hosts.get_or_insert_with(Hosts::new);
+            for path in &self.hosts {
+                let count = hosts.load(path)?;
+                info!("Loaded {} host names from {}", count, path.display());
+            }
+        }
 
-        // If not, we'll have to resolve the ip of NS record. If no NS record exist,
-        // we'll go with what the last server told us.
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(x) => x,
-            None => return Ok(response),
-        };
+        if self.mdns {
+            options.mdns.get_or_insert_with(MdnsResolver::new);
+        }
 
-        // Here we go down the rabbit hole by starting _another_ lookup sequence in the
-        // midst of our current one. Hopefully, this will give us the IP of an approprate
-        // name server.
-        let recursive_response = recursive_loopkup(&new_ns_name, QueryType::A)?;
+        for path in &self.zone {
+            options.zones.push(Zone::load(path)?);
+        }
 
-        // Finally, we pick a random ip from the result, and restart the loop. If no such
-        // record is available, we again return the last result we got.
-        if let Some(new_ns) = recursive_response.get_random_a() {
-            ns = new_ns;
-        } else {
-            return Ok(response);
+        if !self.nxdomain.is_empty() {
+            let nxdomain = options.nxdomain.get_or_insert_with(NxdomainList::new);
+            for name in &self.nxdomain {
+                nxdomain.insert(name);
+            }
         }
+        if let (Some(nxdomain), Some(ttl)) = (&mut options.nxdomain, self.nxdomain_ttl) {
+            nxdomain.ttl = ttl;
+        }
+
+        if !self.blocklist.is_empty() {
+            let blocklist = options.blocklist.get_or_insert_with(Blocklist::new);
+            for path in &self.blocklist {
+                let count = blocklist.load(path)?;
+                info!("Loaded {} names to block from {}", count, path.display());
+            }
+        }
+        if !self.blocklist_category.is_empty() {
+            let blocklist = options.blocklist.get_or_insert_with(Blocklist::new);
+            for (category, path) in &self.blocklist_category {
+                let count = blocklist.load_category(category, path)?;
+                info!(
+                    "Loaded {} names to block as {} from {}",
+                    count,
+                    category,
+                    path.display()
+                );
+            }
+        }
+        if let Some(blocklist) = &mut options.blocklist {
+            for name in &self.allow {
+                blocklist.allow(name);
+            }
+            if let Some(mode) = self.block_mode {
+                blocklist.mode = mode;
+            }
+        }
+
+        if !self.redirect_nxdomain.is_empty() || !self.redirect_blocked.is_empty() {
+            let redirect = options.redirect.get_or_insert_with(Redirect::new);
+            redirect.nxdomain.extend(&self.redirect_nxdomain);
+            for (category, addr) in &self.redirect_blocked {
+                redirect.block_to(category, *addr);
+            }
+        }
+        if let (Some(redirect), Some(ttl)) = (&mut options.redirect, self.redirect_ttl) {
+            redirect.ttl = ttl;
+        }
+
+        #[cfg(feature = "dnssec")]
+        if self.dnssec || !self.trust_anchor.is_empty() {
+            let validator = options.validator.get_or_insert_with(Validator::new);
+            for anchor in &self.trust_anchor {
+                validator.add_anchor(anchor)?;
+            }
+            options.query.dnssec_ok = true;
+        }
+        // The DO bit asking for signatures is part of EDNS
+        if options.query.dnssec_ok && options.query.payload_size.is_none() {
+            return Err("DNSSEC validation requires EDNS".into());
+        }
+
+        Ok(())
     }
 }
 
-fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> Result<DnsPacket> {
-    // Forward queries to Google's public DNS
-    // let server = ("8.8.8.8", 53);
+fn serve(args: ServeArgs) -> Result<()> {
+    let config = args.resolver.config()?;
+    // Before anything starts threads, which wouldn't survive detaching
+    #[cfg(unix)]
+    let mut service = Service::start(args.service, &config)?;
+    #[cfg(not(unix))]
+    init_logging(&config, "info");
+
+    let mut options = config.resolver_options()?;
+    args.resolver.apply(&mut options)?;
+    options.preserve_question |= args.preserve_question;
+    options.minimal_responses |= args.minimal_responses;
+    if args.shuffle_answers || args.shuffle_seed.is_some() {
+        options.shuffler = Some(AnswerShuffler::new(args.shuffle_seed));
+    }
+    if let Some(mode) = args.rotate_answers {
+        match &mut options.rotation {
+            Some(rotation) => rotation.mode = mode,
+            None => options.rotation = Some(AnswerRotation::new(mode, None)),
+        }
+    }
+    if let Some(responses_per_second) = args.rate_limit {
+        let rate_limit = options.rate_limit.get_or_insert_with(RateLimiter::default);
+        rate_limit.responses_per_second = responses_per_second;
+        if let Some(window) = args.rate_limit_window {
+            rate_limit.window = Duration::from_secs(window);
+        }
+        if let Some(slip) = args.rate_limit_slip {
+            rate_limit.slip = slip;
+        }
+    }
+    if let Some(path) = args.query_log.or(config.server.query_log.clone()) {
+        let format = match (args.query_log_format, &config.server.query_log_format) {
+            (Some(format), _) => format,
+            (None, Some(format)) => format.parse()?,
+            (None, None) => QueryLogFormat::default(),
+        };
+        options.query_log = Some(QueryLog::open(path, format)?);
+    }
 
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+    // A cache with a snapshot file starts out warm rather than empty after a
+    // restart, and is saved to it every so often, as well as on the way out
+    let cache_config = config.cache.as_ref();
+    let mut snapshot = None;
+    if let Some(path) = args
+        .cache_file
+        .or(cache_config.and_then(|config| config.file.clone()))
+    {
+        let cache = options.cache.get_or_insert_with(Cache::new).clone();
+        match cache.load(&path) {
+            Ok(count) => info!("Loaded {} cache entries from {}", count, path.display()),
+            Err(e) => warn!("Starting with an empty cache, {}: {}", path.display(), e),
+        }
 
-    let mut packet = DnsPacket::new();
+        let interval = args
+            .cache_save_interval
+            .or(cache_config.and_then(|config| config.save_interval))
+            .unwrap_or(cache::DEFAULT_SNAPSHOT_INTERVAL);
+        snapshot = Some((cache.clone(), path.clone()));
+        thread::spawn(move || cache.save_every(path, Duration::from_secs(interval)));
+    }
 
-    packet.header.id = 6666;
-    packet.header.questions = 1;
-    packet.header.recursion_desired = true;
-    packet
-        .questions
-        .push(DnsQuestion::new(qname.to_string(), qtype));
+    // The metrics are only counted when there's somewhere to serve them
+    if let Some(addr) = args.metrics_listen.or(config.server.metrics_listen) {
+        let listener = server::bind_tcp(addr, BindOptions::default())?;
+        info!("Serving metrics on {}", listener.local_addr()?);
 
-    let mut req_buffer = BytePacketBuffer::new();
-    packet.write(&mut req_buffer)?;
-    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
+        let metrics = Metrics::new();
+        if let Some(cache) = &options.cache {
+            metrics.watch_cache(cache.clone());
+        }
+        options.metrics = Some(metrics.clone());
+        thread::spawn(move || metrics::serve(listener, metrics));
+    }
 
-    let mut res_buffer = BytePacketBuffer::new();
-    socket.recv_from(&mut res_buffer.buf)?;
+    // Secondary zones are answered from once they've been transferred, and
+    // kept up to date from then on
+    options.tsig_keys.extend(args.tsig_key);
+    for secondary in &args.secondary {
+        let secondary = Secondary::parse(secondary, &options.tsig_keys)?;
+        options.secondaries.push(secondary);
+    }
+    for secondary in &options.secondaries {
+        let secondary = secondary.clone();
+        let query = options.query;
+        thread::spawn(move || secondary.run(query));
+    }
+
+    // Local answers only leave out the backends that are down once they've
+    // been probed
+    if !args.health_check.is_empty() {
+        let health = options.health.get_or_insert_with(HealthChecks::new);
+        for (addr, probe) in args.health_check {
+            health.add(addr, probe);
+        }
+    }
+    if let Some(health) = options.health.clone().filter(|health| !health.is_empty()) {
+        thread::spawn(move || health.run());
+    }
+
+    for name in &args.update_key {
+        TsigKey::find(&options.tsig_keys, name)?;
+    }
+    for path in &args.dynamic_zone {
+        let mut zone = DynamicZone::load(path, args.allow_update.clone())?;
+        zone.update_keys = args.update_key.clone();
+        zone.allow_unsigned = args.allow_unsigned_updates;
+        options.dynamic_zones.push(zone);
+    }
+
+    let mut responder = config.mdns_responder()?;
+    if !args.mdns_hosts.is_empty() {
+        let responder = responder.get_or_insert_with(|| {
+            let mut hosts = Hosts::new();
+            hosts.ttl = mdns::DEFAULT_MDNS_TTL;
+            Responder::new(hosts)
+        });
+        for path in &args.mdns_hosts {
+            let count = responder.hosts.load(path)?;
+            info!(
+                "Answering mDNS queries for {} names from {}",
+                count,
+                path.display()
+            );
+        }
+    }
+    if let Some(responder) = responder {
+        let interface = args
+            .mdns_interface
+            .or(config.mdns.as_ref().and_then(|config| config.interface))
+            .unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let socket = mdns::bind(interface)?;
+        info!("Answering mDNS queries on {}", interface);
+        thread::spawn(move || mdns::serve(socket, responder));
+    }
+
+    // Views resolve the way everyone else is resolved other than for what
+    // they change, so they're put together once all the rest is
+    options.views = config.views(&options)?;
+    for view in &options.views {
+        info!(
+            "Serving view {} to {} client subnets",
+            view.name,
+            view.clients.len()
+        );
+    }
+
+    // Stopping lets the queries in flight be answered, and the cache be
+    // saved, before the process exits
+    let shutdown = options.shutdown.clone();
+    #[cfg(unix)]
+    shutdown.stop_on_signals()?;
+
+    let options = Arc::new(options);
+
+    let listen = match (args.bind, &config.server.listen) {
+        (bind, _) if !bind.is_empty() => bind,
+        (_, listen) if !listen.is_empty() => listen.clone(),
+        _ => vec![DEFAULT_LISTEN],
+    };
+
+    // Serving DNS over HTTPS is off by default, and enabled by passing the
+    // address to listen on along with a certificate and its key
+    #[cfg(feature = "tls")]
+    if let Some(addr) = args.doh_listen.or(config.server.doh_listen) {
+        let cert = args.doh_cert.or(config.server.doh_cert);
+        let key = args.doh_key.or(config.server.doh_key);
+        let (cert, key) = cert
+            .zip(key)
+            .ok_or("DNS over HTTPS requires a certificate and its key")?;
+        let config = Arc::new(doh::load_server_config(cert, key)?);
+
+        let listener = server::bind_tcp(addr, BindOptions::default())?;
+        info!("Serving DNS over HTTPS on {}", listener.local_addr()?);
+
+        let options = options.clone();
+        thread::spawn(move || doh::serve(listener, config, options));
+    }
+
+    // And the same goes for DNS over QUIC
+    #[cfg(feature = "doq")]
+    if let Some(addr) = args.doq_listen.or(config.server.doq_listen) {
+        let cert = args.doq_cert.or(config.server.doq_cert);
+        let key = args.doq_key.or(config.server.doq_key);
+        let (cert, key) = cert
+            .zip(key)
+            .ok_or("DNS over QUIC requires a certificate and its key")?;
+        let config = Arc::new(doq::load_server_config(cert, key)?);
+
+        let socket = server::bind_udp(addr, BindOptions::default())?;
+        info!("Serving DNS over QUIC on {}", socket.local_addr()?);
+
+        let options = options.clone();
+        thread::spawn(move || {
+            if let Err(e) = doq::serve(socket, config, options) {
+                log::error!("Failed to serve DNS over QUIC: {}", e);
+            }
+        });
+    }
 
-    DnsPacket::from_buffer(&mut res_buffer)
+    let reuse_port = args.reuse_port || config.server.reuse_port;
+
+    #[cfg(feature = "tokio")]
+    if args.use_async {
+        #[cfg(unix)]
+        let sockets = service.sockets(&listen, reuse_port, 1)?;
+        #[cfg(not(unix))]
+        let sockets = bind_sockets(&listen, reuse_port, 1)?;
+        #[cfg(unix)]
+        service.ready(snapshot.as_ref().map(|(_, path)| path.as_path()))?;
+        return serve_async(sockets, options, snapshot);
+    }
+
+    // The UDP queries are spread out over a pool of worker threads, one per
+    // CPU unless configured otherwise
+    let workers = match args.workers.or(config.server.workers) {
+        Some(workers) => workers,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+
+    #[cfg(unix)]
+    let sockets = service.sockets(&listen, reuse_port, workers)?;
+    #[cfg(not(unix))]
+    let sockets = bind_sockets(&listen, reuse_port, workers)?;
+    #[cfg(unix)]
+    service.ready(snapshot.as_ref().map(|(_, path)| path.as_path()))?;
+
+    for listener in sockets.tcp {
+        let options = options.clone();
+        thread::spawn(move || server::serve_tcp(listener, options));
+    }
+    let mut servers = Vec::new();
+    for (socket, workers) in sockets.udp {
+        let options = options.clone();
+        let shutdown = shutdown.clone();
+        servers.push(thread::spawn(move || {
+            let result = server::serve_udp(socket, options, workers);
+            // A server whose socket fails takes the others down with it
+            shutdown.stop();
+            result
+        }));
+    }
+
+    // The UDP servers return once they're stopped, after answering what they
+    // received before. The TCP connections accepted since are closed right
+    // away, so the listeners are left to go down with the process.
+    let mut result = Ok(());
+    for server in servers {
+        match server.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => result = result.and(Err(e.into())),
+            Err(_) => result = result.and(Err("Server panicked".into())),
+        }
+    }
+    finish(&shutdown, snapshot);
+
+    result
 }
 
-/// Handle a single incoming packet
-fn handle_query(socket: &UdpSocket) -> Result<()> {
-    // With a socket ready, we can go ahead and read a packet. This will
-    // block until one is received.
-    let mut req_buffer = BytePacketBuffer::new();
+/// The service side of the server: where it logs to, whether it detached,
+/// the sockets and notifications systemd passed on, and who it runs as once
+/// it's up
+#[cfg(unix)]
+struct Service {
+    daemon: Option<Daemon>,
+    pid_file: Option<PidFile>,
+    listeners: Vec<Listener>,
+    notifier: Option<Notifier>,
+    user: Option<String>,
+    group: Option<String>,
+}
 
-    // The `recv_from` function will write the data into the provided buffer,
-    // and return the length of the data read as well as the source address.
-    // We're not interested in the length, but we need to keep track of the
-    // source in order to send our reply later on.
-    let (_, src) = socket.recv_from(&mut req_buffer.buf)?;
+#[cfg(unix)]
+impl Service {
+    /// Set up logging, pick up what systemd passed on through the
+    /// environment, detach if asked to and write the pid file. This runs
+    /// before any other thread is started. The flags take precedence over the
+    /// config file.
+    fn start(args: ServiceArgs, config: &Config) -> Result<Service> {
+        let target = match (args.log_target, &config.server.log_target) {
+            (Some(target), _) => target,
+            (None, Some(target)) => target.parse()?,
+            (None, None) => LogTarget::default(),
+        };
+        match target {
+            LogTarget::Stderr => init_logging(config, "info"),
+            LogTarget::Journald => logger(config, "info")
+                .format(daemon::format_journald)
+                .init(),
+            LogTarget::Syslog => SyslogLogger::init(logger(config, "info").build())?,
+        }
 
-    // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into a
-    // `DnsPacket`.
-    let mut request = DnsPacket::from_buffer(&mut req_buffer)?;
+        // The sockets are meant for the process systemd started, not for the
+        // one the daemon runs in, which inherits them
+        let listeners = daemon::listen_fds()?;
+        let notifier = Notifier::from_env();
 
-    // Create and initialize the response object
-    let mut packet = DnsPacket::new();
-    packet.header.id = request.header.id;
-    packet.header.recursion_desired = true;
-    packet.header.recursion_available = true;
-    packet.header.response = true;
+        let daemon = match args.daemon || config.server.daemon {
+            true => Some(daemon::daemonize()?),
+            false => None,
+        };
+        // Written by the daemon, so that it holds its ID
+        let pid_file = match args.pid_file.or(config.server.pid_file.clone()) {
+            Some(path) => Some(PidFile::create(path)?),
+            None => None,
+        };
 
-    // In the normal case, exactly one question is present
-    if let Some(question) = request.questions.pop() {
-        println!("Received query: {:?}", question);
+        Ok(Service {
+            daemon,
+            pid_file,
+            listeners,
+            notifier,
+            user: args.user.or(config.server.user.clone()),
+            group: args.group.or(config.server.group.clone()),
+        })
+    }
 
-        // Since all is set up and as expected, the query can be forwarded to the
-        // target server. There's always the possibility that the query will
-        // fail, in which case `SERVFAIL` response code is set to indicate
-        // as much to the client. If rather everything goes as planned, the
-        // question and response records as copied into our response object.
-        if let Ok(result) = recursive_loopkup(&question.name, question.qtype) {
-            packet.questions.push(question);
-            packet.header.rescode = result.header.rescode;
+    /// The sockets systemd bound for us, or else the ones bound on `listen`,
+    /// see `bind_sockets`
+    fn sockets(
+        &mut self,
+        listen: &[SocketAddr],
+        reuse_port: bool,
+        workers: usize,
+    ) -> Result<Sockets> {
+        if self.listeners.is_empty() {
+            return bind_sockets(listen, reuse_port, workers);
+        }
 
-            for rec in result.answers {
-                println!("Answer: {:?}", rec);
-                packet.answers.push(rec);
+        let mut sockets = Sockets {
+            udp: Vec::new(),
+            tcp: Vec::new(),
+        };
+        for listener in std::mem::take(&mut self.listeners) {
+            match listener {
+                Listener::Udp(socket) => {
+                    info!("Listening on {} (UDP, from systemd)", socket.local_addr()?);
+                    sockets.udp.push((socket, workers));
+                }
+                Listener::Tcp(listener) => {
+                    info!(
+                        "Listening on {} (TCP, from systemd)",
+                        listener.local_addr()?
+                    );
+                    sockets.tcp.push(listener);
+                }
             }
-            for rec in result.authorities {
-                println!("Authority: {:?}", rec);
-                packet.authorities.push(rec);
+        }
+
+        Ok(sockets)
+    }
+
+    /// Once the addresses are bound: give up root, tell systemd the server
+    /// is up, and let the process that was started exit. The pid file and
+    /// the cache `snapshot` are handed over to the user first, though
+    /// removing the one and replacing the other is up to their directories
+    /// being writable by the user.
+    fn ready(&mut self, snapshot: Option<&std::path::Path>) -> Result<()> {
+        if let Some(user) = &self.user {
+            let group = self.group.as_deref();
+            if let Some(pid_file) = &self.pid_file {
+                daemon::chown(pid_file.path(), user, group)?;
             }
-            for rec in result.resources {
-                println!("Resource: {:?}", rec);
-                packet.resources.push(rec);
+            // Until the first one is saved there's nothing to hand over
+            if let Some(path) = snapshot.filter(|path| path.exists()) {
+                daemon::chown(path, user, group)?;
             }
-        } else {
-            packet.header.rescode = ResultCode::SERVFAIL;
+            daemon::drop_privileges(user, group)?;
+            info!("Running as {}", user);
+        }
+        if let Some(notifier) = self.notifier.take() {
+            notifier.ready()?;
+        }
+        if let Some(daemon) = self.daemon.take() {
+            daemon.ready()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The sockets to serve on, with the number of workers for each UDP one
+struct Sockets {
+    udp: Vec<(UdpSocket, usize)>,
+    tcp: Vec<TcpListener>,
+}
+
+/// Bind an UDP socket on every address, along with a TCP listener for the
+/// clients whose responses don't fit in a datagram. With SO_REUSEPORT,
+/// every worker gets a UDP socket of its own instead of sharing one.
+fn bind_sockets(listen: &[SocketAddr], reuse_port: bool, workers: usize) -> Result<Sockets> {
+    let mut sockets = Sockets {
+        udp: Vec::new(),
+        tcp: Vec::new(),
+    };
+
+    for &addr in listen {
+        let bind = BindOptions {
+            only_v6: server::only_v6(addr, listen),
+            reuse_port,
+        };
+        let socket = server::bind_udp(addr, bind)?;
+        let listener = server::bind_tcp(addr, bind)?;
+        let addr = socket.local_addr()?;
+        info!("Listening on {}", addr);
+
+        match reuse_port {
+            true => {
+                sockets.udp.push((socket, 1));
+                for _ in 1..workers {
+                    sockets.udp.push((server::bind_udp(addr, bind)?, 1));
+                }
+            }
+            false => sockets.udp.push((socket, workers)),
+        }
+        sockets.tcp.push(listener);
+    }
+
+    Ok(sockets)
+}
+
+/// Serve on the tokio runtime, with a task per query
+#[cfg(feature = "tokio")]
+fn serve_async(
+    sockets: Sockets,
+    options: Arc<ResolverOptions>,
+    snapshot: Option<(Cache, PathBuf)>,
+) -> Result<()> {
+    use dns_server::async_server;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let shutdown = options.shutdown.clone();
+    let result = runtime.block_on(async move {
+        // The sockets are set up the same way as for the blocking server, and
+        // only handed to tokio after
+        for listener in sockets.tcp {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            tokio::spawn(async_server::serve_tcp(listener, options.clone()));
+        }
+
+        let mut servers = Vec::new();
+        for (socket, _) in sockets.udp {
+            socket.set_nonblocking(true)?;
+            let socket = tokio::net::UdpSocket::from_std(socket)?;
+            let options = options.clone();
+            servers.push(tokio::spawn(async move {
+                let result = async_server::serve_udp(socket, options.clone()).await;
+                options.shutdown.stop();
+                result
+            }));
+        }
+
+        for server in servers {
+            server.await??;
+        }
+
+        Ok(())
+    });
+    // The queries still being answered need the runtime to be around
+    finish(&shutdown, snapshot);
+
+    result
+}
+
+/// Give the queries in flight a chance to be answered once the servers have
+/// stopped, and save the cache for the next start
+fn finish(shutdown: &Shutdown, snapshot: Option<(Cache, PathBuf)>) {
+    let left = shutdown.drain(shutdown::DRAIN_TIMEOUT);
+    if left > 0 {
+        warn!("Gave up on {} queries in flight", left);
+    }
+
+    if let Some((cache, path)) = snapshot {
+        match cache.save(&path) {
+            Ok(count) => info!("Saved {} cache entries to {}", count, path.display()),
+            Err(e) => warn!("Failed to save the cache to {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn query(args: QueryArgs) -> Result<()> {
+    let config = args.resolver.config()?;
+    init_logging(&config, "warn");
+
+    let mut options = config.resolver_options()?;
+    args.resolver.apply(&mut options)?;
+    if let Some(server) = &args.server {
+        options.forwarder = Some(Forwarder::new(vec![server.clone()]));
+    }
+    let options = Arc::new(options);
+
+    let (name, qtype) = match args.reverse {
+        true => (reverse_name(args.name.parse()?), QueryType::PTR),
+        false => (idna::to_ascii(&args.name), args.qtype),
+    };
+
+    let start = Instant::now();
+    let mut response = resolve(&name, qtype, &options)?;
+    let elapsed = start.elapsed();
+
+    // Local answers don't carry the question along
+    if response.questions.is_empty() {
+        response.questions.push(DnsQuestion::new(name, qtype));
+    }
+    if args.json {
+        println!("{}", dns_json::to_dns_json(&response));
+        return Ok(());
+    }
+
+    println!("{}", response);
+    if args.hex {
+        let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+        response.write(&mut buffer)?;
+        println!(";; WIRE FORMAT, {} bytes:", buffer.pos());
+        print!("{}", display::hex_dump(&buffer.buf[..buffer.pos()]));
+        println!();
+    }
+
+    println!(";; Query time: {} msec", elapsed.as_millis());
+    match &options.forwarder {
+        Some(forwarder) => {
+            let servers: Vec<String> = forwarder.upstreams.iter().map(|u| u.to_string()).collect();
+            println!(";; SERVER: {}", servers.join(", "));
         }
+        None => println!(";; SERVER: resolved recursively"),
     }
-    // Being mindful of how unreliable input data from arbitrary senders can be, we
-    // need make sure that a question is actually present. If not, we return `FORMER`
-    // to indicate that the sender made soemthing wrong.
-    else {
-        packet.header.rescode = ResultCode::FORMERR;
+    // Answers that fail validation don't make it this far
+    #[cfg(feature = "dnssec")]
+    if options.validator.is_some() {
+        match response.header.authed_data {
+            true => println!(";; DNSSEC: secure"),
+            false => println!(";; DNSSEC: insecure"),
+        }
     }
 
-    // The only thing remaining is to encode our response and send it off!
-    let mut res_buffer = BytePacketBuffer::new();
-    packet.write(&mut res_buffer)?;
+    Ok(())
+}
+
+fn transfer(args: TransferArgs) -> Result<()> {
+    init_logging(&Config::default(), "warn");
 
-    let len = res_buffer.pos();
-    let data = res_buffer.get_range(0, len)?;
+    let mut options = QueryOptions::default();
+    if let Some(timeout) = args.timeout_ms {
+        options.timeout = Duration::from_millis(timeout);
+    }
 
-    socket.send_to(data, src)?;
+    let zone = match &args.ixfr {
+        Some(path) => {
+            let mut zone = Zone::load(path)?;
+            zone.origin = args.zone.trim_end_matches('.').to_ascii_lowercase();
+            if !transfer::ixfr(&mut zone, args.server, &options, args.key.as_ref())? {
+                eprintln!(";; {} is up to date", zone.origin);
+            }
+            zone
+        }
+        None => transfer::axfr(&args.zone, args.server, &options, args.key.as_ref())?,
+    };
+
+    for record in &zone.records {
+        println!("{}", record);
+    }
 
     Ok(())
 }
 
-fn main() -> Result<()> {
-    // Bind an UDP socket on port 2053
-    let socket = UdpSocket::bind(("0.0.0.0", 2053))?;
+fn stats(args: StatsArgs) -> Result<()> {
+    init_logging(&Config::default(), "warn");
+    let timeout = Duration::from_millis(args.timeout_ms.unwrap_or(2000));
 
-    // For now, queries are handled sequentially, so an infinite loop for servicing
-    // requests is initiated.
     loop {
-        match handle_query(&socket) {
-            Ok(_) => {}
-            Err(e) => eprintln!("An error occurred: {}", e),
+        let stats = metrics::fetch_stats(args.server, args.top, timeout)?;
+        if args.watch.is_some() {
+            // Clear the terminal, so that every refresh takes its place
+            print!("\x1b[2J\x1b[H");
+        }
+        print_stats(&stats);
+
+        match args.watch {
+            Some(interval) => thread::sleep(Duration::from_secs(interval.max(1))),
+            None => return Ok(()),
         }
     }
 }
+
+fn print_stats(stats: &Stats) {
+    let percent = |part: u64, total: u64| match total {
+        0 => 0.0,
+        total => part as f64 * 100.0 / total as f64,
+    };
+
+    println!("Queries:  {}", stats.queries);
+    println!(
+        "Blocked:  {} ({:.1}%)",
+        stats.blocked,
+        percent(stats.blocked, stats.queries)
+    );
+    let lookups = stats.cache_hits + stats.cache_misses;
+    match stats.cache_entries {
+        Some(entries) => println!(
+            "Cache:    {} entries, {:.1}% of {} lookups answered from it",
+            entries,
+            percent(stats.cache_hits, lookups),
+            lookups
+        ),
+        None => println!("Cache:    off"),
+    }
+
+    let clients: Vec<(String, u64)> = stats
+        .clients
+        .iter()
+        .map(|(client, count)| (client.to_string(), *count))
+        .collect();
+    let lists = [
+        ("Query types", &stats.qtypes),
+        ("Top names", &stats.names),
+        ("Top clients", &clients),
+    ];
+    for (title, list) in lists {
+        println!("\n{}", title);
+        for (label, count) in list {
+            println!(
+                "  {:<40} {:>10} {:>6.1}%",
+                label,
+                count,
+                percent(*count, stats.queries)
+            );
+        }
+    }
+}
+
+fn replay(args: ReplayArgs) -> Result<()> {
+    init_logging(&Config::default(), "warn");
+
+    let mut messages = Vec::new();
+    for path in &args.paths {
+        messages.extend(replay::load(path, args.port)?);
+    }
+
+    let report = replay::replay(&messages, args.roundtrip);
+    for (origin, failure) in &report.failures {
+        println!("{}: {}", origin, failure);
+    }
+    println!(
+        "{} of {} messages {}",
+        report.passed(),
+        report.messages,
+        match args.roundtrip {
+            true => "made it through the round trip",
+            false => "parsed",
+        }
+    );
+
+    match report.failures.len() {
+        0 => Ok(()),
+        failed => Err(format!("{} messages failed", failed).into()),
+    }
+}
+
+fn bench(args: BenchArgs) -> Result<()> {
+    init_logging(&Config::default(), "warn");
+
+    let forwarder = Forwarder::new(vec![args.server.clone()]);
+    let options = ResolverOptions::default();
+    let next = AtomicUsize::new(0);
+
+    // Every thread keeps a single query in flight, picking the next one off
+    // the shared counter until they're all sent
+    let start = Instant::now();
+    let results: Vec<(Duration, Option<ResultCode>)> = thread::scope(|scope| {
+        let threads: Vec<_> = (0..args.concurrency.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= args.queries {
+                            return results;
+                        }
+
+                        let name = &args.names[i % args.names.len()];
+                        let start = Instant::now();
+                        let result = forwarder.forward(name, args.qtype, &options.query);
+                        results.push((start.elapsed(), result.ok().map(|r| r.header.rescode)));
+                    }
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect()
+    });
+    let elapsed = start.elapsed();
+
+    println!(
+        "Sent {} queries to {} in {:.2}s, {:.0} queries per second",
+        results.len(),
+        args.server,
+        elapsed.as_secs_f64(),
+        results.len() as f64 / elapsed.as_secs_f64()
+    );
+
+    let mut rescodes: Vec<(ResultCode, usize)> = Vec::new();
+    let mut failed = 0;
+    for (_, rescode) in &results {
+        match rescode {
+            Some(rescode) => match rescodes.iter_mut().find(|(code, _)| code == rescode) {
+                Some((_, count)) => *count += 1,
+                None => rescodes.push((*rescode, 1)),
+            },
+            None => failed += 1,
+        }
+    }
+    rescodes.sort_by_key(|(code, _)| *code as u8);
+    for (rescode, count) in rescodes {
+        println!("  {:?}: {}", rescode, count);
+    }
+    if failed > 0 {
+        println!("  Failed: {}", failed);
+    }
+
+    let mut latencies: Vec<Duration> = results.iter().map(|(latency, _)| *latency).collect();
+    latencies.sort();
+    if let (Some(min), Some(max)) = (latencies.first(), latencies.last()) {
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        let avg = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+        println!(
+            "Latency: min {:.2?}, avg {:.2?}, p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+            min,
+            avg,
+            percentile(50),
+            percentile(99),
+            max
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,265 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_header::Opcode,
+    dns_packet::{DnsPacket, QueryBuilder, ResponseBuilder},
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+    hosts::Hosts,
+    nxdomain::negative_response,
+    query_type::QueryType,
+    result_code::ResultCode,
+};
+
+/// The group mDNS queries and responses are multicast to, on every link
+pub const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+
+/// How long the records of hosts are cached for, as recommended by RFC 6762
+pub const DEFAULT_MDNS_TTL: u32 = 120;
+
+/// How long names are waited on to be answered unless configured otherwise.
+/// Hosts answer right away, so with nothing back by then the name is taken
+/// not to exist.
+pub const DEFAULT_MDNS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The most a response to a querier that doesn't speak mDNS may be cached
+/// for, since it won't see the announcements when the records change
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+/// The largest datagram that's read, which is the most mDNS allows for
+const MAX_PACKET_SIZE: usize = 9000;
+
+/// Whether `name` is one for mDNS to resolve, i.e. below `local.`
+pub fn is_local(name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    name == "local" || name.ends_with(".local")
+}
+
+/// Resolves `.local` names through multicast DNS (RFC 6762) instead of
+/// sending them upstream, where they'd go nowhere. The queries are one-shot
+/// ones, sent from an ephemeral port, which responders answer by unicast.
+#[derive(Clone, Debug)]
+pub struct MdnsResolver {
+    pub timeout: Duration,
+}
+
+impl Default for MdnsResolver {
+    fn default() -> MdnsResolver {
+        MdnsResolver::new()
+    }
+}
+
+impl MdnsResolver {
+    pub fn new() -> MdnsResolver {
+        MdnsResolver {
+            timeout: DEFAULT_MDNS_TIMEOUT,
+        }
+    }
+
+    /// Ask the link for the records of `qname`, taking the first response
+    /// that has any. A name nobody answers for is `NXDOMAIN`.
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let group = SocketAddr::from((MDNS_ADDR, MDNS_PORT));
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_multicast_ttl_v4(255)?;
+
+        let mut query = QueryBuilder::new()
+            .recursion_desired(false)
+            .question(qname, qtype)
+            .build();
+        let mut req_buffer = BytePacketBuffer::new();
+        query.write(&mut req_buffer)?;
+        socket.send_to(&req_buffer.buf[..req_buffer.pos()], group)?;
+
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(timeout))?;
+
+            let (len, src) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => match DnsError::from_timed_read(e) {
+                    DnsError::Timeout => break,
+                    e => return Err(e),
+                },
+            };
+            let response =
+                match DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buf[..len])) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("Ignoring mDNS response from {}: {}", src, e);
+                        continue;
+                    }
+                };
+            if !response.header.response || response.header.id != query.header.id {
+                continue;
+            }
+
+            let answers: Vec<DnsRecord> = response
+                .answers
+                .into_iter()
+                .filter(|record| {
+                    record
+                        .domain()
+                        .eq_ignore_ascii_case(qname.trim_end_matches('.'))
+                        && (qtype == QueryType::ANY || record.query_type() == qtype)
+                })
+                .collect();
+            if !answers.is_empty() {
+                debug!("{} answered {} {:?} over mDNS", src, qname, qtype);
+                return Ok(ResponseBuilder::new()
+                    .question(qname, qtype)
+                    .answers(answers)
+                    .build());
+            }
+        }
+
+        Ok(negative_response(
+            qname,
+            qtype,
+            ResultCode::NXDOMAIN,
+            LEGACY_UNICAST_TTL,
+        ))
+    }
+}
+
+/// Answers the mDNS queries of the link for a set of names, such as those of
+/// the machine the server runs on. The names are usually, though not
+/// necessarily, below `local.`.
+#[derive(Clone, Debug)]
+pub struct Responder {
+    pub hosts: Hosts,
+}
+
+impl Responder {
+    pub fn new(hosts: Hosts) -> Responder {
+        Responder { hosts }
+    }
+
+    /// The response to a query received from `src`, written out, along with
+    /// where to send it. Queries that ask for none of our names are left
+    /// unanswered, since others on the link may have the answer.
+    pub fn respond(
+        &self,
+        query: &DnsPacket,
+        src: SocketAddr,
+    ) -> Result<Option<(BytePacketBuffer, SocketAddr)>> {
+        if query.header.response || query.header.opcode != Opcode::QUERY {
+            return Ok(None);
+        }
+
+        // A querier that doesn't send from the mDNS port is a plain DNS
+        // client, which expects a response like any other server would send
+        let legacy = src.port() != MDNS_PORT;
+
+        let mut answers = Vec::new();
+        for question in &query.questions {
+            let Some(response) = self.hosts.answer(&question.name, question.qtype) else {
+                continue;
+            };
+            for mut record in response.answers {
+                if legacy {
+                    record.set_ttl(record.ttl().min(LEGACY_UNICAST_TTL));
+                }
+                // Known answers the querier lists are left out, as long as it
+                // will hold on to them for a while yet
+                let known = query.answers.iter().any(|known| {
+                    let mut known = known.clone();
+                    let ttl = known.ttl();
+                    known.set_ttl(record.ttl());
+                    known == record && ttl >= record.ttl() / 2
+                });
+                if !known && !answers.contains(&record) {
+                    answers.push(record);
+                }
+            }
+        }
+        if answers.is_empty() {
+            return Ok(None);
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        if legacy {
+            let mut response = ResponseBuilder::reply_to(&query.header)
+                .recursion_available(false)
+                .authoritative(true)
+                .questions(query.questions.iter().cloned())
+                .answers(answers)
+                .build();
+            response.write(&mut buffer)?;
+
+            return Ok(Some((buffer, src)));
+        }
+
+        // Our records are our own, so anything else cached for the names is
+        // stale and flushed
+        let response = ResponseBuilder::new()
+            .authoritative(true)
+            .answers(answers)
+            .build();
+        response.header.write(&mut buffer)?;
+        for record in &response.answers {
+            record.write_cache_flush(&mut buffer)?;
+        }
+
+        let dest = if query.questions.iter().all(|q| q.unicast_response) {
+            src
+        } else {
+            SocketAddr::from((MDNS_ADDR, MDNS_PORT))
+        };
+        Ok(Some((buffer, dest)))
+    }
+}
+
+/// Bind the mDNS port and join the group on `interface`, or on the default
+/// one with `0.0.0.0`. Other responders on the same machine may have the
+/// port already, so it's shared.
+pub fn bind(interface: Ipv4Addr) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+
+    Ok(socket.into())
+}
+
+/// Answer mDNS queries on `socket` for good
+pub fn serve(socket: UdpSocket, responder: Responder) {
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    loop {
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Failed to receive mDNS query: {}", e);
+                continue;
+            }
+        };
+
+        let result = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buf[..len]))
+            .and_then(|query| responder.respond(&query, src));
+        match result {
+            Ok(Some((buffer, dest))) => {
+                if let Err(e) = socket.send_to(&buffer.buf[..buffer.pos()], dest) {
+                    warn!("Failed to send mDNS response to {}: {}", dest, e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => debug!("Ignoring mDNS packet from {}: {}", src, e),
+        }
+    }
+}
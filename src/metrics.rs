@@ -0,0 +1,536 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Write as _},
+    hash::Hash,
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{error, warn};
+
+use crate::{
+    cache::Cache,
+    error::{DnsError, Result},
+    name,
+    query_type::QueryType,
+    result_code::ResultCode,
+    server::TCP_IDLE_TIMEOUT,
+};
+
+/// The path the metrics are served on
+pub const PATH: &str = "/metrics";
+
+/// The path the statistics for `dns-server stats` are served on, in the
+/// format of `Stats`
+pub const STATS_PATH: &str = "/stats";
+
+/// How many of the names and clients that sent the most queries the
+/// statistics list, unless the request asks for another number with `?top=`
+pub const DEFAULT_TOP: usize = 10;
+
+/// How many names and clients the queries are counted for. Beyond that, the
+/// counts are halved to make room, which drops those that were only seen
+/// once while the ones queried the most stay on top.
+pub const MAX_TRACKED: usize = 10_000;
+
+/// The version of the Prometheus text format, see
+/// https://prometheus.io/docs/instrumenting/exposition_formats/
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The upper bounds of the latency histogram buckets, in seconds. Answers
+/// from the cache or the local zones take well under a millisecond, whereas
+/// an upstream can take seconds when it times out.
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// The longest a request to the metrics endpoint may be, headers included
+const MAX_REQUEST_LEN: usize = 8192;
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// How many observations fell into each bucket, not counting those of the
+    /// buckets below it, and those above the largest bound last
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    /// The sum of the observations, in microseconds
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        // Prometheus buckets are cumulative, each one counts everything up to
+        // its bound
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let bound = match LATENCY_BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queries: Mutex<BTreeMap<String, u64>>,
+    responses: Mutex<BTreeMap<String, u64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    upstream_errors: AtomicU64,
+    blocked: AtomicU64,
+    dropped: AtomicU64,
+    /// The queries by name and by client, for the top lists of `Stats`
+    names: Mutex<HashMap<String, u64>>,
+    clients: Mutex<HashMap<IpAddr, u64>>,
+    /// The cache whose size is reported, if there's one
+    cache: Mutex<Option<Cache>>,
+    active_connections: AtomicI64,
+    request_latency: Histogram,
+    upstream_latency: Histogram,
+}
+
+/// Counters and histograms of what the server has been up to, in a form that
+/// Prometheus can scrape. Clones share the counts, so that every thread of
+/// the server adds to the same ones.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    counters: Arc<Counters>,
+}
+
+/// Counts a connection as active for as long as it's held on to
+#[derive(Debug)]
+pub struct ActiveConnection {
+    counters: Arc<Counters>,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.counters
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn record_query(&self, qname: &str, qtype: QueryType, client: IpAddr) {
+        let mut queries = self.counters.queries.lock().unwrap();
+        *queries.entry(qtype.to_string()).or_default() += 1;
+        drop(queries);
+
+        count(&self.counters.names, name::normalize(qname));
+        count(&self.counters.clients, client);
+    }
+
+    /// Count a query that was answered the way the blocklist says
+    pub fn record_blocked(&self) {
+        self.counters.blocked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a query that was dropped unanswered, as there were too many
+    /// waiting for a worker already
+    pub fn record_dropped(&self) {
+        self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Report the number of entries in `cache` along with the counters
+    pub fn watch_cache(&self, cache: Cache) {
+        *self.counters.cache.lock().unwrap() = Some(cache);
+    }
+
+    /// Count a response that was sent, along with how long it took to work
+    /// it out
+    pub fn record_response(&self, rcode: ResultCode, latency: Duration) {
+        let mut responses = self.counters.responses.lock().unwrap();
+        *responses.entry(format!("{:?}", rcode)).or_default() += 1;
+        drop(responses);
+
+        self.counters.request_latency.observe(latency);
+    }
+
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let counter = match hit {
+            true => &self.counters.cache_hits,
+            false => &self.counters.cache_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a lookup that had to go out to the upstreams or the
+    /// authoritative servers, along with how long it took
+    pub fn record_upstream(&self, latency: Duration, failed: bool) {
+        self.counters.upstream_latency.observe(latency);
+        if failed {
+            self.counters
+                .upstream_errors
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a TCP or HTTPS connection as active until the returned value is
+    /// dropped
+    pub fn connection(&self) -> ActiveConnection {
+        self.counters
+            .active_connections
+            .fetch_add(1, Ordering::Relaxed);
+        ActiveConnection {
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let counters = &self.counters;
+        let mut out = String::new();
+
+        render_labelled(
+            &mut out,
+            "dns_queries_total",
+            "Queries received, by type",
+            "qtype",
+            &counters.queries.lock().unwrap(),
+        );
+        render_labelled(
+            &mut out,
+            "dns_responses_total",
+            "Responses sent, by response code",
+            "rcode",
+            &counters.responses.lock().unwrap(),
+        );
+        render_value(
+            &mut out,
+            "dns_cache_hits_total",
+            "Lookups answered from the cache",
+            "counter",
+            counters.cache_hits.load(Ordering::Relaxed),
+        );
+        render_value(
+            &mut out,
+            "dns_cache_misses_total",
+            "Lookups of cacheable types that missed the cache",
+            "counter",
+            counters.cache_misses.load(Ordering::Relaxed),
+        );
+        render_value(
+            &mut out,
+            "dns_upstream_errors_total",
+            "Lookups that no upstream or authoritative server answered",
+            "counter",
+            counters.upstream_errors.load(Ordering::Relaxed),
+        );
+        render_value(
+            &mut out,
+            "dns_blocked_total",
+            "Queries for names on the blocklist",
+            "counter",
+            counters.blocked.load(Ordering::Relaxed),
+        );
+        render_value(
+            &mut out,
+            "dns_dropped_total",
+            "Queries dropped unanswered, with the workers falling behind",
+            "counter",
+            counters.dropped.load(Ordering::Relaxed),
+        );
+        if let Some(cache) = &*counters.cache.lock().unwrap() {
+            render_value(
+                &mut out,
+                "dns_cache_entries",
+                "Responses held in the cache",
+                "gauge",
+                cache.len(),
+            );
+        }
+        render_value(
+            &mut out,
+            "dns_active_connections",
+            "TCP and HTTPS connections currently open",
+            "gauge",
+            counters.active_connections.load(Ordering::Relaxed),
+        );
+        counters.request_latency.render(
+            &mut out,
+            "dns_request_duration_seconds",
+            "Time taken to answer a query",
+        );
+        counters.upstream_latency.render(
+            &mut out,
+            "dns_upstream_duration_seconds",
+            "Time taken by the upstreams or authoritative servers to answer a lookup",
+        );
+
+        out
+    }
+
+    /// The counters along with the `top` names and clients that sent the
+    /// most queries
+    pub fn stats(&self, top: usize) -> Stats {
+        let counters = &self.counters;
+        let qtypes = counters.queries.lock().unwrap().clone();
+
+        Stats {
+            queries: qtypes.values().sum(),
+            blocked: counters.blocked.load(Ordering::Relaxed),
+            cache_hits: counters.cache_hits.load(Ordering::Relaxed),
+            cache_misses: counters.cache_misses.load(Ordering::Relaxed),
+            cache_entries: counters.cache.lock().unwrap().as_ref().map(Cache::len),
+            qtypes: most_first(qtypes, usize::MAX),
+            names: most_first(counters.names.lock().unwrap().clone(), top),
+            clients: most_first(counters.clients.lock().unwrap().clone(), top),
+        }
+    }
+}
+
+fn count<K: Eq + Hash>(counts: &Mutex<HashMap<K, u64>>, key: K) {
+    let mut counts = counts.lock().unwrap();
+    if counts.len() >= MAX_TRACKED && !counts.contains_key(&key) {
+        counts.retain(|_, count| {
+            *count /= 2;
+            *count > 0
+        });
+    }
+    *counts.entry(key).or_default() += 1;
+}
+
+fn most_first<K: Ord>(counts: impl IntoIterator<Item = (K, u64)>, top: usize) -> Vec<(K, u64)> {
+    let mut counts: Vec<(K, u64)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(top);
+    counts
+}
+
+/// What the server has been up to, as `dns-server stats` shows it. It's sent
+/// as plain text, one counter per line, with the lists by one line per
+/// entry in the order they're ranked:
+///
+/// ```text
+/// queries 1200
+/// blocked 31
+/// cache_hits 700
+/// cache_misses 400
+/// cache_entries 388
+/// qtype A 800
+/// qtype AAAA 400
+/// name example.com 120
+/// client 192.168.1.20 950
+/// ```
+///
+/// Lines it doesn't know are skipped, so that newer servers can add to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub queries: u64,
+    pub blocked: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// `None` unless the server caches
+    pub cache_entries: Option<usize>,
+    pub qtypes: Vec<(String, u64)>,
+    pub names: Vec<(String, u64)>,
+    pub clients: Vec<(IpAddr, u64)>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "queries {}", self.queries)?;
+        writeln!(f, "blocked {}", self.blocked)?;
+        writeln!(f, "cache_hits {}", self.cache_hits)?;
+        writeln!(f, "cache_misses {}", self.cache_misses)?;
+        if let Some(entries) = self.cache_entries {
+            writeln!(f, "cache_entries {}", entries)?;
+        }
+        for (qtype, count) in &self.qtypes {
+            writeln!(f, "qtype {} {}", qtype, count)?;
+        }
+        for (name, count) in &self.names {
+            writeln!(f, "name {} {}", name, count)?;
+        }
+        for (client, count) in &self.clients {
+            writeln!(f, "client {} {}", client, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Stats {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Stats> {
+        let invalid = |line: &str| DnsError::Parse(format!("Invalid statistics line: {}", line));
+        let number = |value: &str, line: &str| value.parse::<u64>().map_err(|_| invalid(line));
+
+        let mut stats = Stats::default();
+        for line in s.lines().filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once(' ').ok_or_else(|| invalid(line))?;
+            match key {
+                "queries" => stats.queries = number(value, line)?,
+                "blocked" => stats.blocked = number(value, line)?,
+                "cache_hits" => stats.cache_hits = number(value, line)?,
+                "cache_misses" => stats.cache_misses = number(value, line)?,
+                "cache_entries" => stats.cache_entries = Some(number(value, line)? as usize),
+                "qtype" | "name" | "client" => {
+                    let (label, count) = value.rsplit_once(' ').ok_or_else(|| invalid(line))?;
+                    let count = number(count, line)?;
+                    match key {
+                        "qtype" => stats.qtypes.push((label.to_string(), count)),
+                        "name" => stats.names.push((label.to_string(), count)),
+                        _ => stats
+                            .clients
+                            .push((label.parse().map_err(|_| invalid(line))?, count)),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+/// Ask the server whose metrics are served on `server` for its statistics,
+/// with the `top` names and clients
+pub fn fetch_stats(server: SocketAddr, top: usize, timeout: Duration) -> Result<Stats> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {}?top={} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        STATS_PATH, top, server
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| DnsError::Http("Malformed response".to_string()))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(DnsError::Http(format!("Server responded with {}", status)));
+    }
+
+    body.parse()
+}
+
+fn render_value(out: &mut String, name: &str, help: &str, kind: &str, value: impl ToString) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value.to_string());
+}
+
+fn render_labelled(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: &BTreeMap<String, u64>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (key, value) in values {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, key, value);
+    }
+}
+
+/// Serve the metrics over plain HTTP on `/metrics`, for Prometheus to scrape,
+/// and the statistics on `/stats`, for `fetch_stats`. Every connection is
+/// closed after a single response.
+pub fn serve(listener: TcpListener, metrics: Metrics) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &metrics) {
+                warn!("Failed to serve metrics: {}", e);
+            }
+        });
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+
+    // Nothing but the request line matters, the headers are read past so
+    // that the client isn't cut off in the middle of sending them
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_REQUEST_LEN as u64);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(DnsError::Http(
+                "Connection closed before the end of the headers".to_string(),
+            ));
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let (status, body) = match (method, path) {
+        ("GET", PATH) => ("200 OK", metrics.render()),
+        ("GET", STATS_PATH) => {
+            let top = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("top="))
+                .and_then(|top| top.parse().ok())
+                .unwrap_or(DEFAULT_TOP);
+            ("200 OK", metrics.stats(top).to_string())
+        }
+        (_, PATH | STATS_PATH) => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+
+    Ok(())
+}
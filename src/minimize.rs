@@ -0,0 +1,145 @@
+//! QNAME minimisation (RFC 9156). Resolving a name recursively, the root
+//! servers only need to know its top-level domain to hand out the servers of
+//! that, and those in turn only the label below, and so on. So rather than
+//! telling every server along the way the full name, each of them is only
+//! asked about the name one label below the zone it serves, and only the
+//! servers of the zone of the name itself get the full question.
+//!
+//! Not every server answers questions about the names in between properly,
+//! some of them fail or deny that a name exists when it has no records of
+//! its own (an empty non-terminal). Once one misbehaves, the rest of the
+//! lookup goes on with the full name.
+
+use crate::{dns_packet::DnsPacket, dns_record::DnsRecord, name, query_type::QueryType};
+
+/// The most questions asked for a single lookup, the one about the full name
+/// included, so that names with a lot of labels don't take a query per label
+pub const MAX_MINIMISE_COUNT: usize = 10;
+
+/// The first questions about the names in between each add a single label,
+/// after that the labels left are spread over what's left of
+/// `MAX_MINIMISE_COUNT`
+pub const MINIMISE_ONE_LAB: usize = 4;
+
+/// Where a recursive lookup of a name is at: the zone whose servers are
+/// being asked, and how much of the name they're asked about
+#[derive(Clone, Debug)]
+pub struct Minimizer {
+    qname: String,
+    qtype: QueryType,
+    /// Where each label of `qname` starts, from the first one on
+    starts: Vec<usize>,
+    /// The labels of the longest name known to be in the zone whose servers
+    /// are asked
+    zone_labels: usize,
+    /// The labels of the name they're asked about
+    labels: usize,
+    /// How many questions about the names in between have been asked
+    count: usize,
+}
+
+impl Minimizer {
+    /// Look up `qname`, asking about as little of it as possible, or all of
+    /// it right away unless `enabled`
+    pub fn new(qname: &str, qtype: QueryType, enabled: bool) -> Minimizer {
+        let trimmed = qname.trim_end_matches('.');
+        let mut starts = Vec::new();
+        if !trimmed.is_empty() {
+            starts.push(0);
+            starts.extend(trimmed.match_indices('.').map(|(i, _)| i + 1));
+        }
+
+        let mut minimizer = Minimizer {
+            qname: qname.to_string(),
+            qtype,
+            starts,
+            zone_labels: 0,
+            labels: 0,
+            count: 0,
+        };
+        match enabled {
+            true => minimizer.advance(),
+            false => minimizer.give_up(),
+        }
+        minimizer
+    }
+
+    /// The name and type to ask the servers of the zone about. The names in
+    /// between are asked about with `A`, which is what they'd be asked about
+    /// most of the time anyway, rather than `NS`, which gives away that the
+    /// question is part of a lookup.
+    pub fn question(&self) -> (&str, QueryType) {
+        match self.is_minimized() {
+            true => {
+                let start = self.starts[self.starts.len() - self.labels];
+                (&self.qname[start..], QueryType::A)
+            }
+            false => (&self.qname, self.qtype),
+        }
+    }
+
+    /// Whether the question leaves out part of the name
+    pub fn is_minimized(&self) -> bool {
+        self.labels < self.starts.len()
+    }
+
+    /// Follow a referral to the servers of a zone further down, if the
+    /// response to a minimised question is one. Otherwise the name asked
+    /// about is in the zone of the same servers, which are asked about the
+    /// next labels then, and false is returned.
+    pub fn referral(&mut self, response: &DnsPacket) -> bool {
+        let (asked, _) = self.question();
+        let zone = response
+            .authorities
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::NS { domain, .. } if response.answers.is_empty() => Some(domain),
+                _ => None,
+            })
+            .filter(|domain| name::is_subdomain(asked, domain))
+            .map(|domain| label_count(domain))
+            .filter(|&labels| labels > self.zone_labels)
+            .max();
+
+        match zone {
+            Some(labels) => {
+                self.zone_labels = labels;
+                self.advance();
+                true
+            }
+            None => {
+                self.zone_labels = self.labels;
+                self.advance();
+                false
+            }
+        }
+    }
+
+    /// Ask about the full name from here on, after a server misbehaved
+    pub fn give_up(&mut self) {
+        self.labels = self.starts.len();
+    }
+
+    /// Ask about the next labels of the name below the zone, as many as
+    /// `MINIMISE_ONE_LAB` and `MAX_MINIMISE_COUNT` allow
+    fn advance(&mut self) {
+        let left = self.starts.len().saturating_sub(self.zone_labels);
+        let step = match self.count {
+            count if count < MINIMISE_ONE_LAB => 1,
+            count if count < MAX_MINIMISE_COUNT => left.div_ceil(MAX_MINIMISE_COUNT - count),
+            _ => left,
+        };
+        self.labels = (self.zone_labels + step.max(1)).min(self.starts.len());
+        if self.is_minimized() {
+            self.count += 1;
+        }
+    }
+}
+
+fn label_count(name: &str) -> usize {
+    let name = name.trim_end_matches('.');
+    match name.is_empty() {
+        true => 0,
+        false => name.split('.').count(),
+    }
+}
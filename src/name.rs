@@ -0,0 +1,33 @@
+//! Comparing domain names. Names are kept in the case they came in, so that
+//! they go back out the way they were received, but they match regardless
+//! of the case of their ASCII letters (RFC 4343). A trailing dot makes no
+//! difference either, and the root is the empty name.
+
+/// Whether `a` and `b` are the same name
+pub fn eq(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// Whether `name` is `zone` itself or a name below it
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let zone = zone.trim_end_matches('.');
+    if zone.is_empty() {
+        return true;
+    }
+
+    match name.len().checked_sub(zone.len()) {
+        Some(0) => name.eq_ignore_ascii_case(zone),
+        Some(start) => {
+            name.as_bytes()[start - 1] == b'.'
+                && name.as_bytes()[start..].eq_ignore_ascii_case(zone.as_bytes())
+        }
+        None => false,
+    }
+}
+
+/// The name lowercase and without the trailing dot, for keying maps by name
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,96 @@
+use std::collections::HashSet;
+
+use crate::{
+    dns_packet::{DnsPacket, ResponseBuilder},
+    dns_record::DnsRecord,
+    name,
+    query_type::QueryType,
+    result_code::ResultCode,
+};
+
+/// The negative caching TTL used when none is configured
+pub const DEFAULT_NEGATIVE_TTL: u32 = 300;
+
+/// A set of names that are answered with `NXDOMAIN` no matter what, for
+/// exercising the negative caching of clients in test setups. Unlike a real
+/// negative answer it's never looked up anywhere.
+///
+/// Each answer carries an SOA record in the authority section, since that's
+/// where clients take the negative caching TTL from (RFC 2308).
+#[derive(Clone, Debug)]
+pub struct NxdomainList {
+    names: HashSet<String>,
+    /// How long clients may cache the negative answer for
+    pub ttl: u32,
+}
+
+impl Default for NxdomainList {
+    fn default() -> NxdomainList {
+        NxdomainList::new()
+    }
+}
+
+impl NxdomainList {
+    pub fn new() -> NxdomainList {
+        NxdomainList {
+            names: HashSet::new(),
+            ttl: DEFAULT_NEGATIVE_TTL,
+        }
+    }
+
+    /// Add a name to the list. Names are matched case-insensitively, and a
+    /// trailing dot is ignored.
+    pub fn insert(&mut self, name: &str) {
+        self.names.insert(name::normalize(name));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(&name::normalize(name))
+    }
+
+    /// The response to a query for `qname`, if the name is on the list
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        if !self.contains(qname) {
+            return None;
+        }
+
+        Some(negative_response(
+            qname,
+            qtype,
+            ResultCode::NXDOMAIN,
+            self.ttl,
+        ))
+    }
+}
+
+/// A made up negative answer for `qname`, either `NXDOMAIN` or a `NOERROR`
+/// without any answers, that clients may cache for `ttl` seconds
+pub(crate) fn negative_response(
+    qname: &str,
+    qtype: QueryType,
+    rescode: ResultCode,
+    ttl: u32,
+) -> DnsPacket {
+    // There's no zone to speak of, the SOA is made out to be that of the
+    // parent of the name, as though it didn't exist.
+    let name = name::normalize(qname);
+    let zone = name.split_once('.').map_or("", |(_, parent)| parent);
+    let soa = DnsRecord::SOA {
+        domain: zone.to_string(),
+        mname: "localhost".to_string(),
+        rname: "hostmaster.localhost".to_string(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: ttl,
+        ttl,
+    };
+
+    ResponseBuilder::new()
+        .authoritative(true)
+        .rcode(rescode)
+        .question(qname, qtype)
+        .authority(soa)
+        .build()
+}
@@ -0,0 +1,223 @@
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use crate::{
+    dns_packet::DnsPacket,
+    error::{DnsError, Result},
+    query_type::QueryType,
+    resolver::{self, ResolverOptions, Source},
+};
+
+/// A question on its way through the `Pipeline`
+#[derive(Clone, Copy, Debug)]
+pub struct Query<'a> {
+    pub name: &'a str,
+    pub qtype: QueryType,
+    /// The client that asked, for queries that come from one
+    pub client: Option<SocketAddr>,
+    /// How the query is resolved, which are those of the view the client
+    /// belongs to, see `ResolverOptions::for_client`
+    pub options: &'a Arc<ResolverOptions>,
+}
+
+/// A step of the `Pipeline`, which either answers a query itself or hands it
+/// on to the steps after it with `next.run`. Handing it on lets a handler
+/// change the query on the way in and the response on the way out, so that
+/// logging, rewriting names or answers, or policies such as which clients
+/// get to ask for what can be plugged in without touching the rest.
+///
+/// ```
+/// use dns_server::{
+///     error::Result,
+///     pipeline::{Next, Query, QueryHandler},
+///     resolver::Source,
+///     DnsPacket,
+/// };
+///
+/// struct Logger;
+///
+/// impl QueryHandler for Logger {
+///     fn handle(&self, query: &Query, next: Next) -> Result<(DnsPacket, Source)> {
+///         let result = next.run(query);
+///         if let Ok((response, source)) = &result {
+///             println!("{} {:?}: {:?} from {}", query.name, query.qtype,
+///                      response.header.rescode, source.as_str());
+///         }
+///         result
+///     }
+/// }
+/// ```
+pub trait QueryHandler: Send + Sync {
+    fn handle(&self, query: &Query, next: Next) -> Result<(DnsPacket, Source)>;
+}
+
+/// The steps of the pipeline that come built in, in the order they're run.
+/// Each of them passes the query on when there's nothing for it to do,
+/// because e.g. the name isn't blocked or there's no cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Rewrites the answers that `Redirect` sends elsewhere
+    Redirect,
+    /// Answers the names that are blocked
+    Blocklist,
+    /// Answers the names on the NXDOMAIN list
+    Nxdomain,
+    /// Synthesizes AAAA records for the names that only have A records
+    Dns64,
+    /// Checks the DNSSEC signatures of what comes back
+    Validate,
+    /// Follows aliases to the records they stand for
+    Cnames,
+    /// Answers from the hosts files
+    Hosts,
+    /// Answers from the local, secondary and dynamic zones
+    Zones,
+    /// Looks up `.local` names over multicast DNS
+    Mdns,
+    /// Answers from earlier responses, and keeps the responses of what comes
+    /// after it
+    Cache,
+    /// Asks the upstreams, or the authoritative servers recursively, which
+    /// always has the final say
+    Upstream,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 11] = [
+        Stage::Redirect,
+        Stage::Blocklist,
+        Stage::Nxdomain,
+        Stage::Dns64,
+        Stage::Validate,
+        Stage::Cnames,
+        Stage::Hosts,
+        Stage::Zones,
+        Stage::Mdns,
+        Stage::Cache,
+        Stage::Upstream,
+    ];
+}
+
+#[derive(Clone)]
+enum Handler {
+    Stage(Stage),
+    Custom(Arc<dyn QueryHandler>),
+}
+
+/// The chain of handlers every query goes through, starting out with the
+/// built-in stages, which custom `QueryHandler`s can be put in between of.
+/// The first handler that answers has the final say, apart from the handlers
+/// before it changing its answer on the way back.
+///
+/// Lookups made along the way, such as following an alias, only go through
+/// the part of the pipeline from `Stage::Cnames` on.
+#[derive(Clone)]
+pub struct Pipeline {
+    handlers: Vec<Handler>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline::new()
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.handlers.iter().map(|handler| match handler {
+                Handler::Stage(stage) => format!("{:?}", stage),
+                Handler::Custom(_) => "Custom".to_string(),
+            }))
+            .finish()
+    }
+}
+
+impl Pipeline {
+    /// The built-in stages, in order
+    pub fn new() -> Pipeline {
+        Pipeline {
+            handlers: Stage::ALL.into_iter().map(Handler::Stage).collect(),
+        }
+    }
+
+    /// Run `handler` before anything else
+    pub fn push_front(&mut self, handler: impl QueryHandler + 'static) {
+        self.handlers.insert(0, Handler::Custom(Arc::new(handler)));
+    }
+
+    /// Run `handler` right before `stage`, or last if the stage was removed
+    pub fn insert_before(&mut self, stage: Stage, handler: impl QueryHandler + 'static) {
+        let index = self.position(stage).unwrap_or(self.handlers.len());
+        self.handlers
+            .insert(index, Handler::Custom(Arc::new(handler)));
+    }
+
+    /// Leave out one of the built-in stages, e.g. `Stage::Upstream` for a
+    /// server that only answers from what it has locally
+    pub fn remove(&mut self, stage: Stage) {
+        self.handlers
+            .retain(|handler| !matches!(handler, Handler::Stage(other) if *other == stage));
+    }
+
+    fn position(&self, stage: Stage) -> Option<usize> {
+        self.handlers
+            .iter()
+            .position(|handler| matches!(handler, Handler::Stage(other) if *other == stage))
+    }
+
+    /// Run a query through the whole pipeline
+    pub fn run(&self, query: &Query) -> Result<(DnsPacket, Source)> {
+        Next {
+            handlers: &self.handlers,
+        }
+        .run(query)
+    }
+
+    /// The part of the pipeline from `stage` on, or from the first of the
+    /// built-in stages after it that's still there
+    pub(crate) fn starting_at(&self, stage: Stage) -> Next<'_> {
+        let index = self
+            .handlers
+            .iter()
+            .position(|handler| matches!(handler, Handler::Stage(other) if *other >= stage))
+            .unwrap_or(self.handlers.len());
+
+        Next {
+            handlers: &self.handlers[index..],
+        }
+    }
+
+    /// The part of the pipeline that `stage` hands its queries on to, for
+    /// asking again what it asked before
+    pub(crate) fn after(&self, stage: Stage) -> Next<'_> {
+        match self.position(stage) {
+            Some(index) => Next {
+                handlers: &self.handlers[index + 1..],
+            },
+            None => self.starting_at(stage),
+        }
+    }
+}
+
+/// The handlers after the one that's running, see `QueryHandler`
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    handlers: &'a [Handler],
+}
+
+impl Next<'_> {
+    /// Have the rest of the pipeline answer `query`. Once there's nothing
+    /// left to ask, because the stages that would have answered it were
+    /// removed, that's `DnsError::NoServers`.
+    pub fn run(self, query: &Query) -> Result<(DnsPacket, Source)> {
+        let Some((handler, rest)) = self.handlers.split_first() else {
+            return Err(DnsError::NoServers);
+        };
+
+        let next = Next { handlers: rest };
+        match handler {
+            Handler::Stage(stage) => resolver::run_stage(*stage, query, next),
+            Handler::Custom(handler) => handler.handle(query, next),
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    error::{DnsError, Result},
+};
+
+/// How long a connection may go without a query before it's closed. It's
+/// kept just short of the ten seconds servers such as ours wait, so that it's
+/// usually us closing it rather than the server closing it under a query.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// How many queries may wait for their response on a single connection.
+/// Queries beyond that go out on another connection to the same upstream.
+pub const MAX_IN_FLIGHT: usize = 32;
+
+/// How often the thread reading the responses off a connection checks
+/// whether it has been idle for too long
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connection that messages, each prefixed with its length, can be sent on
+/// from any thread while a thread of its own receives the messages coming
+/// back
+pub(crate) trait MessageStream: Send + Sync {
+    fn send(&self, message: &[u8]) -> io::Result<()>;
+
+    /// Wait for more data, adding what arrived to `buf`. Returns whether
+    /// the connection is still open, and may time out after `POLL_INTERVAL`.
+    fn receive(&self, buf: &mut Vec<u8>) -> io::Result<bool>;
+
+    fn close(&self);
+}
+
+/// A plain TCP connection, with a clone of the socket for each direction
+pub(crate) struct TcpConnection {
+    writer: Mutex<TcpStream>,
+    reader: TcpStream,
+}
+
+impl TcpConnection {
+    pub(crate) fn connect(server: SocketAddr, timeout: Duration) -> Result<TcpConnection> {
+        let reader = TcpStream::connect_timeout(&server, timeout)?;
+        reader.set_read_timeout(Some(POLL_INTERVAL))?;
+        reader.set_write_timeout(Some(timeout))?;
+
+        Ok(TcpConnection {
+            writer: Mutex::new(reader.try_clone()?),
+            reader,
+        })
+    }
+}
+
+impl MessageStream for TcpConnection {
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        self.writer.lock().unwrap().write_all(message)
+    }
+
+    fn receive(&self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        let mut data = [0; 4096];
+        let len = (&self.reader).read(&mut data)?;
+        buf.extend_from_slice(&data[..len]);
+
+        Ok(len > 0)
+    }
+
+    fn close(&self) {
+        let _ = self.reader.shutdown(Shutdown::Both);
+    }
+}
+
+struct State {
+    /// Where to hand the responses to the queries waiting for them, by ID
+    pending: HashMap<u16, Sender<BytePacketBuffer>>,
+    last_used: Instant,
+    closed: bool,
+}
+
+struct Connection {
+    stream: Box<dyn MessageStream>,
+    state: Mutex<State>,
+}
+
+impl Connection {
+    /// Wait for the response to query `id` on this connection, unless it's
+    /// closed, busy, or already has a query with the same ID in flight
+    fn register(&self, id: u16) -> Option<Receiver<BytePacketBuffer>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.pending.len() >= MAX_IN_FLIGHT || state.pending.contains_key(&id) {
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        state.pending.insert(id, sender);
+        state.last_used = Instant::now();
+        Some(receiver)
+    }
+
+    fn load(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    fn exchange(
+        &self,
+        id: u16,
+        responses: Receiver<BytePacketBuffer>,
+        req_buffer: &BytePacketBuffer,
+        timeout: Duration,
+    ) -> Result<BytePacketBuffer> {
+        let mut message = (req_buffer.pos() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&req_buffer.buf[0..req_buffer.pos()]);
+        if let Err(e) = self.stream.send(&message) {
+            self.close();
+            return Err(e.into());
+        }
+
+        match responses.recv_timeout(timeout) {
+            Ok(res_buffer) => Ok(res_buffer),
+            Err(RecvTimeoutError::Timeout) => {
+                self.state.lock().unwrap().pending.remove(&id);
+                Err(DnsError::Timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(DnsError::Io(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                "Connection closed before the response arrived",
+            ))),
+        }
+    }
+
+    /// Read the responses off the connection, handing each to the query with
+    /// its ID in whatever order they come in, until the connection is closed
+    /// or has been idle for `IDLE_TIMEOUT`
+    fn read_responses(&self) {
+        let mut buf = Vec::new();
+        loop {
+            match self.stream.receive(&mut buf) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let state = self.state.lock().unwrap();
+                    if state.pending.is_empty() && state.last_used.elapsed() >= IDLE_TIMEOUT {
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Pooled connection failed: {}", e);
+                    break;
+                }
+            }
+
+            while buf.len() >= 2 {
+                let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+                if buf.len() < len + 2 {
+                    break;
+                }
+                let message: Vec<u8> = buf.drain(..len + 2).skip(2).collect();
+                if message.len() < 2 {
+                    continue;
+                }
+
+                let id = u16::from_be_bytes([message[0], message[1]]);
+                match self.state.lock().unwrap().pending.remove(&id) {
+                    Some(sender) => {
+                        let _ = sender.send(BytePacketBuffer::from_slice(&message));
+                    }
+                    // Most likely a response that arrived after its query
+                    // gave up on it
+                    None => debug!("Dropping response {} that nobody waits for", id),
+                }
+            }
+        }
+
+        self.close();
+    }
+
+    /// Close the connection, failing the queries still waiting on it
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.pending.clear();
+        self.stream.close();
+    }
+}
+
+/// Connections to upstreams over stream transports, which are kept open for
+/// the queries after the one they were opened for, since setting up a
+/// connection costs a round trip, and one over TLS more than that.
+///
+/// Queries don't wait their turn on a connection. Up to `MAX_IN_FLIGHT` of
+/// them are sent one after the other without waiting for the responses,
+/// which are matched up with their queries by ID, as the server is free to
+/// answer in any order (RFC 7766, section 6.2.1.1). A connection that has
+/// gone without a query for `IDLE_TIMEOUT` is closed.
+pub(crate) struct ConnectionPool<K> {
+    connections: Arc<Mutex<HashMap<K, Vec<Arc<Connection>>>>>,
+}
+
+impl<K> Clone for ConnectionPool<K> {
+    fn clone(&self) -> ConnectionPool<K> {
+        ConnectionPool {
+            connections: self.connections.clone(),
+        }
+    }
+}
+
+impl<K> fmt::Debug for ConnectionPool<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionPool").finish_non_exhaustive()
+    }
+}
+
+impl<K> Default for ConnectionPool<K> {
+    fn default() -> ConnectionPool<K> {
+        ConnectionPool {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> ConnectionPool<K> {
+    /// Exchange a query for its response over one of the connections to the
+    /// upstream `key` stands for, opening one with `connect` if none of them
+    /// can take the query
+    pub(crate) fn exchange<S: MessageStream + 'static>(
+        &self,
+        key: &K,
+        req_buffer: &BytePacketBuffer,
+        timeout: Duration,
+        connect: impl FnOnce() -> Result<S>,
+    ) -> Result<BytePacketBuffer> {
+        let id = u16::from_be_bytes([req_buffer.buf[0], req_buffer.buf[1]]);
+
+        // The upstream may have closed a connection just as the query went
+        // out, in which case we start over on a fresh one.
+        if let Some((connection, responses)) = self.shared(key, id) {
+            match connection.exchange(id, responses, req_buffer, timeout) {
+                Err(DnsError::Io(e)) => debug!("Pooled connection failed: {}", e),
+                result => return result,
+            }
+        }
+
+        let connection = Arc::new(Connection {
+            stream: Box::new(connect()?),
+            state: Mutex::new(State {
+                pending: HashMap::new(),
+                last_used: Instant::now(),
+                closed: false,
+            }),
+        });
+        let responses = connection
+            .register(id)
+            .expect("New connections have room for a query");
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .push(connection.clone());
+
+        let reader = connection.clone();
+        thread::spawn(move || reader.read_responses());
+
+        connection.exchange(id, responses, req_buffer, timeout)
+    }
+
+    /// The open connection to the upstream with the fewest queries in flight,
+    /// if it can take another one
+    fn shared(&self, key: &K, id: u16) -> Option<(Arc<Connection>, Receiver<BytePacketBuffer>)> {
+        let mut connections = self.connections.lock().unwrap();
+        let open = connections.get_mut(key)?;
+        open.retain(|connection| !connection.is_closed());
+
+        open.sort_by_key(|connection| connection.load());
+        open.iter().find_map(|connection| {
+            let responses = connection.register(id)?;
+            Some((connection.clone(), responses))
+        })
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::warn;
+
+use crate::{
+    dns_json::json_string,
+    error::{DnsError, Result},
+    query_type::QueryType,
+    resolver::Source,
+    result_code::ResultCode,
+    server::Transport,
+};
+
+/// How the lines of the query log are written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueryLogFormat {
+    /// A JSON object per line, for shipping the log off to analysis tools
+    #[default]
+    Json,
+    /// Fields separated by spaces, for reading along
+    Text,
+}
+
+impl FromStr for QueryLogFormat {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<QueryLogFormat> {
+        match s {
+            "json" => Ok(QueryLogFormat::Json),
+            "text" => Ok(QueryLogFormat::Text),
+            _ => Err(DnsError::Parse(format!("Unknown query log format: {}", s))),
+        }
+    }
+}
+
+/// What's logged about a query once it's been answered
+#[derive(Clone, Debug)]
+pub struct QueryLogEntry<'a> {
+    pub client: SocketAddr,
+    pub transport: Transport,
+    pub qname: &'a str,
+    pub qtype: QueryType,
+    pub rcode: ResultCode,
+    /// The number of records in the answer section as sent
+    pub answers: u16,
+    /// Where the answer came from, or `None` if the query wasn't resolved,
+    /// e.g. because resolving it failed
+    pub source: Option<Source>,
+    /// How long it took from receiving the query to having the response ready
+    pub latency: Duration,
+}
+
+/// Records every query the server answers, one line per query. Clones share
+/// the output, so that every thread of the server writes to the same file.
+#[derive(Clone)]
+pub struct QueryLog {
+    format: QueryLogFormat,
+    output: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl fmt::Debug for QueryLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueryLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+impl QueryLog {
+    pub fn new(output: impl Write + Send + 'static, format: QueryLogFormat) -> QueryLog {
+        QueryLog {
+            format,
+            output: Arc::new(Mutex::new(Box::new(output))),
+        }
+    }
+
+    /// Log to the file at `path`, appending to it if it exists already. A
+    /// path of `-` stands for standard output.
+    pub fn open<P: AsRef<Path>>(path: P, format: QueryLogFormat) -> Result<QueryLog> {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            return Ok(QueryLog::new(io::stdout(), format));
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(QueryLog::new(file, format))
+    }
+
+    pub fn record(&self, entry: &QueryLogEntry) {
+        let line = match self.format {
+            QueryLogFormat::Json => json_line(entry),
+            QueryLogFormat::Text => text_line(entry),
+        };
+
+        // Each line is written in one go, so that lines from different
+        // threads never end up interleaved
+        let mut output = self.output.lock().unwrap();
+        if let Err(e) = output
+            .write_all(line.as_bytes())
+            .and_then(|()| output.flush())
+        {
+            warn!("Failed to write to the query log: {}", e);
+        }
+    }
+}
+
+fn json_line(entry: &QueryLogEntry) -> String {
+    let source = match entry.source {
+        Some(source) => json_string(source.as_str()),
+        None => "null".to_string(),
+    };
+
+    format!(
+        "{{\"time\":\"{}\",\"client\":\"{}\",\"transport\":\"{}\",\"qname\":{},\"qtype\":\"{}\",\
+         \"rcode\":\"{:?}\",\"answers\":{},\"source\":{},\"cache_hit\":{},\"latency_ms\":{:.3}}}\n",
+        jiff::Timestamp::now(),
+        entry.client,
+        entry.transport.as_str(),
+        json_string(entry.qname),
+        entry.qtype,
+        entry.rcode,
+        entry.answers,
+        source,
+        entry.source == Some(Source::Cache),
+        entry.latency.as_secs_f64() * 1000.0
+    )
+}
+
+fn text_line(entry: &QueryLogEntry) -> String {
+    format!(
+        "{} {} {} {} {} {:?} answers={} source={} {:.3}ms\n",
+        jiff::Timestamp::now(),
+        entry.client,
+        entry.transport.as_str(),
+        entry.qname,
+        entry.qtype,
+        entry.rcode,
+        entry.answers,
+        entry.source.map_or("-", |source| source.as_str()),
+        entry.latency.as_secs_f64() * 1000.0
+    )
+}
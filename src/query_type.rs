@@ -1,22 +1,76 @@
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use crate::error::DnsError;
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
 pub enum QueryType {
     UNKNOWN(u16),
-    A, // 1
-    NS, // 2
+    A,      // 1
+    NS,     // 2
     CNAME,  // 5
+    SOA,    // 6
+    PTR,    // 12
     MX,     // 15
+    TXT,    // 16
+    X25,    // 19
+    ISDN,   // 20
+    RT,     // 21
     AAAA,   // 28
+    SRV,    // 33
+    ATMA,   // 34
+    KX,     // 36
+    OPT,    // 41
+    DS,     // 43
+    RRSIG,  // 46
+    NSEC,   // 47
+    DNSKEY, // 48
+    NSEC3,  // 50
+    SVCB,   // 64
+    HTTPS,  // 65
+    NID,    // 104
+    L32,    // 105
+    L64,    // 106
+    LP,     // 107
+    IXFR,   // 251
+    AXFR,   // 252
+    ANY,    // 255
+    CAA,    // 257
 }
 
 impl QueryType {
-    pub fn to_num(&self) -> u16 {
-        match *self {
+    pub fn to_num(self) -> u16 {
+        match self {
             QueryType::UNKNOWN(x) => x,
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
-            QueryType::AAAA => 28
+            QueryType::TXT => 16,
+            QueryType::X25 => 19,
+            QueryType::ISDN => 20,
+            QueryType::RT => 21,
+            QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::ATMA => 34,
+            QueryType::KX => 36,
+            QueryType::OPT => 41,
+            QueryType::DS => 43,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
+            QueryType::SVCB => 64,
+            QueryType::HTTPS => 65,
+            QueryType::NID => 104,
+            QueryType::L32 => 105,
+            QueryType::L64 => 106,
+            QueryType::LP => 107,
+            QueryType::IXFR => 251,
+            QueryType::AXFR => 252,
+            QueryType::ANY => 255,
+            QueryType::CAA => 257,
         }
     }
 
@@ -25,12 +79,179 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
+            19 => QueryType::X25,
+            20 => QueryType::ISDN,
+            21 => QueryType::RT,
             28 => QueryType::AAAA,
-            _ => QueryType::UNKNOWN(num)
+            33 => QueryType::SRV,
+            34 => QueryType::ATMA,
+            36 => QueryType::KX,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            64 => QueryType::SVCB,
+            65 => QueryType::HTTPS,
+            104 => QueryType::NID,
+            105 => QueryType::L32,
+            106 => QueryType::L64,
+            107 => QueryType::LP,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
+            255 => QueryType::ANY,
+            257 => QueryType::CAA,
+            _ => QueryType::UNKNOWN(num),
         }
     }
+}
+
+/// Query types sort by their numeric value, which is the order RRsets appear
+/// in for canonical output. The declaration order of the variants wouldn't
+/// do, since unknown types would all sort first.
+impl Ord for QueryType {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.to_num().cmp(&other.to_num())
+    }
+}
+
+impl PartialOrd for QueryType {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The mnemonic of the type, or the generic `TYPE123` notation for types we
+/// don't know by name, so that the output parses back with `FromStr`
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{}", num),
+            qtype => write!(f, "{:?}", qtype),
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = DnsError;
+
+    /// Parse a query type from its mnemonic, e.g. `AAAA`, or from the generic
+    /// `TYPE123` notation of RFC 3597 for types we don't know by name.
+    fn from_str(s: &str) -> Result<QueryType, Self::Err> {
+        let qtype = match s.to_uppercase().as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "SOA" => QueryType::SOA,
+            "PTR" => QueryType::PTR,
+            "MX" => QueryType::MX,
+            "TXT" => QueryType::TXT,
+            "X25" => QueryType::X25,
+            "ISDN" => QueryType::ISDN,
+            "RT" => QueryType::RT,
+            "AAAA" => QueryType::AAAA,
+            "SRV" => QueryType::SRV,
+            "ATMA" => QueryType::ATMA,
+            "KX" => QueryType::KX,
+            "OPT" => QueryType::OPT,
+            "DS" => QueryType::DS,
+            "RRSIG" => QueryType::RRSIG,
+            "NSEC" => QueryType::NSEC,
+            "DNSKEY" => QueryType::DNSKEY,
+            "NSEC3" => QueryType::NSEC3,
+            "SVCB" => QueryType::SVCB,
+            "HTTPS" => QueryType::HTTPS,
+            "NID" => QueryType::NID,
+            "L32" => QueryType::L32,
+            "L64" => QueryType::L64,
+            "LP" => QueryType::LP,
+            "IXFR" => QueryType::IXFR,
+            "AXFR" => QueryType::AXFR,
+            "ANY" => QueryType::ANY,
+            "CAA" => QueryType::CAA,
+            other => {
+                let num = other
+                    .strip_prefix("TYPE")
+                    .and_then(|num| num.parse::<u16>().ok())
+                    .ok_or_else(|| DnsError::Parse(format!("Unknown query type: {}", s)))?;
+                QueryType::from_num(num)
+            }
+        };
+
+        Ok(qtype)
+    }
+}
+
+/// Query types serialize to their mnemonic, the same as `Display`, rather
+/// than to the name of the variant
+#[cfg(feature = "serde")]
+impl serde::Serialize for QueryType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for QueryType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<QueryType, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
 
+    #[test]
+    fn types_are_parsed_from_their_names() {
+        assert_eq!("aaaa".parse::<QueryType>().unwrap(), QueryType::AAAA);
+        assert_eq!("MX".parse::<QueryType>().unwrap(), QueryType::MX);
+        assert_eq!("TYPE28".parse::<QueryType>().unwrap(), QueryType::AAAA);
+        assert_eq!("type65".parse::<QueryType>().unwrap(), QueryType::HTTPS);
+        assert_eq!(
+            "type99".parse::<QueryType>().unwrap(),
+            QueryType::UNKNOWN(99)
+        );
+        match "BOGUS".parse::<QueryType>() {
+            Err(DnsError::Parse(reason)) => assert_eq!(reason, "Unknown query type: BOGUS"),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn types_sort_by_their_numbers() {
+        let mut types = [
+            QueryType::CAA,
+            QueryType::UNKNOWN(99),
+            QueryType::AAAA,
+            QueryType::MX,
+            QueryType::A,
+            QueryType::UNKNOWN(65280),
+            QueryType::NS,
+            QueryType::HTTPS,
+        ];
+        types.sort();
+
+        let nums: Vec<u16> = types.iter().map(|qtype| qtype.to_num()).collect();
+        assert_eq!(nums, [1, 2, 15, 28, 65, 99, 257, 65280]);
+        assert!(QueryType::A < QueryType::NS);
+        assert!(QueryType::UNKNOWN(300) > QueryType::CAA);
+
+        // Which keeps sets of them in order
+        let set: BTreeSet<QueryType> = [QueryType::SOA, QueryType::A, QueryType::SOA]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            set.into_iter().collect::<Vec<_>>(),
+            [QueryType::A, QueryType::SOA]
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How many responses a client subnet gets per second unless configured
+/// otherwise
+pub const DEFAULT_RESPONSES_PER_SECOND: u32 = 20;
+
+/// The most subnets to keep track of at once. Beyond that, the ones that
+/// haven't been heard from in a while are forgotten.
+pub const MAX_TRACKED: usize = 100_000;
+
+/// How often the subnets that haven't been heard from in a while are looked
+/// for, at most, once `MAX_TRACKED` are kept track of
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do with a response over UDP
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Send,
+    /// Send an empty response with the TC bit set instead, which tells a
+    /// legitimate client to retry over TCP
+    Slip,
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Account {
+    /// How many more responses may be sent right away. It goes negative
+    /// while the subnet is over its limit, and has to be paid back before
+    /// any response is sent again.
+    balance: f64,
+    updated: Instant,
+    /// How many responses were held back since the account was opened
+    limited: u32,
+}
+
+#[derive(Debug)]
+struct Accounts {
+    accounts: HashMap<IpAddr, Account>,
+    /// When a full table may be swept again
+    next_sweep: Instant,
+}
+
+/// Response rate limiting, which keeps the server from being used to flood
+/// someone with responses to queries sent in their name (RFC 5358). Since
+/// the source address of a query over UDP is easily spoofed, a server that
+/// answers anyone is an amplifier for whoever can send it queries.
+///
+/// Every client subnet may receive `responses_per_second` responses a second,
+/// and bursts of as many. Responses beyond that are dropped, except for every
+/// `slip`th of them, which is sent truncated so that a legitimate client
+/// caught up in the limit still gets its answer over TCP. A subnet that keeps
+/// sending ends up in debt for up to `window` worth of responses, which it
+/// has to wait out before it's answered again.
+///
+/// Once `MAX_TRACKED` subnets are kept track of, all of them heard from
+/// recently, as with a flood of queries from spoofed sources, new subnets
+/// aren't tracked until a later sweep makes room. Until then, their
+/// responses are sent truncated, or dropped with a `slip` of 0.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    accounts: Arc<Mutex<Accounts>>,
+    pub responses_per_second: u32,
+    pub window: Duration,
+    /// Send every `slip`th limited response truncated rather than dropping
+    /// it, or drop all of them with 0
+    pub slip: u32,
+    /// The length of the prefix clients are grouped by, as a single client
+    /// often has several addresses to its name
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+}
+
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter::new(DEFAULT_RESPONSES_PER_SECOND)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(responses_per_second: u32) -> RateLimiter {
+        RateLimiter {
+            accounts: Arc::new(Mutex::new(Accounts {
+                accounts: HashMap::new(),
+                next_sweep: Instant::now(),
+            })),
+            responses_per_second,
+            window: Duration::from_secs(15),
+            slip: 2,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+        }
+    }
+
+    /// Count a response to `client`, and decide what to do with it
+    pub fn check(&self, client: IpAddr) -> Action {
+        let now = Instant::now();
+        let rate = self.responses_per_second as f64;
+        let subnet = self.subnet(client);
+        let mut accounts = self.accounts.lock().unwrap();
+
+        if accounts.accounts.len() >= MAX_TRACKED && !accounts.accounts.contains_key(&subnet) {
+            // Sweeping a full table takes a while, so it's only done every so
+            // often rather than for every response
+            if now >= accounts.next_sweep {
+                let forget_after = self.window + Duration::from_secs(1);
+                accounts
+                    .accounts
+                    .retain(|_, account| now.duration_since(account.updated) < forget_after);
+                accounts.next_sweep = now + SWEEP_INTERVAL;
+            }
+            if accounts.accounts.len() >= MAX_TRACKED {
+                return match self.slip {
+                    0 => Action::Drop,
+                    _ => Action::Slip,
+                };
+            }
+        }
+
+        let account = accounts.accounts.entry(subnet).or_insert(Account {
+            balance: rate,
+            updated: now,
+            limited: 0,
+        });
+
+        let elapsed = now.duration_since(account.updated).as_secs_f64();
+        account.balance = (account.balance + elapsed * rate).min(rate);
+        account.balance = (account.balance - 1.0).max(-rate * self.window.as_secs_f64());
+        account.updated = now;
+
+        if account.balance >= 0.0 {
+            return Action::Send;
+        }
+
+        account.limited = account.limited.wrapping_add(1);
+        match self.slip {
+            0 => Action::Drop,
+            slip if account.limited.is_multiple_of(slip) => Action::Slip,
+            _ => Action::Drop,
+        }
+    }
+
+    /// The network `client` is accounted to
+    fn subnet(&self, client: IpAddr) -> IpAddr {
+        match client {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.ipv4_prefix_len.min(32) as u32)
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+            }
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.ipv6_prefix_len.min(128) as u32)
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use crate::{
+    blocklist::Blocklist,
+    dns_packet::DnsPacket,
+    error::{DnsError, Result},
+    hosts::address_response,
+    query_type::QueryType,
+    resolver::Source,
+    result_code::ResultCode,
+};
+
+/// How long clients may cache the rewritten answers for unless configured
+/// otherwise. It's kept short, so that the real answer isn't hidden for long
+/// once there is one.
+pub const DEFAULT_REDIRECT_TTL: u32 = 60;
+
+/// The key of `Redirect::blocked` that stands for every category
+pub const ALL_CATEGORIES: &str = "*";
+
+/// Sends clients somewhere of our choosing, such as an internal landing page
+/// or a page explaining why a name is blocked, instead of telling them that a
+/// name doesn't exist or answering the way the blocklist does. A and AAAA
+/// queries get the addresses of the family asked for, and queries of any
+/// other type an empty answer.
+///
+/// Only the `NXDOMAIN` answers that were looked up are rewritten, those of
+/// the local zones are left as they are, since they're authoritative, and so
+/// are those of the NXDOMAIN list, which is there for testing how clients
+/// deal with them. The rewritten answers never make it into the cache, see
+/// `rewrite`.
+#[derive(Clone, Debug)]
+pub struct Redirect {
+    /// Where the names that don't exist are sent, nowhere if it's empty
+    pub nxdomain: Vec<IpAddr>,
+    /// Where blocked names are sent, by the category of the list that blocks
+    /// them, see `Blocklist::category`. `ALL_CATEGORIES` is for the
+    /// categories without an entry of their own.
+    pub blocked: HashMap<String, Vec<IpAddr>>,
+    pub ttl: u32,
+}
+
+impl Default for Redirect {
+    fn default() -> Redirect {
+        Redirect::new()
+    }
+}
+
+impl Redirect {
+    pub fn new() -> Redirect {
+        Redirect {
+            nxdomain: Vec::new(),
+            blocked: HashMap::new(),
+            ttl: DEFAULT_REDIRECT_TTL,
+        }
+    }
+
+    /// Send the names blocked as part of `category` to `addr` as well
+    pub fn block_to(&mut self, category: &str, addr: IpAddr) {
+        let addrs = self.blocked.entry(category.to_string()).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    /// Where a client asking for `qname` is sent instead of getting
+    /// `response`, which came from `source`, if anywhere
+    fn target(
+        &self,
+        qname: &str,
+        source: Source,
+        response: &DnsPacket,
+        blocklist: Option<&Blocklist>,
+    ) -> Option<&[IpAddr]> {
+        let addrs = match source {
+            Source::Blocklist => {
+                let category = blocklist?.category(qname)?;
+                self.blocked
+                    .get(category)
+                    .or_else(|| self.blocked.get(ALL_CATEGORIES))?
+            }
+            Source::Upstream | Source::Recursive | Source::Cache
+                if response.header.rescode == ResultCode::NXDOMAIN =>
+            {
+                &self.nxdomain
+            }
+            _ => return None,
+        };
+
+        Some(addrs.as_slice()).filter(|addrs| !addrs.is_empty())
+    }
+
+    /// Replace the answer to a query for `qname` with our own, if it's one to
+    /// redirect. This is done to the responses on their way to the client,
+    /// after they've been cached, so that the cache keeps the real answer.
+    /// Returns whether the response was rewritten.
+    pub fn rewrite(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        source: Source,
+        blocklist: Option<&Blocklist>,
+        response: &mut DnsPacket,
+    ) -> bool {
+        match self.target(qname, source, response, blocklist) {
+            Some(addrs) => {
+                *response = address_response(qname, qtype, addrs, self.ttl);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Parse where the names of a blocklist category are sent, as given on the
+/// command line, e.g. `ads=10.0.0.80`. Without a category, it's where all
+/// blocked names are sent.
+pub fn parse_blocked(s: &str) -> Result<(String, IpAddr)> {
+    let (category, addr) = s.rsplit_once('=').unwrap_or((ALL_CATEGORIES, s));
+    let addr = addr.parse().map_err(|_| {
+        DnsError::Parse(format!("Expected [CATEGORY=]ADDRESS for a redirect: {}", s))
+    })?;
+
+    Ok((category.to_string(), addr))
+}
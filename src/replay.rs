@@ -0,0 +1,348 @@
+//! Replaying captured messages through the parser, to check it against real
+//! traffic. Messages are read out of pcap captures, e.g. from tcpdump or
+//! Wireshark, or out of files that each hold a single message the way it
+//! went over the wire. Every message is parsed with `DnsPacket::from_buffer`,
+//! and optionally written out again to see whether that gives back the very
+//! same bytes.
+
+use std::{fmt, fs, path::Path};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_packet::DnsPacket,
+    error::{DnsError, Result},
+};
+
+/// The port that DNS messages are picked out of captures by, unless told
+/// otherwise
+pub const DEFAULT_PORT: u16 = 53;
+
+/// The link types of the captures we can read, see
+/// https://www.tcpdump.org/linktypes.html
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+/// A message out of a capture or a dump
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapturedMessage {
+    /// Where the message came from, e.g. `dns.pcap, frame 12`
+    pub origin: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Why a message didn't make it through the parser
+#[derive(Debug)]
+pub enum Failure {
+    /// The message doesn't parse
+    Parse(DnsError),
+    /// The message parses, but can't be written out again
+    Write(DnsError),
+    /// The message was written out differently from how it came in. The
+    /// bytes first differ at `offset`.
+    Mismatch {
+        offset: usize,
+        original: usize,
+        written: usize,
+    },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Parse(e) => write!(f, "doesn't parse: {}", e),
+            Failure::Write(e) => write!(f, "doesn't write: {}", e),
+            Failure::Mismatch {
+                offset,
+                original,
+                written,
+            } => write!(
+                f,
+                "written differently from offset {}, {} bytes rather than {}",
+                offset, written, original
+            ),
+        }
+    }
+}
+
+/// What came of replaying a batch of messages
+#[derive(Debug, Default)]
+pub struct Report {
+    /// How many messages were replayed
+    pub messages: usize,
+    /// The messages that failed, by their origin
+    pub failures: Vec<(String, Failure)>,
+}
+
+impl Report {
+    /// How many messages were replayed without failing
+    pub fn passed(&self) -> usize {
+        self.messages - self.failures.len()
+    }
+}
+
+/// Parse `bytes` as a message, and if `roundtrip` is set, check that writing
+/// it out again gives back the same bytes
+pub fn check(bytes: &[u8], roundtrip: bool) -> std::result::Result<DnsPacket, Failure> {
+    let mut buffer = BytePacketBuffer::from_slice(bytes);
+    let mut packet = DnsPacket::from_buffer(&mut buffer).map_err(Failure::Parse)?;
+    if !roundtrip {
+        return Ok(packet);
+    }
+
+    let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+    packet.write(&mut buffer).map_err(Failure::Write)?;
+    let written = &buffer.buf[..buffer.pos()];
+    if written != bytes {
+        let offset = written
+            .iter()
+            .zip(bytes)
+            .position(|(a, b)| a != b)
+            .unwrap_or_else(|| written.len().min(bytes.len()));
+        return Err(Failure::Mismatch {
+            offset,
+            original: bytes.len(),
+            written: written.len(),
+        });
+    }
+
+    Ok(packet)
+}
+
+/// Run every message through `check`
+pub fn replay(messages: &[CapturedMessage], roundtrip: bool) -> Report {
+    let mut report = Report {
+        messages: messages.len(),
+        ..Report::default()
+    };
+    for message in messages {
+        if let Err(failure) = check(&message.bytes, roundtrip) {
+            report.failures.push((message.origin.clone(), failure));
+        }
+    }
+
+    report
+}
+
+/// Whether `data` starts the way pcap captures do
+pub fn is_pcap(data: &[u8]) -> bool {
+    matches!(
+        data.get(..4),
+        Some([0xa1, 0xb2, 0xc3, 0xd4] | [0xd4, 0xc3, 0xb2, 0xa1])
+            | Some([0xa1, 0xb2, 0x3c, 0x4d] | [0x4d, 0x3c, 0xb2, 0xa1])
+    )
+}
+
+/// The messages in `path`. A pcap capture gives the messages sent to or from
+/// `port` in it, and any other file a single message. A directory gives the
+/// messages of every file in it, in the order of their names.
+pub fn load<P: AsRef<Path>>(path: P, port: u16) -> Result<Vec<CapturedMessage>> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return load_file(path, port);
+    }
+
+    let mut paths = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    let mut messages = Vec::new();
+    for path in paths.iter().filter(|path| path.is_file()) {
+        messages.extend(load_file(path, port)?);
+    }
+
+    Ok(messages)
+}
+
+fn load_file(path: &Path, port: u16) -> Result<Vec<CapturedMessage>> {
+    let data = fs::read(path)?;
+    if !is_pcap(&data) {
+        return Ok(vec![CapturedMessage {
+            origin: path.display().to_string(),
+            bytes: data,
+        }]);
+    }
+
+    let mut messages = read_pcap(&data, port)?;
+    for message in &mut messages {
+        message.origin = format!("{}, {}", path.display(), message.origin);
+    }
+
+    Ok(messages)
+}
+
+/// The DNS messages sent to or from `port` in a pcap capture, over UDP or
+/// TCP, and over IPv4 or IPv6. Messages over TCP are taken from segments
+/// that hold them whole, so those split over several segments are left out,
+/// as are fragmented datagrams and frames that were cut short by the snap
+/// length of the capture. Captures in the newer pcapng format aren't
+/// supported.
+pub fn read_pcap(data: &[u8], port: u16) -> Result<Vec<CapturedMessage>> {
+    if data.starts_with(&[0x0a, 0x0d, 0x0d, 0x0a]) {
+        return Err(DnsError::Unsupported(
+            "pcapng captures aren't supported, convert them with `editcap -F pcap`".to_string(),
+        ));
+    }
+    if !is_pcap(data) || data.len() < 24 {
+        return Err(DnsError::Parse("Not a pcap capture".to_string()));
+    }
+
+    let little_endian = data[0] == 0xd4 || data[0] == 0x4d;
+    let read_u32 = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        }
+    };
+    let linktype = read_u32(&data[20..24]) & 0xffff;
+
+    let mut messages = Vec::new();
+    let mut pos = 24;
+    let mut frame = 0;
+    while pos < data.len() {
+        frame += 1;
+        let header = data
+            .get(pos..pos + 16)
+            .ok_or_else(|| DnsError::Parse(format!("Frame {} is cut short", frame)))?;
+        let captured = read_u32(&header[8..12]) as usize;
+        let original = read_u32(&header[12..16]) as usize;
+        let bytes = data
+            .get(pos + 16..pos + 16 + captured)
+            .ok_or_else(|| DnsError::Parse(format!("Frame {} is cut short", frame)))?;
+        pos += 16 + captured;
+
+        if captured < original {
+            continue;
+        }
+        let payloads = link_payload(bytes, linktype, little_endian)
+            .and_then(|(ethertype, packet)| ip_payload(ethertype, packet))
+            .map(|(protocol, segment)| transport_payloads(protocol, segment, port))
+            .unwrap_or_default();
+        for bytes in payloads {
+            messages.push(CapturedMessage {
+                origin: format!("frame {}", frame),
+                bytes: bytes.to_vec(),
+            });
+        }
+    }
+
+    Ok(messages)
+}
+
+/// The ethertype of the packet in a frame, along with the packet
+fn link_payload(frame: &[u8], linktype: u32, little_endian: bool) -> Option<(u16, &[u8])> {
+    let ethertype = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    let by_version = |packet: &[u8]| match packet.first()? >> 4 {
+        4 => Some(ETHERTYPE_IPV4),
+        6 => Some(ETHERTYPE_IPV6),
+        _ => None,
+    };
+
+    match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut frame = frame.get(12..)?;
+            while frame.len() >= 2 && ethertype(frame) == ETHERTYPE_VLAN {
+                frame = frame.get(4..)?;
+            }
+            Some((ethertype(frame.get(..2)?), frame.get(2..)?))
+        }
+        LINKTYPE_NULL => {
+            // The address family, in the byte order of the machine that
+            // captured it
+            let family = frame.get(..4)?;
+            let family = match little_endian {
+                true => u32::from_le_bytes([family[0], family[1], family[2], family[3]]),
+                false => u32::from_be_bytes([family[0], family[1], family[2], family[3]]),
+            };
+            match family {
+                2 => Some((ETHERTYPE_IPV4, &frame[4..])),
+                24 | 28 | 30 => Some((ETHERTYPE_IPV6, &frame[4..])),
+                _ => None,
+            }
+        }
+        LINKTYPE_LINUX_SLL => Some((ethertype(frame.get(14..16)?), frame.get(16..)?)),
+        LINKTYPE_LINUX_SLL2 => Some((ethertype(frame.get(..2)?), frame.get(20..)?)),
+        LINKTYPE_RAW => Some((by_version(frame)?, frame)),
+        LINKTYPE_IPV4 => Some((ETHERTYPE_IPV4, frame)),
+        LINKTYPE_IPV6 => Some((ETHERTYPE_IPV6, frame)),
+        _ => None,
+    }
+}
+
+/// The protocol of the segment in an IP packet, along with the segment
+fn ip_payload(ethertype: u16, packet: &[u8]) -> Option<(u8, &[u8])> {
+    match ethertype {
+        ETHERTYPE_IPV4 => {
+            let header_len = (*packet.first()? as usize & 0x0f) * 4;
+            let total_len = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize;
+            // Later fragments, or the first of several
+            let fragment = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]);
+            if fragment & 0x3fff != 0 {
+                return None;
+            }
+            Some((*packet.get(9)?, packet.get(header_len..total_len)?))
+        }
+        ETHERTYPE_IPV6 => {
+            let payload_len = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) as usize;
+            let mut next_header = *packet.get(6)?;
+            let mut payload = packet.get(40..40 + payload_len)?;
+            // Hop-by-hop options, routing and destination options headers
+            // come before the segment. Fragments are left out.
+            while matches!(next_header, 0 | 43 | 60) {
+                let len = (*payload.get(1)? as usize + 1) * 8;
+                next_header = *payload.first()?;
+                payload = payload.get(len..)?;
+            }
+            Some((next_header, payload))
+        }
+        _ => None,
+    }
+}
+
+/// The DNS messages in a segment, if it was sent to or from `port`
+fn transport_payloads(protocol: u8, segment: &[u8], port: u16) -> Vec<&[u8]> {
+    let ports = |segment: &[u8]| {
+        let src = u16::from_be_bytes([segment[0], segment[1]]);
+        let dst = u16::from_be_bytes([segment[2], segment[3]]);
+        src == port || dst == port
+    };
+
+    match protocol {
+        PROTOCOL_UDP if segment.len() >= 8 && ports(segment) => {
+            let len = u16::from_be_bytes([segment[4], segment[5]]) as usize;
+            segment
+                .get(8..len)
+                .map(|payload| vec![payload])
+                .unwrap_or_default()
+        }
+        PROTOCOL_TCP if segment.len() >= 20 && ports(segment) => {
+            let header_len = (segment[12] >> 4) as usize * 4;
+            let mut payload = segment.get(header_len..).unwrap_or_default();
+            // Every message is preceded by its length
+            let mut messages = Vec::new();
+            while payload.len() >= 2 {
+                let len = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+                match payload.get(2..2 + len) {
+                    Some(message) => messages.push(message),
+                    None => break,
+                }
+                payload = &payload[2 + len..];
+            }
+            messages
+        }
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,912 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    thread,
+    time::Instant,
+};
+
+use log::{debug, warn};
+
+use crate::{
+    blocklist::Blocklist,
+    cache::Cache,
+    client::{self, prefer_family, AddressFamily, QueryOptions},
+    dns64::{self, Dns64},
+    dns_packet::DnsPacket,
+    dns_question::DnsQuestion,
+    dns_record::DnsRecord,
+    ecs::{ClientSubnet, ClientSubnetPolicy},
+    error::{DnsError, Result},
+    forwarder::{DomainRoutes, Forwarder},
+    health::HealthChecks,
+    hosts::Hosts,
+    mdns::{self, MdnsResolver},
+    metrics::Metrics,
+    minimize::Minimizer,
+    name,
+    nxdomain::NxdomainList,
+    pipeline::{Next, Pipeline, Query, Stage},
+    query_log::QueryLog,
+    query_type::QueryType,
+    rate_limit::RateLimiter,
+    redirect::Redirect,
+    result_code::ResultCode,
+    rotation::AnswerRotation,
+    secondary::Secondary,
+    shuffle::AnswerShuffler,
+    shutdown::Shutdown,
+    tsig::TsigKey,
+    ttl::TtlPolicy,
+    update::DynamicZone,
+    view::View,
+    zone::Zone,
+};
+
+#[cfg(feature = "dnssec")]
+use crate::dnssec::{Validation, Validator};
+
+/// Knobs controlling how queries are resolved
+#[derive(Clone, Debug, Default)]
+pub struct ResolverOptions {
+    /// Forward queries to these upstreams rather than resolving them
+    /// recursively starting from the root servers
+    pub forwarder: Option<Forwarder>,
+    /// Forward the names in these domains to upstreams of their own, rather
+    /// than to `forwarder` or recursively
+    pub routes: DomainRoutes,
+    /// Synthesize AAAA records from A records for IPv6-only clients
+    pub dns64: Option<Dns64>,
+    /// How queries are sent to upstreams and authoritative servers
+    pub query: QueryOptions,
+    /// Echo the question back to clients exactly as they sent it, class and
+    /// all, rather than re-serializing the parsed version
+    pub preserve_question: bool,
+    /// Shuffle the records of each RRset in the answers we send to clients
+    pub shuffler: Option<AnswerShuffler>,
+    /// Rotate the addresses answered from local zones, see `AnswerRotation`
+    pub rotation: Option<AnswerRotation>,
+    /// Leave the addresses of backends that are down out of the answers from
+    /// the hosts files and local zones, see `HealthChecks`
+    pub health: Option<HealthChecks>,
+    /// The handlers every query goes through, the built-in stages along with
+    /// any custom ones, see `Pipeline`
+    pub pipeline: Pipeline,
+    /// Names that are always answered with `NXDOMAIN`
+    pub nxdomain: Option<NxdomainList>,
+    /// Names that are blocked, such as those of ad servers
+    pub blocklist: Option<Blocklist>,
+    /// Answer with addresses of our own instead of `NXDOMAIN`, or for the
+    /// names that are blocked
+    pub redirect: Option<Redirect>,
+    /// Fixed addresses for names, which take precedence over everything
+    /// but the blocklist and the NXDOMAIN list
+    pub hosts: Option<Hosts>,
+    /// Resolve `.local` names over multicast DNS on the local link
+    pub mdns: Option<MdnsResolver>,
+    /// Zones served locally and authoritatively, overriding whatever the rest
+    /// of the world has
+    pub zones: Vec<Zone>,
+    /// Zones kept in sync with their primaries, and answered from the same
+    /// way as the local ones
+    pub secondaries: Vec<Secondary>,
+    /// Zones that clients may make changes to with UPDATE messages, also
+    /// answered from the same way as the local ones
+    pub dynamic_zones: Vec<DynamicZone>,
+    /// The keys clients may sign their queries with, which get signed
+    /// responses in return, see `tsig::verify`
+    pub tsig_keys: Vec<TsigKey>,
+    /// Leave out the additional section of responses, such as glue records,
+    /// which the clients don't need most of the time
+    pub minimal_responses: bool,
+    /// Answer repeated queries from earlier responses while they're valid
+    pub cache: Option<Cache>,
+    /// Resolving recursively, only ask the name servers along the way about
+    /// as much of the name as they need to know, see `minimize`
+    pub qname_minimization: bool,
+    /// Bounds on the TTLs of what the upstreams and name servers answer
+    pub ttl_policy: Option<TtlPolicy>,
+    /// Tell the upstreams which network the client is in, so that they can
+    /// answer with what's closest to it, see `ecs`
+    pub client_subnet: Option<ClientSubnetPolicy>,
+    /// Record every query the server answers
+    pub query_log: Option<QueryLog>,
+    /// Count queries, responses, cache hits and the like for monitoring
+    pub metrics: Option<Metrics>,
+    /// Limit the responses sent over UDP to each client subnet
+    pub rate_limit: Option<RateLimiter>,
+    /// Validate the DNSSEC signatures of what's looked up, which requires the
+    /// DO bit in `query`
+    #[cfg(feature = "dnssec")]
+    pub validator: Option<Validator>,
+    /// Clients that are resolved differently from the rest, see `for_client`
+    pub views: Vec<View>,
+    /// Stops the servers answering with these options, and keeps track of
+    /// the queries they have in flight
+    pub shutdown: Shutdown,
+}
+
+impl ResolverOptions {
+    /// The options to resolve the queries of a client with: those of the
+    /// first view it belongs to, or these if it doesn't belong to any
+    pub fn for_client(self: &Arc<Self>, addr: IpAddr) -> &Arc<ResolverOptions> {
+        self.views
+            .iter()
+            .find(|view| view.matches(addr))
+            .map_or(self, |view| &view.options)
+    }
+}
+
+/// Where the answer to a query came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// The fixed addresses of the hosts files
+    Hosts,
+    /// One of the local zones
+    Zone,
+    /// The hosts on the local link, over multicast DNS
+    Mdns,
+    Cache,
+    /// The configured upstreams
+    Upstream,
+    /// The authoritative servers, starting from the root
+    Recursive,
+    /// Answered locally since the name is blocked
+    Blocklist,
+    /// Answered locally since the name is on the NXDOMAIN list
+    Nxdomain,
+    /// Answered by a custom handler of the pipeline, see `QueryHandler`
+    Handler,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Hosts => "hosts",
+            Source::Zone => "zone",
+            Source::Mdns => "mdns",
+            Source::Cache => "cache",
+            Source::Upstream => "upstream",
+            Source::Recursive => "recursive",
+            Source::Blocklist => "blocklist",
+            Source::Nxdomain => "nxdomain",
+            Source::Handler => "handler",
+        }
+    }
+}
+
+/// The IPv4 addresses of the root name servers, `a` through `m.root-servers.net`
+pub const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// The most lookups of name server addresses nested in one another, for
+/// name servers without glue whose own name servers have none either. Going
+/// deeper than that, they're most likely in each other's zones.
+const MAX_NS_DEPTH: usize = 4;
+
+/// The most referrals followed for a single lookup. Names rarely take more
+/// than a handful, servers that refer back up or to one another would keep
+/// the lookup going forever.
+const MAX_REFERRALS: usize = 20;
+
+/// The IPv6 addresses of the root name servers, in the same order
+pub const ROOT_SERVERS_V6: [Ipv6Addr; 13] = [
+    Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30),
+    Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb),
+    Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc),
+    Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd),
+    Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe),
+    Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf),
+    Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d),
+    Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53),
+    Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53),
+    Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30),
+    Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1),
+    Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42),
+    Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35),
+];
+
+/// The most aliases followed for a single lookup. Real chains rarely have
+/// more than a couple of links.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The address family name servers are reached over first
+fn ns_family(options: &ResolverOptions) -> AddressFamily {
+    options.query.prefer_family.unwrap_or(AddressFamily::Ipv4)
+}
+
+/// Ask the root servers, moving on to the next one whenever one of them
+/// doesn't answer. Once all the addresses of the preferred family have
+/// failed, the ones of the other family are tried.
+fn query_root(qname: &str, qtype: QueryType, options: &ResolverOptions) -> Result<DnsPacket> {
+    let mut last_error = None;
+
+    let mut roots = ROOT_SERVERS
+        .iter()
+        .map(|&root| IpAddr::V4(root))
+        .chain(ROOT_SERVERS_V6.iter().map(|&root| IpAddr::V6(root)))
+        .collect::<Vec<_>>();
+    prefer_family(&mut roots, ns_family(options), |root| *root);
+
+    for root in roots {
+        debug!(
+            "attempting lookup of {:?} {} with root {}",
+            qtype, qname, root
+        );
+
+        let server = SocketAddr::from((root, 53));
+        match client::query(qname, qtype, server, &options.query) {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                warn!("Root server {} failed: {}, trying next", root, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or(DnsError::NoServers))
+}
+
+/// Resolve a name by starting out at the root servers, and following the
+/// referrals they hand out down to the servers that are authoritative for it.
+/// With `qname_minimization`, the servers along the way are only asked about
+/// as much of the name as they need to know, see `Minimizer`.
+///
+/// Name servers without glue are looked up the same way, down to
+/// `MAX_NS_DEPTH` lookups nested in one another. Past that, or past
+/// `MAX_REFERRALS` referrals, the lookup fails.
+pub fn recursive_lookup(
+    qname: &str,
+    qtype: QueryType,
+    options: &ResolverOptions,
+) -> Result<DnsPacket> {
+    lookup_from_root(qname, qtype, options, 0)
+}
+
+/// `recursive_lookup`, from within `depth` lookups of name server addresses
+fn lookup_from_root(
+    qname: &str,
+    qtype: QueryType,
+    options: &ResolverOptions,
+    depth: usize,
+) -> Result<DnsPacket> {
+    follow_referrals(
+        qname,
+        qtype,
+        options,
+        depth,
+        |server, name, query_type| match server {
+            None => query_root(name, query_type, options),
+            Some(ns) => {
+                debug!(
+                    "attempting lookup of {:?} {} with ns {}",
+                    query_type, name, ns
+                );
+                let server = SocketAddr::from((ns, 53));
+                client::query(name, query_type, server, &options.query)
+            }
+        },
+    )
+}
+
+/// The steps of `lookup_from_root`, with `ask` sending the questions to a
+/// name server, or to the root servers for `None`
+fn follow_referrals<F>(
+    qname: &str,
+    qtype: QueryType,
+    options: &ResolverOptions,
+    depth: usize,
+    mut ask: F,
+) -> Result<DnsPacket>
+where
+    F: FnMut(Option<IpAddr>, &str, QueryType) -> Result<DnsPacket>,
+{
+    let mut minimizer = Minimizer::new(qname, qtype, options.qname_minimization);
+    // The server being asked, starting out with the root servers
+    let mut server = None;
+    let mut referrals = 0;
+
+    // It takes as many steps as there are referrals, up to `MAX_REFERRALS`
+    loop {
+        let (name, query_type) = minimizer.question();
+        let result = ask(server, name, query_type);
+        // Asked about part of the name, the server either refers us further
+        // down, or the name is in its own zone and it's asked about more of
+        // it. Anything else is a server that can't deal with the names in
+        // between, which gets the full name instead.
+        if minimizer.is_minimized() {
+            let response = match result {
+                Ok(response) if response.header.rescode == ResultCode::NOERROR => response,
+                Ok(response) => {
+                    debug!(
+                        "{:?} {} failed with {:?}, asking for {} instead",
+                        query_type, name, response.header.rescode, qname
+                    );
+                    minimizer.give_up();
+                    continue;
+                }
+                Err(e) => {
+                    debug!(
+                        "{:?} {} failed: {}, asking for {} instead",
+                        query_type, name, e, qname
+                    );
+                    minimizer.give_up();
+                    continue;
+                }
+            };
+
+            if minimizer.referral(&response) {
+                match next_ns(&response, qname, qtype, options, depth)? {
+                    Some(ns) => {
+                        server = Some(ns);
+                        count_referral(&mut referrals, qname, qtype)?;
+                    }
+                    None => minimizer.give_up(),
+                }
+            }
+            continue;
+        }
+        let response = result?;
+
+        // If there are entries in the answer section, and no errors, we're done!
+        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
+            return Ok(response);
+        }
+
+        // We might also get a `NXDOMAIN` reply, which is the authoriative name servers
+        // way of telling us that the name doesn't exists.
+        if response.header.rescode == ResultCode::NXDOMAIN {
+            return Ok(response);
+        }
+
+        // Otherwise, we'll try to find a new nameserver based on NS and a corresponding A
+        // record in the additional section. If this succeeds, we can switch name server
+        // and retry the loop. If not, we'll go with what the last server told us.
+        match next_ns(&response, qname, qtype, options, depth)? {
+            Some(ns) => server = Some(ns),
+            None => return Ok(response),
+        }
+        count_referral(&mut referrals, qname, qtype)?;
+    }
+}
+
+/// Count one more referral followed, failing the lookup past `MAX_REFERRALS`
+fn count_referral(referrals: &mut usize, qname: &str, qtype: QueryType) -> Result<()> {
+    *referrals += 1;
+    if *referrals > MAX_REFERRALS {
+        warn!(
+            "Giving up on {} {:?} after {} referrals",
+            qname, qtype, MAX_REFERRALS
+        );
+        return Err(DnsError::NoServers);
+    }
+
+    Ok(())
+}
+
+/// The address of a name server a response refers to, if there's one to be
+/// found
+fn next_ns(
+    response: &DnsPacket,
+    qname: &str,
+    qtype: QueryType,
+    options: &ResolverOptions,
+    depth: usize,
+) -> Result<Option<IpAddr>> {
+    if let Some(ns) = response.get_resolved_ns(qname, ns_family(options)) {
+        return Ok(Some(ns));
+    }
+
+    // If not, we'll have to resolve the ip of NS record. If no NS record exist,
+    // there's no one left to ask.
+    let new_ns_name = match response.get_unresolved_ns(qname) {
+        Some(x) if depth < MAX_NS_DEPTH => x,
+        Some(x) => {
+            warn!(
+                "Giving up on {} {:?}, the address of {} is {} lookups deep",
+                qname, qtype, x, depth
+            );
+            return Err(DnsError::NoServers);
+        }
+        None => return Ok(None),
+    };
+
+    // Here we go down the rabbit hole by starting _another_ lookup sequence in the
+    // midst of our current one. Hopefully, this will give us the IP of an approprate
+    // name server.
+    resolve_ns(new_ns_name, options, depth + 1)
+}
+
+/// Look up an address of a name server, trying the other address family if
+/// it has none of the preferred one
+fn resolve_ns(ns: &str, options: &ResolverOptions, depth: usize) -> Result<Option<IpAddr>> {
+    let qtypes = match ns_family(options) {
+        AddressFamily::Ipv4 => [QueryType::A, QueryType::AAAA],
+        AddressFamily::Ipv6 => [QueryType::AAAA, QueryType::A],
+    };
+
+    for qtype in qtypes {
+        let response = lookup_from_root(ns, qtype, options, depth)?;
+        let addr = match qtype {
+            QueryType::A => response.get_random_a().map(IpAddr::V4),
+            _ => response.get_random_aaaa().map(IpAddr::V6),
+        };
+        if addr.is_some() {
+            return Ok(addr);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Resolve a batch of questions, issuing one packet per question. The header
+/// allows for several questions in one packet, but in practice most servers
+/// only ever answer the first one.
+pub fn resolve_all(
+    queries: Vec<DnsQuestion>,
+    options: &Arc<ResolverOptions>,
+) -> Result<Vec<DnsPacket>> {
+    queries
+        .iter()
+        .map(|question| lookup(&question.name, question.qtype, options))
+        .collect()
+}
+
+/// Look up a name either through the configured upstreams, or recursively if
+/// there aren't any. The hosts files and the local zones take precedence over
+/// both, followed by the cache. Should the lookup fail, the cache may still
+/// have an answer that expired, see `Cache::serve_stale`.
+///
+/// An answer that's nothing but an alias is followed to the records it
+/// stands for, see `chase_cnames`. This is the part of the pipeline from
+/// `Stage::Cnames` on, see `Pipeline`.
+pub fn lookup(qname: &str, qtype: QueryType, options: &Arc<ResolverOptions>) -> Result<DnsPacket> {
+    let query = Query {
+        name: qname,
+        qtype,
+        client: None,
+        options,
+    };
+
+    Ok(options.pipeline.starting_at(Stage::Cnames).run(&query)?.0)
+}
+
+/// Run one of the built-in stages of the pipeline, which hands the query on
+/// to `next` unless it has an answer of its own
+pub(crate) fn run_stage(stage: Stage, query: &Query, next: Next) -> Result<(DnsPacket, Source)> {
+    let (qname, qtype, options) = (query.name, query.qtype, query.options);
+
+    let answer = match stage {
+        Stage::Redirect => {
+            let (mut response, source) = next.run(query)?;
+            if let Some(redirect) = &options.redirect {
+                redirect.rewrite(
+                    qname,
+                    qtype,
+                    source,
+                    options.blocklist.as_ref(),
+                    &mut response,
+                );
+            }
+            return Ok((response, source));
+        }
+        Stage::Blocklist => options
+            .blocklist
+            .as_ref()
+            .and_then(|blocklist| blocklist.answer(qname, qtype))
+            .map(|response| (response, Source::Blocklist)),
+        Stage::Nxdomain => options
+            .nxdomain
+            .as_ref()
+            .and_then(|nxdomain| nxdomain.answer(qname, qtype))
+            .map(|response| (response, Source::Nxdomain)),
+        Stage::Dns64 => return synthesize_aaaa(query, next),
+        Stage::Validate => return validate(query, next),
+        Stage::Cnames => {
+            let (response, source) = next.run(query)?;
+            return Ok((chase_cnames(query, response, next)?, source));
+        }
+        Stage::Hosts => options
+            .hosts
+            .as_ref()
+            .and_then(|hosts| hosts.answer(qname, qtype))
+            .map(|mut response| {
+                if let Some(health) = &options.health {
+                    health.filter(&mut response.answers);
+                }
+                (response, Source::Hosts)
+            }),
+        Stage::Zones => zone_answer(qname, qtype, options).map(|mut response| {
+            if let Some(health) = &options.health {
+                health.filter(&mut response.answers);
+            }
+            if let Some(rotation) = &options.rotation {
+                rotation.rotate(&mut response.answers);
+            }
+            (response, Source::Zone)
+        }),
+        Stage::Mdns => match options.mdns.as_ref().filter(|_| mdns::is_local(qname)) {
+            Some(resolver) => Some((resolver.lookup(qname, qtype)?, Source::Mdns)),
+            None => None,
+        },
+        Stage::Cache => return cached_lookup(query, next),
+        Stage::Upstream => {
+            let subnet = client_subnet(query);
+            let (result, source) = query_upstream(qname, qtype, subnet, options);
+            return Ok((result?, source));
+        }
+    };
+
+    match answer {
+        Some(answer) => Ok(answer),
+        None => next.run(query),
+    }
+}
+
+/// The answer of the zone `qname` is in. With nested zones, the answer comes
+/// from the most specific one.
+fn zone_answer(qname: &str, qtype: QueryType, options: &ResolverOptions) -> Option<DnsPacket> {
+    let zone = options
+        .zones
+        .iter()
+        .filter(|zone| zone.contains(qname))
+        .max_by_key(|zone| zone.origin.len());
+    let secondary = options
+        .secondaries
+        .iter()
+        .filter(|secondary| secondary.contains(qname))
+        .max_by_key(|secondary| secondary.origin.len())
+        .filter(|secondary| zone.is_none_or(|zone| secondary.origin.len() > zone.origin.len()));
+    let dynamic = options
+        .dynamic_zones
+        .iter()
+        .filter(|dynamic| dynamic.contains(qname))
+        .max_by_key(|dynamic| dynamic.origin.len())
+        .filter(|dynamic| {
+            zone.is_none_or(|zone| dynamic.origin.len() > zone.origin.len())
+                && secondary.is_none_or(|secondary| dynamic.origin.len() > secondary.origin.len())
+        });
+
+    dynamic
+        .and_then(|dynamic| dynamic.answer(qname, qtype))
+        .or_else(|| secondary.and_then(|secondary| secondary.answer(qname, qtype)))
+        .or_else(|| zone.and_then(|zone| zone.answer(qname, qtype)))
+}
+
+/// Answer from the cache, or have the rest of the pipeline answer and keep
+/// the response
+fn cached_lookup(query: &Query, next: Next) -> Result<(DnsPacket, Source)> {
+    let (qname, qtype, options) = (query.name, query.qtype, query.options);
+    let Some(cache) = &options.cache else {
+        return next.run(query);
+    };
+
+    let subnet = client_subnet(query);
+    if cache.is_cacheable(qname, qtype) {
+        let response = cache.get_scoped(qname, qtype, subnet.as_ref());
+        if let Some(metrics) = &options.metrics {
+            metrics.record_cache_lookup(response.is_some());
+        }
+        if let Some(response) = response {
+            if cache.claim_prefetch(qname, qtype) {
+                prefetch(query);
+            }
+            return Ok((response, Source::Cache));
+        }
+    }
+
+    // When there's no getting a fresh answer, one that expired not too long
+    // ago is better than none (RFC 8767)
+    let stale = || cache.get_stale(qname, qtype);
+    let (response, source) = match next.run(query) {
+        Ok((response, source)) if response.header.rescode == ResultCode::SERVFAIL => {
+            match stale() {
+                Some(stale) => return Ok((stale, Source::Cache)),
+                None => (response, source),
+            }
+        }
+        Ok(answer) => answer,
+        Err(e) => match stale() {
+            Some(stale) => {
+                warn!("Answering {} {:?} from stale cache: {}", qname, qtype, e);
+                return Ok((stale, Source::Cache));
+            }
+            None => return Err(e),
+        },
+    };
+
+    cache.insert_scoped(qname, qtype, &response, subnet.as_ref());
+
+    Ok((response, source))
+}
+
+/// The network of the client to tell the upstreams about, if any, see
+/// `ResolverOptions::client_subnet`
+fn client_subnet(query: &Query) -> Option<ClientSubnet> {
+    let policy = query.options.client_subnet.as_ref()?;
+    policy.subnet_for(query.client?.ip())
+}
+
+/// Ask the upstreams for an answer, those of the domain the name is in if it
+/// has any of its own, or the authoritative servers if there aren't any
+/// upstreams. The upstreams are told about the network of the client, if
+/// there's a `subnet` to tell them. The TTLs of the answer are brought within
+/// the bounds of `ttl_policy`.
+fn query_upstream(
+    qname: &str,
+    qtype: QueryType,
+    subnet: Option<ClientSubnet>,
+    options: &ResolverOptions,
+) -> (Result<DnsPacket>, Source) {
+    let start = Instant::now();
+    let forwarder = options.routes.route(qname).or(options.forwarder.as_ref());
+    let query = QueryOptions {
+        client_subnet: subnet,
+        ..options.query
+    };
+    let (result, source) = match forwarder {
+        Some(forwarder) => (forwarder.forward(qname, qtype, &query), Source::Upstream),
+        None => (recursive_lookup(qname, qtype, options), Source::Recursive),
+    };
+    if let Some(metrics) = &options.metrics {
+        metrics.record_upstream(start.elapsed(), result.is_err());
+    }
+    let result = match (result, &options.ttl_policy) {
+        (Ok(mut response), Some(policy)) => {
+            policy.apply(&mut response);
+            Ok(response)
+        }
+        (result, _) => result,
+    };
+
+    (result, source)
+}
+
+/// Refresh the cache entry for a question in the background, once
+/// `Cache::claim_prefetch` says it's due. It's looked up by the same part of
+/// the pipeline as it was the first time, with the options shared rather
+/// than copied along with all of their zones and lists.
+fn prefetch(query: &Query) {
+    let Some(cache) = query.options.cache.clone() else {
+        return;
+    };
+    let options = query.options.clone();
+    let (qname, qtype, client) = (query.name.to_string(), query.qtype, query.client);
+
+    thread::spawn(move || {
+        debug!("Prefetching {} {:?}", qname, qtype);
+        let query = Query {
+            name: &qname,
+            qtype,
+            client,
+            options: &options,
+        };
+        match options.pipeline.after(Stage::Cache).run(&query) {
+            Ok((response, _)) => {
+                cache.insert_scoped(&qname, qtype, &response, client_subnet(&query).as_ref())
+            }
+            Err(e) => warn!("Failed to prefetch {} {:?}: {}", qname, qtype, e),
+        }
+        // Only needed if the response wasn't cached, e.g. as it was an error
+        cache.release_prefetch(&qname, qtype);
+    });
+}
+
+/// Follow the CNAMEs in `response` to the records of the type that was asked
+/// for, when the server that answered didn't. That happens whenever the
+/// target of an alias lies outside of the zones the server is authoritative
+/// for, and with aliases from the local zones.
+///
+/// The records of each step are appended to the answers, so that the client
+/// gets the whole chain, and the response code and authority section are
+/// those of the last step, as it's the target that exists or doesn't
+/// (RFC 6604). Loops, and chains longer than `MAX_CNAME_CHAIN`, are cut
+/// short.
+fn chase_cnames(query: &Query, mut response: DnsPacket, next: Next) -> Result<DnsPacket> {
+    let (qname, qtype) = (query.name, query.qtype);
+
+    // Those asking for the alias itself get just that
+    if matches!(qtype, QueryType::CNAME | QueryType::ANY) {
+        return Ok(response);
+    }
+
+    for _ in 0..MAX_CNAME_CHAIN {
+        if response.header.rescode != ResultCode::NOERROR {
+            return Ok(response);
+        }
+
+        let target = match cname_target(&response.answers, qname) {
+            Some(target) => target,
+            None => return Ok(response),
+        };
+        let answered = response
+            .answers
+            .iter()
+            .any(|rec| rec.query_type() == qtype && name::eq(rec.domain(), &target));
+        if answered {
+            return Ok(response);
+        }
+
+        debug!("Following CNAME of {} to {}", qname, target);
+        let target_query = Query {
+            name: &target,
+            ..*query
+        };
+        let (step, _) = next.run(&target_query)?;
+        for rec in step.answers {
+            if !response.answers.contains(&rec) {
+                response.answers.push(rec);
+            }
+        }
+        response.header.rescode = step.header.rescode;
+        response.authorities = step.authorities;
+    }
+
+    warn!("CNAME chain of {} is too long, giving up", qname);
+    Ok(response)
+}
+
+/// The name at the end of the chain of CNAMEs starting at `qname`, if there
+/// is one and it doesn't go around in a loop
+fn cname_target(answers: &[DnsRecord], qname: &str) -> Option<String> {
+    let mut target = qname;
+
+    // A chain that doesn't end by the time every record had its turn can
+    // only be a loop
+    for _ in 0..=answers.len() {
+        match answers.iter().find_map(|rec| match rec {
+            DnsRecord::CNAME { domain, host, .. } if name::eq(domain, target) => {
+                Some(host.as_str())
+            }
+            _ => None,
+        }) {
+            Some(host) => target = host,
+            None if target == qname => return None,
+            None => return Some(target.to_string()),
+        }
+    }
+
+    None
+}
+
+/// Resolve a question on behalf of a client. Blocked names are answered before
+/// anything is looked up. With DNSSEC validation enabled, the AD bit of the
+/// response tells whether it validated as secure, and responses that fail
+/// validation are an error. With DNS64 enabled, an AAAA query for a name that
+/// only has A records is answered with AAAA records synthesized from those
+/// instead.
+pub fn resolve(qname: &str, qtype: QueryType, options: &Arc<ResolverOptions>) -> Result<DnsPacket> {
+    Ok(resolve_with_source(qname, qtype, options)?.0)
+}
+
+/// Like `resolve`, also telling where the answer came from
+pub fn resolve_with_source(
+    qname: &str,
+    qtype: QueryType,
+    options: &Arc<ResolverOptions>,
+) -> Result<(DnsPacket, Source)> {
+    resolve_query(&Query {
+        name: qname,
+        qtype,
+        client: None,
+        options,
+    })
+}
+
+/// Like `resolve_with_source`, for a query that may come from a client, which
+/// the handlers of the pipeline get to see
+pub fn resolve_query(query: &Query) -> Result<(DnsPacket, Source)> {
+    query.options.pipeline.run(query)
+}
+
+/// Check the signatures of the answer of the rest of the pipeline, if DNSSEC
+/// validation is enabled
+fn validate(query: &Query, next: Next) -> Result<(DnsPacket, Source)> {
+    let (mut response, source) = next.run(query)?;
+
+    // Whatever an upstream claims about the authenticity of its response, it's
+    // only our own validation that counts
+    response.header.authed_data = false;
+    #[cfg(feature = "dnssec")]
+    if let Some(validator) = &query.options.validator {
+        // There's nothing to check local answers against, they're
+        // authoritative by definition, and the local link is never signed
+        if !matches!(source, Source::Hosts | Source::Zone | Source::Mdns) {
+            let validation =
+                validator.validate(query.name, query.qtype, &response, query.options)?;
+            response.header.authed_data = validation == Validation::Secure;
+        }
+    }
+
+    Ok((response, source))
+}
+
+/// Answer an AAAA query for a name that only has A records with records
+/// synthesized from those, if DNS64 is enabled
+fn synthesize_aaaa(query: &Query, next: Next) -> Result<(DnsPacket, Source)> {
+    let dns64 = match &query.options.dns64 {
+        Some(dns64) if query.qtype == QueryType::AAAA => dns64,
+        _ => return next.run(query),
+    };
+    let (response, source) = next.run(query)?;
+
+    // Real AAAA records always take precedence, and so does a negative
+    // answer for the name itself.
+    let has_aaaa = response
+        .answers
+        .iter()
+        .any(|rec| matches!(rec, DnsRecord::AAAA { .. }));
+    if has_aaaa || response.header.rescode != ResultCode::NOERROR {
+        return Ok((response, source));
+    }
+
+    let a_query = Query {
+        qtype: QueryType::A,
+        ..*query
+    };
+    let (mut a_response, a_source) = next.run(&a_query)?;
+    if a_response.get_random_a().is_none() {
+        return Ok((response, source));
+    }
+
+    a_response.answers =
+        dns64.synthesize_records(&a_response.answers, dns64::negative_ttl(&response));
+
+    Ok((a_response, a_source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minimize::MAX_MINIMISE_COUNT;
+
+    /// A name server that claims every name is in `example.com`, and refers
+    /// to itself for it
+    fn refer_to_self(asked: &mut usize) -> Result<DnsPacket> {
+        *asked += 1;
+
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        response.authorities.push(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns.example.com".to_string(),
+            ttl: 3600,
+        });
+        response.resources.push(DnsRecord::A {
+            domain: "ns.example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 3600,
+        });
+        Ok(response)
+    }
+
+    #[test]
+    fn servers_referring_to_themselves_are_given_up_on() {
+        for qname_minimization in [false, true] {
+            let options = ResolverOptions {
+                qname_minimization,
+                ..ResolverOptions::default()
+            };
+
+            let mut asked = 0;
+            let result =
+                follow_referrals("www.example.com", QueryType::A, &options, 0, |_, _, _| {
+                    refer_to_self(&mut asked)
+                });
+            assert!(matches!(result, Err(DnsError::NoServers)));
+            assert!(asked <= MAX_REFERRALS + MAX_MINIMISE_COUNT + 1);
+        }
+    }
+}
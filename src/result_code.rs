@@ -1,4 +1,7 @@
+use crate::error::DnsError;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ResultCode {
     NOERROR = 0,
     FORMERR = 1,
@@ -6,6 +9,18 @@ pub enum ResultCode {
     NXDOMAIN = 3,
     NOTIMP = 4,
     REFUSED = 5,
+    /// The rest are the answers to UPDATE messages (RFC 2136): a name that
+    /// should not exist, an RRset that should not exist, an RRset that should,
+    /// a zone we're not authoritative for, and a name outside of the zone
+    YXDOMAIN = 6,
+    YXRRSET = 7,
+    NXRRSET = 8,
+    NOTAUTH = 9,
+    NOTZONE = 10,
+    /// Extended RCODEs don't fit in the four bits of the header, the upper
+    /// bits are carried by the OPT record. So this can only be sent to clients
+    /// that use EDNS in the first place.
+    BADVERS = 16,
 }
 
 impl ResultCode {
@@ -16,7 +31,24 @@ impl ResultCode {
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            6 => ResultCode::YXDOMAIN,
+            7 => ResultCode::YXRRSET,
+            8 => ResultCode::NXRRSET,
+            9 => ResultCode::NOTAUTH,
+            10 => ResultCode::NOTZONE,
+            16 => ResultCode::BADVERS,
+            _ => ResultCode::NOERROR,
+        }
+    }
+
+    /// The code to answer a query with when handling it failed with `error`.
+    /// A query that doesn't parse is the fault of the client, while anything
+    /// else, like an upstream that can't be reached, is a failure on our end.
+    pub fn from_error(error: &DnsError) -> ResultCode {
+        match error {
+            e if e.is_malformed_packet() => ResultCode::FORMERR,
+            DnsError::Unsupported(_) => ResultCode::NOTIMP,
+            _ => ResultCode::SERVFAIL,
         }
     }
 }
@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+    name,
+    query_type::QueryType,
+};
+
+/// The order the addresses of a name are handed out in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RotationMode {
+    /// Each response starting at the address after the one the previous
+    /// response for the name started at
+    #[default]
+    RoundRobin,
+    /// A random order for every response
+    Shuffle,
+    /// A random order for every response, in which addresses with a higher
+    /// weight come first more often
+    Weighted,
+}
+
+impl FromStr for RotationMode {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<RotationMode> {
+        match s {
+            "round-robin" => Ok(RotationMode::RoundRobin),
+            "shuffle" => Ok(RotationMode::Shuffle),
+            "weighted" => Ok(RotationMode::Weighted),
+            _ => Err(DnsError::Parse(format!("Unknown rotation mode: {}", s))),
+        }
+    }
+}
+
+/// Rotates the A and AAAA records answered from local zones, so that clients
+/// which always pick the first address spread out over all of them, making
+/// for rudimentary load balancing between the hosts of a service.
+///
+/// With `RotationMode::Weighted`, an address with a weight of 3 comes first
+/// three times as often as one with the default weight of 1, and one with a
+/// weight of 0 always comes last, e.g. for a standby host.
+#[derive(Clone, Debug)]
+pub struct AnswerRotation {
+    pub mode: RotationMode,
+    pub weights: HashMap<IpAddr, u32>,
+    /// How many responses each RRset has been rotated for so far
+    counters: Arc<Mutex<HashMap<(String, QueryType), usize>>>,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl AnswerRotation {
+    /// Seeding it makes the random orders reproducible, which is mostly
+    /// useful for testing
+    pub fn new(mode: RotationMode, seed: Option<u64>) -> AnswerRotation {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        AnswerRotation {
+            mode,
+            weights: HashMap::new(),
+            counters: Arc::new(Mutex::new(HashMap::new())),
+            rng: Arc::new(Mutex::new(rng)),
+        }
+    }
+
+    pub fn weight(&self, addr: IpAddr) -> u32 {
+        self.weights.get(&addr).copied().unwrap_or(1)
+    }
+
+    /// Put the records of every A and AAAA RRset among `records` in the order
+    /// of the next response. Records of other types, and the order of the
+    /// RRsets themselves, are left alone.
+    pub fn rotate(&self, records: &mut [DnsRecord]) {
+        let mut start = 0;
+        while start < records.len() {
+            let domain = records[start].domain();
+            let qtype = records[start].query_type();
+            let mut end = start + 1;
+            while end < records.len()
+                && name::eq(records[end].domain(), domain)
+                && records[end].query_type() == qtype
+            {
+                end += 1;
+            }
+
+            if matches!(qtype, QueryType::A | QueryType::AAAA) && end - start > 1 {
+                self.rotate_rrset(&mut records[start..end]);
+            }
+            start = end;
+        }
+    }
+
+    fn rotate_rrset(&self, rrset: &mut [DnsRecord]) {
+        match self.mode {
+            RotationMode::RoundRobin => {
+                let key = (rrset[0].domain().to_string(), rrset[0].query_type());
+                let mut counters = self.counters.lock().unwrap();
+                let counter = counters.entry(key).or_default();
+                rrset.rotate_left(*counter % rrset.len());
+                *counter = counter.wrapping_add(1);
+            }
+            RotationMode::Shuffle => rrset.shuffle(&mut *self.rng.lock().unwrap()),
+            RotationMode::Weighted => {
+                // Ordering by a random number to the power of one over the
+                // weight amounts to picking the records one after the other,
+                // each with a chance proportional to its weight (Efraimidis
+                // and Spirakis).
+                let mut rng = self.rng.lock().unwrap();
+                let mut keyed: Vec<(f64, DnsRecord)> = rrset
+                    .iter()
+                    .map(|record| {
+                        let weight = record.ip_addr().map_or(1, |addr| self.weight(addr));
+                        let key = match weight {
+                            0 => -1.0,
+                            weight => rng.gen::<f64>().powf(1.0 / weight as f64),
+                        };
+                        (key, record.clone())
+                    })
+                    .collect();
+                keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+                for (slot, (_, record)) in rrset.iter_mut().zip(keyed) {
+                    *slot = record;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    client::QueryOptions,
+    dns_packet::DnsPacket,
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+    forwarder::parse_addr,
+    name,
+    query_type::QueryType,
+    result_code::ResultCode,
+    transfer,
+    tsig::TsigKey,
+    zone::Zone,
+};
+
+/// How long to wait before trying again when the zone couldn't be
+/// transferred, and there's no SOA record yet to take the interval from
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Loaded {
+    zone: Zone,
+    /// When the zone stops being served, unless it's refreshed by then
+    expires: Instant,
+}
+
+/// A zone served as a secondary, which is kept in sync with its primary
+/// through zone transfers (RFC 1034, section 4.3.5). The timers of the SOA
+/// record say how often the primary is checked for a newer serial, how soon
+/// to try again when it can't be reached, and for how long the zone is
+/// served at all while it can't. The primary may also tell us about changes
+/// straight away with a NOTIFY (RFC 1996).
+///
+/// A zone that hasn't been transferred yet, or that expired, isn't answered
+/// from, and the names in it are looked up like any others.
+#[derive(Clone, Debug)]
+pub struct Secondary {
+    /// The name at the top of the zone, without the trailing dot
+    pub origin: String,
+    pub primary: SocketAddr,
+    /// The key to sign the transfers with, which the primary has to sign
+    /// its answers with in turn
+    pub key: Option<TsigKey>,
+    zone: Arc<RwLock<Option<Loaded>>>,
+    notified: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Secondary {
+    pub fn new(origin: &str, primary: SocketAddr) -> Secondary {
+        Secondary {
+            origin: origin.trim_end_matches('.').to_ascii_lowercase(),
+            primary,
+            key: None,
+            zone: Arc::new(RwLock::new(None)),
+            notified: Arc::new((Mutex::new(false), Condvar::new())),
+        }
+    }
+
+    /// `ZONE@PRIMARY`, e.g. `example.com@192.0.2.1`, or `ZONE@PRIMARY/KEY`
+    /// for transfers signed with the key of `keys` named `KEY`
+    pub fn parse(s: &str, keys: &[TsigKey]) -> Result<Secondary> {
+        let (origin, primary) = s.split_once('@').ok_or_else(|| {
+            DnsError::Parse(format!("Expected ZONE@PRIMARY for a secondary: {}", s))
+        })?;
+        let (primary, key) = match primary.split_once('/') {
+            Some((primary, key)) => (primary, Some(TsigKey::find(keys, key)?.clone())),
+            None => (primary, None),
+        };
+
+        let mut secondary = Secondary::new(origin, parse_addr(primary, 53)?);
+        secondary.key = key;
+        Ok(secondary)
+    }
+
+    /// Whether `qname` is the origin or any name below it
+    pub fn contains(&self, qname: &str) -> bool {
+        name::is_subdomain(qname, &self.origin)
+    }
+
+    /// The response to a query for `qname` from the zone, if it's there to
+    /// answer from, see `Zone::answer`
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let loaded = self.zone.read().unwrap();
+        loaded
+            .as_ref()
+            .filter(|loaded| Instant::now() < loaded.expires)
+            .and_then(|loaded| loaded.zone.answer(qname, qtype))
+    }
+
+    /// The serial of the zone as we have it
+    pub fn serial(&self) -> Option<u32> {
+        self.zone.read().unwrap().as_ref()?.zone.serial()
+    }
+
+    /// Check the primary for a newer version of the zone, and transfer what
+    /// changed if there is one. Returns how long until the next check is due.
+    pub fn refresh(&self, options: &QueryOptions) -> Result<Duration> {
+        let zone = self
+            .zone
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|loaded| loaded.zone.clone());
+        let zone = match zone {
+            Some(mut zone) => {
+                if transfer::ixfr(&mut zone, self.primary, options, self.key.as_ref())? {
+                    info!("Updated {} to serial {:?}", self.origin, zone.serial());
+                }
+                zone
+            }
+            None => {
+                let zone = transfer::axfr(&self.origin, self.primary, options, self.key.as_ref())?;
+                info!("Loaded {} at serial {:?}", self.origin, zone.serial());
+                zone
+            }
+        };
+
+        let (refresh, expire) = match zone.soa() {
+            Some(DnsRecord::SOA {
+                refresh, expire, ..
+            }) => (*refresh, *expire),
+            _ => {
+                return Err(DnsError::InvalidResponse(format!(
+                    "{} from {} has no SOA record",
+                    self.origin, self.primary
+                )));
+            }
+        };
+
+        *self.zone.write().unwrap() = Some(Loaded {
+            zone,
+            expires: Instant::now() + Duration::from_secs(expire as u64),
+        });
+
+        Ok(Duration::from_secs(refresh.max(1) as u64))
+    }
+
+    /// Keep the zone in sync with the primary for good, checking it whenever
+    /// the refresh interval is up or a NOTIFY came in
+    pub fn run(&self, options: QueryOptions) {
+        loop {
+            let wait = match self.refresh(&options) {
+                Ok(refresh) => refresh,
+                Err(e) => {
+                    warn!(
+                        "Failed to refresh {} from {}: {}",
+                        self.origin, self.primary, e
+                    );
+                    self.retry_interval()
+                }
+            };
+
+            let (notified, ready) = &*self.notified;
+            let guard = notified.lock().unwrap();
+            let (mut guard, _) = ready
+                .wait_timeout_while(guard, wait, |notified| !*notified)
+                .unwrap();
+            *guard = false;
+        }
+    }
+
+    /// How long to wait before trying again after a failed refresh
+    fn retry_interval(&self) -> Duration {
+        let loaded = self.zone.read().unwrap();
+        let Some(loaded) = loaded.as_ref() else {
+            return INITIAL_RETRY;
+        };
+        if Instant::now() >= loaded.expires {
+            warn!("{} expired, it's no longer answered from", self.origin);
+        }
+        match loaded.zone.soa() {
+            Some(DnsRecord::SOA { retry, .. }) => Duration::from_secs((*retry).max(1) as u64),
+            _ => INITIAL_RETRY,
+        }
+    }
+
+    /// Have the zone checked for changes right away
+    pub fn notify(&self) {
+        let (notified, ready) = &*self.notified;
+        *notified.lock().unwrap() = true;
+        ready.notify_all();
+    }
+}
+
+/// Parse a secondary zone along with its primary, e.g.
+/// `example.com@192.0.2.1` or `example.com@[2001:db8::1]:5300`. The port
+/// defaults to 53.
+impl FromStr for Secondary {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Secondary> {
+        Secondary::parse(s, &[])
+    }
+}
+
+/// Handle a NOTIFY for one of our secondary zones, returning the result code
+/// to respond with. Only the primary of a zone may notify us of changes to
+/// it, and the zone is checked for them rather than taking their word for
+/// it.
+pub fn handle_notify(
+    secondaries: &[Secondary],
+    request: &DnsPacket,
+    src: SocketAddr,
+) -> ResultCode {
+    let Some(question) = request.questions.first() else {
+        return ResultCode::FORMERR;
+    };
+
+    let secondary = secondaries.iter().find(|secondary| {
+        name::eq(&secondary.origin, &question.name) && secondary.primary.ip() == src.ip()
+    });
+    match secondary {
+        Some(secondary) => {
+            info!("{} notified us of changes to {}", src, secondary.origin);
+            secondary.notify();
+            ResultCode::NOERROR
+        }
+        None => {
+            warn!("Refusing NOTIFY for {} from {}", question.name, src);
+            ResultCode::REFUSED
+        }
+    }
+}
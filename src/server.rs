@@ -0,0 +1,582 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        mpsc::{self, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    byte_packet_buffer::{self, BytePacketBuffer},
+    client::DEFAULT_PAYLOAD_SIZE,
+    dns_header::{DnsHeader, Opcode},
+    dns_packet::{DnsPacket, ResponseBuilder},
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+    metrics::Metrics,
+    pipeline::Query,
+    query_log::QueryLogEntry,
+    rate_limit::Action,
+    resolver::{resolve_query, ResolverOptions, Source},
+    result_code::ResultCode,
+    secondary, shutdown,
+    tsig::{self, Verified},
+    update,
+};
+
+/// How long a TCP client may sit idle before its connection is closed
+pub const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many TCP connections may wait to be accepted
+const TCP_BACKLOG: i32 = 1024;
+
+/// How many UDP queries may wait for a worker, see `serve_udp`
+pub const MAX_QUEUED_QUERIES: usize = 1024;
+
+/// The transport a query came in over, which decides how large the response
+/// may be
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+/// Whether a socket on `addr` should leave IPv4 clients to another one of
+/// the addresses served on. That's the case for `[::]` when `0.0.0.0` with
+/// the same port is served on as well, since otherwise they'd clash.
+pub fn only_v6(addr: SocketAddr, listen: &[SocketAddr]) -> bool {
+    addr.is_ipv6()
+        && addr.ip().is_unspecified()
+        && listen.iter().any(|other| {
+            other.is_ipv4() && other.ip().is_unspecified() && other.port() == addr.port()
+        })
+}
+
+/// How a socket to serve on is set up
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BindOptions {
+    /// Whether an IPv6 socket on `[::]` accepts IPv4 clients as well differs
+    /// between systems by default, so it's set explicitly, to dual-stack
+    /// unless this is set. See `only_v6`.
+    pub only_v6: bool,
+    /// Let other sockets bind to the same address with `SO_REUSEPORT`, so
+    /// that several processes or threads can serve on it, with the kernel
+    /// spreading the clients over them. Only supported on Unix.
+    pub reuse_port: bool,
+}
+
+fn new_socket(addr: SocketAddr, ty: Type, protocol: Protocol, bind: BindOptions) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(bind.only_v6)?;
+    }
+    if bind.reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(DnsError::Unsupported(
+            "sharing ports is only supported on Unix".to_string(),
+        ));
+    }
+
+    Ok(socket)
+}
+
+/// Bind a UDP socket to serve on, see `BindOptions`
+pub fn bind_udp(addr: SocketAddr, bind: BindOptions) -> Result<UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP, bind)?;
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+/// Bind a TCP listener to serve on, see `BindOptions`
+pub fn bind_tcp(addr: SocketAddr, bind: BindOptions) -> Result<TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, Protocol::TCP, bind)?;
+    // Like `TcpListener::bind`, so that a restarted server doesn't have to
+    // wait for the connections of the previous one to time out
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(TCP_BACKLOG)?;
+
+    Ok(socket.into())
+}
+
+/// Handle a single incoming UDP packet
+pub fn handle_udp_query(socket: &UdpSocket, options: &Arc<ResolverOptions>) -> Result<()> {
+    // With a socket ready, we can go ahead and read a packet. This will
+    // block until one is received.
+    let mut buf = vec![0; byte_packet_buffer::MAX_UDP_SIZE];
+
+    // The `recv_from` function will write the data into the provided buffer,
+    // and return the length of the data read as well as the source address.
+    // We need to keep track of the source in order to send our reply later on.
+    let (len, src) = socket.recv_from(&mut buf)?;
+    let mut req_buffer = BytePacketBuffer::from_slice(&buf[..len]);
+
+    let mut res_buffer = match handle_request(&mut req_buffer, src, Transport::Udp, options) {
+        Ok(res_buffer) => res_buffer,
+        Err(DnsError::RateLimited | DnsError::NotAQuery) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let len = res_buffer.pos();
+    let data = res_buffer.get_range(0, len)?;
+
+    socket.send_to(data, src)?;
+
+    Ok(())
+}
+
+/// Serve UDP queries with a pool of `workers` threads. The socket is only
+/// read from here, every packet is handed to whichever worker is free to
+/// parse, resolve and answer it, so that a slow lookup doesn't hold up the
+/// queries behind it. The workers share the options, including the cache.
+///
+/// At most `MAX_QUEUED_QUERIES` packets wait for a worker, any more are
+/// dropped and counted in the metrics, so that a flood can't take up memory
+/// without end.
+///
+/// Once `options.shutdown` stops, the packets that were already received
+/// are answered before this returns.
+pub fn serve_udp(socket: UdpSocket, options: Arc<ResolverOptions>, workers: usize) -> Result<()> {
+    let (sender, receiver) =
+        mpsc::sync_channel::<(BytePacketBuffer, SocketAddr)>(MAX_QUEUED_QUERIES);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    let mut handles = Vec::new();
+    for _ in 0..workers.max(1) {
+        let socket = socket.try_clone()?;
+        let receiver = receiver.clone();
+        let options = options.clone();
+        handles.push(thread::spawn(move || loop {
+            // The lock is only held while waiting for the next packet, which
+            // is then handled with everyone else free to pick up the next one
+            let next = receiver.lock().unwrap().recv();
+            let (mut req_buffer, src) = match next {
+                Ok(next) => next,
+                // The listener is gone
+                Err(_) => return,
+            };
+
+            let result = handle_request(&mut req_buffer, src, Transport::Udp, &options).and_then(
+                |res_buffer| {
+                    socket.send_to(&res_buffer.buf[0..res_buffer.pos()], src)?;
+                    Ok(())
+                },
+            );
+            match result {
+                Ok(()) | Err(DnsError::RateLimited | DnsError::NotAQuery) => {}
+                Err(e) => error!("An error occurred: {}", e),
+            }
+        }));
+    }
+
+    socket.set_read_timeout(Some(shutdown::POLL_INTERVAL))?;
+    let mut buf = vec![0; byte_packet_buffer::MAX_UDP_SIZE];
+    while !options.shutdown.is_stopping() {
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match sender.try_send((BytePacketBuffer::from_slice(&buf[..len]), src)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                debug!(
+                    "Dropping a query from {}, the workers are falling behind",
+                    src
+                );
+                if let Some(metrics) = &options.metrics {
+                    metrics.record_dropped();
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(DnsError::Io(io::Error::other("All UDP workers are gone")));
+            }
+        }
+    }
+
+    // The workers are done once they've emptied the channel
+    drop(sender);
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    Ok(())
+}
+
+/// Accept TCP connections until `options.shutdown` stops, serving each one
+/// on its own thread
+pub fn serve_tcp(listener: TcpListener, options: Arc<ResolverOptions>) {
+    if let Ok(addr) = listener.local_addr() {
+        options.shutdown.wake_tcp_on_stop(addr);
+    }
+
+    for stream in listener.incoming() {
+        if options.shutdown.is_stopping() {
+            return;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept TCP connection: {}", e);
+                continue;
+            }
+        };
+
+        let options = options.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_tcp_connection(stream, &options) {
+                error!("An error occurred: {}", e);
+            }
+        });
+    }
+}
+
+/// Answer the queries on a TCP connection until the client closes it. Over
+/// TCP, every message is prefixed with its length as a two byte integer, and
+/// a client may send any number of queries over the same connection.
+pub fn handle_tcp_connection(mut stream: TcpStream, options: &Arc<ResolverOptions>) -> Result<()> {
+    let _connection = options.metrics.as_ref().map(Metrics::connection);
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let src = stream.peer_addr()?;
+
+    // Up until the server stops, after which the client has to connect
+    // again to the next one
+    while !options.shutdown.is_stopping() {
+        let mut len = [0; 2];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            // The client is done
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(DnsError::from_timed_read(e)),
+        }
+        let len = u16::from_be_bytes(len) as usize;
+
+        let mut buf = vec![0; len];
+        stream
+            .read_exact(&mut buf)
+            .map_err(DnsError::from_timed_read)?;
+        let mut req_buffer = BytePacketBuffer::from_slice(&buf);
+
+        let res_buffer = handle_request(&mut req_buffer, src, Transport::Tcp, options)?;
+
+        let len = res_buffer.pos() as u16;
+        stream.write_all(&len.to_be_bytes())?;
+        stream.write_all(&res_buffer.buf[0..res_buffer.pos()])?;
+    }
+
+    Ok(())
+}
+
+/// Work out the response to a query, and write it to a fresh buffer
+pub(crate) fn handle_request(
+    req_buffer: &mut BytePacketBuffer,
+    src: SocketAddr,
+    transport: Transport,
+    options: &Arc<ResolverOptions>,
+) -> Result<BytePacketBuffer> {
+    let start = Instant::now();
+    let _in_flight = options.shutdown.track();
+
+    // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into a
+    // `DnsPacket`. A packet that doesn't parse still gets a response, as long
+    // as there's a header to take the ID from, since otherwise the client
+    // would be left to time out.
+    let mut request = match DnsPacket::from_buffer(req_buffer) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Failed to parse query from {}: {}", src, e);
+
+            let mut header = DnsHeader::new();
+            req_buffer.seek(0)?;
+            if header.read(req_buffer).is_err() {
+                return Err(e);
+            }
+            if header.response {
+                return Err(DnsError::NotAQuery);
+            }
+            let rescode = ResultCode::from_error(&e);
+            if let Some(metrics) = &options.metrics {
+                metrics.record_response(rescode, start.elapsed());
+            }
+            return error_response(&header, rescode);
+        }
+    };
+
+    // Responses aren't answered, or a spoofed one would have us answer
+    // another server, which might answer in turn
+    if request.header.response {
+        debug!("Dropping a response from {}", src);
+        return Err(DnsError::NotAQuery);
+    }
+
+    // A signed request gets a signed response, and one whose signature
+    // doesn't check out gets `NOTAUTH`, along with a TSIG record that tells
+    // the client why (RFC 8945, section 5.2)
+    let signed = match tsig::verify(&req_buffer.buf, &options.tsig_keys, None) {
+        Ok(Verified::Unsigned) => None,
+        Ok(Verified::Signed { key, mac }) => Some((key, mac)),
+        Ok(Verified::Rejected(rejection)) => {
+            warn!(
+                "Rejecting request from {} signed with {}: {}",
+                src,
+                rejection.key_name(),
+                tsig::rcode_name(rejection.error)
+            );
+            let mut res_buffer = error_response(&request.header, ResultCode::NOTAUTH)?;
+            rejection.write(&mut res_buffer)?;
+            return Ok(res_buffer);
+        }
+        Err(e) => {
+            warn!(
+                "Failed to check the signature of the request from {}: {}",
+                src, e
+            );
+            return error_response(&request.header, ResultCode::FORMERR);
+        }
+    };
+
+    if !options.preserve_question {
+        for question in request.questions.iter_mut() {
+            question.raw = None;
+        }
+    }
+
+    // The largest UDP response we're willing to send, which is also what we
+    // advertise to clients using EDNS
+    let payload_size = options.query.payload_size.unwrap_or(DEFAULT_PAYLOAD_SIZE);
+    let client_payload_size = match request.get_opt() {
+        Some(DnsRecord::OPT { packet_len, .. }) => Some(*packet_len),
+        _ => None,
+    };
+    let dnssec_ok = request.dnssec_ok();
+    let mut source: Option<Source> = None;
+
+    // Create and initialize the response object, which echoes the questions
+    // back so the client can match the response to its query
+    let mut packet = DnsPacket::response_to(&request).build();
+
+    // A NOTIFY is for one of our secondary zones, which is then checked for
+    // changes
+    if request.header.opcode == Opcode::NOTIFY {
+        packet.header.rescode = secondary::handle_notify(&options.secondaries, &request, src);
+        packet.header.authoritative_answer = true;
+    }
+    // An UPDATE makes changes to one of the zones clients may change
+    else if request.header.opcode == Opcode::UPDATE {
+        let key = signed.as_ref().map(|(key, _)| key.name.as_str());
+        packet.header.rescode = update::handle_update(&options.dynamic_zones, req_buffer, src, key);
+    }
+    // Other than that, standard queries are all we know how to answer,
+    // anything else, such as an IQUERY, is met with `NOTIMP`.
+    else if request.header.opcode != Opcode::QUERY {
+        packet.header.rescode = ResultCode::NOTIMP;
+    }
+    // Version 0 is the only version of EDNS there is so far. Clients asking
+    // for anything newer are told which version we do support, by way of the
+    // OPT record in the `BADVERS` response.
+    else if request.edns_version().is_some_and(|version| version > 0) {
+        packet.header.rescode = ResultCode::BADVERS;
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: payload_size,
+            flags: 0,
+            data: Vec::new(),
+        });
+    }
+    // In the normal case, exactly one question is present. Should there be
+    // more, the first is the one answered, as well as the one counted and
+    // logged below.
+    else if let Some(question) = request.questions.first() {
+        info!("Received query from {}: {:?}", src, question);
+
+        // Since all is set up and as expected, the query can be forwarded to the
+        // target server. There's always the possibility that the query will
+        // fail, e.g. because none of the servers answered in time, in which
+        // case `SERVFAIL` response code is set to indicate as much to the
+        // client. If rather everything goes as planned, the response records
+        // are copied into our response object. Clients that belong to a view
+        // are resolved the way the view says.
+        let client_options = options.for_client(src.ip());
+        let result = resolve_query(&Query {
+            name: &question.name,
+            qtype: question.qtype,
+            client: Some(src),
+            options: client_options,
+        });
+
+        match result {
+            Ok((mut result, result_source)) => {
+                source = Some(result_source);
+
+                packet.header.rescode = result.header.rescode;
+                // Only clients that show an interest in DNSSEC get told that
+                // the answer validated (RFC 6840, section 5.8)
+                packet.header.authed_data =
+                    result.header.authed_data && (dnssec_ok || request.header.authed_data);
+
+                if let Some(shuffler) = &options.shuffler {
+                    shuffler.shuffle(&mut result.answers);
+                }
+
+                for rec in result.answers {
+                    info!("Answer: {:?}", rec);
+                    packet.answers.push(rec);
+                }
+                for rec in result.authorities {
+                    debug!("Authority: {:?}", rec);
+                    packet.authorities.push(rec);
+                }
+                // The OPT record of the upstream only applies to the hop
+                // between us and the upstream, we add our own below.
+                for rec in result.resources {
+                    if matches!(rec, DnsRecord::OPT { .. }) {
+                        continue;
+                    }
+                    debug!("Resource: {:?}", rec);
+                    packet.resources.push(rec);
+                }
+
+                if !dnssec_ok {
+                    packet.strip_dnssec(question.qtype);
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to resolve {} {:?}: {}",
+                    question.name, question.qtype, e
+                );
+                packet.header.rescode = ResultCode::SERVFAIL;
+            }
+        }
+    }
+    // Being mindful of how unreliable input data from arbitrary senders can be, we
+    // need make sure that a question is actually present. If not, we return `FORMER`
+    // to indicate that the sender made soemthing wrong.
+    else {
+        packet.header.rescode = ResultCode::FORMERR;
+    }
+
+    // Clients that use EDNS get an OPT record of their own in return
+    if client_payload_size.is_some() && packet.get_opt().is_none() {
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: payload_size,
+            flags: 0,
+            data: Vec::new(),
+        });
+    }
+
+    if options.minimal_responses {
+        packet.strip_additional();
+    }
+
+    // Anyone can have responses sent to someone else over UDP by spoofing
+    // their address, which is what the rate limit is for. Over TCP, the
+    // handshake proves that the client is who it claims to be.
+    if let (Transport::Udp, Some(rate_limit)) = (transport, &options.rate_limit) {
+        match rate_limit.check(src.ip()) {
+            Action::Send => {}
+            Action::Slip => {
+                debug!("Rate limiting {}, sending a truncated response", src);
+                packet.header.truncated_message = true;
+                packet.answers.clear();
+                packet.authorities.clear();
+                packet
+                    .resources
+                    .retain(|rec| matches!(rec, DnsRecord::OPT { .. }));
+            }
+            Action::Drop => {
+                debug!("Rate limiting {}, dropping the response", src);
+                return Err(DnsError::RateLimited);
+            }
+        }
+    }
+
+    // Over UDP, the response has to fit within what the client can take, as
+    // well as what we're willing to send. TCP is only limited by its two byte
+    // length prefix.
+    let max_size = match (transport, client_payload_size) {
+        (Transport::Tcp, _) => u16::MAX as usize,
+        (Transport::Udp, Some(size)) => {
+            (size.min(payload_size) as usize).max(byte_packet_buffer::DEFAULT_SIZE)
+        }
+        (Transport::Udp, None) => byte_packet_buffer::DEFAULT_SIZE,
+    };
+
+    // The only thing remaining is to encode our response, ready to be sent off,
+    // leaving room for the signature, if it's to be signed.
+    let reserved = signed
+        .as_ref()
+        .map_or(0, |(key, _)| tsig::signature_len(key));
+    let mut res_buffer = BytePacketBuffer::with_capacity(max_size.saturating_sub(reserved));
+    let written = packet.write(&mut res_buffer);
+    if let Err(e) = &written {
+        warn!("Failed to write response to {}: {}", src, e);
+        packet.header.rescode = ResultCode::SERVFAIL;
+        packet.header.answers = 0;
+    }
+
+    if let Some(metrics) = &options.metrics {
+        if let Some(question) = packet.questions.first() {
+            metrics.record_query(&question.name, question.qtype, src.ip());
+        }
+        if source == Some(Source::Blocklist) {
+            metrics.record_blocked();
+        }
+        metrics.record_response(packet.header.rescode, start.elapsed());
+    }
+
+    if let (Some(query_log), Some(question)) = (&options.query_log, packet.questions.first()) {
+        query_log.record(&QueryLogEntry {
+            client: src,
+            transport,
+            qname: &question.name,
+            qtype: question.qtype,
+            rcode: packet.header.rescode,
+            answers: packet.header.answers,
+            source,
+            latency: start.elapsed(),
+        });
+    }
+
+    // Should writing fail, e.g. because of a record from upstream that can't
+    // be written, the client is told about the failure rather than left
+    // waiting
+    let mut res_buffer = match written {
+        Ok(()) => res_buffer,
+        Err(_) => error_response(&packet.header, ResultCode::SERVFAIL)?,
+    };
+    if let Some((key, mac)) = &signed {
+        tsig::sign(&mut res_buffer, key, Some(mac))?;
+    }
+
+    Ok(res_buffer)
+}
+
+/// A response to the query with the given header that consists of nothing
+/// but a header of its own, carrying `rescode`
+fn error_response(request: &DnsHeader, rescode: ResultCode) -> Result<BytePacketBuffer> {
+    let mut packet = ResponseBuilder::reply_to(request).rcode(rescode).build();
+
+    let mut res_buffer = BytePacketBuffer::new();
+    packet.write(&mut res_buffer)?;
+
+    Ok(res_buffer)
+}
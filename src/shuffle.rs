@@ -0,0 +1,46 @@
+use std::sync::{Arc, Mutex};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use crate::{dns_record::DnsRecord, name};
+
+/// Shuffles the records of an answer, so that clients which always pick the
+/// first address spread out over all of them. Seeding it makes the order
+/// reproducible, which is mostly useful for testing.
+#[derive(Clone, Debug)]
+pub struct AnswerShuffler {
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl AnswerShuffler {
+    pub fn new(seed: Option<u64>) -> AnswerShuffler {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        AnswerShuffler {
+            rng: Arc::new(Mutex::new(rng)),
+        }
+    }
+
+    /// Only records within the same RRset trade places. Shuffling across them
+    /// would e.g. break up a CNAME chain, which has to stay in order.
+    pub fn shuffle(&self, records: &mut [DnsRecord]) {
+        let mut rng = self.rng.lock().unwrap();
+
+        let mut start = 0;
+        while start < records.len() {
+            let mut end = start + 1;
+            while end < records.len()
+                && name::eq(records[end].domain(), records[start].domain())
+                && records[end].query_type() == records[start].query_type()
+            {
+                end += 1;
+            }
+
+            records[start..end].shuffle(&mut *rng);
+            start = end;
+        }
+    }
+}
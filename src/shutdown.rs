@@ -0,0 +1,222 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use crate::error::Result;
+
+/// How long the queries in flight get to be answered once the server stops
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the UDP servers waiting for the next query check whether they
+/// are to stop. Waking them up with a datagram of our own doesn't work with
+/// `SO_REUSEPORT`, since it may well end up with another socket on the port.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+struct State {
+    stopping: bool,
+    /// Queries being answered right now, see `Shutdown::track`
+    in_flight: usize,
+    /// What to run once the server stops, such as waking up the listeners
+    /// blocked waiting for the next connection
+    on_stop: Vec<Box<dyn FnOnce() + Send>>,
+}
+
+/// Stops a server cleanly: no new queries are accepted once `stop` is
+/// called, while the ones already in flight are still answered, see `drain`.
+/// Clones share the same state, so that the servers and whatever stops them
+/// can each hold on to one.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.0.lock().unwrap();
+        f.debug_struct("Shutdown")
+            .field("stopping", &state.stopping)
+            .field("in_flight", &state.in_flight)
+            .finish()
+    }
+}
+
+/// A query being answered, for as long as it's held
+pub struct InFlight {
+    shutdown: Shutdown,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let (state, changed) = &*self.shutdown.state;
+        state.lock().unwrap().in_flight -= 1;
+        changed.notify_all();
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Stop accepting queries. The first call runs everything registered
+    /// with `on_stop`, any later ones do nothing.
+    pub fn stop(&self) {
+        let (state, changed) = &*self.state;
+        let on_stop = {
+            let mut state = state.lock().unwrap();
+            if state.stopping {
+                return;
+            }
+            state.stopping = true;
+            std::mem::take(&mut state.on_stop)
+        };
+        changed.notify_all();
+
+        for f in on_stop {
+            f();
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.state.0.lock().unwrap().stopping
+    }
+
+    /// Run `f` once the server stops, or right away if it already has
+    pub fn on_stop(&self, f: impl FnOnce() + Send + 'static) {
+        let mut state = self.state.0.lock().unwrap();
+        if !state.stopping {
+            state.on_stop.push(Box::new(f));
+            return;
+        }
+        drop(state);
+        f();
+    }
+
+    /// Block until the server stops
+    pub fn wait(&self) {
+        let (state, changed) = &*self.state;
+        let state = state.lock().unwrap();
+        drop(changed.wait_while(state, |state| !state.stopping).unwrap());
+    }
+
+    /// Count a query as in flight until the returned guard is dropped
+    pub fn track(&self) -> InFlight {
+        self.state.0.lock().unwrap().in_flight += 1;
+        InFlight {
+            shutdown: self.clone(),
+        }
+    }
+
+    /// Wait up to `timeout` for the queries in flight to be answered, and
+    /// return how many are left
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let (state, changed) = &*self.state;
+        let deadline = Instant::now() + timeout;
+        let mut state = state.lock().unwrap();
+        while state.in_flight > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            state = changed.wait_timeout(state, left).unwrap().0;
+        }
+
+        state.in_flight
+    }
+
+    /// Wake up the listener on `addr` once the server stops by connecting to
+    /// it, so that it notices without waiting for the next client. That's
+    /// only a best effort, the connections accepted after are closed right
+    /// away either way.
+    pub fn wake_tcp_on_stop(&self, addr: SocketAddr) {
+        self.on_stop(move || {
+            let _ = TcpStream::connect_timeout(&reachable(addr), Duration::from_secs(1));
+        });
+    }
+
+    /// Stop once the process gets SIGINT or SIGTERM. A second signal exits
+    /// right away, without waiting for the queries in flight.
+    #[cfg(unix)]
+    pub fn stop_on_signals(&self) -> Result<()> {
+        signals::watch(self.clone())
+    }
+}
+
+/// Where to reach a socket bound to `addr` from the same host, which for
+/// the unspecified addresses is the loopback one
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// Only a few things are safe to do in a signal handler, so the handler
+/// merely writes to a pipe, and a thread reading from it does the rest
+#[cfg(unix)]
+mod signals {
+    use std::{
+        fs::File,
+        io::{self, Read},
+        os::fd::FromRawFd,
+        process,
+        sync::atomic::{AtomicI32, Ordering},
+        thread,
+    };
+
+    use log::{info, warn};
+
+    use super::Shutdown;
+    use crate::error::Result;
+
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handle(_signal: libc::c_int) {
+        let byte = 1u8;
+        unsafe {
+            libc::write(
+                PIPE.load(Ordering::Relaxed),
+                &byte as *const u8 as *const libc::c_void,
+                1,
+            );
+        }
+    }
+
+    pub fn watch(shutdown: Shutdown) -> Result<()> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        PIPE.store(fds[1], Ordering::Relaxed);
+        let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        thread::spawn(move || {
+            let mut byte = [0];
+            if pipe.read_exact(&mut byte).is_err() {
+                return;
+            }
+            info!("Shutting down, waiting for the queries in flight");
+            shutdown.stop();
+
+            if pipe.read_exact(&mut byte).is_ok() {
+                warn!("Shutting down right away");
+                process::exit(1);
+            }
+        });
+
+        Ok(())
+    }
+}
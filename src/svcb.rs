@@ -0,0 +1,324 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_record::{base64, quote, unbase64},
+    error::{DnsError, Result},
+};
+
+const KEY_MANDATORY: u16 = 0;
+const KEY_ALPN: u16 = 1;
+const KEY_NO_DEFAULT_ALPN: u16 = 2;
+const KEY_PORT: u16 = 3;
+const KEY_IPV4HINT: u16 = 4;
+const KEY_ECH: u16 = 5;
+const KEY_IPV6HINT: u16 = 6;
+
+/// The name of a key in presentation format, e.g. `alpn`, or `key123` for
+/// the ones without a name
+fn key_name(key: u16) -> String {
+    match key {
+        KEY_MANDATORY => "mandatory".to_string(),
+        KEY_ALPN => "alpn".to_string(),
+        KEY_NO_DEFAULT_ALPN => "no-default-alpn".to_string(),
+        KEY_PORT => "port".to_string(),
+        KEY_IPV4HINT => "ipv4hint".to_string(),
+        KEY_ECH => "ech".to_string(),
+        KEY_IPV6HINT => "ipv6hint".to_string(),
+        key => format!("key{}", key),
+    }
+}
+
+/// The key a name in presentation format stands for, the reverse of
+/// `key_name`
+fn key_num(name: &str) -> Result<u16> {
+    match name.to_ascii_lowercase().as_str() {
+        "mandatory" => Ok(KEY_MANDATORY),
+        "alpn" => Ok(KEY_ALPN),
+        "no-default-alpn" => Ok(KEY_NO_DEFAULT_ALPN),
+        "port" => Ok(KEY_PORT),
+        "ipv4hint" => Ok(KEY_IPV4HINT),
+        "ech" => Ok(KEY_ECH),
+        "ipv6hint" => Ok(KEY_IPV6HINT),
+        name => name
+            .strip_prefix("key")
+            .and_then(|num| num.parse().ok())
+            .ok_or_else(|| DnsError::Parse(format!("unknown SvcParam key {}", name))),
+    }
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Lists of keys and addresses are made up of values of `size` bytes each,
+/// with nothing left over
+fn check_multiple(value: &[u8], size: usize, key: &str) -> Result<()> {
+    match value.len() % size {
+        0 => Ok(()),
+        _ => Err(DnsError::InvalidRecord(format!(
+            "{} SvcParam must be a multiple of {} bytes long",
+            key, size
+        ))),
+    }
+}
+
+/// The SvcParams of an SVCB or HTTPS record (RFC 9460), which tell a client how
+/// to connect to a service: which protocols it speaks, on which port, and
+/// which addresses it can be reached on. The keys a client needs for setting
+/// up a connection are decoded, any others are kept as raw key/value pairs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SvcParams {
+    /// Keys the client must understand in order to use the record
+    pub mandatory: Vec<u16>,
+    /// Protocols supported by the service, e.g. `h2` or `h3`
+    pub alpn: Vec<String>,
+    /// Whether the service lacks support for the default protocol of its scheme
+    pub no_default_alpn: bool,
+    pub port: Option<u16>,
+    pub ipv4hint: Vec<Ipv4Addr>,
+    pub ipv6hint: Vec<Ipv6Addr>,
+    /// The ECHConfigList for encrypting the ClientHello, left empty when the
+    /// service doesn't support Encrypted Client Hello
+    pub ech: Vec<u8>,
+    /// Every other key along with its undecoded value
+    pub other: Vec<(u16, Vec<u8>)>,
+}
+
+impl SvcParams {
+    /// Read the params filling up the remaining `len` bytes of a record
+    pub fn read(buffer: &mut BytePacketBuffer, len: usize) -> Result<SvcParams> {
+        let mut params = SvcParams::default();
+
+        let end = buffer.pos() + len;
+        while buffer.pos() < end {
+            let key = buffer.read_u16()?;
+            let value_len = buffer.read_u16()? as usize;
+            if buffer.pos() + value_len > end {
+                return Err(DnsError::InvalidRecord(format!(
+                    "SvcParam {} runs past the end of the record",
+                    key
+                )));
+            }
+            let value = buffer.read_bytes(value_len)?;
+
+            match key {
+                KEY_MANDATORY => {
+                    check_multiple(&value, 2, "mandatory")?;
+                    params.mandatory = value
+                        .chunks_exact(2)
+                        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                        .collect();
+                }
+                KEY_ALPN => {
+                    // A list of length prefixed protocol ids
+                    let mut pos = 0;
+                    while pos < value.len() {
+                        let id_len = value[pos] as usize;
+                        let id = value.get(pos + 1..pos + 1 + id_len).ok_or_else(|| {
+                            DnsError::InvalidRecord(
+                                "alpn id runs past the end of the SvcParam".to_string(),
+                            )
+                        })?;
+                        params.alpn.push(String::from_utf8_lossy(id).to_string());
+                        pos += 1 + id_len;
+                    }
+                }
+                KEY_NO_DEFAULT_ALPN => params.no_default_alpn = true,
+                KEY_PORT => {
+                    if value.len() != 2 {
+                        return Err(DnsError::InvalidRecord(
+                            "port SvcParam must be two bytes long".to_string(),
+                        ));
+                    }
+                    params.port = Some(u16::from_be_bytes([value[0], value[1]]));
+                }
+                KEY_IPV4HINT => {
+                    check_multiple(&value, 4, "ipv4hint")?;
+                    params.ipv4hint = value
+                        .chunks_exact(4)
+                        .map(|b| Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+                        .collect();
+                }
+                KEY_ECH => params.ech = value,
+                KEY_IPV6HINT => {
+                    check_multiple(&value, 16, "ipv6hint")?;
+                    params.ipv6hint = value
+                        .chunks_exact(16)
+                        .map(|b| Ipv6Addr::from(<[u8; 16]>::try_from(b).unwrap()))
+                        .collect();
+                }
+                _ => params.other.push((key, value)),
+            }
+        }
+
+        Ok(params)
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        let mut entries: Vec<(u16, Vec<u8>)> = Vec::new();
+
+        if !self.mandatory.is_empty() {
+            let value = self
+                .mandatory
+                .iter()
+                .flat_map(|k| k.to_be_bytes())
+                .collect();
+            entries.push((KEY_MANDATORY, value));
+        }
+        if !self.alpn.is_empty() {
+            let mut value = Vec::new();
+            for id in &self.alpn {
+                if id.len() > 0xff {
+                    return Err(DnsError::InvalidRecord(
+                        "alpn id exceeds 255 characters of length".to_string(),
+                    ));
+                }
+                value.push(id.len() as u8);
+                value.extend_from_slice(id.as_bytes());
+            }
+            entries.push((KEY_ALPN, value));
+        }
+        if self.no_default_alpn {
+            entries.push((KEY_NO_DEFAULT_ALPN, Vec::new()));
+        }
+        if let Some(port) = self.port {
+            entries.push((KEY_PORT, port.to_be_bytes().to_vec()));
+        }
+        if !self.ipv4hint.is_empty() {
+            let value = self.ipv4hint.iter().flat_map(|a| a.octets()).collect();
+            entries.push((KEY_IPV4HINT, value));
+        }
+        if !self.ech.is_empty() {
+            entries.push((KEY_ECH, self.ech.clone()));
+        }
+        if !self.ipv6hint.is_empty() {
+            let value = self.ipv6hint.iter().flat_map(|a| a.octets()).collect();
+            entries.push((KEY_IPV6HINT, value));
+        }
+        entries.extend(self.other.iter().cloned());
+
+        // The keys have to appear in increasing order on the wire
+        entries.sort_by_key(|(key, _)| *key);
+
+        for (key, value) in entries {
+            buffer.write_u16(key)?;
+            buffer.write_u16(value.len() as u16)?;
+            for b in value {
+                buffer.write_u8(b)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse the params from the tokens of a zone file, the reverse of the
+    /// presentation format that `Display` writes. Values may be quoted, as in
+    /// `alpn="h2,h3"`, and keys without a name can be given as `key65000`.
+    pub fn parse(tokens: &[&str]) -> Result<SvcParams> {
+        let mut params = SvcParams::default();
+        let mut seen = HashSet::new();
+
+        let mut tokens = tokens.iter();
+        while let Some(token) = tokens.next() {
+            let (name, value) = match token.split_once('=') {
+                // The tokenizer splits a quoted value off from its key
+                Some((name, "")) => (name, tokens.next().copied().unwrap_or_default()),
+                Some((name, value)) => (name, value),
+                None => (*token, ""),
+            };
+            let key = key_num(name)?;
+            if !seen.insert(key) {
+                return Err(DnsError::Parse(format!(
+                    "SvcParam {} given more than once",
+                    key_name(key)
+                )));
+            }
+
+            let invalid = || DnsError::Parse(format!("invalid {} value {}", key_name(key), value));
+            let list = || value.split(',').filter(|item| !item.is_empty());
+            match key {
+                KEY_MANDATORY => {
+                    params.mandatory = list().map(key_num).collect::<Result<_>>()?;
+                    // The keys have to be sorted on the wire just like the
+                    // params themselves
+                    params.mandatory.sort_unstable();
+                }
+                KEY_ALPN => params.alpn = list().map(str::to_string).collect(),
+                KEY_NO_DEFAULT_ALPN => params.no_default_alpn = true,
+                KEY_PORT => params.port = Some(value.parse().map_err(|_| invalid())?),
+                KEY_IPV4HINT => {
+                    params.ipv4hint = list()
+                        .map(|addr| addr.parse().map_err(|_| invalid()))
+                        .collect::<Result<_>>()?;
+                }
+                KEY_ECH => params.ech = unbase64(value).ok_or_else(invalid)?,
+                KEY_IPV6HINT => {
+                    params.ipv6hint = list()
+                        .map(|addr| addr.parse().map_err(|_| invalid()))
+                        .collect::<Result<_>>()?;
+                }
+                _ => params.other.push((key, value.as_bytes().to_vec())),
+            }
+        }
+        params.other.sort();
+
+        Ok(params)
+    }
+}
+
+/// The params in the presentation format of RFC 9460, e.g.
+/// `alpn=h2,h3 port=443`, in increasing order of their keys
+impl fmt::Display for SvcParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut entries: Vec<(u16, Option<String>)> = Vec::new();
+
+        if !self.mandatory.is_empty() {
+            let keys: Vec<String> = self.mandatory.iter().map(|&k| key_name(k)).collect();
+            entries.push((KEY_MANDATORY, Some(keys.join(","))));
+        }
+        if !self.alpn.is_empty() {
+            entries.push((KEY_ALPN, Some(join(&self.alpn))));
+        }
+        if self.no_default_alpn {
+            entries.push((KEY_NO_DEFAULT_ALPN, None));
+        }
+        if let Some(port) = self.port {
+            entries.push((KEY_PORT, Some(port.to_string())));
+        }
+        if !self.ipv4hint.is_empty() {
+            entries.push((KEY_IPV4HINT, Some(join(&self.ipv4hint))));
+        }
+        if !self.ech.is_empty() {
+            entries.push((KEY_ECH, Some(base64(&self.ech))));
+        }
+        if !self.ipv6hint.is_empty() {
+            entries.push((KEY_IPV6HINT, Some(join(&self.ipv6hint))));
+        }
+        for (key, value) in &self.other {
+            entries.push((*key, Some(quote(value))));
+        }
+        entries.sort_by_key(|(key, _)| *key);
+
+        for (i, (key, value)) in entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", key_name(*key))?;
+            if let Some(value) = value {
+                write!(f, "={}", value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use crate::{
+    client::{self, QueryOptions},
+    dns_packet::DnsPacket,
+    error::Result,
+    pool::{self, ConnectionPool, MessageStream},
+    query_type::QueryType,
+};
+
+/// How many idle connections are kept open to each upstream for DNS over
+/// HTTPS
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+pub(crate) type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Connections are kept apart by both the address of the upstream and the
+/// name its certificate was checked against
+type ConnectionKey = (SocketAddr, String);
+
+/// An idle connection, along with when it was last used
+type IdleStream = (TlsStream, Instant);
+
+/// Sends queries over TLS (RFC 7858), so that nobody on the path to the
+/// upstream gets to see or tamper with them. The certificate of the upstream
+/// is validated against the Mozilla root certificates, for the name it's
+/// configured with.
+///
+/// Setting up a TLS session takes a couple of round trips, so connections are
+/// kept open after a query and reused for the queries after it to the same
+/// upstream, see `ConnectionPool`. The connections DNS over HTTPS queries are
+/// sent on are kept apart, since they take one request at a time.
+#[derive(Clone)]
+pub struct TlsClient {
+    config: Arc<ClientConfig>,
+    pool: ConnectionPool<ConnectionKey>,
+    /// The connections for DNS over HTTPS
+    idle: Arc<Mutex<HashMap<ConnectionKey, Vec<IdleStream>>>>,
+}
+
+impl fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsClient").finish_non_exhaustive()
+    }
+}
+
+impl Default for TlsClient {
+    fn default() -> TlsClient {
+        TlsClient::new()
+    }
+}
+
+impl TlsClient {
+    pub fn new() -> TlsClient {
+        TlsClient::with_roots(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        })
+    }
+
+    /// A client that validates certificates against `roots` instead of the
+    /// Mozilla root certificates, such as those of a private CA
+    pub fn with_roots(roots: RootCertStore) -> TlsClient {
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        TlsClient {
+            config: Arc::new(config),
+            pool: ConnectionPool::default(),
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Send a single query to `server`, whose certificate has to be valid for
+    /// `name`. That's either a host name or the address of the server itself.
+    pub fn query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: SocketAddr,
+        name: &str,
+        options: &QueryOptions,
+    ) -> Result<DnsPacket> {
+        let (packet, req_buffer) = client::build_query(qname, qtype, options)?;
+
+        debug!(
+            "Sending query {} for {} {:?} to {} over TLS",
+            packet.header.id, qname, qtype, server
+        );
+
+        let key = (server, name.to_string());
+        let mut res_buffer = self.pool.exchange(&key, &req_buffer, options.timeout, || {
+            self.connect_pipelined(server, name, options.timeout)
+        })?;
+
+        client::check_response(&packet, &mut res_buffer, options)
+    }
+
+    /// Run an exchange over a connection to `server`, reusing an idle one if
+    /// there is any. The connection is kept around for reuse when the
+    /// exchange succeeds. New connections give up on the server after
+    /// `timeout`.
+    pub(crate) fn with_connection<T>(
+        &self,
+        server: SocketAddr,
+        name: &str,
+        timeout: Duration,
+        mut exchange: impl FnMut(&mut TlsStream) -> Result<T>,
+    ) -> Result<T> {
+        // The upstream may well have closed an idle connection in the
+        // meantime, in which case we start over on a fresh one.
+        let key = (server, name.to_string());
+        if let Some(mut stream) = self.take_idle(&key) {
+            match exchange(&mut stream) {
+                Ok(result) => {
+                    self.release(key, stream);
+                    return Ok(result);
+                }
+                Err(e) => debug!("Idle connection to {} failed: {}", server, e),
+            }
+        }
+
+        let mut stream = self.connect(server, name, timeout)?;
+        let result = exchange(&mut stream)?;
+        self.release(key, stream);
+
+        Ok(result)
+    }
+
+    fn connect(&self, server: SocketAddr, name: &str, timeout: Duration) -> Result<TlsStream> {
+        let server_name = ServerName::try_from(name.to_string())?;
+        let connection = ClientConnection::new(self.config.clone(), server_name)?;
+
+        let socket = TcpStream::connect_timeout(&server, timeout)?;
+        socket.set_read_timeout(Some(timeout))?;
+        socket.set_write_timeout(Some(timeout))?;
+
+        Ok(StreamOwned::new(connection, socket))
+    }
+
+    /// Connect to `server` and go through the handshake, for a connection
+    /// to send queries on without waiting for the responses to those before
+    fn connect_pipelined(
+        &self,
+        server: SocketAddr,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<PipelinedTls> {
+        let StreamOwned {
+            conn: mut session,
+            sock: socket,
+        } = self.connect(server, name, timeout)?;
+        while session.is_handshaking() {
+            session.complete_io(&mut &socket)?;
+        }
+        socket.set_read_timeout(Some(pool::POLL_INTERVAL))?;
+
+        Ok(PipelinedTls {
+            session: Mutex::new(session),
+            socket,
+        })
+    }
+
+    /// An idle connection, unless they've all been idle for so long that the
+    /// server has most likely closed them
+    fn take_idle(&self, key: &ConnectionKey) -> Option<TlsStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.get_mut(key)?;
+        streams.retain(|(_, last_used)| last_used.elapsed() < pool::IDLE_TIMEOUT);
+        streams.pop().map(|(stream, _)| stream)
+    }
+
+    fn release(&self, key: ConnectionKey, stream: TlsStream) {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(key).or_default();
+        if streams.len() < MAX_IDLE_CONNECTIONS {
+            streams.push((stream, Instant::now()));
+        }
+    }
+}
+
+/// A TLS connection that queries are written to while the responses are read
+/// off it on another thread. The socket is read and written directly, with
+/// rustls only encrypting and decrypting what goes over it, so that the lock
+/// on the session is never held while waiting for data to arrive.
+pub(crate) struct PipelinedTls {
+    session: Mutex<ClientConnection>,
+    socket: TcpStream,
+}
+
+impl PipelinedTls {
+    fn flush(&self, session: &mut ClientConnection) -> io::Result<()> {
+        while session.wants_write() {
+            session.write_tls(&mut &self.socket)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MessageStream for PipelinedTls {
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.writer().write_all(message)?;
+        self.flush(&mut session)
+    }
+
+    fn receive(&self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        let mut data = [0; 4096];
+        let len = (&self.socket).read(&mut data)?;
+        if len == 0 {
+            return Ok(false);
+        }
+
+        let mut session = self.session.lock().unwrap();
+        let mut data = &data[..len];
+        while !data.is_empty() {
+            session.read_tls(&mut data)?;
+            let state = session
+                .process_new_packets()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+            let start = buf.len();
+            buf.resize(start + state.plaintext_bytes_to_read(), 0);
+            session.reader().read_exact(&mut buf[start..])?;
+            if state.peer_has_closed() {
+                return Ok(false);
+            }
+        }
+        // Such as the acknowledgement of a key update
+        self.flush(&mut session)?;
+
+        Ok(true)
+    }
+
+    fn close(&self) {
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
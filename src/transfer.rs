@@ -0,0 +1,322 @@
+use std::{
+    mem,
+    net::{SocketAddr, TcpStream},
+};
+
+use log::{debug, info};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    client::{read_message, write_message, QueryOptions},
+    dns_packet::{DnsPacket, QueryBuilder},
+    dns_record::DnsRecord,
+    error::{DnsError, Result},
+    query_type::QueryType,
+    result_code::ResultCode,
+    tsig::{self, StreamVerifier, TsigKey},
+    zone::{same_data, Zone},
+};
+
+/// The most records a transfer may bring, so that a server that never ends
+/// its stream can't have us hold on to all of it
+const MAX_RECORDS: usize = 10_000_000;
+
+/// Whether `serial` is newer than `than`, in the serial number arithmetic of
+/// RFC 1982 that lets serials wrap around
+pub fn is_newer(serial: u32, than: u32) -> bool {
+    serial != than && serial.wrapping_sub(than) < 1 << 31
+}
+
+fn serial(record: &DnsRecord) -> Option<u32> {
+    match record {
+        DnsRecord::SOA { serial, .. } => Some(*serial),
+        _ => None,
+    }
+}
+
+/// Pull the whole of the zone at `origin` from `server` (AXFR, RFC 5936).
+/// With a `key`, the request is signed with it, and the responses have to be
+/// signed with it as well.
+pub fn axfr(
+    origin: &str,
+    server: SocketAddr,
+    options: &QueryOptions,
+    key: Option<&TsigKey>,
+) -> Result<Zone> {
+    let origin = origin.trim_end_matches('.').to_ascii_lowercase();
+    match transfer(&origin, None, server, options, key)? {
+        Changes::Full(records) => {
+            info!(
+                "Transferred {} records of {} from {}",
+                records.len(),
+                origin,
+                server
+            );
+            Ok(Zone { origin, records })
+        }
+        // Only the answers to IXFR come in any other form
+        _ => unreachable!(),
+    }
+}
+
+/// Bring `zone` up to date with `server`, pulling only what changed since
+/// its serial (IXFR, RFC 1995). A server that doesn't have the history sends
+/// the whole zone instead, which replaces the records. A zone without an SOA
+/// record has no serial to start from, and is transferred in full. Returns
+/// whether anything changed. `key` is the same as for `axfr`.
+pub fn ixfr(
+    zone: &mut Zone,
+    server: SocketAddr,
+    options: &QueryOptions,
+    key: Option<&TsigKey>,
+) -> Result<bool> {
+    let Some(soa) = zone.soa().cloned() else {
+        *zone = axfr(&zone.origin, server, options, key)?;
+        return Ok(true);
+    };
+
+    match transfer(&zone.origin, Some(&soa), server, options, key)? {
+        Changes::UpToDate => {
+            debug!("{} is up to date with {}", zone.origin, server);
+            Ok(false)
+        }
+        Changes::Full(records) => {
+            info!(
+                "Transferred {} records of {} from {}",
+                records.len(),
+                zone.origin,
+                server
+            );
+            zone.records = records;
+            Ok(true)
+        }
+        Changes::Incremental(diffs) => {
+            if diffs[0].deleted.first().and_then(serial) != serial(&soa) {
+                return Err(DnsError::InvalidResponse(format!(
+                    "The changes to {} from {} don't start from our serial",
+                    zone.origin, server
+                )));
+            }
+
+            for diff in &diffs {
+                for record in &diff.deleted {
+                    if let Some(i) = zone.records.iter().position(|r| same_data(r, record)) {
+                        zone.records.remove(i);
+                    }
+                }
+                for record in &diff.added {
+                    if !zone.records.iter().any(|r| same_data(r, record)) {
+                        zone.records.push(record.clone());
+                    }
+                }
+            }
+            info!(
+                "Applied {} changes to {} from {}",
+                diffs.len(),
+                zone.origin,
+                server
+            );
+            Ok(true)
+        }
+    }
+}
+
+/// One version of a zone to the next
+#[derive(Debug, Default)]
+struct Diff {
+    /// Starting with the SOA record of the older version
+    deleted: Vec<DnsRecord>,
+    /// Starting with the SOA record of the newer version
+    added: Vec<DnsRecord>,
+}
+
+/// What a transfer brought
+#[derive(Debug)]
+enum Changes {
+    /// The serial we have is still the latest
+    UpToDate,
+    /// All the records of the zone
+    Full(Vec<DnsRecord>),
+    /// The changes since the serial we have, oldest first
+    Incremental(Vec<Diff>),
+}
+
+/// How far into the stream of records a transfer is. The stream starts and
+/// ends with the SOA record of the latest version of the zone, with either
+/// the rest of the zone in between, or the changes since some earlier
+/// version. Each change starts off with the SOA record of the version it
+/// changes, followed by the records it deletes, the SOA record of the version
+/// it makes, and the records it adds.
+#[derive(Debug, Default)]
+struct Stream {
+    /// The SOA record of the latest version, once it's been seen
+    latest: Option<DnsRecord>,
+    part: Part,
+}
+
+#[derive(Debug, Default)]
+enum Part {
+    #[default]
+    Start,
+    /// Right after the first SOA record, which tells whether the zone or the
+    /// changes follow
+    Soa,
+    Full(Vec<DnsRecord>),
+    Deleting(Vec<Diff>),
+    Adding(Vec<Diff>),
+}
+
+impl Stream {
+    /// Take in the next record, returning what the transfer brought once
+    /// it's over. `known` is the SOA record of the version of the zone we
+    /// already have, if any.
+    fn push(&mut self, record: DnsRecord, known: Option<&DnsRecord>) -> Result<Option<Changes>> {
+        let is_soa = serial(&record).is_some();
+        let is_latest = is_soa && serial(&record) == self.latest.as_ref().and_then(serial);
+
+        match (mem::take(&mut self.part), is_soa) {
+            (Part::Start, true) => {
+                // A server that has nothing newer for us sends just the SOA
+                if let (Some(latest), Some(known)) = (serial(&record), known.and_then(serial)) {
+                    if !is_newer(latest, known) {
+                        return Ok(Some(Changes::UpToDate));
+                    }
+                }
+                self.latest = Some(record);
+                self.part = Part::Soa;
+            }
+            (Part::Start, false) => {
+                return Err(DnsError::InvalidResponse(
+                    "Zone transfer doesn't start with an SOA record".to_string(),
+                ));
+            }
+            // The changes follow when there's a version of the zone to change
+            (Part::Soa, true) if known.is_some() => {
+                self.part = Part::Deleting(vec![Diff {
+                    deleted: vec![record],
+                    added: Vec::new(),
+                }]);
+            }
+            // A zone with nothing but its SOA record
+            (Part::Soa, true) => return Ok(self.latest.take().map(|soa| Changes::Full(vec![soa]))),
+            (Part::Soa, false) => {
+                self.part = Part::Full(self.latest.iter().cloned().chain([record]).collect());
+            }
+            (Part::Full(records), true) if is_latest => return Ok(Some(Changes::Full(records))),
+            (Part::Full(_), true) => {
+                return Err(DnsError::InvalidResponse(
+                    "Zone transfer ends with a different SOA record than it started with"
+                        .to_string(),
+                ));
+            }
+            (Part::Full(mut records), false) => {
+                records.push(record);
+                self.part = Part::Full(records);
+            }
+            (Part::Deleting(mut diffs), true) => {
+                diffs.last_mut().unwrap().added.push(record);
+                self.part = Part::Adding(diffs);
+            }
+            (Part::Deleting(mut diffs), false) => {
+                diffs.last_mut().unwrap().deleted.push(record);
+                self.part = Part::Deleting(diffs);
+            }
+            (Part::Adding(diffs), true) if is_latest => {
+                return Ok(Some(Changes::Incremental(diffs)));
+            }
+            (Part::Adding(mut diffs), true) => {
+                diffs.push(Diff {
+                    deleted: vec![record],
+                    added: Vec::new(),
+                });
+                self.part = Part::Deleting(diffs);
+            }
+            (Part::Adding(mut diffs), false) => {
+                diffs.last_mut().unwrap().added.push(record);
+                self.part = Part::Adding(diffs);
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Ask `server` for the zone at `origin`, with IXFR when we have a version of
+/// it already, and read the records off the stream of messages it answers
+/// with until the stream is over
+fn transfer(
+    origin: &str,
+    known: Option<&DnsRecord>,
+    server: SocketAddr,
+    options: &QueryOptions,
+    key: Option<&TsigKey>,
+) -> Result<Changes> {
+    let qtype = match known {
+        Some(_) => QueryType::IXFR,
+        None => QueryType::AXFR,
+    };
+    let mut query = QueryBuilder::new()
+        .recursion_desired(false)
+        .question(origin, qtype)
+        .build();
+    if let Some(soa) = known {
+        query.authorities.push(soa.clone());
+    }
+    let mut req_buffer = BytePacketBuffer::new();
+    query.write(&mut req_buffer)?;
+    let mut verifier = match key {
+        Some(key) => Some(StreamVerifier::new(
+            key,
+            &tsig::sign(&mut req_buffer, key, None)?,
+        )),
+        None => None,
+    };
+
+    let mut stream = TcpStream::connect_timeout(&server, options.timeout)?;
+    stream.set_read_timeout(Some(options.timeout))?;
+    write_message(&mut stream, &req_buffer)?;
+
+    let mut progress = Stream::default();
+    let mut count = 0;
+    loop {
+        let mut res_buffer = read_message(&mut stream)?;
+        if let Some(verifier) = &mut verifier {
+            verifier.verify(&res_buffer.buf)?;
+        }
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+        if response.header.id != query.header.id {
+            return Err(DnsError::InvalidResponse(format!(
+                "Response ID {} doesn't match query ID {}",
+                response.header.id, query.header.id
+            )));
+        }
+        if response.header.rescode != ResultCode::NOERROR {
+            return Err(DnsError::InvalidResponse(format!(
+                "{} answered the transfer of {} with {:?}",
+                server, origin, response.header.rescode
+            )));
+        }
+        if response.answers.is_empty() {
+            return Err(DnsError::InvalidResponse(format!(
+                "{} sent no records for {}",
+                server, origin
+            )));
+        }
+
+        for record in response.answers {
+            count += 1;
+            if count > MAX_RECORDS {
+                return Err(DnsError::InvalidResponse(format!(
+                    "Transfer of {} from {} exceeds {} records",
+                    origin, server, MAX_RECORDS
+                )));
+            }
+            if let Some(changes) = progress.push(record, known)? {
+                if let Some(verifier) = &verifier {
+                    verifier.finish()?;
+                }
+                return Ok(changes);
+            }
+        }
+    }
+}
@@ -0,0 +1,721 @@
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_header::DnsHeader,
+    dns_record::unbase64,
+    error::{DnsError, Result},
+};
+
+/// The type of TSIG records, which only ever travel at the end of signed
+/// messages
+const TYPE_TSIG: u16 = 250;
+
+const CLASS_ANY: u16 = 255;
+
+/// How far the clocks of the two ends may be apart, in seconds, as RFC 8945
+/// recommends
+pub const DEFAULT_FUDGE: u16 = 300;
+
+/// How many messages of a zone transfer may come unsigned in a row, between
+/// the signed ones (RFC 8945, section 5.3.1)
+const MAX_UNSIGNED: usize = 99;
+
+/// The signature was made with a key we don't have, or with another
+/// algorithm than the one we have the key for
+pub const BADKEY: u16 = 17;
+/// The MAC doesn't check out
+pub const BADSIG: u16 = 16;
+/// The message was signed too long ago, or too far in the future, for the
+/// fudge it allows
+pub const BADTIME: u16 = 18;
+/// The MAC was cut short, which we don't accept
+pub const BADTRUNC: u16 = 22;
+
+/// The MACs of RFC 8945, section 6, other than HMAC-MD5, which is long broken
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha1,
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl Algorithm {
+    /// The name the algorithm goes by in TSIG records, without the trailing
+    /// dot
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::HmacSha1 => "hmac-sha1",
+            Algorithm::HmacSha256 => "hmac-sha256",
+            Algorithm::HmacSha384 => "hmac-sha384",
+            Algorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    /// The length of the MACs, in bytes
+    pub fn mac_len(&self) -> usize {
+        match self {
+            Algorithm::HmacSha1 => 20,
+            Algorithm::HmacSha256 => 32,
+            Algorithm::HmacSha384 => 48,
+            Algorithm::HmacSha512 => 64,
+        }
+    }
+
+    #[cfg(feature = "tsig")]
+    fn ring(&self) -> ring::hmac::Algorithm {
+        match self {
+            Algorithm::HmacSha1 => ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            Algorithm::HmacSha256 => ring::hmac::HMAC_SHA256,
+            Algorithm::HmacSha384 => ring::hmac::HMAC_SHA384,
+            Algorithm::HmacSha512 => ring::hmac::HMAC_SHA512,
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Algorithm> {
+        match s.trim_end_matches('.').to_ascii_lowercase().as_str() {
+            "hmac-sha1" => Ok(Algorithm::HmacSha1),
+            "hmac-sha256" => Ok(Algorithm::HmacSha256),
+            "hmac-sha384" => Ok(Algorithm::HmacSha384),
+            "hmac-sha512" => Ok(Algorithm::HmacSha512),
+            _ => Err(DnsError::Unsupported(format!("TSIG algorithm {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A secret shared with another server or client, which both ends know by
+/// the same name. Signing messages with it proves that they come from
+/// someone who has it, and that they weren't tampered with on the way.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TsigKey {
+    /// The name of the key, lowercase and without the trailing dot
+    pub name: String,
+    pub algorithm: Algorithm,
+    secret: Vec<u8>,
+}
+
+/// Leaves out the secret, so that it doesn't end up in the logs
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+impl TsigKey {
+    /// A key with the given secret, which takes a build with the `tsig`
+    /// feature
+    pub fn new(name: &str, algorithm: Algorithm, secret: &[u8]) -> Result<TsigKey> {
+        if cfg!(not(feature = "tsig")) {
+            return Err(DnsError::Unsupported(
+                "TSIG requires the tsig feature".to_string(),
+            ));
+        }
+        if secret.is_empty() {
+            return Err(DnsError::Parse(format!("TSIG key {} has no secret", name)));
+        }
+
+        Ok(TsigKey {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            algorithm,
+            secret: secret.to_vec(),
+        })
+    }
+
+    /// The first key of `keys` named `name`
+    pub fn find<'a>(keys: &'a [TsigKey], name: &str) -> Result<&'a TsigKey> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        keys.iter()
+            .find(|key| key.name == name)
+            .ok_or_else(|| DnsError::Parse(format!("Unknown TSIG key {}", name)))
+    }
+
+    #[cfg(feature = "tsig")]
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let key = ring::hmac::Key::new(self.algorithm.ring(), &self.secret);
+        ring::hmac::sign(&key, data).as_ref().to_vec()
+    }
+
+    #[cfg(feature = "tsig")]
+    fn verifies(&self, data: &[u8], mac: &[u8]) -> bool {
+        let key = ring::hmac::Key::new(self.algorithm.ring(), &self.secret);
+        ring::hmac::verify(&key, data, mac).is_ok()
+    }
+
+    // Keys can't be made without the feature, see `new`
+    #[cfg(not(feature = "tsig"))]
+    fn mac(&self, _data: &[u8]) -> Vec<u8> {
+        unreachable!("TSIG keys require the tsig feature")
+    }
+
+    #[cfg(not(feature = "tsig"))]
+    fn verifies(&self, _data: &[u8], _mac: &[u8]) -> bool {
+        unreachable!("TSIG keys require the tsig feature")
+    }
+}
+
+/// `[ALGORITHM:]NAME:SECRET`, the way dig's `-y` takes keys, with the secret
+/// in base64, e.g. `hmac-sha256:transfer-key:c2VjcmV0`. The algorithm is
+/// HMAC-SHA256 unless given.
+impl FromStr for TsigKey {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<TsigKey> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let (algorithm, name, secret) = match fields[..] {
+            [name, secret] => (Algorithm::HmacSha256, name, secret),
+            [algorithm, name, secret] => (algorithm.parse()?, name, secret),
+            _ => {
+                return Err(DnsError::Parse(format!(
+                    "Expected [ALGORITHM:]NAME:SECRET for a TSIG key: {}",
+                    s
+                )))
+            }
+        };
+
+        let secret = unbase64(secret)
+            .ok_or_else(|| DnsError::Parse(format!("Invalid secret for TSIG key {}", name)))?;
+        TsigKey::new(name, algorithm, &secret)
+    }
+}
+
+/// The TSIG record at the end of a signed message (RFC 8945, section 4.2)
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Tsig {
+    /// The name of the key the message was signed with
+    key_name: String,
+    algorithm: String,
+    /// In seconds since the epoch, of which only 48 bits go on the wire
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    /// The ID of the message as it was signed, which forwarders may change
+    original_id: u16,
+    /// One of `BADKEY` and the like, or zero
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl Tsig {
+    fn new(key: &TsigKey, original_id: u16) -> Tsig {
+        Tsig {
+            key_name: key.name.clone(),
+            algorithm: key.algorithm.name().to_string(),
+            time_signed: now(),
+            fudge: DEFAULT_FUDGE,
+            mac: Vec::new(),
+            original_id,
+            error: 0,
+            other: Vec::new(),
+        }
+    }
+
+    /// The data of the record, which comes after its owner name, the key name
+    fn read(buffer: &mut BytePacketBuffer, key_name: String) -> Result<Tsig> {
+        let mut algorithm = String::new();
+        buffer.read_qname(&mut algorithm)?;
+        let time_signed = ((buffer.read_u16()? as u64) << 32) | buffer.read_u32()? as u64;
+        let fudge = buffer.read_u16()?;
+        let mac_len = buffer.read_u16()?;
+        let mac = buffer.read_bytes(mac_len as usize)?;
+        let original_id = buffer.read_u16()?;
+        let error = buffer.read_u16()?;
+        let other_len = buffer.read_u16()?;
+        let other = buffer.read_bytes(other_len as usize)?;
+
+        Ok(Tsig {
+            key_name,
+            algorithm,
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    /// Write the whole record, which is never compressed
+    fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_qname_uncompressed(&self.key_name)?;
+        buffer.write_u16(TYPE_TSIG)?;
+        buffer.write_u16(CLASS_ANY)?;
+        buffer.write_u32(0)?;
+
+        let len_pos = buffer.pos();
+        buffer.write_u16(0)?;
+        buffer.write_qname_uncompressed(&self.algorithm)?;
+        self.write_timers(buffer)?;
+        buffer.write_u16(self.mac.len() as u16)?;
+        for byte in &self.mac {
+            buffer.write_u8(*byte)?;
+        }
+        buffer.write_u16(self.original_id)?;
+        buffer.write_u16(self.error)?;
+        buffer.write_u16(self.other.len() as u16)?;
+        for byte in &self.other {
+            buffer.write_u8(*byte)?;
+        }
+
+        let len = buffer.pos() - (len_pos + 2);
+        buffer.set_u16(len_pos, len as u16)?;
+
+        Ok(())
+    }
+
+    fn write_timers(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_u16((self.time_signed >> 32) as u16)?;
+        buffer.write_u32(self.time_signed as u32)?;
+        buffer.write_u16(self.fudge)
+    }
+
+    /// The fields of the record the MAC is made over along with the message,
+    /// only the timers for the messages of a stream after the first
+    /// (RFC 8945, section 4.3.3)
+    fn variables(&self, timers_only: bool) -> Result<Vec<u8>> {
+        // Two names of up to 255 bytes along with the fixed size fields
+        let mut buffer = BytePacketBuffer::with_capacity(540 + self.other.len());
+        buffer.disable_compression();
+        if !timers_only {
+            buffer.write_qname_uncompressed(&self.key_name.to_ascii_lowercase())?;
+            buffer.write_u16(CLASS_ANY)?;
+            buffer.write_u32(0)?;
+            buffer.write_qname_uncompressed(&self.algorithm.to_ascii_lowercase())?;
+        }
+        self.write_timers(&mut buffer)?;
+        if !timers_only {
+            buffer.write_u16(self.error)?;
+            buffer.write_u16(self.other.len() as u16)?;
+            for byte in &self.other {
+                buffer.write_u8(*byte)?;
+            }
+        }
+
+        Ok(buffer.buf[..buffer.pos()].to_vec())
+    }
+
+    fn is_recent(&self) -> bool {
+        now().abs_diff(self.time_signed) <= self.fudge as u64
+    }
+}
+
+/// What came of checking the signature of a message, see `verify`
+#[derive(Debug)]
+pub enum Verified {
+    /// The message has no TSIG record
+    Unsigned,
+    /// The message was signed with `key`. The response to it is signed over
+    /// `mac` in turn.
+    Signed { key: TsigKey, mac: Vec<u8> },
+    /// The signature doesn't check out
+    Rejected(Rejection),
+}
+
+/// A signature that doesn't check out, along with what to tell the signer
+#[derive(Debug)]
+pub struct Rejection {
+    /// One of `BADKEY`, `BADSIG`, `BADTIME` and `BADTRUNC`
+    pub error: u16,
+    tsig: Tsig,
+    /// The key of a message that was signed too long ago, with which the
+    /// signer is told our time
+    key: Option<TsigKey>,
+}
+
+impl Rejection {
+    /// The name of the key the message claims to be signed with
+    pub fn key_name(&self) -> &str {
+        &self.tsig.key_name
+    }
+
+    /// Add the TSIG record of the response to the message, written to
+    /// `buffer`. Only messages with a MAC that checks out get a signed one,
+    /// carrying our time for the signer to see how far off its clock is.
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        let header = header(&buffer.buf[..buffer.pos()])?;
+        let mut tsig = Tsig {
+            key_name: self.tsig.key_name.clone(),
+            algorithm: self.tsig.algorithm.clone(),
+            time_signed: now(),
+            fudge: self.tsig.fudge,
+            mac: Vec::new(),
+            original_id: header.id,
+            error: self.error,
+            other: Vec::new(),
+        };
+
+        if let Some(key) = &self.key {
+            tsig.other = now().to_be_bytes()[2..].to_vec();
+            let mut data = prior_mac(&self.tsig.mac);
+            data.extend_from_slice(&buffer.buf[..buffer.pos()]);
+            data.extend(tsig.variables(false)?);
+            tsig.mac = key.mac(&data);
+        }
+
+        append(buffer, &tsig)
+    }
+}
+
+/// Sign the message written to `buffer`, adding a TSIG record to the end of
+/// it. A response is signed over the MAC of the request, `request_mac`.
+/// Returns the MAC, which the response to a request is checked against.
+pub fn sign(
+    buffer: &mut BytePacketBuffer,
+    key: &TsigKey,
+    request_mac: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+    sign_over(buffer, key, request_mac, false)
+}
+
+fn sign_over(
+    buffer: &mut BytePacketBuffer,
+    key: &TsigKey,
+    prior: Option<&[u8]>,
+    timers_only: bool,
+) -> Result<Vec<u8>> {
+    let message = &buffer.buf[..buffer.pos()];
+    let mut tsig = Tsig::new(key, header(message)?.id);
+
+    let mut data = prior.map(prior_mac).unwrap_or_default();
+    data.extend_from_slice(message);
+    data.extend(tsig.variables(timers_only)?);
+    tsig.mac = key.mac(&data);
+
+    append(buffer, &tsig)?;
+    Ok(tsig.mac)
+}
+
+/// How many bytes the TSIG record signing a message with `key` takes up, to
+/// leave room for it
+pub fn signature_len(key: &TsigKey) -> usize {
+    record_len(&key.name, key.algorithm.name(), key.algorithm.mac_len())
+}
+
+fn record_len(key_name: &str, algorithm: &str, mac_len: usize) -> usize {
+    // Each name takes a length byte per label plus the terminating zero, on
+    // top of the type, class, TTL and length of the record, and the timers,
+    // the lengths, the original ID and the error of its data
+    let name_len = |name: &str| name.len() + 2;
+    name_len(key_name) + 10 + name_len(algorithm) + 16 + mac_len
+}
+
+/// Check the signature of `message` against `keys`, which for a response is
+/// made over the MAC of the request, `request_mac`. A response to a request
+/// whose signature didn't check out is rejected for the same reason. Fails
+/// for messages with a TSIG record that isn't the last one, or that doesn't
+/// parse.
+pub fn verify(message: &[u8], keys: &[TsigKey], request_mac: Option<&[u8]>) -> Result<Verified> {
+    let Some((start, tsig)) = find(message)? else {
+        return Ok(Verified::Unsigned);
+    };
+
+    let reject = |error, key| {
+        Ok(Verified::Rejected(Rejection {
+            error,
+            tsig: tsig.clone(),
+            key,
+        }))
+    };
+    // A response telling us that our own signature didn't check out
+    if tsig.error != 0 {
+        return reject(tsig.error, None);
+    }
+    let key = keys.iter().find(|key| {
+        key.name.eq_ignore_ascii_case(&tsig.key_name)
+            && key.algorithm.name().eq_ignore_ascii_case(&tsig.algorithm)
+    });
+    let Some(key) = key else {
+        return reject(BADKEY, None);
+    };
+    if tsig.mac.len() < key.algorithm.mac_len() {
+        return reject(BADTRUNC, None);
+    }
+
+    let mut data = request_mac.map(prior_mac).unwrap_or_default();
+    data.extend(unsigned(message, start, tsig.original_id));
+    data.extend(tsig.variables(false)?);
+    if !key.verifies(&data, &tsig.mac) {
+        return reject(BADSIG, None);
+    }
+    if !tsig.is_recent() {
+        return reject(BADTIME, Some(key.clone()));
+    }
+
+    Ok(Verified::Signed {
+        key: key.clone(),
+        mac: tsig.mac,
+    })
+}
+
+/// Checks the signatures of the messages of a stream of responses, such as
+/// those of a zone transfer. Each is signed over the MAC of the one before,
+/// and only every so many have to be signed at all, the first and the last
+/// among them (RFC 8945, section 5.3.1).
+#[derive(Debug)]
+pub struct StreamVerifier {
+    key: TsigKey,
+    /// The MAC of the last signed message, or of the request before any
+    prior_mac: Vec<u8>,
+    /// The messages since the last signed one, which the next MAC covers
+    pending: Vec<u8>,
+    unsigned: usize,
+    first: bool,
+}
+
+impl StreamVerifier {
+    pub fn new(key: &TsigKey, request_mac: &[u8]) -> StreamVerifier {
+        StreamVerifier {
+            key: key.clone(),
+            prior_mac: request_mac.to_vec(),
+            pending: Vec::new(),
+            unsigned: 0,
+            first: true,
+        }
+    }
+
+    /// Check the next message of the stream
+    pub fn verify(&mut self, message: &[u8]) -> Result<()> {
+        let Some((start, tsig)) = find(message)? else {
+            self.unsigned += 1;
+            if self.first || self.unsigned > MAX_UNSIGNED {
+                return Err(self.error("isn't signed"));
+            }
+            self.pending.extend_from_slice(message);
+            return Ok(());
+        };
+
+        if !self.key.name.eq_ignore_ascii_case(&tsig.key_name)
+            || !self
+                .key
+                .algorithm
+                .name()
+                .eq_ignore_ascii_case(&tsig.algorithm)
+        {
+            return Err(self.error(&format!("is signed with key {}", tsig.key_name)));
+        }
+        if tsig.error != 0 {
+            return Err(self.error(&format!(
+                "rejects our signature with {}",
+                rcode_name(tsig.error)
+            )));
+        }
+
+        let mut data = prior_mac(&self.prior_mac);
+        data.append(&mut self.pending);
+        data.extend(unsigned(message, start, tsig.original_id));
+        data.extend(tsig.variables(!self.first)?);
+        if !self.key.verifies(&data, &tsig.mac) {
+            return Err(self.error("has a signature that doesn't check out"));
+        }
+        if !tsig.is_recent() {
+            return Err(self.error("was signed too long ago"));
+        }
+
+        self.prior_mac = tsig.mac;
+        self.unsigned = 0;
+        self.first = false;
+        Ok(())
+    }
+
+    /// Make sure that the stream ended on a signed message
+    pub fn finish(&self) -> Result<()> {
+        match self.unsigned {
+            0 => Ok(()),
+            _ => Err(self.error("ends unsigned")),
+        }
+    }
+
+    fn error(&self, reason: &str) -> DnsError {
+        DnsError::InvalidResponse(format!(
+            "The response signed with {} {}",
+            self.key.name, reason
+        ))
+    }
+}
+
+/// The name of a TSIG error, for telling the user about it
+pub fn rcode_name(error: u16) -> String {
+    match error {
+        BADSIG => "BADSIG".to_string(),
+        BADKEY => "BADKEY".to_string(),
+        BADTIME => "BADTIME".to_string(),
+        BADTRUNC => "BADTRUNC".to_string(),
+        error => format!("error {}", error),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn header(message: &[u8]) -> Result<DnsHeader> {
+    let mut header = DnsHeader::new();
+    header.read(&mut BytePacketBuffer::from_slice(message))?;
+    Ok(header)
+}
+
+/// A MAC as it's prepended to the data of the next one
+fn prior_mac(mac: &[u8]) -> Vec<u8> {
+    let mut data = (mac.len() as u16).to_be_bytes().to_vec();
+    data.extend_from_slice(mac);
+    data
+}
+
+/// Add `tsig` as the last record of the message written to `buffer`, making
+/// room for it if there isn't enough
+fn append(buffer: &mut BytePacketBuffer, tsig: &Tsig) -> Result<()> {
+    let resource_entries = header(&buffer.buf[..buffer.pos()])?.resource_entries;
+    let len = record_len(&tsig.key_name, &tsig.algorithm, tsig.mac.len()) + tsig.other.len();
+    if buffer.buf.len() < buffer.pos() + len {
+        buffer.buf.resize(buffer.pos() + len, 0);
+    }
+    tsig.write(buffer)?;
+    buffer.set_u16(10, resource_entries + 1)
+}
+
+/// Where the TSIG record of `message` starts, if it has one, and what it
+/// holds. It has to be the last record of the message.
+fn find(message: &[u8]) -> Result<Option<(usize, Tsig)>> {
+    let mut buffer = BytePacketBuffer::from_slice(message);
+    let mut header = DnsHeader::new();
+    header.read(&mut buffer)?;
+
+    for _ in 0..header.questions {
+        buffer.read_qname(&mut String::new())?;
+        buffer.step(4)?;
+    }
+
+    let records = header.answers as usize
+        + header.authoritative_entries as usize
+        + header.resource_entries as usize;
+    for i in 0..records {
+        let start = buffer.pos();
+        let mut name = String::new();
+        buffer.read_qname(&mut name)?;
+        let qtype = buffer.read_u16()?;
+        buffer.step(6)?;
+        let data_len = buffer.read_u16()? as usize;
+
+        if qtype == TYPE_TSIG {
+            if i + 1 != records || header.resource_entries == 0 {
+                return Err(DnsError::InvalidRecord(
+                    "A TSIG record has to be the last one of the message".to_string(),
+                ));
+            }
+            return Ok(Some((start, Tsig::read(&mut buffer, name)?)));
+        }
+        buffer.step(data_len)?;
+    }
+
+    Ok(None)
+}
+
+/// `message` as it was before the TSIG record at `start` was added, with the
+/// ID it had then
+fn unsigned(message: &[u8], start: usize, original_id: u16) -> Vec<u8> {
+    let mut data = message[..start].to_vec();
+    data[..2].copy_from_slice(&original_id.to_be_bytes());
+    let resource_entries = u16::from_be_bytes([data[10], data[11]]);
+    data[10..12].copy_from_slice(&(resource_entries - 1).to_be_bytes());
+    data
+}
+
+#[cfg(all(test, feature = "tsig"))]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener},
+        slice, thread,
+    };
+
+    use super::*;
+    use crate::{
+        client::QueryOptions, dns_packet::DnsPacket, dns_record::DnsRecord, transfer, zone::Zone,
+    };
+
+    const ZONE: &str = "$ORIGIN example.test.\n\
+                        $TTL 300\n\
+                        @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+                        @ IN NS ns1\n\
+                        www IN A 10.0.0.1\n";
+
+    fn key(secret: &[u8]) -> TsigKey {
+        TsigKey::new("test-key", Algorithm::HmacSha256, secret).unwrap()
+    }
+
+    /// A primary that checks the signature of the transfer it's asked for,
+    /// and signs every message of its answer with `signing_key`, each over
+    /// the MAC of the one before
+    fn primary(key: TsigKey, signing_key: TsigKey, messages: Vec<Vec<DnsRecord>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buf)).unwrap();
+            let mut prior_mac = match verify(&buf, slice::from_ref(&key), None).unwrap() {
+                Verified::Signed { mac, .. } => mac,
+                verified => panic!("{:?}", verified),
+            };
+
+            for (i, answers) in messages.into_iter().enumerate() {
+                let mut response = DnsPacket::response_to(&query).answers(answers).build();
+                let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+                response.write(&mut buffer).unwrap();
+                prior_mac = sign_over(&mut buffer, &signing_key, Some(&prior_mac), i > 0).unwrap();
+                stream
+                    .write_all(&(buffer.pos() as u16).to_be_bytes())
+                    .unwrap();
+                stream.write_all(&buffer.buf[..buffer.pos()]).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn transfers_are_signed_from_start_to_end() {
+        let zone = Zone::parse(ZONE).unwrap();
+        let soa = zone.soa().unwrap().clone();
+        let messages = vec![
+            vec![soa.clone(), zone.records[1].clone()],
+            vec![zone.records[2].clone()],
+            vec![soa],
+        ];
+
+        let key = key(b"secret");
+        let addr = primary(key.clone(), key.clone(), messages.clone());
+        let transferred =
+            transfer::axfr("example.test", addr, &QueryOptions::default(), Some(&key)).unwrap();
+        assert_eq!(transferred.records.len(), 3);
+
+        // A primary that signs its answers with something else than the key
+        let addr = primary(key.clone(), self::key(b"other secret"), messages);
+        assert!(
+            transfer::axfr("example.test", addr, &QueryOptions::default(), Some(&key)).is_err()
+        );
+    }
+}
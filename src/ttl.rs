@@ -0,0 +1,50 @@
+use crate::{dns_packet::DnsPacket, query_type::QueryType, result_code::ResultCode};
+
+/// Bounds on the TTLs of the responses relayed from upstreams and name
+/// servers, which are rewritten to fit before they're cached or passed on to
+/// clients. Records with a TTL of 0 are meant to be used once and never
+/// cached, so they're left as they are.
+#[derive(Clone, Debug, Default)]
+pub struct TtlPolicy {
+    /// Raise the TTLs below this many seconds to it
+    pub min_ttl: u32,
+    /// Lower the TTLs above this many seconds to it
+    pub max_ttl: Option<u32>,
+    /// Lower the TTLs of the authority section of negative answers, i.e.
+    /// `NXDOMAIN` or a successful response without any answers, to this many
+    /// seconds, which is how long they're cached for (RFC 2308). It takes
+    /// precedence over `min_ttl`.
+    pub max_negative_ttl: Option<u32>,
+}
+
+impl TtlPolicy {
+    pub fn new() -> TtlPolicy {
+        TtlPolicy::default()
+    }
+
+    /// Bring the TTLs of the records of a response within bounds
+    pub fn apply(&self, packet: &mut DnsPacket) {
+        for rec in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            if rec.query_type() == QueryType::OPT || rec.ttl() == 0 {
+                continue;
+            }
+            let ttl = rec.ttl().max(self.min_ttl);
+            rec.set_ttl(self.max_ttl.map_or(ttl, |max_ttl| ttl.min(max_ttl)));
+        }
+
+        let negative = packet.header.rescode == ResultCode::NXDOMAIN
+            || (packet.header.rescode == ResultCode::NOERROR && packet.answers.is_empty());
+        if let (true, Some(max_ttl)) = (negative, self.max_negative_ttl) {
+            for rec in &mut packet.authorities {
+                if rec.ttl() > max_ttl {
+                    rec.set_ttl(max_ttl);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use log::{info, warn};
+use rand::{rngs::OsRng, Rng};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_header::{DnsHeader, Opcode},
+    dns_packet::DnsPacket,
+    dns_question::DnsQuestion,
+    dns_record::{DnsRecord, CLASS_IN},
+    error::{DnsError, Result},
+    query_type::QueryType,
+    result_code::ResultCode,
+    transfer,
+    view::Subnet,
+    zone::{same_data, Zone},
+};
+
+/// The class of the prerequisites that a name or RRset doesn't exist, and of
+/// the records an update deletes one by one
+pub const CLASS_NONE: u16 = 254;
+
+/// The class of the prerequisites that a name or RRset exists, and of the
+/// updates that delete whole RRsets or names
+pub const CLASS_ANY: u16 = 255;
+
+/// An entry of the prerequisite or update section of an UPDATE, which means
+/// whatever its class says it does (RFC 2136, sections 2.4 and 2.5)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpdateRecord {
+    pub name: String,
+    pub qtype: QueryType,
+    pub class: u16,
+    pub ttl: u32,
+    /// The record itself, unless its data is empty, as it is when the entry
+    /// stands for a whole RRset or name
+    pub record: Option<DnsRecord>,
+}
+
+impl UpdateRecord {
+    fn new(name: &str, qtype: QueryType, class: u16) -> UpdateRecord {
+        UpdateRecord {
+            name: name.trim_end_matches('.').to_string(),
+            qtype,
+            class,
+            ttl: 0,
+            record: None,
+        }
+    }
+
+    fn with_record(mut record: DnsRecord, class: u16) -> UpdateRecord {
+        if class != CLASS_IN {
+            record.set_ttl(0);
+        }
+
+        UpdateRecord {
+            name: record.domain().to_string(),
+            qtype: record.query_type(),
+            class,
+            ttl: record.ttl(),
+            record: Some(record),
+        }
+    }
+
+    fn read(buffer: &mut BytePacketBuffer) -> Result<UpdateRecord> {
+        let start_pos = buffer.pos();
+        let mut name = String::new();
+        buffer.read_qname(&mut name)?;
+        let qtype = QueryType::from_num(buffer.read_u16()?);
+        let class = buffer.read_u16()?;
+        let ttl = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+
+        let record = if data_len > 0 {
+            buffer.seek(start_pos)?;
+            Some(DnsRecord::read_any_class(buffer)?.0)
+        } else {
+            None
+        };
+
+        Ok(UpdateRecord {
+            name,
+            qtype,
+            class,
+            ttl,
+            record,
+        })
+    }
+
+    fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        match &self.record {
+            Some(record) => {
+                let mut record = record.clone();
+                record.set_ttl(self.ttl);
+                record.write_class(buffer, self.class)?;
+            }
+            None => {
+                buffer.write_qname(&self.name)?;
+                buffer.write_u16(self.qtype.to_num())?;
+                buffer.write_u16(self.class)?;
+                buffer.write_u32(self.ttl)?;
+                buffer.write_u16(0)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An UPDATE message (RFC 2136), which makes changes to the records of
+/// `zone`, as long as its prerequisites all hold. The changes are made all
+/// together or not at all.
+///
+/// ```
+/// use std::net::Ipv4Addr;
+/// use dns_server::{update::Update, DnsRecord, QueryType};
+///
+/// // Move www over to a new address, as long as nobody else took it over
+/// let update = Update::new("example.test")
+///     .require_no_rrset("www.example.test", QueryType::CNAME)
+///     .delete_rrset("www.example.test", QueryType::A)
+///     .add_record(DnsRecord::A {
+///         domain: "www.example.test".to_string(),
+///         addr: Ipv4Addr::new(10, 0, 0, 2),
+///         ttl: 300,
+///     });
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Update {
+    pub id: u16,
+    /// The origin of the zone to change, without the trailing dot
+    pub zone: String,
+    pub prerequisites: Vec<UpdateRecord>,
+    pub updates: Vec<UpdateRecord>,
+}
+
+impl Update {
+    pub fn new(zone: &str) -> Update {
+        Update {
+            id: OsRng.gen(),
+            zone: zone.trim_end_matches('.').to_ascii_lowercase(),
+            prerequisites: Vec::new(),
+            updates: Vec::new(),
+        }
+    }
+
+    /// Only make the changes if `name` has records of any type
+    pub fn require_name(mut self, name: &str) -> Update {
+        let prerequisite = UpdateRecord::new(name, QueryType::ANY, CLASS_ANY);
+        self.prerequisites.push(prerequisite);
+        self
+    }
+
+    /// Only make the changes if `name` has no records at all
+    pub fn require_no_name(mut self, name: &str) -> Update {
+        let prerequisite = UpdateRecord::new(name, QueryType::ANY, CLASS_NONE);
+        self.prerequisites.push(prerequisite);
+        self
+    }
+
+    /// Only make the changes if `name` has records of type `qtype`
+    pub fn require_rrset(mut self, name: &str, qtype: QueryType) -> Update {
+        let prerequisite = UpdateRecord::new(name, qtype, CLASS_ANY);
+        self.prerequisites.push(prerequisite);
+        self
+    }
+
+    /// Only make the changes if `name` has no records of type `qtype`
+    pub fn require_no_rrset(mut self, name: &str, qtype: QueryType) -> Update {
+        let prerequisite = UpdateRecord::new(name, qtype, CLASS_NONE);
+        self.prerequisites.push(prerequisite);
+        self
+    }
+
+    /// Only make the changes if the RRset of `record` has it. All the records
+    /// required of one RRset together have to be exactly the ones it has.
+    pub fn require_record(mut self, mut record: DnsRecord) -> Update {
+        record.set_ttl(0);
+        self.prerequisites
+            .push(UpdateRecord::with_record(record, CLASS_IN));
+        self
+    }
+
+    /// Add `record` to the zone
+    pub fn add_record(mut self, record: DnsRecord) -> Update {
+        self.updates
+            .push(UpdateRecord::with_record(record, CLASS_IN));
+        self
+    }
+
+    /// Delete the records of `name` of type `qtype`
+    pub fn delete_rrset(mut self, name: &str, qtype: QueryType) -> Update {
+        self.updates.push(UpdateRecord::new(name, qtype, CLASS_ANY));
+        self
+    }
+
+    /// Delete all the records of `name`
+    pub fn delete_name(mut self, name: &str) -> Update {
+        self.updates
+            .push(UpdateRecord::new(name, QueryType::ANY, CLASS_ANY));
+        self
+    }
+
+    /// Delete the record with the same data as `record`
+    pub fn delete_record(mut self, record: DnsRecord) -> Update {
+        self.updates
+            .push(UpdateRecord::with_record(record, CLASS_NONE));
+        self
+    }
+
+    /// Parse an UPDATE message, whose single entry in the zone section names
+    /// the zone, and whose answer and authority sections hold the
+    /// prerequisites and the updates
+    pub fn read(buffer: &mut BytePacketBuffer) -> Result<Update> {
+        let mut header = DnsHeader::new();
+        header.read(buffer)?;
+        if header.opcode != Opcode::UPDATE {
+            return Err(DnsError::InvalidRecord(format!(
+                "opcode {} isn't an UPDATE",
+                header.opcode
+            )));
+        }
+
+        let mut zone = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0));
+        zone.read(buffer)?;
+        if header.questions != 1 || zone.qtype != QueryType::SOA {
+            return Err(DnsError::InvalidRecord(
+                "the zone section of an UPDATE has to be a single SOA entry".to_string(),
+            ));
+        }
+
+        let prerequisites = (0..header.answers)
+            .map(|_| UpdateRecord::read(buffer))
+            .collect::<Result<Vec<_>>>()?;
+        let updates = (0..header.authoritative_entries)
+            .map(|_| UpdateRecord::read(buffer))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Update {
+            id: header.id,
+            zone: zone.name.to_ascii_lowercase(),
+            prerequisites,
+            updates,
+        })
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        let mut header = DnsHeader::new();
+        header.id = self.id;
+        header.opcode = Opcode::UPDATE;
+        header.questions = 1;
+        header.answers = self.prerequisites.len() as u16;
+        header.authoritative_entries = self.updates.len() as u16;
+        header.write(buffer)?;
+
+        DnsQuestion::new(self.zone.clone(), QueryType::SOA).write(buffer)?;
+        for record in self.prerequisites.iter().chain(&self.updates) {
+            record.write(buffer)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A zone that clients may make changes to with UPDATE messages, such as a
+/// DHCP server adding the names of the hosts it hands out addresses to. It's
+/// answered from the same way as the local zones. The changes are written
+/// back to its zone file, if it was loaded from one, so that they survive a
+/// restart, though the comments and layout of the file don't.
+///
+/// Only the clients that sign their updates with one of `update_keys` may make
+/// changes, and everyone else is refused, unless `allow_unsigned` is set.
+#[derive(Clone, Debug)]
+pub struct DynamicZone {
+    /// The name at the top of the zone, without the trailing dot
+    pub origin: String,
+    /// The clients whose updates are taken without a signature, with
+    /// `allow_unsigned`
+    pub allow: Vec<Subnet>,
+    /// Take unsigned updates from the clients in `allow`. The address is all
+    /// there is to tell them by then, which anyone can send UDP packets from,
+    /// so this is insecure and off by default.
+    pub allow_unsigned: bool,
+    /// The names of the TSIG keys that updates may be signed with
+    pub update_keys: Vec<String>,
+    zone: Arc<RwLock<Zone>>,
+    path: Option<PathBuf>,
+}
+
+impl DynamicZone {
+    /// Serve `zone`, which needs an SOA record for its serial to be moved on
+    /// with every change, keeping the changes in memory only
+    pub fn new(zone: Zone, allow: Vec<Subnet>) -> Result<DynamicZone> {
+        if zone.soa().is_none() {
+            return Err(DnsError::Parse(format!(
+                "{} has no SOA record, which a zone taking updates needs",
+                zone.origin
+            )));
+        }
+
+        Ok(DynamicZone {
+            origin: zone.origin.to_ascii_lowercase(),
+            allow,
+            allow_unsigned: false,
+            update_keys: Vec::new(),
+            zone: Arc::new(RwLock::new(zone)),
+            path: None,
+        })
+    }
+
+    /// Serve the zone file at `path`, writing it over with every change
+    pub fn load<P: AsRef<Path>>(path: P, allow: Vec<Subnet>) -> Result<DynamicZone> {
+        let path = path.as_ref();
+        let mut zone = DynamicZone::new(Zone::load(path)?, allow)?;
+        zone.path = Some(path.to_path_buf());
+        Ok(zone)
+    }
+
+    /// Whether `qname` is the origin or any name below it
+    pub fn contains(&self, qname: &str) -> bool {
+        self.zone.read().unwrap().contains(qname)
+    }
+
+    /// The response to a query for `qname` from the zone, see `Zone::answer`
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        self.zone.read().unwrap().answer(qname, qtype)
+    }
+
+    /// A copy of the zone as it is now
+    pub fn zone(&self) -> Zone {
+        self.zone.read().unwrap().clone()
+    }
+
+    /// Whether the client at `addr` may make changes to the zone, with an
+    /// update signed with the key named `key`, if it's signed at all
+    pub fn allows(&self, addr: IpAddr, key: Option<&str>) -> bool {
+        match key {
+            Some(key) => self
+                .update_keys
+                .iter()
+                .any(|name| name.trim_end_matches('.').eq_ignore_ascii_case(key)),
+            None => self.allow_unsigned && self.allow.iter().any(|subnet| subnet.contains(addr)),
+        }
+    }
+
+    /// Check the prerequisites of `update`, and make its changes if they all
+    /// hold, moving the serial on if anything changed. Returns the code to
+    /// respond with, which is `NOERROR` when the update went through.
+    pub fn apply(&self, update: &Update) -> ResultCode {
+        let mut zone = self.zone.write().unwrap();
+        if let Err(rescode) = check_prerequisites(&zone, &update.prerequisites) {
+            return rescode;
+        }
+
+        let mut updated = zone.clone();
+        match apply_updates(&mut updated, &update.updates) {
+            Ok(true) => {}
+            Ok(false) => return ResultCode::NOERROR,
+            Err(rescode) => return rescode,
+        }
+
+        if let Some(path) = &self.path {
+            if let Err(e) = save(&updated, path) {
+                warn!(
+                    "Failed to save {} to {}: {}",
+                    self.origin,
+                    path.display(),
+                    e
+                );
+                return ResultCode::SERVFAIL;
+            }
+        }
+        *zone = updated;
+
+        ResultCode::NOERROR
+    }
+}
+
+/// Answer an UPDATE for one of `zones` from the client at `src`, which has
+/// to be allowed to make changes to it, see `DynamicZone::allows`. `key` is
+/// the name of the key the update was signed with, which has already been
+/// checked. Returns the code to respond with.
+pub fn handle_update(
+    zones: &[DynamicZone],
+    buffer: &mut BytePacketBuffer,
+    src: SocketAddr,
+    key: Option<&str>,
+) -> ResultCode {
+    let update = match buffer.seek(0).and_then(|_| Update::read(buffer)) {
+        Ok(update) => update,
+        Err(e) => {
+            warn!("Failed to parse UPDATE from {}: {}", src, e);
+            return ResultCode::FORMERR;
+        }
+    };
+
+    let Some(zone) = zones.iter().find(|zone| zone.origin == update.zone) else {
+        warn!(
+            "Refusing UPDATE for {} from {}, which we don't serve",
+            update.zone, src
+        );
+        return ResultCode::NOTAUTH;
+    };
+    if !zone.allows(src.ip(), key) {
+        match key {
+            None if zone.allow.iter().any(|subnet| subnet.contains(src.ip())) => warn!(
+                "Refusing unsigned UPDATE for {} from {}, which needs allow_unsigned_updates",
+                zone.origin, src
+            ),
+            _ => warn!("Refusing UPDATE for {} from {}", zone.origin, src),
+        }
+        return ResultCode::REFUSED;
+    }
+
+    let rescode = zone.apply(&update);
+    if rescode == ResultCode::NOERROR {
+        info!("{} updated {}", src, zone.origin);
+    }
+    rescode
+}
+
+/// The checks of RFC 2136, section 3.2, returning the code to respond with
+/// for the first prerequisite that doesn't hold
+fn check_prerequisites(
+    zone: &Zone,
+    prerequisites: &[UpdateRecord],
+) -> std::result::Result<(), ResultCode> {
+    // The records that the RRsets of value dependent prerequisites have to
+    // be made up of, all of them and nothing else
+    let mut rrsets: Vec<(&str, QueryType, Vec<&DnsRecord>)> = Vec::new();
+
+    for prerequisite in prerequisites {
+        if prerequisite.ttl != 0 {
+            return Err(ResultCode::FORMERR);
+        }
+        if !zone.contains(&prerequisite.name) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        let name = prerequisite.name.as_str();
+        let exists = zone.rrset(name, prerequisite.qtype).next().is_some();
+        match (prerequisite.class, &prerequisite.record) {
+            (CLASS_ANY, None) if !exists && prerequisite.qtype == QueryType::ANY => {
+                return Err(ResultCode::NXDOMAIN);
+            }
+            (CLASS_ANY, None) if !exists => return Err(ResultCode::NXRRSET),
+            (CLASS_NONE, None) if exists && prerequisite.qtype == QueryType::ANY => {
+                return Err(ResultCode::YXDOMAIN);
+            }
+            (CLASS_NONE, None) if exists => return Err(ResultCode::YXRRSET),
+            (CLASS_ANY | CLASS_NONE, None) => {}
+            (CLASS_IN, Some(record)) => {
+                let rrset = rrsets.iter_mut().find(|(rrset_name, qtype, _)| {
+                    rrset_name.eq_ignore_ascii_case(name) && *qtype == prerequisite.qtype
+                });
+                match rrset {
+                    Some((_, _, records)) => records.push(record),
+                    None => rrsets.push((name, prerequisite.qtype, vec![record])),
+                }
+            }
+            _ => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    for (name, qtype, records) in rrsets {
+        let existing: Vec<&DnsRecord> = zone.rrset(name, qtype).collect();
+        let all_there = records
+            .iter()
+            .all(|record| existing.iter().any(|existing| same_data(existing, record)));
+        let nothing_else = existing
+            .iter()
+            .all(|existing| records.iter().any(|record| same_data(existing, record)));
+        if !all_there || !nothing_else {
+            return Err(ResultCode::NXRRSET);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether adding `record` would put a CNAME next to records of other types
+fn conflicts_with_alias(zone: &Zone, record: &DnsRecord) -> bool {
+    let is_alias = record.query_type() == QueryType::CNAME;
+    zone.rrset(record.domain(), QueryType::ANY)
+        .any(|existing| (existing.query_type() == QueryType::CNAME) != is_alias)
+}
+
+/// Make the changes of RFC 2136, section 3.4, checking first that they all
+/// make sense. Returns whether anything changed.
+fn apply_updates(
+    zone: &mut Zone,
+    updates: &[UpdateRecord],
+) -> std::result::Result<bool, ResultCode> {
+    for update in updates {
+        if !zone.contains(&update.name) {
+            return Err(ResultCode::NOTZONE);
+        }
+
+        // The types that only make sense in queries, and the OPT record
+        let meta = matches!(
+            update.qtype,
+            QueryType::ANY | QueryType::AXFR | QueryType::IXFR | QueryType::OPT
+        );
+        let valid = match (update.class, &update.record) {
+            (CLASS_IN, Some(_)) => !meta,
+            (CLASS_ANY, None) => update.ttl == 0 && (update.qtype == QueryType::ANY || !meta),
+            (CLASS_NONE, Some(_)) => update.ttl == 0 && !meta,
+            _ => false,
+        };
+        if !valid {
+            return Err(ResultCode::FORMERR);
+        }
+    }
+
+    let origin = zone.origin.clone();
+    let mut changed = false;
+    let mut new_soa = false;
+    for update in updates {
+        let name = update.name.as_str();
+        let apex = name.eq_ignore_ascii_case(&origin);
+
+        changed |= match (update.class, &update.record) {
+            // Only a newer version of the SOA replaces the one of the zone
+            (CLASS_IN, Some(record @ DnsRecord::SOA { serial, .. })) => {
+                let newer = zone
+                    .serial()
+                    .is_none_or(|current| transfer::is_newer(*serial, current));
+                let replaced = apex && newer && zone.add(record.clone());
+                new_soa |= replaced;
+                replaced
+            }
+            // An alias can't have any other records next to it, so adding
+            // either to a name with the other is ignored (RFC 2136 3.4.2.2)
+            (CLASS_IN, Some(record)) if conflicts_with_alias(zone, record) => false,
+            (CLASS_IN, Some(record)) => zone.add(record.clone()),
+            // The SOA and the name servers at the top of the zone stay
+            // whatever else is deleted
+            (CLASS_ANY, None) if apex => match update.qtype {
+                QueryType::SOA | QueryType::NS => false,
+                QueryType::ANY => {
+                    let mut qtypes: Vec<QueryType> = Vec::new();
+                    for record in zone.rrset(name, QueryType::ANY) {
+                        let qtype = record.query_type();
+                        if !matches!(qtype, QueryType::SOA | QueryType::NS)
+                            && !qtypes.contains(&qtype)
+                        {
+                            qtypes.push(qtype);
+                        }
+                    }
+                    for qtype in &qtypes {
+                        zone.remove_rrset(name, *qtype);
+                    }
+                    !qtypes.is_empty()
+                }
+                qtype => zone.remove_rrset(name, qtype),
+            },
+            (CLASS_ANY, None) => zone.remove_rrset(name, update.qtype),
+            (CLASS_NONE, Some(record)) => match record.query_type() {
+                QueryType::SOA => false,
+                QueryType::NS if apex && zone.rrset(name, QueryType::NS).count() <= 1 => false,
+                _ => zone.remove(record),
+            },
+            _ => false,
+        };
+    }
+
+    if changed && !new_soa {
+        zone.bump_serial();
+    }
+
+    Ok(changed)
+}
+
+/// Write the zone over the file at `path`, by way of a temporary file next
+/// to it, so that a crash halfway through can't leave half a zone behind
+fn save(zone: &Zone, path: &Path) -> Result<()> {
+    let mut data = String::new();
+    write!(data, "{}", zone)
+        .map_err(|_| DnsError::InvalidRecord(format!("{} can't be written out", zone.origin)))?;
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path)?;
+
+    Ok(())
+}
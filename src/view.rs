@@ -0,0 +1,93 @@
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+use crate::{
+    error::{DnsError, Result},
+    resolver::ResolverOptions,
+};
+
+/// A range of client addresses, written as `10.0.0.0/8` or `fd00::/8`. A
+/// bare address is a range of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Subnet {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Subnet> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(DnsError::Parse(format!(
+                "Invalid prefix length /{} for {}",
+                prefix_len, addr
+            )));
+        }
+
+        Ok(Subnet { addr, prefix_len })
+    }
+
+    /// Whether `addr` is in the range. IPv4 clients of a socket that serves
+    /// both IPv4 and IPv6 show up as IPv4-mapped IPv6 addresses, which count
+    /// as the IPv4 addresses they are.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32);
+                let mask = mask.unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32);
+                let mask = mask.unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Subnet> {
+        match s.split_once('/') {
+            Some((addr, len)) => Subnet::new(addr.parse()?, len.parse()?),
+            None => {
+                let addr: IpAddr = s.parse()?;
+                Subnet::new(addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+}
+
+/// What a group of clients gets to see, for split horizon setups where the
+/// clients on the internal network are answered from zones of their own,
+/// while everyone else gets the public answers. The clients of a view are
+/// resolved with its `options` rather than the global ones.
+#[derive(Clone, Debug)]
+pub struct View {
+    pub name: String,
+    pub clients: Vec<Subnet>,
+    pub options: Arc<ResolverOptions>,
+}
+
+impl View {
+    /// A view that resolves the way `options` do, until its zones or
+    /// upstreams are changed. It gets a cache of its own, so that the
+    /// answers meant for one view can't leak into another.
+    pub fn new(name: &str, clients: Vec<Subnet>, options: &ResolverOptions) -> View {
+        let mut options = options.clone();
+        options.views.clear();
+        options.cache = options.cache.map(|cache| cache.empty_copy());
+
+        View {
+            name: name.to_string(),
+            clients,
+            options: Arc::new(options),
+        }
+    }
+
+    pub fn matches(&self, addr: IpAddr) -> bool {
+        self.clients.iter().any(|subnet| subnet.contains(addr))
+    }
+}
@@ -0,0 +1,697 @@
+use std::{
+    fmt, fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use crate::{
+    byte_packet_buffer::BytePacketBuffer,
+    dns_packet::{DnsPacket, ResponseBuilder},
+    dns_record::{fqdn, DnsRecord, CLASS_IN},
+    error::{DnsError, Result},
+    name,
+    query_type::QueryType,
+    result_code::ResultCode,
+    svcb::SvcParams,
+};
+
+/// The TTL of records loaded before any `$TTL` line
+pub const DEFAULT_TTL: u32 = 3600;
+
+/// The types of records that can be written out in full, see `parse_record`.
+/// Any others are written in the generic notation.
+const PRESENTATION_TYPES: [QueryType; 12] = [
+    QueryType::A,
+    QueryType::AAAA,
+    QueryType::NS,
+    QueryType::CNAME,
+    QueryType::PTR,
+    QueryType::MX,
+    QueryType::SRV,
+    QueryType::TXT,
+    QueryType::CAA,
+    QueryType::SOA,
+    QueryType::SVCB,
+    QueryType::HTTPS,
+];
+
+/// Records served locally instead of being looked up, loaded from a zone file
+/// in the master file format of RFC 1035, as used by BIND:
+///
+/// ```text
+/// $ORIGIN example.com.
+/// $TTL 1h
+/// @       IN  SOA   ns1 hostmaster (
+///                   2024010101 ; serial
+///                   3600 600 86400 300 )
+///         IN  NS    ns1
+///         IN  A     93.184.216.34
+/// ns1     IN  A     93.184.216.1
+/// www  60 IN  CNAME @
+/// @           MX    10 mail.example.net.
+/// @           TXT   "v=spf1 -all"
+/// @           HTTPS 1 . alpn=h2,h3 ipv4hint=93.184.216.34
+/// ```
+///
+/// Names that don't end in a dot are relative to the `$ORIGIN`, and `@` is the
+/// origin itself. A record that leaves out its name belongs to the same name
+/// as the one before it. Every record gets the TTL of the last `$TTL` line
+/// before it, unless it specifies its own. Anything following a `;` is a
+/// comment, and parentheses let a record span several lines. Records of any
+/// type can be given in the generic notation of RFC 3597, as in
+/// `_443._tcp.www TYPE52 \# 3 030101`.
+///
+/// A zone with an SOA record is authoritative for every name below its
+/// origin, so that names it doesn't have records for don't exist. Without
+/// one, it only overrides the names it does have records for. Wildcards such
+/// as `*.dev` answer for the names below `dev` it doesn't have, see `answer`.
+#[derive(Clone, Debug, Default)]
+pub struct Zone {
+    /// The name all the records are below, without the trailing dot. It's the
+    /// root if no `$ORIGIN` is given.
+    pub origin: String,
+    pub records: Vec<DnsRecord>,
+}
+
+impl Zone {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Zone> {
+        Zone::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> Result<Zone> {
+        let mut zone = Zone::default();
+        let mut default_ttl = DEFAULT_TTL;
+        let mut last_owner: Option<String> = None;
+
+        for entry in tokenize(s)? {
+            let line = entry.line;
+            let context = |reason: String| {
+                DnsError::Parse(format!("Invalid entry on line {}: {}", line, reason))
+            };
+
+            match entry.tokens.first().map(String::as_str) {
+                None => {}
+                Some("$ORIGIN") => match entry.tokens.as_slice() {
+                    [_, origin] => zone.origin = absolute_name(origin, &zone.origin),
+                    _ => return Err(context("$ORIGIN takes a single name".to_string())),
+                },
+                Some("$TTL") => match entry.tokens.as_slice() {
+                    [_, ttl] => default_ttl = parse_ttl(ttl).map_err(|e| context(e.to_string()))?,
+                    _ => return Err(context("$TTL takes a single value".to_string())),
+                },
+                Some(directive) if directive.starts_with('$') => {
+                    return Err(context(format!("unsupported directive {}", directive)));
+                }
+                Some(_) => {
+                    // A line starting with whitespace continues where the
+                    // previous record left off
+                    let (owner, fields) = if entry.inherits_owner {
+                        let owner = last_owner
+                            .clone()
+                            .ok_or_else(|| context("record without a name".to_string()))?;
+                        (owner, &entry.tokens[..])
+                    } else {
+                        let owner = absolute_name(&entry.tokens[0], &zone.origin);
+                        (owner, &entry.tokens[1..])
+                    };
+
+                    let record = parse_record(owner.clone(), fields, &zone.origin, default_ttl)
+                        .map_err(|e| context(e.to_string()))?;
+                    zone.records.push(record);
+                    last_owner = Some(owner);
+                }
+            }
+        }
+
+        Ok(zone)
+    }
+
+    /// The SOA record of the zone, if it has one
+    pub fn soa(&self) -> Option<&DnsRecord> {
+        self.records
+            .iter()
+            .find(|record| matches!(record, DnsRecord::SOA { .. }))
+    }
+
+    /// The serial of the version of the zone, if it has an SOA record
+    pub fn serial(&self) -> Option<u32> {
+        match self.soa()? {
+            DnsRecord::SOA { serial, .. } => Some(*serial),
+            _ => None,
+        }
+    }
+
+    /// Whether `qname` is the origin or any name below it
+    pub fn contains(&self, qname: &str) -> bool {
+        name::is_subdomain(qname, &self.origin)
+    }
+
+    /// The response to a query for `qname`, if the zone has anything to say
+    /// about it. A name that only has records of other types gets an empty
+    /// answer, rather than being looked up elsewhere, and so does a name that
+    /// doesn't exist in an authoritative zone, with `NXDOMAIN`.
+    ///
+    /// Wildcard records such as `*.dev.example.com` answer for the names
+    /// below `dev.example.com` that don't exist (RFC 4592), as if they had
+    /// those records themselves. Names that only exist because there are
+    /// names below them, the empty non-terminals, do exist, so they get an
+    /// empty answer instead.
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        if !self.contains(qname) {
+            return None;
+        }
+
+        let mut records: Vec<DnsRecord> = self.rrset(qname, QueryType::ANY).cloned().collect();
+        let mut exists = !records.is_empty() || self.has_names_below(qname);
+        if !exists {
+            records = self.synthesize(qname);
+            exists = !records.is_empty();
+        }
+
+        let soa = self.soa();
+        if !exists && soa.is_none() {
+            return None;
+        }
+
+        // A query for ANY gets every record the name has, grouped into their
+        // RRsets
+        let mut answers: Vec<DnsRecord> = records
+            .iter()
+            .filter(|record| qtype == QueryType::ANY || record.query_type() == qtype)
+            .cloned()
+            .collect();
+        if qtype == QueryType::ANY {
+            answers.sort_by_key(|record| record.query_type());
+        }
+
+        // An alias stands in for every other type of record
+        if answers.is_empty() {
+            answers = records
+                .into_iter()
+                .filter(|record| matches!(record, DnsRecord::CNAME { .. }))
+                .collect();
+        }
+
+        let rescode = if exists {
+            ResultCode::NOERROR
+        } else {
+            ResultCode::NXDOMAIN
+        };
+        let mut response = ResponseBuilder::new()
+            .authoritative(true)
+            .rcode(rescode)
+            .question(qname, qtype);
+
+        // Negative answers carry the SOA, which tells how long they may be
+        // cached for
+        if answers.is_empty() {
+            if let Some(soa) = soa {
+                response = response.authority(soa.clone());
+            }
+        }
+
+        Some(response.answers(answers).build())
+    }
+
+    /// Whether any record belongs to a name below `name`
+    fn has_names_below(&self, name: &str) -> bool {
+        self.records.iter().any(|record| {
+            let domain = record.domain();
+            !name::eq(domain, name) && name::is_subdomain(domain, name)
+        })
+    }
+
+    /// The records a wildcard has for `qname`, a name that doesn't exist,
+    /// moved over to it. They're those of the wildcard right below the
+    /// closest encloser, the nearest name above `qname` that exists, so a
+    /// wildcard doesn't reach past names that do, e.g. `*.example.com` has
+    /// nothing for `a.dev.example.com` when `dev.example.com` exists.
+    fn synthesize(&self, qname: &str) -> Vec<DnsRecord> {
+        let mut name = qname;
+        while !name.is_empty() {
+            let parent = name.split_once('.').map_or("", |(_, parent)| parent);
+            if !self.contains(parent) {
+                break;
+            }
+            if self.rrset(parent, QueryType::ANY).next().is_some() || self.has_names_below(parent) {
+                let wildcard = if parent.is_empty() {
+                    "*".to_string()
+                } else {
+                    format!("*.{}", parent)
+                };
+                return self
+                    .rrset(&wildcard, QueryType::ANY)
+                    .map(|record| {
+                        let mut record = record.clone();
+                        record.set_domain(qname.to_string());
+                        record
+                    })
+                    .collect();
+            }
+            name = parent;
+        }
+
+        Vec::new()
+    }
+
+    /// The records of `name` of type `qtype`, or all of them for ANY
+    pub fn rrset<'a>(
+        &'a self,
+        name: &'a str,
+        qtype: QueryType,
+    ) -> impl Iterator<Item = &'a DnsRecord> + 'a {
+        self.records.iter().filter(move |record| {
+            name::eq(record.domain(), name)
+                && (qtype == QueryType::ANY || record.query_type() == qtype)
+        })
+    }
+
+    /// Add `record`, or change the TTL of the record with the same data. A
+    /// CNAME replaces the one the name had, and the SOA the one of the zone.
+    /// Returns whether anything changed.
+    pub fn add(&mut self, record: DnsRecord) -> bool {
+        let name = record.domain();
+        let qtype = record.query_type();
+        let existing = self.records.iter().position(|existing| match qtype {
+            QueryType::CNAME | QueryType::SOA => {
+                name::eq(existing.domain(), name) && existing.query_type() == qtype
+            }
+            _ => same_data(existing, &record),
+        });
+        match existing {
+            Some(i) if self.records[i] == record => false,
+            Some(i) => {
+                self.records[i] = record;
+                true
+            }
+            None => {
+                self.records.push(record);
+                true
+            }
+        }
+    }
+
+    /// Delete the records of `name` of type `qtype`, or all of them for ANY.
+    /// Returns whether there were any.
+    pub fn remove_rrset(&mut self, name: &str, qtype: QueryType) -> bool {
+        let len = self.records.len();
+        self.records.retain(|record| {
+            !(name::eq(record.domain(), name)
+                && (qtype == QueryType::ANY || record.query_type() == qtype))
+        });
+        self.records.len() != len
+    }
+
+    /// Delete the record with the same data as `record`, whatever its TTL.
+    /// Returns whether there was one.
+    pub fn remove(&mut self, record: &DnsRecord) -> bool {
+        let len = self.records.len();
+        self.records.retain(|existing| !same_data(existing, record));
+        self.records.len() != len
+    }
+
+    /// Move the serial of the SOA record on by one, so that secondaries pick
+    /// up the changes. It wraps around past the largest one, as serial
+    /// number arithmetic allows (RFC 1982).
+    pub fn bump_serial(&mut self) {
+        for record in &mut self.records {
+            if let DnsRecord::SOA { serial, .. } = record {
+                *serial = serial.wrapping_add(1);
+            }
+        }
+    }
+}
+
+/// The zone in the master file format that `Zone::parse` reads back, with
+/// every name written out in full
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "$ORIGIN {}", fqdn(&self.origin))?;
+
+        for record in &self.records {
+            let qtype = record.query_type();
+            if PRESENTATION_TYPES.contains(&qtype) {
+                writeln!(f, "{}", record)?;
+                continue;
+            }
+
+            let data = record.rdata().map_err(|_| fmt::Error)?;
+            let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(
+                f,
+                "{}\t{}\tIN\t{}\t\\# {} {}",
+                fqdn(record.domain()),
+                record.ttl(),
+                qtype,
+                data.len(),
+                hex
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether two records are the same other than for their TTL and the case
+/// of their names
+pub(crate) fn same_data(a: &DnsRecord, b: &DnsRecord) -> bool {
+    let mut b = b.lowercase_names();
+    b.set_ttl(a.ttl());
+    a.lowercase_names() == b
+}
+
+/// The fields of one entry of a zone file, which may span several lines
+struct Entry {
+    /// The line the entry starts on, for error messages
+    line: usize,
+    /// Whether the entry started with whitespace instead of a name
+    inherits_owner: bool,
+    tokens: Vec<String>,
+}
+
+/// Split a zone file into its entries, taking care of comments, quoted
+/// strings and parentheses
+fn tokenize(s: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut entry: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (i, line) in s.lines().enumerate() {
+        let mut chars = line.chars().peekable();
+
+        if depth == 0 {
+            entries.extend(entry.take());
+        }
+        let entry = entry.get_or_insert_with(|| Entry {
+            line: i + 1,
+            inherits_owner: line.starts_with(|c: char| c.is_whitespace()),
+            tokens: Vec::new(),
+        });
+
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '(' => depth += 1,
+                ')' if depth == 0 => {
+                    return Err(DnsError::Parse(format!(
+                        "Unbalanced parenthesis on line {}",
+                        i + 1
+                    )))
+                }
+                ')' => depth -= 1,
+                '"' => {
+                    let mut token = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            // Either a character taken as it is, or a byte
+                            // by its decimal value, as in `\034`
+                            Some('\\') => {
+                                let digits: String = chars.clone().take(3).collect();
+                                match digits.parse::<u8>() {
+                                    Ok(byte)
+                                        if digits.len() == 3
+                                            && digits.bytes().all(|b| b.is_ascii_digit()) =>
+                                    {
+                                        token.push(byte as char);
+                                        chars.nth(2);
+                                    }
+                                    _ => token.extend(chars.next()),
+                                }
+                            }
+                            Some(c) => token.push(c),
+                            None => {
+                                return Err(DnsError::Parse(format!(
+                                    "Unterminated string on line {}",
+                                    i + 1
+                                )))
+                            }
+                        }
+                    }
+                    entry.tokens.push(token);
+                }
+                c if c.is_whitespace() => {}
+                c => {
+                    let mut token = c.to_string();
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() || matches!(c, ';' | '(' | ')' | '"') {
+                            break;
+                        }
+                        token.push(c);
+                        chars.next();
+                    }
+                    entry.tokens.push(token);
+                }
+            }
+        }
+    }
+
+    if depth > 0 {
+        return Err(DnsError::Parse(
+            "Unbalanced parenthesis at the end of the zone".to_string(),
+        ));
+    }
+    entries.extend(entry);
+
+    Ok(entries)
+}
+
+fn parse_record(
+    domain: String,
+    fields: &[String],
+    origin: &str,
+    default_ttl: u32,
+) -> Result<DnsRecord> {
+    let mut ttl = default_ttl;
+    let mut fields = fields;
+
+    // The TTL and class are both optional, and may come in either order. The
+    // TTL can be told apart from the type by starting with a digit.
+    loop {
+        match fields.first().map(String::as_str) {
+            Some(field) if field.starts_with(|c: char| c.is_ascii_digit()) => {
+                ttl = parse_ttl(field)?;
+            }
+            Some(field) if field.eq_ignore_ascii_case("IN") => {}
+            Some(field)
+                if ["CH", "HS", "CS"]
+                    .iter()
+                    .any(|c| field.eq_ignore_ascii_case(c)) =>
+            {
+                return Err(DnsError::Unsupported(format!(
+                    "unsupported class {}",
+                    field
+                )));
+            }
+            _ => break,
+        }
+        fields = &fields[1..];
+    }
+
+    let (qtype, data) = fields
+        .split_first()
+        .ok_or_else(|| DnsError::Parse("missing record type".to_string()))?;
+    let data: Vec<&str> = data.iter().map(String::as_str).collect();
+    let name = |s: &str| absolute_name(s, origin);
+
+    let qtype = qtype.parse::<QueryType>()?;
+    if let Some((&"\\#", data)) = data.split_first() {
+        return generic_record(domain, qtype, ttl, data);
+    }
+
+    let record = match (qtype, data.as_slice()) {
+        (QueryType::A, [addr]) => DnsRecord::A {
+            domain,
+            addr: addr.parse::<Ipv4Addr>()?,
+            ttl,
+        },
+        (QueryType::AAAA, [addr]) => DnsRecord::AAAA {
+            domain,
+            addr: addr.parse::<Ipv6Addr>()?,
+            ttl,
+        },
+        (QueryType::NS, [host]) => DnsRecord::NS {
+            domain,
+            host: name(host),
+            ttl,
+        },
+        (QueryType::CNAME, [host]) => DnsRecord::CNAME {
+            domain,
+            host: name(host),
+            ttl,
+        },
+        (QueryType::PTR, [host]) => DnsRecord::PTR {
+            domain,
+            host: name(host),
+            ttl,
+        },
+        (QueryType::MX, [priority, host]) => DnsRecord::MX {
+            domain,
+            priority: priority.parse()?,
+            host: name(host),
+            ttl,
+        },
+        (QueryType::SRV, [priority, weight, port, host]) => DnsRecord::SRV {
+            domain,
+            priority: priority.parse()?,
+            weight: weight.parse()?,
+            port: port.parse()?,
+            host: name(host),
+            ttl,
+        },
+        (QueryType::TXT, texts) if !texts.is_empty() => DnsRecord::TXT {
+            domain,
+            data: texts.iter().map(|text| text.to_string()).collect(),
+            ttl,
+        },
+        (QueryType::CAA, [flags, tag, value]) => DnsRecord::CAA {
+            domain,
+            flags: flags.parse()?,
+            tag: tag.to_string(),
+            value: value.to_string(),
+            ttl,
+        },
+        (QueryType::SOA, [mname, rname, serial, refresh, retry, expire, minimum]) => {
+            DnsRecord::SOA {
+                domain,
+                mname: name(mname),
+                rname: name(rname),
+                serial: serial.parse()?,
+                refresh: parse_ttl(refresh)?,
+                retry: parse_ttl(retry)?,
+                expire: parse_ttl(expire)?,
+                minimum: parse_ttl(minimum)?,
+                ttl,
+            }
+        }
+        (QueryType::SVCB, [priority, target, params @ ..]) => DnsRecord::SVCB {
+            domain,
+            priority: priority.parse()?,
+            target: name(target),
+            params: SvcParams::parse(params)?,
+            ttl,
+        },
+        (QueryType::HTTPS, [priority, target, params @ ..]) => DnsRecord::HTTPS {
+            domain,
+            priority: priority.parse()?,
+            target: name(target),
+            params: SvcParams::parse(params)?,
+            ttl,
+        },
+        (
+            qtype @ (QueryType::A
+            | QueryType::AAAA
+            | QueryType::NS
+            | QueryType::CNAME
+            | QueryType::PTR
+            | QueryType::MX
+            | QueryType::SRV
+            | QueryType::TXT
+            | QueryType::CAA
+            | QueryType::SOA
+            | QueryType::SVCB
+            | QueryType::HTTPS),
+            _,
+        ) => {
+            return Err(DnsError::Parse(format!("invalid {:?} record data", qtype)));
+        }
+        (qtype, _) => return Err(DnsError::UnsupportedRecordType(qtype)),
+    };
+
+    Ok(record)
+}
+
+/// A record in the generic notation of RFC 3597, which works for any type:
+/// the length of the data followed by the data in hex, e.g. `\# 4 0a000001`.
+/// It's read the way the record would be off the wire, so that the types we
+/// know come out the same as when they're written out in full.
+fn generic_record(domain: String, qtype: QueryType, ttl: u32, data: &[&str]) -> Result<DnsRecord> {
+    let (len, digits) = data
+        .split_first()
+        .ok_or_else(|| DnsError::Parse("missing length of generic record data".to_string()))?;
+    let len = len.parse::<u16>()?;
+    let digits = digits.concat();
+    if digits.len() != len as usize * 2 {
+        return Err(DnsError::Parse(format!(
+            "generic record data isn't {} bytes long",
+            len
+        )));
+    }
+    let raw = digits
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| Ok(u8::from_str_radix(&String::from_utf8_lossy(pair), 16)?))
+        .collect::<Result<Vec<u8>>>()?;
+
+    let mut buffer = BytePacketBuffer::with_capacity(domain.len() + 12 + raw.len());
+    buffer.write_qname_uncompressed(&domain)?;
+    buffer.write_u16(qtype.to_num())?;
+    buffer.write_u16(CLASS_IN)?;
+    buffer.write_u32(ttl)?;
+    buffer.write_u16(len)?;
+    for b in raw {
+        buffer.write_u8(b)?;
+    }
+
+    DnsRecord::read(&mut BytePacketBuffer::from_slice(
+        &buffer.buf[..buffer.pos()],
+    ))
+}
+
+/// Parse a TTL, which is either a number of seconds or made up of units as
+/// in `1h30m`
+fn parse_ttl(s: &str) -> Result<u32> {
+    if let Ok(ttl) = s.parse::<u32>() {
+        return Ok(ttl);
+    }
+
+    let invalid = || DnsError::Parse(format!("invalid TTL {}", s));
+    let mut ttl: u32 = 0;
+    let mut value: Option<u32> = None;
+    for c in s.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = value
+                .unwrap_or(0)
+                .checked_mul(10)
+                .and_then(|value| value.checked_add(digit));
+            if value.is_none() {
+                return Err(invalid());
+            }
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        ttl = value
+            .take()
+            .and_then(|value| value.checked_mul(unit))
+            .and_then(|value| ttl.checked_add(value))
+            .ok_or_else(invalid)?;
+    }
+    if value.is_some() {
+        return Err(invalid());
+    }
+
+    Ok(ttl)
+}
+
+/// Names are stored the way `read_qname` produces them, in the case they're
+/// written in and without the trailing dot. Names without a trailing dot are
+/// relative to the origin.
+fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        return origin.to_string();
+    }
+
+    match name.strip_suffix('.') {
+        Some(name) => name.to_string(),
+        None if origin.is_empty() => name.to_string(),
+        None => format!("{}.{}", name, origin),
+    }
+}
@@ -0,0 +1,90 @@
+//! Names on the blocklist, and those below the domains on it, are answered
+//! locally, while everything else is looked up as usual.
+
+mod common;
+
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    blocklist::{BlockMode, Blocklist},
+    resolver::ResolverOptions,
+    server,
+    zone::Zone,
+    QueryType, ResultCode,
+};
+
+use common::ask;
+
+/// Serve `example.test`, where www and cdn.tracker have the address 10.0.0.1,
+/// with `blocklist` in front of it
+fn serve(blocklist: Blocklist) -> SocketAddr {
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A 10.0.0.1\n\
+         cdn.tracker IN A 10.0.0.1\n",
+    )
+    .unwrap();
+    let options = ResolverOptions {
+        zones: vec![zone],
+        blocklist: Some(blocklist),
+        ..ResolverOptions::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+    addr
+}
+
+#[test]
+fn listed_names_and_their_subdomains_are_blocked() {
+    let path = std::env::temp_dir().join(format!("dns-server-blocklist-{}", std::process::id()));
+    fs::write(
+        &path,
+        "# Hosts entries, and a whole domain\n\
+         0.0.0.0 localhost ads.example.test\n\
+         ||tracker.example.test^\n",
+    )
+    .unwrap();
+    let mut blocklist = Blocklist::new();
+    assert_eq!(blocklist.load(&path).unwrap(), 2);
+    fs::remove_file(&path).unwrap();
+
+    let server = serve(blocklist.clone());
+
+    for qname in [
+        "ads.example.test",
+        "ADS.example.test",
+        "tracker.example.test",
+        "eu.cdn.tracker.example.test",
+    ] {
+        let response = ask(server, qname, QueryType::A);
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN, "{}", qname);
+        assert!(response.answers.is_empty());
+    }
+    assert_eq!(blocklist.blocked_queries(), 4);
+
+    // A plain name doesn't take the names below it along
+    for qname in ["www.example.test", "www.ads.example.test", "localhost"] {
+        assert!(!blocklist.is_blocked(qname), "{}", qname);
+    }
+    let response = ask(server, "www.example.test", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(blocklist.blocked_queries(), 4);
+
+    // The allowlist wins, and blocked names can get the null address instead
+    blocklist.allow("cdn.tracker.example.test");
+    blocklist.mode = BlockMode::NullAddress;
+    let server = serve(blocklist);
+    let response = ask(server, "cdn.tracker.example.test", QueryType::A);
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    let response = ask(server, "tracker.example.test", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::UNSPECIFIED));
+}
@@ -0,0 +1,382 @@
+//! The cache survives a restart through its snapshot file, minus the time
+//! that passed in between, refreshes popular entries before they expire, and
+//! can answer with expired ones when the upstreams are down. What it keeps is
+//! up to the types and domains it bypasses and the answers it gets, only those
+//! that may be cached are.
+
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use dns_server::{
+    cache::{self, Cache},
+    error::Result,
+    forwarder::{Forwarder, Upstream},
+    pipeline::{Next, Pipeline, Query, QueryHandler, Stage},
+    resolver::{self, ResolverOptions, Source},
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType, ResultCode,
+};
+
+fn response(qname: &str, ttl: u32) -> DnsPacket {
+    let query = DnsPacket::query(qname, QueryType::A);
+    DnsPacket::response_to(&query)
+        .answer(DnsRecord::A {
+            domain: qname.to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl,
+        })
+        .build()
+}
+
+fn snapshot(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("dns-server-cache-{}-{}", std::process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn ttls_count_down_while_cached() {
+    let mut cache = Cache::new();
+    cache.min_ttl = 5;
+    cache.insert(
+        "www.example.com",
+        QueryType::A,
+        &response("www.example.com", 300),
+    );
+    cache.insert(
+        "api.example.com",
+        QueryType::A,
+        &response("api.example.com", 1),
+    );
+    let cached = cache.get("www.example.com", QueryType::A).unwrap();
+    assert_eq!(cached.answers[0].ttl(), 300);
+
+    thread::sleep(Duration::from_millis(1100));
+    let cached = cache.get("www.example.com", QueryType::A).unwrap();
+    assert_eq!(cached.answers[0].ttl(), 299);
+
+    // Kept around for longer than its TTL, but never handed out with none
+    // of it left
+    let cached = cache.get("api.example.com", QueryType::A).unwrap();
+    assert_eq!(cached.answers[0].ttl(), 1);
+}
+
+#[test]
+fn snapshots_bring_back_the_entries() {
+    let path = snapshot("round-trip");
+    let cache = Cache::new();
+    cache.insert(
+        "www.example.com",
+        QueryType::A,
+        &response("www.example.com", 300),
+    );
+    cache.insert(
+        "api.example.com",
+        QueryType::A,
+        &response("api.example.com", 60),
+    );
+    assert_eq!(cache.save(&path).unwrap(), 2);
+
+    let restarted = Cache::new();
+    assert_eq!(restarted.load(&path).unwrap(), 2);
+
+    assert_eq!(
+        restarted.get("www.example.com", QueryType::A),
+        cache.get("www.example.com", QueryType::A)
+    );
+    let entries = restarted.entries();
+    assert_eq!(entries[0].0, "api.example.com|1|1");
+    assert!(entries[0].1 <= Duration::from_secs(60));
+    assert!(entries[1].1 > Duration::from_secs(290));
+}
+
+#[test]
+fn time_passed_since_the_snapshot_counts() {
+    let path = snapshot("elapsed");
+    let cache = Cache::new();
+    cache.insert(
+        "www.example.com",
+        QueryType::A,
+        &response("www.example.com", 300),
+    );
+    cache.insert(
+        "api.example.com",
+        QueryType::A,
+        &response("api.example.com", 60),
+    );
+    cache.save(&path).unwrap();
+
+    // Move the time of the snapshot, after the magic and the version, two
+    // minutes into the past
+    let mut data = fs::read(&path).unwrap();
+    let saved_at = u64::from_be_bytes(data[5..13].try_into().unwrap());
+    data[5..13].copy_from_slice(&(saved_at - 120).to_be_bytes());
+    fs::write(&path, data).unwrap();
+
+    let restarted = Cache::new();
+    assert_eq!(restarted.load(&path).unwrap(), 1);
+    assert!(restarted.get("api.example.com", QueryType::A).is_none());
+
+    let entries = restarted.entries();
+    assert!(entries[0].1 <= Duration::from_secs(180));
+    let cached = restarted.get("www.example.com", QueryType::A).unwrap();
+    assert!(cached.answers[0].ttl() <= 180);
+}
+
+#[test]
+fn snapshots_leave_out_types_that_are_not_cached() {
+    let path = snapshot("bypass");
+    let cache = Cache::new();
+    cache.insert(
+        "www.example.com",
+        QueryType::A,
+        &response("www.example.com", 300),
+    );
+    cache.save(&path).unwrap();
+
+    let mut restarted = Cache::new();
+    restarted.bypass.insert(QueryType::A);
+    assert_eq!(restarted.load(&path).unwrap(), 0);
+}
+
+#[test]
+fn missing_and_foreign_snapshots() {
+    let path = snapshot("missing");
+    assert_eq!(Cache::new().load(&path).unwrap(), 0);
+
+    fs::write(&path, b"not a snapshot").unwrap();
+    assert!(Cache::new().load(&path).is_err());
+}
+
+/// A response with the SOA of example.com as its answer
+fn soa_response() -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.response = true;
+    packet.answers.push(DnsRecord::SOA {
+        domain: "example.com".to_string(),
+        mname: "ns1.example.com".to_string(),
+        rname: "hostmaster.example.com".to_string(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 900,
+        ttl: 3600,
+    });
+    packet
+}
+
+#[test]
+fn bypassed_types_are_never_kept() {
+    let mut cache = Cache::new();
+    cache.bypass.insert(QueryType::SOA);
+
+    cache.insert("example.com", QueryType::SOA, &soa_response());
+    assert!(cache.get("example.com", QueryType::SOA).is_none());
+
+    // Other types are, whatever the case of the name
+    cache.insert("Example.com", QueryType::NS, &soa_response());
+    assert!(cache.get("example.COM", QueryType::NS).is_some());
+}
+
+#[test]
+fn domains_that_are_not_cached_are_never_kept() {
+    let mut cache = Cache::new();
+    cache.no_cache.push("dyn.example.com".to_string());
+
+    cache.insert("Host.DYN.example.com", QueryType::A, &soa_response());
+    assert!(cache.get("host.dyn.example.com", QueryType::A).is_none());
+    cache.insert("dyn.example.com", QueryType::A, &soa_response());
+    assert!(cache.get("dyn.example.com", QueryType::A).is_none());
+
+    cache.insert("www.example.com", QueryType::A, &soa_response());
+    assert!(cache.get("www.example.com", QueryType::A).is_some());
+}
+
+#[test]
+fn negative_answers_are_kept_no_longer_than_allowed() {
+    let mut cache = Cache::new();
+    cache.min_ttl = 600;
+    cache.max_negative_ttl = Some(30);
+
+    let mut negative = soa_response();
+    negative.header.rescode = ResultCode::NXDOMAIN;
+    negative.authorities = std::mem::take(&mut negative.answers);
+    cache.insert("gone.example.com", QueryType::A, &negative);
+    cache.insert("example.com", QueryType::SOA, &soa_response());
+
+    let entries = cache.entries();
+    assert_eq!(entries[0].0, "example.com|6|1");
+    assert!(entries[0].1 > Duration::from_secs(3500));
+    assert_eq!(entries[1].0, "gone.example.com|1|1");
+    assert!(entries[1].1 <= Duration::from_secs(30));
+}
+
+#[test]
+fn only_answers_that_may_be_cached_are_kept() {
+    let cache = Cache::new();
+
+    let mut response = soa_response();
+    response.header.rescode = ResultCode::SERVFAIL;
+    cache.insert("example.com", QueryType::SOA, &response);
+    assert!(cache.get("example.com", QueryType::SOA).is_none());
+
+    cache.insert("example.com", QueryType::SOA, &DnsPacket::new());
+    assert!(cache.get("example.com", QueryType::SOA).is_none());
+}
+
+/// An upstream that answers a single query with 10.0.0.2
+fn answer_once() -> Upstream {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut response = DnsPacket::response_to(&query)
+            .answer(DnsRecord::A {
+                domain: query.questions[0].name.clone(),
+                addr: Ipv4Addr::new(10, 0, 0, 2),
+                ttl: 300,
+            })
+            .build();
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    Upstream::Plain(addr)
+}
+
+fn forwarding_to(cache: &Cache, upstream: Upstream) -> Arc<ResolverOptions> {
+    Arc::new(ResolverOptions {
+        forwarder: Some(Forwarder::new(vec![upstream])),
+        cache: Some(cache.clone()),
+        ..ResolverOptions::default()
+    })
+}
+
+#[test]
+fn entries_about_to_expire_are_prefetched_once() {
+    let mut cache = Cache::new();
+    cache.prefetch = true;
+    cache.insert(
+        "www.example.com",
+        QueryType::A,
+        &response("www.example.com", 1),
+    );
+    assert!(!cache.claim_prefetch("www.example.com", QueryType::A));
+
+    thread::sleep(Duration::from_millis(950));
+    assert!(cache.claim_prefetch("www.example.com", QueryType::A));
+    assert!(!cache.claim_prefetch("www.example.com", QueryType::A));
+
+    cache.release_prefetch("www.example.com", QueryType::A);
+    assert!(cache.claim_prefetch("www.example.com", QueryType::A));
+}
+
+#[test]
+fn lookups_refresh_entries_in_the_background() {
+    let mut cache = Cache::new();
+    cache.prefetch = true;
+    let options = forwarding_to(&cache, answer_once());
+    cache.insert(
+        "www.example.com",
+        QueryType::A,
+        &response("www.example.com", 1),
+    );
+
+    thread::sleep(Duration::from_millis(950));
+    // Answered from the cache right away, while the refresh goes out
+    let cached = resolver::lookup("www.example.com", QueryType::A, &options).unwrap();
+    assert_eq!(cached.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+    thread::sleep(Duration::from_millis(500));
+    let refreshed = cache.get("www.example.com", QueryType::A).unwrap();
+    assert_eq!(refreshed.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 2)));
+}
+
+/// Answers every query with 10.0.0.3, in place of the upstreams
+struct Answer;
+
+impl QueryHandler for Answer {
+    fn handle(&self, query: &Query, _: Next) -> Result<(DnsPacket, Source)> {
+        let mut response = response(query.name, 60);
+        response.answers[0] = DnsRecord::A {
+            domain: query.name.to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 3),
+            ttl: 60,
+        };
+        Ok((response, Source::Handler))
+    }
+}
+
+#[test]
+fn refreshes_go_through_the_rest_of_the_pipeline() {
+    let mut cache = Cache::new();
+    cache.prefetch = true;
+    let mut pipeline = Pipeline::new();
+    pipeline.insert_before(Stage::Upstream, Answer);
+    let options = Arc::new(ResolverOptions {
+        cache: Some(cache.clone()),
+        pipeline,
+        ..ResolverOptions::default()
+    });
+    cache.insert(
+        "www.example.com",
+        QueryType::A,
+        &response("www.example.com", 1),
+    );
+
+    thread::sleep(Duration::from_millis(950));
+    resolver::lookup("www.example.com", QueryType::A, &options).unwrap();
+
+    thread::sleep(Duration::from_millis(500));
+    let refreshed = cache.get("www.example.com", QueryType::A).unwrap();
+    assert_eq!(refreshed.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 3)));
+}
+
+#[test]
+fn expired_answers_are_served_when_upstreams_are_down() {
+    let mut cache = Cache::new();
+    cache.serve_stale = Some(60);
+    // Nothing listens on the port, so the query is refused right away
+    let options = forwarding_to(
+        &cache,
+        Upstream::Plain(SocketAddr::from(([127, 0, 0, 1], 1))),
+    );
+    cache.insert(
+        "www.example.com",
+        QueryType::A,
+        &response("www.example.com", 1),
+    );
+
+    thread::sleep(Duration::from_millis(1100));
+    assert!(cache.get("www.example.com", QueryType::A).is_none());
+
+    let stale = resolver::lookup("www.example.com", QueryType::A, &options).unwrap();
+    assert_eq!(stale.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(stale.answers[0].ttl(), cache::STALE_TTL);
+
+    // Without serving stale, the failure is what the client gets
+    let cache = Cache::new();
+    cache.insert(
+        "www.example.com",
+        QueryType::A,
+        &response("www.example.com", 1),
+    );
+    let options = forwarding_to(
+        &cache,
+        Upstream::Plain(SocketAddr::from(([127, 0, 0, 1], 1))),
+    );
+    thread::sleep(Duration::from_millis(1100));
+    assert!(resolver::lookup("www.example.com", QueryType::A, &options).is_err());
+}
@@ -0,0 +1,474 @@
+//! Queries that go unanswered are sent again, and eventually given up on.
+//! Responses to another question are never taken, and with 0x20 encoding,
+//! only those echoing the random case of the question are. The lookups of
+//! the common types hand back their data as is, and leave out the records of
+//! other types, as do those of the names of addresses. Truncated responses
+//! are asked for again over TCP.
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use dns_server::{
+    client::{self, MxRecord, QueryOptions, SrvRecord},
+    name,
+    resolver::ResolverOptions,
+    server,
+    zone::Zone,
+    BytePacketBuffer, DnsError, DnsPacket, DnsQuestion, DnsRecord, QueryType,
+};
+
+fn options() -> QueryOptions {
+    QueryOptions {
+        timeout: Duration::from_millis(100),
+        retries: 2,
+        ..QueryOptions::default()
+    }
+}
+
+/// The options above, checking the case of responses if `verify_case`
+fn checking_case(verify_case: bool) -> QueryOptions {
+    QueryOptions {
+        verify_case,
+        ..options()
+    }
+}
+
+/// A server that ignores the first `ignore` queries it gets, and answers the
+/// one after them
+fn answer_after(ignore: usize) -> SocketAddr {
+    answer_after_with(ignore, true)
+}
+
+/// Like `answer_after`, with the question in the response either exactly as
+/// it was asked, or lowercased
+fn answer_after_with(ignore: usize, echo_case: bool) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for _ in 0..ignore {
+            socket.recv_from(&mut [0; 512]).unwrap();
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let mut query = DnsPacket::from_buffer(&mut buffer).unwrap();
+        if !echo_case {
+            query.questions[0].name.make_ascii_lowercase();
+            query.questions[0].raw = None;
+        }
+
+        let mut response = DnsPacket::response_to(&query)
+            .answer(DnsRecord::A {
+                domain: query.questions[0].name.clone(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: 300,
+            })
+            .build();
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    addr
+}
+
+#[test]
+fn unanswered_queries_are_sent_again() {
+    let server = answer_after(2);
+
+    let response = client::query("www.example.com", QueryType::A, server, &options()).unwrap();
+    assert_eq!(response.answers.len(), 1);
+}
+
+#[test]
+fn queries_time_out_after_the_last_retry() {
+    let server = answer_after(3);
+
+    let start = Instant::now();
+    let result = client::query("www.example.com", QueryType::A, server, &options());
+    assert!(matches!(result, Err(DnsError::Timeout)));
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn only_reads_that_ran_out_of_time_are_timeouts() {
+    let would_block = || std::io::Error::from(std::io::ErrorKind::WouldBlock);
+    assert!(matches!(
+        DnsError::from(std::io::Error::from(std::io::ErrorKind::TimedOut)),
+        DnsError::Timeout
+    ));
+    // A non-blocking socket without anything to read hasn't timed out
+    assert!(matches!(DnsError::from(would_block()), DnsError::Io(_)));
+    // While a socket with a read timeout says it did with `WouldBlock` on
+    // some platforms
+    assert!(matches!(
+        DnsError::from_timed_read(would_block()),
+        DnsError::Timeout
+    ));
+}
+
+#[test]
+fn names_are_sent_in_random_case() {
+    let name = "abcdefghijklmnopqrstuvwxyz.abcdefghijklmnopqrstuvwxyz.example";
+
+    let randomized = client::randomize_case(name);
+
+    assert!(randomized.eq_ignore_ascii_case(name));
+    assert!(randomized.chars().any(|c| c.is_ascii_uppercase()));
+    assert!(randomized.chars().any(|c| c.is_ascii_lowercase()));
+}
+
+#[test]
+fn responses_must_echo_the_case_of_the_question() {
+    let options = QueryOptions {
+        verify_case: true,
+        retries: 0,
+        ..options()
+    };
+
+    let server = answer_after_with(0, true);
+    let response = client::query("www.example.com", QueryType::A, server, &options).unwrap();
+    assert_eq!(response.answers.len(), 1);
+
+    // Long enough that it's never sent in lowercase as it is
+    let server = answer_after_with(0, false);
+    let name = "abcdefghijklmnopqrstuvwxyz.example.com";
+    let result = client::query(name, QueryType::A, server, &options);
+    assert!(matches!(result, Err(DnsError::Timeout)));
+}
+
+/// A server on the loopback interface that answers a single query with
+/// its own question, passed through `echo` first, followed by `answers`
+fn answer_once(echo: fn(u8) -> u8, answers: Vec<DnsRecord>) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let mut response = buffer.buf;
+        response[2] |= 0x80;
+        response[7] = answers.len() as u8;
+
+        // Only the question is echoed, not the OPT record of the query
+        response[11] = 0;
+        let mut len = 12;
+        while response[len] != 0 {
+            len += response[len] as usize + 1;
+        }
+        len += 5;
+        for b in &mut response[12..len] {
+            *b = echo(*b);
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buf[..len].copy_from_slice(&response[..len]);
+        buffer.step(len).unwrap();
+        for record in &answers {
+            record.write(&mut buffer).unwrap();
+        }
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    addr
+}
+
+#[test]
+fn responses_changing_the_case_are_rejected() {
+    let server = answer_once(|b| b, Vec::new());
+    let response = client::query(
+        "WwW.ExAmPlE.cOm",
+        QueryType::A,
+        server,
+        &checking_case(true),
+    )
+    .unwrap();
+    // In the random case it was sent in
+    assert!(name::eq(&response.questions[0].name, "www.example.com"));
+
+    // Every letter the other way around, which can't be the case it was
+    // sent in
+    let flip = |b: u8| match b.is_ascii_uppercase() {
+        true => b.to_ascii_lowercase(),
+        false => b.to_ascii_uppercase(),
+    };
+    let server = answer_once(flip, Vec::new());
+    assert!(client::query(
+        "WwW.ExAmPlE.cOm",
+        QueryType::A,
+        server,
+        &checking_case(true)
+    )
+    .is_err());
+
+    // Without the check, the same response is fine
+    let server = answer_once(flip, Vec::new());
+    assert!(client::query(
+        "WwW.ExAmPlE.cOm",
+        QueryType::A,
+        server,
+        &checking_case(false)
+    )
+    .is_ok());
+}
+
+#[test]
+fn lookups_leave_out_the_records_of_other_types() {
+    // An alias, followed by the records of its target
+    let target = "target.example.com".to_string();
+    let mut answers = vec![DnsRecord::CNAME {
+        domain: "www.example.com".to_string(),
+        host: target.clone(),
+        ttl: 300,
+    }];
+    answers.extend([1, 2].map(|i| DnsRecord::A {
+        domain: target.clone(),
+        addr: Ipv4Addr::new(10, 0, 0, i),
+        ttl: 300,
+    }));
+
+    let server = answer_once(|b| b, answers.clone());
+    assert_eq!(
+        client::lookup("www.example.com", QueryType::A, server).unwrap(),
+        answers[1..]
+    );
+    let server = answer_once(|b| b, answers);
+    assert!(client::lookup("www.example.com", QueryType::AAAA, server)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn addresses_are_looked_up_under_their_reverse_names() {
+    assert_eq!(
+        client::reverse_name(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10))),
+        "10.2.0.192.in-addr.arpa"
+    );
+    // Every nibble, the zeros included, as in RFC 3596
+    assert_eq!(
+        client::reverse_name("4321:0:1:2:3:4:567:89ab".parse().unwrap()),
+        "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa"
+    );
+    assert_eq!(
+        client::reverse_name(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        format!("1.{}ip6.arpa", "0.".repeat(31))
+    );
+
+    let ptrs = ["www", "mail"].map(|host| DnsRecord::PTR {
+        domain: "10.2.0.192.in-addr.arpa".to_string(),
+        host: format!("{}.example.com", host),
+        ttl: 300,
+    });
+    let server = answer_once(|b| b, ptrs.to_vec());
+    assert_eq!(
+        client::reverse_lookup(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10)), server).unwrap(),
+        ["www.example.com", "mail.example.com"]
+    );
+    let server = answer_once(|b| b, Vec::new());
+    assert!(
+        client::reverse_lookup(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 11)), server)
+            .unwrap()
+            .is_empty()
+    );
+}
+
+/// The response to `query`, with nothing in it but the TC bit when
+/// `truncated`, and ten addresses otherwise
+fn ten_addresses(query: &DnsPacket, truncated: bool) -> BytePacketBuffer {
+    let mut response = DnsPacket::new();
+    response.header.id = query.header.id;
+    response.header.response = true;
+    response.header.truncated_message = truncated;
+    response.questions = query.questions.clone();
+    if !truncated {
+        response.answers = (1..=10)
+            .map(|i| DnsRecord::A {
+                domain: query.questions[0].name.clone(),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: 300,
+            })
+            .collect();
+    }
+
+    let mut buffer = BytePacketBuffer::new();
+    response.write(&mut buffer).unwrap();
+    buffer
+}
+
+/// A server that only ever sends truncated responses over UDP, with the
+/// whole of them over TCP on the same port
+fn truncating() -> SocketAddr {
+    let (udp, tcp) = loop {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        if let Ok(tcp) = TcpListener::bind(udp.local_addr().unwrap()) {
+            break (udp, tcp);
+        }
+    };
+    let addr = udp.local_addr().unwrap();
+
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = udp.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+        let buffer = ten_addresses(&query, true);
+        udp.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+    thread::spawn(move || {
+        for stream in tcp.incoming() {
+            let mut stream = stream.unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut buffer = BytePacketBuffer::new();
+            stream
+                .read_exact(&mut buffer.buf[..u16::from_be_bytes(len) as usize])
+                .unwrap();
+            let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+            let buffer = ten_addresses(&query, false);
+            stream
+                .write_all(&(buffer.pos() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&buffer.buf[..buffer.pos()]).unwrap();
+        }
+    });
+
+    addr
+}
+
+#[test]
+fn truncated_responses_are_asked_for_again_over_tcp() {
+    let server = truncating();
+    let response = client::query(
+        "www.example.com",
+        QueryType::A,
+        server,
+        &checking_case(true),
+    )
+    .unwrap();
+    assert!(!response.header.truncated_message);
+    assert_eq!(response.answers.len(), 10);
+}
+
+/// A server that answers a single query as if it had been asked for
+/// `qname` and `qtype` instead
+fn answer_for(qname: &'static str, qtype: QueryType) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.questions = vec![DnsQuestion::new(qname.to_string(), qtype)];
+        response.answers.push(DnsRecord::A {
+            domain: qname.to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 66),
+            ttl: 300,
+        });
+
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    addr
+}
+
+#[test]
+fn responses_to_another_question_are_rejected() {
+    for (qname, qtype) in [
+        ("www.example.net", QueryType::A),
+        ("www.example.com", QueryType::AAAA),
+    ] {
+        let server = answer_for(qname, qtype);
+        assert!(client::query(
+            "www.example.com",
+            QueryType::A,
+            server,
+            &checking_case(false)
+        )
+        .is_err());
+    }
+
+    // The name only has to match regardless of case
+    let server = answer_for("WWW.example.COM", QueryType::A);
+    let response = client::query(
+        "www.example.com",
+        QueryType::A,
+        server,
+        &checking_case(false),
+    )
+    .unwrap();
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 66)));
+}
+
+#[test]
+fn lookups_hand_back_the_data_of_their_type() {
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         $TTL 300\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         @ IN MX 20 backup\n\
+         @ IN MX 10 mail\n\
+         @ IN TXT \"v=spf1 \" \"-all\"\n\
+         www IN A 10.0.0.1\n\
+         www IN AAAA 2001:db8::1\n\
+         _imaps._tcp IN SRV 0 5 993 mail\n",
+    )
+    .unwrap();
+    let options = ResolverOptions {
+        zones: vec![zone],
+        ..ResolverOptions::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+
+    assert_eq!(
+        client::lookup_a("www.example.test", server).unwrap(),
+        vec![Ipv4Addr::new(10, 0, 0, 1)]
+    );
+    assert_eq!(
+        client::lookup_aaaa("www.example.test", server).unwrap(),
+        vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()]
+    );
+    assert_eq!(
+        client::lookup_mx("example.test", server).unwrap(),
+        vec![
+            MxRecord {
+                priority: 10,
+                host: "mail.example.test".to_string(),
+            },
+            MxRecord {
+                priority: 20,
+                host: "backup.example.test".to_string(),
+            },
+        ]
+    );
+    assert_eq!(
+        client::lookup_txt("example.test", server).unwrap(),
+        vec!["v=spf1 -all".to_string()]
+    );
+    assert_eq!(
+        client::lookup_srv("_imaps._tcp.example.test", server).unwrap(),
+        vec![SrvRecord {
+            priority: 0,
+            weight: 5,
+            port: 993,
+            host: "mail.example.test".to_string(),
+        }]
+    );
+    assert!(client::lookup_a("nothing.example.test", server)
+        .unwrap()
+        .is_empty());
+}
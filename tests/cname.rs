@@ -0,0 +1,104 @@
+//! Aliases are followed to the records they stand for, across zones.
+
+use std::{net::Ipv4Addr, sync::Arc};
+
+use dns_server::{
+    resolver::{self, ResolverOptions},
+    zone::Zone,
+    DnsRecord, QueryType, ResultCode,
+};
+
+fn options() -> Arc<ResolverOptions> {
+    let example = Zone::parse(
+        r#"
+$ORIGIN example.test.
+$TTL 300
+@ IN SOA ns hostmaster 1 3600 600 86400 60
+www IN CNAME web.other.test.
+dangling IN CNAME gone.other.test.
+loop1 IN CNAME loop2
+loop2 IN CNAME loop1
+"#,
+    )
+    .unwrap();
+    let other = Zone::parse(
+        r#"
+$ORIGIN other.test.
+$TTL 300
+@ IN SOA ns hostmaster 1 3600 600 86400 60
+web IN CNAME host
+host IN A 10.0.0.1
+"#,
+    )
+    .unwrap();
+
+    Arc::new(ResolverOptions {
+        zones: vec![example, other],
+        ..ResolverOptions::default()
+    })
+}
+
+fn cname(domain: &str, host: &str) -> DnsRecord {
+    DnsRecord::CNAME {
+        domain: domain.to_string(),
+        host: host.to_string(),
+        ttl: 300,
+    }
+}
+
+#[test]
+fn chains_are_followed_to_the_address() {
+    let response = resolver::lookup("www.example.test", QueryType::A, &options()).unwrap();
+
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(
+        response.answers,
+        [
+            cname("www.example.test", "web.other.test"),
+            cname("web.other.test", "host.other.test"),
+            DnsRecord::A {
+                domain: "host.other.test".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: 300,
+            },
+        ]
+    );
+}
+
+#[test]
+fn missing_targets_are_nxdomain() {
+    let response = resolver::lookup("dangling.example.test", QueryType::A, &options()).unwrap();
+
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(
+        response.answers,
+        [cname("dangling.example.test", "gone.other.test")]
+    );
+    assert!(matches!(
+        response.authorities.as_slice(),
+        [DnsRecord::SOA { domain, .. }] if domain == "other.test"
+    ));
+}
+
+#[test]
+fn loops_are_cut_short() {
+    let response = resolver::lookup("loop1.example.test", QueryType::A, &options()).unwrap();
+
+    assert_eq!(
+        response.answers,
+        [
+            cname("loop1.example.test", "loop2.example.test"),
+            cname("loop2.example.test", "loop1.example.test"),
+        ]
+    );
+}
+
+#[test]
+fn cname_queries_get_the_alias_alone() {
+    let response = resolver::lookup("www.example.test", QueryType::CNAME, &options()).unwrap();
+
+    assert_eq!(
+        response.answers,
+        [cname("www.example.test", "web.other.test")]
+    );
+}
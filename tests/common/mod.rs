@@ -0,0 +1,48 @@
+//! Asking the servers the tests start for names over UDP, the way a stub
+//! resolver would.
+#![allow(dead_code)]
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use dns_server::{BytePacketBuffer, DnsPacket, QueryType};
+
+/// A socket on the loopback interface to ask from, which gives up on a
+/// response after two seconds
+pub fn client() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    socket
+}
+
+/// Send the message in `request` from `client`, and wait for the response
+pub fn exchange(client: &UdpSocket, server: SocketAddr, request: &BytePacketBuffer) -> DnsPacket {
+    client
+        .send_to(&request.buf[..request.pos()], server)
+        .unwrap();
+
+    let mut buffer = BytePacketBuffer::new();
+    client.recv_from(&mut buffer.buf).unwrap();
+    DnsPacket::from_buffer(&mut buffer).unwrap()
+}
+
+/// Ask `server` for the `qtype` records of `qname` from `client`
+pub fn ask_from(
+    client: &UdpSocket,
+    server: SocketAddr,
+    qname: &str,
+    qtype: QueryType,
+) -> DnsPacket {
+    let mut buffer = BytePacketBuffer::new();
+    DnsPacket::query(qname, qtype).write(&mut buffer).unwrap();
+    exchange(client, server, &buffer)
+}
+
+/// Ask `server` for the `qtype` records of `qname` from a socket of its own
+pub fn ask(server: SocketAddr, qname: &str, qtype: QueryType) -> DnsPacket {
+    ask_from(&client(), server, qname, qtype)
+}
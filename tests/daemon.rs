@@ -0,0 +1,92 @@
+//! Running as a system service: pid files, systemd's notifications and
+//! sockets, and where the log goes.
+#![cfg(unix)]
+
+use std::{env, fs, os::unix::net::UnixDatagram, path::PathBuf, process};
+
+use dns_server::daemon::{self, LogTarget, Notifier, PidFile};
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("dns-server-daemon-{}-{}", process::id(), name))
+}
+
+#[test]
+fn log_targets_are_parsed() {
+    assert_eq!("stderr".parse::<LogTarget>().unwrap(), LogTarget::Stderr);
+    assert_eq!("syslog".parse::<LogTarget>().unwrap(), LogTarget::Syslog);
+    assert_eq!(
+        "journald".parse::<LogTarget>().unwrap(),
+        LogTarget::Journald
+    );
+    assert!("file".parse::<LogTarget>().is_err());
+    assert_eq!(LogTarget::default(), LogTarget::Stderr);
+}
+
+#[test]
+fn pid_files_are_removed_when_dropped() {
+    let path = temp_path("pid");
+    let pid_file = PidFile::create(&path).unwrap();
+    assert_eq!(
+        fs::read_to_string(pid_file.path()).unwrap(),
+        format!("{}\n", process::id())
+    );
+
+    drop(pid_file);
+    assert!(!path.exists());
+}
+
+#[test]
+fn pid_files_of_running_processes_are_left_alone() {
+    // The parent of the tests is still running while they are
+    let path = temp_path("running");
+    let parent = std::os::unix::process::parent_id();
+    fs::write(&path, format!("{}\n", parent)).unwrap();
+    assert!(PidFile::create(&path).is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", parent));
+
+    // While a stale file is taken over
+    fs::write(&path, "not a pid\n").unwrap();
+    let pid_file = PidFile::create(&path).unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        format!("{}\n", process::id())
+    );
+    drop(pid_file);
+}
+
+#[test]
+fn files_are_not_handed_over_to_unknown_users() {
+    let path = temp_path("chown");
+    fs::write(&path, "").unwrap();
+    assert!(daemon::chown(&path, "no-such-user-for-dns-server", None).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+// The environment is shared by all the tests, so the ones going through it
+// take turns in a single test
+#[test]
+fn systemd_is_told_and_asked_through_the_environment() {
+    // Sockets meant for another process are left alone
+    env::set_var("LISTEN_PID", "1");
+    env::set_var("LISTEN_FDS", "2");
+    assert!(daemon::listen_fds().unwrap().is_empty());
+    assert!(env::var_os("LISTEN_FDS").is_none());
+
+    // Nothing to tell without a socket to tell it through
+    env::remove_var("NOTIFY_SOCKET");
+    assert!(Notifier::from_env().is_none());
+
+    let path = temp_path("notify");
+    let _ = fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    env::set_var("NOTIFY_SOCKET", &path);
+    let notifier = Notifier::from_env().unwrap();
+    // Which isn't passed on to anything started from here on
+    assert!(env::var_os("NOTIFY_SOCKET").is_none());
+    notifier.ready().unwrap();
+
+    let mut buf = [0; 64];
+    let len = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+    fs::remove_file(&path).unwrap();
+}
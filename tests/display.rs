@@ -0,0 +1,89 @@
+//! Packets print the way dig prints them, and their bytes dump with every
+//! field annotated, compression pointers included.
+
+use std::net::Ipv4Addr;
+
+use dns_server::{display, BytePacketBuffer, DnsPacket, DnsRecord, QueryBuilder, QueryType};
+
+fn response() -> DnsPacket {
+    let query = QueryBuilder::new()
+        .id(4321)
+        .edns(1232)
+        .dnssec_ok()
+        .question("www.example.test", QueryType::A)
+        .build();
+
+    let mut response = DnsPacket::response_to(&query)
+        .recursion_available(true)
+        .answer(DnsRecord::A {
+            domain: "www.example.test".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: 300,
+        })
+        .build();
+    response.resources = query.resources;
+    response
+}
+
+fn wire(mut packet: DnsPacket) -> Vec<u8> {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    buffer.buf[..buffer.pos()].to_vec()
+}
+
+#[test]
+fn packets_print_like_dig() {
+    let printed = response().to_string();
+
+    assert_eq!(
+        printed,
+        ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4321\n\
+         ;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 1\n\
+         \n\
+         ;; OPT PSEUDOSECTION:\n\
+         ; EDNS: version: 0, flags: do; udp: 1232\n\
+         \n\
+         ;; QUESTION SECTION:\n\
+         ;www.example.test.\t\tIN\tA\n\
+         \n\
+         ;; ANSWER SECTION:\n\
+         www.example.test.\t300\tIN\tA\t10.0.0.1\n"
+    );
+}
+
+#[test]
+fn hex_dumps_annotate_every_field() {
+    let dump = display::hex_dump(&wire(response()));
+    let lines: Vec<&str> = dump.lines().collect();
+
+    assert_eq!(lines[0], "0000  10 e1                    id 4321");
+    assert_eq!(
+        lines[1],
+        "0002  81 80                    flags qr rd ra, opcode QUERY, rcode NOERROR"
+    );
+    assert!(lines.contains(&"0010  07 65 78 61 6d 70 6c 65  label \"example\""));
+    // The name of the answer points back at the question
+    assert!(lines.contains(&"0022  c0 0c                    pointer to 000c: www.example.test."));
+    assert!(lines.contains(&"002e  0a 00 00 01              10.0.0.1"));
+    assert!(lines.contains(&"0035  04 d0                    udp payload size 1232"));
+    assert!(
+        lines.contains(&"0037  00 00 80 00              extended rcode 0, version 0, flags 0x8000")
+    );
+}
+
+#[test]
+fn hex_dumps_stop_where_the_packet_breaks() {
+    let mut data = wire(response());
+    data.truncate(0x30);
+
+    let dump = display::hex_dump(&data);
+
+    assert!(
+        dump.ends_with(
+            "002c  00 04                    data length 4\n\
+             002e  0a 00                    record data (truncated)\n"
+        ),
+        "{}",
+        dump
+    );
+}
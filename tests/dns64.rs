@@ -0,0 +1,71 @@
+//! Addresses are embedded in the DNS64 prefix as RFC 6052 lays out, and the
+//! records synthesized out of them live no longer than the SOA says the lack
+//! of AAAA records does.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use dns_server::{
+    dns64::{negative_ttl, Dns64},
+    DnsPacket, DnsRecord,
+};
+
+#[test]
+fn addresses_are_embedded_in_the_prefix() {
+    let addr = Ipv4Addr::new(192, 0, 2, 33);
+    let dns64 = Dns64::parse("64:ff9b::/96").unwrap();
+    assert_eq!(
+        dns64.synthesize(addr),
+        "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap()
+    );
+
+    // Shorter prefixes skip over bits 64 to 71 (RFC 6052, section 2.2)
+    let dns64 = Dns64::parse("2001:db8::/40").unwrap();
+    assert_eq!(
+        dns64.synthesize(addr),
+        "2001:db8:c0:2:21::".parse::<Ipv6Addr>().unwrap()
+    );
+    assert!(Dns64::parse("64:ff9b::/80").is_err());
+}
+
+#[test]
+fn synthesized_records_live_no_longer_than_the_soa() {
+    let mut response = DnsPacket::new();
+    assert_eq!(negative_ttl(&response), 600);
+    response.authorities.push(DnsRecord::SOA {
+        domain: "example.com".to_string(),
+        mname: "ns1.example.com".to_string(),
+        rname: "hostmaster.example.com".to_string(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 300,
+        ttl: 3600,
+    });
+    assert_eq!(negative_ttl(&response), 300);
+
+    let dns64 = Dns64::parse("64:ff9b::/96").unwrap();
+    let records = [
+        DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            host: "example.com".to_string(),
+            ttl: 60,
+        },
+        DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 33),
+            ttl: 3600,
+        },
+    ];
+    assert_eq!(
+        dns64.synthesize_records(&records, negative_ttl(&response)),
+        [
+            records[0].clone(),
+            DnsRecord::AAAA {
+                domain: "example.com".to_string(),
+                addr: "64:ff9b::c000:221".parse().unwrap(),
+                ttl: 300,
+            },
+        ]
+    );
+}
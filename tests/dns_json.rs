@@ -0,0 +1,78 @@
+//! Responses come out as the JSON of the DNS over HTTPS APIs, and with the
+//! `serde` feature, packets make it through any serde format and back.
+
+use std::net::Ipv4Addr;
+
+use dns_server::{dns_json, DnsPacket, DnsRecord, QueryBuilder, QueryType, ResultCode};
+
+fn response() -> DnsPacket {
+    let query = QueryBuilder::new()
+        .id(4321)
+        .edns(1232)
+        .question("example.test", QueryType::TXT)
+        .build();
+
+    let mut response = DnsPacket::response_to(&query)
+        .recursion_available(true)
+        .answer(DnsRecord::TXT {
+            domain: "example.test".to_string(),
+            data: vec!["say \"hi\"".to_string()],
+            ttl: 300,
+        })
+        .additional(DnsRecord::A {
+            domain: "ns.example.test".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 53),
+            ttl: 60,
+        })
+        .build();
+    response.resources.extend(query.resources);
+    response
+}
+
+#[test]
+fn responses_in_doh_json() {
+    assert_eq!(
+        dns_json::to_dns_json(&response()),
+        r#"{"Status":0,"TC":false,"RD":true,"RA":true,"AD":false,"CD":false,"#.to_string()
+            + r#""Question":[{"name":"example.test.","type":16}],"#
+            + r#""Answer":[{"name":"example.test.","type":16,"TTL":300,"data":"\"say \\\"hi\\\"\""}],"#
+            + r#""Additional":[{"name":"ns.example.test.","type":1,"TTL":60,"data":"10.0.0.53"}]}"#
+    );
+}
+
+#[test]
+fn empty_sections_are_left_out() {
+    let query = DnsPacket::query("nope.example.test", QueryType::A);
+    let response = DnsPacket::response_to(&query)
+        .rcode(ResultCode::NXDOMAIN)
+        .build();
+
+    let json = dns_json::to_dns_json(&response);
+    assert!(json.starts_with(r#"{"Status":3,"#), "{}", json);
+    assert!(json.ends_with(r#""Question":[{"name":"nope.example.test.","type":1}]}"#));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn packets_round_trip_through_serde() {
+    let mut response = response();
+    response.answers.push(DnsRecord::UNKNOWN {
+        domain: "example.test".to_string(),
+        qtype: 65280,
+        class: 1,
+        data_len: 2,
+        raw: vec![0xbe, 0xef],
+        ttl: 300,
+    });
+
+    let serialized = toml::to_string(&response).unwrap();
+    let parsed: DnsPacket = toml::from_str(&serialized).unwrap();
+
+    assert!(serialized.contains("qtype = \"TXT\""), "{}", serialized);
+    assert!(
+        serialized.contains("rescode = \"NOERROR\""),
+        "{}",
+        serialized
+    );
+    assert_eq!(parsed, response);
+}
@@ -0,0 +1,257 @@
+//! Packets can be read out of hex dumps, the way they're found in logs and
+//! packet captures. Records that don't fit are cut off, though not the OPT
+//! record, minimal responses keep nothing of the additional section but the
+//! OPT record, and questions are written back as they came and keyed
+//! regardless of the case of their names.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use dns_server::{
+    client::AddressFamily, BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType,
+};
+
+#[test]
+fn glue_points_into_the_authority_section() {
+    // A referral for www.example.com, the way name servers put it together:
+    // the owners of the NS records point at example.com in the question, and
+    // the owners of the glue at the name servers in the authority section
+    let data = b"\x12\x34\x81\x00\x00\x01\x00\x00\x00\x02\x00\x02\
+        \x03www\x07example\x03com\x00\x00\x01\x00\x01\
+        \xC0\x10\x00\x02\x00\x01\x00\x00\x0E\x10\x00\x06\x03ns1\xC0\x10\
+        \xC0\x10\x00\x02\x00\x01\x00\x00\x0E\x10\x00\x06\x03ns2\xC0\x10\
+        \xC0\x2D\x00\x01\x00\x01\x00\x00\x0E\x10\x00\x04\xC0\x00\x02\x01\
+        \xC0\x3F\x00\x01\x00\x01\x00\x00\x0E\x10\x00\x04\xC0\x00\x02\x02";
+    let mut buffer = BytePacketBuffer::new();
+    buffer.buf[..data.len()].copy_from_slice(data);
+    let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+    let ns: Vec<_> = packet.get_ns("www.example.com").collect();
+    assert_eq!(
+        ns,
+        [
+            ("example.com", "ns1.example.com"),
+            ("example.com", "ns2.example.com")
+        ]
+    );
+    assert_eq!(
+        packet.resources,
+        [
+            DnsRecord::A {
+                domain: "ns1.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 3600,
+            },
+            DnsRecord::A {
+                domain: "ns2.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 2),
+                ttl: 3600,
+            },
+        ]
+    );
+    assert_eq!(
+        packet.get_resolved_ns("www.example.com", AddressFamily::Ipv4),
+        Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+    );
+}
+
+#[test]
+fn packets_parse_from_hex_dumps() {
+    let packet = DnsPacket::from_hex(
+        "3de8 0120 0001 0000 0000 0000\n\
+         06 676f6f676c65 03 636f6d 00 0001 0001",
+    )
+    .unwrap();
+
+    assert_eq!(packet.header.id, 0x3de8);
+    assert!(packet.header.recursion_desired);
+    assert_eq!(packet.questions.len(), 1);
+    assert_eq!(packet.questions[0].name, "google.com");
+    assert_eq!(packet.questions[0].qtype, QueryType::A);
+
+    // The same without any whitespace, in uppercase
+    let packed =
+        DnsPacket::from_hex("3DE80120000100000000000006676F6F676C6503636F6D0000010001").unwrap();
+    assert_eq!(packed.header.id, 0x3de8);
+    assert_eq!(packed.questions, packet.questions);
+}
+
+#[test]
+fn hex_dumps_have_to_be_whole_bytes_of_hex() {
+    for dump in ["3de80", "3de8 012", "3de8 01zz", "0x3de8"] {
+        assert!(DnsPacket::from_hex(dump).is_err(), "{}", dump);
+    }
+}
+
+#[test]
+fn records_that_dont_fit_are_cut_off() {
+    let mut packet = DnsPacket::query("www.example.com", QueryType::A);
+    packet.header.response = true;
+    packet.answers = (1..=40)
+        .map(|i| DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, i),
+            ttl: 300,
+        })
+        .collect();
+
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    assert!(packet.header.truncated_message);
+
+    // The header only counts the records that made it into the packet,
+    // all of which are whole
+    buffer.seek(0).unwrap();
+    let response = DnsPacket::from_buffer(&mut buffer).unwrap();
+    assert!(response.header.truncated_message);
+    assert_eq!(response.answers.len(), response.header.answers as usize);
+    assert!(!response.answers.is_empty());
+    assert_eq!(
+        response.answers[..],
+        packet.answers[..response.answers.len()]
+    );
+}
+
+/// A response to a query for www.example.com with `answers` addresses, and
+/// glue for as many name servers
+fn response_with(answers: u8, glue: u8) -> DnsPacket {
+    let mut packet = DnsPacket::query("www.example.com", QueryType::A);
+    packet.header.response = true;
+    packet.answers = (1..=answers)
+        .map(|i| DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, i),
+            ttl: 300,
+        })
+        .collect();
+    packet.resources = (1..=glue)
+        .map(|i| DnsRecord::A {
+            domain: format!("ns{}.example.com", i),
+            addr: Ipv4Addr::new(192, 0, 2, i),
+            ttl: 300,
+        })
+        .collect();
+    packet.resources.push(DnsRecord::OPT {
+        packet_len: 1232,
+        flags: 0,
+        data: Vec::new(),
+    });
+    packet
+}
+
+fn write_and_read(packet: &mut DnsPacket) -> DnsPacket {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    assert!(buffer.pos() <= 512);
+    let len = buffer.pos();
+    DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buffer.buf[..len])).unwrap()
+}
+
+#[test]
+fn truncated_packets_keep_their_opt_record() {
+    let mut packet = response_with(40, 0);
+    let response = write_and_read(&mut packet);
+
+    assert!(response.header.truncated_message);
+    assert!(!response.answers.is_empty());
+    assert!(response.answers.len() < 40);
+    assert_eq!(response.resources.len(), 1);
+    assert_eq!(response.get_opt(), packet.resources.last());
+}
+
+#[test]
+fn extras_that_dont_fit_are_left_out_without_truncating() {
+    let mut packet = response_with(2, 40);
+    let response = write_and_read(&mut packet);
+
+    // Everything that was asked for is there, and the glue that made it in
+    // is whole
+    assert!(!response.header.truncated_message);
+    assert_eq!(response.answers, packet.answers);
+    assert!(response.resources.len() < 41);
+    assert_eq!(response.get_opt(), packet.resources.last());
+    let glue = response.resources.len() - 1;
+    assert_eq!(response.resources[..glue], packet.resources[..glue]);
+}
+
+#[test]
+fn minimal_responses_keep_the_opt_record_alone() {
+    let opt = DnsRecord::OPT {
+        packet_len: 1232,
+        flags: 0,
+        data: Vec::new(),
+    };
+    let mut packet = DnsPacket::new();
+    packet.answers.push(DnsRecord::NS {
+        domain: "example.com".to_string(),
+        host: "ns1.example.com".to_string(),
+        ttl: 300,
+    });
+    packet.resources.push(DnsRecord::A {
+        domain: "ns1.example.com".to_string(),
+        addr: Ipv4Addr::new(192, 0, 2, 1),
+        ttl: 300,
+    });
+    packet.resources.push(opt.clone());
+    packet.header.resource_entries = 2;
+
+    packet.strip_additional();
+
+    assert_eq!(packet.resources, [opt]);
+    assert_eq!(packet.header.resource_entries, 1);
+    assert_eq!(packet.answers.len(), 1);
+}
+
+/// Parse the question at the start of `data` and write it back out
+fn rewrite(data: &[u8], keep_raw: bool) -> Vec<u8> {
+    let mut buffer = BytePacketBuffer::new();
+    buffer.buf[..data.len()].copy_from_slice(data);
+    let mut question = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0));
+    question.read(&mut buffer).unwrap();
+    if !keep_raw {
+        question.raw = None;
+    }
+
+    let mut buffer = BytePacketBuffer::new();
+    question.write(&mut buffer).unwrap();
+    buffer.buf[..buffer.pos()].to_vec()
+}
+
+#[test]
+fn questions_are_written_back_byte_for_byte() {
+    let mixed_case = b"\x03wWw\x07ExAmPlE\x04TeSt\x00\x00\x01\x00\x01";
+    // A dot within a label, which reads the same as two labels
+    let dotted = b"\x05w.w.w\x07example\x04test\x00\x00\x01\x00\x01";
+    assert_eq!(rewrite(mixed_case, true), mixed_case);
+    assert_eq!(rewrite(dotted, true), dotted);
+
+    // Written from the parsed name, the case survives but the label doesn't
+    assert_eq!(rewrite(mixed_case, false), mixed_case);
+    assert_ne!(rewrite(dotted, false), dotted);
+}
+
+#[test]
+fn cache_keys_ignore_the_case_of_names() {
+    let key = |name: &str, qtype| DnsQuestion::new(name.to_string(), qtype).cache_key();
+
+    assert_eq!(key("www.example.com", QueryType::A), "www.example.com|1|1");
+    assert_eq!(
+        key("WwW.ExAmPlE.cOm.", QueryType::A),
+        key("www.example.com", QueryType::A)
+    );
+    assert_ne!(
+        key("www.example.com", QueryType::AAAA),
+        key("www.example.com", QueryType::A)
+    );
+
+    // Questions off the wire carry their class along, CH here
+    let mut buffer = BytePacketBuffer::new();
+    let data = b"\x07version\x04bind\x00\x00\x10\x00\x03";
+    buffer.buf[..data.len()].copy_from_slice(data);
+    let mut chaos = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0));
+    chaos.read(&mut buffer).unwrap();
+    assert_eq!(chaos.cache_key(), "version.bind|16|3");
+    assert_ne!(
+        chaos.cache_key(),
+        key("version.bind", QueryType::UNKNOWN(16))
+    );
+}
@@ -0,0 +1,142 @@
+//! Responses are validated against the keys of their zone, which have to
+//! match a trust anchor. The fixture is `example.test` signed with a single
+//! Ed25519 key, with signatures that are valid from November 2023 to 2091.
+#![cfg(feature = "dnssec")]
+
+use std::{
+    net::{Ipv4Addr, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    dnssec::{Validation, Validator},
+    error::DnsError,
+    forwarder::{Forwarder, Upstream},
+    resolver::ResolverOptions,
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType,
+};
+
+const ANCHOR: &str = "example.test. IN DS 34259 15 2 \
+    5d380f2d2da946ed64a23ebce78aae217fce32c3d10da77a190261b9e15f51a5";
+
+const PUBLIC_KEY: &str = "03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+
+/// The signature over the DNSKEY RRset, and over the A record of `www`
+const KEYSET_SIGNATURE: &str = "1a5890ba3df8f97292bddd224dff8d78c510dd3dde39638d9a04007b99eb88fd\
+    6a021aa84635b347a8c7403cec7d15ddcc381ff04482c5ed0027b1c1480af80a";
+const A_SIGNATURE: &str = "a6336c1b7c5a07d74c0c8407872178abedb2e9a5027f08533d48c86d500c8038\
+    f6c1750127fdff27f43b1cf68b1df484729fad61e2b588356acac0788e8eb104";
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn rrsig(domain: &str, type_covered: QueryType, signature: &str) -> DnsRecord {
+    DnsRecord::RRSIG {
+        domain: domain.to_string(),
+        type_covered,
+        algorithm: 15,
+        labels: domain.split('.').count() as u8,
+        original_ttl: 3600,
+        expiration: 3847483647,
+        inception: 1700000000,
+        key_tag: 34259,
+        signer: "example.test".to_string(),
+        signature: hex(signature),
+        ttl: 3600,
+    }
+}
+
+/// A response to `query`, with `answers` in its answer section
+fn response(query: &DnsPacket, answers: Vec<DnsRecord>) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.header.id = query.header.id;
+    response.header.response = true;
+    response.questions = query.questions.clone();
+    response.answers = answers;
+    response
+}
+
+/// An upstream handing out the signed keys of `example.test`
+fn keys() -> Upstream {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(query.questions[0].qtype, QueryType::DNSKEY);
+
+        let keys = vec![
+            DnsRecord::DNSKEY {
+                domain: "example.test".to_string(),
+                flags: 257,
+                protocol: 3,
+                algorithm: 15,
+                public_key: hex(PUBLIC_KEY),
+                ttl: 3600,
+            },
+            rrsig("example.test", QueryType::DNSKEY, KEYSET_SIGNATURE),
+        ];
+        let mut response = response(&query, keys);
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    Upstream::Plain(addr)
+}
+
+/// The signed answer for `www.example.test`, with `addr` in place of the
+/// address that was signed
+fn answer(addr: Ipv4Addr, signature: &str) -> DnsPacket {
+    let query = DnsPacket::query("www.example.test", QueryType::A);
+    let answers = vec![
+        DnsRecord::A {
+            domain: "www.example.test".to_string(),
+            addr,
+            ttl: 3600,
+        },
+        rrsig("www.example.test", QueryType::A, signature),
+    ];
+    response(&query, answers)
+}
+
+#[test]
+fn signatures_are_checked_up_to_the_anchor() {
+    let mut validator = Validator::new();
+    validator.add_anchor(ANCHOR).unwrap();
+    let options = Arc::new(ResolverOptions {
+        forwarder: Some(Forwarder::new(vec![keys()])),
+        ..ResolverOptions::default()
+    });
+    let validate = |response: &DnsPacket| {
+        validator.validate("www.example.test", QueryType::A, response, &options)
+    };
+
+    let signed = answer(Ipv4Addr::new(10, 0, 0, 1), A_SIGNATURE);
+    assert_eq!(validate(&signed).unwrap(), Validation::Secure);
+
+    // Neither the records nor the signature can be touched
+    let tampered = answer(Ipv4Addr::new(10, 0, 0, 2), A_SIGNATURE);
+    assert!(matches!(validate(&tampered), Err(DnsError::Dnssec(_))));
+    let mut signature = A_SIGNATURE.to_string();
+    signature.replace_range(..2, "a7");
+    let tampered = answer(Ipv4Addr::new(10, 0, 0, 1), &signature);
+    assert!(matches!(validate(&tampered), Err(DnsError::Dnssec(_))));
+
+    // And the keys are only good along with the anchor that vouches for them
+    let mut validator = Validator::new();
+    validator
+        .add_anchor(&ANCHOR.replace("5d380f2d", "5d380f2e"))
+        .unwrap();
+    assert!(matches!(
+        validator.validate("www.example.test", QueryType::A, &signed, &options),
+        Err(DnsError::Dnssec(_))
+    ));
+}
@@ -0,0 +1,157 @@
+//! Queries are answered over HTTPS both as the `dns` parameter of a GET
+//! request and as the body of a POST request, while requests that don't
+//! make sense as either get an HTTP error, or the connection closed on them.
+#![cfg(feature = "tls")]
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use dns_server::{
+    client::QueryOptions, doh, forwarder::DohUrl, resolver::ResolverOptions, tls::TlsClient,
+    zone::Zone, QueryType,
+};
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+/// A server for `example.test` with a certificate for `localhost`, along with
+/// the certificates to trust it with
+fn serve() -> (SocketAddr, RootCertStore) {
+    // Each server gets files of its own, as the tests run at the same time
+    static SERVERS: AtomicUsize = AtomicUsize::new(0);
+    let id = format!(
+        "{}-{}",
+        std::process::id(),
+        SERVERS.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("dns-server-doh-{}.crt", id));
+    let key = dir.join(format!("dns-server-doh-{}.key", id));
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    let config = doh::load_server_config(&cert, &key).unwrap();
+    fs::remove_file(&cert).unwrap();
+    fs::remove_file(&key).unwrap();
+
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A 10.0.0.1\n",
+    )
+    .unwrap();
+    let options = ResolverOptions {
+        zones: vec![zone],
+        ..ResolverOptions::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || doh::serve(listener, Arc::new(config), Arc::new(options)));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    (addr, roots)
+}
+
+/// Send `request` as it is, and read whatever comes back until the server
+/// closes the connection
+fn send(server: SocketAddr, roots: &RootCertStore, request: &str) -> String {
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots.clone())
+        .with_no_client_auth();
+    let name = ServerName::try_from("localhost").unwrap();
+    let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut stream = StreamOwned::new(connection, TcpStream::connect(server).unwrap());
+    stream.write_all(request.as_bytes()).unwrap();
+    stream.flush().unwrap();
+
+    // A connection the server gives up on isn't closed cleanly
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[test]
+fn queries_are_answered_over_get_and_post() {
+    let (server, roots) = serve();
+    let client = TlsClient::with_roots(roots);
+
+    for get in [true, false] {
+        let url = DohUrl {
+            host: "localhost".to_string(),
+            addr: server,
+            path: doh::PATH.to_string(),
+            get,
+        };
+        let response = doh::query(
+            &client,
+            "www.example.test",
+            QueryType::A,
+            &url,
+            &QueryOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+}
+
+#[test]
+fn dns_parameters_are_base64url() {
+    let (server, roots) = serve();
+
+    // A query for www.example.test without padding, as in RFC 8484
+    let response = send(
+        server,
+        &roots,
+        "GET /dns-query?dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlBHRlc3QAAAEAAQ HTTP/1.1\r\n\
+         Host: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: application/dns-message\r\n"));
+
+    let response = send(
+        server,
+        &roots,
+        "GET /dns-query?dns=AAAB*AAB HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+}
+
+#[test]
+fn malformed_requests_are_rejected() {
+    let (server, roots) = serve();
+
+    let response = send(
+        server,
+        &roots,
+        "POST /dns-query HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\n\
+         Content-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+
+    // Without anything after the method, there's no path to answer on
+    let response = send(server, &roots, "GET\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    // There's no telling where a body of an unknown length ends, nor any
+    // point in reading one too large for a DNS message
+    for len in ["twelve", "-1", "65536"] {
+        let response = send(
+            server,
+            &roots,
+            &format!(
+                "POST /dns-query HTTP/1.1\r\nHost: localhost\r\n\
+                 Content-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+                len
+            ),
+        );
+        assert_eq!(response, "");
+    }
+}
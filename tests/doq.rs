@@ -0,0 +1,111 @@
+//! Queries sent over QUIC are answered on the stream they came in on, over a
+//! connection that's kept open for the queries after them.
+#![cfg(feature = "doq")]
+
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    client::QueryOptions,
+    doq::{self, QuicClient},
+    forwarder::{Forwarder, Upstream},
+    resolver::ResolverOptions,
+    zone::Zone,
+    QueryType, ResultCode,
+};
+use rustls::RootCertStore;
+
+/// A server for `example.test` with a certificate for `localhost`, along with
+/// a client that trusts it
+fn serve() -> (SocketAddr, QuicClient) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("dns-server-doq-{}.crt", std::process::id()));
+    let key = dir.join(format!("dns-server-doq-{}.key", std::process::id()));
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    let config = doq::load_server_config(&cert, &key).unwrap();
+    fs::remove_file(&cert).unwrap();
+    fs::remove_file(&key).unwrap();
+
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A 10.0.0.1\n",
+    )
+    .unwrap();
+    let options = ResolverOptions {
+        zones: vec![zone],
+        ..ResolverOptions::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || doq::serve(socket, Arc::new(config), Arc::new(options)));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    (addr, QuicClient::with_roots(roots))
+}
+
+#[test]
+fn queries_are_answered_over_quic() {
+    let (server, client) = serve();
+    let options = QueryOptions::default();
+
+    for _ in 0..3 {
+        let response = client
+            .query(
+                "www.example.test",
+                QueryType::A,
+                server,
+                "localhost",
+                &options,
+            )
+            .unwrap();
+        assert_eq!(response.header.id, 0);
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    let response = client
+        .query(
+            "nope.example.test",
+            QueryType::A,
+            server,
+            "localhost",
+            &options,
+        )
+        .unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+
+    // The certificate is only good for the name it was issued for
+    assert!(client
+        .query(
+            "www.example.test",
+            QueryType::A,
+            server,
+            "example.test",
+            &options
+        )
+        .is_err());
+}
+
+#[test]
+fn quic_upstreams_parse() {
+    assert_eq!(
+        Forwarder::parse_upstream("quic://94.140.14.14#dns.adguard-dns.com").unwrap(),
+        Upstream::Quic {
+            addr: "94.140.14.14:853".parse().unwrap(),
+            name: "dns.adguard-dns.com".to_string(),
+        }
+    );
+    assert_eq!(
+        Forwarder::parse_upstream("quic://[2a10:50c0::ad1:ff]:8853")
+            .unwrap()
+            .to_string(),
+        "quic://[2a10:50c0::ad1:ff]:8853#2a10:50c0::ad1:ff"
+    );
+}
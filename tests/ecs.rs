@@ -0,0 +1,146 @@
+//! The network of the client is passed on to the upstreams in the EDNS Client
+//! Subnet option, and the answers meant for a network are only handed out to
+//! the clients in it.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    cache::Cache,
+    ecs::{ClientSubnet, ClientSubnetPolicy},
+    forwarder::{Forwarder, Upstream},
+    pipeline::Query,
+    resolver::{self, ResolverOptions, Source},
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryBuilder, QueryType,
+};
+
+#[test]
+fn subnets_are_written_with_only_the_bytes_of_their_prefix() {
+    let subnet = ClientSubnet::new("203.0.113.77".parse().unwrap(), 24);
+    assert_eq!(subnet.to_string(), "203.0.113.0/24");
+    let option = subnet.to_option();
+    assert_eq!(option, [0, 8, 0, 7, 0, 1, 24, 0, 203, 0, 113]);
+    assert_eq!(ClientSubnet::parse(&option[4..]).unwrap(), subnet);
+
+    let subnet = ClientSubnet::new("2001:db8:1234:5678::1".parse().unwrap(), 56);
+    assert_eq!(subnet.to_string(), "2001:db8:1234:5600::/56");
+    assert_eq!(subnet.to_option().len(), 4 + 4 + 7);
+
+    // More bytes of the address than the prefix takes
+    assert!(ClientSubnet::parse(&[0, 1, 8, 0, 10, 0]).is_err());
+
+    let query = QueryBuilder::new()
+        .question("www.example.com", QueryType::A)
+        .edns(1232)
+        .client_subnet(subnet)
+        .build();
+    assert_eq!(ClientSubnet::from_packet(&query), Some(subnet));
+}
+
+#[test]
+fn only_the_networks_of_public_clients_are_passed_on() {
+    let policy = ClientSubnetPolicy::new();
+    for local in [
+        "127.0.0.1",
+        "192.168.1.10",
+        "10.1.2.3",
+        "::1",
+        "fd00::1",
+        "fe80::1",
+    ] {
+        assert_eq!(policy.subnet_for(local.parse().unwrap()), None);
+    }
+
+    let subnet = policy.subnet_for("::ffff:198.51.100.9".parse().unwrap());
+    assert_eq!(subnet.unwrap().to_string(), "198.51.100.0/24");
+}
+
+/// An upstream that answers each query with the network it was asked on
+/// behalf of, 10.0.0.1 for 203.0.113.0/24 and 10.0.0.2 for any other, and
+/// says the answer depends on it
+fn answer_by_subnet(queries: usize) -> Upstream {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for _ in 0..queries {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+            let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+            let mut subnet = ClientSubnet::from_packet(&query).unwrap();
+            let last = match subnet.addr {
+                IpAddr::V4(addr) if addr.octets()[..3] == [203, 0, 113] => 1,
+                _ => 2,
+            };
+            subnet.scope_prefix = 24;
+            let mut response = DnsPacket::response_to(&query)
+                .answer(DnsRecord::A {
+                    domain: query.questions[0].name.clone(),
+                    addr: Ipv4Addr::new(10, 0, 0, last),
+                    ttl: 300,
+                })
+                .build();
+            response.resources.push(DnsRecord::OPT {
+                packet_len: 1232,
+                flags: 0,
+                data: subnet.to_option(),
+            });
+            response.header.resource_entries = 1;
+
+            let mut buffer = BytePacketBuffer::new();
+            response.write(&mut buffer).unwrap();
+            socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+        }
+    });
+
+    Upstream::Plain(addr)
+}
+
+fn lookup_for(client: &str, options: &Arc<ResolverOptions>) -> (Ipv4Addr, Source) {
+    let client: IpAddr = client.parse().unwrap();
+    let (response, source) = resolver::resolve_query(&Query {
+        name: "cdn.example.com",
+        qtype: QueryType::A,
+        client: Some(SocketAddr::new(client, 5353)),
+        options,
+    })
+    .unwrap();
+
+    (response.get_random_a().unwrap(), source)
+}
+
+#[test]
+fn answers_are_cached_for_the_network_they_are_meant_for() {
+    let cache = Cache::new();
+    let options = Arc::new(ResolverOptions {
+        forwarder: Some(Forwarder::new(vec![answer_by_subnet(2)])),
+        cache: Some(cache.clone()),
+        client_subnet: Some(ClientSubnetPolicy::new()),
+        ..ResolverOptions::default()
+    });
+
+    let first = Ipv4Addr::new(10, 0, 0, 1);
+    let second = Ipv4Addr::new(10, 0, 0, 2);
+    assert_eq!(
+        lookup_for("203.0.113.5", &options),
+        (first, Source::Upstream)
+    );
+    // Another client of the same network gets the cached answer
+    assert_eq!(lookup_for("203.0.113.99", &options), (first, Source::Cache));
+    // While one elsewhere is asked about again
+    assert_eq!(
+        lookup_for("198.51.100.7", &options),
+        (second, Source::Upstream)
+    );
+    assert_eq!(
+        lookup_for("198.51.100.8", &options),
+        (second, Source::Cache)
+    );
+
+    // The answers aren't for everyone
+    assert!(cache.get("cdn.example.com", QueryType::A).is_none());
+}
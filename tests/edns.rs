@@ -0,0 +1,59 @@
+//! Version 0 is the only version of EDNS there is, clients asking for a newer
+//! one get `BADVERS` back, with the upper bits of it in the OPT record.
+
+use std::{net::UdpSocket, sync::Arc, thread, time::Duration};
+
+use dns_server::{
+    resolver::ResolverOptions, server::handle_udp_query, BytePacketBuffer, DnsPacket, DnsRecord,
+    QueryType, ResultCode,
+};
+
+/// A query for www.example.test with EDNS of `version`, and `options` in
+/// its OPT record
+fn query(version: u8, options: Vec<u8>) -> DnsPacket {
+    let mut query = DnsPacket::query("www.example.test", QueryType::A);
+    query.resources.push(DnsRecord::OPT {
+        packet_len: 1232,
+        flags: (version as u32) << 16,
+        data: options,
+    });
+    query
+}
+
+/// Send `query` to a server on the loopback interface, handing back the
+/// response as it came in as well as parsed
+fn ask(options: ResolverOptions, mut query: DnsPacket) -> (Vec<u8>, DnsPacket) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        handle_udp_query(&socket, &Arc::new(options)).unwrap();
+    });
+
+    let mut buffer = BytePacketBuffer::with_capacity(2048);
+    query.write(&mut buffer).unwrap();
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.send_to(&buffer.buf[..buffer.pos()], server).unwrap();
+    let mut buf = [0; 1232];
+    let (len, _) = client.recv_from(&mut buf).unwrap();
+    let response = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buf[..len]));
+    (buf[..len].to_vec(), response.unwrap())
+}
+
+#[test]
+fn newer_edns_versions_get_badvers() {
+    let (data, response) = ask(ResolverOptions::default(), query(1, Vec::new()));
+    assert_eq!(response.header.rescode, ResultCode::BADVERS);
+    assert!(response.answers.is_empty());
+    // 16 doesn't fit in the four bits of the header, the OPT record holds
+    // the upper eight, and tells the client we speak version 0
+    assert_eq!(data[3] & 0x0F, 0);
+    assert_eq!(response.edns_version(), Some(0));
+    match response.get_opt() {
+        Some(DnsRecord::OPT { flags, .. }) => assert_eq!(flags >> 24, 1),
+        opt => panic!("{:?}", opt),
+    }
+}
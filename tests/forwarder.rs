@@ -0,0 +1,199 @@
+//! Which upstreams a `Forwarder` tries, and in what order, a SERVFAIL moving
+//! on to the next of them. Names in configured domains are routed to the
+//! upstreams of their own.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use dns_server::{
+    client::QueryOptions,
+    forwarder::{DomainRoutes, Forwarder, SelectionPolicy, Upstream},
+    resolver::{self, ResolverOptions},
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType, ResultCode,
+};
+
+fn upstream(port: u16) -> Upstream {
+    Upstream::Plain(SocketAddr::from(([127, 0, 0, 1], port)))
+}
+
+/// An upstream that answers a single query with 10.0.0.1
+fn answer_once() -> Upstream {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut response = DnsPacket::response_to(&query)
+            .answer(DnsRecord::A {
+                domain: query.questions[0].name.clone(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: 300,
+            })
+            .build();
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    Upstream::Plain(addr)
+}
+
+/// An upstream that answers every query with
+/// `rescode` after `delay`, along with an address for the name unless it's
+/// a SERVFAIL
+fn answering(rescode: ResultCode, delay: Duration) -> Upstream {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+        thread::sleep(delay);
+
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.header.rescode = rescode;
+        response.questions = query.questions.clone();
+        if rescode != ResultCode::SERVFAIL {
+            response.answers.push(DnsRecord::A {
+                domain: query.questions[0].name.clone(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: 300,
+            });
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    Upstream::Plain(addr)
+}
+
+#[test]
+fn round_robin_starts_at_the_next_upstream() {
+    let mut forwarder = Forwarder::new(vec![upstream(1), upstream(2), upstream(3)]);
+    forwarder.policy = SelectionPolicy::RoundRobin;
+
+    let first: Vec<Upstream> = (0..4).map(|_| forwarder.select()[0].clone()).collect();
+    assert_eq!(first, [upstream(1), upstream(2), upstream(3), upstream(1)]);
+}
+
+#[test]
+fn upstreams_that_fail_are_tried_last() {
+    // Nothing listens on the first port, so the query is refused right away
+    let dead = upstream(1);
+    let alive = answer_once();
+    let forwarder = Forwarder::new(vec![dead.clone(), alive.clone()]);
+
+    let response = forwarder
+        .forward("www.example.com", QueryType::A, &QueryOptions::default())
+        .unwrap();
+    assert_eq!(response.answers.len(), 1);
+
+    assert!(forwarder.is_down(&dead));
+    assert!(!forwarder.is_down(&alive));
+    assert_eq!(forwarder.select(), [alive, dead]);
+}
+
+#[test]
+fn upstreams_may_leave_out_the_port() {
+    assert_eq!(
+        Forwarder::parse_upstream("192.0.2.1").unwrap(),
+        Upstream::Plain("192.0.2.1:53".parse().unwrap())
+    );
+    assert_eq!(
+        Forwarder::parse_upstream("[2001:db8::1]:5353").unwrap(),
+        Upstream::Plain("[2001:db8::1]:5353".parse().unwrap())
+    );
+    assert!(Forwarder::parse_upstream("dns.example").is_err());
+}
+
+#[test]
+fn servfail_moves_on_to_the_next_upstream() {
+    let forwarder = Forwarder::new(vec![
+        answering(ResultCode::SERVFAIL, Duration::ZERO),
+        answering(ResultCode::NOERROR, Duration::ZERO),
+    ]);
+    let response = forwarder
+        .forward("www.example.com", QueryType::A, &QueryOptions::default())
+        .unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+    // With nothing better to go on, the SERVFAIL itself is relayed
+    let forwarder = Forwarder::new(vec![answering(ResultCode::SERVFAIL, Duration::ZERO)]);
+    let response = forwarder
+        .forward("www.example.com", QueryType::A, &QueryOptions::default())
+        .unwrap();
+    assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+}
+
+#[test]
+fn the_fastest_upstream_is_tried_first() {
+    let slow = answering(ResultCode::NOERROR, Duration::from_millis(150));
+    let fast = answering(ResultCode::NOERROR, Duration::ZERO);
+    let mut forwarder = Forwarder::new(vec![slow.clone(), fast.clone()]);
+    forwarder.policy = "fastest".parse().unwrap();
+
+    // Either of them is measured once, the one we haven't heard from going
+    // first
+    for expected in [&slow, &fast] {
+        assert_eq!(&forwarder.select()[0], expected);
+        let start = Instant::now();
+        forwarder
+            .forward("www.example.com", QueryType::A, &QueryOptions::default())
+            .unwrap();
+        if expected == &slow {
+            assert!(start.elapsed() >= Duration::from_millis(150));
+        }
+    }
+
+    for _ in 0..4 {
+        assert_eq!(forwarder.select(), [fast.clone(), slow.clone()]);
+    }
+}
+
+#[test]
+fn names_are_routed_to_their_most_specific_domain() {
+    let mut routes = DomainRoutes::new();
+    routes.insert("*.corp.example", Forwarder::new(vec![upstream(1)]));
+    routes.insert("lab.corp.example.", Forwarder::new(vec![upstream(2)]));
+
+    let route = |qname| {
+        routes
+            .route(qname)
+            .map(|forwarder| forwarder.upstreams[0].clone())
+    };
+    assert_eq!(route("corp.example"), Some(upstream(1)));
+    assert_eq!(route("WWW.Corp.Example."), Some(upstream(1)));
+    assert_eq!(route("host.lab.corp.example"), Some(upstream(2)));
+    assert_eq!(route("notcorp.example"), None);
+    assert_eq!(route("example"), None);
+}
+
+#[test]
+fn routed_names_skip_the_global_upstreams() {
+    // Nothing listens on the global upstream, so only the route can answer
+    let mut options = ResolverOptions {
+        forwarder: Some(Forwarder::new(vec![upstream(1)])),
+        ..ResolverOptions::default()
+    };
+    options
+        .routes
+        .insert("corp.example", Forwarder::new(vec![answer_once()]));
+    let options = Arc::new(options);
+
+    let response = resolver::lookup("intranet.corp.example", QueryType::A, &options).unwrap();
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    assert!(resolver::lookup("www.example.com", QueryType::A, &options).is_err());
+}
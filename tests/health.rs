@@ -0,0 +1,92 @@
+//! Addresses whose backends fail their health checks are left out of local
+//! answers until they pass again, unless every address of the name is down.
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, TcpListener},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use dns_server::{
+    health::{HealthChecks, Probe},
+    resolver::{self, ResolverOptions},
+    zone::Zone,
+    DnsRecord, QueryType,
+};
+
+const ZONE: &str = "$ORIGIN example.test.\n\
+                    @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+                    www IN A 127.0.0.1\n\
+                    www IN A 127.0.0.2\n";
+
+const UP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+const DOWN: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+fn addresses(options: &Arc<ResolverOptions>) -> Vec<IpAddr> {
+    resolver::resolve("www.example.test", QueryType::A, options)
+        .unwrap()
+        .answers
+        .iter()
+        .filter_map(DnsRecord::ip_addr)
+        .collect()
+}
+
+/// A web server on `addr` that answers every request with `status`
+fn http_server(addr: IpAddr, port: u16, status: &'static str) {
+    let listener = TcpListener::bind((addr, port)).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 512];
+            let _ = stream.read(&mut request);
+            let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+        }
+    });
+}
+
+#[test]
+fn backends_that_are_down_are_left_out() {
+    // Only the first address has anything listening on the port
+    let listener = TcpListener::bind((UP, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut health = HealthChecks::new();
+    health.add(UP, Probe::Tcp(port));
+    health.add(DOWN, Probe::Tcp(port));
+    let options = Arc::new(ResolverOptions {
+        zones: vec![Zone::parse(ZONE).unwrap()],
+        health: Some(health.clone()),
+        ..ResolverOptions::default()
+    });
+
+    // Everything is up until probed, and a single failure isn't enough
+    assert_eq!(addresses(&options), [UP, DOWN]);
+    health.check();
+    assert_eq!(addresses(&options), [UP, DOWN]);
+    health.check();
+    assert_eq!(addresses(&options), [UP]);
+    assert!(!health.is_up(DOWN));
+
+    // With nothing up, the answer is better than none at all
+    drop(listener);
+    health.check();
+    health.check();
+    assert_eq!(addresses(&options), [UP, DOWN]);
+}
+
+#[test]
+fn http_probes_go_by_the_status() {
+    let listener = TcpListener::bind((UP, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+    http_server(UP, port, "200 OK");
+    http_server(DOWN, port, "503 Service Unavailable");
+
+    let probe: Probe = format!("http:{}/healthz", port).parse().unwrap();
+    assert!(probe.check(UP, Duration::from_secs(2)));
+    assert!(!probe.check(DOWN, Duration::from_secs(2)));
+    assert_eq!(probe.to_string(), format!("http:{}/healthz", port));
+    assert!("udp:53".parse::<Probe>().is_err());
+}
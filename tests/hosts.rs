@@ -0,0 +1,108 @@
+//! Names in the hosts files are answered with their fixed addresses, ahead
+//! of everything that would otherwise be looked up.
+
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, OnceLock},
+};
+
+use dns_server::{
+    forwarder::{Forwarder, Upstream},
+    hosts::Hosts,
+    resolver::{self, ResolverOptions},
+    DnsRecord, QueryType, ResultCode,
+};
+
+const HOSTS: &str = "
+# The lab
+10.0.0.1     nas.lab.test  NAS
+2001:db8::1  nas.lab.test
+10.0.0.2     *.dev.lab.test
+10.0.0.3     *.lab.test
+";
+
+fn options() -> Arc<ResolverOptions> {
+    static LOADED: OnceLock<Hosts> = OnceLock::new();
+    let hosts = LOADED.get_or_init(|| {
+        let path = std::env::temp_dir().join(format!("dns-server-hosts-{}", std::process::id()));
+        fs::write(&path, HOSTS).unwrap();
+        let mut hosts = Hosts::new();
+        let count = hosts.load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(count.unwrap(), 5);
+        hosts
+    });
+
+    Arc::new(ResolverOptions {
+        hosts: Some(hosts.clone()),
+        // Anything that isn't answered locally fails right away
+        forwarder: Some(Forwarder::new(vec![Upstream::Plain(SocketAddr::from((
+            [127, 0, 0, 1],
+            1,
+        )))])),
+        ..ResolverOptions::default()
+    })
+}
+
+fn addrs(qname: &str, qtype: QueryType) -> Vec<IpAddr> {
+    let response = resolver::lookup(qname, qtype, &options()).unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert!(response.header.authoritative_answer);
+
+    response
+        .answers
+        .iter()
+        .map(|record| match record {
+            DnsRecord::A { addr, .. } => IpAddr::V4(*addr),
+            DnsRecord::AAAA { addr, .. } => IpAddr::V6(*addr),
+            record => panic!("Unexpected record {:?}", record),
+        })
+        .collect()
+}
+
+#[test]
+fn names_get_their_addresses() {
+    assert_eq!(
+        addrs("NAS.lab.test", QueryType::A),
+        [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]
+    );
+    assert_eq!(
+        addrs("nas.lab.test.", QueryType::AAAA),
+        [IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))]
+    );
+    assert_eq!(
+        addrs("nas", QueryType::A),
+        [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]
+    );
+}
+
+#[test]
+fn wildcards_cover_subdomains() {
+    assert_eq!(
+        addrs("a.b.dev.lab.test", QueryType::A),
+        [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]
+    );
+    assert_eq!(
+        addrs("printer.lab.test", QueryType::A),
+        [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3))]
+    );
+    // The wildcard isn't for the name itself
+    assert!(resolver::lookup("lab.test", QueryType::A, &options()).is_err());
+}
+
+#[test]
+fn other_types_get_no_records() {
+    assert!(addrs("printer.lab.test", QueryType::AAAA).is_empty());
+    assert!(addrs("nas.lab.test", QueryType::MX).is_empty());
+}
+
+#[test]
+fn invalid_addresses_are_reported() {
+    let path = std::env::temp_dir().join(format!("dns-server-bad-hosts-{}", std::process::id()));
+    fs::write(&path, "10.0.0.1 ok.test\n10.0.0 broken.test\n").unwrap();
+    let error = Hosts::new().load(&path).unwrap_err();
+    fs::remove_file(&path).unwrap();
+
+    assert!(error.to_string().contains("line 2"), "{}", error);
+}
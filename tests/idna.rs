@@ -0,0 +1,65 @@
+//! Internationalized names go out on the wire in punycode, and are shown in
+//! Unicode again.
+
+use dns_server::{idna, BytePacketBuffer, DnsPacket, DnsQuestion, DnsRecord, QueryType};
+
+#[test]
+fn labels_are_encoded_as_the_rfc_has_them() {
+    // The samples of RFC 3492, section 7.1
+    for (unicode, punycode) in [
+        ("3年B組金八先生", "3B-ww4c5e180e575a65lsy2b"),
+        (
+            "安室奈美恵-with-SUPER-MONKEYS",
+            "-with-SUPER-MONKEYS-pc58ag80a8qai00g7n9n",
+        ),
+        ("パフィーdeルンバ", "de-jg4avhby1noc0d"),
+        ("ليهمابتكلموشعربي؟", "egbpdaj6bu4bxfgehfvwxn"),
+    ] {
+        let chars: Vec<char> = unicode.chars().collect();
+        assert_eq!(idna::encode(&chars).as_deref(), Some(punycode));
+        assert_eq!(idna::decode(punycode).as_deref(), Some(unicode));
+    }
+
+    assert_eq!(idna::decode("bcher-kva!"), None);
+}
+
+#[test]
+fn names_round_trip_through_punycode() {
+    assert_eq!(idna::to_ascii("Bücher.Example"), "xn--bcher-kva.Example");
+    assert_eq!(
+        idna::to_ascii("www.münchen.example."),
+        "www.xn--mnchen-3ya.example."
+    );
+    assert_eq!(idna::to_ascii("www.example.com"), "www.example.com");
+
+    assert_eq!(idna::to_unicode("XN--bcher-kva.Example"), "bücher.Example");
+    assert_eq!(idna::to_unicode("www.example.com"), "www.example.com");
+    // Labels that aren't valid punycode are shown the way they are
+    assert_eq!(
+        idna::to_unicode("xn--bcher-k_a.example"),
+        "xn--bcher-k_a.example"
+    );
+}
+
+#[test]
+fn questions_are_asked_in_punycode_and_shown_in_unicode() {
+    let question = DnsQuestion::new("bücher.example".to_string(), QueryType::A);
+    assert_eq!(question.name, "xn--bcher-kva.example");
+
+    let mut packet = DnsPacket::new();
+    packet.questions.push(question);
+    packet.answers.push(DnsRecord::A {
+        domain: "xn--bcher-kva.example".to_string(),
+        addr: "10.0.0.1".parse().unwrap(),
+        ttl: 60,
+    });
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    let wire = &buffer.buf[..buffer.pos()];
+    assert!(wire.windows(14).any(|w| w == b"xn--bcher-kva\x07"));
+
+    let shown = packet.to_string();
+    assert!(shown.contains(";bücher.example.\t\tIN\tA"));
+    assert!(shown.contains("bücher.example."));
+    assert!(!shown.contains("xn--"));
+}
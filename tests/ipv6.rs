@@ -0,0 +1,128 @@
+//! Servers listen on IPv6 addresses, with `[::]` taking IPv4 clients as
+//! well, and upstreams are reached over IPv6 just the same, first when
+//! that's the family preferred.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    client::{self, AddressFamily, QueryOptions},
+    forwarder::{Forwarder, Upstream},
+    resolver::ResolverOptions,
+    server::{self, BindOptions},
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType,
+};
+
+/// An upstream on `addr` that gives every name the address `answer`, of
+/// whichever family is asked for
+fn upstream(addr: &str, answer: IpAddr) -> Upstream {
+    let socket = UdpSocket::bind(addr).unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let domain = query.questions[0].name.clone();
+        let record = match answer {
+            IpAddr::V4(addr) => DnsRecord::A {
+                domain,
+                addr,
+                ttl: 300,
+            },
+            IpAddr::V6(addr) => DnsRecord::AAAA {
+                domain,
+                addr,
+                ttl: 300,
+            },
+        };
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.questions = query.questions.clone();
+        response.answers.push(record);
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    Upstream::Plain(addr)
+}
+
+fn serve(addr: &str, upstreams: Vec<Upstream>) -> SocketAddr {
+    let socket = server::bind_udp(addr.parse().unwrap(), BindOptions::default()).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let options = ResolverOptions {
+        forwarder: Some(Forwarder::new(upstreams)),
+        ..ResolverOptions::default()
+    };
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+    addr
+}
+
+#[test]
+fn queries_go_over_ipv6_loopback() {
+    let addr: Ipv6Addr = "2001:db8::2".parse().unwrap();
+    let server = serve("[::1]:0", vec![upstream("[::1]:0", IpAddr::V6(addr))]);
+    assert_eq!(server.ip(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+
+    let response = client::query(
+        "www.example.com",
+        QueryType::AAAA,
+        server,
+        &QueryOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(response.get_random_aaaa(), Some(addr));
+}
+
+#[test]
+fn unspecified_addresses_take_both_families() {
+    let server = serve(
+        "[::]:0",
+        vec![upstream(
+            "127.0.0.1:0",
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 4)),
+        )],
+    );
+
+    for ip in [
+        IpAddr::V6(Ipv6Addr::LOCALHOST),
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+    ] {
+        let response = client::query(
+            "www.example.com",
+            QueryType::A,
+            SocketAddr::new(ip, server.port()),
+            &QueryOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 4)));
+    }
+}
+
+#[test]
+fn upstreams_of_the_preferred_family_go_first() {
+    let forwarder = Forwarder::new(vec![
+        upstream("127.0.0.1:0", IpAddr::V4(Ipv4Addr::new(10, 0, 0, 4))),
+        upstream("[::1]:0", IpAddr::V4(Ipv4Addr::new(10, 0, 0, 6))),
+    ]);
+
+    for (family, answer) in [
+        (AddressFamily::Ipv6, Ipv4Addr::new(10, 0, 0, 6)),
+        (AddressFamily::Ipv4, Ipv4Addr::new(10, 0, 0, 4)),
+    ] {
+        let options = QueryOptions {
+            prefer_family: Some(family),
+            ..QueryOptions::default()
+        };
+        let response = forwarder
+            .forward("www.example.com", QueryType::A, &options)
+            .unwrap();
+        assert_eq!(response.get_random_a(), Some(answer));
+    }
+}
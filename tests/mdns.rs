@@ -0,0 +1,128 @@
+//! The mDNS responder answers for its names the way RFC 6762 has it, to
+//! the group or straight back to the querier.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use dns_server::{
+    hosts::Hosts,
+    mdns::{self, Responder},
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryBuilder, QueryType,
+};
+
+fn responder() -> Responder {
+    let mut hosts = Hosts::new();
+    hosts.ttl = mdns::DEFAULT_MDNS_TTL;
+    hosts.insert("printer.local", "192.168.1.20".parse().unwrap());
+    Responder::new(hosts)
+}
+
+fn query(qname: &str, qtype: QueryType) -> DnsPacket {
+    QueryBuilder::new()
+        .id(0)
+        .recursion_desired(false)
+        .question(qname, qtype)
+        .build()
+}
+
+fn printer(ttl: u32) -> DnsRecord {
+    DnsRecord::A {
+        domain: "printer.local".to_string(),
+        addr: Ipv4Addr::new(192, 168, 1, 20),
+        ttl,
+    }
+}
+
+/// Parse a packet the way it's received
+fn parse(buffer: &BytePacketBuffer) -> DnsPacket {
+    DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(
+        &buffer.buf[..buffer.pos()],
+    ))
+    .unwrap()
+}
+
+fn reparse(packet: &mut DnsPacket) -> DnsPacket {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    parse(&buffer)
+}
+
+fn mdns_peer() -> SocketAddr {
+    SocketAddr::from(([192, 168, 1, 5], mdns::MDNS_PORT))
+}
+
+#[test]
+fn answers_are_multicast_and_flush_caches() {
+    let (buffer, dest) = responder()
+        .respond(&query("Printer.local", QueryType::A), mdns_peer())
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(dest, SocketAddr::from((mdns::MDNS_ADDR, mdns::MDNS_PORT)));
+    // The class follows the header, the name and the type
+    let class = 12 + "printer.local".len() + 2 + 2;
+    assert_eq!(buffer.buf[class..class + 2], [0x80, 0x01]);
+
+    let response = parse(&buffer);
+    assert_eq!(response.header.id, 0);
+    assert!(response.header.response && response.header.authoritative_answer);
+    assert!(response.questions.is_empty());
+    assert_eq!(response.answers[0].ttl(), mdns::DEFAULT_MDNS_TTL);
+}
+
+#[test]
+fn qu_questions_are_answered_by_unicast() {
+    let mut query = query("printer.local", QueryType::A);
+    query.questions[0].unicast_response = true;
+    let query = reparse(&mut query);
+    assert!(query.questions[0].unicast_response);
+
+    let (_, dest) = responder().respond(&query, mdns_peer()).unwrap().unwrap();
+    assert_eq!(dest, mdns_peer());
+}
+
+#[test]
+fn plain_dns_clients_get_a_plain_response() {
+    let mut query = query("printer.local", QueryType::A);
+    query.header.id = 4321;
+    let client = SocketAddr::from(([192, 168, 1, 5], 40000));
+
+    let (buffer, dest) = responder().respond(&query, client).unwrap().unwrap();
+    let response = parse(&buffer);
+
+    assert_eq!(dest, client);
+    assert_eq!(response.header.id, 4321);
+    assert_eq!(response.questions, query.questions);
+    assert_eq!(response.answers, [printer(10)]);
+}
+
+#[test]
+fn known_answers_and_other_names_are_not_answered() {
+    let responder = responder();
+
+    let mut known = query("printer.local", QueryType::A);
+    known.answers.push(printer(100));
+    let known = reparse(&mut known);
+    assert!(responder.respond(&known, mdns_peer()).unwrap().is_none());
+
+    // Too close to expiring to be left out
+    let mut known = query("printer.local", QueryType::A);
+    known.answers.push(printer(30));
+    let known = reparse(&mut known);
+    assert!(responder.respond(&known, mdns_peer()).unwrap().is_some());
+
+    let other = query("scanner.local", QueryType::A);
+    assert!(responder.respond(&other, mdns_peer()).unwrap().is_none());
+    let other_type = query("printer.local", QueryType::AAAA);
+    assert!(responder
+        .respond(&other_type, mdns_peer())
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn local_names() {
+    assert!(mdns::is_local("printer.local"));
+    assert!(mdns::is_local("Printer.LOCAL."));
+    assert!(!mdns::is_local("printer.localhost"));
+    assert!(!mdns::is_local("notlocal"));
+}
@@ -0,0 +1,116 @@
+//! The counters and histograms a running server serves for Prometheus to
+//! scrape, after it has answered a few queries.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    cache::Cache,
+    forwarder::{Forwarder, Upstream},
+    metrics::{self, Metrics},
+    resolver::ResolverOptions,
+    server,
+    zone::Zone,
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType,
+};
+
+use common::ask;
+
+/// An upstream giving every name the address 10.0.0.2
+fn upstream() -> Upstream {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.questions = query.questions.clone();
+        response.answers.push(DnsRecord::A {
+            domain: query.questions[0].name.clone(),
+            addr: Ipv4Addr::new(10, 0, 0, 2),
+            ttl: 300,
+        });
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    Upstream::Plain(addr)
+}
+
+/// What the metrics endpoint responds with, headers and all
+fn scrape(server: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(server).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        metrics::PATH
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn served_queries_are_counted() {
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A 10.0.0.1\n",
+    )
+    .unwrap();
+    let metrics = Metrics::new();
+    let options = ResolverOptions {
+        zones: vec![zone],
+        forwarder: Some(Forwarder::new(vec![upstream()])),
+        cache: Some(Cache::new()),
+        metrics: Some(metrics.clone()),
+        ..ResolverOptions::default()
+    };
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dns = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let http = listener.local_addr().unwrap();
+    thread::spawn(move || metrics::serve(listener, metrics));
+
+    // The second lookup of the upstream's name is answered from the cache
+    ask(dns, "www.example.test", QueryType::A);
+    ask(dns, "nope.example.test", QueryType::AAAA);
+    ask(dns, "www.example.com", QueryType::A);
+    ask(dns, "www.example.com", QueryType::A);
+
+    let response = scrape(http);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains(&format!("Content-Type: {}\r\n", metrics::CONTENT_TYPE)));
+    let lines: Vec<&str> = response.lines().collect();
+    for line in [
+        "dns_queries_total{qtype=\"A\"} 3",
+        "dns_queries_total{qtype=\"AAAA\"} 1",
+        "dns_responses_total{rcode=\"NOERROR\"} 3",
+        "dns_responses_total{rcode=\"NXDOMAIN\"} 1",
+        "dns_cache_hits_total 1",
+        "dns_cache_misses_total 1",
+        "dns_upstream_errors_total 0",
+        "dns_active_connections 0",
+        "dns_request_duration_seconds_bucket{le=\"+Inf\"} 4",
+        "dns_request_duration_seconds_count 4",
+        "dns_upstream_duration_seconds_count 1",
+    ] {
+        assert!(lines.contains(&line), "{} not in {}", line, response);
+    }
+    assert!(lines.contains(&"# TYPE dns_request_duration_seconds histogram"));
+}
@@ -0,0 +1,79 @@
+//! Resolving recursively, each name server is only asked about as much of the
+//! name as it needs to know.
+
+use dns_server::{
+    minimize::{Minimizer, MAX_MINIMISE_COUNT},
+    DnsPacket, DnsRecord, QueryType,
+};
+
+/// A referral to the servers of `zone`
+fn referral(zone: &str) -> DnsPacket {
+    let mut response = DnsPacket::new();
+    response.authorities.push(DnsRecord::NS {
+        domain: zone.to_string(),
+        host: format!("ns1.{}", zone),
+        ttl: 3600,
+    });
+    response
+}
+
+#[test]
+fn servers_are_asked_one_label_below_their_zone() {
+    let mut minimizer = Minimizer::new("www.Example.com", QueryType::AAAA, true);
+    assert_eq!(minimizer.question(), ("com", QueryType::A));
+
+    assert!(minimizer.referral(&referral("com")));
+    assert_eq!(minimizer.question(), ("Example.com", QueryType::A));
+
+    assert!(minimizer.referral(&referral("example.com")));
+    assert_eq!(minimizer.question(), ("www.Example.com", QueryType::AAAA));
+    assert!(!minimizer.is_minimized());
+}
+
+#[test]
+fn names_without_a_zone_of_their_own_are_asked_about_further() {
+    let mut minimizer = Minimizer::new("a.b.example.com", QueryType::A, true);
+    assert!(minimizer.referral(&referral("com")));
+    assert!(minimizer.referral(&referral("example.com")));
+    assert_eq!(minimizer.question(), ("b.example.com", QueryType::A));
+
+    // An empty non-terminal, in the zone of the same servers
+    assert!(!minimizer.referral(&DnsPacket::new()));
+    assert_eq!(minimizer.question(), ("a.b.example.com", QueryType::A));
+    assert!(!minimizer.is_minimized());
+
+    // Referrals back up don't count either
+    let mut minimizer = Minimizer::new("a.b.example.com", QueryType::A, true);
+    assert!(minimizer.referral(&referral("com")));
+    assert!(!minimizer.referral(&referral("com")));
+    assert_eq!(minimizer.question(), ("b.example.com", QueryType::A));
+}
+
+#[test]
+fn misbehaving_servers_get_the_full_name() {
+    let mut minimizer = Minimizer::new("www.example.com", QueryType::MX, true);
+    assert!(minimizer.is_minimized());
+    minimizer.give_up();
+    assert_eq!(minimizer.question(), ("www.example.com", QueryType::MX));
+
+    let minimizer = Minimizer::new("www.example.com", QueryType::MX, false);
+    assert_eq!(minimizer.question(), ("www.example.com", QueryType::MX));
+}
+
+#[test]
+fn long_names_take_a_bounded_number_of_questions() {
+    let qname = (0..30)
+        .map(|i| format!("l{}", i))
+        .collect::<Vec<_>>()
+        .join(".");
+    let mut minimizer = Minimizer::new(&qname, QueryType::A, true);
+
+    let mut questions = 0;
+    while minimizer.is_minimized() {
+        questions += 1;
+        minimizer.referral(&DnsPacket::new());
+    }
+    // Along with the one about the full name
+    assert_eq!(questions + 1, MAX_MINIMISE_COUNT);
+    assert_eq!(minimizer.question(), (qname.as_str(), QueryType::A));
+}
@@ -0,0 +1,83 @@
+//! Names keep the case they came in, from the question that's echoed back to
+//! the record that's answered, while matching regardless of case.
+
+mod common;
+
+use std::{net::UdpSocket, sync::Arc, thread};
+
+use dns_server::{
+    name, resolver::ResolverOptions, server, zone::Zone, DnsRecord, QueryType, ResultCode,
+};
+
+use common::ask;
+
+#[test]
+fn names_match_regardless_of_case_and_trailing_dot() {
+    assert!(name::eq("WwW.Example.COM", "www.example.com."));
+    assert!(!name::eq("www.example.com", "www.example.net"));
+
+    assert!(name::is_subdomain("WWW.Example.com", "example.COM."));
+    assert!(name::is_subdomain("Example.com", "example.com"));
+    assert!(name::is_subdomain("example.com", ""));
+    assert!(!name::is_subdomain("badexample.com", "example.com"));
+    assert!(!name::is_subdomain("example.com", "www.example.com"));
+
+    assert_eq!(name::normalize("WwW.Example.COM."), "www.example.com");
+}
+
+#[test]
+fn canonical_records_have_their_names_in_lowercase() {
+    let record = DnsRecord::MX {
+        domain: "Example.COM".to_string(),
+        priority: 10,
+        host: "Mail.Example.COM".to_string(),
+        ttl: 300,
+    };
+    assert_eq!(
+        record.lowercase_names(),
+        DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: 300,
+        }
+    );
+
+    // The next name of NSEC records is signed the way it is
+    let nsec = DnsRecord::NSEC {
+        domain: "A.example.com".to_string(),
+        next: "B.example.com".to_string(),
+        types: vec![QueryType::A],
+        ttl: 300,
+    };
+    match nsec.lowercase_names() {
+        DnsRecord::NSEC { domain, next, .. } => {
+            assert_eq!(domain, "a.example.com");
+            assert_eq!(next, "B.example.com");
+        }
+        record => panic!("Expected an NSEC record, got {:?}", record),
+    }
+}
+
+#[test]
+fn servers_answer_in_the_case_they_were_asked_in() {
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A 10.0.0.1\n",
+    )
+    .unwrap();
+    let options = ResolverOptions {
+        zones: vec![zone],
+        ..ResolverOptions::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+
+    let response = ask(server, "wWw.ExAmPlE.TeSt", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.questions[0].name, "wWw.ExAmPlE.TeSt");
+    assert_eq!(response.answers.len(), 1);
+    assert!(name::eq(response.answers[0].domain(), "www.example.test"));
+}
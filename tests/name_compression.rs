@@ -0,0 +1,138 @@
+//! Names that repeat within a packet are written once, with the repeats
+//! pointing back at it, except where RFC 3597 rules that out.
+
+use std::net::Ipv4Addr;
+
+use dns_server::{BytePacketBuffer, DnsError, DnsPacket, DnsQuestion, DnsRecord, QueryType};
+
+fn write(packet: &mut DnsPacket, size: usize) -> Result<Vec<u8>, DnsError> {
+    let mut buffer = BytePacketBuffer::with_capacity(size);
+    packet.write(&mut buffer)?;
+    Ok(buffer.buf[..buffer.pos()].to_vec())
+}
+
+fn parse(data: &[u8]) -> DnsPacket {
+    DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(data)).unwrap()
+}
+
+fn a(domain: &str, addr: [u8; 4]) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.to_string(),
+        addr: Ipv4Addr::from(addr),
+        ttl: 300,
+    }
+}
+
+/// How often the full name occurs in the packet
+fn occurrences(data: &[u8], name: &[u8]) -> usize {
+    data.windows(name.len())
+        .filter(|window| *window == name)
+        .count()
+}
+
+#[test]
+fn repeated_names_are_pointers() {
+    let mut packet = DnsPacket::new();
+    packet.questions.push(DnsQuestion::new(
+        "www.example.com".to_string(),
+        QueryType::A,
+    ));
+    for i in 1..=20 {
+        packet.answers.push(a("www.example.com", [10, 0, 0, i]));
+    }
+
+    let data = write(&mut packet, 512).unwrap();
+
+    // The header, the question, and then 2 bytes of pointer plus 14 bytes of
+    // type, class, TTL, length and address for each of the answers
+    assert_eq!(data.len(), 12 + 21 + 20 * 16);
+    assert_eq!(occurrences(&data, b"\x03www\x07example\x03com\x00"), 1);
+    assert!(!packet.header.truncated_message);
+    assert_eq!(parse(&data), packet);
+}
+
+#[test]
+fn shared_suffixes_are_pointers() {
+    let mut packet = DnsPacket::new();
+    packet.answers.push(DnsRecord::NS {
+        domain: "example.com".to_string(),
+        host: "ns1.example.com".to_string(),
+        ttl: 300,
+    });
+    packet.answers.push(DnsRecord::MX {
+        domain: "example.com".to_string(),
+        priority: 10,
+        host: "mail.example.com".to_string(),
+        ttl: 300,
+    });
+    packet.resources.push(a("ns1.example.com", [10, 0, 0, 1]));
+
+    let data = write(&mut packet, 512).unwrap();
+
+    assert_eq!(occurrences(&data, b"\x07example\x03com\x00"), 1);
+    assert_eq!(occurrences(&data, b"\x03ns1"), 1);
+    assert_eq!(parse(&data), packet);
+}
+
+#[test]
+fn names_in_newer_record_types_are_written_in_full() {
+    let mut packet = DnsPacket::new();
+    packet.answers.push(a("host.example.com", [10, 0, 0, 1]));
+    packet.answers.push(DnsRecord::SRV {
+        domain: "_sip._tcp.example.com".to_string(),
+        priority: 10,
+        weight: 5,
+        port: 5060,
+        host: "host.example.com".to_string(),
+        ttl: 300,
+    });
+
+    let data = write(&mut packet, 512).unwrap();
+
+    // The owner of the SRV record points at the first record, its target
+    // doesn't
+    assert_eq!(occurrences(&data, b"\x04host\x07example\x03com\x00"), 2);
+    assert_eq!(parse(&data), packet);
+}
+
+#[test]
+fn discarded_names_are_not_pointed_at() {
+    let mut buffer = BytePacketBuffer::new();
+    buffer.write_qname("example.com").unwrap();
+    let pos = buffer.pos();
+    buffer.write_qname("test").unwrap();
+
+    buffer.truncate(pos).unwrap();
+    buffer.write_qname("www.test").unwrap();
+    buffer.write_qname("www.example.com").unwrap();
+
+    assert_eq!(
+        &buffer.buf[..buffer.pos()],
+        b"\x07example\x03com\x00\x03www\x04test\x00\x03www\xC0\x00"
+    );
+}
+
+#[test]
+fn names_are_written_in_full_without_compression() {
+    let mut buffer = BytePacketBuffer::new();
+    buffer.disable_compression();
+    buffer.write_qname("example.com").unwrap();
+    buffer.write_qname("example.com").unwrap();
+
+    assert_eq!(
+        &buffer.buf[..buffer.pos()],
+        b"\x07example\x03com\x00\x07example\x03com\x00"
+    );
+}
+
+#[test]
+fn names_with_stray_dots_compress_like_the_clean_ones() {
+    let mut buffer = BytePacketBuffer::new();
+    buffer.write_qname("www.example.com.").unwrap();
+    buffer.write_qname("mail..example.com").unwrap();
+
+    assert_eq!(
+        &buffer.buf[..buffer.pos()],
+        b"\x03www\x07example\x03com\x00\x04mail\xc0\x04"
+    );
+}
@@ -0,0 +1,29 @@
+//! Names on the NXDOMAIN list are answered as though they didn't exist, with
+//! an SOA record for clients to take the negative caching TTL from.
+
+use dns_server::{nxdomain::NxdomainList, DnsRecord, QueryType, ResultCode};
+
+#[test]
+fn listed_names_get_nxdomain_with_an_soa() {
+    let mut list = NxdomainList::new();
+    list.insert("Gone.Example.Test.");
+    list.ttl = 30;
+
+    let response = list.answer("gone.example.test", QueryType::A).unwrap();
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert!(response.answers.is_empty());
+    match &response.authorities[..] {
+        [DnsRecord::SOA {
+            domain,
+            minimum,
+            ttl,
+            ..
+        }] => {
+            assert_eq!(domain, "example.test");
+            assert_eq!((*minimum, *ttl), (30, 30));
+        }
+        authorities => panic!("{:?}", authorities),
+    }
+
+    assert!(list.answer("www.example.test", QueryType::A).is_none());
+}
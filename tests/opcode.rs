@@ -0,0 +1,53 @@
+//! Only the opcodes the server implements get anything but `NOTIMP` back,
+//! with the opcode of the query kept in the response.
+
+mod common;
+
+use std::{
+    net::{Ipv4Addr, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    resolver::ResolverOptions, server, zone::Zone, BytePacketBuffer, DnsPacket, Opcode, QueryType,
+    ResultCode,
+};
+
+use common::exchange;
+
+#[test]
+fn opcodes_that_are_not_implemented_get_notimp() {
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A 10.0.0.1\n",
+    )
+    .unwrap();
+    let options = ResolverOptions {
+        zones: vec![zone],
+        ..ResolverOptions::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+
+    let client = common::client();
+    let ask = |opcode| {
+        let mut query = DnsPacket::query("www.example.test", QueryType::A);
+        query.header.opcode = opcode;
+        let mut buffer = BytePacketBuffer::new();
+        query.write(&mut buffer).unwrap();
+        exchange(&client, server, &buffer)
+    };
+
+    let response = ask(Opcode::QUERY);
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+    for opcode in [Opcode::IQUERY, Opcode::STATUS, Opcode::UNKNOWN(3)] {
+        let response = ask(opcode);
+        assert_eq!(response.header.rescode, ResultCode::NOTIMP);
+        assert_eq!(response.header.opcode, opcode);
+        assert!(response.answers.is_empty());
+    }
+}
@@ -0,0 +1,579 @@
+//! Property tests for the wire format: any packet we can build survives being
+//! written and parsed again unchanged, and no input makes the parser panic.
+//! Names are read with every limit of RFC 1035 checked, and records keep the
+//! class they came in as.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use dns_server::{
+    svcb::SvcParams, BytePacketBuffer, DnsError, DnsHeader, DnsPacket, DnsQuestion, DnsRecord,
+    Opcode, QueryType, ResultCode,
+};
+use proptest::prelude::*;
+
+/// The record types the type bitmaps of NSEC records are drawn from, in the
+/// order of their numbers
+const BITMAP_TYPES: [QueryType; 12] = [
+    QueryType::A,
+    QueryType::NS,
+    QueryType::CNAME,
+    QueryType::SOA,
+    QueryType::MX,
+    QueryType::TXT,
+    QueryType::AAAA,
+    QueryType::SRV,
+    QueryType::RRSIG,
+    QueryType::NSEC,
+    QueryType::DNSKEY,
+    QueryType::CAA,
+];
+
+/// Names the way they're held after parsing: in any case, without the
+/// trailing dot, and the root as the empty string
+fn name() -> impl Strategy<Value = String> {
+    prop::collection::vec("[a-zA-Z0-9]([a-zA-Z0-9-]{0,14}[a-zA-Z0-9])?", 0..5)
+        .prop_map(|labels| labels.join("."))
+}
+
+fn rescode() -> impl Strategy<Value = ResultCode> {
+    prop::sample::select(vec![
+        ResultCode::NOERROR,
+        ResultCode::FORMERR,
+        ResultCode::SERVFAIL,
+        ResultCode::NXDOMAIN,
+        ResultCode::NOTIMP,
+        ResultCode::REFUSED,
+    ])
+}
+
+fn header() -> impl Strategy<Value = DnsHeader> {
+    (any::<u16>(), any::<[bool; 8]>(), 0u8..16, rescode()).prop_map(
+        |(id, flags, opcode, rescode)| DnsHeader {
+            id,
+            recursion_desired: flags[0],
+            truncated_message: flags[1],
+            authoritative_answer: flags[2],
+            opcode: Opcode::from_num(opcode),
+            response: flags[3],
+            rescode,
+            checking_disabled: flags[4],
+            authed_data: flags[5],
+            z: flags[6],
+            recursion_available: flags[7],
+            // Written from the lengths of the sections
+            questions: 0,
+            answers: 0,
+            authoritative_entries: 0,
+            resource_entries: 0,
+        },
+    )
+}
+
+fn question() -> impl Strategy<Value = DnsQuestion> {
+    (name(), prop::sample::select(BITMAP_TYPES.to_vec()))
+        .prop_map(|(name, qtype)| DnsQuestion::new(name, qtype))
+}
+
+/// Params the way they're held after parsing, with the keys without a name
+/// of their own in increasing order
+fn svc_params() -> impl Strategy<Value = SvcParams> {
+    (
+        prop::collection::vec(1u16..8, 0..3),
+        prop::collection::vec("[a-z0-9-]{1,8}", 0..3),
+        any::<bool>(),
+        prop::option::of(any::<u16>()),
+        prop::collection::vec(any::<u32>(), 0..3),
+        prop::collection::vec(any::<u8>(), 0..32),
+        prop::collection::vec(any::<u128>(), 0..3),
+        prop::collection::btree_map(7u16.., prop::collection::vec(any::<u8>(), 0..8), 0..3),
+    )
+        .prop_map(
+            |(mandatory, alpn, no_default_alpn, port, ipv4hint, ech, ipv6hint, other)| SvcParams {
+                mandatory,
+                alpn,
+                no_default_alpn,
+                port,
+                ipv4hint: ipv4hint.into_iter().map(Ipv4Addr::from).collect(),
+                ipv6hint: ipv6hint.into_iter().map(Ipv6Addr::from).collect(),
+                ech,
+                other: other.into_iter().collect(),
+            },
+        )
+}
+
+fn record() -> impl Strategy<Value = DnsRecord> {
+    let bytes = || prop::collection::vec(any::<u8>(), 0..64);
+    let text = || "[ -~]{0,40}";
+
+    prop_oneof![
+        (name(), any::<u32>(), any::<u32>()).prop_map(|(domain, addr, ttl)| DnsRecord::A {
+            domain,
+            addr: Ipv4Addr::from(addr),
+            ttl,
+        }),
+        (name(), any::<u128>(), any::<u32>()).prop_map(|(domain, addr, ttl)| {
+            DnsRecord::AAAA {
+                domain,
+                addr: Ipv6Addr::from(addr),
+                ttl,
+            }
+        }),
+        (name(), name(), any::<u32>()).prop_map(|(domain, host, ttl)| DnsRecord::NS {
+            domain,
+            host,
+            ttl
+        }),
+        (name(), name(), any::<u32>()).prop_map(|(domain, host, ttl)| DnsRecord::CNAME {
+            domain,
+            host,
+            ttl
+        }),
+        (name(), name(), any::<u32>()).prop_map(|(domain, host, ttl)| DnsRecord::PTR {
+            domain,
+            host,
+            ttl
+        }),
+        (name(), any::<u16>(), name(), any::<u32>()).prop_map(|(domain, priority, host, ttl)| {
+            DnsRecord::MX {
+                domain,
+                priority,
+                host,
+                ttl,
+            }
+        }),
+        (name(), prop::collection::vec(text(), 1..4), any::<u32>())
+            .prop_map(|(domain, data, ttl)| DnsRecord::TXT { domain, data, ttl }),
+        (name(), name(), name(), any::<[u32; 5]>(), any::<u32>()).prop_map(
+            |(domain, mname, rname, times, ttl)| DnsRecord::SOA {
+                domain,
+                mname,
+                rname,
+                serial: times[0],
+                refresh: times[1],
+                retry: times[2],
+                expire: times[3],
+                minimum: times[4],
+                ttl,
+            }
+        ),
+        (name(), any::<[u16; 3]>(), name(), any::<u32>()).prop_map(
+            |(domain, [priority, weight, port], host, ttl)| DnsRecord::SRV {
+                domain,
+                priority,
+                weight,
+                port,
+                host,
+                ttl,
+            }
+        ),
+        (name(), any::<u8>(), "[a-z0-9]{1,15}", text(), any::<u32>()).prop_map(
+            |(domain, flags, tag, value, ttl)| DnsRecord::CAA {
+                domain,
+                flags,
+                tag,
+                value,
+                ttl,
+            }
+        ),
+        (
+            name(),
+            any::<u16>(),
+            any::<[u8; 2]>(),
+            bytes(),
+            any::<u32>()
+        )
+            .prop_map(|(domain, key_tag, [algorithm, digest_type], digest, ttl)| {
+                DnsRecord::DS {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                }
+            }),
+        (
+            name(),
+            any::<u16>(),
+            any::<[u8; 2]>(),
+            bytes(),
+            any::<u32>()
+        )
+            .prop_map(|(domain, flags, [protocol, algorithm], public_key, ttl)| {
+                DnsRecord::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                }
+            }),
+        (
+            name(),
+            prop::sample::select(BITMAP_TYPES.to_vec()),
+            any::<[u8; 2]>(),
+            any::<[u32; 3]>(),
+            any::<u16>(),
+            name(),
+            bytes(),
+            any::<u32>(),
+        )
+            .prop_map(
+                |(
+                    domain,
+                    type_covered,
+                    [algorithm, labels],
+                    [original_ttl, expiration, inception],
+                    key_tag,
+                    signer,
+                    signature,
+                    ttl,
+                )| DnsRecord::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer,
+                    signature,
+                    ttl,
+                }
+            ),
+        (
+            name(),
+            name(),
+            prop::sample::subsequence(BITMAP_TYPES.to_vec(), 0..=BITMAP_TYPES.len()),
+            any::<u32>(),
+        )
+            .prop_map(|(domain, next, types, ttl)| DnsRecord::NSEC {
+                domain,
+                next,
+                types,
+                ttl,
+            }),
+        (name(), any::<u16>(), name(), svc_params(), any::<u32>()).prop_map(
+            |(domain, priority, target, params, ttl)| DnsRecord::HTTPS {
+                domain,
+                priority,
+                target,
+                params,
+                ttl,
+            }
+        ),
+        // From the range reserved for private use, so that the type is never
+        // one we know how to parse
+        (name(), 65280u16..65535, bytes(), any::<u32>()).prop_map(|(domain, qtype, raw, ttl)| {
+            DnsRecord::UNKNOWN {
+                domain,
+                qtype,
+                class: 1,
+                data_len: raw.len() as u16,
+                raw,
+                ttl,
+            }
+        }),
+    ]
+}
+
+fn opt() -> impl Strategy<Value = DnsRecord> {
+    (512u16.., any::<u32>(), Just(Vec::new())).prop_map(|(packet_len, flags, data)| {
+        DnsRecord::OPT {
+            packet_len,
+            flags,
+            data,
+        }
+    })
+}
+
+fn packet() -> impl Strategy<Value = DnsPacket> {
+    let records = || prop::collection::vec(record(), 0..4);
+
+    (
+        header(),
+        prop::collection::vec(question(), 0..3),
+        records(),
+        records(),
+        records(),
+        prop::option::of(opt()),
+    )
+        .prop_map(
+            |(header, questions, answers, authorities, mut resources, opt)| {
+                resources.extend(opt);
+                DnsPacket {
+                    header,
+                    questions,
+                    answers,
+                    authorities,
+                    resources,
+                }
+            },
+        )
+}
+
+/// Write a packet and parse it back. The buffer is large enough that the
+/// packet is never truncated.
+fn round_trip(packet: &mut DnsPacket) -> Result<DnsPacket, DnsError> {
+    let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+    packet.write(&mut buffer)?;
+
+    let len = buffer.pos();
+    let mut buffer = BytePacketBuffer::from_slice(&buffer.buf[..len]);
+    DnsPacket::from_buffer(&mut buffer)
+}
+
+proptest! {
+    #[test]
+    fn packets_survive_a_round_trip(mut packet in packet()) {
+        let parsed = round_trip(&mut packet).unwrap();
+        prop_assert_eq!(parsed, packet);
+    }
+
+    #[test]
+    fn records_survive_a_round_trip(record in record()) {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(record);
+
+        let parsed = round_trip(&mut packet).unwrap();
+        prop_assert_eq!(parsed.answers, packet.answers);
+    }
+
+    #[test]
+    fn parsing_arbitrary_bytes_never_panics(
+        data in prop::collection::vec(any::<u8>(), 0..1024)
+    ) {
+        let _ = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    }
+
+    #[test]
+    fn parsing_a_corrupted_packet_never_panics(
+        mut packet in packet(),
+        flips in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8)
+    ) {
+        let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+        packet.write(&mut buffer).unwrap();
+        let mut data = buffer.buf[..buffer.pos()].to_vec();
+
+        for (index, value) in flips {
+            let i = index.index(data.len());
+            data[i] = value;
+        }
+
+        let _ = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    }
+}
+
+/// A header claiming one question, followed by `name` as its name
+fn question_packet(name: &[u8]) -> Vec<u8> {
+    let mut data = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    data.extend_from_slice(name);
+    data.extend_from_slice(&[0, 1, 0, 1]);
+    data
+}
+
+#[test]
+fn name_pointing_at_itself_is_rejected() {
+    // The name starts at offset 12, and jumps right back to it
+    let data = question_packet(&[0xC0, 12]);
+
+    let result = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    assert!(matches!(result, Err(DnsError::JumpLimitExceeded(_))));
+}
+
+#[test]
+fn name_pointing_past_the_end_is_rejected() {
+    let data = question_packet(&[0xC0, 0xFF]);
+
+    let result = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    assert!(result.is_err());
+}
+
+#[test]
+fn name_longer_than_255_bytes_is_rejected() {
+    // Five labels of 63 bytes make for 320 bytes on the wire
+    let mut name = Vec::new();
+    for _ in 0..5 {
+        name.push(63);
+        name.extend_from_slice(&[b'a'; 63]);
+    }
+    name.push(0);
+    let data = question_packet(&name);
+
+    let result = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    assert!(result.is_err());
+}
+
+#[test]
+fn label_running_past_the_end_is_rejected() {
+    let data = question_packet(&[10, b'a', b'b']);
+
+    let result = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    assert!(result.is_err());
+}
+
+#[test]
+fn name_pointing_forward_is_rejected() {
+    // The pointer at 12 points past itself, at the root label that follows
+    let data = question_packet(&[0xC0, 14, 0]);
+
+    let result = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    assert!(matches!(result, Err(DnsError::ForwardPointer(12, 14))));
+}
+
+#[test]
+fn missing_records_are_a_count_mismatch() {
+    // One answer claimed on top of the question, but none follow it
+    let mut data = question_packet(&[7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0]);
+    data[7] = 1;
+
+    let error = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data)).unwrap_err();
+    assert!(matches!(
+        error,
+        DnsError::CountMismatch {
+            section: "answer",
+            expected: 1,
+            found: 0
+        }
+    ));
+    assert!(error.is_malformed_packet());
+    assert_eq!(ResultCode::from_error(&error), ResultCode::FORMERR);
+
+    // A record cut off halfway is truncated instead
+    data.extend_from_slice(&[0xC0, 12, 0, 1]);
+    let result = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    assert!(matches!(result, Err(DnsError::EndOfBuffer)));
+}
+
+#[test]
+fn messages_longer_than_65535_bytes_are_rejected() {
+    let mut data = question_packet(&[0]);
+    data.resize(70_000, 0);
+
+    let result = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    assert!(matches!(result, Err(DnsError::MessageTooLong(70_000))));
+}
+
+#[test]
+fn names_are_read_in_the_case_they_came_in() {
+    // The second label isn't ASCII, "BÜCHER" in UTF-8
+    let data = question_packet(b"\x03WwW\x07B\xc3\x9cCHER\x02DE\x00");
+
+    let packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data)).unwrap();
+    assert_eq!(packet.questions[0].name, "WwW.BÜCHER.DE");
+    // Built by hand, as `new` would turn the name into punycode
+    let question = DnsQuestion {
+        name: "www.BÜCHER.de".to_string(),
+        ..DnsQuestion::new(String::new(), QueryType::A)
+    };
+    assert_eq!(packet.questions[0], question);
+}
+
+#[test]
+fn labels_that_arent_utf8_survive_a_round_trip() {
+    // An A record for a name with a byte that isn't UTF-8 and a backslash
+    let mut data = vec![0x12, 0x34, 0x81, 0x80, 0, 0, 0, 1, 0, 0, 0, 0];
+    data.extend_from_slice(
+        b"\x04a\xffb\\\x03\xc3\x9c1\x00\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04",
+    );
+    data.extend_from_slice(&[10, 0, 0, 1]);
+
+    let mut packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data)).unwrap();
+    assert_eq!(packet.answers[0].domain(), "a\\255b\\092.Ü1");
+
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos()], &data[..]);
+}
+
+#[test]
+fn records_of_the_wrong_length_are_passed_on_as_they_are() {
+    // An answer claiming to be an A record of six bytes, followed by a
+    // proper one
+    let mut data = vec![0x12, 0x34, 0x81, 0x80, 0, 0, 0, 2, 0, 0, 0, 0];
+    data.extend_from_slice(b"\x03www\x00\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x06");
+    data.extend_from_slice(&[10, 0, 0, 1, 0xbe, 0xef]);
+    data.extend_from_slice(b"\x03www\x00\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04");
+    data.extend_from_slice(&[10, 0, 0, 2]);
+
+    let mut packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data)).unwrap();
+    assert_eq!(
+        packet.answers[0],
+        DnsRecord::UNKNOWN {
+            domain: "www".to_string(),
+            qtype: 1,
+            class: 1,
+            data_len: 6,
+            raw: vec![10, 0, 0, 1, 0xbe, 0xef],
+            ttl: 300,
+        }
+    );
+    assert_eq!(
+        packet.answers[1],
+        DnsRecord::A {
+            domain: "www".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 2),
+            ttl: 300,
+        }
+    );
+
+    // And written back the same way
+    let answers = packet.answers.clone();
+    assert_eq!(round_trip(&mut packet).unwrap().answers, answers);
+}
+
+#[test]
+fn records_of_other_classes_keep_their_class() {
+    // A CH TXT record, and a CH NS record pointing back at the name above
+    let mut data = vec![0x12, 0x34, 0x81, 0x80, 0, 0, 0, 2, 0, 0, 0, 0];
+    data.extend_from_slice(b"\x07version\x04bind\x00\x00\x10\x00\x03\x00\x00\x00\x00\x00\x04");
+    data.extend_from_slice(b"\x039.9");
+    data.extend_from_slice(b"\xc0\x0c\x00\x02\x00\x03\x00\x00\x00\x00\x00\x05");
+    data.extend_from_slice(b"\x02ns\xc0\x0c");
+
+    let mut packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data)).unwrap();
+    assert_eq!(
+        packet.answers[0],
+        DnsRecord::UNKNOWN {
+            domain: "version.bind".to_string(),
+            qtype: 16,
+            class: 3,
+            data_len: 4,
+            raw: b"\x039.9".to_vec(),
+            ttl: 0,
+        }
+    );
+    // With the name in full, as the pointer means nothing anywhere else
+    assert_eq!(
+        packet.answers[1],
+        DnsRecord::UNKNOWN {
+            domain: "version.bind".to_string(),
+            qtype: 2,
+            class: 3,
+            data_len: 17,
+            raw: b"\x02ns\x07version\x04bind\x00".to_vec(),
+            ttl: 0,
+        }
+    );
+    assert_eq!(
+        packet.answers[1].to_string(),
+        "version.bind.\t0\tCH\tNS\t\\# 17 026e730776657273696f6e0462696e6400"
+    );
+
+    let answers = packet.answers.clone();
+    assert_eq!(round_trip(&mut packet).unwrap().answers, answers);
+}
+
+#[test]
+fn malformed_records_with_names_are_rejected() {
+    // An MX record with a byte to spare after its name, which points at the
+    // owner name
+    let mut data = vec![0x12, 0x34, 0x81, 0x80, 0, 0, 0, 1, 0, 0, 0, 0];
+    data.extend_from_slice(b"\x03www\x00\x00\x0f\x00\x01\x00\x00\x01\x2c\x00\x05");
+    data.extend_from_slice(&[0, 10, 0xc0, 12, 0]);
+
+    let result = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&data));
+    assert!(matches!(result, Err(DnsError::InvalidRecord(_))));
+}
@@ -0,0 +1,113 @@
+//! Custom handlers can be put anywhere in the pipeline queries go through,
+//! to answer them, hand them on, or change what comes back, and the
+//! built-in stages can be left out.
+
+mod common;
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use dns_server::{
+    error::{DnsError, Result},
+    pipeline::{Next, Pipeline, Query, QueryHandler, Stage},
+    resolver::{self, ResolverOptions, Source},
+    server,
+    zone::Zone,
+    DnsPacket, QueryType, ResultCode,
+};
+
+use common::ask;
+
+const ZONE: &str = "$ORIGIN example.test.\n\
+                    @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+                    www IN A 10.0.0.1\n\
+                    alias IN CNAME www\n\
+                    secret IN A 10.0.0.2\n";
+
+/// Refuses to tell clients about `secret.example.test`, while lookups of our
+/// own still get to see it
+struct Policy;
+
+impl QueryHandler for Policy {
+    fn handle(&self, query: &Query, next: Next) -> Result<(DnsPacket, Source)> {
+        if query.client.is_some() && query.name == "secret.example.test" {
+            let mut response = DnsPacket::new();
+            response.header.rescode = ResultCode::REFUSED;
+            return Ok((response, Source::Handler));
+        }
+
+        next.run(query)
+    }
+}
+
+/// Keeps the names it's asked about
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl QueryHandler for Recorder {
+    fn handle(&self, query: &Query, next: Next) -> Result<(DnsPacket, Source)> {
+        self.0.lock().unwrap().push(query.name.to_string());
+        next.run(query)
+    }
+}
+
+fn serve(options: ResolverOptions) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+    addr
+}
+
+#[test]
+fn custom_handlers_see_the_queries_of_clients() {
+    let recorder = Recorder::default();
+    let mut pipeline = Pipeline::new();
+    pipeline.push_front(Policy);
+    pipeline.insert_before(Stage::Zones, recorder.clone());
+    let server = serve(ResolverOptions {
+        zones: vec![Zone::parse(ZONE).unwrap()],
+        pipeline,
+        ..ResolverOptions::default()
+    });
+
+    let response = ask(server, "secret.example.test", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::REFUSED);
+    assert!(response.answers.is_empty());
+
+    let response = ask(server, "www.example.test", QueryType::A);
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+    // Following the alias goes through the handlers after `Stage::Cnames`
+    // once more
+    let response = ask(server, "alias.example.test", QueryType::A);
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        ["www.example.test", "alias.example.test", "www.example.test"]
+    );
+}
+
+#[test]
+fn stages_can_be_left_out() {
+    let mut pipeline = Pipeline::new();
+    pipeline.remove(Stage::Upstream);
+    let options = Arc::new(ResolverOptions {
+        zones: vec![Zone::parse(ZONE).unwrap()],
+        pipeline,
+        ..ResolverOptions::default()
+    });
+
+    let (response, source) =
+        resolver::resolve_with_source("www.example.test", QueryType::A, &options).unwrap();
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(source, Source::Zone);
+    // Nothing but the upstreams could answer this, and there are none left
+    assert!(matches!(
+        resolver::resolve("www.example.com", QueryType::A, &options),
+        Err(DnsError::NoServers)
+    ));
+}
@@ -0,0 +1,125 @@
+//! Queries that go over TCP to an upstream share a connection that's kept
+//! open, without waiting for the responses to the queries before them, which
+//! may come back in any order.
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use dns_server::{
+    client::QueryOptions,
+    forwarder::{Forwarder, Upstream},
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType,
+};
+
+fn address_of(qname: &str) -> Ipv4Addr {
+    match qname {
+        "one.example.com" => Ipv4Addr::new(10, 0, 0, 1),
+        "two.example.com" => Ipv4Addr::new(10, 0, 0, 2),
+        _ => Ipv4Addr::new(10, 0, 0, 3),
+    }
+}
+
+fn read_query(stream: &mut TcpStream) -> DnsPacket {
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).unwrap();
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).unwrap();
+    DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buf)).unwrap()
+}
+
+fn write_answer(stream: &mut TcpStream, query: &DnsPacket) {
+    let qname = &query.questions[0].name;
+    let mut response = DnsPacket::response_to(query)
+        .answer(DnsRecord::A {
+            domain: qname.clone(),
+            addr: address_of(qname),
+            ttl: 300,
+        })
+        .build();
+    let mut buffer = BytePacketBuffer::new();
+    response.write(&mut buffer).unwrap();
+    stream
+        .write_all(&(buffer.pos() as u16).to_be_bytes())
+        .unwrap();
+    stream.write_all(&buffer.buf[..buffer.pos()]).unwrap();
+}
+
+/// An upstream whose responses over UDP are all truncated. Over TCP, it
+/// answers the first query right away, and the two after it only once both
+/// of them arrived, the other way round. Returns how many connections it
+/// accepted as well.
+fn upstream() -> (Upstream, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let listener = TcpListener::bind(addr).unwrap();
+
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut response = DnsPacket::response_to(&query).build();
+        response.header.truncated_message = true;
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let first = read_query(&mut stream);
+                write_answer(&mut stream, &first);
+
+                let second = read_query(&mut stream);
+                let third = read_query(&mut stream);
+                write_answer(&mut stream, &third);
+                write_answer(&mut stream, &second);
+            });
+        }
+    });
+
+    (Upstream::Plain(addr), connections)
+}
+
+#[test]
+fn queries_share_a_connection_and_get_their_own_responses() {
+    let (upstream, connections) = upstream();
+    let forwarder = Forwarder::new(vec![upstream]);
+    let options = QueryOptions::default();
+
+    let response = forwarder
+        .forward("one.example.com", QueryType::A, &options)
+        .unwrap();
+    assert_eq!(response.get_random_a(), Some(address_of("one.example.com")));
+
+    // Neither of these gets a response before the other one is sent
+    thread::scope(|scope| {
+        let queries: Vec<_> = ["two.example.com", "three.example.com"]
+            .into_iter()
+            .map(|qname| {
+                let forwarder = &forwarder;
+                scope.spawn(move || {
+                    let response = forwarder.forward(qname, QueryType::A, &options).unwrap();
+                    assert_eq!(response.get_random_a(), Some(address_of(qname)));
+                })
+            })
+            .collect();
+        for query in queries {
+            query.join().unwrap();
+        }
+    });
+
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,147 @@
+//! Every query answered gets a line in the query log, either as a JSON
+//! object or as fields separated by spaces, each starting with the time.
+
+mod common;
+
+use std::{
+    io::{self, Write},
+    net::UdpSocket,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use dns_server::{
+    query_log::{QueryLog, QueryLogEntry, QueryLogFormat},
+    resolver::{ResolverOptions, Source},
+    server::{self, Transport},
+    zone::Zone,
+    QueryType, ResultCode,
+};
+
+use common::ask_from;
+
+/// The lines logged so far, shared with the log writing them
+#[derive(Clone, Default)]
+struct Lines(Arc<Mutex<Vec<u8>>>);
+
+impl Lines {
+    /// The lines without the time they start with
+    fn take(&self) -> Vec<String> {
+        let output = std::mem::take(&mut *self.0.lock().unwrap());
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let (time, rest) = line
+                    .trim_start_matches("{\"time\":\"")
+                    .split_once([' ', '"'])
+                    .unwrap();
+                assert!(time.parse::<jiff::Timestamp>().is_ok(), "{}", line);
+                rest.trim_start_matches(',').to_string()
+            })
+            .collect()
+    }
+}
+
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn lines_hold_the_fields_of_the_query() {
+    let entry = QueryLogEntry {
+        client: "192.0.2.1:5353".parse().unwrap(),
+        transport: Transport::Udp,
+        qname: "www.\"example\".com",
+        qtype: QueryType::AAAA,
+        rcode: ResultCode::NOERROR,
+        answers: 2,
+        source: Some(Source::Cache),
+        latency: Duration::from_micros(1250),
+    };
+
+    let lines = Lines::default();
+    QueryLog::new(lines.clone(), QueryLogFormat::Json).record(&entry);
+    assert_eq!(
+        lines.take(),
+        ["\"client\":\"192.0.2.1:5353\",\"transport\":\"udp\",\
+             \"qname\":\"www.\\\"example\\\".com\",\"qtype\":\"AAAA\",\"rcode\":\"NOERROR\",\
+             \"answers\":2,\"source\":\"cache\",\"cache_hit\":true,\"latency_ms\":1.250}"]
+    );
+
+    QueryLog::new(lines.clone(), QueryLogFormat::Text).record(&QueryLogEntry {
+        source: None,
+        rcode: ResultCode::SERVFAIL,
+        answers: 0,
+        ..entry
+    });
+    assert_eq!(
+        lines.take(),
+        ["192.0.2.1:5353 udp www.\"example\".com AAAA SERVFAIL answers=0 source=- 1.250ms"]
+    );
+}
+
+#[test]
+fn answered_queries_are_logged() {
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A 10.0.0.1\n",
+    )
+    .unwrap();
+    let lines = Lines::default();
+    let options = ResolverOptions {
+        zones: vec![zone],
+        query_log: Some(QueryLog::new(lines.clone(), QueryLogFormat::Text)),
+        ..ResolverOptions::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+
+    let client = common::client();
+    for qname in ["www.example.test", "nope.example.test"] {
+        ask_from(&client, server, qname, QueryType::A);
+    }
+
+    let fields: Vec<Vec<String>> = lines
+        .take()
+        .iter()
+        .map(|line| line.split(' ').map(str::to_string).collect())
+        .collect();
+    let client = client.local_addr().unwrap().to_string();
+    assert_eq!(fields.len(), 2);
+    assert_eq!(
+        fields[0][..7],
+        [
+            &client,
+            "udp",
+            "www.example.test",
+            "A",
+            "NOERROR",
+            "answers=1",
+            "source=zone"
+        ]
+    );
+    assert_eq!(
+        fields[1][..7],
+        [
+            &client,
+            "udp",
+            "nope.example.test",
+            "A",
+            "NXDOMAIN",
+            "answers=0",
+            "source=zone"
+        ]
+    );
+    assert!(fields[1][7].ends_with("ms"));
+}
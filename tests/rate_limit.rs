@@ -0,0 +1,65 @@
+//! Clients over their response rate get only some of their responses, and
+//! those truncated.
+
+use std::net::IpAddr;
+
+use dns_server::rate_limit::{Action, RateLimiter, MAX_TRACKED};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn responses_over_the_limit_slip_or_drop() {
+    let rate_limit = RateLimiter::new(3);
+
+    let actions: Vec<Action> = (0..7).map(|_| rate_limit.check(ip("192.0.2.1"))).collect();
+    assert_eq!(
+        actions,
+        [
+            Action::Send,
+            Action::Send,
+            Action::Send,
+            Action::Drop,
+            Action::Slip,
+            Action::Drop,
+            Action::Slip,
+        ]
+    );
+}
+
+#[test]
+fn clients_are_limited_by_subnet() {
+    let mut rate_limit = RateLimiter::new(1);
+    rate_limit.slip = 0;
+
+    assert_eq!(rate_limit.check(ip("192.0.2.1")), Action::Send);
+    assert_eq!(rate_limit.check(ip("192.0.2.200")), Action::Drop);
+    assert_eq!(rate_limit.check(ip("198.51.100.1")), Action::Send);
+
+    assert_eq!(rate_limit.check(ip("2001:db8:0:1::1")), Action::Send);
+    assert_eq!(rate_limit.check(ip("2001:db8:0:2::1")), Action::Drop);
+    assert_eq!(rate_limit.check(ip("2001:db8:1::1")), Action::Send);
+}
+
+#[test]
+fn new_subnets_are_truncated_while_every_one_tracked_is_recent() {
+    let mut rate_limit = RateLimiter::new(3);
+    rate_limit.ipv6_prefix_len = 128;
+    for i in 0..MAX_TRACKED as u128 {
+        assert_eq!(
+            rate_limit.check(IpAddr::V6((0x2001_0db8 << 96 | i).into())),
+            Action::Send
+        );
+    }
+
+    // There's no room made for another subnet, which doesn't get a full
+    // response
+    assert_eq!(rate_limit.check(ip("192.0.2.1")), Action::Slip);
+    assert_eq!(rate_limit.check(ip("192.0.2.1")), Action::Slip);
+    // While those kept track of are still accounted for
+    assert_eq!(rate_limit.check(ip("2001:db8::1")), Action::Send);
+
+    rate_limit.slip = 0;
+    assert_eq!(rate_limit.check(ip("192.0.2.1")), Action::Drop);
+}
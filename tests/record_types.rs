@@ -0,0 +1,244 @@
+//! Records of the rarer types are read into their fields and written back
+//! byte for byte, as a forwarding server has to pass them on unchanged. The
+//! same goes for records of types we don't know, and text that isn't text.
+
+use std::net::Ipv4Addr;
+
+use dns_server::{BytePacketBuffer, DnsPacket, DnsRecord};
+
+/// A response to a question for `example`, with a record of `qtype` owned by
+/// it for each of `rdatas`
+fn response(qtype: u16, rdatas: &[&[u8]]) -> Vec<u8> {
+    let mut data = vec![
+        0x12,
+        0x34,
+        0x81,
+        0x80,
+        0,
+        1,
+        0,
+        rdatas.len() as u8,
+        0,
+        0,
+        0,
+        0,
+    ];
+    data.extend_from_slice(b"\x07example\x00\x00\xff\x00\x01");
+    for rdata in rdatas {
+        data.extend_from_slice(&[0xC0, 12]);
+        data.extend_from_slice(&qtype.to_be_bytes());
+        data.extend_from_slice(&[0, 1, 0, 0, 0x0E, 0x10]);
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend_from_slice(rdata);
+    }
+    data
+}
+
+/// Parse the response, make sure it's written back exactly as it came, and
+/// hand back its records
+fn round_trip(data: &[u8]) -> Vec<DnsRecord> {
+    let mut buffer = BytePacketBuffer::new();
+    buffer.buf[..data.len()].copy_from_slice(data);
+    let mut packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos()], data);
+    packet.answers
+}
+
+#[test]
+fn legacy_records() {
+    let records = round_trip(&response(19, &[b"\x0c311061700956"]));
+    assert_eq!(
+        records,
+        [DnsRecord::X25 {
+            domain: "example".to_string(),
+            psdn_address: "311061700956".to_string(),
+            ttl: 3600,
+        }]
+    );
+
+    // With the subaddress and without it
+    let records = round_trip(&response(
+        20,
+        &[b"\x0f150862028003217\x03004", b"\x0f150862028003217"],
+    ));
+    assert_eq!(
+        records,
+        [
+            DnsRecord::ISDN {
+                domain: "example".to_string(),
+                address: "150862028003217".to_string(),
+                subaddress: Some("004".to_string()),
+                ttl: 3600,
+            },
+            DnsRecord::ISDN {
+                domain: "example".to_string(),
+                address: "150862028003217".to_string(),
+                subaddress: None,
+                ttl: 3600,
+            },
+        ]
+    );
+
+    let records = round_trip(&response(21, &[b"\x00\x0a\x05relay\x07example\x00"]));
+    assert_eq!(
+        records,
+        [DnsRecord::RT {
+            domain: "example".to_string(),
+            preference: 10,
+            host: "relay.example".to_string(),
+            ttl: 3600,
+        }]
+    );
+
+    // An E.164 number, and an NSAP address
+    let nsap = [0x47; 20];
+    let mut atma = vec![0];
+    atma.extend_from_slice(&nsap);
+    let records = round_trip(&response(34, &[b"\x01358400123456", &atma]));
+    assert_eq!(
+        records,
+        [
+            DnsRecord::ATMA {
+                domain: "example".to_string(),
+                format: 1,
+                address: b"358400123456".to_vec(),
+                ttl: 3600,
+            },
+            DnsRecord::ATMA {
+                domain: "example".to_string(),
+                format: 0,
+                address: nsap.to_vec(),
+                ttl: 3600,
+            },
+        ]
+    );
+}
+
+#[test]
+fn caa_values_fill_the_rest_of_the_record() {
+    let records = round_trip(&response(257, &[b"\x00\x05issueletsencrypt.org"]));
+    assert_eq!(
+        records,
+        [DnsRecord::CAA {
+            domain: "example".to_string(),
+            flags: 0,
+            tag: "issue".to_string(),
+            value: "letsencrypt.org".to_string(),
+            ttl: 3600,
+        }]
+    );
+
+    // Values that aren't text are relayed raw, like other strings
+    let records = round_trip(&response(257, &[b"\x80\x03tbs\xff\x00\xfe"]));
+    assert!(matches!(records[0], DnsRecord::UNKNOWN { qtype: 257, .. }));
+}
+
+#[test]
+fn unknown_types_are_passed_on_unchanged() {
+    let rdata = b"\x00\x01\x02\xfe\xff";
+    let records = round_trip(&response(99, &[rdata]));
+    assert_eq!(
+        records,
+        [DnsRecord::UNKNOWN {
+            domain: "example".to_string(),
+            qtype: 99,
+            class: 1,
+            data_len: 5,
+            raw: rdata.to_vec(),
+            ttl: 3600,
+        }]
+    );
+}
+
+#[test]
+fn strings_that_arent_utf8_are_relayed_raw() {
+    let records = round_trip(&response(19, &[b"\x0431\xff1"]));
+    assert_eq!(
+        records,
+        [DnsRecord::UNKNOWN {
+            domain: "example".to_string(),
+            qtype: 19,
+            class: 1,
+            data_len: 5,
+            raw: b"\x0431\xff1".to_vec(),
+            ttl: 3600,
+        }]
+    );
+}
+
+#[test]
+fn kx_records() {
+    let records = round_trip(&response(36, &[b"\x00\x0a\x02kx\x07example\x00"]));
+    assert_eq!(
+        records,
+        [DnsRecord::KX {
+            domain: "example".to_string(),
+            preference: 10,
+            exchanger: "kx.example".to_string(),
+            ttl: 3600,
+        }]
+    );
+
+    // Compressed exchangers from servers that don't know better still read
+    let data = response(36, &[b"\x00\x0a\x02kx\xc0\x0c"]);
+    let mut buffer = BytePacketBuffer::new();
+    buffer.buf[..data.len()].copy_from_slice(&data);
+    let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+    assert_eq!(packet.answers, records);
+}
+
+#[test]
+fn ilnp_records() {
+    let records = round_trip(&response(
+        104,
+        &[b"\x00\x0a\x00\x14\x4f\xff\xfe\x28\x9c\x5a"],
+    ));
+    assert_eq!(
+        records,
+        [DnsRecord::NID {
+            domain: "example".to_string(),
+            preference: 10,
+            node_id: 0x0014_4fff_fe28_9c5a,
+            ttl: 3600,
+        }]
+    );
+
+    let records = round_trip(&response(105, &[b"\x00\x0a\x0a\x01\x02\x00"]));
+    assert_eq!(
+        records,
+        [DnsRecord::L32 {
+            domain: "example".to_string(),
+            preference: 10,
+            locator: Ipv4Addr::new(10, 1, 2, 0),
+            ttl: 3600,
+        }]
+    );
+
+    let records = round_trip(&response(
+        106,
+        &[b"\x00\x14\x20\x01\x0d\xb8\x14\x0b\x00\x31"],
+    ));
+    assert_eq!(
+        records,
+        [DnsRecord::L64 {
+            domain: "example".to_string(),
+            preference: 20,
+            locator: 0x2001_0db8_140b_0031,
+            ttl: 3600,
+        }]
+    );
+
+    let records = round_trip(&response(107, &[b"\x00\x0a\x03l64\x07example\x00"]));
+    assert_eq!(
+        records,
+        [DnsRecord::LP {
+            domain: "example".to_string(),
+            preference: 10,
+            host: "l64.example".to_string(),
+            ttl: 3600,
+        }]
+    );
+}
@@ -0,0 +1,144 @@
+//! Names that don't exist, and blocked names by the category of their list,
+//! can be answered with addresses of our own, such as that of a landing page.
+
+mod common;
+
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    blocklist::Blocklist,
+    cache::Cache,
+    forwarder::{Forwarder, Upstream},
+    redirect::{Redirect, ALL_CATEGORIES},
+    resolver::ResolverOptions,
+    server,
+    zone::Zone,
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType, ResultCode,
+};
+
+use common::ask;
+
+const LANDING_PAGE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 80);
+const MALWARE_PAGE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 81);
+
+/// An upstream that claims that every name it's asked for doesn't exist, with
+/// an SOA that lets the answer be cached
+fn nxdomain_upstream() -> Upstream {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || loop {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut response = DnsPacket::response_to(&query)
+            .rcode(ResultCode::NXDOMAIN)
+            .authority(DnsRecord::SOA {
+                domain: "example.com".to_string(),
+                mname: "ns1.example.com".to_string(),
+                rname: "hostmaster.example.com".to_string(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 60,
+                ttl: 60,
+            })
+            .build();
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    Upstream::Plain(addr)
+}
+
+fn serve(options: ResolverOptions) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+    addr
+}
+
+#[test]
+fn names_that_do_not_exist_go_to_the_landing_page() {
+    let cache = Cache::new();
+    let mut redirect = Redirect::new();
+    redirect.nxdomain = vec![IpAddr::V4(LANDING_PAGE)];
+    redirect.ttl = 30;
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n",
+    )
+    .unwrap();
+    let server = serve(ResolverOptions {
+        forwarder: Some(Forwarder::new(vec![nxdomain_upstream()])),
+        cache: Some(cache.clone()),
+        redirect: Some(redirect),
+        zones: vec![zone],
+        ..ResolverOptions::default()
+    });
+
+    let response = ask(server, "typo.example.com", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.get_random_a(), Some(LANDING_PAGE));
+    assert_eq!(response.answers[0].ttl(), 30);
+
+    // Without an IPv6 address to send them to, AAAA queries get nothing
+    let response = ask(server, "typo.example.com", QueryType::AAAA);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert!(response.answers.is_empty());
+
+    // The cache holds on to what the upstream said, and the names of the local
+    // zones don't exist as always
+    let cached = cache.get("typo.example.com", QueryType::A).unwrap();
+    assert_eq!(cached.header.rescode, ResultCode::NXDOMAIN);
+    let response = ask(server, "typo.example.test", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+}
+
+#[test]
+fn blocked_names_go_where_their_category_says() {
+    let path = std::env::temp_dir().join(format!("dns-server-malware-{}", std::process::id()));
+    fs::write(&path, "0.0.0.0 malware.example.com\n").unwrap();
+
+    let mut blocklist = Blocklist::new();
+    blocklist.block("ads.example.com");
+    blocklist.block("tracker.example.com");
+    blocklist.load_category("malware", &path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(blocklist.category("ads.example.com"), Some("default"));
+    assert_eq!(blocklist.category("malware.example.com"), Some("malware"));
+    assert_eq!(blocklist.category("www.example.com"), None);
+
+    let mut redirect = Redirect::new();
+    redirect.block_to("malware", IpAddr::V4(MALWARE_PAGE));
+    let server = serve(ResolverOptions {
+        blocklist: Some(blocklist.clone()),
+        redirect: Some(redirect.clone()),
+        ..ResolverOptions::default()
+    });
+
+    let response = ask(server, "malware.example.com", QueryType::A);
+    assert_eq!(response.get_random_a(), Some(MALWARE_PAGE));
+    // Other categories are answered the way the blocklist does
+    let response = ask(server, "ads.example.com", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+
+    redirect.block_to(ALL_CATEGORIES, IpAddr::V4(LANDING_PAGE));
+    let server = serve(ResolverOptions {
+        blocklist: Some(blocklist),
+        redirect: Some(redirect),
+        ..ResolverOptions::default()
+    });
+    let response = ask(server, "tracker.example.com", QueryType::A);
+    assert_eq!(response.get_random_a(), Some(LANDING_PAGE));
+    let response = ask(server, "malware.example.com", QueryType::A);
+    assert_eq!(response.get_random_a(), Some(MALWARE_PAGE));
+}
@@ -0,0 +1,161 @@
+//! Messages are picked out of pcap captures and message dumps, and replayed
+//! through the parser, with those that don't parse or don't write back out
+//! the same reported.
+
+use std::{fs, net::Ipv4Addr};
+
+use dns_server::{
+    replay::{self, CapturedMessage, Failure},
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType, ResponseBuilder,
+};
+
+fn query(qname: &str) -> Vec<u8> {
+    let mut buffer = BytePacketBuffer::new();
+    DnsPacket::query(qname, QueryType::A)
+        .write(&mut buffer)
+        .unwrap();
+    buffer.buf[..buffer.pos()].to_vec()
+}
+
+fn response(compressed: bool) -> Vec<u8> {
+    let mut response = ResponseBuilder::new()
+        .question("www.example.test", QueryType::A)
+        .answer(DnsRecord::A {
+            domain: "www.example.test".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: 60,
+        })
+        .build();
+
+    let mut buffer = BytePacketBuffer::new();
+    if !compressed {
+        buffer.disable_compression();
+    }
+    response.write(&mut buffer).unwrap();
+    buffer.buf[..buffer.pos()].to_vec()
+}
+
+/// An Ethernet frame carrying `payload` over IPv4, from port 5353 to `port`
+fn frame(protocol: u8, port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut segment = vec![0x14, 0xe9];
+    segment.extend(port.to_be_bytes());
+    match protocol {
+        17 => {
+            segment.extend((8 + payload.len() as u16).to_be_bytes());
+            segment.extend([0, 0]);
+        }
+        _ => {
+            segment.extend([0; 8]);
+            // No options, so a header of 20 bytes
+            segment.extend([0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        }
+    }
+    segment.extend(payload);
+
+    let mut packet = vec![0x45, 0];
+    packet.extend((20 + segment.len() as u16).to_be_bytes());
+    packet.extend([0, 1, 0x40, 0, 64, protocol, 0, 0]);
+    packet.extend([127, 0, 0, 1, 127, 0, 0, 1]);
+    packet.extend(segment);
+
+    let mut frame = vec![0; 12];
+    frame.extend([0x08, 0x00]);
+    frame.extend(packet);
+    frame
+}
+
+fn pcap(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+    data.extend([0; 8]);
+    data.extend(65535u32.to_le_bytes());
+    data.extend(1u32.to_le_bytes());
+    for frame in frames {
+        data.extend([0; 8]);
+        data.extend((frame.len() as u32).to_le_bytes());
+        data.extend((frame.len() as u32).to_le_bytes());
+        data.extend(frame);
+    }
+    data
+}
+
+#[test]
+fn captures_give_the_messages_to_and_from_the_port() {
+    let queries = [query("a.example.test"), query("b.example.test")];
+    let mut stream = Vec::new();
+    for message in &queries {
+        stream.extend((message.len() as u16).to_be_bytes());
+        stream.extend(message);
+    }
+    let www = query("www.example.test");
+    let data = pcap(&[
+        frame(17, 53, &www),
+        // Not DNS
+        frame(17, 123, &[0; 48]),
+        frame(6, 53, &stream),
+        frame(17, 53, &response(true)),
+    ]);
+
+    let messages = replay::read_pcap(&data, replay::DEFAULT_PORT).unwrap();
+    let origins: Vec<&str> = messages.iter().map(|m| m.origin.as_str()).collect();
+    assert_eq!(origins, ["frame 1", "frame 3", "frame 3", "frame 4"]);
+    assert_eq!(messages[0].bytes, www);
+    assert_eq!(messages[2].bytes, queries[1]);
+
+    let report = replay::replay(&messages, true);
+    assert_eq!(report.messages, 4);
+    assert!(report.failures.is_empty());
+}
+
+#[test]
+fn failures_are_reported_with_where_the_message_came_from() {
+    let mut truncated = query("www.example.test");
+    truncated.truncate(20);
+    let messages = [
+        CapturedMessage {
+            origin: "truncated".to_string(),
+            bytes: truncated,
+        },
+        CapturedMessage {
+            origin: "uncompressed".to_string(),
+            bytes: response(false),
+        },
+    ];
+
+    // Names written in full still parse, they just come out compressed
+    let report = replay::replay(&messages, false);
+    assert_eq!(report.passed(), 1);
+    assert_eq!(report.failures[0].0, "truncated");
+    assert!(matches!(report.failures[0].1, Failure::Parse(_)));
+
+    let report = replay::replay(&messages, true);
+    assert_eq!(report.passed(), 0);
+    match &report.failures[1] {
+        (
+            origin,
+            Failure::Mismatch {
+                original, written, ..
+            },
+        ) => {
+            assert_eq!(origin, "uncompressed");
+            assert!(written < original);
+        }
+        failure => panic!("Expected a mismatch, got {:?}", failure),
+    }
+}
+
+#[test]
+fn directories_give_the_messages_of_every_file() {
+    let dir = std::env::temp_dir().join(format!("dns-server-replay-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("1.bin"), query("www.example.test")).unwrap();
+    fs::write(dir.join("2.pcap"), pcap(&[frame(17, 53, &response(true))])).unwrap();
+
+    let messages = replay::load(&dir, replay::DEFAULT_PORT);
+    fs::remove_dir_all(&dir).unwrap();
+    let messages = messages.unwrap();
+
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0].origin.ends_with("1.bin"));
+    assert!(messages[1].origin.ends_with("2.pcap, frame 1"));
+    assert!(replay::replay(&messages, true).failures.is_empty());
+}
@@ -0,0 +1,70 @@
+//! Responses put together with `ResponseBuilder` have a header that matches
+//! the query they answer and the records they carry.
+
+use std::net::Ipv4Addr;
+
+use dns_server::{BytePacketBuffer, DnsPacket, DnsRecord, QueryBuilder, QueryType, ResultCode};
+
+fn a(domain: &str, addr: [u8; 4]) -> DnsRecord {
+    DnsRecord::A {
+        domain: domain.to_string(),
+        addr: Ipv4Addr::from(addr),
+        ttl: 300,
+    }
+}
+
+#[test]
+fn response_matches_the_query() {
+    let query = QueryBuilder::new()
+        .id(0x1234)
+        .question("www.example.com", QueryType::A)
+        .build();
+
+    let response = DnsPacket::response_to(&query)
+        .answer(a("www.example.com", [10, 0, 0, 1]))
+        .answer(a("www.example.com", [10, 0, 0, 2]))
+        .rcode(ResultCode::NOERROR)
+        .build();
+
+    assert_eq!(response.header.id, 0x1234);
+    assert!(response.header.response);
+    assert!(response.header.recursion_desired);
+    assert!(response.header.recursion_available);
+    assert!(!response.header.authoritative_answer);
+    assert_eq!(response.questions, query.questions);
+    assert_eq!(response.header.questions, 1);
+    assert_eq!(response.header.answers, 2);
+    assert_eq!(response.header.authoritative_entries, 0);
+    assert_eq!(response.header.resource_entries, 0);
+}
+
+#[test]
+fn counts_survive_a_round_trip() {
+    let query = DnsPacket::query("missing.example.com", QueryType::A);
+
+    let mut response = DnsPacket::response_to(&query)
+        .authoritative(true)
+        .rcode(ResultCode::NXDOMAIN)
+        .authority(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            mname: "ns.example.com".to_string(),
+            rname: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 300,
+        })
+        .build();
+    let built = response.clone();
+
+    let mut buffer = BytePacketBuffer::new();
+    response.write(&mut buffer).unwrap();
+    let parsed = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(
+        &buffer.buf[..buffer.pos()],
+    ))
+    .unwrap();
+
+    assert_eq!(parsed, built);
+}
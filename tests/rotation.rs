@@ -0,0 +1,79 @@
+//! The addresses of names in local zones are handed out in a different order
+//! from one response to the next, spreading clients out over the hosts.
+
+use std::{collections::HashMap, net::Ipv4Addr, sync::Arc};
+
+use dns_server::{
+    resolver::{self, ResolverOptions},
+    rotation::{AnswerRotation, RotationMode},
+    zone::Zone,
+    DnsRecord, QueryType,
+};
+
+const ZONE: &str = "$ORIGIN example.test.\n\
+                    @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+                    www IN A 10.0.0.1\n\
+                    www IN A 10.0.0.2\n\
+                    www IN A 10.0.0.3\n\
+                    web IN CNAME www\n";
+
+fn options(rotation: AnswerRotation) -> Arc<ResolverOptions> {
+    Arc::new(ResolverOptions {
+        zones: vec![Zone::parse(ZONE).unwrap()],
+        rotation: Some(rotation),
+        ..ResolverOptions::default()
+    })
+}
+
+fn addresses(qname: &str, options: &Arc<ResolverOptions>) -> Vec<Ipv4Addr> {
+    resolver::resolve(qname, QueryType::A, options)
+        .unwrap()
+        .answers
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecord::A { addr, .. } => Some(addr),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn round_robin_starts_at_the_next_address_every_time() {
+    let options = options(AnswerRotation::new(RotationMode::RoundRobin, None));
+    let first: Vec<_> = (0..4)
+        .map(|_| addresses("www.example.test", &options)[0])
+        .collect();
+    assert_eq!(
+        first,
+        [
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 3),
+            Ipv4Addr::new(10, 0, 0, 1),
+        ]
+    );
+
+    // The CNAME stays in front of the addresses it points to
+    let response = resolver::resolve("web.example.test", QueryType::A, &options).unwrap();
+    assert!(matches!(response.answers[0], DnsRecord::CNAME { .. }));
+    assert_eq!(response.answers.len(), 4);
+}
+
+#[test]
+fn weighted_addresses_come_first_more_often() {
+    let mut rotation = AnswerRotation::new(RotationMode::Weighted, Some(7));
+    rotation.weights.insert("10.0.0.1".parse().unwrap(), 8);
+    rotation.weights.insert("10.0.0.3".parse().unwrap(), 0);
+    let options = options(rotation);
+
+    let mut first = HashMap::new();
+    for _ in 0..200 {
+        let addrs = addresses("www.example.test", &options);
+        assert_eq!(addrs.len(), 3);
+        assert_eq!(addrs[2], Ipv4Addr::new(10, 0, 0, 3));
+        *first.entry(addrs[0]).or_insert(0) += 1;
+    }
+
+    assert!(first[&Ipv4Addr::new(10, 0, 0, 1)] > 150);
+    assert!(first[&Ipv4Addr::new(10, 0, 0, 2)] > 0);
+}
@@ -0,0 +1,149 @@
+//! Secondary zones are answered from once they're transferred, and brought
+//! up to date when their primary notifies us of changes.
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    slice,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use dns_server::{
+    client::QueryOptions,
+    dns_header::Opcode,
+    resolver::{self, ResolverOptions},
+    secondary::{self, Secondary},
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryBuilder, QueryType, ResultCode,
+};
+
+fn soa(serial: u32, expire: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: "example.test".to_string(),
+        mname: "ns.example.test".to_string(),
+        rname: "hostmaster.example.test".to_string(),
+        serial,
+        refresh: 3600,
+        retry: 600,
+        expire,
+        minimum: 60,
+        ttl: 300,
+    }
+}
+
+fn www(last: u8) -> DnsRecord {
+    DnsRecord::A {
+        domain: "www.example.test".to_string(),
+        addr: Ipv4Addr::new(10, 0, 0, last),
+        ttl: 300,
+    }
+}
+
+/// A primary that answers each transfer with the next of `transfers`
+fn primary(transfers: Vec<Vec<DnsRecord>>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for answers in transfers {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buf)).unwrap();
+
+            let mut response = DnsPacket::response_to(&query).answers(answers).build();
+            let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+            response.write(&mut buffer).unwrap();
+            stream
+                .write_all(&(buffer.pos() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&buffer.buf[..buffer.pos()]).unwrap();
+        }
+    });
+
+    addr
+}
+
+fn lookup(options: &Arc<ResolverOptions>) -> Vec<DnsRecord> {
+    resolver::lookup("www.example.test", QueryType::A, options)
+        .unwrap()
+        .answers
+}
+
+fn options(secondary: &Secondary) -> Arc<ResolverOptions> {
+    Arc::new(ResolverOptions {
+        secondaries: vec![secondary.clone()],
+        ..ResolverOptions::default()
+    })
+}
+
+#[test]
+fn zones_are_answered_from_once_transferred() {
+    let addr = primary(vec![vec![soa(1, 86400), www(1), soa(1, 86400)]]);
+    let secondary: Secondary = format!("Example.test.@{}", addr).parse().unwrap();
+    assert_eq!(secondary.origin, "example.test");
+    assert!(secondary.answer("www.example.test", QueryType::A).is_none());
+
+    let refresh = secondary.refresh(&QueryOptions::default()).unwrap();
+
+    assert_eq!(refresh, Duration::from_secs(3600));
+    assert_eq!(secondary.serial(), Some(1));
+    assert_eq!(lookup(&options(&secondary)), [www(1)]);
+}
+
+#[test]
+fn notify_from_the_primary_brings_the_changes() {
+    let addr = primary(vec![
+        vec![soa(1, 86400), www(1), soa(1, 86400)],
+        vec![
+            soa(2, 86400),
+            soa(1, 86400),
+            www(1),
+            soa(2, 86400),
+            www(2),
+            soa(2, 86400),
+        ],
+    ]);
+    let secondary = Secondary::new("example.test", addr);
+    let runner = secondary.clone();
+    thread::spawn(move || runner.run(QueryOptions::default()));
+
+    let wait_for = |serial| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while secondary.serial() != Some(serial) {
+            assert!(Instant::now() < deadline, "never got serial {}", serial);
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    wait_for(1);
+
+    let notify = |src: SocketAddr| {
+        let mut request = QueryBuilder::new()
+            .question("example.test", QueryType::SOA)
+            .build();
+        request.header.opcode = Opcode::NOTIFY;
+        secondary::handle_notify(slice::from_ref(&secondary), &request, src)
+    };
+    assert_eq!(notify("192.0.2.1:53".parse().unwrap()), ResultCode::REFUSED);
+    assert_eq!(
+        notify("127.0.0.1:5300".parse().unwrap()),
+        ResultCode::NOERROR
+    );
+
+    wait_for(2);
+    assert_eq!(lookup(&options(&secondary)), [www(2)]);
+}
+
+#[test]
+fn expired_zones_are_not_answered_from() {
+    let addr = primary(vec![vec![soa(1, 1), www(1), soa(1, 1)]]);
+    let secondary = Secondary::new("example.test", addr);
+    secondary.refresh(&QueryOptions::default()).unwrap();
+    assert!(secondary.answer("www.example.test", QueryType::A).is_some());
+
+    thread::sleep(Duration::from_millis(1100));
+    assert!(secondary.answer("www.example.test", QueryType::A).is_none());
+}
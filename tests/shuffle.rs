@@ -0,0 +1,43 @@
+//! Answers are shuffled within their RRsets, in an order that's the same
+//! every time for the same seed.
+
+use std::net::Ipv4Addr;
+
+use dns_server::{shuffle::AnswerShuffler, DnsRecord};
+
+/// An alias, followed by the 20 addresses of its target
+fn answer() -> Vec<DnsRecord> {
+    let mut records = vec![DnsRecord::CNAME {
+        domain: "www.example.com".to_string(),
+        host: "web.example.com".to_string(),
+        ttl: 300,
+    }];
+    records.extend((1..=20).map(|i| DnsRecord::A {
+        domain: "web.example.com".to_string(),
+        addr: Ipv4Addr::new(10, 0, 0, i),
+        ttl: 300,
+    }));
+    records
+}
+
+fn shuffled(shuffler: &AnswerShuffler) -> Vec<DnsRecord> {
+    let mut records = answer();
+    shuffler.shuffle(&mut records);
+    records
+}
+
+#[test]
+fn fixed_seeds_give_a_fixed_order() {
+    let first = shuffled(&AnswerShuffler::new(Some(42)));
+    assert_eq!(first, shuffled(&AnswerShuffler::new(Some(42))));
+    assert_ne!(first, answer());
+    assert_ne!(first, shuffled(&AnswerShuffler::new(Some(43))));
+
+    // The alias stays ahead of the addresses, which are all still there
+    assert_eq!(first[0], answer()[0]);
+    let mut addrs = first[1..].to_vec();
+    addrs.sort_by_key(|rec| format!("{:?}", rec));
+    let mut expected = answer()[1..].to_vec();
+    expected.sort_by_key(|rec| format!("{:?}", rec));
+    assert_eq!(addrs, expected);
+}
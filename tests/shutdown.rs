@@ -0,0 +1,139 @@
+//! Servers stop taking queries once they're shut down, after answering the
+//! ones they already have, and can share their ports with other processes.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use dns_server::{
+    resolver::ResolverOptions,
+    server::{self, BindOptions},
+    shutdown::Shutdown,
+    zone::Zone,
+    BytePacketBuffer, DnsPacket, QueryType,
+};
+
+fn options() -> ResolverOptions {
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A 10.0.0.1\n",
+    )
+    .unwrap();
+
+    ResolverOptions {
+        zones: vec![zone],
+        ..ResolverOptions::default()
+    }
+}
+
+fn ask(server: SocketAddr) -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+
+    let mut query = DnsPacket::query("www.example.test", QueryType::A);
+    let mut buffer = BytePacketBuffer::new();
+    query.write(&mut buffer).unwrap();
+    socket.send_to(&buffer.buf[..buffer.pos()], server).unwrap();
+
+    let mut buffer = BytePacketBuffer::new();
+    socket.recv_from(&mut buffer.buf).ok()?;
+    DnsPacket::from_buffer(&mut buffer).unwrap().get_random_a()
+}
+
+#[test]
+fn udp_servers_return_once_stopped() {
+    let options = Arc::new(options());
+    let socket = server::bind_udp("127.0.0.1:0".parse().unwrap(), BindOptions::default()).unwrap();
+    let addr = socket.local_addr().unwrap();
+    let server = {
+        let options = options.clone();
+        thread::spawn(move || server::serve_udp(socket, options, 2))
+    };
+
+    assert_eq!(ask(addr), Some(Ipv4Addr::new(10, 0, 0, 1)));
+
+    let start = Instant::now();
+    options.shutdown.stop();
+    server.join().unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn tcp_connections_after_the_stop_are_closed() {
+    let options = Arc::new(options());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = {
+        let options = options.clone();
+        thread::spawn(move || server::serve_tcp(listener, options))
+    };
+
+    options.shutdown.stop();
+    server.join().unwrap();
+}
+
+#[test]
+fn draining_waits_for_the_queries_in_flight() {
+    let shutdown = Shutdown::new();
+    assert_eq!(shutdown.drain(Duration::from_secs(1)), 0);
+
+    let in_flight = shutdown.track();
+    let held = shutdown.track();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        drop(in_flight);
+    });
+    assert_eq!(shutdown.drain(Duration::from_millis(500)), 1);
+
+    drop(held);
+    assert_eq!(shutdown.drain(Duration::from_millis(500)), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn ports_can_be_shared() {
+    let reuse_port = BindOptions {
+        reuse_port: true,
+        ..BindOptions::default()
+    };
+    let first = server::bind_udp("127.0.0.1:0".parse().unwrap(), reuse_port).unwrap();
+    let addr = first.local_addr().unwrap();
+    assert!(server::bind_udp(addr, reuse_port).is_ok());
+    assert!(server::bind_udp(addr, BindOptions::default()).is_err());
+
+    let first = server::bind_tcp("127.0.0.1:0".parse().unwrap(), reuse_port).unwrap();
+    let addr = first.local_addr().unwrap();
+    assert!(server::bind_tcp(addr, reuse_port).is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn sigterm_saves_the_cache_on_the_way_out() {
+    use std::process::{Command, Stdio};
+
+    let path = std::env::temp_dir().join(format!("dns-server-shutdown-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dns-server"))
+        .args(["serve", "--bind", "127.0.0.1:0", "--cache-file"])
+        .arg(&path)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    let killed = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+
+    let status = child.wait().unwrap();
+    assert!(status.success(), "{}", status);
+    assert!(path.exists());
+}
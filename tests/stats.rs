@@ -0,0 +1,94 @@
+//! A running server reports the names and clients with the most queries,
+//! the query types, and how many were blocked, for `dns-server stats`.
+
+mod common;
+
+use std::{
+    net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use dns_server::{
+    blocklist::Blocklist,
+    cache::Cache,
+    metrics::{self, Metrics, Stats},
+    resolver::ResolverOptions,
+    server,
+    zone::Zone,
+    QueryType,
+};
+
+use common::ask;
+
+const ZONE: &str = "$ORIGIN example.test.\n\
+                    @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+                    www IN A 10.0.0.1\n\
+                    mail IN A 10.0.0.2\n";
+
+#[test]
+fn running_servers_report_their_statistics() {
+    let mut blocklist = Blocklist::new();
+    blocklist.block("ads.example.net");
+    let metrics = Metrics::new();
+    metrics.watch_cache(Cache::new());
+    let options = ResolverOptions {
+        zones: vec![Zone::parse(ZONE).unwrap()],
+        blocklist: Some(blocklist),
+        metrics: Some(metrics.clone()),
+        ..ResolverOptions::default()
+    };
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dns = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let http = listener.local_addr().unwrap();
+    thread::spawn(move || metrics::serve(listener, metrics));
+
+    for _ in 0..3 {
+        ask(dns, "www.example.test", QueryType::A);
+    }
+    ask(dns, "www.example.test", QueryType::AAAA);
+    ask(dns, "mail.example.test", QueryType::A);
+    ask(dns, "ads.example.net", QueryType::A);
+
+    let stats = metrics::fetch_stats(http, 2, Duration::from_secs(2)).unwrap();
+    assert_eq!(stats.queries, 6);
+    assert_eq!(stats.blocked, 1);
+    assert_eq!(stats.cache_entries, Some(0));
+    assert_eq!(
+        stats.qtypes,
+        [("A".to_string(), 5), ("AAAA".to_string(), 1)]
+    );
+    // Names with as many queries as each other are in alphabetical order
+    assert_eq!(
+        stats.names,
+        [
+            ("www.example.test".to_string(), 4),
+            ("ads.example.net".to_string(), 1)
+        ]
+    );
+    assert_eq!(stats.clients, [(IpAddr::V4(Ipv4Addr::LOCALHOST), 6)]);
+}
+
+#[test]
+fn statistics_read_back_the_way_they_were_written() {
+    let stats = Stats {
+        queries: 12,
+        blocked: 2,
+        cache_hits: 4,
+        cache_misses: 6,
+        cache_entries: None,
+        qtypes: vec![("A".to_string(), 12)],
+        names: vec![("example.com".to_string(), 12)],
+        clients: vec![("::1".parse().unwrap(), 12)],
+    };
+    assert_eq!(stats.to_string().parse::<Stats>().unwrap(), stats);
+
+    // Lines from newer servers are skipped
+    let stats: Stats = "queries 3\nlatency_p99 12\n".parse().unwrap();
+    assert_eq!(stats.queries, 3);
+    assert!("queries many".parse::<Stats>().is_err());
+}
@@ -0,0 +1,154 @@
+//! HTTPS and SVCB records load from zone files in the presentation format of
+//! RFC 9460, and print back in the same format. Off the wire, their params
+//! are decoded into their types, and written back the way they came.
+
+use std::net::Ipv4Addr;
+
+use dns_server::{svcb::SvcParams, zone::Zone, BytePacketBuffer, DnsRecord, QueryType};
+
+fn records(zone: &str) -> Vec<DnsRecord> {
+    Zone::parse(&format!("$ORIGIN example.test.\n$TTL 300\n{}", zone))
+        .unwrap()
+        .records
+}
+
+#[test]
+fn https_records_in_zone_files() {
+    let records = records(
+        r#"
+@   HTTPS 0 www
+www HTTPS 1 . alpn="h2,h3" port=8443 ipv4hint=192.0.2.1,192.0.2.2 ech=AEX+DQBB
+"#,
+    );
+
+    assert_eq!(
+        records[0],
+        DnsRecord::HTTPS {
+            domain: "example.test".to_string(),
+            priority: 0,
+            target: "www.example.test".to_string(),
+            params: SvcParams::default(),
+            ttl: 300,
+        }
+    );
+    assert_eq!(
+        records[1],
+        DnsRecord::HTTPS {
+            domain: "www.example.test".to_string(),
+            priority: 1,
+            target: String::new(),
+            params: SvcParams {
+                alpn: vec!["h2".to_string(), "h3".to_string()],
+                port: Some(8443),
+                ipv4hint: vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)],
+                ech: vec![0x00, 0x45, 0xfe, 0x0d, 0x00, 0x41],
+                ..SvcParams::default()
+            },
+            ttl: 300,
+        }
+    );
+    assert_eq!(
+        records[1].data(),
+        "1 . alpn=h2,h3 port=8443 ipv4hint=192.0.2.1,192.0.2.2 ech=AEX+DQBB"
+    );
+}
+
+#[test]
+fn presentation_and_generic_notation_agree() {
+    // One of the examples of RFC 9460, appendix D.2, along with its wire
+    // format field by field
+    let records = records(
+        r#"
+svc SVCB 16 foo.example.org. mandatory=alpn,ipv4hint alpn=h2,h3-19 ipv4hint=192.0.2.1 key65333=ex1
+raw SVCB \# 55 ( 0010 03666f6f076578616d706c65036f726700
+                 0000 0004 00010004
+                 0001 0009 026832 0568332d3139
+                 0004 0004 c0000201
+                 ff35 0003 657831 )
+"#,
+    );
+
+    match (&records[0], &records[1]) {
+        (
+            DnsRecord::SVCB {
+                priority, params, ..
+            },
+            DnsRecord::SVCB {
+                priority: raw_priority,
+                params: raw_params,
+                ..
+            },
+        ) => {
+            assert_eq!(priority, raw_priority);
+            assert_eq!(params, raw_params);
+        }
+        records => panic!("expected SVCB records, got {:?}", records),
+    }
+    assert_eq!(records[0].query_type(), QueryType::SVCB);
+    assert_eq!(
+        records[1].data(),
+        "16 foo.example.org. mandatory=alpn,ipv4hint alpn=h2,h3-19 ipv4hint=192.0.2.1 \
+         key65333=\"ex1\""
+    );
+}
+
+#[test]
+fn invalid_params_are_rejected() {
+    for params in [
+        "port=443 port=8443",
+        "port=http",
+        "ipv4hint=2001:db8::1",
+        "ech=not*base64",
+        "color=blue",
+    ] {
+        let zone = format!("www.example.test. HTTPS 1 . {}", params);
+        assert!(Zone::parse(&zone).is_err(), "{}", params);
+    }
+}
+
+#[test]
+fn params_off_the_wire_are_decoded() {
+    // alpn=h2,h3 port=8443 ipv4hint=192.0.2.1,192.0.2.2 and a key of our own
+    let data = b"\x00\x01\x00\x06\x02h2\x02h3\
+        \x00\x03\x00\x02\x20\xfb\
+        \x00\x04\x00\x08\xc0\x00\x02\x01\xc0\x00\x02\x02\
+        \x02\x9a\x00\x02hi";
+
+    let mut buffer = BytePacketBuffer::new();
+    buffer.buf[..data.len()].copy_from_slice(data);
+    let params = SvcParams::read(&mut buffer, data.len()).unwrap();
+
+    assert_eq!(params.alpn, ["h2", "h3"]);
+    assert_eq!(params.port, Some(8443));
+    assert_eq!(
+        params.ipv4hint,
+        [Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)]
+    );
+    assert_eq!(params.other, [(666, b"hi".to_vec())]);
+    assert!(!params.no_default_alpn);
+
+    // And written back the same way
+    let mut buffer = BytePacketBuffer::new();
+    params.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos()], data);
+}
+
+#[test]
+fn lists_with_bytes_left_over_are_rejected() {
+    for data in [
+        // mandatory with half a key
+        &b"\x00\x00\x00\x03\x00\x01\x00"[..],
+        // ipv4hint with an address and a half
+        b"\x00\x04\x00\x06\xc0\x00\x02\x01\xc0\x00",
+        // ipv6hint a byte short of an address
+        b"\x00\x06\x00\x0f\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+    ] {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buf[..data.len()].copy_from_slice(data);
+        assert!(
+            SvcParams::read(&mut buffer, data.len()).is_err(),
+            "{:?}",
+            data
+        );
+    }
+}
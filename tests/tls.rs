@@ -0,0 +1,109 @@
+//! Queries sent over TLS go to an upstream whose certificate checks out for
+//! the name it's configured with, on a connection that's kept open for the
+//! queries after them.
+#![cfg(feature = "tls")]
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{mpsc, Arc},
+    thread,
+};
+
+use dns_server::{
+    client::QueryOptions, tls::TlsClient, BytePacketBuffer, DnsPacket, DnsRecord, QueryType,
+};
+use rustls::{
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+
+/// An upstream with a certificate for `localhost`, answering every query
+/// with 10.0.0.1 and reporting each connection it accepts, along with a
+/// client that trusts it
+fn upstream(connected: mpsc::Sender<()>) -> (SocketAddr, TlsClient) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![certified.cert.der().clone()],
+            PrivateKeyDer::Pkcs8(key),
+        )
+        .unwrap();
+    let config = Arc::new(config);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let connection = ServerConnection::new(config.clone()).unwrap();
+            let mut stream = StreamOwned::new(connection, stream.unwrap());
+            let _ = connected.send(());
+            thread::spawn(move || answer(&mut stream));
+        }
+    });
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    (addr, TlsClient::with_roots(roots))
+}
+
+/// Answer the length prefixed queries coming in on `stream` until it's closed
+fn answer(stream: &mut StreamOwned<ServerConnection, TcpStream>) {
+    loop {
+        let mut len = [0; 2];
+        if stream.read_exact(&mut len).is_err() {
+            return;
+        }
+        let mut buffer = BytePacketBuffer::new();
+        stream
+            .read_exact(&mut buffer.buf[..u16::from_be_bytes(len) as usize])
+            .unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.questions = query.questions.clone();
+        response.answers.push(DnsRecord::A {
+            domain: query.questions[0].name.clone(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: 300,
+        });
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        let mut message = (buffer.pos() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&buffer.buf[..buffer.pos()]);
+        stream.write_all(&message).unwrap();
+        stream.flush().unwrap();
+    }
+}
+
+#[test]
+fn queries_are_answered_over_tls() {
+    let (connected, connections) = mpsc::channel();
+    let (server, client) = upstream(connected);
+    let options = QueryOptions::default();
+
+    for qname in ["www.example.test", "api.example.test", "www.example.test"] {
+        let response = client
+            .query(qname, QueryType::A, server, "localhost", &options)
+            .unwrap();
+        assert_eq!(response.questions[0].name, qname);
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+    // All of them over the same connection
+    assert_eq!(connections.try_iter().count(), 1);
+
+    // The certificate is only good for the name it was issued for
+    assert!(client
+        .query(
+            "www.example.test",
+            QueryType::A,
+            server,
+            "example.test",
+            &options
+        )
+        .is_err());
+}
@@ -0,0 +1,146 @@
+//! Zones are pulled from a primary in full with AXFR, or brought up to date
+//! with IXFR, whatever number of messages the records arrive in.
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    thread::{self, JoinHandle},
+};
+
+use dns_server::{
+    client::QueryOptions, transfer, zone::Zone, BytePacketBuffer, DnsPacket, DnsRecord, QueryType,
+    ResultCode,
+};
+
+fn soa(serial: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: "example.test".to_string(),
+        mname: "ns.example.test".to_string(),
+        rname: "hostmaster.example.test".to_string(),
+        serial,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 60,
+        ttl: 300,
+    }
+}
+
+fn a(name: &str, last: u8) -> DnsRecord {
+    DnsRecord::A {
+        domain: format!("{}.example.test", name),
+        addr: Ipv4Addr::new(10, 0, 0, last),
+        ttl: 300,
+    }
+}
+
+/// A primary that answers a single transfer with the given messages, and
+/// hands back the query it got
+fn primary(
+    rcode: ResultCode,
+    messages: Vec<Vec<DnsRecord>>,
+) -> (SocketAddr, JoinHandle<DnsPacket>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buf)).unwrap();
+
+        for answers in messages {
+            let mut response = DnsPacket::response_to(&query)
+                .rcode(rcode)
+                .answers(answers)
+                .build();
+            let mut buffer = BytePacketBuffer::with_capacity(u16::MAX as usize);
+            response.write(&mut buffer).unwrap();
+            stream
+                .write_all(&(buffer.pos() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&buffer.buf[..buffer.pos()]).unwrap();
+        }
+
+        query
+    });
+
+    (addr, handle)
+}
+
+fn zone(records: Vec<DnsRecord>) -> Zone {
+    Zone {
+        origin: "example.test".to_string(),
+        records,
+    }
+}
+
+#[test]
+fn axfr_takes_every_message() {
+    let (addr, primary) = primary(
+        ResultCode::NOERROR,
+        vec![vec![soa(1), a("www", 1)], vec![a("mail", 2), soa(1)]],
+    );
+
+    let zone = transfer::axfr("Example.test.", addr, &QueryOptions::default(), None).unwrap();
+    let query = primary.join().unwrap();
+
+    assert_eq!(query.questions[0].qtype, QueryType::AXFR);
+    assert_eq!(zone.origin, "example.test");
+    assert_eq!(zone.records, [soa(1), a("www", 1), a("mail", 2)]);
+}
+
+#[test]
+fn ixfr_applies_the_changes_in_order() {
+    let (addr, primary) = primary(
+        ResultCode::NOERROR,
+        vec![
+            vec![soa(3), soa(1), a("www", 1), soa(2), a("www", 2)],
+            vec![soa(2), a("old", 9), soa(3), a("new", 3), soa(3)],
+        ],
+    );
+
+    let mut zone = zone(vec![soa(1), a("www", 1), a("old", 9)]);
+    assert!(transfer::ixfr(&mut zone, addr, &QueryOptions::default(), None).unwrap());
+    let query = primary.join().unwrap();
+
+    assert_eq!(query.questions[0].qtype, QueryType::IXFR);
+    assert_eq!(query.authorities, [soa(1)]);
+    assert_eq!(zone.records, [a("www", 2), soa(3), a("new", 3)]);
+}
+
+#[test]
+fn ixfr_of_a_current_zone_changes_nothing() {
+    let (addr, _) = primary(ResultCode::NOERROR, vec![vec![soa(1)]]);
+
+    let mut zone = zone(vec![soa(1), a("www", 1)]);
+    assert!(!transfer::ixfr(&mut zone, addr, &QueryOptions::default(), None).unwrap());
+    assert_eq!(zone.records, [soa(1), a("www", 1)]);
+}
+
+#[test]
+fn ixfr_may_be_answered_in_full() {
+    let (addr, _) = primary(ResultCode::NOERROR, vec![vec![soa(2), a("www", 2), soa(2)]]);
+
+    let mut zone = zone(vec![soa(1), a("www", 1)]);
+    assert!(transfer::ixfr(&mut zone, addr, &QueryOptions::default(), None).unwrap());
+    assert_eq!(zone.records, [soa(2), a("www", 2)]);
+}
+
+#[test]
+fn refused_transfers_fail() {
+    let (addr, _) = primary(ResultCode::REFUSED, vec![vec![]]);
+
+    let error = transfer::axfr("example.test", addr, &QueryOptions::default(), None).unwrap_err();
+    assert!(error.to_string().contains("REFUSED"), "{}", error);
+}
+
+#[test]
+fn serials_wrap_around() {
+    assert!(transfer::is_newer(2, 1));
+    assert!(transfer::is_newer(1, u32::MAX));
+    assert!(!transfer::is_newer(1, 1));
+    assert!(!transfer::is_newer(u32::MAX, 1));
+}
@@ -0,0 +1,174 @@
+//! Messages signed with a TSIG key are checked against the keys we have,
+//! and the responses to them are signed in turn, including the UPDATEs that
+//! change dynamic zones. The signed zone transfers we ask for are checked
+//! next to the code that checks them, in `tsig`.
+#![cfg(feature = "tsig")]
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    slice,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use dns_server::{
+    resolver::ResolverOptions,
+    server,
+    tsig::{self, Algorithm, TsigKey, Verified},
+    update::{DynamicZone, Update},
+    zone::Zone,
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType, ResultCode,
+};
+
+const ZONE: &str = "$ORIGIN example.test.\n\
+                    $TTL 300\n\
+                    @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+                    @ IN NS ns1\n\
+                    www IN A 10.0.0.1\n";
+
+fn key(secret: &[u8]) -> TsigKey {
+    TsigKey::new("test-key", Algorithm::HmacSha256, secret).unwrap()
+}
+
+fn serve(options: ResolverOptions) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+    addr
+}
+
+fn exchange(server: SocketAddr, request: &BytePacketBuffer) -> Vec<u8> {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    socket
+        .send_to(&request.buf[..request.pos()], server)
+        .unwrap();
+
+    let mut buf = [0; 4096];
+    let (len, _) = socket.recv_from(&mut buf).unwrap();
+    buf[..len].to_vec()
+}
+
+fn rcode(message: &[u8]) -> ResultCode {
+    DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(message))
+        .unwrap()
+        .header
+        .rescode
+}
+
+#[test]
+fn keys_parse_the_way_dig_takes_them() {
+    let key: TsigKey = "hmac-sha512:Transfer-Key.:c2VjcmV0".parse().unwrap();
+    assert_eq!(key.name, "transfer-key");
+    assert_eq!(key.algorithm, Algorithm::HmacSha512);
+
+    let key: TsigKey = "transfer-key:c2VjcmV0".parse().unwrap();
+    assert_eq!(key.algorithm, Algorithm::HmacSha256);
+
+    assert!("hmac-md5:transfer-key:c2VjcmV0".parse::<TsigKey>().is_err());
+    assert!("transfer-key".parse::<TsigKey>().is_err());
+    assert!("transfer-key:not base64!".parse::<TsigKey>().is_err());
+}
+
+#[test]
+fn only_untouched_messages_signed_with_a_known_key_verify() {
+    let key = key(b"secret");
+    let mut buffer = BytePacketBuffer::new();
+    DnsPacket::query("www.example.test", QueryType::A)
+        .write(&mut buffer)
+        .unwrap();
+    let mac = tsig::sign(&mut buffer, &key, None).unwrap();
+    let message = buffer.buf[..buffer.pos()].to_vec();
+
+    match tsig::verify(&message, slice::from_ref(&key), None).unwrap() {
+        Verified::Signed {
+            key: signer,
+            mac: signed,
+        } => {
+            assert_eq!(signer, key);
+            assert_eq!(signed, mac);
+        }
+        verified => panic!("{:?}", verified),
+    }
+
+    // The question as sent, with its case flipped
+    let mut tampered = message.clone();
+    tampered[13] ^= 0x20;
+    assert!(matches!(
+        tsig::verify(&tampered, slice::from_ref(&key), None).unwrap(),
+        Verified::Rejected(rejection) if rejection.error == tsig::BADSIG
+    ));
+    assert!(matches!(
+        tsig::verify(&message, &[self::key(b"other secret")], None).unwrap(),
+        Verified::Rejected(rejection) if rejection.error == tsig::BADSIG
+    ));
+    let other = TsigKey::new("other-key", Algorithm::HmacSha256, b"secret").unwrap();
+    assert!(matches!(
+        tsig::verify(&message, &[other], None).unwrap(),
+        Verified::Rejected(rejection) if rejection.error == tsig::BADKEY
+    ));
+}
+
+#[test]
+fn signed_queries_get_signed_responses() {
+    let key = key(b"secret");
+    let server = serve(ResolverOptions {
+        zones: vec![Zone::parse(ZONE).unwrap()],
+        tsig_keys: vec![key.clone()],
+        ..ResolverOptions::default()
+    });
+
+    let mut buffer = BytePacketBuffer::new();
+    DnsPacket::query("www.example.test", QueryType::A)
+        .write(&mut buffer)
+        .unwrap();
+    let mac = tsig::sign(&mut buffer, &key, None).unwrap();
+    let response = exchange(server, &buffer);
+    assert_eq!(rcode(&response), ResultCode::NOERROR);
+    assert!(matches!(
+        tsig::verify(&response, slice::from_ref(&key), Some(&mac)).unwrap(),
+        Verified::Signed { .. }
+    ));
+
+    // The server doesn't have this one
+    let unknown = TsigKey::new("unknown-key", Algorithm::HmacSha256, b"secret").unwrap();
+    let mut buffer = BytePacketBuffer::new();
+    DnsPacket::query("www.example.test", QueryType::A)
+        .write(&mut buffer)
+        .unwrap();
+    let mac = tsig::sign(&mut buffer, &unknown, None).unwrap();
+    let response = exchange(server, &buffer);
+    assert_eq!(rcode(&response), ResultCode::NOTAUTH);
+    assert!(matches!(
+        tsig::verify(&response, &[unknown], Some(&mac)).unwrap(),
+        Verified::Rejected(rejection) if rejection.error == tsig::BADKEY
+    ));
+}
+
+#[test]
+fn updates_signed_with_an_update_key_are_allowed() {
+    let key = key(b"secret");
+    let mut zone = DynamicZone::new(Zone::parse(ZONE).unwrap(), Vec::new()).unwrap();
+    zone.update_keys = vec!["test-key".to_string()];
+    let server = serve(ResolverOptions {
+        dynamic_zones: vec![zone.clone()],
+        tsig_keys: vec![key.clone()],
+        ..ResolverOptions::default()
+    });
+
+    let update = Update::new("example.test").add_record(DnsRecord::A {
+        domain: "host.example.test".to_string(),
+        addr: Ipv4Addr::new(10, 0, 0, 7),
+        ttl: 300,
+    });
+    let mut buffer = BytePacketBuffer::new();
+    update.write(&mut buffer).unwrap();
+    assert_eq!(rcode(&exchange(server, &buffer)), ResultCode::REFUSED);
+
+    tsig::sign(&mut buffer, &key, None).unwrap();
+    assert_eq!(rcode(&exchange(server, &buffer)), ResultCode::NOERROR);
+    assert_eq!(zone.zone().serial(), Some(2));
+}
@@ -0,0 +1,115 @@
+//! The TTLs that upstreams answer with are brought within bounds before
+//! they're relayed to clients and cached.
+
+use std::{
+    net::{Ipv4Addr, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    cache::Cache,
+    forwarder::{Forwarder, Upstream},
+    resolver::{self, ResolverOptions},
+    ttl::TtlPolicy,
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType, ResultCode,
+};
+
+fn policy() -> TtlPolicy {
+    TtlPolicy {
+        min_ttl: 60,
+        max_ttl: Some(3600),
+        max_negative_ttl: Some(30),
+    }
+}
+
+fn soa(ttl: u32) -> DnsRecord {
+    DnsRecord::SOA {
+        domain: "example.com".to_string(),
+        mname: "ns1.example.com".to_string(),
+        rname: "hostmaster.example.com".to_string(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 900,
+        ttl,
+    }
+}
+
+#[test]
+fn ttls_are_clamped_into_bounds() {
+    let mut packet = DnsPacket::new();
+    for (last, ttl) in [(1, 5), (2, 0), (3, 300), (4, 86400)] {
+        packet.answers.push(DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, last),
+            ttl,
+        });
+    }
+    packet.authorities.push(soa(7200));
+    policy().apply(&mut packet);
+
+    let ttls: Vec<u32> = packet.answers.iter().map(|rec| rec.ttl()).collect();
+    // Records that mustn't be cached stay that way
+    assert_eq!(ttls, [60, 0, 300, 3600]);
+    assert_eq!(packet.authorities[0].ttl(), 3600);
+}
+
+#[test]
+fn negative_answers_are_capped_below_the_minimum() {
+    let mut packet = DnsPacket::new();
+    packet.header.rescode = ResultCode::NXDOMAIN;
+    packet.authorities.push(soa(7200));
+    policy().apply(&mut packet);
+    assert_eq!(packet.authorities[0].ttl(), 30);
+
+    // As are answers without any records
+    let mut packet = DnsPacket::new();
+    packet.authorities.push(soa(300));
+    policy().apply(&mut packet);
+    assert_eq!(packet.authorities[0].ttl(), 30);
+}
+
+/// An upstream that answers a single query with an address that lives for a
+/// day
+fn answer_once() -> Upstream {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let mut buffer = BytePacketBuffer::new();
+        let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+        let query = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        let mut response = DnsPacket::response_to(&query)
+            .answer(DnsRecord::A {
+                domain: query.questions[0].name.clone(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: 86400,
+            })
+            .build();
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        socket.send_to(&buffer.buf[..buffer.pos()], src).unwrap();
+    });
+
+    Upstream::Plain(addr)
+}
+
+#[test]
+fn relayed_answers_are_cached_with_their_clamped_ttls() {
+    let cache = Cache::new();
+    let options = Arc::new(ResolverOptions {
+        forwarder: Some(Forwarder::new(vec![answer_once()])),
+        cache: Some(cache.clone()),
+        ttl_policy: Some(policy()),
+        ..ResolverOptions::default()
+    });
+
+    let response = resolver::lookup("www.example.com", QueryType::A, &options).unwrap();
+    assert_eq!(response.answers[0].ttl(), 3600);
+
+    let cached = cache.get("www.example.com", QueryType::A).unwrap();
+    assert!(cached.answers[0].ttl() <= 3600);
+}
@@ -0,0 +1,244 @@
+//! Queries over UDP are read whole, however much larger than 512 bytes they
+//! are, as with EDNS options, while responses are cut down to what the client
+//! can take.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use dns_server::{
+    error::Result,
+    metrics::Metrics,
+    pipeline::{Next, Pipeline, Query, QueryHandler},
+    resolver::{ResolverOptions, Source},
+    server,
+    zone::Zone,
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryBuilder, QueryType, ResultCode,
+};
+
+fn options() -> ResolverOptions {
+    // Along with more addresses for big.example.test than fit in 512 bytes
+    let big: String = (1..=60)
+        .map(|i| format!("big IN A 10.0.1.{}\n", i))
+        .collect();
+    let zone = Zone::parse(&format!(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A 10.0.0.1\n\
+         {}",
+        big
+    ))
+    .unwrap();
+    ResolverOptions {
+        zones: vec![zone],
+        ..ResolverOptions::default()
+    }
+}
+
+/// Ask the server for www.example.test with 1200 bytes of EDNS padding
+/// (RFC 7830), which takes the query well past 512 bytes
+fn ask_padded(server: SocketAddr) -> DnsPacket {
+    let mut padding = vec![0, 12, 0x04, 0xb0];
+    padding.resize(4 + 1200, 0);
+    let mut query = DnsPacket::query("www.example.test", QueryType::A);
+    query.resources.push(DnsRecord::OPT {
+        packet_len: 1232,
+        flags: 0,
+        data: padding,
+    });
+    let mut buffer = BytePacketBuffer::with_capacity(2048);
+    query.write(&mut buffer).unwrap();
+    assert!(buffer.pos() > 1200);
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    client.send_to(&buffer.buf[..buffer.pos()], server).unwrap();
+    let mut buffer = BytePacketBuffer::with_capacity(1232);
+    let (len, _) = client.recv_from(&mut buffer.buf).unwrap();
+    DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buffer.buf[..len])).unwrap()
+}
+
+fn assert_answered(response: &DnsPacket) {
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+}
+
+#[test]
+fn single_queries_are_read_whole() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let options = Arc::new(options());
+        loop {
+            let _ = server::handle_udp_query(&socket, &options);
+        }
+    });
+
+    assert_answered(&ask_padded(server));
+}
+
+#[test]
+fn queries_handed_to_workers_are_read_whole() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let _ = server::serve_udp(socket, Arc::new(options()), 2);
+    });
+
+    assert_answered(&ask_padded(server));
+    assert_answered(&ask_padded(server));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_servers_read_queries_whole() {
+    use dns_server::async_server;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let socket = tokio::net::UdpSocket::from_std(socket).unwrap();
+            async_server::serve_udp(socket, Arc::new(options())).await
+        })
+    });
+
+    assert_answered(&ask_padded(server));
+}
+
+#[test]
+fn responses_too_large_for_the_client_are_truncated() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let options = Arc::new(options());
+        loop {
+            let _ = server::handle_udp_query(&socket, &options);
+        }
+    });
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let ask = |query: DnsPacket| {
+        let mut query = query;
+        let mut buffer = BytePacketBuffer::new();
+        query.write(&mut buffer).unwrap();
+        client.send_to(&buffer.buf[..buffer.pos()], server).unwrap();
+        let mut buffer = BytePacketBuffer::with_capacity(2048);
+        let (len, _) = client.recv_from(&mut buffer.buf).unwrap();
+        let response =
+            DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buffer.buf[..len])).unwrap();
+        (len, response)
+    };
+
+    // Without EDNS, the response takes 512 bytes at the most
+    let (len, response) = ask(DnsPacket::query("big.example.test", QueryType::A));
+    assert!(len <= 512);
+    assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    assert!(response.header.truncated_message);
+    assert!(!response.answers.is_empty());
+
+    // With it, as much as the client can take, along with our OPT record
+    let query = QueryBuilder::new()
+        .question("big.example.test", QueryType::A)
+        .edns(600)
+        .build();
+    let (len, response) = ask(query);
+    assert!(len > 512 && len <= 600);
+    assert!(response.header.truncated_message);
+    assert!(response.get_opt().is_some());
+}
+
+#[test]
+fn responses_are_dropped_rather_than_answered() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let options = Arc::new(options());
+        loop {
+            let _ = server::handle_udp_query(&socket, &options);
+        }
+    });
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let send = |query: &mut DnsPacket| {
+        let mut buffer = BytePacketBuffer::new();
+        query.write(&mut buffer).unwrap();
+        client.send_to(&buffer.buf[..buffer.pos()], server).unwrap();
+    };
+    let mut buffer = BytePacketBuffer::new();
+
+    let mut response = DnsPacket::query("www.example.test", QueryType::A);
+    response.header.response = true;
+    send(&mut response);
+    assert!(client.recv_from(&mut buffer.buf).is_err());
+
+    // With more than one question, the first is the one answered
+    let mut query = DnsPacket::query("www.example.test", QueryType::A);
+    query
+        .questions
+        .append(&mut DnsPacket::query("big.example.test", QueryType::A).questions);
+    send(&mut query);
+    let (len, _) = client.recv_from(&mut buffer.buf).unwrap();
+    let response =
+        DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(&buffer.buf[..len])).unwrap();
+    assert_answered(&response);
+}
+
+/// Holds up every query until the sender of the channel is dropped
+struct Stall(Mutex<mpsc::Receiver<()>>);
+
+impl QueryHandler for Stall {
+    fn handle(&self, query: &Query, next: Next) -> Result<(DnsPacket, Source)> {
+        let _ = self.0.lock().unwrap().recv();
+        next.run(query)
+    }
+}
+
+#[test]
+fn queries_past_what_the_workers_can_take_are_dropped() {
+    let (release, stalled) = mpsc::channel();
+    let mut pipeline = Pipeline::new();
+    pipeline.push_front(Stall(Mutex::new(stalled)));
+    let metrics = Metrics::new();
+    let options = ResolverOptions {
+        pipeline,
+        metrics: Some(metrics.clone()),
+        ..options()
+    };
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+
+    assert!(metrics.render().contains("dns_dropped_total 0\n"));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut buffer = BytePacketBuffer::new();
+    DnsPacket::query("www.example.test", QueryType::A)
+        .write(&mut buffer)
+        .unwrap();
+    // In batches, so that the socket buffer doesn't fill up before the
+    // queue does
+    let dropped = (0..100).any(|_| {
+        for _ in 0..100 {
+            client.send_to(&buffer.buf[..buffer.pos()], server).unwrap();
+        }
+        thread::sleep(Duration::from_millis(10));
+        !metrics.render().contains("dns_dropped_total 0\n")
+    });
+    drop(release);
+    assert!(dropped);
+}
@@ -0,0 +1,259 @@
+//! Clients allowed to make changes to a dynamic zone can add and delete its
+//! records with UPDATE messages, as long as their prerequisites hold. Those
+//! that don't sign them are only allowed to when that's asked for.
+
+mod common;
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread,
+};
+
+use dns_server::{
+    resolver::ResolverOptions,
+    server,
+    update::{DynamicZone, Update},
+    zone::Zone,
+    BytePacketBuffer, DnsRecord, QueryType, ResultCode,
+};
+
+use common::{ask, client, exchange};
+
+const ZONE: &str = "$ORIGIN example.test.\n\
+                    $TTL 300\n\
+                    @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+                    @ IN NS ns1\n\
+                    ns1 IN A 10.0.0.53\n\
+                    www IN A 10.0.0.1\n";
+
+fn a(name: &str, addr: Ipv4Addr) -> DnsRecord {
+    DnsRecord::A {
+        domain: name.to_string(),
+        addr,
+        ttl: 300,
+    }
+}
+
+fn cname(name: &str, host: &str) -> DnsRecord {
+    DnsRecord::CNAME {
+        domain: name.to_string(),
+        host: host.to_string(),
+        ttl: 300,
+    }
+}
+
+/// A zone taking unsigned updates from `allow`, see tests/tsig.rs for
+/// signed ones
+fn dynamic_zone(allow: &str) -> DynamicZone {
+    let mut zone =
+        DynamicZone::new(Zone::parse(ZONE).unwrap(), vec![allow.parse().unwrap()]).unwrap();
+    zone.allow_unsigned = true;
+    zone
+}
+
+fn serve(zone: DynamicZone) -> SocketAddr {
+    let options = ResolverOptions {
+        dynamic_zones: vec![zone],
+        ..ResolverOptions::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || server::serve_udp(socket, Arc::new(options), 1));
+    addr
+}
+
+fn send(server: SocketAddr, update: &Update) -> ResultCode {
+    let mut buffer = BytePacketBuffer::new();
+    update.write(&mut buffer).unwrap();
+    let response = exchange(&client(), server, &buffer);
+    assert_eq!(response.header.id, update.id);
+    response.header.rescode
+}
+
+#[test]
+fn records_can_be_added_and_deleted() {
+    let zone = dynamic_zone("127.0.0.1");
+    let server = serve(zone.clone());
+
+    let update = Update::new("example.test")
+        .require_no_name("host.example.test")
+        .add_record(a("host.example.test", Ipv4Addr::new(10, 0, 0, 7)));
+    assert_eq!(send(server, &update), ResultCode::NOERROR);
+    assert_eq!(
+        ask(server, "host.example.test", QueryType::A).get_random_a(),
+        Some(Ipv4Addr::new(10, 0, 0, 7))
+    );
+    assert_eq!(zone.zone().serial(), Some(2));
+
+    let update = Update::new("example.test").delete_rrset("www.example.test", QueryType::A);
+    assert_eq!(send(server, &update), ResultCode::NOERROR);
+    assert_eq!(
+        ask(server, "www.example.test", QueryType::A).get_random_a(),
+        None
+    );
+    assert_eq!(zone.zone().serial(), Some(3));
+}
+
+#[test]
+fn nothing_changes_unless_the_prerequisites_hold() {
+    let zone = dynamic_zone("127.0.0.1");
+    let host = a("host.example.test", Ipv4Addr::new(10, 0, 0, 7));
+
+    let update = Update::new("example.test")
+        .require_no_name("www.example.test")
+        .add_record(host.clone());
+    assert_eq!(zone.apply(&update), ResultCode::YXDOMAIN);
+
+    let update = Update::new("example.test")
+        .require_rrset("www.example.test", QueryType::AAAA)
+        .add_record(host.clone());
+    assert_eq!(zone.apply(&update), ResultCode::NXRRSET);
+
+    let update = Update::new("example.test")
+        .require_record(a("www.example.test", Ipv4Addr::new(10, 0, 0, 2)))
+        .add_record(host.clone());
+    assert_eq!(zone.apply(&update), ResultCode::NXRRSET);
+
+    let update = Update::new("example.test")
+        .require_name("www.example.net")
+        .add_record(host);
+    assert_eq!(zone.apply(&update), ResultCode::NOTZONE);
+
+    let unchanged = zone.zone();
+    assert_eq!(unchanged.serial(), Some(1));
+    assert!(unchanged
+        .rrset("host.example.test", QueryType::ANY)
+        .next()
+        .is_none());
+
+    let update = Update::new("example.test")
+        .require_record(a("www.example.test", Ipv4Addr::new(10, 0, 0, 1)))
+        .delete_record(a("www.example.test", Ipv4Addr::new(10, 0, 0, 1)));
+    assert_eq!(zone.apply(&update), ResultCode::NOERROR);
+    assert_eq!(zone.zone().serial(), Some(2));
+}
+
+#[test]
+fn aliases_are_not_added_next_to_other_records() {
+    let zone = dynamic_zone("127.0.0.1");
+
+    let update = Update::new("example.test").add_record(cname("www.example.test", "example.net"));
+    assert_eq!(zone.apply(&update), ResultCode::NOERROR);
+    let unchanged = zone.zone();
+    assert_eq!(unchanged.serial(), Some(1));
+    assert_eq!(
+        unchanged
+            .rrset("www.example.test", QueryType::ANY)
+            .collect::<Vec<_>>(),
+        [&a("www.example.test", Ipv4Addr::new(10, 0, 0, 1))]
+    );
+}
+
+#[test]
+fn other_records_are_not_added_next_to_aliases() {
+    let zone = dynamic_zone("127.0.0.1");
+    let update =
+        Update::new("example.test").add_record(cname("alias.example.test", "www.example.test"));
+    assert_eq!(zone.apply(&update), ResultCode::NOERROR);
+    assert_eq!(zone.zone().serial(), Some(2));
+
+    let update =
+        Update::new("example.test").add_record(a("alias.example.test", Ipv4Addr::new(10, 0, 0, 7)));
+    assert_eq!(zone.apply(&update), ResultCode::NOERROR);
+    assert_eq!(zone.zone().serial(), Some(2));
+
+    // While another alias takes the place of the one there
+    let update =
+        Update::new("example.test").add_record(cname("alias.example.test", "ns1.example.test"));
+    assert_eq!(zone.apply(&update), ResultCode::NOERROR);
+    assert_eq!(
+        zone.zone()
+            .rrset("alias.example.test", QueryType::ANY)
+            .collect::<Vec<_>>(),
+        [&cname("alias.example.test", "ns1.example.test")]
+    );
+}
+
+#[test]
+fn only_allowed_clients_may_make_changes() {
+    let server = serve(dynamic_zone("127.0.0.2"));
+
+    let update = Update::new("example.test").delete_name("www.example.test");
+    assert_eq!(send(server, &update), ResultCode::REFUSED);
+    assert_eq!(
+        ask(server, "www.example.test", QueryType::A).get_random_a(),
+        Some(Ipv4Addr::new(10, 0, 0, 1))
+    );
+
+    let update = Update::new("example.net").delete_name("www.example.net");
+    assert_eq!(send(server, &update), ResultCode::NOTAUTH);
+}
+
+#[test]
+fn unsigned_updates_are_refused_unless_asked_for() {
+    let mut zone = dynamic_zone("127.0.0.1");
+    zone.allow_unsigned = false;
+    let server = serve(zone);
+
+    let update = Update::new("example.test").delete_name("www.example.test");
+    assert_eq!(send(server, &update), ResultCode::REFUSED);
+    assert_eq!(
+        ask(server, "www.example.test", QueryType::A).get_random_a(),
+        Some(Ipv4Addr::new(10, 0, 0, 1))
+    );
+}
+
+#[test]
+fn the_soa_and_name_servers_of_the_zone_stay() {
+    let zone = dynamic_zone("127.0.0.1");
+
+    let update = Update::new("example.test").delete_name("example.test");
+    assert_eq!(zone.apply(&update), ResultCode::NOERROR);
+    let update = Update::new("example.test").delete_record(DnsRecord::NS {
+        domain: "example.test".to_string(),
+        host: "ns1.example.test".to_string(),
+        ttl: 300,
+    });
+    assert_eq!(zone.apply(&update), ResultCode::NOERROR);
+
+    let zone = zone.zone();
+    assert_eq!(zone.serial(), Some(1));
+    assert_eq!(zone.rrset("example.test", QueryType::NS).count(), 1);
+}
+
+#[test]
+fn changes_are_written_back_to_the_zone_file() {
+    let path = std::env::temp_dir().join(format!("dns-server-update-{}.zone", std::process::id()));
+    std::fs::write(&path, ZONE).unwrap();
+
+    let zone = DynamicZone::load(&path, Vec::new()).unwrap();
+    let update = Update::new("example.test")
+        .add_record(a("host.example.test", Ipv4Addr::new(10, 0, 0, 7)))
+        .add_record(DnsRecord::TXT {
+            domain: "host.example.test".to_string(),
+            data: vec!["say \"hi\"".to_string()],
+            ttl: 60,
+        });
+    assert_eq!(zone.apply(&update), ResultCode::NOERROR);
+
+    let reloaded = Zone::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(reloaded.serial(), Some(2));
+    assert_eq!(reloaded.records, zone.zone().records);
+}
+
+#[test]
+fn updates_read_back_as_written() {
+    let update = Update::new("example.test")
+        .require_name("www.example.test")
+        .require_record(a("www.example.test", Ipv4Addr::new(10, 0, 0, 1)))
+        .delete_rrset("www.example.test", QueryType::A)
+        .delete_record(a("ns1.example.test", Ipv4Addr::new(10, 0, 0, 53)))
+        .add_record(a("www.example.test", Ipv4Addr::new(10, 0, 0, 2)));
+
+    let mut buffer = BytePacketBuffer::new();
+    update.write(&mut buffer).unwrap();
+    buffer.seek(0).unwrap();
+    assert_eq!(Update::read(&mut buffer).unwrap(), update);
+}
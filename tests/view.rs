@@ -0,0 +1,87 @@
+//! Clients are answered from the view their address belongs to, and everyone
+//! else the way they would be without views.
+
+mod common;
+
+use std::{
+    net::{IpAddr, Ipv4Addr, UdpSocket},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use dns_server::{
+    resolver::ResolverOptions,
+    server,
+    view::{Subnet, View},
+    zone::Zone,
+    QueryType,
+};
+
+use common::ask_from;
+
+fn zone(addr: &str) -> Zone {
+    Zone::parse(&format!(
+        "$ORIGIN example.test.\n\
+         $TTL 300\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         www IN A {}\n",
+        addr
+    ))
+    .unwrap()
+}
+
+/// Ask the server from `client`, and take the address it answers with
+fn ask(server: &UdpSocket, client: Ipv4Addr) -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((client, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let server = server.local_addr().unwrap();
+    ask_from(&socket, server, "www.example.test", QueryType::A).get_random_a()
+}
+
+#[test]
+fn clients_in_a_view_get_its_zones() {
+    let mut options = ResolverOptions {
+        zones: vec![zone("203.0.113.1")],
+        ..ResolverOptions::default()
+    };
+    let mut internal = View::new("internal", vec!["127.0.0.2".parse().unwrap()], &options);
+    Arc::make_mut(&mut internal.options).zones = vec![zone("10.0.0.1")];
+    options.views.push(internal);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.try_clone().unwrap();
+    let options = Arc::new(options);
+    thread::spawn(move || server::serve_udp(server, options, 1));
+
+    assert_eq!(
+        ask(&socket, Ipv4Addr::new(127, 0, 0, 2)),
+        Some(Ipv4Addr::new(10, 0, 0, 1))
+    );
+    assert_eq!(
+        ask(&socket, Ipv4Addr::new(127, 0, 0, 1)),
+        Some(Ipv4Addr::new(203, 0, 113, 1))
+    );
+}
+
+#[test]
+fn subnets_match_their_prefix() {
+    let subnet: Subnet = "192.168.0.0/16".parse().unwrap();
+    assert!(subnet.contains(IpAddr::from([192, 168, 10, 1])));
+    assert!(!subnet.contains(IpAddr::from([192, 169, 0, 1])));
+    // As the IPv4 clients of dual-stack sockets show up
+    assert!(subnet.contains("::ffff:192.168.10.1".parse().unwrap()));
+
+    let subnet: Subnet = "fd00::/8".parse().unwrap();
+    assert!(subnet.contains("fd12:3456::1".parse().unwrap()));
+    assert!(!subnet.contains("2001:db8::1".parse().unwrap()));
+    assert!(!subnet.contains(IpAddr::from([10, 0, 0, 1])));
+
+    let everyone: Subnet = "0.0.0.0/0".parse().unwrap();
+    assert!(everyone.contains(IpAddr::from([8, 8, 8, 8])));
+
+    assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+    assert!("10.0.0/8".parse::<Subnet>().is_err());
+}
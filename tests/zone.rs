@@ -0,0 +1,215 @@
+//! Answers from local zones carry whole RRsets, and every RRset of the name
+//! for ANY. Wildcards answer for the names that don't exist below them.
+//! Records may have a TTL of their own rather than that of `$TTL`, may be
+//! given in the generic notation of RFC 3597, and have to make sense to be
+//! loaded at all.
+
+use dns_server::{
+    zone::{Zone, DEFAULT_TTL},
+    BytePacketBuffer, DnsPacket, DnsRecord, QueryType, ResultCode,
+};
+
+const ZONE: &str = r#"
+$ORIGIN example.test.
+$TTL 300
+@ IN SOA ns1 hostmaster 1 3600 600 86400 60
+@ IN NS ns1
+www IN A 10.0.0.1
+www IN AAAA 2001:db8::1
+www IN A 10.0.0.2
+www IN TXT "web"
+"#;
+
+/// Write the answer and parse it back, as a client would see it
+fn answer(qname: &str, qtype: QueryType) -> DnsPacket {
+    let zone = Zone::parse(ZONE).unwrap();
+    let mut packet = zone.answer(qname, qtype).unwrap();
+
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    DnsPacket::from_buffer(&mut BytePacketBuffer::from_slice(
+        &buffer.buf[..buffer.pos()],
+    ))
+    .unwrap()
+}
+
+#[test]
+fn answers_hold_the_whole_rrset() {
+    let packet = answer("www.example.test", QueryType::A);
+
+    assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+    assert_eq!(packet.header.answers, 2);
+    assert!(packet
+        .answers
+        .iter()
+        .all(|record| matches!(record, DnsRecord::A { .. })));
+}
+
+#[test]
+fn any_gets_every_rrset_of_the_name() {
+    let packet = answer("www.example.test", QueryType::ANY);
+
+    let types: Vec<QueryType> = packet
+        .answers
+        .iter()
+        .map(|record| record.query_type())
+        .collect();
+    assert_eq!(
+        types,
+        [QueryType::A, QueryType::A, QueryType::TXT, QueryType::AAAA]
+    );
+    assert_eq!(packet.header.answers, 4);
+    assert!(packet.authorities.is_empty());
+}
+
+#[test]
+fn any_for_a_missing_name_is_nxdomain() {
+    let packet = answer("nope.example.test", QueryType::ANY);
+
+    assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+    assert!(packet.answers.is_empty());
+    assert_eq!(packet.header.authoritative_entries, 1);
+}
+
+#[test]
+fn records_keep_a_ttl_of_their_own() {
+    let zone = Zone::parse(
+        "a.example.test. A 10.0.0.1 ; before any $TTL\n\
+         $TTL 300\n\
+         www.example.test. 60 A 10.0.0.1\n\
+         mail.example.test. A 10.0.0.3\n\
+         mail.example.test. MX 10 mail.example.test.\n",
+    )
+    .unwrap();
+
+    for (qname, ttl) in [
+        ("a.example.test", DEFAULT_TTL),
+        ("www.example.test", 60),
+        ("mail.example.test", 300),
+    ] {
+        let response = zone.answer(qname, QueryType::A).unwrap();
+        match &response.answers[..] {
+            [DnsRecord::A {
+                ttl: record_ttl, ..
+            }] => assert_eq!(*record_ttl, ttl, "{}", qname),
+            answers => panic!("{:?}", answers),
+        }
+    }
+
+    // Names that only have records of other types get an empty answer
+    let response = zone.answer("WWW.example.test", QueryType::MX).unwrap();
+    assert!(response.answers.is_empty());
+    assert!(zone.answer("ftp.example.test", QueryType::A).is_none());
+}
+
+#[test]
+fn records_have_to_make_sense() {
+    for line in [
+        "www.example.test. A",
+        "www.example.test. A 10.0.0",
+        "www.example.test. MX mail.example.test.",
+        "www.example.test. 60",
+    ] {
+        assert!(Zone::parse(line).is_err(), "{}", line);
+    }
+    assert!(Zone::parse("$TTL soon").is_err());
+}
+
+#[test]
+fn records_of_any_type_in_generic_notation() {
+    let zone = Zone::parse(
+        r#"
+$ORIGIN example.test.
+_443._tcp.www TYPE52 \# 3 03 0101
+mail A \# 4 0A000019
+"#,
+    )
+    .unwrap();
+
+    let packet = zone
+        .answer("_443._tcp.www.example.test", QueryType::UNKNOWN(52))
+        .unwrap();
+    assert_eq!(
+        packet.answers,
+        [DnsRecord::UNKNOWN {
+            domain: "_443._tcp.www.example.test".to_string(),
+            qtype: 52,
+            class: 1,
+            data_len: 3,
+            raw: vec![3, 1, 1],
+            ttl: 3600,
+        }]
+    );
+    assert_eq!(packet.answers[0].data(), "\\# 3 030101");
+
+    // Types we know come out as if they were written out in full
+    let packet = zone.answer("mail.example.test", QueryType::A).unwrap();
+    assert_eq!(packet.get_random_a(), Some("10.0.0.25".parse().unwrap()));
+
+    assert!(Zone::parse("www.example.test. TYPE52 \\# 3 0301").is_err());
+}
+
+#[test]
+fn wildcards_answer_for_names_that_do_not_exist() {
+    let zone = Zone::parse(
+        "$ORIGIN example.test.\n\
+         @ IN SOA ns1 hostmaster 1 3600 600 86400 60\n\
+         *.dev IN A 10.0.0.1\n\
+         *.dev IN TXT \"wildcard\"\n\
+         api.dev IN A 10.0.0.2\n\
+         host.team.dev IN A 10.0.0.3\n\
+         *.cdn IN CNAME edge.example.net.\n",
+    )
+    .unwrap();
+    let answer = |qname: &str, qtype| zone.answer(qname, qtype).unwrap();
+
+    // Any depth below the wildcard, with the records moved to the name asked
+    // for, in the case it was asked in
+    let packet = answer("Build.Dev.example.test", QueryType::A);
+    assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+    assert_eq!(
+        packet.answers,
+        vec![DnsRecord::A {
+            domain: "Build.Dev.example.test".to_string(),
+            addr: "10.0.0.1".parse().unwrap(),
+            ttl: 3600,
+        }]
+    );
+    assert_eq!(
+        answer("a.b.dev.example.test", QueryType::A).answers.len(),
+        1
+    );
+    assert_eq!(answer("*.dev.example.test", QueryType::A).answers.len(), 1);
+
+    // The types the wildcard doesn't have get an empty answer, and aliases
+    // stand in for every type
+    let packet = answer("build.dev.example.test", QueryType::AAAA);
+    assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+    assert!(packet.answers.is_empty());
+    let packet = answer("img.cdn.example.test", QueryType::AAAA);
+    assert!(matches!(
+        &packet.answers[..],
+        [DnsRecord::CNAME { domain, .. }] if domain == "img.cdn.example.test"
+    ));
+
+    // Names that exist keep their own records, or the lack of them
+    let packet = answer("api.dev.example.test", QueryType::A);
+    assert_eq!(packet.get_random_a(), "10.0.0.2".parse().ok());
+    let packet = answer("api.dev.example.test", QueryType::TXT);
+    assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+    assert!(packet.answers.is_empty());
+
+    // An empty non-terminal exists as well, and stops the wildcard above it
+    // from reaching the names below
+    let packet = answer("team.dev.example.test", QueryType::A);
+    assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+    assert!(packet.answers.is_empty());
+    let packet = answer("other.team.dev.example.test", QueryType::A);
+    assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+    let packet = answer("dev.example.test", QueryType::A);
+    assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+
+    // Nothing above the wildcard matches it
+    let packet = answer("www.example.test", QueryType::A);
+    assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+}